@@ -0,0 +1,268 @@
+use core::mem::{offset_of, size_of};
+use core::ptr::read_unaligned;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+ only
+    length: u32,
+    xsdt_address: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// ACPI Generic Address Structure (GAS).
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    const SYSTEM_MEMORY: u8 = 0;
+    const SYSTEM_IO: u8 = 1;
+
+    /// Write one byte to the register. Returns false for unsupported address spaces.
+    pub fn write_u8(&self, value: u8) -> bool {
+        let addr = self.address;
+        match self.address_space {
+            Self::SYSTEM_MEMORY => match memory::map_mmio(PhysAddr::new(addr), 1) {
+                Ok(reg) => {
+                    reg.register::<u8>(0).set(value);
+                    true
+                }
+                Err(_) => false,
+            },
+            Self::SYSTEM_IO => {
+                unsafe { Port::<u8>::new(addr as u16).write(value) };
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Fixed ACPI Description Table, up to the fields we actually use.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Fadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved2: u8,
+    flags: u32,
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+}
+
+/// What the rest of the kernel needs to know from the FADT.
+#[derive(Copy, Clone)]
+pub struct FadtInfo {
+    pub reset_reg: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub smi_cmd: u16,
+    pub acpi_enable: u8,
+    pub pm1a_cnt: u16,
+    pub pm1b_cnt: u16,
+    pub dsdt: Option<PhysAddr>,
+}
+
+/// SLP_TYPa/SLP_TYPb values for a sleep state, taken from the DSDT.
+#[derive(Copy, Clone, Debug)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+struct Tables {
+    /// Physical address of the RSDT or XSDT.
+    root: PhysAddr,
+    /// true = XSDT (64-bit entries), false = RSDT (32-bit entries)
+    extended: bool,
+}
+
+static TABLES: Once<Tables> = Once::new();
+static FADT: Once<FadtInfo> = Once::new();
+static S5: Once<SleepType> = Once::new();
+
+/// Locate the root table from the RSDP the bootloader handed us and cache the FADT.
+pub fn init(rsdp_addr: u64) {
+    let rsdp: Rsdp = unsafe { read_phys(PhysAddr::new(rsdp_addr)) };
+    if &rsdp.signature != b"RSD PTR " {
+        crate::serial_println!("acpi: bad RSDP signature");
+        return;
+    }
+    let tables = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        Tables { root: PhysAddr::new(rsdp.xsdt_address), extended: true }
+    } else {
+        Tables { root: PhysAddr::new(rsdp.rsdt_address as u64), extended: false }
+    };
+    TABLES.call_once(|| tables);
+
+    if let Some(addr) = find_table(b"FACP") {
+        let fadt: Fadt = unsafe { read_phys(addr) };
+        let len = fadt.header.length as usize;
+        let flags = fadt.flags;
+        // RESET_REG_SUP (bit 10); the field itself only exists from revision 2 on.
+        let has_reset = len > offset_of!(Fadt, reset_value) && flags & (1 << 10) != 0;
+        // Prefer the 64-bit X_DSDT pointer when the table is long enough to have one.
+        let x_dsdt = if len >= size_of::<Fadt>() { fadt.x_dsdt } else { 0 };
+        let dsdt = if x_dsdt != 0 { x_dsdt } else { fadt.dsdt as u64 };
+
+        let info = FadtInfo {
+            reset_reg: if has_reset { Some(fadt.reset_reg) } else { None },
+            reset_value: fadt.reset_value,
+            smi_cmd: fadt.smi_cmd as u16,
+            acpi_enable: fadt.acpi_enable,
+            pm1a_cnt: fadt.pm1a_cnt_blk as u16,
+            pm1b_cnt: fadt.pm1b_cnt_blk as u16,
+            dsdt: if dsdt != 0 { Some(PhysAddr::new(dsdt)) } else { None },
+        };
+        FADT.call_once(|| info);
+
+        if let Some(s5) = info.dsdt.and_then(parse_s5) {
+            S5.call_once(|| s5);
+        }
+    }
+}
+
+/// Find the `\_S5_` package in the DSDT's AML without a full interpreter.
+///
+/// The encoding we look for is: NameOp "_S5_" PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...
+fn parse_s5(dsdt: PhysAddr) -> Option<SleepType> {
+    let header: SdtHeader = unsafe { read_phys(dsdt) };
+    let aml = unsafe {
+        let start = memory::phys_to_virt(dsdt).as_ptr::<u8>();
+        core::slice::from_raw_parts(start, header.length as usize)
+    };
+    let body = &aml[size_of::<SdtHeader>()..];
+
+    let pos = body.windows(4).position(|w| w == b"_S5_")?;
+    // Must be preceded by NameOp (0x08), optionally with the root prefix '\'.
+    let is_name = (pos >= 1 && body[pos - 1] == 0x08)
+        || (pos >= 2 && body[pos - 2] == 0x08 && body[pos - 1] == b'\\');
+    if !is_name || *body.get(pos + 4)? != 0x12 {
+        return None;
+    }
+
+    let mut i = pos + 5;
+    // PkgLength: bits 6-7 of the lead byte count the extra length bytes.
+    i += (*body.get(i)? >> 6) as usize + 1;
+    i += 1; // NumElements
+    let (a, n) = aml_byte(body.get(i..)?)?;
+    let (b, _) = aml_byte(body.get(i + n..)?)?;
+    Some(SleepType { a, b })
+}
+
+/// Decode a small AML integer: ZeroOp, OneOp or BytePrefix. Returns (value, bytes used).
+fn aml_byte(bytes: &[u8]) -> Option<(u8, usize)> {
+    match *bytes.first()? {
+        0x00 => Some((0, 1)),
+        0x01 => Some((1, 1)),
+        0x0A => Some((*bytes.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// Physical address of the first table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let tables = TABLES.get()?;
+    let root: SdtHeader = unsafe { read_phys(tables.root) };
+    let entry_size = if tables.extended { 8 } else { 4 };
+    let count = (root.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let entries = tables.root + size_of::<SdtHeader>() as u64;
+
+    for i in 0..count {
+        let entry = entries + (i * entry_size) as u64;
+        let addr = unsafe {
+            if tables.extended { read_phys::<u64>(entry) } else { read_phys::<u32>(entry) as u64 }
+        };
+        let header: SdtHeader = unsafe { read_phys(PhysAddr::new(addr)) };
+        if &header.signature == signature {
+            return Some(PhysAddr::new(addr));
+        }
+    }
+    None
+}
+
+pub fn fadt() -> Option<&'static FadtInfo> {
+    FADT.get()
+}
+
+pub fn s5() -> Option<SleepType> {
+    S5.get().copied()
+}
+
+/// Read a (possibly unaligned) value from physical memory.
+///
+/// # Safety
+/// `addr` must point to at least `size_of::<T>()` readable bytes.
+pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    read_unaligned(memory::phys_to_virt(addr).as_ptr::<T>())
+}
@@ -0,0 +1,55 @@
+//! Stack traces from the frame-pointer chain (the kernel is built with
+//! `-C force-frame-pointers=yes`): each frame starts with the caller's RBP,
+//! followed by the return address.
+//!
+//! Addresses are printed both as they are and relative to the kernel image,
+//! which KASLR moves; feed the latter to `addr2line -e kernel`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+
+use crate::memory::paging;
+use crate::serial_println;
+
+const MAX_FRAMES: usize = 32;
+
+static IMAGE_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Record where the bootloader loaded the kernel.
+pub fn init(image_offset: u64) {
+    IMAGE_OFFSET.store(image_offset, Ordering::Relaxed);
+}
+
+/// Print the return addresses of the calling function and its callers.
+///
+/// Safe on the panic path: every frame is checked to be mapped before it is
+/// read (giving up if the page table is locked), and the chain must move up
+/// the stack, so a corrupt one ends the trace instead of faulting.
+#[inline(never)]
+pub fn print() {
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    let offset = IMAGE_OFFSET.load(Ordering::Relaxed);
+    serial_println!("backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !readable(rbp) {
+            break;
+        }
+        // Safety: both words were just checked to be mapped.
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        serial_println!("  {:#018x}  (image + {:#x})", ret, ret.wrapping_sub(offset));
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Whether the two words of the frame at `rbp` can be read without faulting.
+fn readable(rbp: u64) -> bool {
+    let (Ok(first), Ok(last)) = (VirtAddr::try_new(rbp), VirtAddr::try_new(rbp + 15)) else { return false };
+    paging::try_is_mapped(first) && paging::try_is_mapped(last)
+}
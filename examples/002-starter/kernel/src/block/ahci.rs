@@ -0,0 +1,428 @@
+//! AHCI: how SATA disks are driven today, and what the q35 machine has
+//! (an ICH9 controller at 00:1f.2, with the boot disk on port 0).
+//!
+//! The controller (the HBA) has its registers in memory, at BAR 5, with a
+//! block of them for each of up to 32 ports. A port fetches commands from a
+//! command list in RAM: 32 slots, each pointing at a command table with the
+//! command itself, as the FIS (frame) the disk gets over the wire, and a
+//! list of memory regions (the PRDT) for the data, which the HBA moves by
+//! DMA. Setting a slot's bit in the port's command-issue register sends
+//! it; the bit clears when the disk is done, and the port interrupts. The
+//! disk's answers land in a received-FIS area, also in RAM.
+//!
+//! We use slot 0 alone, one command at a time, without native command
+//! queueing, through a bounce buffer like `virtio`'s.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use super::{check_range, next_name, register, BlockDevice, BlockError};
+use crate::dma::{self, DmaBuffer};
+use crate::memory::mmio::{map_mmio, Mmio};
+use crate::pci::{self, Bar};
+use crate::sync::{Mutex, RwLock, WaitQueue};
+use crate::{interrupts, serial_println, time};
+
+pub const SECTOR_SIZE: usize = 512;
+/// The most one command moves: the bounce buffer.
+const MAX_TRANSFER: usize = 64 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// HBA registers.
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0C;
+const VS: usize = 0x10;
+
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+/// Port registers, from the port's block.
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const P_CLB: usize = 0x00;
+const P_FB: usize = 0x08;
+const P_IS: usize = 0x10;
+const P_IE: usize = 0x14;
+const P_CMD: usize = 0x18;
+const P_TFD: usize = 0x20;
+const P_SIG: usize = 0x24;
+const P_SSTS: usize = 0x28;
+const P_SERR: usize = 0x30;
+const P_CI: usize = 0x38;
+
+/// Port command bits: start, FIS receive enable, and their running flags.
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// Port interrupt bits: a register, PIO setup, DMA setup or set-device-bits
+/// FIS arrived, or the disk reported an error (task file error).
+const IS_DHRS: u32 = 1 << 0;
+const IS_PSS: u32 = 1 << 1;
+const IS_DSS: u32 = 1 << 2;
+const IS_SDBS: u32 = 1 << 3;
+const IS_TFES: u32 = 1 << 30;
+
+/// Task file: the disk's ATA status byte.
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+
+/// The signature of a plain ATA disk (not ATAPI, not a port multiplier).
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_REG_H2D: u8 = 0x27;
+
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_EXT: u8 = 0xEA;
+
+/// Where things are in a port's page of DMA memory: the command list
+/// (32 headers of 32 bytes, 1 KiB aligned), the received FIS area (256
+/// bytes, 256 aligned) and slot 0's command table (128 aligned): the
+/// command FIS, then one PRDT entry at 0x80.
+const COMMAND_LIST: usize = 0;
+const RECEIVED_FIS: usize = 1024;
+const COMMAND_TABLE: usize = 2048;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+/// The controller's registers, shared by its ports.
+struct Hba {
+    regs: Mmio,
+    device: pci::Device,
+}
+
+// Like `virtio::Transport`: mapped in the shared kernel half, and only
+// touched a register at a time.
+unsafe impl Send for Hba {}
+unsafe impl Sync for Hba {}
+
+impl Hba {
+    fn read(&self, offset: usize) -> u32 {
+        self.regs.register::<u32>(offset).get()
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.regs.register::<u32>(offset).set(value)
+    }
+}
+
+pub struct AhciDisk {
+    hba: Arc<Hba>,
+    port: usize,
+    sectors: u64,
+    model: String,
+    irq: Once<u8>,
+    inner: Mutex<Inner>,
+    /// Interrupt status bits the handler took from the port since the last
+    /// command began; it has to clear them to lower the line.
+    status: AtomicU32,
+    done: WaitQueue,
+    interrupts: AtomicU64,
+}
+
+struct Inner {
+    /// The command list, received FIS area and command table.
+    memory: DmaBuffer,
+    data: DmaBuffer,
+}
+
+/// Every AHCI disk found, for `self_test`.
+static DISKS: RwLock<Vec<Arc<AhciDisk>>> = RwLock::new(Vec::new());
+
+/// Wait for `done`, polling until `deadline`.
+fn poll(deadline: Duration, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if time::uptime() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+impl AhciDisk {
+    fn port_read(&self, offset: usize) -> u32 {
+        self.hba.read(PORT_BASE + PORT_SIZE * self.port + offset)
+    }
+
+    fn port_write(&self, offset: usize, value: u32) {
+        self.hba.write(PORT_BASE + PORT_SIZE * self.port + offset, value)
+    }
+
+    /// Stop the port processing commands and receiving FISes, and wait
+    /// until it has.
+    fn stop(&self) -> bool {
+        self.port_write(P_CMD, self.port_read(P_CMD) & !CMD_ST);
+        let deadline = time::uptime() + TIMEOUT;
+        let stopped = poll(deadline, || self.port_read(P_CMD) & CMD_CR == 0);
+        self.port_write(P_CMD, self.port_read(P_CMD) & !CMD_FRE);
+        stopped && poll(deadline, || self.port_read(P_CMD) & CMD_FR == 0)
+    }
+
+    fn start(&self) {
+        self.port_write(P_CMD, self.port_read(P_CMD) | CMD_FRE);
+        self.port_write(P_CMD, self.port_read(P_CMD) | CMD_ST);
+    }
+
+    /// Point a stopped port at `inner`'s memory, clear what it had
+    /// pending, and start it.
+    fn attach(&self, inner: &Inner) {
+        let base = inner.memory.phys().as_u64();
+        for (offset, addr) in [(P_CLB, base + COMMAND_LIST as u64), (P_FB, base + RECEIVED_FIS as u64)] {
+            self.port_write(offset, addr as u32);
+            self.port_write(offset + 4, (addr >> 32) as u32);
+        }
+        self.port_write(P_SERR, u32::MAX);
+        self.port_write(P_IS, u32::MAX);
+        self.start();
+    }
+
+    /// On the HBA's line: if this port interrupted, take and clear its
+    /// status, then the HBA's bit for the port, and wake the waiting thread.
+    fn on_interrupt(&self) {
+        let status = self.port_read(P_IS);
+        if status == 0 {
+            return;
+        }
+        self.port_write(P_IS, status);
+        self.hba.write(IS, 1 << self.port);
+        self.status.fetch_or(status, Ordering::AcqRel);
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        self.done.notify_all();
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Run ATA `command` on slot 0, at `lba` for `count` sectors, moving
+    /// `len` bytes of the bounce buffer (to the disk if `write`), and wait
+    /// for it to finish.
+    fn command(&self, inner: &mut Inner, command: u8, lba: u64, count: u16, len: usize, write: bool) -> Result<(), BlockError> {
+        // The command header: FIS length in dwords, write, PRDT entries,
+        // bytes moved (the HBA counts), the command table's address.
+        let table = inner.memory.phys().as_u64() + COMMAND_TABLE as u64;
+        let memory = inner.memory.as_mut_slice();
+        let flags = 5 | (write as u32) << 6 | ((len > 0) as u32) << 16;
+        let header = [flags, 0, table as u32, (table >> 32) as u32];
+        for (i, dword) in header.iter().enumerate() {
+            memory[COMMAND_LIST + 4 * i..][..4].copy_from_slice(&dword.to_le_bytes());
+        }
+        let fis = &mut memory[COMMAND_TABLE..COMMAND_TABLE + 64];
+        fis.fill(0);
+        let lba_bytes = lba.to_le_bytes();
+        fis[0] = FIS_REG_H2D;
+        fis[1] = 0x80; // a command, not a control update
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba_bytes[..3]);
+        fis[7] = 1 << 6; // LBA addressing
+        fis[8..11].copy_from_slice(&lba_bytes[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+        if len > 0 {
+            let data = inner.data.phys().as_u64();
+            // Byte count minus one, and interrupt when this region is done.
+            let entry = [data as u32, (data >> 32) as u32, 0, (len as u32 - 1) | 1 << 31];
+            for (i, dword) in entry.iter().enumerate() {
+                memory[PRDT + 4 * i..][..4].copy_from_slice(&dword.to_le_bytes());
+            }
+        }
+
+        let deadline = time::uptime() + TIMEOUT;
+        if !poll(deadline, || self.port_read(P_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+            return Err(BlockError::Io("disk stays busy"));
+        }
+        self.status.store(0, Ordering::Release);
+        fence(Ordering::SeqCst);
+        self.port_write(P_CI, 1);
+
+        let finished = || self.port_read(P_CI) & 1 == 0 || (self.status.load(Ordering::Acquire) | self.port_read(P_IS)) & IS_TFES != 0;
+        let in_time = if self.irq.get().is_some() {
+            self.done.wait_until_deadline(finished, deadline)
+        } else {
+            poll(deadline, finished)
+        };
+        let status = self.status.swap(0, Ordering::AcqRel) | self.port_read(P_IS);
+        if !in_time || status & IS_TFES != 0 || self.port_read(P_TFD) & TFD_ERR != 0 {
+            // The port stops on an error; clear it and start over.
+            self.stop();
+            self.attach(inner);
+            return Err(BlockError::Io(if in_time { "disk reported an error" } else { "disk timed out" }));
+        }
+        Ok(())
+    }
+
+    /// Ask the disk who it is: its size and model.
+    fn identify(&self, inner: &mut Inner) -> Result<(u64, String), BlockError> {
+        self.command(inner, ATA_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+        let bytes = &inner.data.as_mut_slice()[..SECTOR_SIZE];
+        let word = |i: usize| u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]);
+        let sectors = (100..104).rev().fold(0, |n, i| n << 16 | word(i) as u64);
+        // Two characters a word, the first in the high byte.
+        let model: String = (27..47).flat_map(|i| [(word(i) >> 8) as u8 as char, word(i) as u8 as char]).collect();
+        Ok((sectors, String::from(model.trim())))
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let lba = start + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.command(&mut inner, ATA_READ_DMA_EXT, lba, (chunk.len() / SECTOR_SIZE) as u16, chunk.len(), false)?;
+            chunk.copy_from_slice(&inner.data.as_mut_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, data.len())?;
+        let mut inner = self.inner.lock();
+        for (i, chunk) in data.chunks(MAX_TRANSFER).enumerate() {
+            let lba = start + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            inner.data.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.command(&mut inner, ATA_WRITE_DMA_EXT, lba, (chunk.len() / SECTOR_SIZE) as u16, chunk.len(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        self.command(&mut inner, ATA_FLUSH_EXT, 0, 0, 0, false)
+    }
+
+    fn describe(&self) -> String {
+        let irq = self.irq.get().map_or(String::from("polled"), |line| format!("irq {}", line));
+        format!("SATA {} (AHCI at {}, port {}, {})", self.model, self.hba.device.addr, self.port, irq)
+    }
+}
+
+/// Set up one AHCI controller: map its registers, switch it to AHCI mode,
+/// and register the ATA disk on each port that has one, as `sd0`, `sd1`...
+fn init_controller(device: &pci::Device) -> Result<(), &'static str> {
+    let Some(Bar::Memory { addr, size, .. }) = device.bar(5) else {
+        return Err("no register BAR");
+    };
+    let regs = map_mmio(PhysAddr::new(addr), size as usize).map_err(|_| "cannot map registers")?;
+    device.enable();
+    let hba = Arc::new(Hba { regs, device: *device });
+    hba.write(GHC, hba.read(GHC) | GHC_AE);
+    let version = hba.read(VS);
+    serial_println!("block: AHCI {}.{} at {}, ports {:#x}", version >> 16, (version >> 8) & 0xFF, device.addr, hba.read(PI));
+
+    let implemented = hba.read(PI);
+    for port in (0..32).filter(|port| implemented & 1 << port != 0) {
+        let port_regs = PORT_BASE + PORT_SIZE * port;
+        // A device is there and the link is up (DET 3), and active (IPM 1).
+        let link = hba.read(port_regs + P_SSTS);
+        if link & 0xF != 3 || (link >> 8) & 0xF != 1 {
+            continue;
+        }
+        let signature = hba.read(port_regs + P_SIG);
+        if signature != SIG_ATA {
+            serial_println!("block: AHCI port {}: signature {:#x}, not an ATA disk, skipped", port, signature);
+            continue;
+        }
+        let memory = dma::alloc(4096, 4096).map_err(|_| "no memory for a command list")?;
+        let data = dma::alloc(MAX_TRANSFER, 4096).map_err(|_| "no memory for a bounce buffer")?;
+        let mut disk = AhciDisk {
+            hba: hba.clone(),
+            port,
+            sectors: 0,
+            model: String::new(),
+            irq: Once::new(),
+            inner: Mutex::new(Inner { memory, data }),
+            status: AtomicU32::new(0),
+            done: WaitQueue::new(),
+            interrupts: AtomicU64::new(0),
+        };
+        if !disk.stop() {
+            serial_println!("block: AHCI port {}: won't stop, skipped", port);
+            continue;
+        }
+        disk.attach(&disk.inner.lock());
+        // Polled: the interrupt handler needs the disk finished.
+        let identity = disk.identify(&mut disk.inner.lock());
+        let Ok((sectors, model)) = identity else {
+            serial_println!("block: AHCI port {}: IDENTIFY failed", port);
+            continue;
+        };
+        disk.sectors = sectors;
+        disk.model = model;
+
+        let disk = Arc::new(disk);
+        disk.port_write(P_IS, u32::MAX);
+        disk.port_write(P_IE, IS_DHRS | IS_PSS | IS_DSS | IS_SDBS | IS_TFES);
+        if let Some(line) = device.interrupt_line {
+            let handler = disk.clone();
+            if interrupts::add_line_handler(line, Box::new(move || handler.on_interrupt())) {
+                disk.irq.call_once(|| line);
+                hba.write(GHC, hba.read(GHC) | GHC_IE);
+            }
+        }
+        DISKS.write().push(disk.clone());
+        register(&next_name("sd"), disk);
+    }
+    Ok(())
+}
+
+/// Register the disks of every AHCI controller.
+pub fn probe() {
+    for device in pci::devices().iter().filter(|d| d.class == 0x01 && d.subclass == 0x06 && d.prog_if == 0x01) {
+        if let Err(err) = init_controller(device) {
+            serial_println!("block: AHCI at {}: {}", device.addr, err);
+        }
+    }
+}
+
+/// On each AHCI disk: a read longer than one command matches the same
+/// sectors read alone, the end of the disk is enforced, a write to the last
+/// sector reads back (and is undone), and with an interrupt line, commands
+/// raised interrupts. True with no AHCI disks.
+pub fn self_test() -> bool {
+    DISKS.read().iter().all(|disk| {
+        let before = disk.interrupts();
+        let sectors = (MAX_TRANSFER / SECTOR_SIZE + 8).min(disk.sectors as usize);
+        if sectors == 0 {
+            return true;
+        }
+        let mut long = vec![0u8; sectors * SECTOR_SIZE];
+        let mut one = [0u8; SECTOR_SIZE];
+        let mut ok = disk.read_blocks(0, &mut long).is_ok()
+            && disk.read_blocks(sectors as u64 - 1, &mut one).is_ok()
+            && long[long.len() - SECTOR_SIZE..] == one[..]
+            && disk.read_blocks(disk.sectors, &mut one) == Err(BlockError::OutOfRange);
+
+        let last = disk.sectors - 1;
+        let mut saved = [0u8; SECTOR_SIZE];
+        let pattern: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 17 + 1) as u8).collect();
+        ok &= disk.read_blocks(last, &mut saved).is_ok()
+            && disk.write_blocks(last, &pattern).is_ok()
+            && disk.flush().is_ok()
+            && disk.read_blocks(last, &mut one).is_ok()
+            && one[..] == pattern[..]
+            && disk.write_blocks(last, &saved).is_ok()
+            && disk.flush().is_ok();
+        ok && (disk.irq.get().is_none() || disk.interrupts() > before)
+    })
+}
@@ -0,0 +1,569 @@
+//! ATA in PIO mode: the IDE disks of the BIOS path's `pc` machine (an
+//! i440fx with a PIIX IDE controller), where the boot disk is the primary
+//! channel's master. The CPU moves every word itself, through a data port;
+//! nothing is faster to write a driver for, or slower to run.
+//!
+//! Each of the two legacy channels has eight command registers (sector
+//! count, the LBA in three bytes, the drive select, command and status),
+//! a control register, and an IRQ line: 14 and 15. A drive raises it when
+//! a sector is ready to read or has been written; reading the status
+//! register lowers it. Addresses are 28-bit LBAs (128 GiB), or 48-bit ones
+//! for drives that have them, with each register written twice.
+//!
+//! A CD drive on a channel speaks ATAPI: SCSI commands, sent in 12-byte
+//! packets through the data port, each answered in rounds of as many
+//! bytes as the drive says, an interrupt before each. It is registered as
+//! `cd0`, read-only, in 2 KiB sectors; the disc is the one in the drive at
+//! boot.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use super::{check_range, next_name, register, BlockDevice, BlockError};
+use crate::sync::{Mutex, RwLock, WaitQueue};
+use crate::{interrupts, pci, serial_println, time};
+
+pub const SECTOR_SIZE: usize = 512;
+/// The most sectors one command moves.
+const MAX_SECTORS: usize = 256;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Command block registers, from the channel's base port.
+const DATA: u16 = 0;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+/// Status bits.
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+/// Device control bit: don't raise the IRQ line.
+const CONTROL_NIEN: u8 = 0x02;
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_FLUSH: u8 = 0xE7;
+const CMD_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+const CMD_PACKET: u8 = 0xA0;
+const CMD_IDENTIFY_PACKET: u8 = 0xA1;
+
+/// What an ATAPI device leaves in LBA mid and high when it refuses
+/// IDENTIFY.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+/// SCSI commands, sent in packets.
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+pub const CD_SECTOR_SIZE: usize = 2048;
+/// The most sectors one READ(10) moves here: 64 KiB.
+const MAX_CD_SECTORS: usize = 32;
+
+/// The legacy channels: command block, control register, IRQ line.
+const CHANNELS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+
+/// One IDE channel, shared by its two drives: one command at a time.
+struct Channel {
+    base: u16,
+    control: u16,
+    /// Its IRQ line, if we have its interrupts; otherwise commands poll.
+    irq: Once<u8>,
+    /// Held for each command, from issuing it to its last sector.
+    lock: Mutex<()>,
+    /// Set by the interrupt handler, cleared before each wait for it.
+    fired: AtomicBool,
+    done: WaitQueue,
+    interrupts: AtomicU64,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    /// The status without acknowledging an interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn set_interrupts(&self, on: bool) {
+        unsafe { Port::<u8>::new(self.control).write(if on { 0 } else { CONTROL_NIEN }) }
+    }
+
+    /// Give the drive the 400ns it needs to put its status up after a
+    /// select or a command: four reads of the alternate status.
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Select the master or slave drive.
+    fn select(&self, drive: u8) {
+        self.write(DRIVE, drive);
+        self.settle();
+    }
+
+    /// On IRQ 14 or 15: reading the status lowers the line.
+    fn on_interrupt(&self) {
+        self.read(STATUS);
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        self.fired.store(true, Ordering::Release);
+        self.done.notify_all();
+    }
+
+    /// Wait until the drive isn't busy, then check what it says: `Ok` with
+    /// the status, unless it reports an error or a fault.
+    fn wait_not_busy(&self, deadline: Duration) -> Result<u8, BlockError> {
+        self.settle();
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(BlockError::Io(if status & STATUS_DF != 0 { "drive fault" } else { "drive reported an error" }));
+                }
+                return Ok(status);
+            }
+            if time::uptime() >= deadline {
+                return Err(BlockError::Io("drive timed out"));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait for the drive to be done with a sector: for its interrupt if
+    /// we have them, and then for it not to be busy.
+    fn wait_sector(&self, deadline: Duration) -> Result<u8, BlockError> {
+        if self.irq.get().is_some() && !self.done.wait_until_deadline(|| self.fired.load(Ordering::Acquire), deadline) {
+            return Err(BlockError::Io("drive timed out"));
+        }
+        self.fired.store(false, Ordering::Release);
+        self.wait_not_busy(deadline)
+    }
+
+    /// Wait until the drive wants data (DRQ).
+    fn wait_drq(&self, deadline: Duration) -> Result<(), BlockError> {
+        if self.wait_not_busy(deadline)? & STATUS_DRQ == 0 {
+            return Err(BlockError::Io("drive wants no data"));
+        }
+        Ok(())
+    }
+
+    /// The 256 words an IDENTIFY answers with, once the drive has them up.
+    fn read_identify(&self) -> [u16; 256] {
+        let mut words = [0u16; 256];
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        self.read(STATUS);
+        words
+    }
+}
+
+/// The model in IDENTIFY's words 27 to 46: two characters a word, the
+/// first in the high byte.
+fn model(words: &[u16; 256]) -> String {
+    let model: String = words[27..47].iter().flat_map(|w| [(w >> 8) as u8 as char, *w as u8 as char]).collect();
+    String::from(model.trim())
+}
+
+pub struct AtaDisk {
+    channel: Arc<Channel>,
+    /// The drive register's value for it: LBA mode, and master or slave.
+    drive: u8,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+/// Every ATA disk found, for `self_test`.
+static DISKS: RwLock<Vec<Arc<AtaDisk>>> = RwLock::new(Vec::new());
+
+impl AtaDisk {
+    /// Ask drive `slave` of `channel` who it is. `None` if there is no
+    /// drive, or it isn't an ATA disk (an ATAPI CD drive, say).
+    fn identify(channel: &Arc<Channel>, slave: bool) -> Option<AtaDisk> {
+        let drive = 0xE0 | (slave as u8) << 4;
+        channel.select(drive);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.write(COMMAND, CMD_IDENTIFY);
+        // No drive: the bus floats (0xFF) or nothing answers (0).
+        if matches!(channel.alt_status(), 0 | 0xFF) {
+            return None;
+        }
+        let deadline = time::uptime() + TIMEOUT;
+        while channel.alt_status() & STATUS_BSY != 0 {
+            if time::uptime() >= deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        // ATAPI and SATA devices put their signature here and abort.
+        if channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        channel.wait_drq(deadline).ok()?;
+        let words = channel.read_identify();
+
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            words[100..104].iter().rev().fold(0, |n, &w| n << 16 | w as u64)
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        Some(AtaDisk { channel: channel.clone(), drive, sectors, lba48, model: model(&words) })
+    }
+
+    /// Issue a read or write of `count` sectors from `lba` on: the 28-bit
+    /// command if the range fits, otherwise the 48-bit one.
+    fn issue(&self, write: bool, lba: u64, count: usize) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let wide = lba + count as u64 > 1 << 28;
+        if wide && !self.lba48 {
+            return Err(BlockError::OutOfRange);
+        }
+        channel.fired.store(false, Ordering::Release);
+        if wide {
+            // The high bytes first, then the low ones.
+            channel.select(0x40 | self.drive & 0x10);
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (lba >> 24) as u8);
+            channel.write(LBA_MID, (lba >> 32) as u8);
+            channel.write(LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            channel.select(self.drive | (lba >> 24) as u8 & 0x0F);
+        }
+        // With 28-bit commands, 256 sectors is written as 0.
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, lba as u8);
+        channel.write(LBA_MID, (lba >> 8) as u8);
+        channel.write(LBA_HIGH, (lba >> 16) as u8);
+        channel.write(COMMAND, match (write, wide) {
+            (false, false) => CMD_READ,
+            (false, true) => CMD_READ_EXT,
+            (true, false) => CMD_WRITE,
+            (true, true) => CMD_WRITE_EXT,
+        });
+        Ok(())
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.channel.interrupts.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let _guard = self.channel.lock.lock();
+        let mut data = Port::<u16>::new(self.channel.base + DATA);
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let deadline = time::uptime() + TIMEOUT;
+            self.issue(false, start + (i * MAX_SECTORS) as u64, chunk.len() / SECTOR_SIZE)?;
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                if self.channel.wait_sector(deadline)? & STATUS_DRQ == 0 {
+                    return Err(BlockError::Io("drive has no data"));
+                }
+                for pair in sector.chunks_mut(2) {
+                    pair.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, data.len())?;
+        let _guard = self.channel.lock.lock();
+        let mut port = Port::<u16>::new(self.channel.base + DATA);
+        for (i, chunk) in data.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let deadline = time::uptime() + TIMEOUT;
+            self.issue(true, start + (i * MAX_SECTORS) as u64, chunk.len() / SECTOR_SIZE)?;
+            // The first sector goes as soon as the drive asks; it interrupts
+            // after each one it has taken.
+            self.channel.wait_drq(deadline)?;
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.channel.fired.store(false, Ordering::Release);
+                for pair in sector.chunks(2) {
+                    unsafe { port.write(u16::from_le_bytes([pair[0], pair[1]])) };
+                }
+                self.channel.wait_sector(deadline)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Have the drive write its cache out.
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        self.channel.fired.store(false, Ordering::Release);
+        self.channel.select(self.drive);
+        self.channel.write(COMMAND, if self.lba48 { CMD_FLUSH_EXT } else { CMD_FLUSH });
+        self.channel.wait_sector(time::uptime() + TIMEOUT).map(|_| ())
+    }
+
+    fn describe(&self) -> String {
+        let position = if self.drive & 0x10 == 0 { "master" } else { "slave" };
+        let irq = self.channel.irq.get().map_or(String::from("polled"), |line| format!("irq {}", line));
+        format!("ATA {} ({} at {:#x}, {}, {})", self.model, position, self.channel.base, if self.lba48 { "LBA48" } else { "LBA28" }, irq)
+    }
+}
+
+/// A CD drive on an IDE channel.
+pub struct AtapiDrive {
+    channel: Arc<Channel>,
+    /// The drive register's value for it: master or slave.
+    drive: u8,
+    /// Sectors on the disc in it at boot; 0 without one.
+    sectors: u64,
+    model: String,
+}
+
+/// Every CD drive found, for `self_test`.
+static DRIVES: RwLock<Vec<Arc<AtapiDrive>>> = RwLock::new(Vec::new());
+
+impl AtapiDrive {
+    /// Ask drive `slave` of `channel`, which refused IDENTIFY, whether it
+    /// is an ATAPI drive, and what disc is in it.
+    fn identify(channel: &Arc<Channel>, slave: bool) -> Option<AtapiDrive> {
+        let drive = 0xA0 | (slave as u8) << 4;
+        channel.select(drive);
+        if (channel.read(LBA_MID), channel.read(LBA_HIGH)) != ATAPI_SIGNATURE {
+            return None;
+        }
+        channel.write(COMMAND, CMD_IDENTIFY_PACKET);
+        channel.wait_drq(time::uptime() + TIMEOUT).ok()?;
+        let words = channel.read_identify();
+        let mut cd = AtapiDrive { channel: channel.clone(), drive, sectors: 0, model: model(&words) };
+        // The first command after power-on fails, with a "unit attention"
+        // for the drive's reset: ask again.
+        let sectors = (0..3).find_map(|_| cd.capacity().ok());
+        cd.sectors = sectors.unwrap_or(0);
+        Some(cd)
+    }
+
+    /// READ CAPACITY: the last sector's number, then the sector size, both
+    /// big-endian. How many sectors.
+    fn capacity(&self) -> Result<u64, BlockError> {
+        let mut reply = [0u8; 8];
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+        self.packet(&packet, &mut reply)?;
+        if u32::from_be_bytes(reply[4..8].try_into().unwrap()) as usize != CD_SECTOR_SIZE {
+            return Err(BlockError::Io("sectors other than 2 KiB"));
+        }
+        Ok(u32::from_be_bytes(reply[..4].try_into().unwrap()) as u64 + 1)
+    }
+
+    /// Send the drive SCSI command `packet` and read its answer into
+    /// `buf`. Each round, the drive says in LBA mid and high how many bytes
+    /// it has up; the interrupt after the last says it is done.
+    fn packet(&self, packet: &[u8; 12], buf: &mut [u8]) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let deadline = time::uptime() + TIMEOUT;
+        channel.select(self.drive);
+        // PIO, and the most bytes a round may move.
+        let limit = buf.len().min(0xFFFE);
+        channel.write(FEATURES, 0);
+        channel.write(LBA_MID, limit as u8);
+        channel.write(LBA_HIGH, (limit >> 8) as u8);
+        channel.write(COMMAND, CMD_PACKET);
+        channel.wait_drq(deadline)?;
+        channel.fired.store(false, Ordering::Release);
+        let mut data = Port::<u16>::new(channel.base + DATA);
+        for pair in packet.chunks(2) {
+            unsafe { data.write(u16::from_le_bytes([pair[0], pair[1]])) };
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            if channel.wait_sector(deadline)? & STATUS_DRQ == 0 {
+                return Err(BlockError::Io("drive has no data"));
+            }
+            let count = channel.read(LBA_MID) as usize | (channel.read(LBA_HIGH) as usize) << 8;
+            if count == 0 || done + count > buf.len() {
+                return Err(BlockError::Io("drive sent more than asked for"));
+            }
+            for pair in buf[done..done + count].chunks_mut(2) {
+                let word = unsafe { data.read() }.to_le_bytes();
+                pair.copy_from_slice(&word[..pair.len()]);
+            }
+            done += count;
+        }
+        channel.wait_sector(deadline).map(|_| ())
+    }
+}
+
+impl BlockDevice for AtapiDrive {
+    fn block_size(&self) -> usize {
+        CD_SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let _guard = self.channel.lock.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_CD_SECTORS * CD_SECTOR_SIZE).enumerate() {
+            let lba = start + (i * MAX_CD_SECTORS) as u64;
+            let mut packet = [0u8; 12];
+            packet[0] = SCSI_READ_10;
+            packet[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+            packet[7..9].copy_from_slice(&((chunk.len() / CD_SECTOR_SIZE) as u16).to_be_bytes());
+            self.packet(&packet, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, _start: u64, _data: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+
+    /// Nothing is ever written.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        let position = if self.drive & 0x10 == 0 { "master" } else { "slave" };
+        let irq = self.channel.irq.get().map_or(String::from("polled"), |line| format!("irq {}", line));
+        let disc = if self.sectors == 0 { ", no disc" } else { "" };
+        format!("ATAPI {} ({} at {:#x}, {}){}", self.model, position, self.channel.base, irq, disc)
+    }
+}
+
+/// Register the disks on the legacy IDE channels, as `hd0`, `hd1`, ...,
+/// and the CD drives, as `cd0`, ..., if there is an IDE controller using
+/// them: on the `pc` machine, not on q35, whose disks are on AHCI.
+pub fn probe() {
+    let Some(ide) = pci::devices().iter().find(|d| d.class == 0x01 && d.subclass == 0x01) else { return };
+    for (i, &(base, control, line)) in CHANNELS.iter().enumerate() {
+        // Programming interface bits 0 and 2: that channel is in native
+        // mode, at ports from its BARs, which we don't do.
+        if ide.prog_if & (1 << (2 * i)) != 0 {
+            serial_println!("block: IDE channel {} at {} is in native mode, skipped", i, ide.addr);
+            continue;
+        }
+        let channel = Arc::new(Channel {
+            base,
+            control,
+            irq: Once::new(),
+            lock: Mutex::new(()),
+            fired: AtomicBool::new(false),
+            done: WaitQueue::new(),
+            interrupts: AtomicU64::new(0),
+        });
+        // Identify by polling, then take the line if there is anything on it.
+        channel.set_interrupts(false);
+        let mut disks = Vec::new();
+        let mut drives = Vec::new();
+        for slave in [false, true] {
+            match AtaDisk::identify(&channel, slave) {
+                Some(disk) => disks.push(disk),
+                None => drives.extend(AtapiDrive::identify(&channel, slave)),
+            }
+        }
+        if disks.is_empty() && drives.is_empty() {
+            continue;
+        }
+        let handler = channel.clone();
+        if interrupts::add_line_handler(line, Box::new(move || handler.on_interrupt())) {
+            channel.irq.call_once(|| line);
+            channel.set_interrupts(true);
+        }
+        for disk in disks {
+            let disk = Arc::new(disk);
+            DISKS.write().push(disk.clone());
+            register(&next_name("hd"), disk);
+        }
+        for drive in drives {
+            let drive = Arc::new(drive);
+            DRIVES.write().push(drive.clone());
+            register(&next_name("cd"), drive);
+        }
+    }
+}
+
+/// On each ATA disk: a read of more sectors than one command moves
+/// matches the same sectors read alone, the end of the disk is enforced,
+/// a write to the last sector reads back (and is undone), and with an IRQ
+/// line, commands raised interrupts. On each CD drive with a disc: the
+/// same long read, the end, and writes refused. True with no ATA disks.
+pub fn self_test() -> bool {
+    let cds = DRIVES.read().iter().all(|cd| {
+        let sectors = (MAX_CD_SECTORS + 2).min(cd.sectors as usize);
+        if sectors == 0 {
+            return true;
+        }
+        let mut long = vec![0u8; sectors * CD_SECTOR_SIZE];
+        let mut one = [0u8; CD_SECTOR_SIZE];
+        cd.read_blocks(0, &mut long).is_ok()
+            && cd.read_blocks(sectors as u64 - 1, &mut one).is_ok()
+            && long[long.len() - CD_SECTOR_SIZE..] == one[..]
+            && cd.read_blocks(cd.sectors, &mut one) == Err(BlockError::OutOfRange)
+            && cd.write_blocks(0, &one) == Err(BlockError::ReadOnly)
+    });
+    cds && DISKS.read().iter().all(|disk| {
+        let before = disk.interrupts();
+        let sectors = (MAX_SECTORS + 8).min(disk.sectors as usize);
+        if sectors == 0 {
+            return true;
+        }
+        let mut long = vec![0u8; sectors * SECTOR_SIZE];
+        let mut one = [0u8; SECTOR_SIZE];
+        let mut ok = disk.read_blocks(0, &mut long).is_ok()
+            && disk.read_blocks(sectors as u64 - 1, &mut one).is_ok()
+            && long[long.len() - SECTOR_SIZE..] == one[..]
+            && disk.read_blocks(disk.sectors, &mut one) == Err(BlockError::OutOfRange);
+
+        let last = disk.sectors - 1;
+        let mut saved = [0u8; SECTOR_SIZE];
+        let pattern: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 11 + 3) as u8).collect();
+        ok &= disk.read_blocks(last, &mut saved).is_ok()
+            && disk.write_blocks(last, &pattern).is_ok()
+            && disk.flush().is_ok()
+            && disk.read_blocks(last, &mut one).is_ok()
+            && one[..] == pattern[..]
+            && disk.write_blocks(last, &saved).is_ok()
+            && disk.flush().is_ok();
+        ok && (disk.channel.irq.get().is_none() || disk.interrupts() > before)
+    })
+}
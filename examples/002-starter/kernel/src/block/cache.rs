@@ -0,0 +1,353 @@
+//! The block cache: recently used blocks of a device, kept in memory, so a
+//! filesystem reading its FAT or a directory again and again goes to the
+//! disk once. PIO disks move a sector per interrupt; from the cache it is a
+//! copy.
+//!
+//! A `CachedDevice` wraps a device and is one itself. Reads fill the cache;
+//! writes only change it, marking blocks dirty, and reach the device when
+//! they are synced: by `flush`, by the `bdflush` thread every few seconds,
+//! or when the block is evicted. When the cache is full the least recently
+//! used block goes. Eviction writes a dirty block early, before the `flush`
+//! that would have; so a filesystem can count on blocks written between two
+//! flushes reaching the disk after the first and by the second, not on
+//! their order among themselves.
+//!
+//! Filesystems mount through `get`, which gives every user of a device the
+//! same cache. The device itself, as `block::get` gives it, is uncached:
+//! reading it directly can miss what the cache holds.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::request::{self, Request};
+use super::{check_range, BlockDevice, BlockError, RamDisk};
+use crate::sync::Mutex;
+use crate::{serial_println, thread, time};
+
+/// Bytes each device may cache.
+const CACHE_BYTES: usize = 512 * 1024;
+/// How long a dirty block may wait for the `bdflush` thread.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// When it was last used, its key in `Blocks::lru`.
+    used: u64,
+}
+
+struct Blocks {
+    entries: BTreeMap<u64, Entry>,
+    /// Block numbers by when they were last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    /// Counts uses, for `Entry::used`.
+    clock: u64,
+}
+
+impl Blocks {
+    /// Mark `block`, which is cached, as just used.
+    fn touch(&mut self, block: u64) {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&block).expect("a cached block");
+        self.lru.remove(&entry.used);
+        entry.used = self.clock;
+        self.lru.insert(self.clock, block);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty blocks written to the device.
+    pub writebacks: u64,
+    pub evictions: u64,
+    /// Blocks cached now, and how many of them are dirty.
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice>,
+    /// Blocks it may hold.
+    capacity: usize,
+    blocks: Mutex<Blocks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writebacks: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CachedDevice {
+    /// A cache of up to `capacity` blocks (at least one) of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> CachedDevice {
+        CachedDevice {
+            device,
+            capacity: capacity.max(1),
+            blocks: Mutex::new(Blocks { entries: BTreeMap::new(), lru: BTreeMap::new(), clock: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            writebacks: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Cache `data` as `block`, making room first if it is full. Write-back
+    /// of the evicted block can fail, and then nothing changes.
+    fn insert(&self, blocks: &mut Blocks, block: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        if let Some(entry) = blocks.entries.get_mut(&block) {
+            entry.data.copy_from_slice(data);
+            entry.dirty |= dirty;
+            blocks.touch(block);
+            return Ok(());
+        }
+        if blocks.entries.len() >= self.capacity {
+            let (&used, &victim) = blocks.lru.first_key_value().expect("a full cache");
+            let entry = &blocks.entries[&victim];
+            if entry.dirty {
+                self.device.write_blocks(victim, &entry.data)?;
+                self.writebacks.fetch_add(1, Ordering::Relaxed);
+            }
+            blocks.lru.remove(&used);
+            blocks.entries.remove(&victim);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        blocks.entries.insert(block, Entry { data: data.into(), dirty, used: 0 });
+        blocks.touch(block);
+        Ok(())
+    }
+
+    /// Write every dirty block to the device, runs of neighbours at once,
+    /// and all the runs submitted before waiting for any. A run that fails
+    /// stays dirty; the first failure is returned.
+    fn write_back(&self, blocks: &mut Blocks) -> Result<(), BlockError> {
+        let dirty: Vec<u64> = blocks.entries.iter().filter(|(_, entry)| entry.dirty).map(|(&block, _)| block).collect();
+        let mut runs = Vec::new();
+        let mut at = 0;
+        while at < dirty.len() {
+            let run = dirty[at..].iter().enumerate().take_while(|&(i, &block)| block == dirty[at] + i as u64).count();
+            let data: Vec<u8> = dirty[at..at + run].iter().flat_map(|block| blocks.entries[block].data.iter().copied()).collect();
+            runs.push((&dirty[at..at + run], data));
+            at += run;
+        }
+        let requests: Vec<Request> = runs.iter().map(|(run, data)| Request::Write { start: run[0], data }).collect();
+        let mut result = Ok(());
+        for ((run, _), written) in runs.iter().zip(request::submit_all(&*self.device, &requests)) {
+            if let Err(err) = written {
+                result = result.and(Err(err));
+                continue;
+            }
+            for block in run.iter() {
+                blocks.entries.get_mut(block).expect("a cached block").dirty = false;
+            }
+            self.writebacks.fetch_add(run.len() as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn stats(&self) -> Stats {
+        let blocks = self.blocks.lock();
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            writebacks: self.writebacks.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            cached: blocks.entries.len(),
+            dirty: blocks.entries.values().filter(|entry| entry.dirty).count(),
+        }
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    /// Cached blocks are copied; each run of missing ones is read from the
+    /// device in one go, and cached.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let size = self.block_size();
+        let mut blocks = self.blocks.lock();
+        let count = buf.len() / size;
+        let mut i = 0;
+        while i < count {
+            let block = start + i as u64;
+            if blocks.entries.contains_key(&block) {
+                buf[i * size..(i + 1) * size].copy_from_slice(&blocks.entries[&block].data);
+                blocks.touch(block);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                i += 1;
+                continue;
+            }
+            let run = (i..count).take_while(|&j| !blocks.entries.contains_key(&(start + j as u64))).count();
+            let span = &mut buf[i * size..(i + run) * size];
+            self.device.read_blocks(block, span)?;
+            for (j, data) in span.chunks(size).enumerate() {
+                self.insert(&mut blocks, block + j as u64, data, false)?;
+            }
+            self.misses.fetch_add(run as u64, Ordering::Relaxed);
+            i += run;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, data.len())?;
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut blocks = self.blocks.lock();
+        for (i, data) in data.chunks(self.block_size()).enumerate() {
+            self.insert(&mut blocks, start + i as u64, data, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back(&mut self.blocks.lock())?;
+        self.device.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn describe(&self) -> String {
+        alloc::format!("{}, cached", self.device.describe())
+    }
+}
+
+/// The caches made so far, by device name.
+static CACHES: Mutex<Vec<(String, Arc<CachedDevice>)>> = Mutex::new(Vec::new());
+
+/// Device `name` behind its cache, made on first use.
+pub fn get(name: &str) -> Option<Arc<CachedDevice>> {
+    let mut caches = CACHES.lock();
+    if let Some((_, cache)) = caches.iter().find(|(n, _)| n == name) {
+        return Some(cache.clone());
+    }
+    let device = super::get(name)?;
+    let capacity = CACHE_BYTES / device.block_size();
+    let cache = Arc::new(CachedDevice::new(device, capacity));
+    caches.push((String::from(name), cache.clone()));
+    Some(cache)
+}
+
+/// Write every cache's dirty blocks to its device.
+pub fn sync_all() {
+    let caches: Vec<_> = CACHES.lock().clone();
+    for (name, cache) in caches {
+        if let Err(err) = cache.flush() {
+            serial_println!("cache: syncing {}: {}", name, err);
+        }
+    }
+}
+
+fn bdflush() {
+    loop {
+        time::sleep(SYNC_INTERVAL);
+        sync_all();
+    }
+}
+
+/// Start the thread that syncs the caches every `SYNC_INTERVAL`. Needs
+/// `thread::init`.
+pub fn init() {
+    if thread::Builder::new().name("bdflush").spawn(bdflush).is_none() {
+        serial_println!("cache: cannot start the bdflush thread");
+    }
+}
+
+pub fn list() {
+    let caches = CACHES.lock();
+    if caches.is_empty() {
+        return serial_println!("cache: nothing cached");
+    }
+    serial_println!("  name       hits   misses  hit%  written  evicted  cached  dirty");
+    for (name, cache) in caches.iter() {
+        let stats = cache.stats();
+        let lookups = stats.hits + stats.misses;
+        serial_println!(
+            "  {:<6} {:>8} {:>8}  {:>3}%  {:>7}  {:>7}  {:>6}  {:>5}",
+            name,
+            stats.hits,
+            stats.misses,
+            (stats.hits * 100).checked_div(lookups).unwrap_or(0),
+            stats.writebacks,
+            stats.evictions,
+            stats.cached,
+            stats.dirty
+        );
+    }
+}
+
+/// Over a RAM disk, four blocks of cache: a read misses then hits; writes
+/// stay in the cache until a flush writes them back, neighbours at once; a
+/// full cache evicts the least recently used block, writing it first if it
+/// is dirty; a read across cached and missing blocks reads only the missing.
+pub fn self_test() -> bool {
+    let Some(disk) = RamDisk::new(512, 16) else { return false };
+    let disk = Arc::new(disk);
+    let cache = CachedDevice::new(disk.clone(), 4);
+    let block = |n: u8| vec![n; 512];
+    let on_disk = |n: u64| {
+        let mut buf = [0u8; 512];
+        disk.read_blocks(n, &mut buf).map(|()| buf[0])
+    };
+    let mut buf = vec![0u8; 512];
+
+    let mut ok = disk.write_blocks(0, &block(9)).is_ok()
+        && cache.read_blocks(0, &mut buf).is_ok()
+        && cache.read_blocks(0, &mut buf).is_ok()
+        && buf == block(9)
+        && cache.stats() == Stats { hits: 1, misses: 1, cached: 1, ..Stats::default() };
+
+    let mut two = block(1);
+    two.extend(block(2));
+    ok &= cache.write_blocks(1, &two).is_ok()
+        && on_disk(1) == Ok(0)
+        && cache.read_blocks(2, &mut buf).is_ok()
+        && buf == block(2)
+        && cache.stats().dirty == 2
+        && cache.flush().is_ok()
+        && on_disk(1) == Ok(1)
+        && on_disk(2) == Ok(2)
+        && cache.stats().writebacks == 2
+        && cache.stats().dirty == 0;
+
+    // Cached, oldest first: 0, 1, 2. Dirty 3 and use 0: 4 evicts 1, 5
+    // evicts 2, and 6 evicts 3, writing it. 0, used, stays.
+    ok &= cache.write_blocks(3, &block(3)).is_ok()
+        && cache.read_blocks(0, &mut buf).is_ok()
+        && cache.write_blocks(4, &block(4)).is_ok()
+        && cache.read_blocks(5, &mut buf).is_ok()
+        && cache.stats().evictions == 2
+        && on_disk(3) == Ok(0)
+        && cache.read_blocks(6, &mut buf).is_ok()
+        && on_disk(3) == Ok(3)
+        && cache.stats().writebacks == 3;
+    let misses = cache.stats().misses;
+    ok &= cache.read_blocks(0, &mut buf).is_ok() && cache.stats().misses == misses;
+
+    // 4, 5, 6 and 0 are cached: reading 5..8 misses only 7, which evicts
+    // 4, dirty.
+    let mut span = vec![0u8; 3 * 512];
+    let hits = cache.stats().hits;
+    ok &= cache.read_blocks(5, &mut span).is_ok()
+        && cache.stats().misses == misses + 1
+        && cache.stats().hits == hits + 2
+        && on_disk(4) == Ok(4)
+        && cache.stats().writebacks == 4;
+    ok && cache.read_blocks(15, &mut span) == Err(BlockError::OutOfRange)
+}
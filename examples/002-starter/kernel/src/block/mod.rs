@@ -0,0 +1,251 @@
+//! Block devices: storage read and written in whole blocks, by number. A
+//! filesystem only sees the `BlockDevice` trait, so it runs the same over a
+//! RAM disk (`ramdisk`) as over a disk controller's driver.
+//!
+//! Devices are registered by name (`ram0`, `vd0`, `hd0`, `sd0`, `nvme0`...)
+//! when they are found, and shared: each is an `Arc<dyn BlockDevice>`,
+//! locked inside as it needs. Transfers are whole blocks, from a buffer whose
+//! length is a multiple of the block size; writes may sit in a cache until
+//! `flush`. Each partition of a disk is registered as a device too
+//! (`vd0p1`, ...), see `partition`. Filesystems go through a `cache` of
+//! recently used blocks.
+//!
+//! `read_blocks` and `write_blocks` return when the transfer is done. A
+//! driver that can have several transfers out at once also takes requests
+//! through `submit`, which returns at once; see `request`.
+
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod nvme;
+pub mod partition;
+pub mod ramdisk;
+pub mod request;
+pub mod virtio;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::serial_println;
+use crate::sync::RwLock;
+
+pub use ramdisk::RamDisk;
+pub use request::{Pending, Request};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The buffer isn't a whole number of blocks.
+    Unaligned,
+    /// Blocks past the end of the device.
+    OutOfRange,
+    ReadOnly,
+    /// The device said no; what it said.
+    Io(&'static str),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::Unaligned => f.write_str("buffer is not a whole number of blocks"),
+            BlockError::OutOfRange => f.write_str("past the end of the device"),
+            BlockError::ReadOnly => f.write_str("device is read-only"),
+            BlockError::Io(what) => write!(f, "I/O error: {}", what),
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Bytes in a block: 512, or a larger power of two.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks from `start` on into `buf`, as many as it holds.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `data` to the blocks from `start` on.
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError>;
+
+    /// Make every write so far reach the medium.
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// Start `request` and return without waiting for it. Devices that
+    /// can't have it out in the background do it here, and return it done.
+    fn submit(&self, request: Request) -> Pending {
+        Pending::ready(match request {
+            Request::Read { start, len } => {
+                let mut buf = vec![0u8; len];
+                self.read_blocks(start, &mut buf).map(|()| buf)
+            }
+            Request::Write { start, data } => self.write_blocks(start, data).map(|()| Vec::new()),
+            Request::Flush => self.flush().map(|()| Vec::new()),
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// What it is, for listings: "RAM disk", a controller and model, ...
+    fn describe(&self) -> String;
+}
+
+/// How many blocks of `device` a transfer of `len` bytes from block `start`
+/// covers, if it is whole blocks within the device. For drivers to check
+/// requests with.
+pub fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::Unaligned);
+    }
+    let count = (len / device.block_size()) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: RwLock<Vec<(String, Arc<dyn BlockDevice>)>> = RwLock::new(Vec::new());
+
+/// Add `device` as `name`. False if the name is taken.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> bool {
+    let mut devices = DEVICES.write();
+    if devices.iter().any(|(taken, _)| taken == name) {
+        return false;
+    }
+    serial_println!(
+        "block: {}: {}, {} blocks of {} bytes ({} KiB)",
+        name,
+        device.describe(),
+        device.block_count(),
+        device.block_size(),
+        device.block_count() * device.block_size() as u64 / 1024
+    );
+    devices.push((String::from(name), device));
+    true
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.read().iter().find(|(n, _)| n == name).map(|(_, device)| device.clone())
+}
+
+/// The names of every device, in the order they were registered.
+pub fn names() -> Vec<String> {
+    DEVICES.read().iter().map(|(name, _)| name.clone()).collect()
+}
+
+/// The first free name `prefix0`, `prefix1`, ...
+pub fn next_name(prefix: &str) -> String {
+    let devices = DEVICES.read();
+    (0..)
+        .map(|i| alloc::format!("{}{}", prefix, i))
+        .find(|name| devices.iter().all(|(taken, _)| taken != name))
+        .expect("a free name")
+}
+
+/// Register the RAM disk holding a copy of the initrd archive, if there is
+/// one.
+pub fn init() {
+    if let Some(archive) = crate::initrd::archive() {
+        match RamDisk::from_image(archive, ramdisk::BLOCK_SIZE) {
+            Some(disk) => {
+                register(&next_name("ram"), Arc::new(disk));
+            }
+            None => serial_println!("block: no memory for a RAM disk of the initrd"),
+        }
+    }
+}
+
+/// Look for disks on the buses, once PCI is scanned and threads can wait
+/// for interrupts, then for partitions on every device found so far.
+pub fn probe() {
+    virtio::probe();
+    ata::probe();
+    ahci::probe();
+    nvme::probe();
+    for name in names() {
+        partition::scan(&name);
+    }
+}
+
+pub fn list() {
+    let devices = DEVICES.read();
+    if devices.is_empty() {
+        return serial_println!("block: no devices");
+    }
+    serial_println!("  name     blocks  size  KiB      device");
+    for (name, device) in devices.iter() {
+        serial_println!(
+            "  {:<6} {:>8}  {:>4}  {:>7}  {}{}",
+            name,
+            device.block_count(),
+            device.block_size(),
+            device.block_count() * device.block_size() as u64 / 1024,
+            device.describe(),
+            if device.read_only() { " (read-only)" } else { "" }
+        );
+    }
+}
+
+/// Print block `block` of device `name` as hex and ASCII.
+pub fn dump(name: &str, block: u64) {
+    let Some(device) = get(name) else {
+        return serial_println!("block: no device {}", name);
+    };
+    let mut buf = vec![0u8; device.block_size()];
+    if let Err(err) = device.read_blocks(block, &mut buf) {
+        return serial_println!("block: {} block {}: {}", name, block, err);
+    }
+    for (i, line) in buf.chunks(16).enumerate() {
+        let mut text = alloc::format!("  {:04x} ", i * 16);
+        for byte in line {
+            text.push_str(&alloc::format!(" {:02x}", byte));
+        }
+        text.push_str("  ");
+        text.extend(line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        serial_println!("{}", text);
+    }
+}
+
+/// What every device must do, checked on a RAM disk: whole blocks only,
+/// within the device, reads see earlier writes, and a read-only device
+/// refuses writes. A disk seeded from an image starts with the image,
+/// padded with zeros. Then requests', partition tables', the cache's, and
+/// the drivers' own tests, on the disks they found.
+pub fn self_test() -> bool {
+    let Some(disk) = RamDisk::new(512, 8) else { return false };
+    let device: &dyn BlockDevice = &disk;
+    let mut buf = [0u8; 1024];
+    let mut ok = device.read_blocks(0, &mut buf[..100]) == Err(BlockError::Unaligned)
+        && device.read_blocks(7, &mut buf) == Err(BlockError::OutOfRange)
+        && device.read_blocks(u64::MAX, &mut buf) == Err(BlockError::OutOfRange)
+        && device.read_blocks(6, &mut buf).is_ok()
+        && buf.iter().all(|&b| b == 0);
+
+    let pattern: Vec<u8> = (0..1024).map(|i| (i * 7) as u8).collect();
+    ok &= device.write_blocks(3, &pattern).is_ok() && device.flush().is_ok();
+    ok &= device.read_blocks(3, &mut buf).is_ok() && buf[..] == pattern[..];
+    let mut one = [0u8; 512];
+    ok &= device.read_blocks(4, &mut one).is_ok() && one[..] == pattern[512..];
+    ok &= device.read_blocks(2, &mut one).is_ok() && one.iter().all(|&b| b == 0);
+
+    let Some(seeded) = RamDisk::from_image(b"seed", 512) else { return false };
+    ok &= seeded.block_count() == 1 && seeded.read_blocks(0, &mut one).is_ok() && &one[..4] == b"seed" && one[4..].iter().all(|&b| b == 0);
+    seeded.set_read_only(true);
+    ok &= seeded.write_blocks(0, &one) == Err(BlockError::ReadOnly);
+
+    ok &= check_range(device, 8, 0) == Ok(0) && check_range(device, 0, 4096) == Ok(8);
+
+    // ram0, if there is an initrd, starts as a copy of it.
+    ok &= match (crate::initrd::archive(), get("ram0")) {
+        (Some(archive), Some(ram0)) => {
+            let mut first = vec![0u8; ram0.block_size()];
+            let n = first.len().min(archive.len());
+            ram0.read_blocks(0, &mut first).is_ok() && first[..n] == archive[..n]
+        }
+        (archive, _) => archive.is_none(),
+    };
+    ok && request::self_test() && partition::self_test() && cache::self_test() && virtio::self_test() && ata::self_test() && ahci::self_test() && nvme::self_test()
+}
@@ -0,0 +1,469 @@
+//! NVMe: the interface of the SSD in most laptops, and the one QEMU
+//! attaches with `-device nvme`. It was designed for flash, not to look
+//! like a disk from the eighties: no task file, no channels, just queues.
+//!
+//! The driver puts 64-byte commands in a submission queue in RAM and
+//! writes the new tail to the queue's doorbell register; the controller
+//! puts a 16-byte completion for each in the paired completion queue, with
+//! a phase bit that flips every time round the ring (so a new entry is one
+//! whose phase differs from what was there before), and interrupts. Queue
+//! pair 0 is the admin queue, for commands about the controller: identify,
+//! create I/O queues. We create one I/O pair for reads and writes.
+//!
+//! Its interrupts are MSI-X messages: a vector of its own, straight to the
+//! local APIC, no line to share. Data goes by DMA to pages named in the
+//! command (PRPs): the first page in the command, the rest in a list page.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use super::{check_range, next_name, register, BlockDevice, BlockError};
+use crate::dma::{self, DmaBuffer};
+use crate::memory::mmio::{map_mmio, Mmio};
+use crate::pci::{self, Bar};
+use crate::smp::lapic;
+use crate::sync::{Mutex, RwLock, WaitQueue};
+use crate::{interrupts, serial_println, time};
+
+const PAGE_SIZE: usize = 4096;
+/// The most one command moves: the bounce buffer.
+const MAX_TRANSFER: usize = 64 * 1024;
+/// Entries in each queue; one command is in flight at a time anyway.
+const QUEUE_SIZE: u16 = 16;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Controller registers.
+const CAP: usize = 0x00;
+const VS: usize = 0x08;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1C;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// Submission and completion entries of 2^6 and 2^4 bytes.
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+/// Admin commands.
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+/// What to identify (CDW10).
+const CNS_NAMESPACE: u32 = 0;
+const CNS_CONTROLLER: u32 = 1;
+
+/// I/O commands.
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// The namespace we use: the first.
+const NAMESPACE: u32 = 1;
+
+/// A submission and completion queue pair.
+struct Queue {
+    id: u16,
+    sq: DmaBuffer,
+    cq: DmaBuffer,
+    tail: u16,
+    head: u16,
+    /// The phase a new completion at `head` has.
+    phase: bool,
+    next_id: u16,
+}
+
+impl Queue {
+    fn new(id: u16) -> Result<Queue, &'static str> {
+        let sq = dma::alloc(64 * QUEUE_SIZE as usize, PAGE_SIZE).map_err(|_| "no memory for a queue")?;
+        let cq = dma::alloc(16 * QUEUE_SIZE as usize, PAGE_SIZE).map_err(|_| "no memory for a queue")?;
+        Ok(Queue { id, sq, cq, tail: 0, head: 0, phase: true, next_id: 0 })
+    }
+
+    /// Whether the completion at `head` is new.
+    fn has_completion(&self) -> bool {
+        let status = unsafe { (self.cq.virt() + 16 * self.head as u64 + 14).as_ptr::<u16>().read_volatile() };
+        (status & 1 != 0) == self.phase
+    }
+}
+
+/// The controller's registers.
+struct Regs {
+    mmio: Mmio,
+    /// Bytes between doorbells.
+    stride: usize,
+}
+
+// As for `virtio::Transport`: mapped in the shared kernel half, touched a
+// register at a time.
+unsafe impl Send for Regs {}
+unsafe impl Sync for Regs {}
+
+impl Regs {
+    fn read(&self, offset: usize) -> u32 {
+        self.mmio.register::<u32>(offset).get()
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.mmio.register::<u32>(offset).set(value)
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    fn sq_doorbell(&self, queue: &Queue) {
+        self.write(DOORBELLS + 2 * queue.id as usize * self.stride, queue.tail as u32);
+    }
+
+    fn cq_doorbell(&self, queue: &Queue) {
+        self.write(DOORBELLS + (2 * queue.id as usize + 1) * self.stride, queue.head as u32);
+    }
+}
+
+pub struct NvmeDisk {
+    device: pci::Device,
+    regs: Regs,
+    admin: Mutex<Queue>,
+    io: Mutex<IoState>,
+    blocks: u64,
+    block_size: usize,
+    max_transfer: usize,
+    model: String,
+    /// Its MSI-X vector; without one, completions are polled.
+    vector: Once<u8>,
+    done: WaitQueue,
+    interrupts: AtomicU64,
+}
+
+struct IoState {
+    queue: Queue,
+    data: DmaBuffer,
+    /// The PRP list for the bounce buffer: the address of each page of it
+    /// but the first, made once.
+    prp_list: DmaBuffer,
+}
+
+/// Every NVMe disk found, for `self_test`.
+static DISKS: RwLock<Vec<Arc<NvmeDisk>>> = RwLock::new(Vec::new());
+
+fn poll(deadline: Duration, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if time::uptime() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// A command: opcode, then namespace, data pointers and command dwords
+/// 10 to 15. The command ID is filled in when it is submitted.
+struct Command {
+    opcode: u8,
+    namespace: u32,
+    prp1: u64,
+    prp2: u64,
+    dwords: [u32; 6],
+}
+
+impl Command {
+    fn new(opcode: u8) -> Command {
+        Command { opcode, namespace: 0, prp1: 0, prp2: 0, dwords: [0; 6] }
+    }
+}
+
+impl NvmeDisk {
+    /// Put `command` on `queue`, ring its doorbell and wait for its
+    /// completion; its result dword, or the error it reported.
+    fn run(&self, queue: &mut Queue, command: Command, wait: bool) -> Result<u32, BlockError> {
+        let id = queue.next_id;
+        queue.next_id = queue.next_id.wrapping_add(1);
+        let mut entry = [0u32; 16];
+        entry[0] = command.opcode as u32 | (id as u32) << 16;
+        entry[1] = command.namespace;
+        entry[6] = command.prp1 as u32;
+        entry[7] = (command.prp1 >> 32) as u32;
+        entry[8] = command.prp2 as u32;
+        entry[9] = (command.prp2 >> 32) as u32;
+        entry[10..16].copy_from_slice(&command.dwords);
+        let slot = (queue.sq.virt() + 64 * queue.tail as u64).as_mut_ptr::<u32>();
+        for (i, dword) in entry.iter().enumerate() {
+            unsafe { slot.add(i).write_volatile(*dword) };
+        }
+        queue.tail = (queue.tail + 1) % QUEUE_SIZE;
+        fence(Ordering::SeqCst);
+        self.regs.sq_doorbell(queue);
+
+        let deadline = time::uptime() + TIMEOUT;
+        let arrived = if wait && self.vector.get().is_some() {
+            self.done.wait_until_deadline(|| queue.has_completion(), deadline)
+        } else {
+            poll(deadline, || queue.has_completion())
+        };
+        if !arrived {
+            return Err(BlockError::Io("controller timed out"));
+        }
+        fence(Ordering::SeqCst);
+        let completion = (queue.cq.virt() + 16 * queue.head as u64).as_ptr::<u32>();
+        let (result, status) = unsafe { (completion.read_volatile(), completion.add(3).read_volatile()) };
+        queue.head = (queue.head + 1) % QUEUE_SIZE;
+        if queue.head == 0 {
+            queue.phase = !queue.phase;
+        }
+        self.regs.cq_doorbell(queue);
+        if (status & 0xFFFF) as u16 != id {
+            return Err(BlockError::Io("completion for another command"));
+        }
+        // Status code and type, above the phase bit.
+        if (status >> 17) & 0x7FF != 0 {
+            return Err(BlockError::Io("controller reported an error"));
+        }
+        Ok(result)
+    }
+
+    /// Admin command, polled: admin commands only run during setup.
+    fn admin(&self, command: Command) -> Result<u32, BlockError> {
+        let mut admin = self.admin.lock();
+        self.run(&mut admin, command, false)
+    }
+
+    /// Move `len` bytes of the bounce buffer by `opcode` from block `lba` on.
+    fn transfer(&self, io: &mut IoState, opcode: u8, lba: u64, len: usize) -> Result<(), BlockError> {
+        let mut command = Command::new(opcode);
+        command.namespace = NAMESPACE;
+        command.prp1 = io.data.phys().as_u64();
+        command.prp2 = match len.div_ceil(PAGE_SIZE) {
+            0 | 1 => 0,
+            2 => io.data.phys().as_u64() + PAGE_SIZE as u64,
+            _ => io.prp_list.phys().as_u64(),
+        };
+        let blocks = (len / self.block_size) as u32;
+        command.dwords = [lba as u32, (lba >> 32) as u32, blocks - 1, 0, 0, 0];
+        self.run(&mut io.queue, command, true).map(|_| ())
+    }
+
+    fn on_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        self.done.notify_all();
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for NvmeDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let mut io = self.io.lock();
+        for (i, chunk) in buf.chunks_mut(self.max_transfer).enumerate() {
+            let lba = start + (i * self.max_transfer / self.block_size) as u64;
+            self.transfer(&mut io, IO_READ, lba, chunk.len())?;
+            chunk.copy_from_slice(&io.data.as_mut_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, data.len())?;
+        let mut io = self.io.lock();
+        for (i, chunk) in data.chunks(self.max_transfer).enumerate() {
+            let lba = start + (i * self.max_transfer / self.block_size) as u64;
+            io.data.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.transfer(&mut io, IO_WRITE, lba, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut io = self.io.lock();
+        let mut command = Command::new(IO_FLUSH);
+        command.namespace = NAMESPACE;
+        self.run(&mut io.queue, command, true).map(|_| ())
+    }
+
+    fn describe(&self) -> String {
+        let irq = self.vector.get().map_or(String::from("polled"), |vector| format!("MSI-X vector {:#x}", vector));
+        format!("NVMe {} (at {}, namespace {}, {})", self.model, self.device.addr, NAMESPACE, irq)
+    }
+}
+
+/// Reset the controller, give it the admin queue, and enable it again.
+fn enable(regs: &Regs, admin: &Queue, timeout: Duration) -> Result<(), &'static str> {
+    regs.write(CC, regs.read(CC) & !CC_EN);
+    if !poll(time::uptime() + timeout, || regs.read(CSTS) & CSTS_RDY == 0) {
+        return Err("controller won't stop");
+    }
+    let last = QUEUE_SIZE as u32 - 1;
+    regs.write(AQA, last << 16 | last);
+    regs.write_u64(ASQ, admin.sq.phys().as_u64());
+    regs.write_u64(ACQ, admin.cq.phys().as_u64());
+    regs.write(CC, CC_EN | CC_IOSQES | CC_IOCQES);
+    if !poll(time::uptime() + timeout, || regs.read(CSTS) & (CSTS_RDY | CSTS_CFS) != 0) || regs.read(CSTS) & CSTS_CFS != 0 {
+        return Err("controller won't start");
+    }
+    Ok(())
+}
+
+/// Set up one controller: enable it, identify it and namespace 1, create
+/// the I/O queue pair with its MSI-X vector, and register it as `nvme0`...
+fn init_controller(device: &pci::Device) -> Result<(), &'static str> {
+    let Some(Bar::Memory { addr, size, .. }) = device.bar(0) else {
+        return Err("no register BAR");
+    };
+    let mmio = map_mmio(PhysAddr::new(addr), size as usize).map_err(|_| "cannot map registers")?;
+    device.enable();
+    // Admin commands are polled until MSI-X is set up: keep the controller
+    // off the PIC line it might share meanwhile.
+    let command = device.addr.read_u16(pci::COMMAND);
+    device.addr.write_u16(pci::COMMAND, command | pci::COMMAND_INTX_DISABLE);
+    let cap = mmio.register::<u32>(CAP).get() as u64 | (mmio.register::<u32>(CAP + 4).get() as u64) << 32;
+    let regs = Regs { mmio, stride: 4 << ((cap >> 32) & 0xF) };
+    if (cap & 0xFFFF) + 1 < QUEUE_SIZE as u64 {
+        return Err("queues too small");
+    }
+    // CAP.TO: how long enabling may take, in 500 ms units.
+    let timeout = Duration::from_millis(500 * ((cap >> 24) & 0xFF).max(1));
+    let admin = Queue::new(0)?;
+    enable(&regs, &admin, timeout)?;
+    let version = regs.read(VS);
+
+    let identify = dma::alloc(PAGE_SIZE, PAGE_SIZE).map_err(|_| "no memory")?;
+    let data = dma::alloc(MAX_TRANSFER, PAGE_SIZE).map_err(|_| "no memory for a bounce buffer")?;
+    let mut prp_list = dma::alloc(PAGE_SIZE, PAGE_SIZE).map_err(|_| "no memory")?;
+    for (i, entry) in prp_list.as_mut_slice().chunks_mut(8).take(MAX_TRANSFER / PAGE_SIZE - 1).enumerate() {
+        entry.copy_from_slice(&(data.phys().as_u64() + ((i + 1) * PAGE_SIZE) as u64).to_le_bytes());
+    }
+    let mut disk = NvmeDisk {
+        device: *device,
+        regs,
+        admin: Mutex::new(admin),
+        io: Mutex::new(IoState { queue: Queue::new(1)?, data, prp_list }),
+        blocks: 0,
+        block_size: 512,
+        max_transfer: MAX_TRANSFER,
+        model: String::new(),
+        vector: Once::new(),
+        done: WaitQueue::new(),
+        interrupts: AtomicU64::new(0),
+    };
+
+    let ask = |cns: u32, namespace: u32| {
+        let mut command = Command::new(ADMIN_IDENTIFY);
+        command.namespace = namespace;
+        command.prp1 = identify.phys().as_u64();
+        command.dwords[0] = cns;
+        command
+    };
+    disk.admin(ask(CNS_CONTROLLER, 0)).map_err(|_| "identify controller failed")?;
+    let bytes = unsafe { core::slice::from_raw_parts(identify.virt().as_ptr::<u8>(), PAGE_SIZE) };
+    disk.model = String::from(core::str::from_utf8(&bytes[24..64]).unwrap_or("?").trim());
+    // MDTS: the most one command moves, as a power of two of pages.
+    if bytes[77] != 0 {
+        disk.max_transfer = MAX_TRANSFER.min(PAGE_SIZE << bytes[77]);
+    }
+    disk.admin(ask(CNS_NAMESPACE, NAMESPACE)).map_err(|_| "identify namespace failed")?;
+    let bytes = unsafe { core::slice::from_raw_parts(identify.virt().as_ptr::<u8>(), PAGE_SIZE) };
+    disk.blocks = u64::from_le_bytes(bytes[0..8].try_into().expect("8 bytes"));
+    // The LBA format in use: its block size is a power of two.
+    let format = 128 + 4 * (bytes[26] & 0xF) as usize;
+    disk.block_size = 1 << bytes[format + 2];
+    if disk.blocks == 0 {
+        return Err("namespace 1 is empty");
+    }
+    if disk.block_size > disk.max_transfer {
+        return Err("blocks larger than a transfer");
+    }
+
+    let disk = Arc::new(disk);
+    // MSI-X entry 0 for the I/O completion queue.
+    let handler = disk.clone();
+    if let Some(vector) = interrupts::alloc_msi_vector(Box::new(move || handler.on_interrupt())) {
+        match device.route_msix(0, vector, lapic::id()) {
+            Ok(()) => {
+                disk.vector.call_once(|| vector);
+            }
+            Err(err) => serial_println!("block: NVMe at {}: {}, polling", device.addr, err),
+        }
+    }
+    let (cq, sq) = {
+        let io = disk.io.lock();
+        (io.queue.cq.phys().as_u64(), io.queue.sq.phys().as_u64())
+    };
+    let size = (QUEUE_SIZE as u32 - 1) << 16;
+    let mut create_cq = Command::new(ADMIN_CREATE_CQ);
+    create_cq.prp1 = cq;
+    // Physically contiguous, and interrupts on (entry 0) if we have them.
+    create_cq.dwords = [size | 1, 1 | (disk.vector.get().is_some() as u32) << 1, 0, 0, 0, 0];
+    disk.admin(create_cq).map_err(|_| "creating the completion queue failed")?;
+    let mut create_sq = Command::new(ADMIN_CREATE_SQ);
+    create_sq.prp1 = sq;
+    create_sq.dwords = [size | 1, 1 << 16 | 1, 0, 0, 0, 0];
+    disk.admin(create_sq).map_err(|_| "creating the submission queue failed")?;
+
+    serial_println!("block: NVMe {}.{} at {}", version >> 16, (version >> 8) & 0xFF, device.addr);
+    DISKS.write().push(disk.clone());
+    register(&next_name("nvme"), disk);
+    Ok(())
+}
+
+/// Register every NVMe controller's first namespace.
+pub fn probe() {
+    for device in pci::devices().iter().filter(|d| d.class == 0x01 && d.subclass == 0x08 && d.prog_if == 0x02) {
+        if let Err(err) = init_controller(device) {
+            serial_println!("block: NVMe at {}: {}", device.addr, err);
+        }
+    }
+}
+
+/// On each NVMe disk: a read longer than one command matches the same
+/// blocks read alone, the end of the disk is enforced, a write to the last
+/// block reads back (and is undone), and with MSI-X, commands raised
+/// interrupts. True with no NVMe disks.
+pub fn self_test() -> bool {
+    DISKS.read().iter().all(|disk| {
+        let before = disk.interrupts();
+        let size = disk.block_size;
+        let blocks = (disk.max_transfer / size + 2).min(disk.blocks as usize);
+        let mut long = vec![0u8; blocks * size];
+        let mut one = vec![0u8; size];
+        let mut ok = disk.read_blocks(0, &mut long).is_ok()
+            && disk.read_blocks(blocks as u64 - 1, &mut one).is_ok()
+            && long[long.len() - size..] == one[..]
+            && disk.read_blocks(disk.blocks, &mut one) == Err(BlockError::OutOfRange);
+
+        let last = disk.blocks - 1;
+        let mut saved = vec![0u8; size];
+        let pattern: Vec<u8> = (0..size).map(|i| (i * 7 + 9) as u8).collect();
+        ok &= disk.read_blocks(last, &mut saved).is_ok()
+            && disk.write_blocks(last, &pattern).is_ok()
+            && disk.flush().is_ok()
+            && disk.read_blocks(last, &mut one).is_ok()
+            && one == pattern
+            && disk.write_blocks(last, &saved).is_ok()
+            && disk.flush().is_ok();
+        ok && (disk.vector.get().is_none() || disk.interrupts() > before)
+    })
+}
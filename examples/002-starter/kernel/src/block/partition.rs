@@ -0,0 +1,440 @@
+//! Partition tables: how one disk is cut into several. A partition is a
+//! run of blocks with a type saying what is in it; we register each as a
+//! block device of its own (`vd0p1`, `hd0p2`, ...) whose block 0 is the
+//! partition's first, so a filesystem on it never sees the offset.
+//!
+//! Two kinds of table:
+//! - MBR, from the PC's first hard disks: four entries in block 0, after
+//!   the boot code, each a start, a length and a one-byte type. One can be
+//!   an extended partition, holding a chain of boot records of one more
+//!   partition each (numbered from 5).
+//! - GPT, from UEFI: a header in block 1 and an array of entries after it,
+//!   each with a type GUID and a name, both checked by CRC32, and a backup
+//!   copy at the end of the disk. Block 0 still holds an MBR with one entry
+//!   of type 0xEE covering the disk, so old tools see it as full.
+//!
+//! `bootloader`'s BIOS image is an MBR disk with its FAT partition first,
+//! its UEFI image the same as GPT.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{check_range, get, register, BlockDevice, BlockError, Pending, RamDisk, Request};
+use crate::serial_println;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_ENTRIES: usize = 446;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_PROTECTIVE: u8 = 0xEE;
+/// Logical partitions we follow before deciding the chain loops.
+const MAX_LOGICAL: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Largest entry array we read: 128 entries of 128 bytes is usual.
+const GPT_MAX_ENTRIES_LEN: usize = 1024 * 1024;
+
+/// A GPT GUID, by its fields; on disk the first three are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        Guid { data1, data2, data3, data4 }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Guid {
+        Guid {
+            data1: u32::from_le_bytes(bytes[0..4].try_into().expect("4 bytes")),
+            data2: u16::from_le_bytes([bytes[4], bytes[5]]),
+            data3: u16::from_le_bytes([bytes[6], bytes[7]]),
+            data4: bytes[8..16].try_into().expect("8 bytes"),
+        }
+    }
+
+    fn is_nil(&self) -> bool {
+        *self == Guid::new(0, 0, 0, [0; 8])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data4;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-", self.data1, self.data2, self.data3, d[0], d[1])?;
+        d[2..].iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+const GPT_EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+const GPT_BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+const GPT_LINUX: Guid = Guid::new(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+
+/// What a partition says it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl Kind {
+    /// The usual name for the type, if we know it.
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Mbr(0x01) => "FAT12",
+            Kind::Mbr(0x04 | 0x06 | 0x0E) => "FAT16",
+            Kind::Mbr(0x0B | 0x0C) => "FAT32",
+            Kind::Mbr(0x07) => "NTFS/exFAT",
+            Kind::Mbr(0x82) => "Linux swap",
+            Kind::Mbr(0x83) => "Linux",
+            Kind::Mbr(0xEF) => "EFI system",
+            Kind::Gpt(GPT_EFI_SYSTEM) => "EFI system",
+            Kind::Gpt(GPT_BASIC_DATA) => "basic data",
+            Kind::Gpt(GPT_LINUX) => "Linux",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Mbr(id) => write!(f, "{} ({:#04x})", self.name(), id),
+            Kind::Gpt(guid) => write!(f, "{} ({})", self.name(), guid),
+        }
+    }
+}
+
+/// One entry of a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// 1 to 4 for MBR primaries, 5 on for logicals; GPT's from 1.
+    pub number: usize,
+    pub start: u64,
+    pub blocks: u64,
+    pub kind: Kind,
+    /// GPT's name for it; empty on MBR.
+    pub name: String,
+}
+
+/// A disk's partition table.
+pub struct Table {
+    pub scheme: &'static str,
+    pub entries: Vec<Entry>,
+}
+
+fn read_block(device: &dyn BlockDevice, block: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0u8; device.block_size()];
+    device.read_blocks(block, &mut buf)?;
+    Ok(buf)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// CRC-32 as GPT (and zip, and Ethernet) uses it.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+/// The four entries of the boot record in `block`: (type, start, blocks),
+/// start relative to whatever the record's is. None without the signature.
+fn boot_record(block: &[u8]) -> Option<[(u8, u64, u64); 4]> {
+    if u16::from_le_bytes([block[510], block[511]]) != MBR_SIGNATURE {
+        return None;
+    }
+    Some(core::array::from_fn(|i| {
+        let entry = &block[MBR_ENTRIES + 16 * i..MBR_ENTRIES + 16 * (i + 1)];
+        (entry[4], u32_at(entry, 8) as u64, u32_at(entry, 12) as u64)
+    }))
+}
+
+/// The partitions in an MBR's slots, the logical ones in its extended
+/// partition after them.
+fn read_mbr(device: &dyn BlockDevice, primaries: [(u8, u64, u64); 4]) -> Result<Vec<Entry>, BlockError> {
+    let mut entries = Vec::new();
+    let mut extended = None;
+    for (i, &(kind, start, blocks)) in primaries.iter().enumerate() {
+        if kind == 0 || blocks == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&kind) {
+            extended.get_or_insert(start);
+            continue;
+        }
+        entries.push(Entry { number: i + 1, start, blocks, kind: Kind::Mbr(kind), name: String::new() });
+    }
+    // Each extended boot record: a partition, relative to the record, and
+    // the next record, relative to the extended partition.
+    if let Some(base) = extended {
+        let mut record = base;
+        for number in 5..5 + MAX_LOGICAL {
+            let Some([(kind, start, blocks), (_, next, _), ..]) = boot_record(&read_block(device, record)?) else {
+                break;
+            };
+            if kind != 0 && blocks != 0 {
+                entries.push(Entry { number, start: record + start, blocks, kind: Kind::Mbr(kind), name: String::new() });
+            }
+            if next == 0 {
+                break;
+            }
+            record = base + next;
+        }
+    }
+    Ok(entries)
+}
+
+/// The GPT whose header is in block `at`, if it is whole.
+fn read_gpt(device: &dyn BlockDevice, at: u64) -> Result<Option<Vec<Entry>>, BlockError> {
+    let mut header = read_block(device, at)?;
+    let header_len = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(92..=header.len()).contains(&header_len) || u64_at(&header, 24) != at {
+        return Ok(None);
+    }
+    let crc = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_len]) != crc {
+        return Ok(None);
+    }
+    let (array, count, entry_len) = (u64_at(&header, 72), u32_at(&header, 80) as usize, u32_at(&header, 84) as usize);
+    let len = count * entry_len;
+    if entry_len < 128 || len > GPT_MAX_ENTRIES_LEN {
+        return Ok(None);
+    }
+    let size = device.block_size();
+    let mut bytes = vec![0u8; len.div_ceil(size) * size];
+    device.read_blocks(array, &mut bytes)?;
+    if crc32(&bytes[..len]) != u32_at(&header, 88) {
+        return Ok(None);
+    }
+    let mut entries = Vec::new();
+    for (i, entry) in bytes[..len].chunks(entry_len).enumerate() {
+        let kind = Guid::from_bytes(&entry[0..16]);
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if kind.is_nil() || last < first {
+            continue;
+        }
+        let name = char::decode_utf16(entry[56..128].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|&c| c != 0))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        entries.push(Entry { number: i + 1, start: first, blocks: last - first + 1, kind: Kind::Gpt(kind), name });
+    }
+    Ok(Some(entries))
+}
+
+/// `device`'s partition table: GPT if block 0 is a protective MBR and
+/// the header in block 1 or its backup in the last block checks out, else
+/// MBR. None if there is neither.
+pub fn read_table(device: &dyn BlockDevice) -> Result<Option<Table>, BlockError> {
+    if device.block_count() < 2 {
+        return Ok(None);
+    }
+    let Some(primaries) = boot_record(&read_block(device, 0)?) else {
+        return Ok(None);
+    };
+    if primaries.iter().any(|&(kind, _, _)| kind == MBR_PROTECTIVE) {
+        let gpt = match read_gpt(device, 1)? {
+            Some(entries) => Some(entries),
+            None => read_gpt(device, device.block_count() - 1)?,
+        };
+        return Ok(gpt.map(|entries| Table { scheme: "GPT", entries }));
+    }
+    let entries = read_mbr(device, primaries)?;
+    Ok(Some(Table { scheme: "MBR", entries }))
+}
+
+/// A partition as a block device of its own: blocks counted from its start,
+/// and none past its end.
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    disk_name: String,
+    entry: Entry,
+}
+
+impl Partition {
+    /// None if `entry` reaches past the end of `disk`.
+    pub fn new(disk: Arc<dyn BlockDevice>, disk_name: &str, entry: Entry) -> Option<Partition> {
+        let end = entry.start.checked_add(entry.blocks)?;
+        (end <= disk.block_count()).then(|| Partition { disk, disk_name: String::from(disk_name), entry })
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.entry.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        self.disk.read_blocks(self.entry.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, data.len())?;
+        self.disk.write_blocks(self.entry.start + start, data)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    /// Passed on to the disk, so it is out in the background there too.
+    fn submit(&self, request: Request) -> Pending {
+        let (start, len) = match request {
+            Request::Read { start, len } => (start, len),
+            Request::Write { start, data } => (start, data.len()),
+            Request::Flush => return self.disk.submit(request),
+        };
+        match check_range(self, start, len) {
+            Ok(_) => self.disk.submit(request.moved(self.entry.start)),
+            Err(err) => Pending::ready(Err(err)),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn describe(&self) -> String {
+        match self.entry.name.as_str() {
+            "" => format!("partition {} of {}, {}", self.entry.number, self.disk_name, self.entry.kind),
+            name => format!("partition {} of {}, {}, \"{}\"", self.entry.number, self.disk_name, self.entry.kind, name),
+        }
+    }
+}
+
+/// Read device `name`'s partition table, print it, and register each
+/// partition as `<name>p<number>`.
+pub fn scan(name: &str) {
+    let Some(disk) = get(name) else {
+        return serial_println!("block: no device {}", name);
+    };
+    let table = match read_table(&*disk) {
+        Ok(Some(table)) => table,
+        Ok(None) => return,
+        Err(err) => return serial_println!("block: {}: reading the partition table: {}", name, err),
+    };
+    serial_println!("block: {}: {} partition table, {} partitions", name, table.scheme, table.entries.len());
+    serial_println!("  #       start     blocks  type");
+    for entry in table.entries {
+        serial_println!("  {:<2} {:>10} {:>10}  {}  {}", entry.number, entry.start, entry.blocks, entry.kind, entry.name);
+        let number = entry.number;
+        match Partition::new(disk.clone(), name, entry) {
+            Some(partition) => {
+                register(&format!("{}p{}", name, number), Arc::new(partition));
+            }
+            None => serial_println!("block: {}: partition {} runs past the end of the disk", name, number),
+        }
+    }
+}
+
+/// Write MBR entry `slot` of the boot record in `block`.
+fn put_mbr_entry(block: &mut [u8], slot: usize, kind: u8, start: u32, blocks: u32) {
+    let entry = &mut block[MBR_ENTRIES + 16 * slot..MBR_ENTRIES + 16 * (slot + 1)];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    block[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+}
+
+/// A GPT over a 128-block disk, with one EFI system partition named "EFI"
+/// at blocks 34 to 99, header in block `at`.
+fn put_gpt(disk: &RamDisk, at: u64, array: u64) -> bool {
+    let mut entries = vec![0u8; 4 * 128];
+    let guid = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+    entries[0..16].copy_from_slice(&guid);
+    entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+    entries[40..48].copy_from_slice(&99u64.to_le_bytes());
+    for (i, c) in "EFI".encode_utf16().enumerate() {
+        entries[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+    }
+    let mut header = [0u8; 512];
+    header[0..8].copy_from_slice(GPT_SIGNATURE);
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&at.to_le_bytes());
+    header[72..80].copy_from_slice(&array.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+    let crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    disk.write_blocks(at, &header).is_ok() && disk.write_blocks(array, &entries).is_ok()
+}
+
+/// On RAM disks: an MBR with a primary and two logical partitions in an
+/// extended one reads back as those three, and a partition's blocks are
+/// the disk's from its start, ending at its end, submitted or not; a GPT reads back with its
+/// type and name, from the backup when the header in block 1 is damaged,
+/// and not at all when both are; no signature, no table.
+pub fn self_test() -> bool {
+    let Some(disk) = RamDisk::new(512, 256) else { return false };
+    let disk: Arc<dyn BlockDevice> = Arc::new(disk);
+    let mut block = [0u8; 512];
+    let mut ok = matches!(read_table(&*disk), Ok(None));
+
+    put_mbr_entry(&mut block, 0, 0x0C, 8, 40);
+    put_mbr_entry(&mut block, 1, 0x05, 64, 128);
+    ok &= disk.write_blocks(0, &block).is_ok();
+    block = [0u8; 512];
+    put_mbr_entry(&mut block, 0, 0x83, 2, 30);
+    put_mbr_entry(&mut block, 1, 0x05, 40, 20);
+    ok &= disk.write_blocks(64, &block).is_ok();
+    block = [0u8; 512];
+    put_mbr_entry(&mut block, 0, 0x06, 2, 10);
+    ok &= disk.write_blocks(104, &block).is_ok();
+    let found: Vec<(usize, u64, u64, Kind)> = match read_table(&*disk) {
+        Ok(Some(table)) if table.scheme == "MBR" => table.entries.into_iter().map(|e| (e.number, e.start, e.blocks, e.kind)).collect(),
+        _ => return false,
+    };
+    ok &= found == [(1, 8, 40, Kind::Mbr(0x0C)), (5, 66, 30, Kind::Mbr(0x83)), (6, 106, 10, Kind::Mbr(0x06))];
+
+    let entry = Entry { number: 1, start: 8, blocks: 40, kind: Kind::Mbr(0x0C), name: String::new() };
+    let Some(partition) = Partition::new(disk.clone(), "test", entry.clone()) else { return false };
+    let pattern = [0x5Au8; 512];
+    ok &= partition.write_blocks(39, &pattern).is_ok()
+        && disk.read_blocks(47, &mut block).is_ok()
+        && block == pattern
+        && partition.read_blocks(40, &mut block) == Err(BlockError::OutOfRange)
+        && partition.submit(Request::Read { start: 39, len: 512 }).wait().as_deref() == Ok(&pattern[..])
+        && partition.submit(Request::Read { start: 39, len: 1024 }).wait() == Err(BlockError::OutOfRange)
+        && Partition::new(disk.clone(), "test", Entry { start: 250, ..entry }).is_none();
+
+    let Some(gpt) = RamDisk::new(512, 128) else { return false };
+    block = [0u8; 512];
+    put_mbr_entry(&mut block, 0, MBR_PROTECTIVE, 1, 127);
+    ok &= gpt.write_blocks(0, &block).is_ok() && put_gpt(&gpt, 1, 2) && put_gpt(&gpt, 127, 123);
+    let efi = |table: Result<Option<Table>, BlockError>| match table {
+        Ok(Some(table)) => {
+            table.scheme == "GPT"
+                && table.entries
+                    == [Entry { number: 1, start: 34, blocks: 66, kind: Kind::Gpt(GPT_EFI_SYSTEM), name: String::from("EFI") }]
+        }
+        _ => false,
+    };
+    ok &= efi(read_table(&gpt));
+    // Damage the primary header, then the backup's entries.
+    ok &= gpt.read_blocks(1, &mut block).is_ok();
+    block[40] ^= 1;
+    ok &= gpt.write_blocks(1, &block).is_ok() && efi(read_table(&gpt));
+    ok &= gpt.read_blocks(123, &mut block).is_ok();
+    block[60] ^= 1;
+    ok && gpt.write_blocks(123, &block).is_ok() && matches!(read_table(&gpt), Ok(None))
+}
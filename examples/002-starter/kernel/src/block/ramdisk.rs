@@ -0,0 +1,102 @@
+//! RAM disks: a block device in kernel memory, from `vmalloc`, for
+//! filesystem code to run against before there are disk drivers, and for
+//! tests. One can start as a copy of a file from the initrd, or of the
+//! whole archive (`ram0`, see `block::init`). Its contents are gone at
+//! reboot, so `flush` has nothing to do.
+
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+
+use super::{check_range, BlockDevice, BlockError};
+use crate::initrd;
+use crate::memory::vmalloc;
+use crate::sync::Mutex;
+
+/// The block size RAM disks get unless asked otherwise: a sector's.
+pub const BLOCK_SIZE: usize = 512;
+
+pub struct RamDisk {
+    /// Where the blocks are, from `vmalloc`.
+    start: VirtAddr,
+    block_size: usize,
+    blocks: u64,
+    read_only: AtomicBool,
+    /// Held for each transfer, so one sees the others whole.
+    lock: Mutex<()>,
+}
+
+impl RamDisk {
+    /// `blocks` zeroed blocks of `block_size` bytes.
+    pub fn new(block_size: usize, blocks: u64) -> Option<RamDisk> {
+        assert!(block_size.is_power_of_two() && block_size >= 512, "bad block size {}", block_size);
+        let len = (block_size as u64).checked_mul(blocks)?;
+        let start = vmalloc::vmalloc(len, "ramdisk")?;
+        Some(RamDisk { start, block_size, blocks, read_only: AtomicBool::new(false), lock: Mutex::new(()) })
+    }
+
+    /// A disk starting with `image`, the last block padded with zeros.
+    pub fn from_image(image: &[u8], block_size: usize) -> Option<RamDisk> {
+        let disk = RamDisk::new(block_size, (image.len().div_ceil(block_size) as u64).max(1))?;
+        unsafe { disk.start.as_mut_ptr::<u8>().copy_from_nonoverlapping(image.as_ptr(), image.len()) };
+        Some(disk)
+    }
+
+    /// A disk starting as a copy of initrd file `path`.
+    pub fn from_initrd(path: &str) -> Option<RamDisk> {
+        RamDisk::from_image(initrd::read(path)?, BLOCK_SIZE)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    fn at(&self, block: u64) -> *mut u8 {
+        (self.start + block * self.block_size as u64).as_mut_ptr()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let _guard = self.lock.lock();
+        unsafe { buf.as_mut_ptr().copy_from_nonoverlapping(self.at(start), buf.len()) };
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, start, data.len())?;
+        let _guard = self.lock.lock();
+        unsafe { self.at(start).copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    fn describe(&self) -> String {
+        String::from("RAM disk")
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        vmalloc::vfree(self.start);
+    }
+}
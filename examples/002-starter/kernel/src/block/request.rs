@@ -0,0 +1,240 @@
+//! Requests in flight. `BlockDevice::submit` hands a request to the device
+//! and returns at once with a `Pending`; the caller goes on, submits more,
+//! and waits when it needs the result: blocking the thread (`wait`), or as
+//! a future (`.await`, on the `task` executor). Meanwhile the scheduler
+//! runs other threads, and a device that takes several requests at a time
+//! has them all out together. Requests out together may complete in any
+//! order: to read what a write wrote, wait for the write first.
+//!
+//! The driver completes the request: from its interrupt's bottom half, it
+//! copies in what was read (`Completion::fill`) and finishes the request,
+//! which wakes the waiting thread or task. A long request may go to the
+//! device in parts; it is done when the last part is, with the first error
+//! any part had. Devices that only transfer synchronously complete a
+//! request before `submit` returns (`Pending::ready`), which is what the
+//! trait does for them.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use super::{BlockDevice, BlockError, RamDisk};
+use crate::sync::{Mutex, WaitQueue};
+use crate::time;
+
+/// How long `wait` waits before giving up on the device.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// `len` bytes from block `start` on.
+    Read { start: u64, len: usize },
+    /// `data` to the blocks from `start` on. The device has copied it by
+    /// the time `submit` returns.
+    Write { start: u64, data: &'a [u8] },
+    Flush,
+}
+
+impl Request<'_> {
+    /// The same request, `offset` blocks further on: for a partition.
+    pub fn moved(self, offset: u64) -> Self {
+        match self {
+            Request::Read { start, len } => Request::Read { start: start + offset, len },
+            Request::Write { start, data } => Request::Write { start: start + offset, data },
+            Request::Flush => Request::Flush,
+        }
+    }
+}
+
+struct State {
+    /// Parts not finished yet.
+    parts: usize,
+    /// The first error of a part, if any.
+    result: Result<(), BlockError>,
+    /// What a read has read so far.
+    data: Vec<u8>,
+    /// The task awaiting it.
+    waker: Option<Waker>,
+}
+
+/// Where a driver completes a request. Only touched in thread context: a
+/// bottom half, or the submitter polling the device.
+pub struct Completion {
+    done: AtomicBool,
+    state: Mutex<State>,
+    /// Threads in `wait`.
+    waiters: WaitQueue,
+}
+
+impl Completion {
+    /// A request the driver sends in `parts` parts, at least one, which
+    /// reads `len` bytes (0 for a write or flush).
+    pub fn new(parts: usize, len: usize) -> Arc<Completion> {
+        Arc::new(Completion {
+            done: AtomicBool::new(false),
+            state: Mutex::new(State { parts, result: Ok(()), data: vec![0; len], waker: None }),
+            waiters: WaitQueue::new(),
+        })
+    }
+
+    /// Put `bytes`, read, at byte `offset` of the request's data.
+    pub fn fill(&self, offset: usize, bytes: &[u8]) {
+        self.state.lock().data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A part finished with `result`. After the last, the request is done
+    /// and its waiter woken.
+    pub fn finish_part(&self, result: Result<(), BlockError>) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.parts == 0 {
+                return;
+            }
+            state.parts -= 1;
+            if state.result.is_ok() {
+                state.result = result;
+            }
+            if state.parts > 0 {
+                return;
+            }
+            self.done.store(true, Ordering::Release);
+            state.waker.take()
+        };
+        self.waiters.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Finish every part not finished yet with `err`: for parts the driver
+    /// couldn't send.
+    pub fn fail(&self, err: BlockError) {
+        while !self.done.load(Ordering::Acquire) {
+            self.finish_part(Err(err));
+        }
+    }
+
+    /// The result, once done: the data for a read, empty otherwise.
+    fn take(&self) -> Result<Vec<u8>, BlockError> {
+        let mut state = self.state.lock();
+        let result = state.result;
+        result.map(|()| core::mem::take(&mut state.data))
+    }
+}
+
+/// A submitted request, done or not. Its result is the data for a read,
+/// and empty for a write or flush.
+pub struct Pending {
+    completion: Arc<Completion>,
+}
+
+impl Pending {
+    /// Waiting on `completion`, which the driver keeps.
+    pub fn new(completion: Arc<Completion>) -> Pending {
+        Pending { completion }
+    }
+
+    /// A request already done, with `result`.
+    pub fn ready(result: Result<Vec<u8>, BlockError>) -> Pending {
+        let (result, data) = match result {
+            Ok(data) => (Ok(()), data),
+            Err(err) => (Err(err), Vec::new()),
+        };
+        let completion = Arc::new(Completion {
+            done: AtomicBool::new(true),
+            state: Mutex::new(State { parts: 0, result, data, waker: None }),
+            waiters: WaitQueue::new(),
+        });
+        Pending { completion }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.completion.done.load(Ordering::Acquire)
+    }
+
+    /// Block until it is done; an error if the device doesn't finish it in
+    /// time.
+    pub fn wait(self) -> Result<Vec<u8>, BlockError> {
+        let done = &self.completion.done;
+        if !self.completion.waiters.wait_until_deadline(|| done.load(Ordering::Acquire), time::uptime() + TIMEOUT) {
+            return Err(BlockError::Io("device timed out"));
+        }
+        self.completion.take()
+    }
+}
+
+impl Future for Pending {
+    type Output = Result<Vec<u8>, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.is_done() {
+            self.completion.state.lock().waker = Some(cx.waker().clone());
+            // It may have finished before the waker was in place.
+            if !self.is_done() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(self.completion.take())
+    }
+}
+
+/// Submit every request, then wait for them all; the results in order.
+pub fn submit_all(device: &dyn BlockDevice, requests: &[Request]) -> Vec<Result<Vec<u8>, BlockError>> {
+    let pending: Vec<Pending> = requests.iter().map(|&request| device.submit(request)).collect();
+    pending.into_iter().map(Pending::wait).collect()
+}
+
+/// On a RAM disk, which completes in `submit` (so in order): requests out
+/// of range or not whole blocks fail, a read sees the write before it, a
+/// request completed in parts finishes with the last and keeps the first
+/// error, and a `Pending` awaited on the executor gives what `wait` does.
+pub fn self_test() -> bool {
+    let Some(disk) = RamDisk::new(512, 8) else { return false };
+    let pattern: Vec<u8> = (0..1024).map(|i| (i * 3) as u8).collect();
+    let results = submit_all(
+        &disk,
+        &[
+            Request::Write { start: 2, data: &pattern },
+            Request::Flush,
+            Request::Read { start: 2, len: 1024 },
+            Request::Read { start: 7, len: 1024 },
+            Request::Read { start: 0, len: 100 },
+        ],
+    );
+    let mut ok = results[0] == Ok(Vec::new())
+        && results[1] == Ok(Vec::new())
+        && results[2].as_deref() == Ok(&pattern[..])
+        && results[3] == Err(BlockError::OutOfRange)
+        && results[4] == Err(BlockError::Unaligned)
+        && Request::Read { start: 1, len: 512 }.moved(4) == Request::Read { start: 5, len: 512 };
+
+    let completion = Completion::new(3, 4);
+    let pending = Pending::new(completion.clone());
+    completion.fill(2, b"cd");
+    completion.finish_part(Ok(()));
+    completion.fill(0, b"ab");
+    completion.finish_part(Err(BlockError::Io("first")));
+    ok &= !pending.is_done();
+    completion.finish_part(Err(BlockError::Io("second")));
+    ok &= pending.is_done() && pending.wait() == Err(BlockError::Io("first"));
+
+    let completion = Completion::new(2, 0);
+    completion.finish_part(Ok(()));
+    completion.fail(BlockError::Io("unsent"));
+    ok &= Pending::new(completion).wait() == Err(BlockError::Io("unsent"));
+
+    let awaited = Arc::new(spin::Mutex::new(None));
+    let (disk, result) = (Arc::new(disk), awaited.clone());
+    let mut executor = crate::task::Executor::new();
+    executor.spawn(async move {
+        *result.lock() = Some(disk.submit(Request::Read { start: 2, len: 1024 }).await);
+    });
+    executor.run();
+    let awaited = awaited.lock().take();
+    ok && awaited.as_ref().and_then(|result| result.as_deref().ok()) == Some(&pattern[..])
+}
@@ -0,0 +1,359 @@
+//! virtio-blk: the disk QEMU attaches with `-device virtio-blk-pci`, the
+//! simplest real storage to drive. A request is a chain of three buffers
+//! on the device's one virtqueue: a header the device reads (read, write
+//! or flush, and the first sector), the data, and a status byte the device
+//! writes when it is done. Then it interrupts.
+//!
+//! Up to `SLOTS` requests are out at once, each in a slot of its own: a
+//! header and a bounce buffer (the caller's buffer may span frames that
+//! aren't contiguous, the bounce buffer's are). `submit` puts a request in
+//! free slots and returns; the interrupt schedules `REAP` on the
+//! workqueue, which takes back what the device has done, copies in what
+//! was read, and completes the request. Without an interrupt line,
+//! `submit` polls the device until the request is done. Sectors are 512
+//! bytes whatever the disk says its block size is.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Once;
+
+use super::request::Completion;
+use super::{check_range, next_name, register, BlockDevice, BlockError, Pending, Request};
+use crate::dma::{self, DmaBuffer};
+use crate::sync::{Mutex, RwLock, WaitQueue};
+use crate::virtio::{self, Buffer, Transport, Virtqueue};
+use crate::workqueue::Work;
+use crate::{interrupts, pci, serial_println, time};
+
+pub const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 16;
+/// Requests out at once. Each takes three descriptors of the queue.
+const SLOTS: usize = 4;
+/// The most one request moves; longer transfers take several.
+const MAX_TRANSFER: usize = 64 * 1024;
+/// How long a request may take before we give up on the device.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Features.
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Request types.
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+/// Request status values.
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Where the status byte is in `Slot::header`, after the 16-byte header.
+const STATUS_OFFSET: usize = 16;
+
+pub struct VirtioBlk {
+    transport: Transport,
+    blocks: u64,
+    read_only: bool,
+    can_flush: bool,
+    /// The PIC line it interrupts on; without one, requests are polled.
+    irq: Once<u8>,
+    inner: Mutex<Inner>,
+    /// Slots not in use, for `freed`'s waiters to check.
+    free: AtomicUsize,
+    /// Threads waiting for a free slot.
+    freed: WaitQueue,
+    interrupts: AtomicU64,
+}
+
+struct Inner {
+    queue: Virtqueue,
+    slots: Vec<Slot>,
+}
+
+struct Slot {
+    /// The request header, then the status byte.
+    header: DmaBuffer,
+    /// The bounce buffer.
+    data: DmaBuffer,
+    /// The request the device has in it, if any.
+    busy: Option<InFlight>,
+}
+
+struct InFlight {
+    /// The chain's first descriptor, as the device hands it back.
+    head: u16,
+    /// For a read: where in the request's data the bytes go, and how many.
+    read: Option<(usize, usize)>,
+    completion: Arc<Completion>,
+}
+
+/// Every virtio disk found, for `REAP` and `self_test`.
+static DISKS: RwLock<Vec<Arc<VirtioBlk>>> = RwLock::new(Vec::new());
+
+/// The interrupts' bottom half: every disk takes back what it has done.
+/// It runs on the one worker thread, so other work items mustn't wait for
+/// a virtio disk.
+static REAP: Work = Work::new(|| DISKS.read().iter().for_each(|disk| disk.reap()));
+
+impl VirtioBlk {
+    /// Set up `device`: agree on features, give it a queue and the slots'
+    /// buffers, and take its interrupt line.
+    pub fn new(device: pci::Device) -> Result<Arc<VirtioBlk>, &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let mut queue = Virtqueue::new(QUEUE_SIZE).map_err(|_| "no memory for the queue")?;
+        transport.setup_queue(0, &mut queue)?;
+        let mut slots = Vec::new();
+        for _ in 0..SLOTS {
+            let header = dma::alloc(STATUS_OFFSET + 1, 16).map_err(|_| "no memory for requests")?;
+            let data = dma::alloc(MAX_TRANSFER, 4096).map_err(|_| "no memory for the bounce buffers")?;
+            slots.push(Slot { header, data, busy: None });
+        }
+        let blocks = transport.config_u64(0);
+        transport.driver_ok();
+
+        let disk = Arc::new(VirtioBlk {
+            transport,
+            blocks,
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+            irq: Once::new(),
+            inner: Mutex::new(Inner { queue, slots }),
+            free: AtomicUsize::new(SLOTS),
+            freed: WaitQueue::new(),
+            interrupts: AtomicU64::new(0),
+        });
+        DISKS.write().push(disk.clone());
+        if let Some(line) = device.interrupt_line {
+            let handler = disk.clone();
+            if interrupts::add_line_handler(line, Box::new(move || handler.on_interrupt())) {
+                disk.irq.call_once(|| line);
+            }
+        }
+        Ok(disk)
+    }
+
+    /// On its line, which others may share: if it was this device, note
+    /// that and have the workqueue take back what it has done. Reading the
+    /// ISR lowers the line.
+    fn on_interrupt(&self) {
+        if self.transport.read_isr() & virtio::ISR_QUEUE != 0 {
+            self.interrupts.fetch_add(1, Ordering::Relaxed);
+            REAP.schedule();
+        }
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Send a request of `kind` for `len` bytes from `sector` on, `data`
+    /// for a write, in a free slot; it is part of `completion`, at byte
+    /// `offset` of its data. Waits for a slot if every one is out.
+    fn send(&self, kind: u32, sector: u64, offset: usize, len: usize, data: &[u8], completion: &Arc<Completion>) -> Result<(), BlockError> {
+        let deadline = time::uptime() + TIMEOUT;
+        loop {
+            let mut inner = self.inner.lock();
+            let Inner { queue, slots } = &mut *inner;
+            if let Some(slot) = slots.iter_mut().find(|slot| slot.busy.is_none()) {
+                let header = slot.header.virt().as_mut_ptr::<u8>();
+                unsafe {
+                    header.cast::<u32>().write_volatile(kind);
+                    header.add(4).cast::<u32>().write_volatile(0);
+                    header.add(8).cast::<u64>().write_volatile(sector);
+                    header.add(STATUS_OFFSET).write_volatile(0xFF);
+                }
+                slot.data.as_mut_slice()[..data.len()].copy_from_slice(data);
+                let phys = slot.header.phys();
+                let header_buffer = Buffer { addr: phys, len: STATUS_OFFSET as u32, device_writes: false };
+                let status_buffer = Buffer { addr: phys + STATUS_OFFSET as u64, len: 1, device_writes: true };
+                let data_buffer = Buffer { addr: slot.data.phys(), len: len as u32, device_writes: kind == T_IN };
+                let with_data = [header_buffer, data_buffer, status_buffer];
+                let chain: &[Buffer] = if len == 0 { &[header_buffer, status_buffer] } else { &with_data };
+                let head = queue.submit(chain).ok_or(BlockError::Io("virtqueue full"))?;
+                let read = (kind == T_IN).then_some((offset, len));
+                slot.busy = Some(InFlight { head, read, completion: completion.clone() });
+                self.free.fetch_sub(1, Ordering::AcqRel);
+                self.transport.notify(queue);
+                return Ok(());
+            }
+            drop(inner);
+            let freed = match self.irq.get() {
+                Some(_) => self.freed.wait_until_deadline(|| self.free.load(Ordering::Acquire) > 0, deadline),
+                None => {
+                    self.reap();
+                    time::uptime() < deadline
+                }
+            };
+            if !freed {
+                return Err(BlockError::Io("device timed out"));
+            }
+        }
+    }
+
+    /// Take back every request the device has done: read its status, copy
+    /// in what it read, finish its part, and free its slot. Chains of
+    /// requests given up on are handed back too, and free their slots.
+    fn reap(&self) {
+        let mut inner = self.inner.lock();
+        let Inner { queue, slots } = &mut *inner;
+        let mut freed = false;
+        while let Some((head, _)) = queue.pop_used() {
+            let Some(slot) = slots.iter_mut().find(|slot| slot.busy.as_ref().is_some_and(|busy| busy.head == head)) else {
+                continue;
+            };
+            let busy = slot.busy.take().expect("a busy slot");
+            let result = match unsafe { slot.header.virt().as_ptr::<u8>().add(STATUS_OFFSET).read_volatile() } {
+                S_OK => Ok(()),
+                S_IOERR => Err(BlockError::Io("device reported an error")),
+                S_UNSUPP => Err(BlockError::Io("request not supported")),
+                _ => Err(BlockError::Io("bad request status")),
+            };
+            if let (Ok(()), Some((offset, len))) = (result, busy.read) {
+                busy.completion.fill(offset, &slot.data.as_mut_slice()[..len]);
+            }
+            busy.completion.finish_part(result);
+            self.free.fetch_add(1, Ordering::AcqRel);
+            freed = true;
+        }
+        drop(inner);
+        if freed {
+            self.freed.notify_all();
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let data = self.submit(Request::Read { start, len: buf.len() }).wait()?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.submit(Request::Write { start, data }).wait().map(|_| ())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.submit(Request::Flush).wait().map(|_| ())
+    }
+
+    /// In parts of up to `MAX_TRANSFER`, each in a slot; if one can't be
+    /// sent, it and the rest fail. Without the flush feature the device
+    /// has no write cache to flush.
+    fn submit(&self, request: Request) -> Pending {
+        let (kind, start, len, data) = match request {
+            Request::Read { start, len } => (T_IN, start, len, &[][..]),
+            Request::Write { .. } if self.read_only => return Pending::ready(Err(BlockError::ReadOnly)),
+            Request::Write { start, data } => (T_OUT, start, data.len(), data),
+            Request::Flush if !self.can_flush => return Pending::ready(Ok(Vec::new())),
+            Request::Flush => (T_FLUSH, 0, 0, &[][..]),
+        };
+        if let Err(err) = check_range(self, start, len) {
+            return Pending::ready(Err(err));
+        }
+        let parts = len.div_ceil(MAX_TRANSFER).max(1);
+        let completion = Completion::new(parts, if kind == T_IN { len } else { 0 });
+        for part in 0..parts {
+            let offset = part * MAX_TRANSFER;
+            let part_len = (len - offset).min(MAX_TRANSFER);
+            let part_data = if kind == T_OUT { &data[offset..offset + part_len] } else { &[][..] };
+            let sector = start + (offset / SECTOR_SIZE) as u64;
+            if let Err(err) = self.send(kind, sector, offset, part_len, part_data, &completion) {
+                completion.fail(err);
+                break;
+            }
+        }
+        let pending = Pending::new(completion);
+        if self.irq.get().is_none() {
+            let deadline = time::uptime() + TIMEOUT;
+            while !pending.is_done() && time::uptime() < deadline {
+                self.reap();
+                core::hint::spin_loop();
+            }
+        }
+        pending
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn describe(&self) -> String {
+        match self.irq.get() {
+            Some(line) => format!("virtio-blk at {}, irq {}", self.transport.device.addr, line),
+            None => format!("virtio-blk at {}, polled", self.transport.device.addr),
+        }
+    }
+}
+
+/// Register every virtio disk on the PCI bus, as `vd0`, `vd1`, ...
+pub fn probe() {
+    for device in pci::devices() {
+        if virtio::device_type(device) != Some(virtio::TYPE_BLOCK) {
+            continue;
+        }
+        match VirtioBlk::new(*device) {
+            Ok(disk) => {
+                register(&next_name("vd"), disk);
+            }
+            Err(err) => serial_println!("block: virtio-blk at {}: {}", device.addr, err),
+        }
+    }
+}
+
+/// On each virtio disk: a read longer than one request matches the same
+/// blocks read alone, as do more reads submitted at once than there are
+/// slots; the end of the disk is enforced, a write to the last block reads
+/// back (and is undone), and with an interrupt line, requests raised
+/// interrupts. True with no virtio disks.
+pub fn self_test() -> bool {
+    DISKS.read().iter().all(|disk| {
+        if disk.blocks == 0 {
+            return disk.read_blocks(0, &mut [0u8; SECTOR_SIZE]) == Err(BlockError::OutOfRange);
+        }
+        let before = disk.interrupts();
+        let blocks = (MAX_TRANSFER / SECTOR_SIZE + 8).min(disk.blocks as usize);
+        let mut long = vec![0u8; blocks * SECTOR_SIZE];
+        let mut one = [0u8; SECTOR_SIZE];
+        let mut ok = disk.read_blocks(0, &mut long).is_ok()
+            && disk.read_blocks(blocks as u64 - 1, &mut one).is_ok()
+            && long[long.len() - SECTOR_SIZE..] == one[..]
+            && disk.read_blocks(disk.blocks, &mut one) == Err(BlockError::OutOfRange);
+
+        let pending: Vec<Pending> = (0..SLOTS + 2).map(|i| disk.submit(Request::Read { start: (i % blocks) as u64, len: SECTOR_SIZE })).collect();
+        for (i, pending) in pending.into_iter().enumerate() {
+            let at = i % blocks * SECTOR_SIZE;
+            ok &= pending.wait().as_deref() == Ok(&long[at..at + SECTOR_SIZE]);
+        }
+
+        if !disk.read_only {
+            let last = disk.blocks - 1;
+            let mut saved = [0u8; SECTOR_SIZE];
+            let pattern: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 13 + 5) as u8).collect();
+            ok &= disk.read_blocks(last, &mut saved).is_ok()
+                && disk.write_blocks(last, &pattern).is_ok()
+                && disk.flush().is_ok()
+                && disk.read_blocks(last, &mut one).is_ok()
+                && one[..] == pattern[..]
+                && disk.write_blocks(last, &saved).is_ok()
+                && disk.flush().is_ok();
+        }
+        ok && (disk.irq.get().is_none() || disk.interrupts() > before)
+    })
+}
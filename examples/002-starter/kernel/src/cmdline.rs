@@ -0,0 +1,100 @@
+//! Kernel command line, passed by the runner through QEMU's fw_cfg device
+//! (`-fw_cfg name=opt/teachme/cmdline,string=...`, see `KERNEL_CMDLINE`).
+//!
+//! Options are space separated words, either flags (`nokaslr`) or `key=value` (`oom=fail`).
+
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const CMDLINE_FILE: &[u8] = b"opt/teachme/cmdline";
+const MAX_LEN: usize = 256;
+
+struct Buffer {
+    bytes: [u8; MAX_LEN],
+    len: usize,
+}
+
+static CMDLINE: Once<Buffer> = Once::new();
+
+/// Read the command line. Uses only port I/O, so it can run before the heap exists.
+pub fn init() {
+    CMDLINE.call_once(|| {
+        let mut buf = Buffer { bytes: [0; MAX_LEN], len: 0 };
+        unsafe {
+            if let Some((selector, size)) = find_file(CMDLINE_FILE) {
+                select(selector);
+                buf.len = (size as usize).min(MAX_LEN);
+                for b in &mut buf.bytes[..buf.len] {
+                    *b = read_u8();
+                }
+            }
+        }
+        buf
+    });
+    if !as_str().is_empty() {
+        crate::serial_println!("cmdline: {}", as_str());
+    }
+}
+
+pub fn as_str() -> &'static str {
+    CMDLINE
+        .get()
+        .and_then(|b| core::str::from_utf8(&b.bytes[..b.len]).ok())
+        .map(|s| s.trim_end_matches('\0').trim())
+        .unwrap_or("")
+}
+
+/// true if the bare word `name` is present.
+pub fn has_flag(name: &str) -> bool {
+    as_str().split_whitespace().any(|w| w == name)
+}
+
+/// Value of the first `key=value` option with this key.
+pub fn get(key: &str) -> Option<&'static str> {
+    as_str()
+        .split_whitespace()
+        .filter_map(|w| w.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+unsafe fn select(selector: u16) {
+    Port::<u16>::new(FW_CFG_SELECTOR).write(selector);
+}
+
+unsafe fn read_u8() -> u8 {
+    Port::<u8>::new(FW_CFG_DATA).read()
+}
+
+unsafe fn read_be<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    for b in &mut bytes {
+        *b = read_u8();
+    }
+    bytes
+}
+
+/// Look a file up in the fw_cfg directory; returns (selector, size).
+unsafe fn find_file(name: &[u8]) -> Option<(u16, u32)> {
+    select(FW_CFG_SIGNATURE);
+    if read_be::<4>() != *b"QEMU" {
+        return None;
+    }
+    select(FW_CFG_FILE_DIR);
+    let count = u32::from_be_bytes(read_be());
+    for _ in 0..count {
+        let size = u32::from_be_bytes(read_be());
+        let selector = u16::from_be_bytes(read_be());
+        let _reserved: [u8; 2] = read_be();
+        let file: [u8; 56] = read_be();
+        let len = file.iter().position(|&b| b == 0).unwrap_or(file.len());
+        if &file[..len] == name {
+            return Some((selector, size));
+        }
+    }
+    None
+}
@@ -0,0 +1,133 @@
+//! The console user programs get as file descriptors 0, 1 and 2. Output
+//! goes to COM1; input comes from COM1 and the PS/2 keyboard, whichever is
+//! typed on.
+//!
+//! Input is a line at a time, like a terminal in canonical mode: what is
+//! typed is echoed and backspace erases it, and `read` blocks until Enter,
+//! then returns the line with its newline (as much as fits; the rest comes
+//! with the next `read`). Ctrl-D ends a line without a newline, so on an
+//! empty line it is end of file: `read` returns 0.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::serial;
+use crate::sync::{Mutex, WaitQueue};
+use crate::task::keyboard::{self, Decoder, Key};
+
+const MAX_LINE: usize = 256;
+/// Ctrl-D.
+const END_OF_FILE: u8 = 0x04;
+
+/// Readers waiting for a key; both input interrupts wake them.
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+/// One reader at a time: it owns the line being typed.
+static READER: Mutex<Option<Reader>> = Mutex::new(None);
+
+/// The line being typed.
+#[derive(Default)]
+struct LineEditor {
+    line: Vec<u8>,
+}
+
+impl LineEditor {
+    /// Take one typed byte, echoing it with `echo`. Returns the line when
+    /// it is finished.
+    fn feed(&mut self, byte: u8, mut echo: impl FnMut(&[u8])) -> Option<Vec<u8>> {
+        match byte {
+            b'\r' | b'\n' => {
+                echo(b"\n");
+                self.line.push(b'\n');
+                return Some(core::mem::take(&mut self.line));
+            }
+            END_OF_FILE => return Some(core::mem::take(&mut self.line)),
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    echo(b"\x08 \x08");
+                }
+            }
+            b if (0x20..0x7f).contains(&b) && self.line.len() < MAX_LINE - 1 => {
+                self.line.push(b);
+                echo(&[b]);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[derive(Default)]
+struct Reader {
+    keys: Decoder,
+    editor: LineEditor,
+    /// What the last `read` left of a line.
+    pending: VecDeque<u8>,
+}
+
+impl Reader {
+    /// The next byte typed on either input, blocking until there is one.
+    fn next_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = serial::pop_byte() {
+                return byte;
+            }
+            while let Some(scancode) = keyboard::pop_scancode() {
+                match self.keys.feed(scancode) {
+                    Some(Key::Char(c)) => return c as u8,
+                    Some(Key::Backspace) => return 0x08,
+                    _ => {}
+                }
+            }
+            INPUT_READY.wait_until(|| serial::has_input() || keyboard::has_input());
+        }
+    }
+
+    fn read_line(&mut self) -> Vec<u8> {
+        loop {
+            let byte = self.next_byte();
+            if let Some(line) = self.editor.feed(byte, serial::write_bytes) {
+                return line;
+            }
+        }
+    }
+}
+
+/// Called from the COM1 and keyboard interrupt handlers when input arrives.
+pub fn input_arrived() {
+    INPUT_READY.notify_all();
+}
+
+/// Fill `buf` from the current line, waiting for one if there is none;
+/// returns how many bytes, 0 at end of file.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut reader = READER.lock();
+    let reader = reader.get_or_insert_with(Reader::default);
+    if reader.pending.is_empty() {
+        let line = reader.read_line();
+        reader.pending.extend(line);
+    }
+    let n = buf.len().min(reader.pending.len());
+    for (dst, src) in buf.iter_mut().zip(reader.pending.drain(..n)) {
+        *dst = src;
+    }
+    n
+}
+
+pub fn write(bytes: &[u8]) {
+    serial::write_bytes(bytes);
+}
+
+/// Edit a line with backspaces (one too many), end one with Ctrl-D and
+/// send Ctrl-D on an empty line.
+pub fn self_test() -> bool {
+    let mut editor = LineEditor::default();
+    let mut echoed = Vec::new();
+    let mut lines = Vec::new();
+    for &byte in b"ab\x08\x08\x08cd\x7fe\rxy\x04\x04" {
+        if let Some(line) = editor.feed(byte, |bytes| echoed.extend_from_slice(bytes)) {
+            lines.push(line);
+        }
+    }
+    lines == [&b"ce\n"[..], b"xy", b""] && echoed == b"ab\x08 \x08\x08 \x08cd\x08 \x08e\nxy"
+}
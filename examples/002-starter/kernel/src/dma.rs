@@ -0,0 +1,226 @@
+//! Physically contiguous buffers for devices that do DMA (virtio, AHCI, NICs).
+//!
+//! A buffer comes from the buddy allocator, so it is naturally aligned to its
+//! power-of-two size, and the driver gets both addresses: `virt` for the CPU and
+//! `phys` to program into the device.
+//!
+//! x86 keeps DMA coherent with the caches, so write-back buffers are simply the
+//! physical-map alias of the frames. Other cache modes get their own mapping (at an
+//! address from `vmalloc`) with the matching PAT index. The physical map still covers those frames as
+//! write-back, so never touch such a buffer through `memory::phys_to_virt`.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{buddy, paging, phys_to_virt, vmalloc};
+
+const PAGE_SIZE: u64 = 4096;
+const IA32_PAT: u32 = 0x277;
+
+/// PAT memory type encodings.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+/// Power-on PAT with entry 4 changed from WB to WC; entries 0-3 keep their
+/// defaults so PCD/PWT alone mean what they always did.
+const PAT_VALUE: u64 = PAT_WB | PAT_WT << 8 | PAT_UC_MINUS << 16 | PAT_UC << 24
+    | PAT_WC << 32 | PAT_WT << 40 | PAT_UC_MINUS << 48 | PAT_UC << 56;
+/// In a 4KiB page table entry bit 7 selects the upper half of the PAT
+/// (the same bit means "huge page" one level up).
+const PTE_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PAT_READY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    /// Needs PAT; used for framebuffers and other write-mostly device memory.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Page-table bits selecting this mode's PAT entry.
+    fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PTE_PAT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// More than the buddy allocator's largest block.
+    TooLarge,
+    OutOfMemory,
+    /// Write-combining was requested but the CPU has no PAT.
+    Unsupported,
+    MapFailed,
+}
+
+/// A DMA buffer; freed (and unmapped) on drop.
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    order: usize,
+    mode: CacheMode,
+}
+
+impl DmaBuffer {
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The address to hand to the device.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.mode != CacheMode::WriteBack {
+            for i in 0..1u64 << self.order {
+                let page = Page::containing_address(self.virt + i * PAGE_SIZE);
+                paging::unmap(page).expect("dma: window page not mapped");
+            }
+            vmalloc::free_range(self.virt);
+        }
+        unsafe { buddy::free(PhysFrame::containing_address(self.phys), self.order) };
+    }
+}
+
+pub fn pat_supported() -> bool {
+    __cpuid(1).edx & (1 << 16) != 0
+}
+
+/// Program the PAT so that write-combining is available. Call once at boot.
+pub fn init() {
+    if !pat_supported() {
+        crate::serial_println!("dma: no PAT, write-combining unavailable");
+        return;
+    }
+    program_pat();
+    PAT_READY.store(true, Ordering::Relaxed);
+}
+
+/// Give an application processor the same PAT as the bootstrap processor;
+/// page tables are shared, so the PAT indices must mean the same everywhere.
+pub fn init_ap() {
+    if PAT_READY.load(Ordering::Relaxed) {
+        program_pat();
+    }
+}
+
+fn program_pat() {
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // Intel SDM 11.12.4: flush caches and TLBs after changing the PAT.
+        asm!("wbinvd", options(nostack));
+    }
+    tlb::flush_all();
+}
+
+/// A zeroed, write-back buffer of at least `len` bytes aligned to `align`.
+pub fn alloc(len: usize, align: usize) -> Result<DmaBuffer, DmaError> {
+    alloc_with(len, align, CacheMode::WriteBack)
+}
+
+/// Like [`alloc`], with the given cache mode.
+pub fn alloc_with(len: usize, align: usize, mode: CacheMode) -> Result<DmaBuffer, DmaError> {
+    if mode == CacheMode::WriteCombining && !PAT_READY.load(Ordering::Relaxed) {
+        return Err(DmaError::Unsupported);
+    }
+    let frames = len.max(align).max(1).div_ceil(PAGE_SIZE as usize);
+    let order = frames.next_power_of_two().trailing_zeros() as usize;
+    if order > buddy::MAX_ORDER {
+        return Err(DmaError::TooLarge);
+    }
+    let frame = buddy::alloc(order).ok_or(DmaError::OutOfMemory)?;
+    let phys = frame.start_address();
+    let bytes = PAGE_SIZE << order;
+
+    let virt = if mode == CacheMode::WriteBack {
+        phys_to_virt(phys)
+    } else {
+        let Some(virt) = vmalloc::alloc_range(bytes, "dma") else {
+            unsafe { buddy::free(frame, order) };
+            return Err(DmaError::OutOfMemory);
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | mode.page_flags();
+        for i in 0..1u64 << order {
+            let page = Page::containing_address(virt + i * PAGE_SIZE);
+            let page_frame = PhysFrame::containing_address(phys + i * PAGE_SIZE);
+            if unsafe { paging::map_to(page, page_frame, flags) }.is_err() {
+                for j in 0..i {
+                    let _ = paging::unmap(Page::containing_address(virt + j * PAGE_SIZE));
+                }
+                vmalloc::free_range(virt);
+                unsafe { buddy::free(PhysFrame::containing_address(phys), order) };
+                return Err(DmaError::MapFailed);
+            }
+        }
+        virt
+    };
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, bytes as usize) };
+    Ok(DmaBuffer { virt, phys, len, order, mode })
+}
+
+/// Allocate a buffer in every cache mode and check its addresses and page flags.
+pub fn self_test() -> bool {
+    let modes = [CacheMode::WriteBack, CacheMode::WriteThrough, CacheMode::Uncached, CacheMode::WriteCombining];
+    let before = buddy::stats().free_frames();
+    let mut ok = true;
+    for mode in modes {
+        let len = 3 * PAGE_SIZE as usize;
+        let result = match mode {
+            CacheMode::WriteBack => alloc(len, 16 * 1024),
+            _ => alloc_with(len, 16 * 1024, mode),
+        };
+        let mut buf = match result {
+            Ok(buf) => buf,
+            Err(DmaError::Unsupported) => continue,
+            Err(_) => return false,
+        };
+        ok &= buf.phys().as_u64().is_multiple_of(16 * 1024) && buf.len() == len && buf.mode() == mode;
+        ok &= !buf.is_empty() && buf.as_mut_slice().iter().all(|&b| b == 0);
+        buf.as_mut_slice()[100] = 0x5A;
+        let last = buf.virt() + (buf.len() - 1) as u64;
+        ok &= paging::translate(last).is_some_and(|(phys, flags)| {
+            let cache = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH | PTE_PAT;
+            // Write-back buffers live in the huge-page physical map, where bit 7 means "huge".
+            buf.mode() == CacheMode::WriteBack
+                || (phys == buf.phys() + (buf.len() - 1) as u64 && flags & cache == mode.page_flags())
+        });
+        ok &= unsafe { buf.virt().as_ptr::<u8>().add(100).read_volatile() } == 0x5A;
+    }
+    ok && buddy::stats().free_frames() == before
+}
@@ -0,0 +1,44 @@
+use core::arch::x86_64::{__cpuid, _rdrand64_step, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// State of the fallback generator (splitmix64), seeded from the TSC.
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+pub fn has_rdrand() -> bool {
+    __cpuid(1).ecx & (1 << 30) != 0
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    // RDRAND can transiently fail; the usual advice is to retry a few times.
+    for _ in 0..10 {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Mix the current TSC into the state and return the next splitmix64 output.
+fn fallback() -> u64 {
+    let tsc = unsafe { _rdtsc() };
+    let mut z = STATE
+        .fetch_add(0x9E37_79B9_7F4A_7C15 ^ tsc.rotate_left(29), Ordering::Relaxed)
+        .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A random 64-bit value: hardware RNG when the CPU has one, TSC-seeded PRNG otherwise.
+///
+/// Good enough for layout randomization; not for key material.
+pub fn random_u64() -> u64 {
+    if has_rdrand() {
+        if let Some(value) = unsafe { rdrand() } {
+            return value;
+        }
+    }
+    fallback()
+}
@@ -0,0 +1,230 @@
+//! devfs: devices as files, mounted at `/dev`. Nothing is stored; each
+//! name stands for a device, and reading or writing it talks to the device:
+//!
+//! - `console`: what user programs get as 0, 1 and 2, a line at a time.
+//! - `ttyS0`: COM1 raw, bytes as they arrive, no echo or line editing.
+//! - `null` reads as empty and swallows writes; `zero` reads as zeros;
+//!   `random` reads as random bytes (see `entropy`). Writes to them are
+//!   taken and dropped.
+//! - Every block device by its name (`vd0`, `hd0p1`, ...), read and written
+//!   at any byte offset through the block cache, so what a mounted
+//!   filesystem has cached is what the file shows.
+//!
+//! Character devices have no size and no offset: reads and writes ignore
+//! it, and they can't seek.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode, Metadata, Node};
+use super::FsError;
+use crate::block::{self, BlockDevice, RamDisk};
+use crate::{console, entropy, serial};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharDevice {
+    Console,
+    Serial,
+    Null,
+    Zero,
+    Random,
+}
+
+const CHAR_DEVICES: [(&str, CharDevice); 5] = [
+    ("console", CharDevice::Console),
+    ("null", CharDevice::Null),
+    ("random", CharDevice::Random),
+    ("ttyS0", CharDevice::Serial),
+    ("zero", CharDevice::Zero),
+];
+
+impl Inode for CharDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::CharDevice, mode: 0o666, size: 0 }
+    }
+}
+
+impl File for CharDevice {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self {
+            CharDevice::Console => Ok(console::read(buf)),
+            CharDevice::Serial => {
+                // Wait for a byte, then take what else has come.
+                buf[0] = serial::read_byte();
+                let mut n = 1;
+                while n < buf.len() {
+                    let Some(byte) = serial::pop_byte() else { break };
+                    buf[n] = byte;
+                    n += 1;
+                }
+                Ok(n)
+            }
+            CharDevice::Null => Ok(0),
+            CharDevice::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            CharDevice::Random => {
+                for chunk in buf.chunks_mut(8) {
+                    chunk.copy_from_slice(&entropy::random_u64().to_le_bytes()[..chunk.len()]);
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        match self {
+            CharDevice::Console => console::write(data),
+            CharDevice::Serial => serial::write_bytes(data),
+            CharDevice::Null | CharDevice::Zero | CharDevice::Random => {}
+        }
+        Ok(data.len())
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+}
+
+/// A block device as a file of `block_count * block_size` bytes.
+struct BlockFile {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockFile {
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+
+    /// Hand `f` each block from byte `offset` for `len` bytes, read into a
+    /// buffer, with the range of it in the transfer and the transfer's
+    /// range. `f` says whether to write the block back.
+    fn each_block(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], core::ops::Range<usize>, core::ops::Range<usize>) -> bool,
+    ) -> Result<usize, FsError> {
+        let size = self.device.block_size();
+        let len = len.min(self.size().saturating_sub(offset) as usize);
+        let mut block = vec![0u8; size];
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let number = at / size as u64;
+            let start = (at % size as u64) as usize;
+            let n = (size - start).min(len - done);
+            self.device.read_blocks(number, &mut block)?;
+            if f(&mut block, start..start + n, done..done + n) {
+                self.device.write_blocks(number, &block)?;
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+}
+
+impl Inode for BlockFile {
+    fn metadata(&self) -> Metadata {
+        let mode = if self.device.read_only() { 0o444 } else { 0o660 };
+        Metadata { kind: FileType::BlockDevice, mode, size: self.size() }
+    }
+}
+
+impl File for BlockFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.each_block(offset, buf.len(), |block, from, to| {
+            buf[to].copy_from_slice(&block[from]);
+            false
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if self.device.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        self.each_block(offset, data.len(), |block, to, from| {
+            block[to].copy_from_slice(&data[from]);
+            true
+        })
+    }
+
+    fn writable(&self) -> bool {
+        !self.device.read_only()
+    }
+}
+
+/// `/dev` itself.
+struct DevDir;
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::Directory, mode: 0o755, size: 0 }
+    }
+}
+
+impl Dir for DevDir {
+    fn lookup(&self, name: &str) -> Result<Node, FsError> {
+        if let Some(&(_, device)) = CHAR_DEVICES.iter().find(|(n, _)| *n == name) {
+            return Ok(Node::File(Arc::new(device)));
+        }
+        let device = block::cache::get(name).ok_or(FsError::NotFound)?;
+        Ok(Node::File(Arc::new(BlockFile { device })))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let chars = CHAR_DEVICES.iter().map(|(name, device)| DirEntry { name: String::from(*name), metadata: device.metadata() });
+        let blocks = block::names().into_iter().filter_map(|name| {
+            let device = block::get(&name)?;
+            Some(DirEntry { name, metadata: BlockFile { device }.metadata() })
+        });
+        Ok(chars.chain(blocks).collect())
+    }
+}
+
+/// Mount devfs at `/dev`.
+pub fn init() {
+    vfs::mount("/dev", "devfs", "devfs", Arc::new(DevDir));
+}
+
+/// The character devices that don't wait for input: `null` is empty and
+/// takes writes, `zero` fills, `random` differs from read to read. A
+/// block device reads and writes across a block boundary and stops at
+/// its end. `/dev` lists every character device.
+pub fn self_test() -> bool {
+    let read = |name: &str, buf: &mut [u8]| match DevDir.lookup(name) {
+        Ok(Node::File(file)) => file.read_at(0, buf).ok(),
+        _ => None,
+    };
+    let mut buf = [0xFFu8; 16];
+    let mut other = [0u8; 16];
+    let mut ok = read("null", &mut buf) == Some(0)
+        && CharDevice::Null.write_at(0, b"gone") == Ok(4)
+        && read("zero", &mut buf) == Some(16)
+        && buf == [0; 16]
+        && read("random", &mut buf) == Some(16)
+        && read("random", &mut other) == Some(16)
+        && buf != other
+        && read("zero", &mut []) == Some(0)
+        && matches!(DevDir.lookup("nothing"), Err(FsError::NotFound));
+    ok &= DevDir.read_dir().is_ok_and(|entries| {
+        CHAR_DEVICES.iter().all(|(name, _)| entries.iter().any(|entry| entry.name == *name && entry.metadata.kind == FileType::CharDevice))
+    });
+
+    let Some(disk) = RamDisk::new(512, 4) else { return false };
+    let file = BlockFile { device: Arc::new(disk) };
+    let mut back = [0u8; 6];
+    ok && file.metadata().size == 2048
+        && file.write_at(509, b"across") == Ok(6)
+        && file.read_at(509, &mut back) == Ok(6)
+        && &back == b"across"
+        && file.read_at(2046, &mut back) == Ok(2)
+        && file.read_at(2048, &mut back) == Ok(0)
+        && file.write_at(2047, b"ab") == Ok(1)
+}
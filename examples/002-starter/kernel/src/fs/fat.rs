@@ -0,0 +1,1319 @@
+//! FAT: the filesystem of MS-DOS, still everywhere because everything can
+//! read it. The disk is split into clusters (a few sectors each); the File
+//! Allocation Table has one entry per cluster, holding the number of the
+//! next cluster of the same file, so a file is a linked list through the
+//! table starting from the cluster its directory entry names. A directory
+//! is a file of 32-byte entries: an 8.3 name, attributes, first cluster
+//! and size. Long names hide in extra entries just before the short one,
+//! 13 UTF-16 characters each, that old systems skip as volume labels.
+//!
+//! The layout, in sectors: the boot sector with the BIOS Parameter Block
+//! (BPB) describing the rest, other reserved sectors, the FAT (twice, as a
+//! spare), on FAT16 a fixed root directory, then the clusters, numbered
+//! from 2. FAT16 and FAT32 differ in the width of a table entry and in
+//! FAT32's root directory being an ordinary cluster chain. Which one a
+//! volume is depends only on how many clusters it has.
+//!
+//! Writes go in an order that a crash part-way through can leak clusters
+//! but never leave a file pointing at clusters that aren't its own: a file
+//! grows by writing the data into free clusters, then marking them in the
+//! FAT, then (the device flushed between steps) its directory entry's new
+//! size; it shrinks entry first, FAT after. Each FAT volume found at boot
+//! is mounted by its device's name (`hd0p1`, `vd0`, ...), and in the VFS
+//! at `/mnt/<name>`.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode, Metadata};
+use super::{components, FsError};
+use crate::block::{self, BlockDevice, RamDisk};
+use crate::sync::{Mutex, RwLock};
+use crate::{serial_println, time};
+
+const BOOT_SIGNATURE: u16 = 0xAA55;
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Attribute bits.
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Set whenever a file changes, for backup programs.
+const ATTR_ARCHIVE: u8 = 0x20;
+/// What a long-name entry has for attributes.
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First name byte of a free entry, and of the entry after the last.
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+/// Long-name entries: the last (first on disk) has this in its sequence
+/// number; 13 characters each.
+const LONG_LAST: u8 = 0x40;
+const LONG_CHARS: usize = 13;
+/// Byte 12 of a short entry: base name or extension shown in lowercase.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// Characters a short name may hold besides letters and digits.
+const SHORT_NAME_SYMBOLS: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters no name may hold.
+const INVALID_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+const MAX_NAME_UNITS: usize = 255;
+
+/// The FSInfo sector of FAT32: signatures, then the free cluster count and
+/// where to look for one, both hints that may be 0xFFFFFFFF, "unknown".
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_FREE: usize = 488;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// Volumes with fewer clusters than these are FAT12, FAT16.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Table entries at or above this end a chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    fn entry_size(self) -> usize {
+        match self {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        }
+    }
+}
+
+/// Where a directory entry is: its sector, and its offset in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    sector: u64,
+    offset: usize,
+}
+
+/// A file or directory, as its directory entry describes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The long name if it has one, else the 8.3 name.
+    pub name: String,
+    short_name: String,
+    attr: u8,
+    /// First cluster; 0 for an empty file, and for FAT16's root directory.
+    cluster: u32,
+    pub size: u32,
+    /// Its short entry; the root directory has none.
+    entry: Option<Location>,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// A mounted FAT volume.
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    sector_size: usize,
+    /// Device blocks in a sector.
+    blocks_per_sector: u64,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    /// Copies of the FAT.
+    fats: u64,
+    /// FAT16's root directory: its first sector and how many.
+    root_start: u64,
+    root_sectors: u64,
+    /// FAT32's root directory: its first cluster.
+    root_cluster: u32,
+    data_start: u64,
+    clusters: u32,
+    label: String,
+    /// FAT32's FSInfo sector, if it has a good one.
+    fsinfo: Option<u64>,
+    /// Held by whatever changes the volume.
+    alloc: Mutex<Alloc>,
+    /// The nodes the VFS has handles to, by where their entries are: every
+    /// handle to a file shares one.
+    nodes: Mutex<BTreeMap<Location, Weak<Mutex<Node>>>>,
+}
+
+struct Alloc {
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// Whether FSInfo's hints were marked unknown, as they are once we
+    /// allocate without keeping them up to date.
+    fsinfo_stale: bool,
+}
+
+/// A directory's contents, and the sector each of its sectors' worth of
+/// bytes came from.
+struct DirContents {
+    bytes: Vec<u8>,
+    sectors: Vec<u64>,
+    /// Its clusters; none for FAT16's root directory.
+    clusters: Vec<u32>,
+    sector_size: usize,
+}
+
+impl DirContents {
+    fn location(&self, index: usize) -> Location {
+        let at = index * DIR_ENTRY_SIZE;
+        Location { sector: self.sectors[at / self.sector_size], offset: at % self.sector_size }
+    }
+
+    fn entries(&self) -> usize {
+        self.bytes.len() / DIR_ENTRY_SIZE
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        matches!(self.bytes[index * DIR_ENTRY_SIZE], ENTRY_FREE | ENTRY_END)
+    }
+}
+
+/// The long name being put together from the entries before a short one.
+struct LongName {
+    /// Characters, in on-disk chunks of 13, the last chunk first.
+    chunks: Vec<[u16; LONG_CHARS]>,
+    checksum: u8,
+    /// The sequence number the next entry must have; the short entry comes
+    /// after the one numbered 1.
+    next: u8,
+}
+
+/// The checksum of a short name its long-name entries carry, so a long
+/// name left behind by a system that didn't know them is noticed.
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// A date and time as FAT stores them: (date, time), to two seconds.
+fn fat_timestamp() -> (u16, u16) {
+    let now = time::now();
+    let date = now.year.saturating_sub(1980) << 9 | (now.month as u16) << 5 | now.day as u16;
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second / 2) as u16;
+    (date, time)
+}
+
+/// `NAME    TXT` as `NAME.TXT`, or `name.txt` as the case bits say.
+fn short_name(entry: &[u8]) -> String {
+    let decode = |bytes: &[u8], lower: bool| -> String {
+        let text = bytes.iter().map(|&b| b as char).collect::<String>();
+        let text = String::from(text.trim_end_matches(' '));
+        if lower {
+            text.to_ascii_lowercase()
+        } else {
+            text
+        }
+    };
+    let mut raw = [0u8; 11];
+    raw.copy_from_slice(&entry[..11]);
+    // A real first byte of 0xE5 is stored as 0x05, 0xE5 meaning free.
+    if raw[0] == 0x05 {
+        raw[0] = ENTRY_FREE;
+    }
+    let base = decode(&raw[..8], entry[12] & CASE_LOWER_BASE != 0);
+    let ext = decode(&raw[8..], entry[12] & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The entries of a directory's contents, long names joined to their short
+/// entries; `.`, `..`, the volume label and free entries left out.
+fn parse_dir(dir: &DirContents) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (index, entry) in dir.bytes.as_chunks::<DIR_ENTRY_SIZE>().0.iter().enumerate() {
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attr = entry[11];
+        if attr & 0x3F == ATTR_LONG_NAME {
+            let sequence = entry[0] & 0x1F;
+            let mut chunk = [0u16; LONG_CHARS];
+            let units = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+            for (c, offset) in chunk.iter_mut().zip(units) {
+                *c = u16_at(entry, offset);
+            }
+            if entry[0] & LONG_LAST != 0 {
+                long = Some(LongName { chunks: vec![chunk], checksum: entry[13], next: sequence.wrapping_sub(1) });
+            } else if let Some(name) = long.as_mut().filter(|name| sequence != 0 && name.next == sequence && name.checksum == entry[13]) {
+                name.chunks.push(chunk);
+                name.next -= 1;
+            } else {
+                long = None;
+            }
+            continue;
+        }
+        let long_name = long.take().filter(|name| name.next == 0 && name.checksum == short_name_checksum(entry));
+        if attr & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short = short_name(entry);
+        if short == "." || short == ".." {
+            continue;
+        }
+        let name = match long_name {
+            Some(name) => {
+                let units = name.chunks.iter().rev().flatten().copied().take_while(|&c| c != 0);
+                char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+            }
+            None => short.clone(),
+        };
+        nodes.push(Node {
+            name,
+            short_name: short,
+            attr,
+            cluster: (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32,
+            size: u32_at(entry, 28),
+            entry: Some(dir.location(index)),
+        });
+    }
+    nodes
+}
+
+/// A short directory entry for an empty file or a directory.
+fn short_entry(name: &[u8; 11], attr: u8, case: u8, cluster: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[12] = case;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry
+}
+
+/// The long-name entries for `name` on short name `short`, in disk order:
+/// the end of the name first.
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    units.push(0);
+    units.resize(units.len().div_ceil(LONG_CHARS) * LONG_CHARS, 0xFFFF);
+    let count = units.len() / LONG_CHARS;
+    let offsets: Vec<usize> = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2)).collect();
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0u8; DIR_ENTRY_SIZE];
+            entry[0] = sequence as u8 | if sequence == count { LONG_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = short_name_checksum(short);
+            for (unit, &offset) in units[(sequence - 1) * LONG_CHARS..].iter().zip(&offsets) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+impl FatFs {
+    /// Read the boot sector of `device` and check that it describes a FAT16
+    /// or FAT32 volume that fits on it.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        if device.block_count() == 0 {
+            return Err(FsError::Unsupported("empty device"));
+        }
+        let mut boot = vec![0u8; device.block_size()];
+        device.read_blocks(0, &mut boot)?;
+        if u16_at(&boot, 510) != BOOT_SIGNATURE || !matches!(boot[0], 0xEB | 0xE9) {
+            return Err(FsError::Unsupported("not a FAT boot sector"));
+        }
+        let sector_size = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            size => size as u64,
+        };
+        if !(512..=4096).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+            || sector_size < device.block_size()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Unsupported("not a FAT boot sector"));
+        }
+        let blocks_per_sector = (sector_size / device.block_size()) as u64;
+        if total * blocks_per_sector > device.block_count() {
+            return Err(FsError::Corrupt("volume larger than its device"));
+        }
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE as u64).div_ceil(sector_size as u64);
+        let data_start = reserved + fats * fat_sectors + root_sectors;
+        if total <= data_start {
+            return Err(FsError::Corrupt("no room for clusters"));
+        }
+        let clusters = ((total - data_start) / sectors_per_cluster) as u32;
+        let fat_type = match clusters {
+            0..FAT12_MAX_CLUSTERS => return Err(FsError::Unsupported("FAT12")),
+            FAT12_MAX_CLUSTERS..FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, label_at) = match fat_type {
+            FatType::Fat16 => (0, 43),
+            FatType::Fat32 => (u32_at(&boot, 44), 71),
+        };
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err(FsError::Corrupt("root directory doesn't match the FAT type"));
+        }
+        if fat_sectors * sector_size as u64 / (fat_type.entry_size() as u64) < clusters as u64 + 2 {
+            return Err(FsError::Corrupt("FAT too small for the clusters"));
+        }
+        let label: String = boot[label_at..label_at + 11].iter().map(|&b| b as char).collect();
+        let label = match label.trim_end() {
+            "NO NAME" => String::new(),
+            label => String::from(label),
+        };
+        let mut fs = FatFs {
+            device,
+            fat_type,
+            sector_size,
+            blocks_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            fats,
+            root_start: reserved + fats * fat_sectors,
+            root_sectors,
+            root_cluster,
+            data_start,
+            clusters,
+            label,
+            fsinfo: None,
+            alloc: Mutex::new(Alloc { next_free: 2, fsinfo_stale: false }),
+            nodes: Mutex::new(BTreeMap::new()),
+        };
+        if fat_type == FatType::Fat32 {
+            if !fs.is_data_cluster(root_cluster) {
+                return Err(FsError::Corrupt("bad root cluster"));
+            }
+            let sector = u16_at(&boot, 48) as u64;
+            let mut info = vec![0u8; sector_size];
+            if (1..reserved).contains(&sector) {
+                fs.read_sectors(sector, &mut info)?;
+                if u32_at(&info, 0) == FSINFO_LEAD && u32_at(&info, 484) == FSINFO_STRUCT {
+                    fs.fsinfo = Some(sector);
+                }
+            }
+        }
+        Ok(fs)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sector_size * self.sectors_per_cluster as usize
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(self.device.read_blocks(sector * self.blocks_per_sector, buf)?)
+    }
+
+    /// The table entry of `cluster`: the next cluster of its chain.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = cluster as usize * self.fat_type.entry_size();
+        let mut sector = vec![0u8; self.sector_size];
+        self.read_sectors(self.fat_start + (offset / self.sector_size) as u64, &mut sector)?;
+        let at = offset % self.sector_size;
+        Ok(match self.fat_type {
+            FatType::Fat16 => u16_at(&sector, at) as u32,
+            FatType::Fat32 => u32_at(&sector, at) & 0x0FFF_FFFF,
+        })
+    }
+
+    /// The clusters of the chain starting at `first`, in order.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < self.fat_type.end_of_chain() {
+            if !self.is_data_cluster(cluster) {
+                return Err(FsError::Corrupt("chain through a cluster that isn't data"));
+            }
+            // A chain longer than the volume loops.
+            if clusters.len() > self.clusters as usize {
+                return Err(FsError::Corrupt("cluster chain loops"));
+            }
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_sectors(self.cluster_sector(cluster), buf)
+    }
+
+    pub fn root(&self) -> Node {
+        Node {
+            name: String::from("/"),
+            short_name: String::new(),
+            attr: ATTR_DIRECTORY,
+            cluster: self.root_cluster,
+            size: 0,
+            entry: None,
+        }
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn dir_contents(&self, dir: &Node) -> Result<DirContents, FsError> {
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        // Cluster 0 is FAT16's root directory (`..` entries name it so too).
+        if dir.cluster == 0 && self.fat_type == FatType::Fat16 {
+            let mut bytes = vec![0u8; self.root_sectors as usize * self.sector_size];
+            self.read_sectors(self.root_start, &mut bytes)?;
+            let sectors = (self.root_start..self.root_start + self.root_sectors).collect();
+            return Ok(DirContents { bytes, sectors, clusters: Vec::new(), sector_size: self.sector_size });
+        }
+        let clusters = self.chain(dir.cluster)?;
+        let mut bytes = vec![0u8; clusters.len() * self.cluster_size()];
+        for (cluster, buf) in clusters.iter().zip(bytes.chunks_mut(self.cluster_size())) {
+            self.read_cluster(*cluster, buf)?;
+        }
+        let sectors = clusters
+            .iter()
+            .flat_map(|&cluster| (0..self.sectors_per_cluster).map(move |i| self.cluster_sector(cluster) + i))
+            .collect();
+        Ok(DirContents { bytes, sectors, clusters, sector_size: self.sector_size })
+    }
+
+    /// The files and directories in directory `dir`.
+    pub fn read_dir(&self, dir: &Node) -> Result<Vec<Node>, FsError> {
+        Ok(parse_dir(&self.dir_contents(dir)?))
+    }
+
+    /// The node at `path`, from the root; names match whatever their case,
+    /// long or short.
+    pub fn lookup(&self, path: &str) -> Result<Node, FsError> {
+        let mut node = self.root();
+        for name in components(path) {
+            node = self.child(&node, name)?;
+        }
+        Ok(node)
+    }
+
+    fn child(&self, dir: &Node, name: &str) -> Result<Node, FsError> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|child| child.name.eq_ignore_ascii_case(name) || child.short_name.eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Read file `node` from byte `offset` into `buf`; how many bytes,
+    /// fewer than asked at the end of the file.
+    pub fn read(&self, node: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if node.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let cluster_size = self.cluster_size();
+        let chain = self.chain(node.cluster)?;
+        if (chain.len() * cluster_size) < (offset as usize + len) {
+            return Err(FsError::Corrupt("file shorter than its size"));
+        }
+        let mut cluster_buf = vec![0u8; cluster_size];
+        let mut done = 0;
+        while done < len {
+            let at = offset as usize + done;
+            self.read_cluster(chain[at / cluster_size], &mut cluster_buf)?;
+            let start = at % cluster_size;
+            let n = (cluster_size - start).min(len - done);
+            buf[done..done + n].copy_from_slice(&cluster_buf[start..start + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// The whole of file `node`.
+    pub fn read_all(&self, node: &Node) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0u8; node.size as usize];
+        let n = self.read(node, 0, &mut data)?;
+        data.truncate(n);
+        Ok(data)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), FsError> {
+        Ok(self.device.write_blocks(sector * self.blocks_per_sector, data)?)
+    }
+
+    /// Make `cluster`'s table entry `value`, in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = cluster as usize * self.fat_type.entry_size();
+        let (sector, at) = ((offset / self.sector_size) as u64, offset % self.sector_size);
+        let mut buf = vec![0u8; self.sector_size];
+        for copy in 0..self.fats {
+            let sector = self.fat_start + copy * self.fat_sectors + sector;
+            self.read_sectors(sector, &mut buf)?;
+            match self.fat_type {
+                FatType::Fat16 => buf[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+                // The top four bits are reserved, and kept.
+                FatType::Fat32 => {
+                    let value = u32_at(&buf, at) & 0xF000_0000 | value & 0x0FFF_FFFF;
+                    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            self.write_sectors(sector, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Up to `limit` free clusters, looking from `alloc.next_free` on and
+    /// round to where it started.
+    fn free_clusters(&self, alloc: &Alloc, limit: usize) -> Result<Vec<u32>, FsError> {
+        let mut free = Vec::new();
+        let per_sector = self.sector_size / self.fat_type.entry_size();
+        let mut buf = vec![0u8; self.sector_size];
+        let mut loaded = None;
+        for i in 0..self.clusters {
+            if free.len() == limit {
+                break;
+            }
+            let cluster = 2 + (alloc.next_free - 2 + i) % self.clusters;
+            let sector = cluster as usize / per_sector;
+            if loaded != Some(sector) {
+                self.read_sectors(self.fat_start + sector as u64, &mut buf)?;
+                loaded = Some(sector);
+            }
+            let at = cluster as usize % per_sector * self.fat_type.entry_size();
+            let value = match self.fat_type {
+                FatType::Fat16 => u16_at(&buf, at) as u32,
+                FatType::Fat32 => u32_at(&buf, at) & 0x0FFF_FFFF,
+            };
+            if value == 0 {
+                free.push(cluster);
+            }
+        }
+        Ok(free)
+    }
+
+    /// `count` free clusters to use; nothing is marked yet.
+    fn find_free(&self, alloc: &mut Alloc, count: usize) -> Result<Vec<u32>, FsError> {
+        let free = self.free_clusters(alloc, count)?;
+        if free.len() < count {
+            return Err(FsError::NoSpace);
+        }
+        if let Some(&last) = free.last() {
+            alloc.next_free = if last + 1 < self.clusters + 2 { last + 1 } else { 2 };
+        }
+        // We don't keep FSInfo's count up to date: say it is unknown, so
+        // the next system to mount the volume counts again.
+        if let (Some(sector), false) = (self.fsinfo, alloc.fsinfo_stale) {
+            let mut info = vec![0u8; self.sector_size];
+            self.read_sectors(sector, &mut info)?;
+            info[FSINFO_FREE..FSINFO_FREE + 4].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+            info[FSINFO_FREE + 4..FSINFO_FREE + 8].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+            self.write_sectors(sector, &info)?;
+            alloc.fsinfo_stale = true;
+        }
+        Ok(free)
+    }
+
+    /// Make `clusters` a chain, and hang it on `after`, the end of an
+    /// existing one, if there is one. The new chain is whole before
+    /// anything points to it.
+    fn link(&self, after: Option<u32>, clusters: &[u32]) -> Result<(), FsError> {
+        let end = self.fat_type.end_of_chain() | 0xF;
+        for (i, &cluster) in clusters.iter().enumerate().rev() {
+            self.set_fat_entry(cluster, clusters.get(i + 1).copied().unwrap_or(end))?;
+        }
+        match (after, clusters.first()) {
+            (Some(after), Some(&first)) => self.set_fat_entry(after, first),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `len` bytes from byte `at` of the file on `chain`: `data`, or
+    /// zeros without it.
+    fn write_span(&self, chain: &[u32], at: u64, len: usize, data: Option<&[u8]>) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let mut buf = vec![0u8; cluster_size];
+        let mut done = 0;
+        while done < len {
+            let pos = at as usize + done;
+            let cluster = chain[pos / cluster_size];
+            let start = pos % cluster_size;
+            let n = (cluster_size - start).min(len - done);
+            if n < cluster_size {
+                self.read_cluster(cluster, &mut buf)?;
+            }
+            match data {
+                Some(data) => buf[start..start + n].copy_from_slice(&data[done..done + n]),
+                None => buf[start..start + n].fill(0),
+            }
+            self.write_sectors(self.cluster_sector(cluster), &buf)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Write the 32 bytes of a directory entry at `location`.
+    fn write_entry(&self, location: Location, entry: &[u8; DIR_ENTRY_SIZE]) -> Result<(), FsError> {
+        let mut sector = vec![0u8; self.sector_size];
+        self.read_sectors(location.sector, &mut sector)?;
+        sector[location.offset..location.offset + DIR_ENTRY_SIZE].copy_from_slice(entry);
+        self.write_sectors(location.sector, &sector)
+    }
+
+    /// Store `node`'s first cluster and size in its directory entry, with
+    /// the time it was changed.
+    fn store_entry(&self, node: &Node) -> Result<(), FsError> {
+        let location = node.entry.ok_or(FsError::Unsupported("the root directory has no entry"))?;
+        let mut sector = vec![0u8; self.sector_size];
+        self.read_sectors(location.sector, &mut sector)?;
+        let entry = &mut sector[location.offset..location.offset + DIR_ENTRY_SIZE];
+        let (date, time) = fat_timestamp();
+        entry[11] |= ATTR_ARCHIVE;
+        entry[20..22].copy_from_slice(&((node.cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(node.cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+        self.write_sectors(location.sector, &sector)
+    }
+
+    /// Write `data` to file `node` from byte `offset`, growing it as needed
+    /// (a gap past its end reads as zeros); `node` is updated to match.
+    pub fn write(&self, node: &mut Node, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if node.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let mut alloc = self.alloc.lock();
+        self.write_locked(&mut alloc, node, offset, data)?;
+        Ok(data.len())
+    }
+
+    fn write_locked(&self, alloc: &mut Alloc, node: &mut Node, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let old_size = node.size as u64;
+        let end = old_size.max(offset + data.len() as u64);
+        let Ok(new_size) = u32::try_from(end) else {
+            return Err(FsError::Unsupported("files of 4 GiB or more"));
+        };
+        if data.is_empty() && end == old_size {
+            return Ok(());
+        }
+        let mut chain = self.chain(node.cluster)?;
+        let needed = end.div_ceil(self.cluster_size() as u64) as usize;
+        let new = match needed.checked_sub(chain.len()) {
+            Some(count) if count > 0 => self.find_free(alloc, count)?,
+            _ => Vec::new(),
+        };
+        let last = chain.last().copied();
+        chain.extend_from_slice(&new);
+
+        // The data, into clusters the file has or that nothing uses yet.
+        if offset > old_size {
+            self.write_span(&chain, old_size, (offset - old_size) as usize, None)?;
+        }
+        self.write_span(&chain, offset, data.len(), Some(data))?;
+        // Then the FAT, then the entry.
+        self.link(last, &new)?;
+        self.device.flush()?;
+        node.size = new_size;
+        if node.cluster == 0 {
+            node.cluster = chain.first().copied().unwrap_or(0);
+        }
+        self.store_entry(node)?;
+        Ok(self.device.flush()?)
+    }
+
+    /// Make file `node` `size` bytes long: cut it, freeing the clusters it
+    /// no longer needs, or grow it with zeros.
+    pub fn truncate(&self, node: &mut Node, size: u64) -> Result<(), FsError> {
+        if node.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let mut alloc = self.alloc.lock();
+        if size >= node.size as u64 {
+            return self.write_locked(&mut alloc, node, size, &[]);
+        }
+        let chain = self.chain(node.cluster)?;
+        let keep = size.div_ceil(self.cluster_size() as u64) as usize;
+        // The entry first: then the file is never longer than its chain.
+        node.size = size as u32;
+        if keep == 0 {
+            node.cluster = 0;
+        }
+        self.store_entry(node)?;
+        self.device.flush()?;
+        if keep > 0 && keep < chain.len() {
+            self.set_fat_entry(chain[keep - 1], self.fat_type.end_of_chain() | 0xF)?;
+        }
+        for &cluster in chain.iter().skip(keep) {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        if let Some(&first) = chain.get(keep) {
+            alloc.next_free = alloc.next_free.min(first);
+        }
+        Ok(self.device.flush()?)
+    }
+
+    /// A short name for `name` in a directory holding `existing`: `name`
+    /// itself if it is a valid 8.3 name in one case (the case bits record
+    /// which), else one like `LONGNA~1.TXT`, then with a long name.
+    fn short_name_for(name: &str, existing: &[Node]) -> ([u8; 11], u8, bool) {
+        let (base, ext) = match name.rfind('.') {
+            Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+            _ => (name, ""),
+        };
+        let valid = |c: u8| c.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&c);
+        let one_case = |part: &str| !(part.bytes().any(|c| c.is_ascii_lowercase()) && part.bytes().any(|c| c.is_ascii_uppercase()));
+        let mut short = [b' '; 11];
+        if (1..=8).contains(&base.len())
+            && ext.len() <= 3
+            && base.bytes().chain(ext.bytes()).all(valid)
+            && one_case(base)
+            && one_case(ext)
+        {
+            short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+            let mut case = 0;
+            if base.bytes().any(|c| c.is_ascii_lowercase()) {
+                case |= CASE_LOWER_BASE;
+            }
+            if ext.bytes().any(|c| c.is_ascii_lowercase()) {
+                case |= CASE_LOWER_EXT;
+            }
+            return (short, case, false);
+        }
+        let squash = |part: &str, max: usize| -> Vec<u8> {
+            part.bytes().filter(|&c| valid(c)).map(|c| c.to_ascii_uppercase()).take(max).collect()
+        };
+        let mut basis = squash(base, 6);
+        if basis.is_empty() {
+            basis.push(b'_');
+        }
+        let ext = squash(ext, 3);
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        for n in 1.. {
+            let tail = format!("~{}", n);
+            let keep = basis.len().min(8 - tail.len());
+            short[..8].fill(b' ');
+            short[..keep].copy_from_slice(&basis[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            let taken = short_name(&short_entry(&short, 0, 0, 0));
+            if existing.iter().all(|node| !node.short_name.eq_ignore_ascii_case(&taken)) {
+                break;
+            }
+        }
+        (short, 0, true)
+    }
+
+    /// Add an entry for `name` to directory `dir`, with `attr` and first
+    /// cluster `cluster`: its long-name entries, then its short one, in a
+    /// run of free entries, growing the directory if there isn't one.
+    fn add_entry(&self, alloc: &mut Alloc, dir: &Node, name: &str, attr: u8, cluster: u32) -> Result<Node, FsError> {
+        let units = name.encode_utf16().count();
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || units > MAX_NAME_UNITS
+            || name.ends_with(['.', ' '])
+            || name.chars().any(|c| c < ' ' || INVALID_CHARS.contains(&c))
+        {
+            return Err(FsError::InvalidName);
+        }
+        let mut contents = self.dir_contents(dir)?;
+        let existing = parse_dir(&contents);
+        if existing.iter().any(|node| node.name.eq_ignore_ascii_case(name) || node.short_name.eq_ignore_ascii_case(name)) {
+            return Err(FsError::Exists);
+        }
+        let (short, case, long) = Self::short_name_for(name, &existing);
+        let mut entries = if long { long_name_entries(name, &short) } else { Vec::new() };
+        let mut entry = short_entry(&short, attr | ATTR_ARCHIVE, case, cluster);
+        let (date, time) = fat_timestamp();
+        for at in [14, 22] {
+            entry[at..at + 2].copy_from_slice(&time.to_le_bytes());
+            entry[at + 2..at + 4].copy_from_slice(&date.to_le_bytes());
+        }
+        entry[18..20].copy_from_slice(&date.to_le_bytes());
+        entries.push(entry);
+
+        let run = entries.len();
+        let start = (0..contents.entries()).find(|&i| i + run <= contents.entries() && (i..i + run).all(|j| contents.is_free(j)));
+        let start = match start {
+            Some(start) => start,
+            None if contents.clusters.is_empty() => return Err(FsError::NoSpace),
+            None => {
+                // Free entries at the end count towards the run.
+                let tail = (0..contents.entries()).rev().take_while(|&i| contents.is_free(i)).count();
+                let per_cluster = self.cluster_size() / DIR_ENTRY_SIZE;
+                let count = (run - tail).div_ceil(per_cluster);
+                let new = self.find_free(alloc, count)?;
+                let zeros = vec![0u8; self.cluster_size()];
+                for &cluster in &new {
+                    self.write_sectors(self.cluster_sector(cluster), &zeros)?;
+                    contents.sectors.extend((0..self.sectors_per_cluster).map(|i| self.cluster_sector(cluster) + i));
+                }
+                self.link(contents.clusters.last().copied(), &new)?;
+                self.device.flush()?;
+                contents.entries() - tail
+            }
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            self.write_entry(contents.location(start + i), entry)?;
+        }
+        self.device.flush()?;
+        Ok(Node {
+            name: String::from(name),
+            short_name: short_name(&entry),
+            attr: attr | ATTR_ARCHIVE,
+            cluster,
+            size: 0,
+            entry: Some(contents.location(start + run - 1)),
+        })
+    }
+
+    /// Create an empty file `name` in directory `dir`.
+    pub fn create(&self, dir: &Node, name: &str) -> Result<Node, FsError> {
+        let mut alloc = self.alloc.lock();
+        self.add_entry(&mut alloc, dir, name, 0, 0)
+    }
+
+    /// Create directory `name` in directory `dir`: a cluster holding `.`
+    /// and `..`, then its entry.
+    pub fn create_dir(&self, dir: &Node, name: &str) -> Result<Node, FsError> {
+        let mut alloc = self.alloc.lock();
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let cluster = self.find_free(&mut alloc, 1)?[0];
+        let mut contents = vec![0u8; self.cluster_size()];
+        // `..` of a directory in the root names cluster 0, even on FAT32.
+        let parent = if dir.entry.is_none() { 0 } else { dir.cluster };
+        contents[..32].copy_from_slice(&short_entry(b".          ", ATTR_DIRECTORY, 0, cluster));
+        contents[32..64].copy_from_slice(&short_entry(b"..         ", ATTR_DIRECTORY, 0, parent));
+        self.write_sectors(self.cluster_sector(cluster), &contents)?;
+        self.link(None, &[cluster])?;
+        self.device.flush()?;
+        let node = self.add_entry(&mut alloc, dir, name, ATTR_DIRECTORY, cluster);
+        if node.is_err() {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        node
+    }
+
+    /// Its root directory, for the VFS.
+    pub fn root_dir(self: &Arc<Self>) -> Arc<dyn Dir> {
+        Arc::new(FatNode { fs: self.clone(), node: Arc::new(Mutex::new(self.root())) })
+    }
+
+    /// The node handles to `node`'s entry share: the one they have if
+    /// there are any, else `node`, shared from now on.
+    fn shared(&self, node: Node) -> Arc<Mutex<Node>> {
+        let Some(location) = node.entry else { return Arc::new(Mutex::new(node)) };
+        let mut nodes = self.nodes.lock();
+        if let Some(shared) = nodes.get(&location).and_then(Weak::upgrade) {
+            return shared;
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let shared = Arc::new(Mutex::new(node));
+        nodes.insert(location, Arc::downgrade(&shared));
+        shared
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} \"{}\", {} clusters of {} bytes",
+            self.fat_type.name(),
+            self.label,
+            self.clusters,
+            self.cluster_size()
+        )
+    }
+}
+
+/// A file or directory, as the VFS sees it: its directory entry, shared
+/// by every handle to it (`FatFs::shared`), so that a write or cut through
+/// one moves the size and first cluster for all of them.
+struct FatNode {
+    fs: Arc<FatFs>,
+    node: Arc<Mutex<Node>>,
+}
+
+impl FatNode {
+    fn new(fs: &Arc<FatFs>, node: Node) -> FatNode {
+        FatNode { fs: fs.clone(), node: fs.shared(node) }
+    }
+}
+
+fn metadata(node: &Node) -> Metadata {
+    let (kind, mode) = if node.is_dir() { (FileType::Directory, 0o755) } else { (FileType::Regular, 0o644) };
+    // The read-only attribute takes away write permission.
+    let mode = if node.attr & ATTR_READ_ONLY != 0 { mode & !0o222 } else { mode };
+    Metadata { kind, mode, size: node.size as u64 }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Metadata {
+        metadata(&self.node.lock())
+    }
+}
+
+impl File for FatNode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.fs.read(&self.node.lock(), offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.write(&mut self.node.lock(), offset, data)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.truncate(&mut self.node.lock(), size)
+    }
+
+    fn writable(&self) -> bool {
+        !self.fs.device.read_only()
+    }
+}
+
+impl Dir for FatNode {
+    fn lookup(&self, name: &str) -> Result<vfs::Node, FsError> {
+        let child = self.fs.child(&self.node.lock(), name)?;
+        Ok(if child.is_dir() {
+            vfs::Node::Dir(Arc::new(FatNode::new(&self.fs, child)))
+        } else {
+            vfs::Node::File(Arc::new(FatNode::new(&self.fs, child)))
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let nodes = self.fs.read_dir(&self.node.lock())?;
+        Ok(nodes.iter().map(|node| DirEntry { name: node.name.clone(), metadata: metadata(node) }).collect())
+    }
+
+    fn create(&self, name: &str) -> Result<Arc<dyn File>, FsError> {
+        let file = self.fs.create(&self.node.lock(), name)?;
+        Ok(Arc::new(FatNode::new(&self.fs, file)))
+    }
+
+    fn mkdir(&self, name: &str) -> Result<(), FsError> {
+        self.fs.create_dir(&self.node.lock(), name).map(|_| ())
+    }
+}
+
+/// Mounted volumes, by device name.
+static VOLUMES: RwLock<Vec<(String, Arc<FatFs>)>> = RwLock::new(Vec::new());
+
+/// Mount the FAT volume on block device `name`.
+pub fn mount(name: &str) -> Result<Arc<FatFs>, FsError> {
+    if let Some(fs) = volume(name) {
+        return Ok(fs);
+    }
+    let device = block::cache::get(name).ok_or(FsError::NotFound)?;
+    let fs = Arc::new(FatFs::mount(device)?);
+    serial_println!("fat: {}: {}", name, fs.describe());
+    VOLUMES.write().push((String::from(name), fs.clone()));
+    vfs::mount(&format!("/mnt/{}", name), "fat", name, fs.root_dir());
+    Ok(fs)
+}
+
+pub fn volume(name: &str) -> Option<Arc<FatFs>> {
+    VOLUMES.read().iter().find(|(n, _)| n == name).map(|(_, fs)| fs.clone())
+}
+
+/// Mount every block device that holds a FAT volume: the boot disk's
+/// partition, a data disk.
+pub fn probe() {
+    for name in block::names() {
+        if let Err(err @ (FsError::Corrupt(_) | FsError::Io(_))) = mount(&name) {
+            serial_println!("fat: {}: {}", name, err);
+        }
+    }
+}
+
+pub fn list() {
+    let volumes = VOLUMES.read();
+    if volumes.is_empty() {
+        return serial_println!("fat: no volumes");
+    }
+    for (name, fs) in volumes.iter() {
+        serial_println!("  {:<8} {}", name, fs.describe());
+    }
+}
+
+/// List directory `path` of the volume on `name`.
+pub fn ls(name: &str, path: &str) {
+    let Some(fs) = volume(name) else {
+        return serial_println!("fat: {} is not mounted", name);
+    };
+    match fs.lookup(path).and_then(|dir| fs.read_dir(&dir)) {
+        Ok(nodes) => {
+            for node in nodes {
+                let kind = if node.is_dir() { "dir" } else { "" };
+                serial_println!("  {:>10} {:>3}  {}", node.size, kind, node.name);
+            }
+        }
+        Err(err) => serial_println!("fat: {}: {}", path, err),
+    }
+}
+
+/// Print file `path` of the volume on `name`.
+pub fn cat(name: &str, path: &str) {
+    let Some(fs) = volume(name) else {
+        return serial_println!("fat: {} is not mounted", name);
+    };
+    match fs.lookup(path).and_then(|file| fs.read_all(&file)) {
+        Ok(data) => crate::serial::write_bytes(&data),
+        Err(err) => serial_println!("fat: {}: {}", path, err),
+    }
+}
+
+/// The directory holding `path` on `fs`, and the last name in it.
+fn parent_of<'a>(fs: &FatFs, path: &'a str) -> Result<(Node, &'a str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    Ok((fs.lookup(dir)?, name))
+}
+
+/// Make file `path` of the volume on `name` hold `data`, creating it if
+/// it isn't there.
+pub fn write_file(name: &str, path: &str, data: &[u8]) {
+    let Some(fs) = volume(name) else {
+        return serial_println!("fat: {} is not mounted", name);
+    };
+    let result = match fs.lookup(path) {
+        Err(FsError::NotFound) => parent_of(&fs, path).and_then(|(dir, file)| fs.create(&dir, file)),
+        found => found,
+    }
+    .and_then(|mut file| {
+        fs.truncate(&mut file, 0)?;
+        fs.write(&mut file, 0, data)
+    });
+    if let Err(err) = result {
+        serial_println!("fat: {}: {}", path, err);
+    }
+}
+
+/// Make directory `path` on the volume on `name`.
+pub fn mkdir(name: &str, path: &str) {
+    let Some(fs) = volume(name) else {
+        return serial_println!("fat: {} is not mounted", name);
+    };
+    if let Err(err) = parent_of(&fs, path).and_then(|(dir, new)| fs.create_dir(&dir, new)) {
+        serial_println!("fat: {}: {}", path, err);
+    }
+}
+
+/// Test volume layout: FAT16 of 8192 512-byte sectors, a cluster a sector;
+/// two FATs of 32 sectors, a 32-entry root directory.
+const TEST_SECTORS: u64 = 8192;
+const TEST_FAT: u64 = 1;
+const TEST_FAT_SECTORS: u64 = 32;
+const TEST_ROOT: u64 = TEST_FAT + 2 * TEST_FAT_SECTORS;
+const TEST_DATA: u64 = TEST_ROOT + 2;
+
+/// A FAT16 volume, built by hand on a RAM disk: in the root, a 600-byte
+/// file with a long name on clusters 3 and 5, and directory `SUB` (cluster
+/// 4) holding `hello.txt` (cluster 6), a short name shown in lowercase.
+pub(super) fn test_volume() -> Option<RamDisk> {
+    let disk = RamDisk::new(512, TEST_SECTORS)?;
+    let mut boot = [0u8; 512];
+    boot[0] = 0xEB;
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(TEST_FAT as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[17..19].copy_from_slice(&32u16.to_le_bytes());
+    boot[19..21].copy_from_slice(&(TEST_SECTORS as u16).to_le_bytes());
+    boot[22..24].copy_from_slice(&(TEST_FAT_SECTORS as u16).to_le_bytes());
+    boot[43..54].copy_from_slice(b"TEST       ");
+    boot[510..512].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+
+    let mut fat = [0u8; 512];
+    for (cluster, next) in [(0, 0xFFF8u16), (1, 0xFFFF), (3, 5), (4, 0xFFFF), (5, 0xFFFF), (6, 0xFFFF)] {
+        fat[cluster * 2..cluster * 2 + 2].copy_from_slice(&next.to_le_bytes());
+    }
+
+    let file_entry = |name: &[u8; 11], attr: u8, case: u8, cluster: u32, size: u32| {
+        let mut entry = short_entry(name, attr, case, cluster);
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    };
+    let long = *b"ALONGF~1TXT";
+    let mut root = Vec::new();
+    root.extend(file_entry(b"TEST       ", ATTR_VOLUME_ID, 0, 0, 0));
+    long_name_entries("A long file name.txt", &long).iter().for_each(|entry| root.extend(entry));
+    root.extend(file_entry(&long, 0, 0, 3, 600));
+    root.extend(file_entry(b"SUB        ", ATTR_DIRECTORY, 0, 4, 0));
+    root.resize(1024, 0);
+
+    let mut sub = Vec::new();
+    sub.extend(file_entry(b".          ", ATTR_DIRECTORY, 0, 4, 0));
+    sub.extend(file_entry(b"..         ", ATTR_DIRECTORY, 0, 0, 0));
+    sub.extend(file_entry(b"HELLO   TXT", 0, CASE_LOWER_BASE | CASE_LOWER_EXT, 6, 5));
+    sub.resize(512, 0);
+
+    let data: Vec<u8> = (0..1024).map(|i| (i * 7 + 3) as u8).collect();
+    let mut hello = [0u8; 512];
+    hello[..5].copy_from_slice(b"hello");
+
+    let cluster = |n: u64| TEST_DATA + n - 2;
+    let ok = disk.write_blocks(0, &boot).is_ok()
+        && disk.write_blocks(TEST_FAT, &fat).is_ok()
+        && disk.write_blocks(TEST_FAT + TEST_FAT_SECTORS, &fat).is_ok()
+        && disk.write_blocks(TEST_ROOT, &root).is_ok()
+        && disk.write_blocks(cluster(3), &data[..512]).is_ok()
+        && disk.write_blocks(cluster(4), &sub).is_ok()
+        && disk.write_blocks(cluster(5), &data[512..]).is_ok()
+        && disk.write_blocks(cluster(6), &hello).is_ok();
+    ok.then_some(disk)
+}
+
+/// On a volume built by hand: the boot sector reads as FAT16 with its
+/// label, the root lists a long name and a directory, paths resolve in any
+/// case and by short name, a file reads whole and from the middle across a
+/// cluster boundary, and missing names, files as directories and reading
+/// a directory fail as they should. Then writing: a new file grows over
+/// clusters and past a gap, reads back, keeps both FATs alike, and shrinks
+/// and empties giving its clusters back; names clash, are refused, or get
+/// numbered short names; a directory grows a cluster when full, and the
+/// fixed FAT16 root can't. A device with no boot sector doesn't mount.
+pub fn self_test() -> bool {
+    let Some(disk) = test_volume() else { return false };
+    let Ok(fs) = FatFs::mount(Arc::new(disk)).map(Arc::new) else { return false };
+    let mut ok = fs.fat_type == FatType::Fat16 && fs.label == "TEST";
+
+    let names: Vec<String> = match fs.read_dir(&fs.root()) {
+        Ok(nodes) => nodes.into_iter().map(|node| node.name).collect(),
+        Err(_) => return false,
+    };
+    ok &= names == ["A long file name.txt", "SUB"];
+
+    let expected: Vec<u8> = (0..600).map(|i| (i * 7 + 3) as u8).collect();
+    let Ok(file) = fs.lookup("/a LONG file name.TXT") else { return false };
+    ok &= file.size == 600 && !file.is_dir() && fs.read_all(&file).as_deref() == Ok(&expected[..]);
+    let mut middle = [0u8; 100];
+    ok &= fs.read(&file, 460, &mut middle) == Ok(100) && middle[..] == expected[460..560];
+    ok &= fs.read(&file, 590, &mut middle) == Ok(10) && fs.read(&file, 600, &mut middle) == Ok(0);
+    ok &= fs.lookup("ALONGF~1.TXT").map(|node| node.name).as_deref() == Ok("A long file name.txt");
+
+    ok &= match fs.lookup("/sub/./HELLO.txt") {
+        Ok(hello) => hello.name == "hello.txt" && fs.read_all(&hello).as_deref() == Ok(&b"hello"[..]),
+        Err(_) => false,
+    };
+    ok &= fs.lookup("/sub/missing") == Err(FsError::NotFound)
+        && fs.lookup("/ALONGF~1.TXT/x").err() == Some(FsError::NotADirectory)
+        && fs.lookup("/sub").and_then(|dir| fs.read_all(&dir)) == Err(FsError::IsADirectory);
+
+    let Ok(sub) = fs.lookup("/sub") else { return false };
+    let free = || fs.free_clusters(&fs.alloc.lock(), usize::MAX).map(|free| free.len());
+    let Ok(before) = free() else { return false };
+    let Ok(mut file) = fs.create(&sub, "new file.txt") else { return false };
+    let data: Vec<u8> = (0..1500).map(|i| (i * 11 + 1) as u8).collect();
+    ok &= fs.write(&mut file, 0, &data) == Ok(1500)
+        && file.size == 1500
+        && fs.lookup("/SUB/NEW FILE.TXT").as_ref() == Ok(&file)
+        && fs.read_all(&file).as_deref() == Ok(&data[..]);
+    ok &= fs.write(&mut file, 2000, b"end") == Ok(3)
+        && file.size == 2003
+        && fs.read_all(&file).is_ok_and(|all| {
+            all[..1500] == data[..] && all[1500..2000].iter().all(|&b| b == 0) && &all[2000..] == b"end"
+        });
+    let (mut first, mut second) = ([0u8; 512], [0u8; 512]);
+    ok &= fs.read_sectors(TEST_FAT, &mut first).is_ok()
+        && fs.read_sectors(TEST_FAT + TEST_FAT_SECTORS, &mut second).is_ok()
+        && first == second;
+    ok &= fs.truncate(&mut file, 700).is_ok()
+        && fs.lookup("/sub/new file.txt").is_ok_and(|node| node.size == 700)
+        && fs.chain(file.cluster).map(|chain| chain.len()) == Ok(2)
+        && fs.read_all(&file).as_deref() == Ok(&data[..700]);
+    ok &= fs.truncate(&mut file, 0).is_ok() && file.cluster == 0 && free() == Ok(before);
+
+    // Through the VFS, two handles to the file share its entry: what one
+    // writes, the other sees, and the clusters go back when it is emptied.
+    let open = || match fs.root_dir().lookup("sub") {
+        Ok(vfs::Node::Dir(dir)) => match dir.lookup("new file.txt") {
+            Ok(vfs::Node::File(file)) => Some(file),
+            _ => None,
+        },
+        _ => None,
+    };
+    let (Some(one), Some(two)) = (open(), open()) else { return false };
+    ok &= one.write_at(0, &data) == Ok(1500)
+        && two.metadata().size == 1500
+        && two.truncate(0).is_ok()
+        && one.write_at(0, b"again") == Ok(5)
+        && two.read_at(0, &mut middle) == Ok(5)
+        && &middle[..5] == b"again"
+        && fs.lookup("/sub/new file.txt").is_ok_and(|node| node.size == 5);
+    ok &= two.truncate(0).is_ok() && one.metadata().size == 0 && free() == Ok(before);
+
+    ok &= fs.create(&sub, "NEW FILE.TXT") == Err(FsError::Exists)
+        && fs.create(&sub, "a/b") == Err(FsError::InvalidName)
+        && fs.create(&sub, "trailing.") == Err(FsError::InvalidName);
+    ok &= fs.create(&sub, "readme.md").is_ok_and(|node| node.short_name == "readme.md")
+        && fs.create(&sub, "Long name one.txt").is_ok()
+        && fs.create(&sub, "Long name two.txt").is_ok()
+        && fs.lookup("/sub/LONGNA~2.TXT").is_ok_and(|node| node.name == "Long name two.txt");
+
+    // A cluster holds 16 entries: `.`, `..` and 20 files need two.
+    let Ok(dir) = fs.create_dir(&sub, "dir") else { return false };
+    ok &= (0..20).all(|i| fs.create(&dir, &format!("f{}", i)).is_ok())
+        && fs.read_dir(&dir).map(|nodes| nodes.len()) == Ok(20)
+        && fs.chain(dir.cluster).map(|chain| chain.len()) == Ok(2)
+        && fs.lookup("/sub/dir/F19").is_ok();
+    // The root has 32 entries, 5 of them used.
+    let root = fs.root();
+    ok &= (0..27).all(|i| fs.create(&root, &format!("r{}", i)).is_ok()) && fs.create(&root, "r27") == Err(FsError::NoSpace);
+
+    let Some(blank) = RamDisk::new(512, 64) else { return false };
+    ok && FatFs::mount(Arc::new(blank)).is_err()
+}
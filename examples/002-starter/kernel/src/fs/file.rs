@@ -0,0 +1,231 @@
+//! Files for the kernel's own use: the ELF loader reading a program, swap
+//! paging out to a swap file. They go through the VFS as a program's do,
+//! but without a process or descriptors, and without the syscalls' checks.
+//!
+//! A `KFile` is an open file: the file's inode, whether it was opened for
+//! writing, and a position for `read`, `write` and `seek` (`read_at` and
+//! `write_at` leave it alone). It holds the inode while it lives and
+//! drops it when it goes, so a handle can't be leaked by an early return;
+//! `list` shows the handles open now.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::vfs::{self, Metadata};
+use super::FsError;
+use crate::serial_println;
+use crate::sync::Mutex;
+
+/// Where `seek` moves to: from the start, the position or the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// The handles open now, by id: path, and whether for writing.
+static OPEN: Mutex<BTreeMap<u64, (String, bool)>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct KFile {
+    id: u64,
+    path: String,
+    file: Arc<dyn vfs::File>,
+    writable: bool,
+    pos: u64,
+}
+
+impl KFile {
+    /// File `path`, for reading.
+    pub fn open(path: &str) -> Result<KFile, FsError> {
+        Ok(KFile::new(path, vfs::open(path)?, false))
+    }
+
+    /// File `path`, for reading and writing; `ReadOnly` if its filesystem
+    /// doesn't write.
+    pub fn open_rw(path: &str) -> Result<KFile, FsError> {
+        let file = vfs::open(path)?;
+        if !file.writable() {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(KFile::new(path, file, true))
+    }
+
+    /// File `path`, for reading and writing, emptied, or created if it
+    /// isn't there.
+    pub fn create(path: &str) -> Result<KFile, FsError> {
+        let file = vfs::create(path)?;
+        file.truncate(0)?;
+        Ok(KFile::new(path, file, true))
+    }
+
+    fn new(path: &str, file: Arc<dyn vfs::File>, writable: bool) -> KFile {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        OPEN.lock().insert(id, (String::from(path), writable));
+        KFile { id, path: String::from(path), file, writable, pos: 0 }
+    }
+
+    /// The path it was opened by.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn stat(&self) -> Metadata {
+        self.file.metadata()
+    }
+
+    /// Read into `buf` from the position, and move it past what was read;
+    /// 0 at the end.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let read = self.file.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+
+    /// Everything from the position to the end.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0u8; self.stat().size.saturating_sub(self.pos) as usize];
+        let mut done = 0;
+        while done < data.len() {
+            match self.read(&mut data[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        data.truncate(done);
+        Ok(data)
+    }
+
+    /// Read from byte `offset` into `buf`; how many bytes, 0 at the end.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.file.read_at(offset, buf)
+    }
+
+    /// Write `data` at the position, and move it past what was written.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        let written = self.write_at(self.pos, data)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    /// `write` all of `data`; `NoSpace` if the file takes less.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), FsError> {
+        if self.write(data)? < data.len() {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// Write `data` at byte `offset`, growing the file if it goes past the
+    /// end; how many bytes.
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if !self.writable {
+            return Err(FsError::ReadOnly);
+        }
+        self.file.write_at(offset, data)
+    }
+
+    /// Cut the file to `size` bytes, or grow it with zeros.
+    pub fn set_len(&self, size: u64) -> Result<(), FsError> {
+        if !self.writable {
+            return Err(FsError::ReadOnly);
+        }
+        self.file.truncate(size)
+    }
+
+    /// Move the position, past the end if need be; `None` (and no move)
+    /// before the start.
+    pub fn seek(&mut self, to: SeekFrom) -> Option<u64> {
+        self.pos = match to {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset)?,
+            SeekFrom::End(offset) => self.stat().size.checked_add_signed(offset)?,
+        };
+        Some(self.pos)
+    }
+}
+
+impl Drop for KFile {
+    fn drop(&mut self) {
+        OPEN.lock().remove(&self.id);
+    }
+}
+
+/// The whole of file `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    KFile::open(path)?.read_to_end()
+}
+
+/// What is at `path`, following links.
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    vfs::stat(path)
+}
+
+/// The handles open now: path, and whether for writing.
+pub fn open_files() -> Vec<(String, bool)> {
+    OPEN.lock().values().cloned().collect()
+}
+
+pub fn list() {
+    let files = open_files();
+    if files.is_empty() {
+        return serial_println!("file: no kernel files open");
+    }
+    for (path, writable) in files {
+        serial_println!("  {} {}", if writable { "rw" } else { "r " }, path);
+    }
+}
+
+/// On a file of a tfs RAM disk: what's written reads back, from the
+/// position and at offsets; seeks from each end, not before the start; a
+/// read-only handle doesn't write; the inode is let go with the last
+/// handle, which is listed until then. By path: `/proc` is a directory,
+/// `/proc/uptime` reads but doesn't open for writing.
+pub fn self_test() -> bool {
+    use super::tfs::{self, Tfs};
+    use super::vfs::FileType;
+    use crate::block::{BlockDevice, RamDisk};
+
+    let Some(disk) = RamDisk::new(512, 256) else { return false };
+    let disk: Arc<dyn BlockDevice> = Arc::new(disk);
+    let Ok(fs) = tfs::format(&*disk).and_then(|()| Tfs::mount(disk)) else { return false };
+    let Ok(file) = Arc::new(fs).root_dir().create("file") else { return false };
+    let path = "/file-self-test";
+    let listed = || open_files().iter().filter(|(p, _)| p == path).count();
+
+    let mut rw = KFile::new(path, file.clone(), true);
+    let mut ro = KFile::new(path, file.clone(), false);
+    let mut buf = [0u8; 5];
+    let mut ok = listed() == 2 && Arc::strong_count(&file) == 3;
+    ok &= rw.write_all(b"hello world").is_ok()
+        && rw.seek(SeekFrom::Start(6)) == Some(6)
+        && rw.read(&mut buf) == Ok(5)
+        && &buf == b"world"
+        && rw.read(&mut buf) == Ok(0)
+        && rw.seek(SeekFrom::Current(-5)) == Some(6)
+        && rw.seek(SeekFrom::End(-12)).is_none()
+        && rw.seek(SeekFrom::End(-11)) == Some(0)
+        && rw.read_to_end().as_deref() == Ok(&b"hello world"[..]);
+    ok &= rw.set_len(5).is_ok()
+        && rw.write_at(8, b"!") == Ok(1)
+        && ro.stat().size == 9
+        && ro.read_to_end().as_deref() == Ok(&b"hello\0\0\0!"[..])
+        && ro.read_at(8, &mut buf) == Ok(1)
+        && ro.write(b"x") == Err(FsError::ReadOnly)
+        && ro.set_len(0) == Err(FsError::ReadOnly);
+    drop(rw);
+    ok &= listed() == 1 && Arc::strong_count(&file) == 2;
+    drop(ro);
+    ok &= listed() == 0 && Arc::strong_count(&file) == 1;
+
+    ok && stat("/proc").is_ok_and(|metadata| metadata.kind == FileType::Directory)
+        && KFile::open("/proc").err() == Some(FsError::IsADirectory)
+        && read("/proc/uptime").is_ok_and(|data| !data.is_empty())
+        && KFile::open_rw("/proc/uptime").err() == Some(FsError::ReadOnly)
+        && read("/proc/missing").err() == Some(FsError::NotFound)
+}
@@ -0,0 +1,325 @@
+//! The initramfs: the initrd archive as a tree of directories, files and
+//! symbolic links, in memory, mounted at `/` at boot. Files' contents are
+//! slices of the archive, which stays mapped, so building the tree copies
+//! nothing but names.
+//!
+//! Read-only, but for `add`: files the kernel makes itself, such as the
+//! test programs it assembles, go in next to the archive's. The VFS sees
+//! each inode as a `RamNode`, made as a walk comes to it.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Once;
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode as VfsInode, Metadata, Node};
+use super::{components, FsError};
+use crate::initrd::{Member, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use crate::serial_println;
+use crate::sync::RwLock;
+
+/// An inode number: the index in `Initramfs::inodes`.
+pub type Ino = usize;
+const ROOT_INO: Ino = 0;
+/// Symbolic links followed in one lookup before giving up on a loop.
+const MAX_SYMLINKS: usize = 8;
+
+enum Contents {
+    Dir(BTreeMap<String, Ino>),
+    File(&'static [u8]),
+    Symlink(String),
+}
+
+struct Inode {
+    /// Type and permission bits, as in the archive.
+    mode: u32,
+    contents: Contents,
+}
+
+pub struct Initramfs {
+    inodes: RwLock<Vec<Inode>>,
+}
+
+static ROOT: Once<Arc<Initramfs>> = Once::new();
+
+/// Make `fs` the filesystem at `/`.
+pub fn mount_root(fs: Initramfs) {
+    let fs = ROOT.call_once(|| Arc::new(fs));
+    vfs::mount("/", "initramfs", "initrd", fs.root_dir());
+}
+
+/// The filesystem at `/`; empty until `initrd::init` mounts the archive.
+pub fn root() -> &'static Initramfs {
+    ROOT.call_once(|| Arc::new(Initramfs::empty()))
+}
+
+impl Initramfs {
+    /// Just the root directory.
+    pub fn empty() -> Initramfs {
+        let root = Inode { mode: S_IFDIR | 0o755, contents: Contents::Dir(BTreeMap::new()) };
+        Initramfs { inodes: RwLock::new(alloc::vec![root]) }
+    }
+
+    /// A tree of an archive's members. Directories missing from the archive
+    /// are made; a member that can't go in (below a file, say) is skipped.
+    pub fn from_members(members: Vec<Member<'static>>) -> Initramfs {
+        let fs = Initramfs::empty();
+        for member in members {
+            let contents = match member.mode & S_IFMT {
+                S_IFDIR => Contents::Dir(BTreeMap::new()),
+                S_IFLNK => Contents::Symlink(String::from_utf8_lossy(member.data).into_owned()),
+                _ => Contents::File(member.data),
+            };
+            if let Err(err) = fs.insert(&member.path, member.mode, contents) {
+                serial_println!("initramfs: {}: {}", member.path, err);
+            }
+        }
+        fs
+    }
+
+    /// Put `contents` at `path`, making the directories on the way. A
+    /// directory already there keeps its entries; anything else is replaced.
+    fn insert(&self, path: &str, mode: u32, contents: Contents) -> Result<Ino, FsError> {
+        let mut inodes = self.inodes.write();
+        let names: Vec<&str> = components(path).collect();
+        let Some((last, parents)) = names.split_last() else {
+            return Err(FsError::Exists);
+        };
+        let mut dir = ROOT_INO;
+        for name in parents {
+            dir = match child(&inodes, dir, name)? {
+                Some(ino) => ino,
+                None => {
+                    let inode = Inode { mode: S_IFDIR | 0o755, contents: Contents::Dir(BTreeMap::new()) };
+                    add_child(&mut inodes, dir, name, inode)
+                }
+            };
+        }
+        match child(&inodes, dir, last)? {
+            Some(ino) if matches!((&inodes[ino].contents, &contents), (Contents::Dir(_), Contents::Dir(_))) => {
+                inodes[ino].mode = mode;
+                Ok(ino)
+            }
+            Some(ino) => {
+                inodes[ino] = Inode { mode, contents };
+                Ok(ino)
+            }
+            None => Ok(add_child(&mut inodes, dir, last, Inode { mode, contents })),
+        }
+    }
+
+    /// Add (or replace) file `path`.
+    pub fn add(&self, path: &str, data: &'static [u8]) -> Result<(), FsError> {
+        self.insert(path, S_IFREG | 0o755, Contents::File(data)).map(|_| ())
+    }
+
+    /// The inode at `path`, following symbolic links, `..` going up.
+    pub fn lookup(&self, path: &str) -> Result<Ino, FsError> {
+        let inodes = self.inodes.read();
+        // The directories walked down so far, for `..`; the names to go.
+        let mut walked = alloc::vec![ROOT_INO];
+        let mut rest: Vec<String> = components(path).rev().map(String::from).collect();
+        let mut links = 0;
+        while let Some(name) = rest.pop() {
+            let dir = *walked.last().expect("the root");
+            if name == ".." {
+                if walked.len() > 1 {
+                    walked.pop();
+                }
+                continue;
+            }
+            let ino = child(&inodes, dir, &name)?.ok_or(FsError::NotFound)?;
+            match &inodes[ino].contents {
+                Contents::Symlink(target) => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(FsError::Corrupt("too many symbolic links"));
+                    }
+                    if target.starts_with('/') {
+                        walked.truncate(1);
+                    }
+                    rest.extend(components(target).rev().map(String::from));
+                }
+                _ => walked.push(ino),
+            }
+        }
+        Ok(*walked.last().expect("the root"))
+    }
+
+    /// The contents of file `path`.
+    pub fn read(&self, path: &str) -> Result<&'static [u8], FsError> {
+        let ino = self.lookup(path)?;
+        match self.inodes.read()[ino].contents {
+            Contents::File(data) => Ok(data),
+            Contents::Dir(_) => Err(FsError::IsADirectory),
+            Contents::Symlink(_) => unreachable!("lookup follows links"),
+        }
+    }
+
+    /// The entries of directory `path`: name, mode and size.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<(String, u32, usize)>, FsError> {
+        let ino = self.lookup(path)?;
+        let inodes = self.inodes.read();
+        let Contents::Dir(entries) = &inodes[ino].contents else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(entries.iter().map(|(name, &ino)| (name.clone(), inodes[ino].mode, size(&inodes[ino]))).collect())
+    }
+
+    /// Its root directory, for the VFS.
+    pub fn root_dir(self: &Arc<Self>) -> Arc<dyn Dir> {
+        Arc::new(RamNode { fs: self.clone(), ino: ROOT_INO })
+    }
+
+    fn node(self: &Arc<Self>, ino: Ino) -> Node {
+        let node = RamNode { fs: self.clone(), ino };
+        match &self.inodes.read()[ino].contents {
+            Contents::Dir(_) => Node::Dir(Arc::new(node)),
+            Contents::File(_) => Node::File(Arc::new(node)),
+            Contents::Symlink(target) => Node::Symlink(target.clone()),
+        }
+    }
+
+    /// Every file, by path, with its size.
+    pub fn files(&self) -> Vec<(String, usize)> {
+        let inodes = self.inodes.read();
+        let mut files = Vec::new();
+        let mut dirs = alloc::vec![(String::new(), ROOT_INO)];
+        while let Some((path, dir)) = dirs.pop() {
+            let Contents::Dir(entries) = &inodes[dir].contents else { continue };
+            for (name, &ino) in entries.iter().rev() {
+                let path = format!("{}/{}", path, name);
+                match &inodes[ino].contents {
+                    Contents::Dir(_) => dirs.push((path, ino)),
+                    Contents::File(data) => files.push((path, data.len())),
+                    Contents::Symlink(_) => {}
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+}
+
+fn size(inode: &Inode) -> usize {
+    match &inode.contents {
+        Contents::File(data) => data.len(),
+        Contents::Symlink(target) => target.len(),
+        Contents::Dir(entries) => entries.len(),
+    }
+}
+
+fn metadata(inode: &Inode) -> Metadata {
+    let kind = match inode.contents {
+        Contents::Dir(_) => FileType::Directory,
+        Contents::File(_) => FileType::Regular,
+        Contents::Symlink(_) => FileType::Symlink,
+    };
+    Metadata { kind, mode: inode.mode & !S_IFMT, size: size(inode) as u64 }
+}
+
+/// The inode called `name` in directory `dir`, if there is one.
+fn child(inodes: &[Inode], dir: Ino, name: &str) -> Result<Option<Ino>, FsError> {
+    match &inodes[dir].contents {
+        Contents::Dir(entries) => Ok(entries.get(name).copied()),
+        _ => Err(FsError::NotADirectory),
+    }
+}
+
+fn add_child(inodes: &mut Vec<Inode>, dir: Ino, name: &str, inode: Inode) -> Ino {
+    let ino = inodes.len();
+    inodes.push(inode);
+    if let Contents::Dir(entries) = &mut inodes[dir].contents {
+        entries.insert(String::from(name), ino);
+    }
+    ino
+}
+
+/// An inode, as the VFS sees it.
+struct RamNode {
+    fs: Arc<Initramfs>,
+    ino: Ino,
+}
+
+impl VfsInode for RamNode {
+    fn metadata(&self) -> Metadata {
+        metadata(&self.fs.inodes.read()[self.ino])
+    }
+}
+
+impl File for RamNode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Contents::File(data) = self.fs.inodes.read()[self.ino].contents else {
+            return Err(FsError::IsADirectory);
+        };
+        let from = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - from);
+        buf[..n].copy_from_slice(&data[from..from + n]);
+        Ok(n)
+    }
+}
+
+impl Dir for RamNode {
+    fn lookup(&self, name: &str) -> Result<Node, FsError> {
+        let ino = child(&self.fs.inodes.read(), self.ino, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(ino))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let inodes = self.fs.inodes.read();
+        let Contents::Dir(entries) = &inodes[self.ino].contents else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(entries.iter().map(|(name, &ino)| DirEntry { name: name.clone(), metadata: metadata(&inodes[ino]) }).collect())
+    }
+}
+
+/// List directory `path` of the root filesystem.
+pub fn ls(path: &str) {
+    match root().read_dir(path) {
+        Ok(entries) => {
+            for (name, mode, size) in entries {
+                let kind = match mode & S_IFMT {
+                    S_IFDIR => "dir",
+                    S_IFLNK => "lnk",
+                    _ => "",
+                };
+                serial_println!("  {:>8} {:>3}  {}", size, kind, name);
+            }
+        }
+        Err(err) => serial_println!("initramfs: {}: {}", path, err),
+    }
+}
+
+/// A tree from members out of order and without their directories: the
+/// directories are made, files read back, `..` and links (relative,
+/// absolute, through directories) are followed and a loop of links is
+/// caught; `add` replaces a file, and paths through a file fail.
+pub fn self_test() -> bool {
+    let member = |path: &str, mode: u32, data: &'static [u8]| Member { path: String::from(path), mode, data };
+    let fs = Initramfs::from_members(alloc::vec![
+        member("/usr/bin/tool", S_IFREG | 0o755, b"tool"),
+        member("/etc/motd", S_IFREG | 0o644, b"hi\n"),
+        member("/etc", S_IFDIR | 0o700, b""),
+        member("/bin", S_IFLNK | 0o777, b"usr/bin"),
+        member("/usr/lib/motd", S_IFLNK | 0o777, b"../../etc/motd"),
+        member("/loop", S_IFLNK | 0o777, b"/loop"),
+    ]);
+    let ok = fs.read("/etc/motd") == Ok(&b"hi\n"[..])
+        && fs.read("/bin/tool") == Ok(&b"tool"[..])
+        && fs.read("/usr/lib/motd") == Ok(&b"hi\n"[..])
+        && fs.read("/usr/bin/../../etc/./motd") == Ok(&b"hi\n"[..])
+        && fs.read("/etc") == Err(FsError::IsADirectory)
+        && fs.read("/missing") == Err(FsError::NotFound)
+        && fs.read("/etc/motd/x") == Err(FsError::NotADirectory)
+        && fs.lookup("/loop").is_err()
+        && fs.read_dir("/etc").is_ok_and(|entries| entries == [(String::from("motd"), S_IFREG | 0o644, 3)])
+        && fs.read_dir("/").is_ok_and(|entries| entries.len() == 4)
+        && fs.lookup("/etc").is_ok_and(|ino| fs.inodes.read()[ino].mode == S_IFDIR | 0o700);
+    ok && fs.add("/etc/motd", b"bye\n").is_ok()
+        && fs.read("/etc/motd") == Ok(&b"bye\n"[..])
+        && fs.add("/etc/motd/x", b"").is_err()
+        && fs.files() == [(String::from("/etc/motd"), 4), (String::from("/usr/bin/tool"), 4)]
+}
@@ -0,0 +1,532 @@
+//! ISO 9660, the format of CD-ROMs, read-only as the medium is, with the
+//! Rock Ridge extensions that give files Unix names, modes and symbolic
+//! links. The runner's `--cdrom` mode boots from a disc it makes; the
+//! kernel finds the drive (`cd0`, see `block::ata`) and mounts the disc,
+//! as every volume found at boot, at `/mnt/<device>`.
+//!
+//! A disc is a row of 2 KiB sectors. The first 16 are left to the system;
+//! from sector 16 on come volume descriptors, up to a terminator, among
+//! them the primary one: the volume's name and size, and its root
+//! directory's record. A directory is a file of records, none crossing a
+//! sector: where a file is (one extent, a run of sectors), its size,
+//! whether it is a directory, and a name in capitals with a version,
+//! `README.TXT;1`. Numbers are written both little- and big-endian; we
+//! read the little.
+//!
+//! Rock Ridge puts entries in each record's System Use area, after the
+//! name (the System Use Sharing Protocol, SUSP): `NM` for the real name,
+//! `PX` for the mode, `SL` for a link's target, `CE` to carry on in
+//! another sector when the record is full, and `RE` and `CL` for
+//! directories moved to keep the tree eight levels deep. An `SP` entry in
+//! the root's first record says they are there. Without them, names are
+//! the ISO ones, lowercased, without the version.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode, Metadata, Node};
+use super::FsError;
+use crate::block::{self, BlockDevice, RamDisk};
+use crate::serial_println;
+use crate::sync::RwLock;
+
+const SECTOR_SIZE: usize = 2048;
+/// Where the volume descriptors start.
+const FIRST_DESCRIPTOR: u32 = 16;
+/// Descriptors looked at for the primary one before giving up.
+const MAX_DESCRIPTORS: u32 = 32;
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_TERMINATOR: u8 = 255;
+/// Where the root directory's record is in the primary descriptor.
+const ROOT_RECORD: usize = 156;
+/// The shortest directory record: 33 bytes and a name of one.
+const MIN_RECORD: usize = 34;
+/// The record flag of a directory.
+const FLAG_DIRECTORY: u8 = 0x02;
+/// `CE` areas followed for one record, in case they go round in a loop.
+const MAX_CONTINUATIONS: usize = 16;
+
+/// `SL` component flags: it goes on in the next component; it is `.`,
+/// `..`, or the root.
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// `README.TXT;1` as `readme.txt`, and `DIR` as `dir`.
+fn plain_name(iso: &[u8]) -> String {
+    let name = String::from_utf8_lossy(iso);
+    let name = name.split(';').next().unwrap_or_default().trim_end_matches('.');
+    name.to_lowercase()
+}
+
+/// A directory record, with what Rock Ridge says about it.
+#[derive(Debug, Clone, Default)]
+struct Record {
+    extent: u32,
+    size: u32,
+    dir: bool,
+    name: String,
+    /// From `PX`: type and permission bits.
+    mode: Option<u32>,
+    /// From `SL`.
+    symlink: Option<String>,
+    /// `RE`: a directory moved here from deeper in the tree; it is listed
+    /// where a `CL` entry points to it instead.
+    relocated: bool,
+}
+
+/// A mounted disc.
+pub struct Iso9660 {
+    device: Arc<dyn BlockDevice>,
+    /// Device blocks in a sector.
+    per_sector: u64,
+    volume_id: String,
+    sectors: u32,
+    root: Record,
+    /// Bytes to skip at the start of each System Use area, as `SP` says;
+    /// `None` without Rock Ridge.
+    rock_ridge: Option<usize>,
+}
+
+impl Iso9660 {
+    /// Find the primary volume descriptor on `device`, and see from the
+    /// root's first record whether there is Rock Ridge.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Iso9660, FsError> {
+        let size = device.block_size();
+        if size > SECTOR_SIZE || !SECTOR_SIZE.is_multiple_of(size) {
+            return Err(FsError::Unsupported("device blocks larger than 2 KiB"));
+        }
+        let per_sector = (SECTOR_SIZE / size) as u64;
+        let device_sectors = device.block_count() / per_sector;
+        let mut descriptor = vec![0u8; SECTOR_SIZE];
+        for at in FIRST_DESCRIPTOR.. {
+            if at as u64 >= device_sectors || at == FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+                return Err(FsError::Unsupported("not an ISO 9660 volume"));
+            }
+            device.read_blocks(at as u64 * per_sector, &mut descriptor)?;
+            if &descriptor[1..6] != b"CD001" {
+                return Err(FsError::Unsupported("not an ISO 9660 volume"));
+            }
+            match descriptor[0] {
+                TYPE_PRIMARY => break,
+                TYPE_TERMINATOR => return Err(FsError::Corrupt("no primary volume descriptor")),
+                _ => {}
+            }
+        }
+        if u16_at(&descriptor, 128) as usize != SECTOR_SIZE {
+            return Err(FsError::Unsupported("logical blocks other than 2 KiB"));
+        }
+        let mut fs = Iso9660 {
+            device,
+            per_sector,
+            volume_id: String::from(String::from_utf8_lossy(&descriptor[40..72]).trim_end()),
+            sectors: u32_at(&descriptor, 80),
+            root: Record::default(),
+            rock_ridge: None,
+        };
+        fs.root = fs.record(&descriptor[ROOT_RECORD..ROOT_RECORD + MIN_RECORD])?;
+        if !fs.root.dir {
+            return Err(FsError::Corrupt("the root is not a directory"));
+        }
+
+        // `SP`: two check bytes, then how many bytes to skip in each area.
+        let mut first = vec![0u8; MIN_RECORD + 7];
+        fs.read_bytes(fs.root.extent, 0, &mut first)?;
+        let dot = &first[..(first[0] as usize).min(first.len())];
+        if dot.len() >= MIN_RECORD + 7 && &dot[MIN_RECORD..MIN_RECORD + 2] == b"SP" && dot[MIN_RECORD + 4..MIN_RECORD + 6] == [0xBE, 0xEF] {
+            fs.rock_ridge = Some(dot[MIN_RECORD + 6] as usize);
+        }
+        Ok(fs)
+    }
+
+    /// Read `buf.len()` bytes from byte `offset` of the extent at `sector`.
+    fn read_bytes(&self, sector: u32, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let first = sector as u64 + offset / SECTOR_SIZE as u64;
+        let skip = (offset % SECTOR_SIZE as u64) as usize;
+        let count = (skip + buf.len()).div_ceil(SECTOR_SIZE);
+        if first + count as u64 > self.sectors as u64 {
+            return Err(FsError::Corrupt("extent past the end of the volume"));
+        }
+        let mut data = vec![0u8; count * SECTOR_SIZE];
+        self.device.read_blocks(first * self.per_sector, &mut data)?;
+        buf.copy_from_slice(&data[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    /// Parse the directory record `raw`, and its Rock Ridge entries.
+    fn record(&self, raw: &[u8]) -> Result<Record, FsError> {
+        let name_len = raw[32] as usize;
+        if raw.len() < 33 + name_len {
+            return Err(FsError::Corrupt("directory record shorter than its name"));
+        }
+        let mut record = Record {
+            extent: u32_at(raw, 2),
+            size: u32_at(raw, 10),
+            dir: raw[25] & FLAG_DIRECTORY != 0,
+            name: plain_name(&raw[33..33 + name_len]),
+            ..Record::default()
+        };
+        if let Some(skip) = self.rock_ridge {
+            // A pad byte keeps the System Use area at an even offset.
+            let start = 33 + name_len + (name_len + 1) % 2 + skip;
+            self.system_use(raw.get(start..).unwrap_or_default(), &mut record)?;
+        }
+        Ok(record)
+    }
+
+    /// Apply the Rock Ridge entries in `area`, and in the areas `CE`
+    /// entries carry on in, to `record`.
+    fn system_use(&self, area: &[u8], record: &mut Record) -> Result<(), FsError> {
+        let mut area = area.to_vec();
+        let mut name: Option<Vec<u8>> = None;
+        let mut target: Option<String> = None;
+        // The last `SL` component went on into the next one.
+        let mut joined = false;
+        let mut child = None;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut next = None;
+            let mut at = 0;
+            while at + 4 <= area.len() {
+                let len = area[at + 2] as usize;
+                if len < 4 || at + len > area.len() {
+                    break;
+                }
+                let entry = &area[at..at + len];
+                at += len;
+                match &entry[..2] {
+                    b"NM" if len >= 5 => name.get_or_insert_with(Vec::new).extend_from_slice(&entry[5..]),
+                    b"PX" if len >= 12 => record.mode = Some(u32_at(entry, 4)),
+                    b"SL" if len >= 5 => {
+                        let target = target.get_or_insert_with(String::new);
+                        let mut rest = &entry[5..];
+                        while rest.len() >= 2 {
+                            let (flags, part_len) = (rest[0], rest[1] as usize);
+                            let part = rest.get(2..2 + part_len).ok_or(FsError::Corrupt("SL component past its entry"))?;
+                            if !joined && !target.is_empty() && !target.ends_with('/') {
+                                target.push('/');
+                            }
+                            if flags & SL_ROOT != 0 {
+                                target.push('/');
+                            } else if flags & SL_PARENT != 0 {
+                                target.push_str("..");
+                            } else if flags & SL_CURRENT != 0 {
+                                target.push('.');
+                            } else {
+                                target.push_str(&String::from_utf8_lossy(part));
+                            }
+                            joined = flags & SL_CONTINUE != 0;
+                            rest = &rest[2 + part_len..];
+                        }
+                    }
+                    b"CE" if len >= 28 => next = Some((u32_at(entry, 4), u32_at(entry, 12), u32_at(entry, 20))),
+                    b"RE" => record.relocated = true,
+                    b"CL" if len >= 12 => child = Some(u32_at(entry, 4)),
+                    b"ST" => break,
+                    _ => {}
+                }
+            }
+            let Some((sector, offset, len)) = next else { break };
+            area = vec![0u8; (len as usize).min(SECTOR_SIZE)];
+            self.read_bytes(sector, offset as u64, &mut area)?;
+        }
+        if let Some(name) = name {
+            record.name = String::from(String::from_utf8_lossy(&name));
+        }
+        record.symlink = target;
+        // A directory moved away: here is a file pointing to where it
+        // went, and its size is in the record of `.` there.
+        if let Some(sector) = child {
+            let mut dot = [0u8; MIN_RECORD];
+            self.read_bytes(sector, 0, &mut dot)?;
+            record.extent = sector;
+            record.size = u32_at(&dot, 10);
+            record.dir = true;
+        }
+        Ok(())
+    }
+
+    /// The records of directory `dir`, without `.` and `..`, nor the
+    /// directories moved into it.
+    fn records(&self, dir: &Record) -> Result<Vec<Record>, FsError> {
+        let mut data = vec![0u8; (dir.size as usize).next_multiple_of(SECTOR_SIZE)];
+        self.read_bytes(dir.extent, 0, &mut data)?;
+        let mut records = Vec::new();
+        let mut at = 0;
+        while at < dir.size as usize {
+            let len = data[at] as usize;
+            // Records don't cross sectors: the rest of this one is empty.
+            if len == 0 {
+                at = (at / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                continue;
+            }
+            if len < MIN_RECORD || at + len > data.len() {
+                return Err(FsError::Corrupt("bad directory record length"));
+            }
+            let raw = &data[at..at + len];
+            at += len;
+            if raw[32] == 1 && raw[33] <= 1 {
+                continue;
+            }
+            let record = self.record(raw)?;
+            if !record.relocated {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Its root directory, for the VFS.
+    pub fn root_dir(self: &Arc<Self>) -> Arc<dyn Dir> {
+        Arc::new(IsoNode { fs: self.clone(), record: self.root.clone() })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "\"{}\", {} sectors of 2 KiB, {}",
+            self.volume_id,
+            self.sectors,
+            if self.rock_ridge.is_some() { "Rock Ridge" } else { "plain ISO 9660" }
+        )
+    }
+}
+
+fn metadata(record: &Record) -> Metadata {
+    let mode = |default: u32| record.mode.map_or(default, |mode| mode & 0o7777);
+    match &record.symlink {
+        Some(target) => Metadata { kind: FileType::Symlink, mode: 0o777, size: target.len() as u64 },
+        None if record.dir => Metadata { kind: FileType::Directory, mode: mode(0o555), size: record.size as u64 },
+        None => Metadata { kind: FileType::Regular, mode: mode(0o444), size: record.size as u64 },
+    }
+}
+
+/// A file or directory, as the VFS sees it: its record.
+struct IsoNode {
+    fs: Arc<Iso9660>,
+    record: Record,
+}
+
+impl IsoNode {
+    fn node(&self, record: Record) -> Node {
+        match record.symlink {
+            Some(target) => Node::Symlink(target),
+            None if record.dir => Node::Dir(Arc::new(IsoNode { fs: self.fs.clone(), record })),
+            None => Node::File(Arc::new(IsoNode { fs: self.fs.clone(), record })),
+        }
+    }
+}
+
+impl Inode for IsoNode {
+    fn metadata(&self) -> Metadata {
+        metadata(&self.record)
+    }
+}
+
+impl File for IsoNode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.record.dir {
+            return Err(FsError::IsADirectory);
+        }
+        let size = self.record.size as u64;
+        let from = offset.min(size);
+        let n = buf.len().min((size - from) as usize);
+        self.fs.read_bytes(self.record.extent, from, &mut buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl Dir for IsoNode {
+    fn lookup(&self, name: &str) -> Result<Node, FsError> {
+        let record = self.fs.records(&self.record)?.into_iter().find(|record| record.name == name).ok_or(FsError::NotFound)?;
+        Ok(self.node(record))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let records = self.fs.records(&self.record)?;
+        Ok(records.iter().map(|record| DirEntry { name: record.name.clone(), metadata: metadata(record) }).collect())
+    }
+}
+
+/// Mounted discs, by device name.
+static VOLUMES: RwLock<Vec<(String, Arc<Iso9660>)>> = RwLock::new(Vec::new());
+
+/// Mount the disc in block device `name`.
+pub fn mount(name: &str) -> Result<Arc<Iso9660>, FsError> {
+    if let Some(fs) = VOLUMES.read().iter().find(|(n, _)| n == name).map(|(_, fs)| fs.clone()) {
+        return Ok(fs);
+    }
+    let device = block::cache::get(name).ok_or(FsError::NotFound)?;
+    let fs = Arc::new(Iso9660::mount(device)?);
+    serial_println!("iso9660: {}: {}", name, fs.describe());
+    VOLUMES.write().push((String::from(name), fs.clone()));
+    vfs::mount(&format!("/mnt/{}", name), "iso9660", name, fs.root_dir());
+    Ok(fs)
+}
+
+/// Mount every block device that holds an ISO 9660 volume.
+pub fn probe() {
+    for name in block::names() {
+        if let Err(err @ (FsError::Corrupt(_) | FsError::Io(_))) = mount(&name) {
+            serial_println!("iso9660: {}: {}", name, err);
+        }
+    }
+}
+
+pub fn list() {
+    let volumes = VOLUMES.read();
+    if volumes.is_empty() {
+        return serial_println!("iso9660: no discs");
+    }
+    for (name, fs) in volumes.iter() {
+        serial_println!("  {:<8} {}", name, fs.describe());
+    }
+}
+
+/// A number both ways round, as the format writes them.
+fn both_endian(value: u32) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    bytes[4..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+/// A directory record for `name`, with `system_use` after it.
+fn dir_record(extent: u32, size: u32, dir: bool, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; 33];
+    record[2..10].copy_from_slice(&both_endian(extent));
+    record[10..18].copy_from_slice(&both_endian(size));
+    record[25] = if dir { FLAG_DIRECTORY } else { 0 };
+    record[28..32].copy_from_slice(&[1, 0, 0, 1]);
+    record[32] = name.len() as u8;
+    record.extend_from_slice(name);
+    if name.len().is_multiple_of(2) {
+        record.push(0);
+    }
+    record.extend_from_slice(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    record[0] = record.len() as u8;
+    record
+}
+
+/// A SUSP entry.
+fn su_entry(signature: &[u8; 2], data: &[u8]) -> Vec<u8> {
+    [&signature[..], &[4 + data.len() as u8, 1], data].concat()
+}
+
+/// A disc with Rock Ridge, made here, read from devices of 2 KiB and of
+/// 512-byte blocks: the names are `NM`'s, one carried on by `CE`, or the
+/// ISO name without one; modes are `PX`'s; files read whole and from an
+/// offset; links have `SL`'s targets, relative and from the root; a
+/// directory moved (`RE`) is only where its `CL` is. A device without a
+/// disc doesn't mount.
+pub fn self_test() -> bool {
+    const README: &[u8] = b"hello from the disc\n";
+    const DEEP: &[u8] = b"eight levels down, nearly\n";
+    let nm = |name: &str| su_entry(b"NM", &[&[0], name.as_bytes()].concat());
+    let px = |mode: u32| su_entry(b"PX", &[both_endian(mode), both_endian(1), both_endian(0), both_endian(0)].concat());
+    let put = |image: &mut Vec<u8>, sector: usize, data: &[u8]| {
+        image[sector * SECTOR_SIZE..sector * SECTOR_SIZE + data.len()].copy_from_slice(data);
+    };
+
+    let mut image = vec![0u8; 24 * SECTOR_SIZE];
+    let mut primary = vec![TYPE_PRIMARY];
+    primary.extend_from_slice(b"CD001\x01");
+    primary.resize(40, 0);
+    primary.extend_from_slice(b"SELF TEST");
+    primary.resize(72, b' ');
+    primary.resize(80, 0);
+    primary.extend_from_slice(&both_endian(24));
+    primary.resize(128, 0);
+    primary.extend_from_slice(&[0x00, 0x08, 0x08, 0x00]);
+    primary.resize(ROOT_RECORD, 0);
+    primary.extend_from_slice(&dir_record(18, 2048, true, &[0], &[]));
+    put(&mut image, 16, &primary);
+    put(&mut image, 17, b"\xFFCD001\x01");
+
+    let continued = nm("Me.txt");
+    let readme = [
+        su_entry(b"NM", b"\x01Read"),
+        px(0o100644),
+        su_entry(b"CE", &[both_endian(21), both_endian(0), both_endian(continued.len() as u32)].concat()),
+    ];
+    let link = su_entry(b"SL", &[&[0, 0, 7][..], b"Sub Dir", &[0, 8], b"deep.txt"].concat());
+    let absolute = su_entry(b"SL", &[&[0, SL_ROOT, 0, SL_CONTINUE, 3][..], b"cd-", &[0, 4], b"root"].concat());
+    let root = [
+        dir_record(18, 2048, true, &[0], &su_entry(b"SP", &[0xBE, 0xEF, 0])),
+        dir_record(18, 2048, true, &[1], &[]),
+        dir_record(20, README.len() as u32, false, b"README.TXT;1", &readme.concat()),
+        dir_record(19, 2048, true, b"SUB", &[nm("Sub Dir"), px(0o040750)].concat()),
+        dir_record(0, 0, false, b"LINK.;1", &[nm("link"), link].concat()),
+        dir_record(0, 0, false, b"ABS.;1", &[nm("abs"), absolute].concat()),
+        dir_record(22, DEEP.len() as u32, false, b"PLAIN.TXT;1", &[]),
+        dir_record(23, 2048, true, b"MOVED", &[nm("moved"), su_entry(b"RE", &[])].concat()),
+    ];
+    put(&mut image, 18, &root.concat());
+    let sub = [
+        dir_record(19, 2048, true, &[0], &[]),
+        dir_record(18, 2048, true, &[1], &[]),
+        dir_record(22, DEEP.len() as u32, false, b"DEEP.TXT;1", &nm("deep.txt")),
+        dir_record(0, 0, false, b"MOVED.;1", &[nm("moved"), su_entry(b"CL", &both_endian(23))].concat()),
+    ];
+    put(&mut image, 19, &sub.concat());
+    put(&mut image, 20, README);
+    put(&mut image, 21, &continued);
+    put(&mut image, 22, DEEP);
+    put(&mut image, 23, &[dir_record(23, 2048, true, &[0], &[]), dir_record(19, 2048, true, &[1], &[])].concat());
+
+    let read = |node: Result<Node, FsError>, offset: u64| match node {
+        Ok(Node::File(file)) => {
+            let mut buf = [0u8; 64];
+            file.read_at(offset, &mut buf).ok().map(|n| buf[..n].to_vec())
+        }
+        _ => None,
+    };
+    let target = |node: Result<Node, FsError>| match node {
+        Ok(Node::Symlink(target)) => Some(target),
+        _ => None,
+    };
+    let check = |block_size: usize| {
+        let Some(disk) = RamDisk::from_image(&image, block_size) else { return false };
+        let Ok(fs) = Iso9660::mount(Arc::new(disk)) else { return false };
+        let root = Arc::new(fs).root_dir();
+        let names: Vec<String> = root.read_dir().map(|entries| entries.into_iter().map(|entry| entry.name).collect()).unwrap_or_default();
+        let Ok(Node::Dir(sub)) = root.lookup("Sub Dir") else { return false };
+        let moved = matches!(sub.lookup("moved"), Ok(Node::Dir(moved)) if moved.read_dir().is_ok_and(|entries| entries.is_empty()));
+        names == ["ReadMe.txt", "Sub Dir", "link", "abs", "plain.txt"]
+            && read(root.lookup("ReadMe.txt"), 0).as_deref() == Some(README)
+            && read(root.lookup("ReadMe.txt"), 6).as_deref() == Some(&README[6..])
+            && read(root.lookup("ReadMe.txt"), 100).as_deref() == Some(&[][..])
+            && root.read_dir().is_ok_and(|entries| entries[0].metadata == Metadata { kind: FileType::Regular, mode: 0o644, size: README.len() as u64 })
+            && sub.metadata().mode == 0o750
+            && read(sub.lookup("deep.txt"), 0).as_deref() == Some(DEEP)
+            && read(root.lookup("plain.txt"), 0).as_deref() == Some(DEEP)
+            && target(root.lookup("link")).as_deref() == Some("Sub Dir/deep.txt")
+            && target(root.lookup("abs")).as_deref() == Some("/cd-root")
+            && moved
+            && matches!(root.lookup("moved"), Err(FsError::NotFound))
+            && matches!(root.create("new"), Err(FsError::ReadOnly))
+    };
+
+    let Some(blank) = RamDisk::new(2048, 24) else { return false };
+    let Some(small) = RamDisk::new(512, 8) else { return false };
+    check(SECTOR_SIZE)
+        && check(512)
+        && matches!(Iso9660::mount(Arc::new(blank)), Err(FsError::Unsupported(_)))
+        && matches!(Iso9660::mount(Arc::new(small)), Err(FsError::Unsupported(_)))
+}
@@ -0,0 +1,75 @@
+//! Filesystems: files and directories on top of the block layer. Each
+//! filesystem reads its own on-disk format through a `BlockDevice`, so it
+//! runs the same on a RAM disk as on a real one. The VFS puts them all in
+//! one tree of paths.
+//!
+//! - `vfs`: the traits filesystems implement, the mount table, paths.
+//! - `initramfs`: the initrd archive as a tree, the root filesystem.
+//! - `devfs`: devices as files, at `/dev`.
+//! - `procfs`: processes, memory and interrupts as text files, at `/proc`.
+//! - `fat`: FAT16 and FAT32, the format of USB sticks, of EFI system
+//!   partitions, and of the boot partition `bootloader` makes.
+//! - `tfs`: the teaching filesystem, a superblock, bitmaps and an inode
+//!   table, small enough to read in a hex dump; the host's `tfs` tool
+//!   makes and checks it.
+//! - `iso9660`: CD-ROMs, with Rock Ridge names, modes and links.
+//! - `file`: open files for the kernel's own use, such as loading programs.
+
+pub mod devfs;
+pub mod fat;
+pub mod file;
+pub mod initramfs;
+pub mod iso9660;
+pub mod procfs;
+pub mod tfs;
+pub mod vfs;
+
+use core::fmt;
+
+use crate::block::BlockError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    /// A name the filesystem can't store.
+    InvalidName,
+    NoSpace,
+    ReadOnly,
+    /// The on-disk structures don't add up; what didn't.
+    Corrupt(&'static str),
+    /// Valid, but not something we handle.
+    Unsupported(&'static str),
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> FsError {
+        FsError::Io(err)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => f.write_str("no such file or directory"),
+            FsError::NotADirectory => f.write_str("not a directory"),
+            FsError::IsADirectory => f.write_str("is a directory"),
+            FsError::Exists => f.write_str("already exists"),
+            FsError::InvalidName => f.write_str("invalid name"),
+            FsError::NoSpace => f.write_str("no space left"),
+            FsError::ReadOnly => f.write_str("read-only filesystem"),
+            FsError::Corrupt(what) => write!(f, "corrupt filesystem: {}", what),
+            FsError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            FsError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// The components of `path`, without empty ones and `.`: `/a//b/./c` is
+/// `a`, `b`, `c`.
+pub fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty() && *part != ".")
+}
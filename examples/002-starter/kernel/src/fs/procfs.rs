@@ -0,0 +1,225 @@
+//! procfs: the kernel's state as text files, mounted at `/proc`. Nothing
+//! is stored; a file's text is made from the live state when it is
+//! opened, and that open reads that text however it reads it.
+//!
+//! - `uptime`: seconds since boot.
+//! - `meminfo`: physical frames and the kernel heap.
+//! - `interrupts`: how many times each interrupt vector has come in.
+//! - `mounts`: what is mounted where.
+//! - `<pid>/status`: a process: name, parent, state, caps, ...
+//! - `self`: a link to the directory of the process looking.
+//!
+//! Listings give the files size 0: what they hold is only known once made.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode, Metadata, Node};
+use super::FsError;
+use crate::memory::frame_alloc;
+use crate::process::{self, Pid, State};
+use crate::{heap, interrupts, time};
+
+/// What makes a file's text.
+type Generator = fn() -> String;
+
+/// The files at the top, and what makes their text.
+const FILES: [(&str, Generator); 4] =
+    [("interrupts", interrupts), ("meminfo", meminfo), ("mounts", mounts), ("uptime", uptime)];
+
+fn uptime() -> String {
+    let uptime = time::uptime();
+    format!("{}.{:02}\n", uptime.as_secs(), uptime.subsec_millis() / 10)
+}
+
+fn meminfo() -> String {
+    let mut lines: Vec<(&str, usize, &str)> = Vec::new();
+    if let Some(frames) = frame_alloc::stats() {
+        lines.extend([
+            ("MemTotal", frames.usable * 4, "kB"),
+            ("MemUsed", frames.used * 4, "kB"),
+            ("MemFree", frames.free * 4, "kB"),
+            ("MemBad", frames.bad * 4, "kB"),
+        ]);
+    }
+    let heap = heap::stats();
+    lines.extend([
+        ("HeapLive", heap.live_bytes / 1024, "kB"),
+        ("HeapPeak", heap.peak_bytes / 1024, "kB"),
+        ("HeapAllocs", heap.allocs, ""),
+        ("HeapFrees", heap.frees, ""),
+        ("HeapFailed", heap.failed, ""),
+    ]);
+    lines.into_iter().map(|(name, value, unit)| format!("{:<11} {:>10} {}\n", format!("{}:", name), value, unit)).collect()
+}
+
+fn interrupts() -> String {
+    interrupts::counts().into_iter().map(|(vector, name, count)| format!("{:>4}: {:>10}  {}\n", vector, count, name)).collect()
+}
+
+fn mounts() -> String {
+    vfs::mounts().into_iter().map(|(path, fs_type, source)| format!("{} {} {}\n", source, path, fs_type)).collect()
+}
+
+/// `<pid>/status`; `None` if there is no such process.
+fn status(pid: Pid) -> Option<String> {
+    let process = process::get(pid)?;
+    let state = process.state();
+    let yes_no = |b: bool| String::from(if b { "yes" } else { "no" });
+    let mut lines = Vec::from([
+        ("Name", process.name()),
+        ("Pid", format!("{}", pid)),
+        ("PPid", format!("{}", process.parent().map_or(0, |pid| pid.0))),
+        ("State", String::from(state.name())),
+    ]);
+    if let State::Zombie(status) = state {
+        lines.push(("Exit", format!("{}", status)));
+    }
+    lines.extend([
+        ("Thread", format!("{}", process.main_thread().map_or(0, |id| id.0))),
+        ("Files", format!("{}", process.file_count())),
+        ("Caps", format!("{}", process.caps())),
+        ("Filtered", yes_no(process.is_filtered())),
+        ("Traced", yes_no(process.is_traced())),
+    ]);
+    Some(lines.into_iter().map(|(name, value)| format!("{:<9} {}\n", format!("{}:", name), value)).collect())
+}
+
+/// A file's text, made when it was looked up.
+struct Text(Vec<u8>);
+
+impl Inode for Text {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::Regular, mode: 0o444, size: self.0.len() as u64 }
+    }
+}
+
+impl File for Text {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let rest = self.0.get(offset as usize..).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+}
+
+fn snapshot(text: String) -> Node {
+    Node::File(Arc::new(Text(text.into_bytes())))
+}
+
+fn entry(name: String, kind: FileType) -> DirEntry {
+    let mode = if kind == FileType::Directory { 0o555 } else { 0o444 };
+    DirEntry { name, metadata: Metadata { kind, mode, size: 0 } }
+}
+
+/// `/proc/<pid>`.
+struct PidDir(Pid);
+
+impl Inode for PidDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::Directory, mode: 0o555, size: 0 }
+    }
+}
+
+impl Dir for PidDir {
+    fn lookup(&self, name: &str) -> Result<Node, FsError> {
+        match name {
+            "status" => status(self.0).map(snapshot).ok_or(FsError::NotFound),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        process::get(self.0).ok_or(FsError::NotFound)?;
+        Ok(Vec::from([entry(String::from("status"), FileType::Regular)]))
+    }
+}
+
+/// `/proc` itself.
+struct ProcDir;
+
+impl Inode for ProcDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::Directory, mode: 0o555, size: 0 }
+    }
+}
+
+impl Dir for ProcDir {
+    fn lookup(&self, name: &str) -> Result<Node, FsError> {
+        if let Some(&(_, make)) = FILES.iter().find(|(n, _)| *n == name) {
+            return Ok(snapshot(make()));
+        }
+        if name == "self" {
+            let process = process::current().ok_or(FsError::NotFound)?;
+            return Ok(Node::Symlink(format!("{}", process.pid())));
+        }
+        // Only the canonical spelling of a PID: "007" is not process 7.
+        let pid = name.parse::<u64>().ok().filter(|pid| format!("{}", pid) == name).map(Pid);
+        match pid.and_then(process::get) {
+            Some(process) => Ok(Node::Dir(Arc::new(PidDir(process.pid())))),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let files = FILES.iter().map(|(name, _)| entry(String::from(*name), FileType::Regular));
+        let pids = process::all().into_iter().map(|process| entry(format!("{}", process.pid()), FileType::Directory));
+        let mut entries: Vec<DirEntry> = files.chain(pids).collect();
+        if process::current().is_some() {
+            entries.push(entry(String::from("self"), FileType::Symlink));
+        }
+        Ok(entries)
+    }
+}
+
+/// Mount procfs at `/proc`.
+pub fn init() {
+    vfs::mount("/proc", "procfs", "proc", Arc::new(ProcDir));
+}
+
+/// Every top-level file opens and is text; `uptime` doesn't go back and
+/// the timer has been counted. A file's text stays the same however it is
+/// read, and takes no writes. Every process has a directory with a status
+/// naming it; no process, or a kernel thread asking for `self`, finds none.
+pub fn self_test() -> bool {
+    let read = |dir: &dyn Dir, name: &str| match dir.lookup(name) {
+        Ok(Node::File(file)) => {
+            let mut data = alloc::vec![0u8; file.metadata().size as usize];
+            let n = file.read_at(0, &mut data).ok()?;
+            String::from_utf8(data[..n].to_vec()).ok()
+        }
+        _ => None,
+    };
+    let seconds = |text: Option<String>| text.and_then(|text| text.trim_end().replace('.', "").parse::<u64>().ok());
+    let timer = format!("{}", crate::pic::Irq::Timer.vector());
+
+    let ok = FILES.iter().all(|(name, _)| read(&ProcDir, name).is_some())
+        && matches!((seconds(read(&ProcDir, "uptime")), seconds(read(&ProcDir, "uptime"))), (Some(a), Some(b)) if a <= b)
+        && read(&ProcDir, "interrupts").is_some_and(|text| {
+            text.lines().any(|line| line.trim_start().starts_with(&format!("{}:", timer)) && line.ends_with("timer"))
+        })
+        && read(&ProcDir, "meminfo").is_some_and(|text| text.contains("HeapLive:"));
+    let file = Text(Vec::from(&b"snapshot\n"[..]));
+    let mut buf = [0u8; 4];
+    let ok = ok
+        && file.read_at(4, &mut buf) == Ok(4)
+        && &buf == b"shot"
+        && file.read_at(8, &mut buf) == Ok(1)
+        && file.read_at(20, &mut buf) == Ok(0)
+        && file.write_at(0, b"x") == Err(FsError::ReadOnly);
+
+    let Ok(entries) = ProcDir.read_dir() else { return false };
+    ok && entries.iter().filter(|entry| entry.metadata.kind == FileType::Directory).all(|entry| {
+        // It may have been reaped since.
+        match ProcDir.lookup(&entry.name) {
+            Ok(Node::Dir(dir)) => read(&*dir, "status").is_none_or(|text| text.contains(&format!("Pid:      {}\n", entry.name))),
+            Err(FsError::NotFound) => true,
+            _ => false,
+        }
+    }) && process::all().iter().all(|process| entries.iter().any(|entry| entry.name == format!("{}", process.pid())))
+        && matches!(ProcDir.lookup("0"), Err(FsError::NotFound))
+        && matches!(ProcDir.lookup("01"), Err(FsError::NotFound))
+        && matches!(ProcDir.lookup("self"), Err(FsError::NotFound))
+}
@@ -0,0 +1,1278 @@
+//! tfs, the teaching filesystem: as simple as a filesystem with inodes
+//! gets, so that a hex dump of a volume can be read by hand. The host tool
+//! (`cargo run -p tfs -- mkfs|fsck|dump <image>`) makes, checks and prints
+//! the same format.
+//!
+//! The volume is a row of 1 KiB blocks; every number on it is a
+//! little-endian u32 unless said otherwise:
+//!
+//! - Block 0, the superblock: `TFS1`, the block count, the inode count,
+//!   where the inode bitmap, the block bitmap, the inode table and the
+//!   data area start, how many blocks and inodes are free, then where the
+//!   journal starts and how many blocks it has.
+//! - The inode bitmap, then the block bitmap: bit `n` (byte `n / 8`, low
+//!   bit first) set if inode or block `n` is in use. The blocks before
+//!   the data area are always in use, and so is inode 0, which means "no
+//!   inode".
+//! - The inode table, 64 bytes an inode: kind (u16: 0 free, 1 file, 2
+//!   directory), links (u16, the directory entries naming it), size, 12
+//!   direct block numbers, one indirect block number (a block of 256 more),
+//!   then 4 spare bytes. Block number 0 is a hole, reading as zeros.
+//! - The journal: a header block, then room for a transaction's blocks.
+//! - The data area.
+//!
+//! Inode 1 is the root directory. A directory is a file of 32-byte
+//! entries: an inode number (0 for a free entry), then up to 28 bytes of
+//! name, NUL-padded.
+//!
+//! Each change (a write, a truncate, a create) is a transaction: the
+//! superblock, bitmap, inode, indirect and directory blocks it writes are
+//! held in memory, then committed, all or none, through the journal (the
+//! device flushed between steps, as xv6 does it):
+//!
+//! 1. The blocks go into the journal, after its header.
+//! 2. The header: `JRNL`, the transactions committed so far, how many
+//!    blocks this one has, a checksum of the header and those blocks, and
+//!    where each block goes. Once it is on the disk, the change is.
+//! 3. The blocks go to their places.
+//! 4. The header again, saying 0 blocks.
+//!
+//! Mounting a volume whose header still counts blocks (a crash came
+//! between 2 and 4) puts them in place again. A header whose checksum
+//! doesn't match is one the crash tore before the commit, so nothing of
+//! the transaction is anywhere else yet. A write that fails once the
+//! header is written leaves the volume read-only: the transaction is on
+//! the disk, and mounting again puts it in place. A file's own data isn't in the
+//! journal (but for the block a cut ends in): it is written in place
+//! first, before the transaction that points at it commits, so a crash
+//! can lose a write but never leave a file with blocks of another's
+//! (ext3's "ordered" mode). A volume made before journaling (journal
+//! length 0) is written in place, and after a crash `fsck` mends it.
+//!
+//! The runner's `--crash-test` shows it: the kernel (`crashtest=<device>`
+//! on its command line) checks the volume, then changes it over and over
+//! until the runner kills QEMU, and boots again to check what's left.
+//! Each volume found at boot is mounted by its device's name, in the VFS
+//! at `/mnt/<name>`.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::vfs::{self, Dir, DirEntry, File, FileType, Inode, Metadata};
+use super::FsError;
+use crate::block::{self, BlockDevice, RamDisk};
+use crate::{cmdline, serial_println};
+use crate::sync::{Mutex, RwLock};
+
+const BLOCK_SIZE: usize = 1024;
+const MAGIC: &[u8; 4] = b"TFS1";
+const INODE_SIZE: usize = 64;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BITS_PER_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+const DIRECT: usize = 12;
+const POINTERS: usize = BLOCK_SIZE / 4;
+const MAX_FILE_SIZE: u64 = ((DIRECT + POINTERS) * BLOCK_SIZE) as u64;
+const ENTRY_SIZE: usize = 32;
+const MAX_NAME: usize = ENTRY_SIZE - 4;
+const ROOT: u32 = 1;
+/// `format` makes an inode for every this many bytes of the volume.
+const BYTES_PER_INODE: u64 = 4096;
+/// The journal `format` makes: the header and 31 blocks for a transaction,
+/// more than any change writes.
+const JOURNAL_BLOCKS: u32 = 32;
+const JOURNAL_MAGIC: &[u8; 4] = b"JRNL";
+/// Where the block numbers start in the journal header.
+const JOURNAL_HOMES: usize = 16;
+
+/// Inode kinds; 0 is a free inode.
+const KIND_FILE: u16 = 1;
+const KIND_DIR: u16 = 2;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Block 0. Where each region starts follows from the three counts; it is
+/// stored anyway, to be read off a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Superblock {
+    block_count: u32,
+    inode_count: u32,
+    inode_bitmap: u32,
+    block_bitmap: u32,
+    inode_table: u32,
+    data_start: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    /// 0 without a journal.
+    journal: u32,
+    journal_blocks: u32,
+}
+
+impl Superblock {
+    /// The layout of a volume of `block_count` blocks, `inode_count`
+    /// inodes and a journal of `journal_blocks` (maybe 0), with nothing but
+    /// the root directory in it.
+    fn new(block_count: u32, inode_count: u32, journal_blocks: u32) -> Superblock {
+        let inode_bitmap = 1;
+        let block_bitmap = inode_bitmap + inode_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table = block_bitmap + block_count.div_ceil(BITS_PER_BLOCK);
+        let journal = inode_table + inode_count.div_ceil(INODES_PER_BLOCK);
+        let data_start = journal + journal_blocks;
+        Superblock {
+            block_count,
+            inode_count,
+            inode_bitmap,
+            block_bitmap,
+            inode_table,
+            data_start,
+            free_blocks: block_count.saturating_sub(data_start),
+            free_inodes: inode_count.saturating_sub(2),
+            journal: if journal_blocks > 0 { journal } else { 0 },
+            journal_blocks,
+        }
+    }
+
+    /// How many blocks a transaction may have: as many as fit in the
+    /// journal after its header, and whose numbers fit in the header.
+    fn journal_capacity(&self) -> usize {
+        (self.journal_blocks.saturating_sub(1) as usize).min((BLOCK_SIZE - JOURNAL_HOMES) / 4)
+    }
+
+    fn parse(block: &[u8]) -> Result<Superblock, FsError> {
+        if &block[..4] != MAGIC {
+            return Err(FsError::Unsupported("not a tfs volume"));
+        }
+        let field = |i: usize| u32_at(block, 4 + i * 4);
+        let sb = Superblock {
+            block_count: field(0),
+            inode_count: field(1),
+            inode_bitmap: field(2),
+            block_bitmap: field(3),
+            inode_table: field(4),
+            data_start: field(5),
+            free_blocks: field(6),
+            free_inodes: field(7),
+            journal: field(8),
+            journal_blocks: field(9),
+        };
+        let layout = Superblock::new(sb.block_count, sb.inode_count, sb.journal_blocks);
+        if (sb.inode_bitmap, sb.block_bitmap, sb.inode_table, sb.data_start, sb.journal)
+            != (layout.inode_bitmap, layout.block_bitmap, layout.inode_table, layout.data_start, layout.journal)
+            || sb.inode_count < 2
+            || sb.data_start >= sb.block_count
+        {
+            return Err(FsError::Corrupt("superblock layout doesn't add up"));
+        }
+        Ok(sb)
+    }
+
+    fn to_block(self) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..4].copy_from_slice(MAGIC);
+        let fields = [
+            self.block_count,
+            self.inode_count,
+            self.inode_bitmap,
+            self.block_bitmap,
+            self.inode_table,
+            self.data_start,
+            self.free_blocks,
+            self.free_inodes,
+            self.journal,
+            self.journal_blocks,
+        ];
+        for (i, field) in fields.into_iter().enumerate() {
+            put_u32(&mut block, 4 + i * 4, field);
+        }
+        block
+    }
+}
+
+/// An inode as the table holds it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct DiskInode {
+    kind: u16,
+    links: u16,
+    size: u32,
+    direct: [u32; DIRECT],
+    indirect: u32,
+}
+
+impl DiskInode {
+    fn parse(bytes: &[u8]) -> DiskInode {
+        DiskInode {
+            kind: u16_at(bytes, 0),
+            links: u16_at(bytes, 2),
+            size: u32_at(bytes, 4),
+            direct: core::array::from_fn(|i| u32_at(bytes, 8 + i * 4)),
+            indirect: u32_at(bytes, 8 + DIRECT * 4),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; INODE_SIZE] {
+        let mut bytes = [0u8; INODE_SIZE];
+        bytes[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.links.to_le_bytes());
+        put_u32(&mut bytes, 4, self.size);
+        for (i, &block) in self.direct.iter().enumerate() {
+            put_u32(&mut bytes, 8 + i * 4, block);
+        }
+        put_u32(&mut bytes, 8 + DIRECT * 4, self.indirect);
+        bytes
+    }
+}
+
+/// Blocks on `device` and device blocks in one, if it can hold a volume.
+fn geometry(device: &dyn BlockDevice) -> Result<(u32, u64), FsError> {
+    let size = device.block_size();
+    if size > BLOCK_SIZE || !BLOCK_SIZE.is_multiple_of(size) {
+        return Err(FsError::Unsupported("device blocks larger than 1 KiB"));
+    }
+    let per_block = (BLOCK_SIZE / size) as u64;
+    let blocks = u32::try_from(device.block_count() / per_block).unwrap_or(u32::MAX);
+    if blocks == 0 {
+        return Err(FsError::Unsupported("empty device"));
+    }
+    Ok((blocks, per_block))
+}
+
+/// Make an empty volume on `device`, over whatever it held: the bitmaps,
+/// the inode table and an empty journal, then the superblock that makes
+/// them a volume.
+pub fn format(device: &dyn BlockDevice) -> Result<(), FsError> {
+    let (block_count, per_block) = geometry(device)?;
+    let inodes = (block_count as u64 * BLOCK_SIZE as u64 / BYTES_PER_INODE).clamp(16, u32::MAX as u64) as u32;
+    let sb = Superblock::new(block_count, inodes.next_multiple_of(INODES_PER_BLOCK), JOURNAL_BLOCKS);
+    if sb.data_start >= block_count {
+        return Err(FsError::NoSpace);
+    }
+    let write = |block: u32, data: &[u8]| device.write_blocks(block as u64 * per_block, data);
+
+    let mut inode_bitmap = vec![0u8; (sb.block_bitmap - sb.inode_bitmap) as usize * BLOCK_SIZE];
+    inode_bitmap[0] = 0b11;
+    write(sb.inode_bitmap, &inode_bitmap)?;
+    let mut block_bitmap = vec![0u8; (sb.inode_table - sb.block_bitmap) as usize * BLOCK_SIZE];
+    for block in 0..sb.data_start as usize {
+        block_bitmap[block / 8] |= 1 << (block % 8);
+    }
+    write(sb.block_bitmap, &block_bitmap)?;
+    let mut table = vec![0u8; BLOCK_SIZE];
+    let root = DiskInode { kind: KIND_DIR, links: 1, ..DiskInode::default() };
+    let at = ROOT as usize * INODE_SIZE;
+    table[at..at + INODE_SIZE].copy_from_slice(&root.to_bytes());
+    write(sb.inode_table, &table)?;
+    table.fill(0);
+    for block in sb.inode_table + 1..sb.journal {
+        write(block, &table)?;
+    }
+    // A volume that was here before may have left a transaction.
+    write(sb.journal, &journal_header(0, &[], &[]))?;
+    device.flush()?;
+    write(0, &sb.to_block())?;
+    Ok(device.flush()?)
+}
+
+/// The journal's header block, for a transaction of `copies` (its blocks,
+/// one after another) going to `homes`; none once they are in place.
+fn journal_header(sequence: u32, homes: &[u32], copies: &[u8]) -> Vec<u8> {
+    let mut header = vec![0u8; BLOCK_SIZE];
+    header[..4].copy_from_slice(JOURNAL_MAGIC);
+    put_u32(&mut header, 4, sequence);
+    put_u32(&mut header, 8, homes.len() as u32);
+    for (i, &home) in homes.iter().enumerate() {
+        put_u32(&mut header, JOURNAL_HOMES + i * 4, home);
+    }
+    let checksum = journal_checksum(&header, copies);
+    put_u32(&mut header, 12, checksum);
+    header
+}
+
+/// FNV-1a of the header, its checksum taken as 0, and the copies.
+fn journal_checksum(header: &[u8], copies: &[u8]) -> u32 {
+    let bytes = header[..12].iter().chain(&[0; 4]).chain(&header[16..]).chain(copies);
+    bytes.fold(0x811C_9DC5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Put in place the transaction the journal of `sb`'s volume holds, if
+/// one committed and wasn't all in place when the volume went down; the
+/// transactions committed so far and how many blocks went in place.
+fn replay(device: &dyn BlockDevice, per_block: u64, sb: &Superblock) -> Result<(u32, usize), FsError> {
+    if sb.journal_blocks == 0 {
+        return Ok((0, 0));
+    }
+    let at = |block: u32| block as u64 * per_block;
+    let mut header = vec![0u8; BLOCK_SIZE];
+    device.read_blocks(at(sb.journal), &mut header)?;
+    if &header[..4] != JOURNAL_MAGIC {
+        return Ok((0, 0));
+    }
+    let (sequence, count) = (u32_at(&header, 4), u32_at(&header, 8) as usize);
+    // A header that doesn't add up was torn by the crash: its transaction
+    // never committed.
+    if count == 0 || count > sb.journal_capacity() {
+        return Ok((sequence, 0));
+    }
+    let mut copies = vec![0u8; count * BLOCK_SIZE];
+    device.read_blocks(at(sb.journal + 1), &mut copies)?;
+    if journal_checksum(&header, &copies) != u32_at(&header, 12) {
+        return Ok((sequence, 0));
+    }
+    let homes: Vec<u32> = (0..count).map(|i| u32_at(&header, JOURNAL_HOMES + i * 4)).collect();
+    if homes.iter().any(|&home| home >= sb.block_count || (sb.journal..sb.data_start).contains(&home)) {
+        return Err(FsError::Corrupt("journal block goes outside the volume or into the journal"));
+    }
+    for (&home, copy) in homes.iter().zip(copies.chunks(BLOCK_SIZE)) {
+        device.write_blocks(at(home), copy)?;
+    }
+    device.flush()?;
+    device.write_blocks(at(sb.journal), &journal_header(sequence, &[], &[]))?;
+    device.flush()?;
+    Ok((sequence, count))
+}
+
+/// A mounted tfs volume.
+pub struct Tfs {
+    device: Arc<dyn BlockDevice>,
+    /// Device blocks in a block.
+    per_block: u64,
+    /// The superblock as mounted; the free counts live in `alloc`.
+    sb: Superblock,
+    /// Held by whatever changes the volume.
+    alloc: Mutex<Alloc>,
+    /// A commit failed after its header was written; nothing more is
+    /// until the volume is mounted again.
+    failed: AtomicBool,
+    /// How many blocks mounting put in place from the journal.
+    replayed: usize,
+}
+
+struct Alloc {
+    free_blocks: u32,
+    free_inodes: u32,
+    /// Transactions committed.
+    sequence: u32,
+    /// The transaction under way's blocks: only its thread, holding this,
+    /// reads them.
+    pending: Pending,
+}
+
+/// Blocks a transaction has written, by number, to go to the disk when it
+/// commits.
+type Pending = BTreeMap<u32, Vec<u8>>;
+
+/// What readers outside a transaction see of one: nothing until it commits.
+const COMMITTED: &Pending = &BTreeMap::new();
+
+impl Tfs {
+    /// Read the superblock of `device` and check that it describes a
+    /// volume that fits on it, with a directory for a root; first, put in
+    /// place what the journal holds.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Tfs, FsError> {
+        let (blocks, per_block) = geometry(&*device)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        device.read_blocks(0, &mut block)?;
+        let sb = Superblock::parse(&block)?;
+        if sb.block_count > blocks {
+            return Err(FsError::Corrupt("volume larger than its device"));
+        }
+        let (sequence, replayed) = replay(&*device, per_block, &sb)?;
+        // The free counts may have been among what was replayed.
+        device.read_blocks(0, &mut block)?;
+        let sb = Superblock::parse(&block)?;
+        let alloc = Mutex::new(Alloc { free_blocks: sb.free_blocks, free_inodes: sb.free_inodes, sequence, pending: Pending::new() });
+        let fs = Tfs { device, per_block, sb, alloc, failed: AtomicBool::new(false), replayed };
+        if fs.inode(COMMITTED, ROOT)?.kind != KIND_DIR {
+            return Err(FsError::Corrupt("the root isn't a directory"));
+        }
+        Ok(fs)
+    }
+
+    /// Block `block`, as the transaction that wrote `pending` has it.
+    fn read_block(&self, pending: &Pending, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if let Some(data) = pending.get(&block) {
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        Ok(self.device.read_blocks(block as u64 * self.per_block, buf)?)
+    }
+
+    /// Write a file's data to `block` now: it reaches the disk before the
+    /// transaction pointing at it commits.
+    fn write_data(&self, block: u32, data: &[u8]) -> Result<(), FsError> {
+        Ok(self.device.write_blocks(block as u64 * self.per_block, data)?)
+    }
+
+    /// Make what `change` does one transaction: the blocks it writes reach
+    /// their places all together, if it succeeds, else not at all. If the
+    /// commit fails once the transaction is on the disk, the new counts
+    /// stay and the volume goes read-only.
+    fn transaction<T>(&self, change: impl FnOnce(&mut Alloc) -> Result<T, FsError>) -> Result<T, FsError> {
+        let mut alloc = self.alloc.lock();
+        if self.failed.load(Ordering::Acquire) {
+            return Err(FsError::ReadOnly);
+        }
+        let before = (alloc.free_blocks, alloc.free_inodes, alloc.sequence);
+        let result = change(&mut alloc).map_err(|err| (err, false)).and_then(|value| self.commit(&mut alloc).map(|()| value));
+        result.map_err(|(err, committed)| {
+            alloc.pending.clear();
+            if committed {
+                self.failed.store(true, Ordering::Release);
+            } else {
+                (alloc.free_blocks, alloc.free_inodes, alloc.sequence) = before;
+            }
+            err
+        })
+    }
+
+    /// Commit the transaction under way, as the module's doc comment says.
+    /// A failure says whether the transaction was on the disk by then: its
+    /// header written, or without a journal, any block written in place.
+    fn commit(&self, alloc: &mut Alloc) -> Result<(), (FsError, bool)> {
+        let blocks = core::mem::take(&mut alloc.pending);
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let at = |block: u32| block as u64 * self.per_block;
+        let committed = |err: block::BlockError| (FsError::from(err), true);
+        if self.sb.journal_blocks > 0 {
+            if blocks.len() > self.sb.journal_capacity() {
+                return Err((FsError::NoSpace, false));
+            }
+            let homes: Vec<u32> = blocks.keys().copied().collect();
+            let copies: Vec<u8> = blocks.values().flatten().copied().collect();
+            alloc.sequence = alloc.sequence.wrapping_add(1);
+            let header = journal_header(alloc.sequence, &homes, &copies);
+            self.device
+                .write_blocks(at(self.sb.journal + 1), &copies)
+                .and_then(|()| self.device.flush())
+                .and_then(|()| self.device.write_blocks(at(self.sb.journal), &header))
+                .map_err(|err| (FsError::from(err), false))?;
+            self.device.flush().map_err(committed)?;
+        }
+        for (&block, data) in &blocks {
+            self.device.write_blocks(at(block), data).map_err(committed)?;
+        }
+        self.device.flush().map_err(committed)?;
+        if self.sb.journal_blocks > 0 {
+            // Not flushed: until it is on the disk, mounting puts the same
+            // blocks in place again.
+            self.device.write_blocks(at(self.sb.journal), &journal_header(alloc.sequence, &[], &[])).map_err(committed)?;
+        }
+        Ok(())
+    }
+
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), FsError> {
+        if ino == 0 || ino >= self.sb.inode_count {
+            return Err(FsError::Corrupt("inode number out of range"));
+        }
+        Ok((self.sb.inode_table + ino / INODES_PER_BLOCK, (ino % INODES_PER_BLOCK) as usize * INODE_SIZE))
+    }
+
+    fn inode(&self, pending: &Pending, ino: u32) -> Result<DiskInode, FsError> {
+        let (block, at) = self.inode_location(ino)?;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_block(pending, block, &mut buf)?;
+        Ok(DiskInode::parse(&buf[at..at + INODE_SIZE]))
+    }
+
+    fn write_inode(&self, pending: &mut Pending, ino: u32, inode: &DiskInode) -> Result<(), FsError> {
+        let (block, at) = self.inode_location(ino)?;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_block(pending, block, &mut buf)?;
+        buf[at..at + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+        pending.insert(block, buf);
+        Ok(())
+    }
+
+    /// The block holding each `BLOCK_SIZE` of `inode`'s size, 0 for a hole.
+    fn blocks(&self, pending: &Pending, inode: &DiskInode) -> Result<Vec<u32>, FsError> {
+        let count = (inode.size as u64).div_ceil(BLOCK_SIZE as u64) as usize;
+        let mut blocks = inode.direct.to_vec();
+        if count > DIRECT && inode.indirect != 0 {
+            self.check_data_block(inode.indirect)?;
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            self.read_block(pending, inode.indirect, &mut buf)?;
+            blocks.extend((0..POINTERS).map(|i| u32_at(&buf, i * 4)));
+        }
+        blocks.resize(count, 0);
+        for &block in &blocks {
+            if block != 0 {
+                self.check_data_block(block)?;
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn check_data_block(&self, block: u32) -> Result<(), FsError> {
+        if !(self.sb.data_start..self.sb.block_count).contains(&block) {
+            return Err(FsError::Corrupt("block number outside the data area"));
+        }
+        Ok(())
+    }
+
+    /// Read from byte `offset` of `inode` into `buf`; how many bytes,
+    /// fewer than asked at the end.
+    fn read(&self, pending: &Pending, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = inode.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let blocks = self.blocks(pending, inode)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < len {
+            let at = offset as usize + done;
+            let start = at % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - start).min(len - done);
+            match blocks[at / BLOCK_SIZE] {
+                0 => block.fill(0),
+                number => self.read_block(pending, number, &mut block)?,
+            }
+            buf[done..done + n].copy_from_slice(&block[start..start + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Every entry of directory `dir`, free ones included: inode number
+    /// (0 if free) and name.
+    fn slots(&self, pending: &Pending, dir: &DiskInode) -> Result<Vec<(u32, String)>, FsError> {
+        if dir.kind != KIND_DIR {
+            return Err(FsError::NotADirectory);
+        }
+        let mut data = vec![0u8; dir.size as usize];
+        self.read(pending, dir, 0, &mut data)?;
+        data.as_chunks::<ENTRY_SIZE>().0.iter()
+            .map(|entry| {
+                let name = &entry[4..];
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                match core::str::from_utf8(&name[..len]) {
+                    Ok(name) => Ok((u32_at(entry, 0), String::from(name))),
+                    Err(_) => Err(FsError::Corrupt("directory entry name isn't UTF-8")),
+                }
+            })
+            .collect()
+    }
+
+    fn entries(&self, pending: &Pending, dir: &DiskInode) -> Result<Vec<(u32, String)>, FsError> {
+        Ok(self.slots(pending, dir)?.into_iter().filter(|&(ino, _)| ino != 0).collect())
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let entries = self.entries(COMMITTED, &self.inode(COMMITTED, dir)?)?;
+        entries.into_iter().find(|(_, n)| n == name).map(|(ino, _)| ino).ok_or(FsError::NotFound)
+    }
+
+    /// Up to `limit` clear bits among the first `bits` of the bitmap
+    /// starting at block `start`, lowest first.
+    fn clear_bits(&self, pending: &Pending, start: u32, bits: u32, limit: usize) -> Result<Vec<u32>, FsError> {
+        let mut clear = Vec::new();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for block in 0..bits.div_ceil(BITS_PER_BLOCK) {
+            self.read_block(pending, start + block, &mut buf)?;
+            for (i, &byte) in buf.iter().enumerate().filter(|&(_, &byte)| byte != 0xFF) {
+                for bit in 0..8 {
+                    let n = block * BITS_PER_BLOCK + i as u32 * 8 + bit;
+                    if clear.len() == limit || n >= bits {
+                        return Ok(clear);
+                    }
+                    if byte & 1 << bit == 0 {
+                        clear.push(n);
+                    }
+                }
+            }
+        }
+        Ok(clear)
+    }
+
+    /// Set or clear bits `numbers` of the bitmap starting at block `start`.
+    fn set_bits(&self, pending: &mut Pending, start: u32, numbers: &[u32], value: bool) -> Result<(), FsError> {
+        let mut numbers = numbers.to_vec();
+        numbers.sort_unstable();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut loaded = None;
+        for n in numbers {
+            let block = n / BITS_PER_BLOCK;
+            if loaded != Some(block) {
+                if let Some(done) = loaded {
+                    pending.insert(start + done, buf.clone());
+                }
+                self.read_block(pending, start + block, &mut buf)?;
+                loaded = Some(block);
+            }
+            let (byte, bit) = ((n % BITS_PER_BLOCK / 8) as usize, n % 8);
+            if value {
+                buf[byte] |= 1 << bit;
+            } else {
+                buf[byte] &= !(1 << bit);
+            }
+        }
+        if let Some(block) = loaded {
+            pending.insert(start + block, buf);
+        }
+        Ok(())
+    }
+
+    /// Mark `blocks` in use or free, and count them in the superblock.
+    fn mark_blocks(&self, alloc: &mut Alloc, blocks: &[u32], used: bool) -> Result<(), FsError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        self.set_bits(&mut alloc.pending, self.sb.block_bitmap, blocks, used)?;
+        let count = blocks.len() as u32;
+        alloc.free_blocks = if used { alloc.free_blocks.saturating_sub(count) } else { alloc.free_blocks + count };
+        self.store_counts(alloc)
+    }
+
+    fn mark_inode(&self, alloc: &mut Alloc, ino: u32, used: bool) -> Result<(), FsError> {
+        self.set_bits(&mut alloc.pending, self.sb.inode_bitmap, &[ino], used)?;
+        alloc.free_inodes = if used { alloc.free_inodes.saturating_sub(1) } else { alloc.free_inodes + 1 };
+        self.store_counts(alloc)
+    }
+
+    fn store_counts(&self, alloc: &mut Alloc) -> Result<(), FsError> {
+        let sb = Superblock { free_blocks: alloc.free_blocks, free_inodes: alloc.free_inodes, ..self.sb };
+        alloc.pending.insert(0, sb.to_block());
+        Ok(())
+    }
+
+    /// Write `data` to inode `ino` from byte `offset`, growing it as
+    /// needed; blocks for the holes written into are allocated, others
+    /// past its end stay holes. `inode` is updated to match. A directory's
+    /// entries go in the transaction, a file's data straight to the disk.
+    fn write_locked(&self, alloc: &mut Alloc, ino: u32, inode: &mut DiskInode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = (inode.size as u64).max(offset + data.len() as u64);
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::Unsupported("files of more than 268 KiB"));
+        }
+        let mut blocks = self.blocks(&alloc.pending, inode)?;
+        blocks.resize(end.div_ceil(BLOCK_SIZE as u64) as usize, 0);
+        let (first, last) = ((offset / BLOCK_SIZE as u64) as usize, ((offset + data.len() as u64 - 1) / BLOCK_SIZE as u64) as usize);
+        let holes = blocks[first..=last].iter().filter(|&&block| block == 0).count();
+        let new_indirect = last >= DIRECT && inode.indirect == 0;
+        let mut new = self.clear_bits(&alloc.pending, self.sb.block_bitmap, self.sb.block_count, holes + new_indirect as usize)?;
+        if new.len() < holes + new_indirect as usize {
+            return Err(FsError::NoSpace);
+        }
+        let indirect = if new_indirect { new.pop().expect("a block for it") } else { inode.indirect };
+
+        let mut fresh = new.iter();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for (i, block) in blocks.iter_mut().enumerate().take(last + 1).skip(first) {
+            let from = (i * BLOCK_SIZE).max(offset as usize);
+            let to = ((i + 1) * BLOCK_SIZE).min(offset as usize + data.len());
+            if *block == 0 {
+                *block = *fresh.next().expect("a block for each hole");
+                buf.fill(0);
+            } else if to - from < BLOCK_SIZE {
+                self.read_block(&alloc.pending, *block, &mut buf)?;
+            }
+            let at = offset as usize;
+            buf[from % BLOCK_SIZE..from % BLOCK_SIZE + (to - from)].copy_from_slice(&data[from - at..to - at]);
+            if inode.kind == KIND_DIR {
+                alloc.pending.insert(*block, buf.clone());
+            } else {
+                self.write_data(*block, &buf)?;
+            }
+        }
+        if new_indirect {
+            new.push(indirect);
+        }
+        self.mark_blocks(alloc, &new, true)?;
+        if last >= DIRECT && !new.is_empty() {
+            let mut pointers = vec![0u8; BLOCK_SIZE];
+            for (i, &block) in blocks.iter().skip(DIRECT).enumerate() {
+                put_u32(&mut pointers, i * 4, block);
+            }
+            alloc.pending.insert(indirect, pointers);
+        }
+        for (slot, &block) in inode.direct.iter_mut().zip(&blocks) {
+            *slot = block;
+        }
+        inode.indirect = indirect;
+        inode.size = end as u32;
+        self.write_inode(&mut alloc.pending, ino, inode)
+    }
+
+    fn write(&self, ino: u32, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.transaction(|alloc| {
+            let mut inode = self.inode(&alloc.pending, ino)?;
+            if inode.kind != KIND_FILE {
+                return Err(FsError::IsADirectory);
+            }
+            self.write_locked(alloc, ino, &mut inode, offset, data)?;
+            Ok(data.len())
+        })
+    }
+
+    /// Make file `ino` `size` bytes long: cut it, freeing the blocks it no
+    /// longer needs, or grow it with a hole. The bytes of its last block
+    /// past the end are zeroed, so that growing it again reads zeros.
+    fn truncate(&self, ino: u32, size: u64) -> Result<(), FsError> {
+        self.transaction(|alloc| {
+            let mut inode = self.inode(&alloc.pending, ino)?;
+            if inode.kind != KIND_FILE {
+                return Err(FsError::IsADirectory);
+            }
+            if size > MAX_FILE_SIZE {
+                return Err(FsError::Unsupported("files of more than 268 KiB"));
+            }
+            if size >= inode.size as u64 {
+                inode.size = size as u32;
+                return self.write_inode(&mut alloc.pending, ino, &inode);
+            }
+            let blocks = self.blocks(&alloc.pending, &inode)?;
+            let keep = size.div_ceil(BLOCK_SIZE as u64) as usize;
+            let tail = size as usize % BLOCK_SIZE;
+            // The zeroed tail goes in the transaction: on the disk before
+            // the cut, it would lose bytes the file still has.
+            if tail != 0 && blocks[keep - 1] != 0 {
+                let mut buf = vec![0u8; BLOCK_SIZE];
+                self.read_block(&alloc.pending, blocks[keep - 1], &mut buf)?;
+                buf[tail..].fill(0);
+                alloc.pending.insert(blocks[keep - 1], buf);
+            }
+            let mut freed: Vec<u32> = blocks[keep..].iter().copied().filter(|&block| block != 0).collect();
+            inode.direct[keep.min(DIRECT)..].fill(0);
+            if keep <= DIRECT && inode.indirect != 0 {
+                freed.push(inode.indirect);
+                inode.indirect = 0;
+            }
+            inode.size = size as u32;
+            self.write_inode(&mut alloc.pending, ino, &inode)?;
+            if keep > DIRECT && inode.indirect != 0 {
+                let mut pointers = vec![0u8; BLOCK_SIZE];
+                for (i, &block) in blocks[DIRECT..keep].iter().enumerate() {
+                    put_u32(&mut pointers, i * 4, block);
+                }
+                alloc.pending.insert(inode.indirect, pointers);
+            }
+            self.mark_blocks(alloc, &freed, false)
+        })
+    }
+
+    /// Create `name` in directory `dir`, of `kind`, empty: a free inode,
+    /// and an entry naming it, in the first free slot or at the end.
+    fn create(&self, dir: u32, name: &str, kind: u16) -> Result<u32, FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME || name.contains(['/', '\0']) {
+            return Err(FsError::InvalidName);
+        }
+        self.transaction(|alloc| {
+            let mut parent = self.inode(&alloc.pending, dir)?;
+            let slots = self.slots(&alloc.pending, &parent)?;
+            if slots.iter().any(|(ino, n)| *ino != 0 && n == name) {
+                return Err(FsError::Exists);
+            }
+            let ino = *self.clear_bits(&alloc.pending, self.sb.inode_bitmap, self.sb.inode_count, 1)?.first().ok_or(FsError::NoSpace)?;
+            self.write_inode(&mut alloc.pending, ino, &DiskInode { kind, links: 1, ..DiskInode::default() })?;
+            self.mark_inode(alloc, ino, true)?;
+
+            let slot = slots.iter().position(|&(ino, _)| ino == 0).unwrap_or(slots.len());
+            let mut entry = [0u8; ENTRY_SIZE];
+            put_u32(&mut entry, 0, ino);
+            entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+            self.write_locked(alloc, dir, &mut parent, (slot * ENTRY_SIZE) as u64, &entry)?;
+            Ok(ino)
+        })
+    }
+
+    /// What is wrong with the volume, none if it is consistent: entries
+    /// naming inodes that aren't in use, link counts other than the
+    /// entries naming an inode, blocks claimed twice, bitmaps other than
+    /// what the inodes use, and free counts other than the bitmaps'. What
+    /// `fsck` checks, without mending anything.
+    fn check(&self) -> Result<Vec<String>, FsError> {
+        let alloc = self.alloc.lock();
+        let sb = self.sb;
+        let mut problems = Vec::new();
+        let mut refs = vec![0u32; sb.inode_count as usize];
+        refs[ROOT as usize] = 1;
+        let mut dirs = vec![ROOT];
+        while let Some(dir) = dirs.pop() {
+            for (ino, name) in self.entries(COMMITTED, &self.inode(COMMITTED, dir)?)? {
+                if ino >= sb.inode_count {
+                    problems.push(format!("directory {}: {} names inode {}, out of range", dir, name, ino));
+                    continue;
+                }
+                refs[ino as usize] += 1;
+                match self.inode(COMMITTED, ino)?.kind {
+                    KIND_DIR if refs[ino as usize] == 1 => dirs.push(ino),
+                    KIND_DIR | KIND_FILE => {}
+                    _ => problems.push(format!("directory {}: {} names inode {}, not in use", dir, name, ino)),
+                }
+            }
+        }
+
+        let mut inodes_used = vec![false; sb.inode_count as usize];
+        let mut owner = vec![0u32; sb.block_count as usize];
+        inodes_used[0] = true;
+        for ino in ROOT..sb.inode_count {
+            let inode = self.inode(COMMITTED, ino)?;
+            if inode.kind == 0 {
+                continue;
+            }
+            inodes_used[ino as usize] = true;
+            if inode.links as u32 != refs[ino as usize] {
+                problems.push(format!("inode {}: links {}, but {} entries name it", ino, inode.links, refs[ino as usize]));
+            }
+            let mut blocks = match self.blocks(COMMITTED, &inode) {
+                Ok(blocks) => blocks,
+                Err(err) => {
+                    problems.push(format!("inode {}: {}", ino, err));
+                    continue;
+                }
+            };
+            if inode.indirect != 0 && self.check_data_block(inode.indirect).is_ok() {
+                blocks.push(inode.indirect);
+            }
+            for block in blocks.into_iter().filter(|&block| block != 0) {
+                match owner[block as usize] {
+                    0 => owner[block as usize] = ino,
+                    other => problems.push(format!("block {}: inode {}'s and inode {}'s", block, other, ino)),
+                }
+            }
+        }
+        let blocks_used: Vec<bool> = (0..sb.block_count).map(|block| block < sb.data_start || owner[block as usize] != 0).collect();
+
+        let mut free = [0; 2];
+        for (i, (what, start, used)) in [("inode", sb.inode_bitmap, &inodes_used), ("block", sb.block_bitmap, &blocks_used)].into_iter().enumerate() {
+            let mut marked = vec![true; used.len()];
+            for n in self.clear_bits(COMMITTED, start, used.len() as u32, usize::MAX)? {
+                marked[n as usize] = false;
+            }
+            let wrong = (0..used.len()).filter(|&n| marked[n] != used[n]).count();
+            if wrong > 0 {
+                problems.push(format!("{} bitmap: {} bits other than the inodes say", what, wrong));
+            }
+            free[i] = marked.iter().filter(|&&marked| !marked).count() as u32;
+        }
+        if [alloc.free_inodes, alloc.free_blocks] != free {
+            problems.push(format!(
+                "superblock: {} free inodes and {} free blocks, but the bitmaps have {} and {}",
+                alloc.free_inodes, alloc.free_blocks, free[0], free[1]
+            ));
+        }
+        Ok(problems)
+    }
+
+    /// Its root directory, for the VFS.
+    pub fn root_dir(self: &Arc<Self>) -> Arc<dyn Dir> {
+        Arc::new(TfsNode { fs: self.clone(), ino: ROOT })
+    }
+
+    fn describe(&self) -> String {
+        let alloc = self.alloc.lock();
+        let journal = match self.sb.journal_blocks {
+            0 => String::from("no journal"),
+            blocks => format!("journal of {} blocks, {} transactions", blocks, alloc.sequence),
+        };
+        format!(
+            "{} blocks of 1 KiB, {} free; {} inodes, {} free; {}",
+            self.sb.block_count, alloc.free_blocks, self.sb.inode_count, alloc.free_inodes, journal
+        )
+    }
+}
+
+fn metadata(inode: &DiskInode) -> Metadata {
+    match inode.kind {
+        KIND_DIR => Metadata { kind: FileType::Directory, mode: 0o755, size: inode.size as u64 },
+        _ => Metadata { kind: FileType::Regular, mode: 0o644, size: inode.size as u64 },
+    }
+}
+
+/// A file or directory, as the VFS sees it: its inode number. Every call
+/// reads the inode afresh, so all the handles on a file agree.
+struct TfsNode {
+    fs: Arc<Tfs>,
+    ino: u32,
+}
+
+impl TfsNode {
+    fn node(&self, ino: u32) -> Result<vfs::Node, FsError> {
+        let node = TfsNode { fs: self.fs.clone(), ino };
+        Ok(match self.fs.inode(COMMITTED, ino)?.kind {
+            KIND_DIR => vfs::Node::Dir(Arc::new(node)),
+            KIND_FILE => vfs::Node::File(Arc::new(node)),
+            _ => return Err(FsError::Corrupt("directory entry names a free inode")),
+        })
+    }
+}
+
+impl Inode for TfsNode {
+    fn metadata(&self) -> Metadata {
+        // An inode that can't be read shows as an empty file; reading it
+        // gives the error.
+        self.fs.inode(COMMITTED, self.ino).map_or(Metadata { kind: FileType::Regular, mode: 0, size: 0 }, |inode| metadata(&inode))
+    }
+}
+
+impl File for TfsNode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.fs.inode(COMMITTED, self.ino)?;
+        if inode.kind != KIND_FILE {
+            return Err(FsError::IsADirectory);
+        }
+        self.fs.read(COMMITTED, &inode, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.write(self.ino, offset, data)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.truncate(self.ino, size)
+    }
+
+    fn writable(&self) -> bool {
+        !self.fs.device.read_only() && !self.fs.failed.load(Ordering::Acquire)
+    }
+}
+
+impl Dir for TfsNode {
+    fn lookup(&self, name: &str) -> Result<vfs::Node, FsError> {
+        self.node(self.fs.lookup(self.ino, name)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let entries = self.fs.entries(COMMITTED, &self.fs.inode(COMMITTED, self.ino)?)?;
+        entries
+            .into_iter()
+            .map(|(ino, name)| Ok(DirEntry { name, metadata: metadata(&self.fs.inode(COMMITTED, ino)?) }))
+            .collect()
+    }
+
+    fn create(&self, name: &str) -> Result<Arc<dyn File>, FsError> {
+        let ino = self.fs.create(self.ino, name, KIND_FILE)?;
+        Ok(Arc::new(TfsNode { fs: self.fs.clone(), ino }))
+    }
+
+    fn mkdir(&self, name: &str) -> Result<(), FsError> {
+        self.fs.create(self.ino, name, KIND_DIR).map(|_| ())
+    }
+}
+
+/// Mounted volumes, by device name.
+static VOLUMES: RwLock<Vec<(String, Arc<Tfs>)>> = RwLock::new(Vec::new());
+
+/// Mount the tfs volume on block device `name`.
+pub fn mount(name: &str) -> Result<Arc<Tfs>, FsError> {
+    if let Some(fs) = volume(name) {
+        return Ok(fs);
+    }
+    let device = block::cache::get(name).ok_or(FsError::NotFound)?;
+    let fs = Arc::new(Tfs::mount(device)?);
+    if fs.replayed > 0 {
+        serial_println!("tfs: {}: put {} blocks in place from the journal", name, fs.replayed);
+    }
+    serial_println!("tfs: {}: {}", name, fs.describe());
+    VOLUMES.write().push((String::from(name), fs.clone()));
+    vfs::mount(&format!("/mnt/{}", name), "tfs", name, fs.root_dir());
+    Ok(fs)
+}
+
+fn volume(name: &str) -> Option<Arc<Tfs>> {
+    VOLUMES.read().iter().find(|(n, _)| n == name).map(|(_, fs)| fs.clone())
+}
+
+/// Mount every block device that holds a tfs volume.
+pub fn probe() {
+    for name in block::names() {
+        if let Err(err @ (FsError::Corrupt(_) | FsError::Io(_))) = mount(&name) {
+            serial_println!("tfs: {}: {}", name, err);
+        }
+    }
+}
+
+/// Make an empty volume on block device `name` and mount it. Not on a
+/// device with a volume mounted from it.
+pub fn mkfs(name: &str) {
+    if volume(name).is_some() {
+        return serial_println!("tfs: {} is mounted", name);
+    }
+    let Some(device) = block::cache::get(name) else {
+        return serial_println!("tfs: no device {}", name);
+    };
+    if let Err(err) = format(&*device).and_then(|()| mount(name).map(|_| ())) {
+        serial_println!("tfs: {}: {}", name, err);
+    }
+}
+
+pub fn list() {
+    let volumes = VOLUMES.read();
+    if volumes.is_empty() {
+        return serial_println!("tfs: no volumes");
+    }
+    for (name, fs) in volumes.iter() {
+        serial_println!("  {:<8} {}", name, fs.describe());
+    }
+}
+
+/// Check the volume mounted from device `name`, printing what is wrong;
+/// whether it is consistent.
+pub fn check(name: &str) -> bool {
+    let Some(fs) = volume(name) else {
+        serial_println!("tfs: {} isn't mounted", name);
+        return false;
+    };
+    match fs.check() {
+        Ok(problems) if problems.is_empty() => {
+            serial_println!("tfs: {}: consistent", name);
+            true
+        }
+        Ok(problems) => {
+            for problem in &problems {
+                serial_println!("  {}", problem);
+            }
+            serial_println!("tfs: {}: {} problems", name, problems.len());
+            false
+        }
+        Err(err) => {
+            serial_println!("tfs: {}: {}", name, err);
+            false
+        }
+    }
+}
+
+/// The crash test, if the command line asks for it (`crashtest=<device>`,
+/// as the runner's `--crash-test` does): check the volume on the device,
+/// making one if there is none, then change it until the runner kills the
+/// machine. Each round writes 20 KiB, past the direct blocks, to one of 16
+/// files, or cuts one back to nothing, so blocks are taken and freed all
+/// the time. The runner reads what this prints.
+pub fn crash_test() {
+    let Some(name) = cmdline::get("crashtest") else { return };
+    let fs = match mount(name) {
+        Err(FsError::Unsupported(_)) => {
+            serial_println!("crashtest: {}: no volume, making one", name);
+            let device = block::cache::get(name).ok_or(FsError::NotFound);
+            device.and_then(|device| format(&*device)).and_then(|()| mount(name))
+        }
+        fs => fs,
+    };
+    let fs = match fs {
+        Ok(fs) => fs,
+        Err(err) => return serial_println!("crashtest: {}: check: {}", name, err),
+    };
+    let verdict = if check(name) { "consistent" } else { "INCONSISTENT" };
+    serial_println!("crashtest: {}: check: {}", name, verdict);
+
+    let root = fs.root_dir();
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect();
+    serial_println!("crashtest: {}: writing", name);
+    for round in 0u64.. {
+        let file = format!("f{}", round % 16);
+        let result = match root.lookup(&file) {
+            Ok(vfs::Node::File(file)) if file.metadata().size > 0 => file.truncate(0),
+            Ok(vfs::Node::File(file)) => file.write_at(0, &data).map(|_| ()),
+            _ => root.create(&file).map(|_| ()),
+        };
+        if let Err(err) = result {
+            return serial_println!("crashtest: {}: round {}: {}", name, round, err);
+        }
+        if round % 64 == 0 {
+            serial_println!("crashtest: {}: round {}", name, round);
+        }
+    }
+}
+
+/// On a 1 MiB RAM disk: a fresh volume mounts with only the root in use.
+/// A file written across blocks and past the direct blocks (leaving a
+/// hole) reads back, and cut and grown again reads zeros past the cut;
+/// emptied, every block is free again, the bitmap and the counts agreeing.
+/// A directory grows past a block of entries; names clash or are refused;
+/// what was written is there after mounting again, and the volume checks
+/// consistent. The journal: a header whose checksum doesn't match isn't
+/// replayed, a whole one is, once; and a device that stops writing after
+/// any number of writes, as a crash would, leaves a consistent volume with
+/// the change whole or not at all; one whose writes start failing leaves
+/// it taken back or the volume read-only, and consistent once mounted
+/// again. A device without a volume doesn't mount.
+pub fn self_test() -> bool {
+    let Some(disk) = RamDisk::new(512, 2048) else { return false };
+    let disk: Arc<dyn BlockDevice> = Arc::new(disk);
+    if format(&*disk).is_err() {
+        return false;
+    }
+    let Ok(fs) = Tfs::mount(disk.clone()) else { return false };
+    let fs = Arc::new(fs);
+    let free = || {
+        let alloc = fs.alloc.lock();
+        let bitmap = fs.clear_bits(COMMITTED, fs.sb.block_bitmap, fs.sb.block_count, usize::MAX).map(|clear| clear.len() as u32);
+        (bitmap == Ok(alloc.free_blocks)).then_some(alloc.free_blocks)
+    };
+    let Some(empty) = free() else { return false };
+    let mut ok = fs.sb.inode_count == 256 && fs.sb.data_start == 19 + JOURNAL_BLOCKS && empty == 1024 - 19 - JOURNAL_BLOCKS;
+    ok &= fs.entries(COMMITTED, &fs.inode(COMMITTED, ROOT).unwrap_or_default()) == Ok(Vec::new());
+
+    let root = fs.root_dir();
+    let Ok(file) = root.create("file") else { return false };
+    // The root's first block of entries.
+    let Some(start) = free() else { return false };
+    ok &= start == empty - 1;
+    let data: Vec<u8> = (0..3000).map(|i| (i * 7 + 1) as u8).collect();
+    let far = 20 * BLOCK_SIZE as u64;
+    let mut back = vec![0u8; far as usize + 3];
+    ok &= file.write_at(0, &data) == Ok(3000)
+        && file.write_at(far, b"end") == Ok(3)
+        && file.metadata().size == far + 3
+        && file.read_at(0, &mut back) == Ok(back.len())
+        && back[..3000] == data[..]
+        && back[3000..far as usize].iter().all(|&b| b == 0)
+        && &back[far as usize..] == b"end";
+    // Three blocks of data, the one at the end, and the indirect block.
+    ok &= free() == Some(start - 5);
+    ok &= file.truncate(1500).is_ok()
+        && free() == Some(start - 2)
+        && file.truncate(2500).is_ok()
+        && file.read_at(0, &mut back) == Ok(2500)
+        && back[..1500] == data[..1500]
+        && back[1500..2500].iter().all(|&b| b == 0);
+    ok &= file.truncate(0).is_ok() && free() == Some(start);
+    ok &= file.write_at(MAX_FILE_SIZE, b"x") == Err(FsError::Unsupported("files of more than 268 KiB"));
+
+    // 32 entries fill a block.
+    ok &= root.mkdir("dir").is_ok();
+    let Ok(vfs::Node::Dir(dir)) = root.lookup("dir") else { return false };
+    ok &= (0..40).all(|i| dir.create(&format!("f{}", i)).is_ok())
+        && dir.read_dir().map(|entries| entries.len()) == Ok(40)
+        && dir.metadata().size == 40 * ENTRY_SIZE as u64
+        && matches!(dir.lookup("f39"), Ok(vfs::Node::File(_)));
+    ok &= root.create("file").err() == Some(FsError::Exists)
+        && root.create("a/b").err() == Some(FsError::InvalidName)
+        && root.create("a name that is far too long for tfs").err() == Some(FsError::InvalidName)
+        && root.lookup("missing").err() == Some(FsError::NotFound)
+        && file.write_at(0, b"kept").is_ok();
+
+    let counts = |fs: &Tfs| {
+        let alloc = fs.alloc.lock();
+        (alloc.free_blocks, alloc.free_inodes, alloc.sequence)
+    };
+    let Ok(again) = Tfs::mount(disk.clone()) else { return false };
+    let again = Arc::new(again);
+    ok &= counts(&again) == counts(&fs)
+        && again.replayed == 0
+        && again.check() == Ok(Vec::new())
+        && matches!(again.root_dir().lookup("file"), Ok(vfs::Node::File(file)) if file.metadata().size == 4);
+
+    // A transaction changing the superblock's free inode count to 7.
+    let at = |block: u32| block as u64 * again.per_block;
+    let copy = Superblock { free_inodes: 7, ..again.sb }.to_block();
+    let torn = journal_header(100, &[0], &vec![0u8; BLOCK_SIZE]);
+    ok &= disk.write_blocks(at(again.sb.journal + 1), &copy).is_ok()
+        && disk.write_blocks(at(again.sb.journal), &torn).is_ok()
+        && Tfs::mount(disk.clone()).is_ok_and(|fs| fs.replayed == 0 && counts(&fs).1 != 7);
+    ok &= disk.write_blocks(at(again.sb.journal), &journal_header(100, &[0], &copy)).is_ok()
+        && Tfs::mount(disk.clone()).is_ok_and(|fs| fs.replayed == 1 && counts(&fs).1 == 7 && counts(&fs).2 == 100)
+        && Tfs::mount(disk).is_ok_and(|fs| fs.replayed == 0 && counts(&fs).1 == 7);
+    ok &= (0..40).all(crash_after) && (0..40).all(fail_after);
+
+    let Some(blank) = RamDisk::new(512, 64) else { return false };
+    ok && matches!(Tfs::mount(Arc::new(blank)), Err(FsError::Unsupported(_)))
+}
+
+/// A RAM disk that drops every write after the first `left`, as a machine
+/// going down mid-change would, or with `fail`, refuses them.
+struct Crashing {
+    disk: RamDisk,
+    left: AtomicUsize,
+    fail: bool,
+}
+
+impl BlockDevice for Crashing {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), block::BlockError> {
+        self.disk.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), block::BlockError> {
+        let mut left = self.left.load(Ordering::Relaxed);
+        while left > 0 {
+            match self.left.compare_exchange_weak(left, left - 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return self.disk.write_blocks(start, data),
+                Err(now) => left = now,
+            }
+        }
+        if self.fail {
+            return Err(block::BlockError::Io("write refused"));
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), block::BlockError> {
+        self.disk.flush()
+    }
+
+    fn describe(&self) -> String {
+        String::from("crashing RAM disk")
+    }
+}
+
+/// Create a file and write 20 KiB to it, on a device that goes down after
+/// `writes` writes; whether the volume, mounted again, is consistent, with
+/// the file not there, empty, or whole.
+fn crash_after(writes: usize) -> bool {
+    let Some(disk) = RamDisk::new(512, 2048) else { return false };
+    let device = Arc::new(Crashing { disk, left: AtomicUsize::new(usize::MAX), fail: false });
+    let Ok(fs) = format(&*device).and_then(|()| Tfs::mount(device.clone())) else { return false };
+    device.left.store(writes, Ordering::Relaxed);
+    let data = vec![7u8; 20 * BLOCK_SIZE];
+    let _ = Arc::new(fs).root_dir().create("file").and_then(|file| file.write_at(0, &data));
+    device.left.store(usize::MAX, Ordering::Relaxed);
+
+    let Ok(fs) = Tfs::mount(device) else { return false };
+    let fs = Arc::new(fs);
+    let mut back = vec![0u8; data.len()];
+    fs.check() == Ok(Vec::new())
+        && match fs.root_dir().lookup("file") {
+            Ok(vfs::Node::File(file)) => file.metadata().size == 0 || (file.read_at(0, &mut back) == Ok(data.len()) && back == data),
+            Err(err) => err == FsError::NotFound,
+            Ok(_) => false,
+        }
+}
+
+/// Create a file and write 20 KiB to it, on a device whose writes fail
+/// after the first `writes`; whether the volume took back what failed, its
+/// counts agreeing with the disk, or went read-only, and mounted again is
+/// consistent.
+fn fail_after(writes: usize) -> bool {
+    let Some(disk) = RamDisk::new(512, 2048) else { return false };
+    let device = Arc::new(Crashing { disk, left: AtomicUsize::new(usize::MAX), fail: true });
+    let Ok(fs) = format(&*device).and_then(|()| Tfs::mount(device.clone())) else { return false };
+    let fs = Arc::new(fs);
+    device.left.store(writes, Ordering::Relaxed);
+    let data = vec![7u8; 20 * BLOCK_SIZE];
+    let failed = fs.root_dir().create("file").and_then(|file| file.write_at(0, &data)).is_err();
+    device.left.store(usize::MAX, Ordering::Relaxed);
+
+    let ok = if failed && fs.failed.load(Ordering::Acquire) {
+        fs.root_dir().create("more").err() == Some(FsError::ReadOnly)
+    } else {
+        fs.check() == Ok(Vec::new())
+    };
+    ok && Tfs::mount(device).is_ok_and(|fs| fs.check() == Ok(Vec::new()))
+}
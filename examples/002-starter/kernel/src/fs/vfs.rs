@@ -0,0 +1,430 @@
+//! The virtual filesystem: one tree of paths over all the mounted
+//! filesystems. Each filesystem hands out its files and directories as
+//! `File` and `Dir` trait objects; the VFS walks a path from `/` asking
+//! each directory for the next name, and where a filesystem is mounted on
+//! a directory it carries on in that filesystem's root instead. `..` goes
+//! back the way the walk came, so it crosses mounts too, and symbolic
+//! links splice their target into what is left of the path.
+//!
+//! A mount point needn't exist: the directories on the way to one are
+//! made up, empty, as `/mnt` is for `/mnt/hd0p1` on an initrd without it.
+//! Listing a directory shows what is mounted in it.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{components, FsError};
+use crate::serial_println;
+use crate::sync::RwLock;
+
+/// Symbolic links followed in one walk before giving up on a loop.
+const MAX_SYMLINKS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// Permission bits, `0o644` and the like.
+    pub mode: u32,
+    /// Bytes for a file or a block device, the target's length for a link;
+    /// for a directory, whatever its filesystem says; 0 for a character
+    /// device.
+    pub size: u64,
+}
+
+/// What every file and directory has.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+}
+
+pub trait File: Inode {
+    /// Read from byte `offset` into `buf`; how many bytes, 0 at the end.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Write `data` at byte `offset`, growing the file if it goes past
+    /// the end; how many bytes.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Whether `write_at` can work, for `open` to refuse early.
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+pub trait Dir: Inode {
+    /// The entry called `name`; never `.` or `..`, which the VFS handles.
+    fn lookup(&self, name: &str) -> Result<Node, FsError>;
+
+    /// The entries, without `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Create an empty file `name`.
+    fn create(&self, _name: &str) -> Result<Arc<dyn File>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn mkdir(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// What a name in a directory refers to.
+#[derive(Clone)]
+pub enum Node {
+    File(Arc<dyn File>),
+    Dir(Arc<dyn Dir>),
+    /// A symbolic link and its target.
+    Symlink(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+struct Mount {
+    /// Where: `/`, `/mnt/hd0p1`.
+    path: String,
+    fs_type: &'static str,
+    /// What: a device name, `initrd`.
+    source: String,
+    root: Arc<dyn Dir>,
+}
+
+/// A directory made up on the way to a mount point.
+struct MountPoint;
+
+impl Inode for MountPoint {
+    fn metadata(&self) -> Metadata {
+        Metadata { kind: FileType::Directory, mode: 0o755, size: 0 }
+    }
+}
+
+impl Dir for MountPoint {
+    fn lookup(&self, _name: &str) -> Result<Node, FsError> {
+        Err(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(Vec::new())
+    }
+}
+
+/// A namespace: filesystems mounted at paths.
+struct Vfs {
+    mounts: RwLock<Vec<Mount>>,
+}
+
+/// The kernel's namespace, the one user programs see.
+static VFS: Vfs = Vfs::new();
+
+/// `path` made absolute and plain: `/a/b` for `a//b/./` (`..` is left in).
+fn canonical(path: &str) -> String {
+    let path: String = components(path).flat_map(|name| ["/", name]).collect();
+    if path.is_empty() {
+        String::from("/")
+    } else {
+        path
+    }
+}
+
+/// The directory part of `path` and the last name in it.
+fn split(path: &str) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidName);
+    }
+    Ok((if dir.is_empty() { "/" } else { dir }, name))
+}
+
+impl Vfs {
+    const fn new() -> Vfs {
+        Vfs { mounts: RwLock::new(Vec::new()) }
+    }
+
+    /// Mount the filesystem whose root is `root` at `path`.
+    fn mount(&self, path: &str, fs_type: &'static str, source: &str, root: Arc<dyn Dir>) -> Result<(), FsError> {
+        let path = canonical(path);
+        let mut mounts = self.mounts.write();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(FsError::Exists);
+        }
+        mounts.push(Mount { path, fs_type, source: String::from(source), root });
+        Ok(())
+    }
+
+    fn mounted(&self, path: &str) -> Option<Arc<dyn Dir>> {
+        self.mounts.read().iter().find(|mount| mount.path == path).map(|mount| mount.root.clone())
+    }
+
+    /// Whether something is mounted below `path`.
+    fn below_mount(&self, path: &str) -> bool {
+        self.mounts.read().iter().any(|mount| mount.path.strip_prefix(path).is_some_and(|rest| rest.starts_with('/')))
+    }
+
+    /// The node at `path` and its path without links, `.` or `..`. A link
+    /// at the end is followed if `follow` is.
+    fn walk(&self, path: &str, follow: bool) -> Result<(String, Node), FsError> {
+        let root = self.mounted("/").ok_or(FsError::NotFound)?;
+        // The directories walked down so far, for `..`; the names to go.
+        let mut walked: Vec<(String, Arc<dyn Dir>)> = alloc::vec![(String::new(), root)];
+        let mut rest: Vec<String> = components(path).rev().map(String::from).collect();
+        let mut links = 0;
+        while let Some(name) = rest.pop() {
+            if name == ".." {
+                if walked.len() > 1 {
+                    walked.pop();
+                }
+                continue;
+            }
+            let (dir_path, dir) = walked.last().expect("the root");
+            let path = format!("{}/{}", dir_path, name);
+            let node = match self.mounted(&path) {
+                Some(root) => Node::Dir(root),
+                None => match dir.lookup(&name) {
+                    Err(FsError::NotFound) if self.below_mount(&path) => Node::Dir(Arc::new(MountPoint)),
+                    found => found?,
+                },
+            };
+            match node {
+                Node::Dir(dir) => walked.push((path, dir)),
+                Node::Symlink(target) if follow || !rest.is_empty() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(FsError::Corrupt("too many symbolic links"));
+                    }
+                    if target.starts_with('/') {
+                        walked.truncate(1);
+                    }
+                    rest.extend(components(&target).rev().map(String::from));
+                }
+                node if rest.is_empty() => return Ok((path, node)),
+                _ => return Err(FsError::NotADirectory),
+            }
+        }
+        let (path, dir) = walked.pop().expect("the root");
+        Ok((canonical(&path), Node::Dir(dir)))
+    }
+
+    fn dir(&self, path: &str) -> Result<Arc<dyn Dir>, FsError> {
+        match self.walk(path, true)?.1 {
+            Node::Dir(dir) => Ok(dir),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// File `path`, following links.
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, FsError> {
+        match self.walk(path, true)?.1 {
+            Node::File(file) => Ok(file),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// File `path`, created empty in its directory if it isn't there.
+    fn create(&self, path: &str) -> Result<Arc<dyn File>, FsError> {
+        match self.open(path) {
+            Err(FsError::NotFound) => {
+                let (dir, name) = split(path)?;
+                self.dir(dir)?.create(name)
+            }
+            found => found,
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        let (dir, name) = split(path)?;
+        self.dir(dir)?.mkdir(name)
+    }
+
+    /// The entries of directory `path`, and what is mounted in it.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let (path, node) = self.walk(path, true)?;
+        let Node::Dir(dir) = node else { return Err(FsError::NotADirectory) };
+        let mut entries = dir.read_dir()?;
+        let prefix = if path == "/" { "" } else { &path };
+        for mount in self.mounts.read().iter() {
+            let Some(name) = mount.path.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('/')) else { continue };
+            let name = name.split('/').next().unwrap_or(name);
+            if name.is_empty() || entries.iter().any(|entry| entry.name == name) {
+                continue;
+            }
+            let metadata = if name.len() + 1 == mount.path.len() - prefix.len() {
+                mount.root.metadata()
+            } else {
+                MountPoint.metadata()
+            };
+            entries.push(DirEntry { name: String::from(name), metadata });
+        }
+        Ok(entries)
+    }
+
+    /// Make file `path` hold `data`, creating it if it isn't there.
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let file = self.create(path)?;
+        file.truncate(0)?;
+        let written = file.write_at(0, data)?;
+        if written < data.len() {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// The whole of file `path`.
+    fn read_all(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let file = self.open(path)?;
+        let mut data = alloc::vec![0u8; file.metadata().size as usize];
+        let mut done = 0;
+        while done < data.len() {
+            match file.read_at(done as u64, &mut data[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        data.truncate(done);
+        Ok(data)
+    }
+}
+
+/// Mount `root` at `path` in the kernel's namespace.
+pub fn mount(path: &str, fs_type: &'static str, source: &str, root: Arc<dyn Dir>) {
+    match VFS.mount(path, fs_type, source, root) {
+        Ok(()) => serial_println!("vfs: {} ({}) on {}", source, fs_type, canonical(path)),
+        Err(err) => serial_println!("vfs: mounting {} on {}: {}", source, path, err),
+    }
+}
+
+/// File `path` of the kernel's namespace.
+pub fn open(path: &str) -> Result<Arc<dyn File>, FsError> {
+    VFS.open(path)
+}
+
+/// File `path` of the kernel's namespace, created empty if it isn't there.
+pub fn create(path: &str) -> Result<Arc<dyn File>, FsError> {
+    VFS.create(path)
+}
+
+/// What is at `path` of the kernel's namespace, following links.
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    match VFS.walk(path, true)?.1 {
+        Node::File(file) => Ok(file.metadata()),
+        Node::Dir(dir) => Ok(dir.metadata()),
+        Node::Symlink(_) => unreachable!("the walk follows links"),
+    }
+}
+
+/// What is mounted in the kernel's namespace: path, type and source of
+/// each, in the order they were mounted.
+pub fn mounts() -> Vec<(String, &'static str, String)> {
+    VFS.mounts.read().iter().map(|mount| (mount.path.clone(), mount.fs_type, mount.source.clone())).collect()
+}
+
+pub fn list() {
+    let mounts = mounts();
+    if mounts.is_empty() {
+        return serial_println!("vfs: nothing mounted");
+    }
+    for (path, fs_type, source) in mounts {
+        serial_println!("  {:<12} {:<10} {}", path, fs_type, source);
+    }
+}
+
+pub fn ls(path: &str) {
+    match VFS.read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                let kind = match entry.metadata.kind {
+                    FileType::Regular => "",
+                    FileType::Directory => "dir",
+                    FileType::Symlink => "lnk",
+                    FileType::CharDevice => "chr",
+                    FileType::BlockDevice => "blk",
+                };
+                serial_println!("  {:>10} {:>3} {:o}  {}", entry.metadata.size, kind, entry.metadata.mode, entry.name);
+            }
+        }
+        Err(err) => serial_println!("vfs: {}: {}", path, err),
+    }
+}
+
+pub fn cat(path: &str) {
+    match VFS.read_all(path) {
+        Ok(data) => crate::serial::write_bytes(&data),
+        Err(err) => serial_println!("vfs: {}: {}", path, err),
+    }
+}
+
+pub fn write(path: &str, data: &[u8]) {
+    if let Err(err) = VFS.write_file(path, data) {
+        serial_println!("vfs: {}: {}", path, err);
+    }
+}
+
+pub fn mkdir(path: &str) {
+    if let Err(err) = VFS.mkdir(path) {
+        serial_println!("vfs: {}: {}", path, err);
+    }
+}
+
+/// A namespace of its own: an initramfs at `/` with a link into a FAT
+/// volume mounted at `/mnt/fat`, where the initramfs has no `/mnt`. Paths
+/// cross the mount both ways, through the link and back up with `..`;
+/// `/` and `/mnt` list the made-up mount point; files write on FAT but
+/// not on the initramfs, and a second mount on one path fails.
+pub fn self_test() -> bool {
+    use crate::initrd::{Member, S_IFLNK, S_IFREG};
+    use super::fat::{self, FatFs};
+    use super::initramfs::Initramfs;
+
+    let Some(disk) = fat::test_volume() else { return false };
+    let Ok(volume) = FatFs::mount(Arc::new(disk)) else { return false };
+    let member = |path: &str, mode: u32, data: &'static [u8]| Member { path: String::from(path), mode, data };
+    let initramfs = Arc::new(Initramfs::from_members(alloc::vec![
+        member("/etc/motd", S_IFREG | 0o644, b"hi\n"),
+        member("/data", S_IFLNK | 0o777, b"mnt/fat/SUB"),
+    ]));
+    let vfs = Vfs::new();
+    let names = |path: &str| vfs.read_dir(path).map(|entries| entries.into_iter().map(|entry| entry.name).collect::<Vec<_>>());
+
+    let ok = vfs.mount("/", "initramfs", "test", initramfs.root_dir()).is_ok()
+        && vfs.mount("/mnt/fat/", "fat", "test", Arc::new(volume).root_dir()).is_ok()
+        && vfs.mount("/mnt/fat", "fat", "test", Arc::new(MountPoint)).is_err()
+        && vfs.read_all("/etc/motd").as_deref() == Ok(&b"hi\n"[..])
+        && vfs.read_all("/mnt/fat/sub/HELLO.TXT").as_deref() == Ok(&b"hello"[..])
+        && vfs.read_all("/data/hello.txt").as_deref() == Ok(&b"hello"[..])
+        && vfs.read_all("/data/../../../etc/motd").as_deref() == Ok(&b"hi\n"[..])
+        && vfs.read_all("/mnt/fat/A long file name.txt").is_ok_and(|data| data.len() == 600)
+        && names("/") == Ok(alloc::vec![String::from("data"), String::from("etc"), String::from("mnt")])
+        && names("/mnt") == Ok(alloc::vec![String::from("fat")])
+        && vfs.walk("/data", false).is_ok_and(|(path, node)| path == "/data" && matches!(node, Node::Symlink(_)))
+        && vfs.walk("/data/./", true).is_ok_and(|(path, _)| path == "/mnt/fat/SUB")
+        && vfs.open("/mnt/fat").err() == Some(FsError::IsADirectory)
+        && vfs.open("/etc/motd/x").err() == Some(FsError::NotADirectory)
+        && vfs.open("/mnt/nothing").err() == Some(FsError::NotFound);
+    ok && vfs.write_file("/data/new.txt", b"written").is_ok()
+        && vfs.read_all("/mnt/fat/SUB/new.txt").as_deref() == Ok(&b"written"[..])
+        && vfs.create("/data/new.txt").is_ok_and(|file| file.writable() && file.metadata().size == 7)
+        && vfs.mkdir("/mnt/fat/dir").is_ok()
+        && names("/mnt/fat/dir") == Ok(Vec::new())
+        && vfs.write_file("/etc/new", b"").err() == Some(FsError::ReadOnly)
+        && vfs.open("/etc/motd").is_ok_and(|file| !file.writable())
+        && vfs.mkdir("/mnt/..").err() == Some(FsError::InvalidName)
+}
@@ -0,0 +1,113 @@
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use spin::Once;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::memory::stack;
+use crate::smp::{self, MAX_CPUS};
+
+/// IST slot used by the double-fault handler, so it runs on a known-good stack
+/// even when the fault was caused by overflowing the current one.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const IST_STACK_PAGES: u64 = 4;
+
+static GDT: Once<CpuTables> = Once::new();
+/// The TSS each CPU loaded, for `set_kernel_stack`.
+static CPU_TSS: [AtomicPtr<TaskStateSegment>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    user_code: SegmentSelector,
+    user_data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// A CPU's GDT. Every CPU needs its own: loading the TSS marks its descriptor
+/// busy, and each TSS points at that CPU's own interrupt stacks.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    tss: *mut TaskStateSegment,
+    selectors: Selectors,
+}
+
+// Safety: a TSS is only written by the CPU that loaded it (its RSP0, see
+// `set_kernel_stack`).
+unsafe impl Send for CpuTables {}
+unsafe impl Sync for CpuTables {}
+
+fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    let df = stack::alloc("double-fault IST", IST_STACK_PAGES).expect("double-fault stack");
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = df.top;
+    tss
+}
+
+/// A CPU's TSS, never freed. A raw pointer: its RSP0 changes later.
+fn alloc_tss() -> *mut TaskStateSegment {
+    Box::into_raw(Box::new(new_tss()))
+}
+
+fn build(tss_ptr: *mut TaskStateSegment) -> CpuTables {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.append(Descriptor::kernel_code_segment());
+    let data = gdt.append(Descriptor::kernel_data_segment());
+    // User data before user code: the order `sysret` expects.
+    let user_data = gdt.append(Descriptor::user_data_segment());
+    let user_code = gdt.append(Descriptor::user_code_segment());
+    // Safety: the TSS is never freed, and only its stack pointers change later.
+    let tss = gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss_ptr) });
+    CpuTables { gdt, tss: tss_ptr, selectors: Selectors { code, data, user_code, user_data, tss } }
+}
+
+/// Load our own GDT with a TSS holding guarded interrupt stacks. Needs the heap and paging.
+pub fn init() {
+    load(GDT.call_once(|| build(alloc_tss())));
+}
+
+/// Tables for an application processor, built by the bootstrap processor
+/// (which can allocate) for the AP to `load`. Never freed.
+pub fn alloc_ap() -> &'static CpuTables {
+    Box::leak(Box::new(build(alloc_tss())))
+}
+
+/// Switch the calling CPU to `tables`.
+pub fn load(tables: &'static CpuTables) {
+    let sel = &tables.selectors;
+    tables.gdt.load();
+    unsafe {
+        CS::set_reg(sel.code);
+        SS::set_reg(sel.data);
+        DS::set_reg(sel.data);
+        ES::set_reg(sel.data);
+        load_tss(sel.tss);
+    }
+    CPU_TSS[smp::current()].store(tables.tss, Ordering::Relaxed);
+}
+
+/// Code and data selectors for ring 3 (RPL 3). The same on every CPU.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    let sel = &GDT.get().expect("gdt::init not called").selectors;
+    (sel.user_code, sel.user_data)
+}
+
+/// Code and data selectors for ring 0, for the `syscall` MSRs.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    let sel = &GDT.get().expect("gdt::init not called").selectors;
+    (sel.code, sel.data)
+}
+
+/// Set this CPU's RSP0: the stack it switches to when an interrupt arrives
+/// in ring 3. Call with interrupts off.
+pub fn set_kernel_stack(top: u64) {
+    let tss = CPU_TSS[smp::current()].load(Ordering::Relaxed);
+    assert!(!tss.is_null(), "no TSS loaded on this CPU");
+    // Safety: only this CPU writes its TSS, and the hardware reads RSP0 only
+    // at a privilege change, which can't interrupt this store.
+    unsafe { ptr::addr_of_mut!((*tss).privilege_stack_table[0]).write(x86_64::VirtAddr::new(top)) };
+}
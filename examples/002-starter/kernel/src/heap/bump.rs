@@ -0,0 +1,61 @@
+use core::alloc::Layout;
+use core::ptr;
+
+use super::{align_up, Backend};
+
+/// Hands out memory by moving a pointer forward; only reclaims when everything is freed.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator { heap_start: 0, heap_end: 0, next: 0, allocations: 0 }
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for BumpAllocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.heap_start = start;
+        self.heap_end = start + size;
+        self.next = start;
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        match alloc_start.checked_add(layout.size()) {
+            Some(end) if end <= self.heap_end => {
+                self.next = end;
+                self.allocations += 1;
+                alloc_start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    fn largest_free(&self) -> usize {
+        self.free_bytes()
+    }
+}
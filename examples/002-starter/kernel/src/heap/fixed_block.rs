@@ -0,0 +1,92 @@
+use core::alloc::Layout;
+
+use super::linked_list::LinkedListAllocator;
+use super::Backend;
+
+/// Block sizes served from the per-size free lists. Each block is also aligned to its size,
+/// so sizes must be powers of two. Bigger requests go to the fallback allocator.
+const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Segregated free lists of fixed-size blocks on top of a linked-list fallback.
+pub struct FixedBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+impl FixedBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedBlockAllocator { list_heads: [EMPTY; BLOCK_SIZES.len()], fallback: LinkedListAllocator::new() }
+    }
+
+    /// Index of the smallest block size that fits `layout`, if any.
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required)
+    }
+}
+
+impl Default for FixedBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for FixedBlockAllocator {
+    const NAME: &'static str = "fixed-block";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.fallback.init(start, size);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let Some(index) = Self::list_index(&layout) else {
+            return self.fallback.alloc(layout);
+        };
+        match self.list_heads[index].take() {
+            Some(node) => {
+                self.list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                // List empty: carve a new block out of the fallback allocator.
+                let size = BLOCK_SIZES[index];
+                self.fallback.alloc(Layout::from_size_align_unchecked(size, size))
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::list_index(&layout) {
+            Some(index) => {
+                let node = ListNode { next: self.list_heads[index].take() };
+                let node_ptr = ptr as *mut ListNode;
+                node_ptr.write(node);
+                self.list_heads[index] = Some(&mut *node_ptr);
+            }
+            None => self.fallback.dealloc(ptr, layout),
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        let mut total = self.fallback.free_bytes();
+        for (i, head) in self.list_heads.iter().enumerate() {
+            let mut current = head.as_deref();
+            while let Some(node) = current {
+                total += BLOCK_SIZES[i];
+                current = node.next.as_deref();
+            }
+        }
+        total
+    }
+
+    fn largest_free(&self) -> usize {
+        let cached = (0..BLOCK_SIZES.len()).rev().find(|&i| self.list_heads[i].is_some()).map_or(0, |i| BLOCK_SIZES[i]);
+        self.fallback.largest_free().max(cached)
+    }
+}
+
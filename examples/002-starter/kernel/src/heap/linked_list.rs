@@ -0,0 +1,158 @@
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use super::{align_up, Backend};
+
+/// Header written at the start of every free region.
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    fn start(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+/// First-fit allocator over an address-ordered free list; neighbours are merged on free.
+pub struct LinkedListAllocator {
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator { head: ListNode { size: 0, next: None } }
+    }
+
+    /// Insert a region into the sorted list, merging with adjacent free regions.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, align_of::<ListNode>()), addr);
+        assert!(size >= size_of::<ListNode>());
+
+        // Find the last node that starts before `addr`.
+        let mut prev = &mut self.head;
+        while prev.next.as_ref().is_some_and(|n| n.start() < addr) {
+            prev = prev.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode { size, next: prev.next.take() };
+        // Absorb the following region if it touches ours.
+        if let Some(next) = node.next.take() {
+            if addr + size == next.start() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+        // Grow the previous region instead of linking a new node if they touch.
+        if prev.size != 0 && prev.end() == addr {
+            prev.size += node.size;
+            prev.next = node.next;
+            return;
+        }
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        prev.next = Some(&mut *node_ptr);
+    }
+
+    /// Unlink the first region that fits; returns it with the aligned allocation start.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let found = current.next.take().map(|r| (r, alloc_start));
+                current.next = next;
+                return found;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        None
+    }
+
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end() {
+            return Err(());
+        }
+        // Whatever is left over must be able to hold a ListNode again.
+        let excess = region.end() - alloc_end;
+        if excess > 0 && excess < size_of::<ListNode>() {
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    /// Round the layout up so every block can later become a ListNode.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        (layout.size().max(size_of::<ListNode>()), layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for LinkedListAllocator {
+    const NAME: &'static str = "linked-list";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.add_free_region(start, size);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        let Some((region, alloc_start)) = self.find_region(size, align) else {
+            return ptr::null_mut();
+        };
+        let (region_start, region_end) = (region.start(), region.end());
+        let alloc_end = alloc_start + size;
+        // Give back the alignment gap in front and the tail behind the block.
+        if alloc_start - region_start >= size_of::<ListNode>() {
+            self.add_free_region(region_start, alloc_start - region_start);
+        }
+        if region_end > alloc_end {
+            self.add_free_region(alloc_end, region_end - alloc_end);
+        }
+        alloc_start as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    fn free_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(node) = current {
+            total += node.size;
+            current = node.next.as_deref();
+        }
+        total
+    }
+
+    fn largest_free(&self) -> usize {
+        let mut largest = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(node) = current {
+            largest = largest.max(node.size);
+            current = node.next.as_deref();
+        }
+        largest
+    }
+}
@@ -0,0 +1,227 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::kaslr;
+use crate::memory::{huge, paging, wipe};
+
+mod bump;
+mod fixed_block;
+mod linked_list;
+mod oom;
+mod stats;
+pub mod suite;
+mod tags;
+
+pub use bump::BumpAllocator;
+pub use fixed_block::FixedBlockAllocator;
+pub use linked_list::LinkedListAllocator;
+pub use oom::{policy as oom_policy, set_policy as set_oom_policy, try_box, try_vec, OomPolicy};
+pub use stats::{stats, SIZE_CLASSES};
+pub use tags::{for_each as for_each_tag, tag};
+
+/// Lowest possible heap address; KASLR slides the real start up to `HEAP_SLIDE` above it.
+const HEAP_BASE: usize = 0x_4444_4444_0000;
+const HEAP_SLIDE: u64 = 256 * 1024 * 1024 * 1024;
+/// Virtual space reserved for the heap. Nothing is mapped up front: pages are
+/// mapped by the page-fault handler the first time they are touched, so this is
+/// only a cap on how big the heap may grow.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+static MAPPED_PAGES: AtomicUsize = AtomicUsize::new(0);
+static HEAP_START: AtomicUsize = AtomicUsize::new(HEAP_BASE);
+
+/// Where the heap begins (randomized at boot unless `nokaslr`).
+pub fn start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// An allocation strategy that can back the kernel heap.
+///
+/// Every backend manages one contiguous region and is driven through `Locked<_>`,
+/// so the same code (and the same tests in `suite`) works for all of them.
+pub trait Backend: Default {
+    const NAME: &'static str;
+
+    /// # Safety
+    /// `[start, start + size)` must be mapped, unused memory. Call once.
+    unsafe fn init(&mut self, start: usize, size: usize);
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+    /// Bytes that could still be handed out.
+    fn free_bytes(&self) -> usize;
+    /// Size of the biggest single block that could be handed out (ignoring alignment).
+    fn largest_free(&self) -> usize;
+}
+
+// Pick the global heap backend with a cargo feature (linked list by default).
+#[cfg(all(feature = "heap-bump", feature = "heap-fixed-block"))]
+compile_error!("select at most one heap-* feature");
+
+#[cfg(feature = "heap-bump")]
+type Active = BumpAllocator;
+#[cfg(feature = "heap-fixed-block")]
+type Active = FixedBlockAllocator;
+#[cfg(not(any(feature = "heap-bump", feature = "heap-fixed-block")))]
+type Active = LinkedListAllocator;
+
+/// `GlobalAlloc` takes `&self`, so the allocator lives behind a lock.
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked { inner: Mutex::new(inner) }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+unsafe impl<A: Backend> GlobalAlloc for Locked<A> {
+    #[cfg(not(debug_assertions))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.lock().alloc(layout);
+        record(ptr, layout);
+        ptr
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        wipe::on_free(ptr, layout.size());
+        self.lock().dealloc(ptr, layout);
+        stats::record_dealloc(layout.size());
+    }
+
+    // Debug builds put a small header in front of each block to remember its tag.
+    #[cfg(debug_assertions)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, offset) = tags::outer_layout(layout);
+        let ptr = self.lock().alloc(outer);
+        record(ptr, layout);
+        if ptr.is_null() { return ptr; }
+        tags::on_alloc(ptr, offset, layout.size())
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        wipe::on_free(ptr, layout.size());
+        let (outer, offset) = tags::outer_layout(layout);
+        let ptr = tags::on_dealloc(ptr, offset);
+        self.lock().dealloc(ptr, outer);
+        stats::record_dealloc(layout.size());
+    }
+}
+
+fn record(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() {
+        stats::record_failure();
+        oom::on_failure(layout);
+    } else {
+        stats::record_alloc(layout.size());
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Locked<Active> = Locked::new(Active::new());
+
+/// Hand the reserved heap range to the allocator. Page faults must already be handled.
+pub fn init() {
+    let start = HEAP_BASE + kaslr::slide(HEAP_SLIDE, 2 * 1024 * 1024) as usize;
+    HEAP_START.store(start, Ordering::Relaxed);
+    unsafe { ALLOCATOR.lock().init(start, HEAP_MAX_SIZE) };
+    if let Some(policy) = crate::cmdline::get("oom").and_then(OomPolicy::parse) {
+        oom::set_policy(policy);
+    }
+}
+
+/// Called by the page-fault handler: map a fresh frame if `addr` lies in the heap range.
+///
+/// Must not allocate: we may be inside the allocator already.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64() as usize;
+    let heap_start = start();
+    if !(heap_start..heap_start + HEAP_MAX_SIZE).contains(&addr) {
+        return false;
+    }
+    let addr = VirtAddr::new(addr as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    // Prefer one 2MiB page for the whole surrounding chunk; fall back to 4KiB.
+    let heap_end = VirtAddr::new((heap_start + HEAP_MAX_SIZE) as u64);
+    if huge::try_map_chunk(addr, VirtAddr::new(heap_start as u64), heap_end, flags) {
+        MAPPED_PAGES.fetch_add(512, Ordering::Relaxed);
+        return true;
+    }
+    match paging::map_new(Page::<Size4KiB>::containing_address(addr), flags) {
+        Ok(_) => {
+            MAPPED_PAGES.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Bytes of the heap range currently backed by physical frames.
+pub fn mapped_bytes() -> usize {
+    MAPPED_PAGES.load(Ordering::Relaxed) * 4096
+}
+
+pub fn backend_name() -> &'static str {
+    Active::NAME
+}
+
+pub fn free_bytes() -> usize {
+    ALLOCATOR.lock().free_bytes()
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Exercise Box, Vec and String on the live kernel heap and check that
+/// all memory comes back.
+pub fn self_test() -> bool {
+    use alloc::{boxed::Box, string::String, vec::Vec};
+    let before = free_bytes();
+    {
+        let boxed = Box::new(41u64);
+        let mut numbers: Vec<u64> = (0..1000).collect();
+        numbers.push(*boxed + 1);
+        let mut text = String::from("hello");
+        text.push_str(", heap");
+        if numbers.iter().sum::<u64>() != 499_500 + 42 || text.len() != 11 {
+            return false;
+        }
+    }
+    // The bump backend only takes memory back once nothing at all is
+    // allocated, which never happens on a booted kernel.
+    let leaked = Active::NAME != "bump" && free_bytes() != before;
+    !leaked && poison_self_test() && oom::self_test()
+}
+
+/// Freed memory reads back as poison (debug builds only).
+#[cfg(debug_assertions)]
+fn poison_self_test() -> bool {
+    let block = alloc::vec![0u8; 64];
+    let ptr = block.as_ptr();
+    drop(block);
+    // Deliberately reading freed memory: nothing can have reused it yet.
+    (0..64).all(|i| unsafe { ptr.add(i).read_volatile() } == tags::POISON)
+}
+
+#[cfg(not(debug_assertions))]
+fn poison_self_test() -> bool {
+    true
+}
+
+/// Write one byte past the end of a block, which the free should catch (debug builds only).
+pub fn smash() {
+    let mut block = alloc::vec![0u8; 24];
+    let ptr = block.as_mut_ptr();
+    unsafe { ptr.add(24).write_volatile(0) };
+    drop(block);
+}
@@ -0,0 +1,125 @@
+//! Fallible allocation and what to do when the heap runs dry.
+//!
+//! `try_alloc`, `try_box` and `try_vec` return `None`/`Err` on failure and never
+//! panic, whatever the policy. Everything else (`Box::new`, `Vec::push`, ...) cannot
+//! report failure, so the `OomPolicy` decides: panic right away with details, or log
+//! and return null. A null from an infallible API still ends in Rust's
+//! `handle_alloc_error`, so `LogAndFail` only buys time for code using the raw
+//! `alloc::alloc` functions. `KillProcess` will terminate the process that was
+//! allocating once there are processes; until then it behaves like `LogAndFail`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    Panic,
+    LogAndFail,
+    KillProcess,
+}
+
+impl OomPolicy {
+    /// From the `oom=` boot option or shell argument.
+    pub fn parse(name: &str) -> Option<OomPolicy> {
+        match name {
+            "panic" => Some(OomPolicy::Panic),
+            "fail" => Some(OomPolicy::LogAndFail),
+            "kill" => Some(OomPolicy::KillProcess),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OomPolicy::Panic => "panic",
+            OomPolicy::LogAndFail => "fail",
+            OomPolicy::KillProcess => "kill",
+        }
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::Panic as u8);
+/// Nesting depth of `fallible` sections; failures inside one are the caller's to handle.
+static FALLIBLE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn policy() -> OomPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => OomPolicy::Panic,
+        1 => OomPolicy::LogAndFail,
+        _ => OomPolicy::KillProcess,
+    }
+}
+
+pub fn set_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Run `f` with allocation failures reported to it instead of the OOM policy,
+/// e.g. around `Vec::try_reserve` or `String::try_reserve`.
+pub fn fallible<R>(f: impl FnOnce() -> R) -> R {
+    FALLIBLE.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    FALLIBLE.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Called by the global allocator when it is about to return null.
+pub(super) fn on_failure(layout: Layout) {
+    if FALLIBLE.load(Ordering::Relaxed) > 0 {
+        return;
+    }
+    let (size, align) = (layout.size(), layout.align());
+    match policy() {
+        OomPolicy::Panic => panic!("out of memory: {} bytes (align {}), {} bytes free", size, align, super::free_bytes()),
+        OomPolicy::LogAndFail => crate::serial_println!("heap: allocation of {} bytes (align {}) failed", size, align),
+        OomPolicy::KillProcess => {
+            crate::serial_println!("heap: allocation of {} bytes failed, no process to kill", size)
+        }
+    }
+}
+
+/// Allocate `layout` or return None. Free with `alloc::alloc::dealloc`.
+pub fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    if layout.size() == 0 {
+        return Some(NonNull::dangling());
+    }
+    NonNull::new(fallible(|| unsafe { alloc::alloc::alloc(layout) }))
+}
+
+/// `Box::new` that hands the value back instead of failing.
+pub fn try_box<T>(value: T) -> Result<Box<T>, T> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    match try_alloc(layout) {
+        Some(ptr) => unsafe {
+            let ptr = ptr.cast::<T>().as_ptr();
+            ptr.write(value);
+            Ok(Box::from_raw(ptr))
+        },
+        None => Err(value),
+    }
+}
+
+/// An empty vector with room for `capacity` elements, or None.
+pub fn try_vec<T>(capacity: usize) -> Option<Vec<T>> {
+    let mut vec = Vec::new();
+    fallible(|| vec.try_reserve_exact(capacity)).ok()?;
+    Some(vec)
+}
+
+/// Fail a few allocations through the fallible APIs and check nothing panicked.
+pub fn self_test() -> bool {
+    let failed = super::stats().failed;
+    let too_big = super::HEAP_MAX_SIZE * 2;
+    let mut ok = try_vec::<u8>(too_big).is_none();
+    ok &= try_alloc(Layout::from_size_align(too_big, 8).unwrap()).is_none();
+    ok &= try_box([0u8; 4096]).is_ok_and(|b| b.iter().all(|&x| x == 0));
+    ok &= try_vec::<u64>(100).is_some_and(|v| v.capacity() >= 100);
+    ok && super::stats().failed == failed + 2
+}
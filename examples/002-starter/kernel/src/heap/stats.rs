@@ -0,0 +1,50 @@
+use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+/// Upper bounds of the size classes; the last class catches everything bigger.
+pub const SIZE_CLASSES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 4096, usize::MAX];
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub failed: usize,
+    /// Number of allocations so far in each of `SIZE_CLASSES`.
+    pub by_class: [usize; SIZE_CLASSES.len()],
+}
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static BY_CLASS: [AtomicUsize; SIZE_CLASSES.len()] = [const { AtomicUsize::new(0) }; SIZE_CLASSES.len()];
+
+pub(super) fn record_alloc(size: usize) {
+    let live = LIVE.fetch_add(size, Relaxed) + size;
+    PEAK.fetch_max(live, Relaxed);
+    ALLOCS.fetch_add(1, Relaxed);
+    let class = SIZE_CLASSES.iter().position(|&max| size <= max).unwrap();
+    BY_CLASS[class].fetch_add(1, Relaxed);
+}
+
+pub(super) fn record_dealloc(size: usize) {
+    LIVE.fetch_sub(size, Relaxed);
+    FREES.fetch_add(1, Relaxed);
+}
+
+pub(super) fn record_failure() {
+    FAILED.fetch_add(1, Relaxed);
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        live_bytes: LIVE.load(Relaxed),
+        peak_bytes: PEAK.load(Relaxed),
+        allocs: ALLOCS.load(Relaxed),
+        frees: FREES.load(Relaxed),
+        failed: FAILED.load(Relaxed),
+        by_class: core::array::from_fn(|i| BY_CLASS[i].load(Relaxed)),
+    }
+}
@@ -0,0 +1,280 @@
+//! Tests and a small workload shared by every heap backend, plus a randomized
+//! stress benchmark (`bench_all`).
+//!
+//! Each backend gets a fresh scratch region (borrowed from the live kernel heap),
+//! so designs can be compared side by side in one boot.
+
+use alloc::alloc::{alloc, dealloc};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::arch::x86_64::_rdtsc;
+
+use super::{Backend, BumpAllocator, FixedBlockAllocator, LinkedListAllocator};
+use crate::serial_println;
+
+const SCRATCH_SIZE: usize = 256 * 1024;
+
+struct Case {
+    name: &'static str,
+    run: fn(&mut dyn Heap) -> bool,
+}
+
+const CASES: &[Case] = &[
+    Case { name: "single", run: single },
+    Case { name: "many-small", run: many_small },
+    Case { name: "alignment", run: alignment },
+    Case { name: "large", run: large },
+    Case { name: "reuse", run: reuse },
+];
+
+/// Object-safe view of a backend so the cases are plain functions.
+trait Heap {
+    fn alloc(&mut self, layout: Layout) -> *mut u8;
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+    fn free_bytes(&self) -> usize;
+    fn largest_free(&self) -> usize;
+}
+
+impl<B: Backend> Heap for B {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        unsafe { Backend::alloc(self, layout) }
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { Backend::dealloc(self, ptr, layout) }
+    }
+
+    fn free_bytes(&self) -> usize {
+        Backend::free_bytes(self)
+    }
+
+    fn largest_free(&self) -> usize {
+        Backend::largest_free(self)
+    }
+}
+
+/// Run all cases against every backend; returns false if any failed.
+pub fn run_all() -> bool {
+    let mut ok = true;
+    ok &= run::<BumpAllocator>();
+    ok &= run::<LinkedListAllocator>();
+    ok &= run::<FixedBlockAllocator>();
+    ok
+}
+
+/// A scratch region of `size` bytes borrowed from the kernel heap; freed on drop.
+struct Scratch {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl Scratch {
+    fn new(size: usize) -> Option<Scratch> {
+        let layout = Layout::from_size_align(size, 4096).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        (!ptr.is_null()).then_some(Scratch { ptr, layout })
+    }
+
+    /// A fresh backend managing the whole region.
+    fn heap<B: Backend>(&self) -> B {
+        let mut heap = B::default();
+        unsafe { heap.init(self.ptr as usize, self.layout.size()) };
+        heap
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+pub fn run<B: Backend>() -> bool {
+    let Some(scratch) = Scratch::new(SCRATCH_SIZE) else {
+        serial_println!("{}: could not allocate scratch region", B::NAME);
+        return false;
+    };
+
+    let mut all_ok = true;
+    for case in CASES {
+        // A fresh backend per case so results don't depend on order.
+        let mut heap = scratch.heap::<B>();
+        let initial = Heap::free_bytes(&heap);
+
+        let start = unsafe { _rdtsc() };
+        let passed = (case.run)(&mut heap);
+        let cycles = unsafe { _rdtsc() } - start;
+        let leaked = initial.saturating_sub(Heap::free_bytes(&heap));
+
+        let ok = passed && leaked == 0;
+        all_ok &= ok;
+        serial_println!(
+            "  {:<12} {:<11} {:<6} {:>10} cycles  {} bytes leaked",
+            B::NAME, case.name, if ok { "ok" } else { "FAILED" }, cycles, leaked
+        );
+    }
+    all_ok
+}
+
+fn single(heap: &mut dyn Heap) -> bool {
+    let layout = Layout::new::<u64>();
+    let p = heap.alloc(layout) as *mut u64;
+    if p.is_null() { return false; }
+    unsafe { p.write(0xDEAD_BEEF) };
+    let ok = unsafe { p.read() } == 0xDEAD_BEEF;
+    heap.dealloc(p as *mut u8, layout);
+    ok
+}
+
+fn many_small(heap: &mut dyn Heap) -> bool {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let mut ptrs = Vec::with_capacity(500);
+    for i in 0..500u64 {
+        let p = heap.alloc(layout) as *mut u64;
+        if p.is_null() { break; }
+        unsafe { p.write(i) };
+        ptrs.push(p);
+    }
+    let ok = ptrs.len() == 500 && ptrs.iter().enumerate().all(|(i, p)| unsafe { p.read() } == i as u64);
+    for p in ptrs {
+        heap.dealloc(p as *mut u8, layout);
+    }
+    ok
+}
+
+fn alignment(heap: &mut dyn Heap) -> bool {
+    let mut ok = true;
+    for align in [16, 64, 512, 4096] {
+        let layout = Layout::from_size_align(40, align).unwrap();
+        let p = heap.alloc(layout);
+        ok &= !p.is_null() && (p as usize).is_multiple_of(align);
+        if !p.is_null() {
+            heap.dealloc(p, layout);
+        }
+    }
+    ok
+}
+
+fn large(heap: &mut dyn Heap) -> bool {
+    let layout = Layout::from_size_align(64 * 1024, 8).unwrap();
+    let p = heap.alloc(layout);
+    if p.is_null() { return false; }
+    unsafe {
+        p.write_bytes(0xAB, layout.size());
+        let ok = *p.add(layout.size() - 1) == 0xAB;
+        heap.dealloc(p, layout);
+        ok
+    }
+}
+
+fn reuse(heap: &mut dyn Heap) -> bool {
+    // Far more total bytes than the region holds: only works if frees are reused.
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    for _ in 0..1000 {
+        let p = heap.alloc(layout);
+        if p.is_null() { return false; }
+        heap.dealloc(p, layout);
+    }
+    true
+}
+
+const BENCH_SIZE: usize = 1024 * 1024;
+const BENCH_OPS: usize = 20_000;
+const BENCH_SLOTS: usize = 256;
+/// Same seed for every backend, so they all see the identical request sequence.
+const BENCH_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+struct BenchResult {
+    cycles_per_op: u64,
+    failed: usize,
+    peak_live: usize,
+    peak_footprint: usize,
+    /// Worst observed 1 - largest_free / free_bytes, in percent.
+    worst_fragmentation: usize,
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Mostly small blocks, some medium, a few large, with mixed alignment.
+fn random_layout(rng: &mut u64) -> Layout {
+    let r = xorshift(rng);
+    let size = match r % 100 {
+        0..=69 => 8 + (r >> 8) as usize % 248,
+        70..=94 => 256 + (r >> 8) as usize % 3840,
+        _ => 4096 + (r >> 8) as usize % 12288,
+    };
+    let align = [8, 8, 16, 64][(r >> 40) as usize % 4];
+    Layout::from_size_align(size, align).unwrap()
+}
+
+fn fragmentation(heap: &dyn Heap) -> usize {
+    (heap.largest_free() * 100).checked_div(heap.free_bytes()).map_or(0, |largest| 100 - largest)
+}
+
+/// Random allocs and frees over a fixed number of slots.
+fn stress(heap: &mut dyn Heap) -> BenchResult {
+    let mut rng = BENCH_SEED;
+    let mut slots: [Option<(*mut u8, Layout)>; BENCH_SLOTS] = [None; BENCH_SLOTS];
+    let initial = heap.free_bytes();
+    let (mut live, mut failed) = (0, 0);
+    let mut result = BenchResult { cycles_per_op: 0, failed: 0, peak_live: 0, peak_footprint: 0, worst_fragmentation: 0 };
+    let mut cycles = 0;
+    for op in 0..BENCH_OPS {
+        let slot = &mut slots[xorshift(&mut rng) as usize % BENCH_SLOTS];
+        let start = unsafe { _rdtsc() };
+        match slot.take() {
+            Some((ptr, layout)) => {
+                heap.dealloc(ptr, layout);
+                live -= layout.size();
+            }
+            None => {
+                let layout = random_layout(&mut rng);
+                let ptr = heap.alloc(layout);
+                if ptr.is_null() {
+                    failed += 1;
+                } else {
+                    *slot = Some((ptr, layout));
+                    live += layout.size();
+                }
+            }
+        }
+        cycles += unsafe { _rdtsc() } - start;
+        result.peak_live = result.peak_live.max(live);
+        // Walking the free lists is slow, so sample the footprint only now and then.
+        if op % 256 == 0 {
+            result.peak_footprint = result.peak_footprint.max(initial - heap.free_bytes());
+            result.worst_fragmentation = result.worst_fragmentation.max(fragmentation(heap));
+        }
+    }
+    for (ptr, layout) in slots.into_iter().flatten() {
+        heap.dealloc(ptr, layout);
+    }
+    result.cycles_per_op = cycles / BENCH_OPS as u64;
+    result.failed = failed;
+    result
+}
+
+/// Run the stress workload on every backend and print a comparison table.
+pub fn bench_all() -> bool {
+    let Some(scratch) = Scratch::new(BENCH_SIZE) else {
+        serial_println!("could not allocate the {} KiB benchmark region", BENCH_SIZE / 1024);
+        return false;
+    };
+    serial_println!("  {} random ops over {} slots in {} KiB", BENCH_OPS, BENCH_SLOTS, BENCH_SIZE / 1024);
+    serial_println!("  backend       cycles/op  failed  peak live  peak footprint  worst frag");
+    let report = |name: &str, r: BenchResult| {
+        serial_println!(
+            "  {:<12} {:>10} {:>7} {:>8} K {:>13} K {:>10}%",
+            name, r.cycles_per_op, r.failed, r.peak_live / 1024, r.peak_footprint / 1024, r.worst_fragmentation
+        );
+    };
+    report(BumpAllocator::NAME, stress(&mut scratch.heap::<BumpAllocator>()));
+    report(LinkedListAllocator::NAME, stress(&mut scratch.heap::<LinkedListAllocator>()));
+    report(FixedBlockAllocator::NAME, stress(&mut scratch.heap::<FixedBlockAllocator>()));
+    true
+}
@@ -0,0 +1,132 @@
+//! Allocation tags: label a stretch of code with `heap::tag("name")` and every
+//! allocation made while the guard lives is charged to that name, even if it is
+//! freed elsewhere. Only active in debug builds; release builds compile it away.
+//!
+//! Debug builds also guard every block: a canary in the header and a red zone after
+//! the data are checked on free, and freed memory is filled with `POISON` so a
+//! use-after-free reads an obvious 0x6b6b... instead of plausible stale data.
+
+#[cfg(debug_assertions)]
+mod imp {
+    use core::alloc::Layout;
+    use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use spin::Mutex;
+
+    pub const MAX_TAGS: usize = 16;
+
+    /// Stored in front of every allocation in debug builds.
+    #[repr(C, align(16))]
+    struct Header {
+        tag: usize,
+        size: usize,
+        /// Last field, right before the data, so an underflow hits it first.
+        canary: u64,
+    }
+    const HEADER: usize = core::mem::size_of::<Header>();
+    const CANARY: u64 = 0xCA11_AB1E_5AFE_C0DE;
+    /// Bytes after the data filled with `RED_ZONE_BYTE`.
+    const RED_ZONE: usize = 16;
+    const RED_ZONE_BYTE: u8 = 0xFD;
+    pub const POISON: u8 = 0x6B;
+
+    struct Table {
+        names: [&'static str; MAX_TAGS],
+        live: [usize; MAX_TAGS],
+        allocs: [usize; MAX_TAGS],
+    }
+
+    static TABLE: Mutex<Table> = Mutex::new(Table {
+        names: ["untagged", "", "", "", "", "", "", "", "", "", "", "", "", "", "", ""],
+        live: [0; MAX_TAGS],
+        allocs: [0; MAX_TAGS],
+    });
+    static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+    pub struct TagGuard {
+        previous: usize,
+    }
+
+    impl Drop for TagGuard {
+        fn drop(&mut self) {
+            CURRENT.store(self.previous, Relaxed);
+        }
+    }
+
+    pub fn tag(name: &'static str) -> TagGuard {
+        let index = {
+            let mut table = TABLE.lock();
+            match table.names.iter().position(|&n| n == name) {
+                Some(i) => i,
+                None => match table.names.iter().position(|n| n.is_empty()) {
+                    Some(i) => { table.names[i] = name; i }
+                    None => 0, // table full: fall back to "untagged"
+                },
+            }
+        };
+        TagGuard { previous: CURRENT.swap(index, Relaxed) }
+    }
+
+    /// Grow `layout` so a header fits in front and a red zone behind;
+    /// returns (outer layout, offset of user data).
+    pub fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let offset = layout.align().max(HEADER);
+        let align = layout.align().max(core::mem::align_of::<Header>());
+        let outer = Layout::from_size_align(offset + layout.size() + RED_ZONE, align).unwrap();
+        (outer, offset)
+    }
+
+    /// Write the header and red zone for a fresh allocation and return the pointer handed to the caller.
+    pub unsafe fn on_alloc(outer: *mut u8, offset: usize, size: usize) -> *mut u8 {
+        let user = outer.add(offset);
+        let tag = CURRENT.load(Relaxed);
+        (user.sub(HEADER) as *mut Header).write(Header { tag, size, canary: CANARY });
+        user.add(size).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        let mut table = TABLE.lock();
+        table.live[tag] += size;
+        table.allocs[tag] += 1;
+        user
+    }
+
+    /// Check the guards, poison the block, charge the free to the allocation's tag
+    /// and return the outer pointer.
+    pub unsafe fn on_dealloc(user: *mut u8, offset: usize) -> *mut u8 {
+        let header = &*(user.sub(HEADER) as *const Header);
+        if header.canary != CANARY || header.tag >= MAX_TAGS {
+            panic!("heap: header of block at {:p} overwritten (underflow or double free)", user);
+        }
+        let (tag, size) = (header.tag, header.size);
+        let red_zone = core::slice::from_raw_parts(user.add(size), RED_ZONE);
+        if let Some(i) = red_zone.iter().position(|&b| b != RED_ZONE_BYTE) {
+            let name = TABLE.lock().names[tag];
+            panic!("heap: write {} bytes past the end of a {}-byte block at {:p} (tag \"{}\")", i, size, user, name);
+        }
+        // Also clobber the canary, so freeing the same block twice is caught.
+        user.sub(HEADER).write_bytes(POISON, HEADER);
+        user.write_bytes(POISON, size + RED_ZONE);
+        TABLE.lock().live[tag] -= size;
+        user.sub(offset)
+    }
+
+    /// Call `f(name, live_bytes, allocations)` for every tag in use.
+    pub fn for_each(mut f: impl FnMut(&str, usize, usize)) {
+        let table = TABLE.lock();
+        for i in 0..MAX_TAGS {
+            if !table.names[i].is_empty() && table.allocs[i] > 0 {
+                f(table.names[i], table.live[i], table.allocs[i]);
+            }
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    pub struct TagGuard;
+
+    pub fn tag(_name: &'static str) -> TagGuard {
+        TagGuard
+    }
+
+    pub fn for_each(_f: impl FnMut(&str, usize, usize)) {}
+}
+
+pub use imp::*;
@@ -0,0 +1,306 @@
+//! The initial ramdisk: files the runner packs into a cpio archive (the
+//! "newc" format, as Linux's initramfs) and the bootloader loads into memory
+//! next to the kernel. A tar archive (ustar) works too. It is read-only and
+//! stays mapped, so a file is just a `&'static [u8]`.
+//!
+//! The archive becomes the root filesystem, `fs::initramfs`, mounted at `/`
+//! at boot, so programs and config files are there before any disk driver.
+//!
+//! The runner builds the `userland` programs and packs them as /bin/<name>,
+//! listing their paths, one a line, in `MANIFEST`; `programs` reads it back.
+//! The kernel can add files of its own (`add`), for programs it builds
+//! itself; those take the place of archive files with the same path.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::fs::initramfs::{self, Initramfs};
+use crate::serial_println;
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+/// The user programs the runner packed.
+pub const MANIFEST: &str = "/etc/programs";
+
+/// tar: 512-byte blocks, a header block before each member's contents.
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The archive as the bootloader loaded it, for `block` to copy.
+static ARCHIVE: Once<&'static [u8]> = Once::new();
+
+/// A member of an archive: a file, a directory or a symbolic link (whose
+/// contents are its target).
+pub struct Member<'a> {
+    pub path: String,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// Read the archive the bootloader loaded, if there is one, and mount it
+/// at `/`; without one, `/` is empty. Needs the heap.
+pub fn init(ramdisk: Option<&'static [u8]>) {
+    let fs = match ramdisk.map(|data| (data, parse(data))) {
+        None => {
+            serial_println!("initrd: none");
+            Initramfs::empty()
+        }
+        Some((data, Ok(members))) => {
+            ARCHIVE.call_once(|| data);
+            serial_println!("initrd: {} entries in {} KiB", members.len(), data.len() / 1024);
+            Initramfs::from_members(members)
+        }
+        Some((_, Err(err))) => {
+            serial_println!("initrd: {}, ignored", err);
+            Initramfs::empty()
+        }
+    };
+    initramfs::mount_root(fs);
+    let programs = programs();
+    let missing = programs.iter().filter(|path| read(path).is_none()).count();
+    serial_println!("initrd: {} user programs in {}, {} missing", programs.len(), MANIFEST, missing);
+}
+
+/// `/name` for an archive path (`name`, `./name` or `/name`); `None` for `.`.
+fn normalize(name: &str) -> Option<String> {
+    let name = name.trim_start_matches("./").trim_start_matches('/').trim_end_matches('/');
+    (!name.is_empty() && name != ".").then(|| alloc::format!("/{}", name))
+}
+
+fn hex_field(header: &[u8], index: usize) -> Result<u32, &'static str> {
+    let field = &header[6 + index * 8..6 + (index + 1) * 8];
+    let text = core::str::from_utf8(field).map_err(|_| "bad header field")?;
+    u32::from_str_radix(text, 16).map_err(|_| "bad header field")
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// The members of an archive, cpio or tar, in archive order.
+pub fn parse(data: &[u8]) -> Result<Vec<Member<'_>>, &'static str> {
+    if data.starts_with(MAGIC) {
+        parse_cpio(data)
+    } else if data.get(257..262) == Some(TAR_MAGIC) {
+        parse_tar(data)
+    } else {
+        Err("neither a newc cpio nor a tar archive")
+    }
+}
+
+/// The files, directories and links of a newc archive.
+fn parse_cpio(data: &[u8]) -> Result<Vec<Member<'_>>, &'static str> {
+    let mut members = Vec::new();
+    let mut at = 0;
+    loop {
+        let header = data.get(at..at + HEADER_LEN).ok_or("truncated header")?;
+        if &header[..6] != MAGIC {
+            return Err("not a newc cpio archive");
+        }
+        let mode = hex_field(header, 1)?;
+        let size = hex_field(header, 6)? as usize;
+        let name_len = hex_field(header, 11)? as usize;
+        let name_start = at + HEADER_LEN;
+        // The name includes its terminating NUL.
+        let name = data.get(name_start..name_start + name_len.saturating_sub(1)).ok_or("truncated name")?;
+        let name = core::str::from_utf8(name).map_err(|_| "name not UTF-8")?;
+        let data_start = align4(name_start + name_len);
+        if name == TRAILER {
+            return Ok(members);
+        }
+        let contents = data.get(data_start..data_start + size).ok_or("truncated file")?;
+        if matches!(mode & S_IFMT, S_IFREG | S_IFDIR | S_IFLNK) {
+            if let Some(path) = normalize(name) {
+                members.push(Member { path, mode, data: contents });
+            }
+        }
+        at = align4(data_start + size);
+    }
+}
+
+/// An octal number field of a tar header: digits, ended by a NUL or space.
+fn octal_field(field: &[u8]) -> Result<usize, &'static str> {
+    let mut digits = field.iter().skip_while(|&&b| b == b' ').take_while(|&&b| b != 0 && b != b' ');
+    digits.try_fold(0, |value: usize, &digit| match digit {
+        b'0'..=b'7' => Ok(value * 8 + (digit - b'0') as usize),
+        _ => Err("bad tar number"),
+    })
+}
+
+/// A NUL-terminated string field of a tar header.
+fn string_field(field: &[u8]) -> Result<&str, &'static str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| "name not UTF-8")
+}
+
+/// The files, directories and links of a ustar archive. GNU long names
+/// ('L' members) are followed; other extensions are skipped.
+fn parse_tar(data: &[u8]) -> Result<Vec<Member<'_>>, &'static str> {
+    let mut members = Vec::new();
+    let mut long_name: Option<&str> = None;
+    let mut at = 0;
+    loop {
+        let header = data.get(at..at + TAR_BLOCK).ok_or("truncated header")?;
+        // Two zero blocks end the archive; one will do.
+        if header.iter().all(|&b| b == 0) {
+            return Ok(members);
+        }
+        // The checksum: the sum of the header's bytes, its own field as spaces.
+        let sum: usize = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as usize } else { b as usize }).sum();
+        if octal_field(&header[148..156])? != sum {
+            return Err("bad tar header checksum");
+        }
+        let size = octal_field(&header[124..136])?;
+        let contents = data.get(at + TAR_BLOCK..at + TAR_BLOCK + size).ok_or("truncated file")?;
+        let name = match long_name.take() {
+            Some(name) => String::from(name),
+            None => match string_field(&header[345..500])? {
+                "" => String::from(string_field(&header[..100])?),
+                prefix => alloc::format!("{}/{}", prefix, string_field(&header[..100])?),
+            },
+        };
+        let permissions = octal_field(&header[100..108])? as u32 & 0o7777;
+        let member = match header[156] {
+            b'0' | 0 => Some((S_IFREG, contents)),
+            b'5' => Some((S_IFDIR, &contents[..0])),
+            b'2' => Some((S_IFLNK, string_field(&header[157..257])?.as_bytes())),
+            b'L' => {
+                long_name = Some(string_field(contents)?);
+                None
+            }
+            _ => None,
+        };
+        if let (Some((kind, data)), Some(path)) = (member, normalize(&name)) {
+            members.push(Member { path, mode: kind | permissions, data });
+        }
+        at += TAR_BLOCK + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    }
+}
+
+/// The whole archive, if the bootloader loaded a good one.
+pub fn archive() -> Option<&'static [u8]> {
+    ARCHIVE.get().copied()
+}
+
+/// Add (or replace) the file at `path`.
+pub fn add(path: &str, data: &'static [u8]) {
+    if let Err(err) = initramfs::root().add(path, data) {
+        serial_println!("initrd: adding {}: {}", path, err);
+    }
+}
+
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    initramfs::root().read(path).ok()
+}
+
+/// The paths `manifest` lists: one a line, blank lines skipped.
+fn manifest_paths(manifest: &[u8]) -> Vec<&str> {
+    let text = core::str::from_utf8(manifest).unwrap_or("");
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).collect()
+}
+
+/// The user programs the runner packed, by path, from `MANIFEST`; none if
+/// the archive has no manifest.
+pub fn programs() -> Vec<&'static str> {
+    read(MANIFEST).map(manifest_paths).unwrap_or_default()
+}
+
+pub fn list() {
+    let files = initramfs::root().files();
+    if files.is_empty() {
+        return serial_println!("initrd: no files");
+    }
+    for (path, len) in files {
+        serial_println!("  {:>8}  {}", len, path);
+    }
+}
+
+pub fn list_programs() {
+    let programs = programs();
+    if programs.is_empty() {
+        return serial_println!("initrd: no {}", MANIFEST);
+    }
+    for path in programs {
+        match read(path) {
+            Some(data) => serial_println!("  {:>8}  {}", data.len(), path),
+            None => serial_println!("  {:>8}  {}", "missing", path),
+        }
+    }
+}
+
+/// A newc entry, as the runner writes them.
+fn entry(archive: &mut Vec<u8>, name: &str, mode: u32, contents: &[u8]) {
+    let fields = [0, mode, 0, 0, 1, 0, contents.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(MAGIC);
+    for field in fields {
+        archive.extend_from_slice(alloc::format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(align4(archive.len()), 0);
+    archive.extend_from_slice(contents);
+    archive.resize(align4(archive.len()), 0);
+}
+
+/// A ustar member, as `tar` writes them.
+fn tar_entry(archive: &mut Vec<u8>, name: &str, kind: u8, mode: u32, contents: &[u8], link: &str) {
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(alloc::format!("{:07o}", mode).as_bytes());
+    header[124..135].copy_from_slice(alloc::format!("{:011o}", contents.len()).as_bytes());
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].fill(b' ');
+    let sum: usize = header.iter().map(|&b| b as usize).sum();
+    header[148..155].copy_from_slice(alloc::format!("{:06o}\0", sum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().div_ceil(TAR_BLOCK) * TAR_BLOCK, 0);
+}
+
+/// Parse a cpio archive with a directory, two files and odd lengths, refuse
+/// a truncated one; parse a tar archive with a directory, a file and a
+/// link, refuse one with a bad checksum; and read a manifest.
+pub fn self_test() -> bool {
+    let mut archive = Vec::new();
+    entry(&mut archive, ".", 0o040755, b"");
+    entry(&mut archive, "bin", 0o040755, b"");
+    entry(&mut archive, "bin/a", S_IFREG | 0o755, b"abc");
+    entry(&mut archive, "./etc/motd", S_IFREG | 0o644, b"hello\n");
+    entry(&mut archive, TRAILER, 0, b"");
+    let Ok(members) = parse(&archive) else { return false };
+    let ok = members.len() == 3
+        && members[0].path == "/bin"
+        && members[0].mode & S_IFMT == S_IFDIR
+        && members[1].path == "/bin/a"
+        && members[1].data == b"abc"
+        && members[2].path == "/etc/motd"
+        && members[2].data == b"hello\n"
+        && parse(&archive[..archive.len() - 20]).is_err();
+
+    let mut tar = Vec::new();
+    tar_entry(&mut tar, "./etc/", b'5', 0o755, b"", "");
+    tar_entry(&mut tar, "./etc/motd", b'0', 0o644, &[b'x'; 700], "");
+    tar_entry(&mut tar, "./motd", b'2', 0o777, b"", "etc/motd");
+    tar.resize(tar.len() + 2 * TAR_BLOCK, 0);
+    let Ok(members) = parse(&tar) else { return false };
+    let ok = ok
+        && members.len() == 3
+        && members[0].path == "/etc"
+        && members[0].mode == S_IFDIR | 0o755
+        && members[1].path == "/etc/motd"
+        && members[1].data == [b'x'; 700]
+        && members[2].path == "/motd"
+        && members[2].mode & S_IFMT == S_IFLNK
+        && members[2].data == b"etc/motd";
+    tar[TAR_BLOCK + 3] ^= 1;
+    ok && parse(&tar).is_err() && manifest_paths(b"/bin/a\n\n/bin/b\n") == ["/bin/a", "/bin/b"]
+}
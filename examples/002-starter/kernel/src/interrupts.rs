@@ -0,0 +1,426 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use crate::gdt;
+use crate::heap;
+use crate::memory::{cow, stack};
+use crate::memory::vma::{self, FaultOutcome};
+use crate::pic::{self, Irq, PIC1_OFFSET};
+use crate::smp::{self, lapic};
+use crate::{serial, serial_println};
+use crate::task::keyboard;
+use crate::process::signal;
+use crate::user::TrapFrame;
+use crate::{process, softirq, thread, time, user};
+
+static IDT: Once<InterruptDescriptorTable> = Once::new();
+
+/// How many times each vector's interrupt has come in, on all CPUs.
+/// Exceptions aren't counted.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Every vector that has come in at least once: the vector, what it is,
+/// and how many times.
+pub fn counts() -> Vec<(u8, String, u64)> {
+    (0..=255u8)
+        .filter_map(|vector| {
+            let count = COUNTS[vector as usize].load(Ordering::Relaxed);
+            (count != 0).then(|| (vector, vector_name(vector), count))
+        })
+        .collect()
+}
+
+fn vector_name(vector: u8) -> String {
+    match vector {
+        _ if vector == Irq::Timer.vector() => String::from("timer"),
+        _ if vector == Irq::Keyboard.vector() => String::from("keyboard"),
+        _ if vector == Irq::Com1.vector() => String::from("com1"),
+        smp::TICK_VECTOR => String::from("tick ipi"),
+        smp::WAKEUP_VECTOR => String::from("wakeup ipi"),
+        _ if (PIC1_OFFSET..PIC1_OFFSET + 16).contains(&vector) => format!("irq {}", vector - PIC1_OFFSET),
+        _ if (MSI_FIRST_VECTOR..MSI_FIRST_VECTOR + MSI_VECTORS as u8).contains(&vector) => {
+            format!("msi {}", vector - MSI_FIRST_VECTOR)
+        }
+        _ => String::from("?"),
+    }
+}
+
+// Entries that store the interrupted registers as a `TrapFrame` (like the
+// system call entries in `user`) and pass it to a handler. For exceptions
+// with an error code, the code's slot becomes RAX's and the code is the
+// handler's second argument. Both leave RSP 16-byte aligned at the call,
+// and clear DF, which ring 3 may have set.
+global_asm!(
+    ".macro push_from_rbx",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    ".endm",
+    ".macro pop_to_rax",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    ".endm",
+    ".macro interrupt_entry name, handler",
+    ".global \\name",
+    "\\name:",
+    "    push rax",
+    "    push_from_rbx",
+    "    cld",
+    "    mov rdi, rsp",
+    "    call \\handler",
+    "    pop_to_rax",
+    "    iretq",
+    ".endm",
+    ".macro fault_entry name, handler",
+    ".global \\name",
+    "\\name:",
+    "    xchg rax, [rsp]",
+    "    push_from_rbx",
+    "    cld",
+    "    mov rdi, rsp",
+    "    mov rsi, rax",
+    "    call \\handler",
+    "    pop_to_rax",
+    "    iretq",
+    ".endm",
+    "interrupt_entry timer_entry, {timer}",
+    "interrupt_entry tick_ipi_entry, {tick_ipi}",
+    "fault_entry page_fault_entry, {page_fault}",
+    "fault_entry general_protection_entry, {general_protection}",
+    "interrupt_entry invalid_opcode_entry, {invalid_opcode}",
+    "interrupt_entry divide_error_entry, {divide_error}",
+    timer = sym timer_interrupt,
+    tick_ipi = sym tick_ipi_interrupt,
+    page_fault = sym page_fault,
+    general_protection = sym general_protection,
+    invalid_opcode = sym invalid_opcode,
+    divide_error = sym divide_error,
+);
+
+extern "C" {
+    fn timer_entry();
+    fn tick_ipi_entry();
+    fn page_fault_entry();
+    fn general_protection_entry();
+    fn invalid_opcode_entry();
+    fn divide_error_entry();
+}
+
+pub fn init() {
+    let idt = IDT.call_once(|| {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt[Irq::Keyboard.vector()].set_handler_fn(keyboard_handler);
+        idt[Irq::Com1.vector()].set_handler_fn(com1_handler);
+        idt[smp::WAKEUP_VECTOR].set_handler_fn(wakeup_ipi_handler);
+        idt[lapic::SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
+        for (line, handler) in LINE_ENTRIES.into_iter().enumerate() {
+            if Irq::from_line(line as u8).is_none() && line as u8 != pic::CASCADE_LINE {
+                idt[PIC1_OFFSET + line as u8].set_handler_fn(handler);
+            }
+        }
+        for (i, handler) in MSI_ENTRIES.into_iter().enumerate() {
+            idt[MSI_FIRST_VECTOR + i as u8].set_handler_fn(handler);
+        }
+        // Plain assembly, not an `extern "x86-interrupt"` function: it
+        // returns into the kernel code that entered ring 3, not with `iretq`.
+        unsafe {
+            idt[user::TRAP_VECTOR]
+                .set_handler_addr(user::trap_handler())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        // Assembly too, for the entries that may act on signals on the way
+        // back to ring 3: that takes all of the user's registers.
+        unsafe {
+            idt.page_fault.set_handler_addr(VirtAddr::from_ptr(page_fault_entry as *const ()));
+            idt.general_protection_fault.set_handler_addr(VirtAddr::from_ptr(general_protection_entry as *const ()));
+            idt.invalid_opcode.set_handler_addr(VirtAddr::from_ptr(invalid_opcode_entry as *const ()));
+            idt.divide_error.set_handler_addr(VirtAddr::from_ptr(divide_error_entry as *const ()));
+            idt[Irq::Timer.vector()].set_handler_addr(VirtAddr::from_ptr(timer_entry as *const ()));
+            idt[smp::TICK_VECTOR].set_handler_addr(VirtAddr::from_ptr(tick_ipi_entry as *const ()));
+        }
+        idt
+    });
+    idt.load();
+    pic::init();
+    pic::unmask(Irq::Keyboard);
+    serial::enable_rx_interrupt();
+    pic::unmask(Irq::Com1);
+}
+
+/// Load the IDT on an application processor; all CPUs share it.
+pub fn init_ap() {
+    IDT.get().expect("IDT not initialized").load();
+}
+
+extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
+    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+}
+
+/// A fault from ring 3 only concerns the program: send its process
+/// `signal` (see `signal::raise_fault`), or end user code that has no
+/// process, and go on with whatever runs next. False for a fault in the
+/// kernel, which is a bug.
+fn user_fault(frame: &mut TrapFrame, sig: u32, what: &'static str, addr: Option<u64>, reason: &'static str) -> bool {
+    if frame.cs & 3 != 3 {
+        return false;
+    }
+    let fault = signal::Fault { what, addr, reason, rip: frame.rip };
+    interrupts::enable();
+    if !signal::raise_fault(sig, fault) {
+        serial_println!("user code: {}: killed by {}", fault, signal::name(sig));
+        user::leave(128 + sig as i64);
+    }
+    signal::deliver(frame);
+    interrupts::disable();
+    true
+}
+
+extern "C" fn general_protection(frame: &mut TrapFrame, code: u64) {
+    if !user_fault(frame, signal::SIGSEGV, "general protection fault", None, "") {
+        panic!("EXCEPTION: GENERAL PROTECTION FAULT (code {:#x})\n{:#x?}", code, frame);
+    }
+}
+
+extern "C" fn invalid_opcode(frame: &mut TrapFrame) {
+    if !user_fault(frame, signal::SIGILL, "invalid opcode", None, "") {
+        panic!("EXCEPTION: INVALID OPCODE\n{:#x?}", frame);
+    }
+}
+
+extern "C" fn divide_error(frame: &mut TrapFrame) {
+    if !user_fault(frame, signal::SIGFPE, "divide error", None, "") {
+        panic!("EXCEPTION: DIVIDE ERROR\n{:#x?}", frame);
+    }
+}
+
+extern "C" fn page_fault(frame: &mut TrapFrame, code: u64) {
+    let code = PageFaultErrorCode::from_bits_truncate(code);
+    let addr = Cr2::read().unwrap_or(VirtAddr::zero());
+    let outcome = if code.contains(PageFaultErrorCode::USER_MODE) {
+        // From ring 3, on the thread's own ring-0 stack: like a system call,
+        // this may block on the process's locks.
+        interrupts::enable();
+        let outcome = process::handle_page_fault(addr, code);
+        interrupts::disable();
+        if matches!(outcome, FaultOutcome::Handled) {
+            return return_to_user(frame);
+        }
+        outcome
+    } else {
+        let write_to_present = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+        if code.contains(write_to_present) && cow::handle_write_fault(addr) {
+            return;
+        }
+        if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && heap::handle_page_fault(addr) {
+            return;
+        }
+        vma::handle_page_fault(addr, code)
+    };
+    let reason = match outcome {
+        FaultOutcome::Handled => return,
+        FaultOutcome::AccessViolation(reason) => reason,
+        FaultOutcome::Unmapped => "no mapping",
+    };
+    if user_fault(frame, signal::SIGSEGV, "page fault", Some(addr.as_u64()), reason) {
+        return;
+    }
+    // Usually an overflow escalates to a double fault (no room to push this frame),
+    // but a big stack frame can jump straight into the guard page with room to spare.
+    if let Some(name) = stack::overflowed_stack(addr) {
+        panic!("kernel stack overflow in thread {} (touched guard page at {:#x})\n{:#x?}", name, addr.as_u64(), frame);
+    }
+    panic!("EXCEPTION: PAGE FAULT at {:#x}: {} ({:?})\n{:#x?}", addr.as_u64(), reason, code, frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _code: u64) -> ! {
+    // CR2 still holds the address of the page fault that escalated, if there was one.
+    let addr = Cr2::read().unwrap_or(VirtAddr::zero());
+    if let Some(name) = stack::overflowed_stack(addr) {
+        panic!("kernel stack overflow in thread {} (double fault, CR2={:#x})\n{:#?}", name, addr.as_u64(), frame);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", frame);
+}
+
+extern "C" fn timer_interrupt(frame: &mut TrapFrame) {
+    count(Irq::Timer.vector());
+    time::on_tick();
+    // Acknowledge first: if we switch threads, this handler only finishes when
+    // the current thread runs again.
+    pic::end_of_interrupt(Irq::Timer);
+    smp::broadcast_tick();
+    softirq::run();
+    thread::on_tick();
+    return_to_user(frame);
+}
+
+/// The BSP's timer tick, on the other CPUs: preempt like the timer does.
+extern "C" fn tick_ipi_interrupt(frame: &mut TrapFrame) {
+    count(smp::TICK_VECTOR);
+    lapic::end_of_interrupt();
+    softirq::run();
+    thread::on_tick();
+    return_to_user(frame);
+}
+
+/// At the end of an interrupt that came from ring 3: act on signals sent
+/// meanwhile, so even a program that never makes a system call gets them.
+fn return_to_user(frame: &mut TrapFrame) {
+    if frame.cs & 3 == 3 && signal::pending() {
+        interrupts::enable();
+        signal::deliver(frame);
+        interrupts::disable();
+    }
+}
+
+/// Work was queued for this (idle) CPU.
+extern "x86-interrupt" fn wakeup_ipi_handler(_frame: InterruptStackFrame) {
+    count(smp::WAKEUP_VECTOR);
+    lapic::end_of_interrupt();
+    softirq::run();
+    thread::reschedule();
+}
+
+extern "x86-interrupt" fn keyboard_handler(_frame: InterruptStackFrame) {
+    count(Irq::Keyboard.vector());
+    let scancode: u8 = unsafe { Port::new(0x60).read() };
+    keyboard::add_scancode(scancode);
+    pic::end_of_interrupt(Irq::Keyboard);
+    softirq::run();
+}
+
+extern "x86-interrupt" fn com1_handler(_frame: InterruptStackFrame) {
+    count(Irq::Com1.vector());
+    serial::on_interrupt();
+    pic::end_of_interrupt(Irq::Com1);
+    softirq::run();
+}
+
+/// A device interrupt handler: checks whether its device raised the
+/// interrupt and, if so, has it lower the line (nothing to lower for a
+/// message-signalled one). Runs in the interrupt, so it must not allocate
+/// or block.
+pub type DeviceHandler = Box<dyn Fn() + Send + Sync>;
+
+/// The handlers on each legacy IRQ line that a driver claimed at run time.
+/// PCI devices share lines, so every handler on the line runs.
+static LINE_HANDLERS: Mutex<[Vec<DeviceHandler>; 16]> = Mutex::new([const { Vec::new() }; 16]);
+
+/// Run `handler` on every interrupt on IRQ `line` from now on, and unmask
+/// the line. False for a line the kernel keeps for itself (the timer, the
+/// keyboard, COM1, the cascade) or that doesn't exist.
+pub fn add_line_handler(line: u8, handler: DeviceHandler) -> bool {
+    if line >= 16 || Irq::from_line(line).is_some() || line == pic::CASCADE_LINE {
+        return false;
+    }
+    // The lock is taken in interrupts; keep them out while we hold it.
+    interrupts::without_interrupts(|| LINE_HANDLERS.lock()[line as usize].push(handler));
+    pic::unmask_line(line);
+    true
+}
+
+const LINE_ENTRIES: [HandlerFunc; 16] = [
+    line_handler::<0>, line_handler::<1>, line_handler::<2>, line_handler::<3>,
+    line_handler::<4>, line_handler::<5>, line_handler::<6>, line_handler::<7>,
+    line_handler::<8>, line_handler::<9>, line_handler::<10>, line_handler::<11>,
+    line_handler::<12>, line_handler::<13>, line_handler::<14>, line_handler::<15>,
+];
+
+/// Any line given to `add_line_handler`. The handlers run before the EOI:
+/// a PCI line is level-triggered and would fire again at once if the
+/// device still held it.
+extern "x86-interrupt" fn line_handler<const LINE: u8>(_frame: InterruptStackFrame) {
+    if pic::spurious(LINE) {
+        return;
+    }
+    count(PIC1_OFFSET + LINE);
+    for handler in LINE_HANDLERS.lock()[LINE as usize].iter() {
+        handler();
+    }
+    pic::end_of_line(LINE);
+    softirq::run();
+}
+
+/// Vectors for message-signalled interrupts (MSI, MSI-X): a device raises
+/// one by writing its number to the local APIC, so it needs no line and
+/// shares none.
+pub const MSI_FIRST_VECTOR: u8 = 0x50;
+const MSI_VECTORS: usize = 16;
+
+static MSI_HANDLERS: [Once<DeviceHandler>; MSI_VECTORS] = [const { Once::new() }; MSI_VECTORS];
+static MSI_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A vector of its own for a device, running `handler`; what to program
+/// into its MSI or MSI-X entry. `None` once all are taken.
+pub fn alloc_msi_vector(handler: DeviceHandler) -> Option<u8> {
+    let index = MSI_NEXT.fetch_add(1, Ordering::Relaxed);
+    if index >= MSI_VECTORS {
+        return None;
+    }
+    MSI_HANDLERS[index].call_once(|| handler);
+    Some(MSI_FIRST_VECTOR + index as u8)
+}
+
+const MSI_ENTRIES: [HandlerFunc; MSI_VECTORS] = [
+    msi_handler::<0>, msi_handler::<1>, msi_handler::<2>, msi_handler::<3>,
+    msi_handler::<4>, msi_handler::<5>, msi_handler::<6>, msi_handler::<7>,
+    msi_handler::<8>, msi_handler::<9>, msi_handler::<10>, msi_handler::<11>,
+    msi_handler::<12>, msi_handler::<13>, msi_handler::<14>, msi_handler::<15>,
+];
+
+/// Any vector from `alloc_msi_vector`. It came through the local APIC, so
+/// that is where the EOI goes.
+extern "x86-interrupt" fn msi_handler<const INDEX: usize>(_frame: InterruptStackFrame) {
+    count(MSI_FIRST_VECTOR + INDEX as u8);
+    if let Some(handler) = MSI_HANDLERS[INDEX].get() {
+        handler();
+    }
+    lapic::end_of_interrupt();
+    softirq::run();
+}
+
+/// The APIC raises this when an interrupt goes away before it is delivered. No EOI.
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {}
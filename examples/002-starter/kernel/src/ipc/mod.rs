@@ -0,0 +1,15 @@
+//! Inter-process communication: ways for processes, each in its own
+//! address space, to hand each other data through the kernel.
+
+pub mod mqueue;
+pub mod pipe;
+pub mod shm;
+
+pub fn dump() {
+    mqueue::dump();
+    shm::dump();
+}
+
+pub fn self_test() -> bool {
+    mqueue::self_test() & pipe::self_test() & shm::self_test()
+}
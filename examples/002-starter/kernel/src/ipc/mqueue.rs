@@ -0,0 +1,279 @@
+//! Message queues: bounded FIFOs of byte messages that processes (and
+//! kernel threads) send to and receive from.
+//!
+//! A queue is named by a key, like a System V one: whoever opens a key
+//! first creates the queue, later opens get the same one, and key 0 always
+//! makes a new, private queue. Each queue also has an ID, what the system
+//! calls take. Queues outlive the processes using them until removed.
+//!
+//! Sending blocks while the queue is full and receiving while it is empty,
+//! each up to a deadline: `None` waits for as long as it takes, and one
+//! already past only tries once. Removing a queue wakes everyone blocked
+//! on it with `MqError::Removed`.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::process;
+use crate::serial_println;
+use crate::sync::{Mutex, WaitQueue};
+use crate::thread;
+use crate::time;
+use crate::user::programs;
+
+/// The largest message, in bytes.
+pub const MAX_MESSAGE: usize = 256;
+/// The most messages one queue can hold.
+pub const MAX_CAPACITY: usize = 64;
+/// Queues that can exist at once.
+const MAX_QUEUES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqError {
+    /// No queue has that ID.
+    NotFound,
+    /// The queue was removed while, or before, we waited on it.
+    Removed,
+    /// A message over `MAX_MESSAGE`, or one too big for the receiver.
+    TooBig,
+    /// A capacity of 0 or over `MAX_CAPACITY`.
+    BadCapacity,
+    /// `MAX_QUEUES` exist already.
+    TooMany,
+    /// The deadline passed first.
+    TimedOut,
+}
+
+pub struct MessageQueue {
+    id: u64,
+    key: u64,
+    capacity: usize,
+    messages: Mutex<VecDeque<Vec<u8>>>,
+    /// `messages.len()`, for the wait conditions, which can't take the lock.
+    queued: AtomicUsize,
+    removed: AtomicBool,
+    sent: AtomicU64,
+    /// Senders waiting for room.
+    not_full: WaitQueue,
+    /// Receivers waiting for a message.
+    not_empty: WaitQueue,
+}
+
+static QUEUES: Mutex<BTreeMap<u64, Arc<MessageQueue>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// `queue.wait_until(condition)`, up to `deadline` if there is one.
+fn wait(queue: &WaitQueue, condition: impl FnMut() -> bool, deadline: Option<Duration>) -> bool {
+    match deadline {
+        Some(deadline) => queue.wait_until_deadline(condition, deadline),
+        None => {
+            queue.wait_until(condition);
+            true
+        }
+    }
+}
+
+impl MessageQueue {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Append `message`, waiting up to `deadline` for room.
+    pub fn send(&self, message: &[u8], deadline: Option<Duration>) -> Result<(), MqError> {
+        if message.len() > MAX_MESSAGE {
+            return Err(MqError::TooBig);
+        }
+        // Copied before locking: the lock is held only to move it in.
+        let mut message = Some(message.to_vec());
+        loop {
+            if self.removed.load(Ordering::Acquire) {
+                return Err(MqError::Removed);
+            }
+            {
+                let mut messages = self.messages.lock();
+                if messages.len() < self.capacity {
+                    messages.push_back(message.take().expect("sent once"));
+                    self.queued.store(messages.len(), Ordering::Release);
+                    self.sent.fetch_add(1, Ordering::Relaxed);
+                    drop(messages);
+                    self.not_empty.notify_all();
+                    return Ok(());
+                }
+            }
+            let room = || self.queued.load(Ordering::Acquire) < self.capacity || self.removed.load(Ordering::Acquire);
+            if !wait(&self.not_full, room, deadline) {
+                return Err(MqError::TimedOut);
+            }
+        }
+    }
+
+    /// Take the oldest message, waiting up to `deadline` for one. A message
+    /// longer than `max_len` stays queued: `TooBig`.
+    pub fn receive(&self, max_len: usize, deadline: Option<Duration>) -> Result<Vec<u8>, MqError> {
+        loop {
+            if self.removed.load(Ordering::Acquire) {
+                return Err(MqError::Removed);
+            }
+            {
+                let mut messages = self.messages.lock();
+                match messages.front() {
+                    Some(message) if message.len() > max_len => return Err(MqError::TooBig),
+                    Some(_) => {
+                        let message = messages.pop_front().expect("front exists");
+                        self.queued.store(messages.len(), Ordering::Release);
+                        drop(messages);
+                        self.not_full.notify_all();
+                        return Ok(message);
+                    }
+                    None => {}
+                }
+            }
+            let ready = || self.queued.load(Ordering::Acquire) > 0 || self.removed.load(Ordering::Acquire);
+            if !wait(&self.not_empty, ready, deadline) {
+                return Err(MqError::TimedOut);
+            }
+        }
+    }
+}
+
+/// The queue for `key`, created with room for `capacity` messages if there
+/// is none (the capacity of an existing one is kept); key 0 always creates.
+pub fn open(key: u64, capacity: usize) -> Result<Arc<MessageQueue>, MqError> {
+    let mut queues = QUEUES.lock();
+    if key != 0 {
+        if let Some(queue) = queues.values().find(|queue| queue.key == key) {
+            return Ok(queue.clone());
+        }
+    }
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        return Err(MqError::BadCapacity);
+    }
+    if queues.len() >= MAX_QUEUES {
+        return Err(MqError::TooMany);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let queue = Arc::new(MessageQueue {
+        id,
+        key,
+        capacity,
+        messages: Mutex::new(VecDeque::new()),
+        queued: AtomicUsize::new(0),
+        removed: AtomicBool::new(false),
+        sent: AtomicU64::new(0),
+        not_full: WaitQueue::new(),
+        not_empty: WaitQueue::new(),
+    });
+    queues.insert(id, queue.clone());
+    Ok(queue)
+}
+
+pub fn get(id: u64) -> Option<Arc<MessageQueue>> {
+    QUEUES.lock().get(&id).cloned()
+}
+
+/// Remove queue `id`: its messages are dropped, its key is free again and
+/// whoever waits on it gets `Removed`.
+pub fn remove(id: u64) -> Result<(), MqError> {
+    let queue = QUEUES.lock().remove(&id).ok_or(MqError::NotFound)?;
+    queue.removed.store(true, Ordering::Release);
+    queue.not_full.notify_all();
+    queue.not_empty.notify_all();
+    Ok(())
+}
+
+pub fn dump() {
+    let queues: Vec<Arc<MessageQueue>> = QUEUES.lock().values().cloned().collect();
+    if queues.is_empty() {
+        return serial_println!("message queues: none");
+    }
+    serial_println!("  id  key                 capacity  queued       sent");
+    for queue in queues {
+        serial_println!(
+            "  {:>2}  {:#018x}  {:>8}  {:>6}  {:>9}",
+            queue.id, queue.key, queue.capacity, queue.queued.load(Ordering::Relaxed), queue.sent.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Run `/bin/pong` against `programs::ping(rounds)` on fresh queues; the
+/// exit statuses of ping and pong, and how long it all took in TSC cycles.
+fn ping_pong(rounds: u32) -> Option<(i32, i32, u64)> {
+    let ping = open(programs::PING_KEY, 1).ok()?;
+    let pong = open(programs::PONG_KEY, 1).ok()?;
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let result = match (process::spawn("/bin/pong", &["pong"]), process::spawn_image("ping", &programs::ping(rounds), &["ping"])) {
+        (Ok(ponger), Ok(pinger)) => {
+            let ping_status = pinger.wait_exit();
+            let pong_status = ponger.wait_exit();
+            let cycles = unsafe { core::arch::x86_64::_rdtsc() } - start;
+            process::reap(pinger.pid());
+            process::reap(ponger.pid());
+            Some((ping_status, pong_status, cycles))
+        }
+        (ponger, pinger) => {
+            // Whichever did start gives up when its first receive times out.
+            for process in [ponger, pinger].into_iter().flatten() {
+                process.wait_exit();
+                process::reap(process.pid());
+            }
+            None
+        }
+    };
+    let _ = remove(ping.id());
+    let _ = remove(pong.id());
+    result
+}
+
+/// Round-trip latency between two processes: ping sends a message, pong
+/// sends it back. Startup and exit cost the same for any number of rounds,
+/// so one run of 1 round is subtracted from one of `ROUNDS`.
+pub fn bench() {
+    const ROUNDS: u32 = 1000;
+    let (Some((0, 0, one)), Some((0, 0, many))) = (ping_pong(1), ping_pong(ROUNDS)) else {
+        return serial_println!("ipc bench: ping-pong failed");
+    };
+    let round_trip = time::tsc_duration(many.saturating_sub(one)) / (ROUNDS - 1);
+    serial_println!("ipc bench: {} round trips, {:?} each", ROUNDS, round_trip);
+}
+
+/// Messages come out in order, a full queue and an empty one time out
+/// (at once with a deadline already past), an oversized message is refused
+/// and one too big for the receiver stays queued. A blocked receiver gets
+/// what another thread sends, and removing a queue wakes a blocked sender.
+/// Keys find the same queue, and two processes ping-pong through a pair.
+pub fn self_test() -> bool {
+    let Ok(queue) = open(0, 2) else { return false };
+    let now = Some(time::uptime());
+    let mut ok = queue.receive(MAX_MESSAGE, now) == Err(MqError::TimedOut);
+    ok &= queue.send(b"one", None).is_ok() && queue.send(b"two", None).is_ok();
+    ok &= queue.send(b"three", now) == Err(MqError::TimedOut);
+    let start = time::ticks();
+    ok &= queue.send(b"three", Some(time::uptime() + Duration::from_millis(30))) == Err(MqError::TimedOut);
+    ok &= (3..=5).contains(&(time::ticks() - start));
+    ok &= queue.send(&[0; MAX_MESSAGE + 1], None) == Err(MqError::TooBig);
+    ok &= queue.receive(2, None) == Err(MqError::TooBig);
+    ok &= queue.receive(MAX_MESSAGE, None).as_deref() == Ok(&b"one"[..]);
+    ok &= queue.receive(MAX_MESSAGE, None).as_deref() == Ok(&b"two"[..]);
+
+    let theirs = queue.clone();
+    let receiver = thread::spawn(move || theirs.receive(MAX_MESSAGE, None));
+    time::sleep(Duration::from_millis(20));
+    ok &= queue.send(b"wake", None).is_ok();
+    ok &= receiver.join().as_deref() == Ok(&b"wake"[..]);
+
+    ok &= queue.send(b"a", None).is_ok() && queue.send(b"b", None).is_ok();
+    let theirs = queue.clone();
+    let sender = thread::spawn(move || theirs.send(b"c", None));
+    time::sleep(Duration::from_millis(20));
+    ok &= remove(queue.id()).is_ok() && sender.join() == Err(MqError::Removed);
+    ok &= get(queue.id()).is_none() && remove(queue.id()) == Err(MqError::NotFound);
+
+    let (Ok(a), Ok(b), Ok(private)) = (open(0x5e1f, 1), open(0x5e1f, 8), open(0, 1)) else { return false };
+    ok &= a.id() == b.id() && private.id() != a.id() && b.capacity == 1;
+    ok &= remove(a.id()).is_ok() && remove(private.id()).is_ok();
+
+    ok && ping_pong(20).is_some_and(|(ping, pong, _)| ping == 0 && pong == 0)
+}
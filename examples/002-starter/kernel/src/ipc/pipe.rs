@@ -0,0 +1,260 @@
+//! Pipes: a one-way stream of bytes from a write end to a read end, through
+//! a ring buffer in the kernel. Unlike message queues they have no name:
+//! the two ends are only reachable through descriptors, so a pipe connects
+//! the process that made it with the children that inherit its ends.
+//!
+//! Reading blocks while the pipe is empty and writing while it is full.
+//! Once the write end is closed, reads drain what is left and then return
+//! 0, end of file; once the read end is closed, writes fail with `Broken`.
+//! An end is closed when the last descriptor for it is. A blocked reader or
+//! writer also gives up (`Interrupted`) when its caller says so, as a
+//! process does for a signal.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::sync::{Mutex, WaitQueue};
+use crate::thread;
+use crate::time;
+
+/// Bytes a pipe holds before writers block.
+pub const CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// The read end is closed: nobody will read what is written.
+    Broken,
+    /// The caller asked to stop waiting.
+    Interrupted,
+}
+
+/// A fixed ring of bytes: `len` of them, from `head` on, wrapping around.
+struct Ring {
+    bytes: Box<[u8]>,
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    /// Append as much of `data` as fits; how much that was.
+    fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.bytes.len() - self.len);
+        for (i, &byte) in data[..n].iter().enumerate() {
+            let at = (self.head + self.len + i) % self.bytes.len();
+            self.bytes[at] = byte;
+        }
+        self.len += n;
+        n
+    }
+
+    /// Take up to `buf.len()` bytes from the front; how many.
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len);
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte = self.bytes[(self.head + i) % self.bytes.len()];
+        }
+        self.head = (self.head + n) % self.bytes.len();
+        self.len -= n;
+        n
+    }
+}
+
+struct Pipe {
+    id: u64,
+    ring: Mutex<Ring>,
+    /// `ring.len`, for the wait conditions, which can't take the lock.
+    buffered: AtomicUsize,
+    reader_open: AtomicBool,
+    writer_open: AtomicBool,
+    /// Readers waiting for bytes or end of file.
+    readable: WaitQueue,
+    /// Writers waiting for room or a broken pipe.
+    writable: WaitQueue,
+}
+
+/// The read end. Dropping it closes it.
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// The write end. Dropping it closes it.
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new, empty pipe: its read end and its write end.
+pub fn new() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        ring: Mutex::new(Ring { bytes: vec![0; CAPACITY].into_boxed_slice(), head: 0, len: 0 }),
+        buffered: AtomicUsize::new(0),
+        reader_open: AtomicBool::new(true),
+        writer_open: AtomicBool::new(true),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /// Which pipe this is, for `procs files`.
+    pub fn id(&self) -> u64 {
+        self.pipe.id
+    }
+
+    /// Read into `buf`, waiting for at least one byte while the write end is
+    /// open and `interrupted` says not to stop; how much was read, 0 at end
+    /// of file.
+    pub fn read(&self, buf: &mut [u8], interrupted: impl Fn() -> bool) -> Result<usize, PipeError> {
+        let pipe = &*self.pipe;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // Loaded before the ring is: if the write end was closed by
+            // then, the ring holds everything that will ever be written.
+            let writer_open = pipe.writer_open.load(Ordering::Acquire);
+            {
+                let mut ring = pipe.ring.lock();
+                let n = ring.pop(buf);
+                pipe.buffered.store(ring.len, Ordering::Release);
+                drop(ring);
+                if n > 0 {
+                    pipe.writable.notify_all();
+                    return Ok(n);
+                }
+            }
+            if !writer_open {
+                return Ok(0);
+            }
+            if interrupted() {
+                return Err(PipeError::Interrupted);
+            }
+            pipe.readable.wait_until(|| {
+                pipe.buffered.load(Ordering::Acquire) > 0 || !pipe.writer_open.load(Ordering::Acquire) || interrupted()
+            });
+        }
+    }
+}
+
+impl PipeWriter {
+    pub fn id(&self) -> u64 {
+        self.pipe.id
+    }
+
+    /// Write as much of `data` as fits, waiting for room for at least one
+    /// byte while the read end is open and `interrupted` says not to stop;
+    /// how much was written.
+    pub fn write(&self, data: &[u8], interrupted: impl Fn() -> bool) -> Result<usize, PipeError> {
+        let pipe = &*self.pipe;
+        loop {
+            if !pipe.reader_open.load(Ordering::Acquire) {
+                return Err(PipeError::Broken);
+            }
+            if data.is_empty() {
+                return Ok(0);
+            }
+            {
+                let mut ring = pipe.ring.lock();
+                let n = ring.push(data);
+                pipe.buffered.store(ring.len, Ordering::Release);
+                drop(ring);
+                if n > 0 {
+                    pipe.readable.notify_all();
+                    return Ok(n);
+                }
+            }
+            if interrupted() {
+                return Err(PipeError::Interrupted);
+            }
+            pipe.writable.wait_until(|| {
+                pipe.buffered.load(Ordering::Acquire) < CAPACITY || !pipe.reader_open.load(Ordering::Acquire) || interrupted()
+            });
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.reader_open.store(false, Ordering::Release);
+        self.pipe.writable.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.writer_open.store(false, Ordering::Release);
+        self.pipe.readable.notify_all();
+    }
+}
+
+/// Bytes come out in order across the ring's wrap-around, a blocked reader
+/// is woken by a write and a blocked writer by a read, closing the write
+/// end gives end of file once the pipe is drained, bytes written just
+/// before the close included, closing the read end breaks it, and an
+/// interrupted wait gives up.
+pub fn self_test() -> bool {
+    let (reader, writer) = new();
+    let never = || false;
+    let mut buf = [0u8; 8];
+    let mut ok = writer.write(b"hello", never) == Ok(5);
+    ok &= reader.read(&mut buf[..3], never) == Ok(3) && &buf[..3] == b"hel";
+    ok &= reader.read(&mut buf, never) == Ok(2) && &buf[..2] == b"lo";
+    ok &= reader.read(&mut buf, || true) == Err(PipeError::Interrupted);
+
+    // Fill it from an offset, so the next write wraps around.
+    let fill = vec![0x5a; CAPACITY - 2];
+    ok &= writer.write(&fill, never) == Ok(CAPACITY - 2) && writer.write(b"wrap", never) == Ok(2);
+    ok &= writer.write(b"!", || true) == Err(PipeError::Interrupted);
+    let mut drained = vec![0; CAPACITY];
+    ok &= reader.read(&mut drained, never) == Ok(CAPACITY) && &drained[CAPACITY - 2..] == b"wr";
+
+    let (reader, writer) = (Arc::new(reader), Arc::new(writer));
+    let theirs = reader.clone();
+    let blocked_reader = thread::spawn(move || {
+        let mut buf = [0u8; 8];
+        theirs.read(&mut buf, || false).map(|n| buf[..n].to_vec())
+    });
+    time::sleep(Duration::from_millis(20));
+    ok &= writer.write(b"wake", never) == Ok(4);
+    ok &= blocked_reader.join().as_deref() == Ok(&b"wake"[..]);
+
+    ok &= writer.write(&fill, never) == Ok(CAPACITY - 2) && writer.write(b"ab", never) == Ok(2);
+    let theirs = writer.clone();
+    let blocked_writer = thread::spawn(move || theirs.write(b"c", || false));
+    time::sleep(Duration::from_millis(20));
+    ok &= reader.read(&mut buf[..1], never) == Ok(1);
+    ok &= blocked_writer.join() == Ok(1);
+
+    drop(writer);
+    ok &= reader.read(&mut drained, never) == Ok(CAPACITY) && reader.read(&mut buf, never) == Ok(0);
+
+    // Write, close, then read: the bytes come before end of file, whether
+    // the reader was waiting or not.
+    let (reader, writer) = new();
+    ok &= writer.write(b"last", never) == Ok(4);
+    drop(writer);
+    ok &= reader.read(&mut buf, never) == Ok(4) && &buf[..4] == b"last" && reader.read(&mut buf, never) == Ok(0);
+    let (reader, writer) = new();
+    let closing = thread::spawn(move || writer.write(b"last", || false));
+    let mut got = Vec::new();
+    while let Ok(n @ 1..) = reader.read(&mut buf, never) {
+        got.extend_from_slice(&buf[..n]);
+    }
+    ok &= closing.join() == Ok(4) && got == b"last";
+
+    let (reader, writer) = new();
+    let writer = Arc::new(writer);
+    ok &= writer.write(&fill, never).is_ok() && writer.write(b"ab", never).is_ok();
+    let theirs = writer.clone();
+    let blocked_writer = thread::spawn(move || theirs.write(b"c", || false));
+    time::sleep(Duration::from_millis(20));
+    drop(reader);
+    ok && blocked_writer.join() == Err(PipeError::Broken) && writer.write(b"d", never) == Err(PipeError::Broken)
+}
@@ -0,0 +1,204 @@
+//! Shared memory segments: frames that several processes map at once, so
+//! what one writes the others read without the kernel copying anything.
+//! The usual pattern is to put the data in a segment and send a short
+//! message queue message saying where it is.
+//!
+//! Segments are named by key like message queues (see `mqueue`). A segment
+//! holds a reference to each of its frames, and so does each page mapping
+//! them (see `AddressSpace::map_shared`): removing a segment only drops its
+//! own, and the memory is freed once the last process has unmapped it or
+//! exited.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::{self, AddressSpace};
+use crate::memory::frame_alloc;
+use crate::memory::phys_to_virt;
+use crate::memory::vma::{Prot, VmaError};
+use crate::process;
+use crate::serial_println;
+use crate::sync::Mutex;
+use crate::user::programs;
+
+use super::mqueue;
+
+const PAGE_SIZE: u64 = 4096;
+/// The largest segment: 1 MiB.
+pub const MAX_SIZE: u64 = 256 * PAGE_SIZE;
+/// Segments that can exist at once.
+const MAX_SEGMENTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// No segment has that ID.
+    NotFound,
+    /// A size of 0 or over `MAX_SIZE`, or over the size of the existing
+    /// segment with that key.
+    BadSize,
+    /// `MAX_SEGMENTS` exist already.
+    TooMany,
+    OutOfMemory,
+}
+
+pub struct Segment {
+    id: u64,
+    key: u64,
+    frames: Vec<PhysFrame>,
+}
+
+static SEGMENTS: Mutex<BTreeMap<u64, Arc<Segment>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Segment {
+    /// `pages` zeroed frames.
+    fn allocate(id: u64, key: u64, pages: usize) -> Option<Segment> {
+        let mut segment = Segment { id, key, frames: Vec::with_capacity(pages) };
+        for _ in 0..pages {
+            // Dropping `segment` frees what was allocated so far.
+            let frame = frame_alloc::allocate_frame()?;
+            unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+            segment.frames.push(frame);
+        }
+        Some(segment)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * PAGE_SIZE
+    }
+
+    /// Pages mapping the first frame: how many times the segment is mapped.
+    fn mappings(&self) -> usize {
+        frame_alloc::ref_count(self.frames[0]).saturating_sub(1)
+    }
+
+    /// Map the whole segment into `space` with `prot`; returns where.
+    pub fn map_into(&self, space: &mut AddressSpace, prot: Prot) -> Result<VirtAddr, VmaError> {
+        space.map_shared(&self.frames, prot)
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            unsafe { frame_alloc::deallocate_frame(frame) };
+        }
+    }
+}
+
+/// The segment for `key`, created with `size` bytes (rounded up to whole
+/// pages) if there is none; key 0 always creates.
+pub fn create(key: u64, size: u64) -> Result<Arc<Segment>, ShmError> {
+    if size == 0 || size > MAX_SIZE {
+        return Err(ShmError::BadSize);
+    }
+    let mut segments = SEGMENTS.lock();
+    if key != 0 {
+        if let Some(segment) = segments.values().find(|segment| segment.key == key) {
+            return if size <= segment.size() { Ok(segment.clone()) } else { Err(ShmError::BadSize) };
+        }
+    }
+    if segments.len() >= MAX_SEGMENTS {
+        return Err(ShmError::TooMany);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let pages = size.div_ceil(PAGE_SIZE) as usize;
+    let segment = Arc::new(Segment::allocate(id, key, pages).ok_or(ShmError::OutOfMemory)?);
+    segments.insert(id, segment.clone());
+    Ok(segment)
+}
+
+pub fn get(id: u64) -> Option<Arc<Segment>> {
+    SEGMENTS.lock().get(&id).cloned()
+}
+
+/// Remove segment `id`: its key is free again, and its memory goes once
+/// nobody has it mapped.
+pub fn remove(id: u64) -> Result<(), ShmError> {
+    SEGMENTS.lock().remove(&id).map(drop).ok_or(ShmError::NotFound)
+}
+
+pub fn dump() {
+    let segments: Vec<Arc<Segment>> = SEGMENTS.lock().values().cloned().collect();
+    if segments.is_empty() {
+        return serial_println!("shared memory: none");
+    }
+    serial_println!("  id  key                     size  mappings");
+    for segment in segments {
+        serial_println!("  {:>2}  {:#018x}  {:>8}  {:>8}", segment.id, segment.key, segment.size(), segment.mappings());
+    }
+}
+
+/// Run `programs::shm_writer` and `programs::shm_reader` on a fresh segment
+/// and queue; the reader's exit status.
+fn writer_to_reader() -> Option<i32> {
+    let segment = create(programs::SHM_KEY, PAGE_SIZE).ok()?;
+    let queue = mqueue::open(programs::SHM_QUEUE_KEY, 1).ok()?;
+    let writer = process::spawn_image("shm-writer", &programs::shm_writer(), &["shm-writer"]);
+    let reader = process::spawn_image("shm-reader", &programs::shm_reader(), &["shm-reader"]);
+    let status = match (writer, reader) {
+        (Ok(writer), Ok(reader)) => {
+            writer.wait_exit();
+            let status = reader.wait_exit();
+            process::reap(writer.pid());
+            process::reap(reader.pid());
+            Some(status)
+        }
+        (writer, reader) => {
+            for process in [writer, reader].into_iter().flatten() {
+                process.wait_exit();
+                process::reap(process.pid());
+            }
+            None
+        }
+    };
+    let _ = mqueue::remove(queue.id());
+    let _ = remove(segment.id());
+    status
+}
+
+/// Two address spaces map one segment, one writable and one read-only,
+/// and see the same memory; it is freed only after the segment is removed
+/// and the last mapping is gone. Keys find the same segment. One process
+/// writes a value to a segment and tells another where it is through a
+/// message queue; the reader exits with it.
+pub fn self_test() -> bool {
+    let before = frame_alloc::stats().map(|s| s.free);
+    let ok = (|| {
+        let segment = create(0, 5000).ok()?;
+        let (mut a, mut b) = (AddressSpace::new()?, AddressSpace::new()?);
+        let in_a = segment.map_into(&mut a, Prot::READ | Prot::WRITE).ok()?;
+        let in_b = segment.map_into(&mut b, Prot::READ).ok()?;
+        let mut ok = segment.size() == 2 * PAGE_SIZE && segment.mappings() == 2;
+        let last = |at: VirtAddr| at + PAGE_SIZE + 8;
+        let (phys_a, flags_a) = a.translate(last(in_a))?;
+        let (phys_b, flags_b) = b.translate(last(in_b))?;
+        ok &= phys_a == phys_b && flags_a.contains(PageTableFlags::WRITABLE) && !flags_b.contains(PageTableFlags::WRITABLE);
+        a.switch();
+        unsafe { last(in_a).as_mut_ptr::<u64>().write_volatile(0x5ea1) };
+        b.switch();
+        ok &= unsafe { last(in_b).as_ptr::<u64>().read_volatile() } == 0x5ea1;
+        address_space::switch_to_kernel();
+        ok &= a.munmap(in_a, 5000).is_ok() && segment.mappings() == 1;
+        ok &= remove(segment.id()).is_ok() && get(segment.id()).is_none();
+        drop(segment);
+        ok &= b.translate(last(in_b)).is_some();
+        Some(ok)
+    })() == Some(true);
+    let freed = frame_alloc::stats().map(|s| s.free) == before;
+
+    let Ok(first) = create(0x5e6, PAGE_SIZE) else { return false };
+    let same = create(0x5e6, 100).ok().map(|s| s.id()) == Some(first.id());
+    let too_big = create(0x5e6, 2 * PAGE_SIZE).err() == Some(ShmError::BadSize);
+    let keys = same && too_big && remove(first.id()).is_ok() && create(0, MAX_SIZE + 1).err() == Some(ShmError::BadSize);
+
+    ok && freed && keys && writer_to_reader() == Some(programs::SHM_VALUE as i32)
+}
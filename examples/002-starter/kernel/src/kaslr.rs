@@ -0,0 +1,35 @@
+//! Kernel address space layout randomization.
+//!
+//! The bootloader already places the kernel image and its own mappings at random
+//! addresses (`mappings.aslr` in `BOOTLOADER_CONFIG`). On top of that we slide the
+//! regions we place ourselves: the heap, the kernel stacks and the physical map.
+//! Boot with `nokaslr` on the command line to get the fixed addresses back for debugging.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{cmdline, entropy};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Decide whether to randomize. Needs `cmdline::init`.
+pub fn init() {
+    let enabled = !cmdline::has_flag("nokaslr");
+    ENABLED.store(enabled, Ordering::Relaxed);
+    crate::serial_println!(
+        "kaslr: {} (random source: {})",
+        if enabled { "on" } else { "off" },
+        if entropy::has_rdrand() { "rdrand" } else { "tsc" }
+    );
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A random multiple of `align` below `range` (0 when KASLR is off).
+pub fn slide(range: u64, align: u64) -> u64 {
+    if !enabled() || range < align {
+        return 0;
+    }
+    entropy::random_u64() % (range / align) * align
+}
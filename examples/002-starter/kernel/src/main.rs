@@ -1,13 +1,119 @@
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(thread_local)]
 
+extern crate alloc;
+
+mod acpi;
+mod backtrace;
+mod block;
+mod cmdline;
+mod console;
+mod dma;
+mod entropy;
+mod fs;
+mod gdt;
+mod heap;
+mod initrd;
+mod interrupts;
+mod ipc;
+mod kaslr;
+mod memory;
+mod pci;
+mod pic;
+mod power;
+mod preempt;
+mod process;
+mod serial;
+mod shell;
+mod smp;
+mod softirq;
+mod sync;
+mod syscall;
+mod task;
+mod thread;
+mod time;
+mod user;
+mod virtio;
+mod workqueue;
+
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use x86_64::instructions::hlt;
 
-entry_point!(kernel_main);
+/// Ask the bootloader to map all physical memory so we can read ACPI tables etc.
+/// Its own mappings (kernel image, stack, boot info...) go to random addresses in
+/// the upper half, which keeps the lower half free for the regions we manage.
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.aslr = true;
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
+    config
+};
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    serial::init();
+    serial::println("kernel: hello from serial");
+
+    let phys_offset = boot_info.physical_memory_offset.into_option().expect("no physical memory mapping");
+    memory::init(phys_offset);
+    cmdline::init();
+    kaslr::init();
+    serial_println!("kernel: image loaded at {:#x}", boot_info.kernel_image_offset);
+    backtrace::init(boot_info.kernel_image_offset);
+    memory::memtest::run(&boot_info.memory_regions);
+    unsafe {
+        memory::frame_alloc::init(&boot_info.memory_regions);
+        memory::paging::init();
+    }
+    smp::reserve_trampoline();
+    // The heap grows on page faults, so exceptions must work before the first allocation.
+    interrupts::init();
+    heap::init();
+    memory::wipe::init();
+    memory::vmalloc::init();
+    memory::address_space::init();
+    memory::map::init(&boot_info.memory_regions);
+    memory::map::dump();
+    memory::huge::init();
+    dma::init();
+    memory::stack::register_boot_stack();
+    gdt::init();
+    user::init_cpu();
+    syscall::init();
+    let ramdisk = boot_info.ramdisk_addr.into_option().map(|addr| {
+        // Mapped by the bootloader, and never unmapped or written.
+        unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) }
+    });
+    initrd::init(ramdisk);
+    user::programs::install();
+    block::init();
+    fs::devfs::init();
+    fs::procfs::init();
+    let image = memory::wx::KernelImage {
+        addr: boot_info.kernel_addr,
+        len: boot_info.kernel_len,
+        offset: boot_info.kernel_image_offset,
+    };
+    memory::wx::enforce(&image);
+    thread::tls::init(&image);
+    if let Some(stats) = memory::frame_alloc::stats() {
+        serial_println!("memory: {} KiB usable, {} KiB free", stats.usable * 4, stats.free * 4);
+        // Give the buddy allocator a quarter of RAM for contiguous allocations.
+        memory::buddy::init(stats.free / 4);
+    }
+
+    if let Some(rsdp) = boot_info.rsdp_addr.into_option() {
+        acpi::init(rsdp);
+    }
+    memory::numa::init();
+    smp::init();
+
     // If a framebuffer (graphics) is provided (UEFI or BIOS VBE), draw a 200x100 rect.
     if let Some(fb) = boot_info.framebuffer.as_mut() {
         let info = fb.info();
@@ -31,10 +137,31 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         }
     }
 
-    loop { hlt(); }
+    // Nothing before this point expects hardware interrupts; from here on the
+    // timer preempts the shell to run other threads.
+    thread::init();
+    workqueue::init();
+    softirq::init();
+    sync::rcu::init();
+    block::cache::init();
+    time::init();
+    x86_64::instructions::interrupts::enable();
+    // Drivers wait for their devices' interrupts.
+    pci::init();
+    block::probe();
+    fs::fat::probe();
+    fs::tfs::probe();
+    fs::iso9660::probe();
+    fs::tfs::crash_test();
+    process::run_init();
+    shell::run();
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    // The panic may have hit while printing (or be about a stuck serial lock).
+    unsafe { serial::force_unlock() };
+    serial_println!("KERNEL PANIC: {}", info);
+    backtrace::print();
     loop { hlt(); }
-}
\ No newline at end of file
+}
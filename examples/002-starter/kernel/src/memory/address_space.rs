@@ -0,0 +1,444 @@
+//! Per-process address spaces.
+//!
+//! Every address space has its own PML4. The user part (`USER_START..USER_END`,
+//! PML4 slots 192..256) is private; every other slot points at the same lower-level
+//! tables as the kernel's boot PML4, so kernel mappings are shared. For that to hold
+//! for mappings made later, `init` gives each empty kernel slot in the lower half its
+//! page-directory-pointer table up front: after that nothing ever changes a PML4 entry
+//! outside the user range.
+//!
+//! Besides what is mapped up front (a program's segments and stack), an
+//! address space has regions (`VmaTree`) that are only reserved: the heap
+//! `sbrk` grows, and anonymous `mmap`s. Their pages are allocated and zeroed
+//! by the page-fault handler on first touch, and are never swapped. Shared
+//! memory (`map_shared`) is the exception: its frames exist already, so
+//! they are mapped at once. So is the kernel's time page, read-only, in
+//! every address space.
+//!
+//! The address space a thread switched to is part of its state: it is loaded
+//! again whenever the thread is switched back in (`resume`), on whichever CPU.
+//! Other threads run in the kernel's.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frame_alloc::{self, FRAME_ALLOCATOR};
+use super::vma::{Backing, FaultOutcome, Prot, VmaError, VmaTree};
+use super::{paging, phys_offset, phys_to_virt};
+use crate::smp::{self, MAX_CPUS};
+use crate::time::vdso;
+
+pub const USER_START: u64 = 0x_6000_0000_0000;
+/// End of the lower half.
+pub const USER_END: u64 = 0x_8000_0000_0000;
+/// Programs and their heaps lie below this; `mmap` hands out addresses
+/// from here up to `MMAP_END`.
+pub const MMAP_START: u64 = USER_START + 0x_1000_0000_0000;
+/// The last 4 GiB are left to the stack.
+pub const MMAP_END: u64 = USER_END - 0x1_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+const USER_SLOTS: core::ops::Range<usize> = 192..256;
+/// Lower-half slots used by kernel regions (heap, stacks, windows...).
+const KERNEL_LOW_SLOTS: core::ops::Range<usize> = 128..192;
+
+static KERNEL_L4: Once<PhysFrame> = Once::new();
+/// PML4 currently loaded in each CPU's CR3.
+static ACTIVE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// PML4 the running thread last switched to; 0 if it never did.
+#[thread_local]
+static THREAD_L4: Cell<u64> = Cell::new(0);
+
+/// Pin down the kernel's PML4 entries. Call once, before creating address spaces.
+pub fn init() {
+    let (l4, _) = Cr3::read();
+    KERNEL_L4.call_once(|| l4);
+    ACTIVE[smp::current()].store(l4.start_address().as_u64(), Ordering::Relaxed);
+    let mut added = 0;
+    paging::with_level_4(|table| {
+        for i in KERNEL_LOW_SLOTS {
+            if table[i].is_unused() {
+                let Some(frame) = new_table() else { break };
+                table[i].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                added += 1;
+            }
+        }
+    });
+    crate::serial_println!("memory: {} kernel PML4 slots pre-populated for sharing", added);
+}
+
+/// A zeroed frame for a page table.
+fn new_table() -> Option<PhysFrame> {
+    let frame = frame_alloc::allocate_frame()?;
+    unsafe { table_at(frame).zero() };
+    Some(frame)
+}
+
+/// # Safety
+/// `frame` must hold a page table nobody else is accessing right now.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+pub fn is_user(addr: VirtAddr) -> bool {
+    (USER_START..USER_END).contains(&addr.as_u64())
+}
+
+/// A PML4 with private user mappings; user frames and tables are freed on drop.
+pub struct AddressSpace {
+    l4: PhysFrame,
+    regions: VmaTree,
+    /// Where the heap starts, and the current break (its end; not page aligned).
+    heap_start: VirtAddr,
+    brk: VirtAddr,
+}
+
+const HEAP: &str = "heap";
+const MMAP: &str = "mmap";
+const SHARED: &str = "shared";
+
+impl AddressSpace {
+    /// A new address space with the kernel mapped, and of user mappings only
+    /// the time page (see `time::vdso`), once there is one.
+    pub fn new() -> Option<AddressSpace> {
+        let kernel = *KERNEL_L4.get().expect("address_space::init not called");
+        let l4 = new_table()?;
+        let table = unsafe { table_at(l4) };
+        let kernel_table = unsafe { &*phys_to_virt(kernel.start_address()).as_ptr::<PageTable>() };
+        for (i, entry) in kernel_table.iter().enumerate() {
+            if !USER_SLOTS.contains(&i) {
+                table[i] = entry.clone();
+            }
+        }
+        let heap_start = VirtAddr::new(USER_START);
+        let mut space = AddressSpace { l4, regions: VmaTree::new(), heap_start, brk: heap_start };
+        if let Some(frame) = vdso::frame() {
+            frame_alloc::share_frame(frame);
+            let page = Page::containing_address(VirtAddr::new(vdso::TIME_PAGE));
+            if space.map_frame(page, frame, PageTableFlags::NO_EXECUTE).is_err() {
+                unsafe { frame_alloc::deallocate_frame(frame) };
+                return None;
+            }
+        }
+        Some(space)
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(table_at(self.l4), phys_offset()) }
+    }
+
+    /// Map a fresh zeroed frame at `page`, which must be in the user range.
+    /// USER_ACCESSIBLE is added to `flags`.
+    pub fn map_user(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert!(is_user(page.start_address()), "{:?} is not a user address", page);
+        let frame = frame_alloc::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096) };
+        let result = self.map_frame(page, frame, flags);
+        if result.is_err() {
+            unsafe { frame_alloc::deallocate_frame(frame) };
+        }
+        result.map(|()| frame)
+    }
+
+    /// Map `frame` at `page`, handing this address space the caller's
+    /// reference to it. USER_ACCESSIBLE is added to `flags`.
+    fn map_frame(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+        // Flushing only matters if this space is active; `ignore` otherwise.
+        unsafe { mapper.map_to(page, frame, flags, frames) }.map(|flush| {
+            if self.is_active() { flush.flush() } else { flush.ignore() }
+        })
+    }
+
+    /// Unmap a user page and free its frame, if it is mapped.
+    fn unmap_user(&mut self, page: Page) {
+        let active = self.is_active();
+        if let Ok((frame, flush)) = self.mapper().unmap(page) {
+            if active { flush.flush() } else { flush.ignore() }
+            unsafe { frame_alloc::deallocate_frame(frame) };
+        }
+    }
+
+    /// Unmap the pages of `[start, end)`, which must be page aligned.
+    fn unmap_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        if start >= end {
+            return;
+        }
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            self.unmap_user(page);
+        }
+    }
+
+    /// Start the (empty) heap at `start`, which must be page aligned: at the
+    /// end of the program, so before anything is reserved.
+    pub fn set_heap_start(&mut self, start: VirtAddr) {
+        self.heap_start = start;
+        self.brk = start;
+    }
+
+    /// Move the break by `increment` bytes and return the old one. The heap
+    /// region grows (or shrinks) with it, up to `MMAP_START`; pages it gives
+    /// up are freed.
+    pub fn sbrk(&mut self, increment: i64) -> Result<VirtAddr, VmaError> {
+        let old = self.brk;
+        let new = old.as_u64().checked_add_signed(increment).ok_or(VmaError::NoRoom)?;
+        if new < self.heap_start.as_u64() || new > MMAP_START {
+            return Err(VmaError::NoRoom);
+        }
+        let len = VirtAddr::new(new).align_up(PAGE_SIZE) - self.heap_start;
+        let had = self.regions.find(self.heap_start).is_some();
+        match (had, len) {
+            (false, 0) => {}
+            (false, _) => {
+                self.regions.insert(self.heap_start, len, Prot::READ | Prot::WRITE | Prot::USER, Backing::Anonymous, HEAP)?
+            }
+            (true, 0) => {
+                let heap = self.regions.take(self.heap_start)?;
+                self.unmap_range(heap.start, heap.end);
+            }
+            (true, _) => {
+                let old_end = self.regions.resize(self.heap_start, len)?;
+                self.unmap_range(self.heap_start + len, old_end);
+            }
+        }
+        self.brk = VirtAddr::new(new);
+        Ok(old)
+    }
+
+    /// Reserve `len` bytes of zero-filled memory with `prot` between
+    /// `MMAP_START` and `MMAP_END`, and return where.
+    pub fn mmap(&mut self, len: u64, prot: Prot) -> Result<VirtAddr, VmaError> {
+        if len == 0 {
+            return Err(VmaError::Unaligned);
+        }
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let start = self
+            .regions
+            .find_gap(VirtAddr::new(MMAP_START), VirtAddr::new(MMAP_END), len)
+            .ok_or(VmaError::NoRoom)?;
+        self.regions.insert(start, len, prot | Prot::USER, Backing::Anonymous, MMAP)?;
+        Ok(start)
+    }
+
+    /// Map `frames`, which someone else allocated, between `MMAP_START` and
+    /// `MMAP_END` with `prot`, and return where. Each page mapped takes a
+    /// reference to its frame, so the frames stay until every mapping and
+    /// their owner have let go.
+    pub fn map_shared(&mut self, frames: &[PhysFrame], prot: Prot) -> Result<VirtAddr, VmaError> {
+        if frames.is_empty() {
+            return Err(VmaError::Unaligned);
+        }
+        let len = frames.len() as u64 * PAGE_SIZE;
+        let start = self
+            .regions
+            .find_gap(VirtAddr::new(MMAP_START), VirtAddr::new(MMAP_END), len)
+            .ok_or(VmaError::NoRoom)?;
+        self.regions.insert(start, len, prot | Prot::USER, Backing::Anonymous, SHARED)?;
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = Page::containing_address(start + i as u64 * PAGE_SIZE);
+            frame_alloc::share_frame(frame);
+            if self.map_frame(page, frame, prot.page_flags()).is_err() {
+                unsafe { frame_alloc::deallocate_frame(frame) };
+                let vma = self.regions.take(start)?;
+                self.unmap_range(vma.start, vma.end);
+                return Err(VmaError::NoRoom);
+            }
+        }
+        Ok(start)
+    }
+
+    /// Undo an `mmap` or `map_shared`: only whole mappings, `start` and
+    /// `len` as it gave and took them.
+    pub fn munmap(&mut self, start: VirtAddr, len: u64) -> Result<(), VmaError> {
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        match self.regions.find(start) {
+            Some(vma) if (vma.name == MMAP || vma.name == SHARED) && vma.start == start && vma.end - vma.start == len => {}
+            _ => return Err(VmaError::NotFound),
+        }
+        let vma = self.regions.take(start)?;
+        self.unmap_range(vma.start, vma.end);
+        Ok(())
+    }
+
+    /// Resolve a fault at `addr` in a reserved region by mapping a zeroed
+    /// page there.
+    pub fn handle_fault(&mut self, addr: VirtAddr, code: PageFaultErrorCode) -> FaultOutcome {
+        let Some(vma) = self.regions.find(addr) else { return FaultOutcome::Unmapped };
+        if let Err(reason) = vma.check_access(code) {
+            return FaultOutcome::AccessViolation(reason);
+        }
+        let flags = vma.prot.page_flags();
+        if self.map_user(Page::containing_address(addr), flags).is_err() {
+            return FaultOutcome::AccessViolation("out of memory while paging in");
+        }
+        if let Some(vma) = self.regions.find_mut(addr) {
+            vma.faulted_pages += 1;
+        }
+        FaultOutcome::Handled
+    }
+
+    /// Change the flags of a mapped user page; USER_ACCESSIBLE is added.
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let flush = unsafe { self.mapper().update_flags(page, flags) }?;
+        if active { flush.flush() } else { flush.ignore() }
+        Ok(())
+    }
+
+    /// Copy `bytes` to `addr` through the physical mapping, whatever the page
+    /// flags say. False if any of it isn't mapped.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> bool {
+        let mut done = 0;
+        while done < bytes.len() {
+            let at = addr + done as u64;
+            let Some((phys, _)) = self.translate(at) else { return false };
+            let n = (bytes.len() - done).min(4096 - usize::from(at.page_offset()));
+            unsafe {
+                let dst = phys_to_virt(phys).as_mut_ptr::<u8>();
+                dst.copy_from_nonoverlapping(bytes[done..].as_ptr(), n);
+            }
+            done += n;
+        }
+        true
+    }
+
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { frame, offset, flags } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    /// Whether this CPU has it loaded.
+    pub fn is_active(&self) -> bool {
+        ACTIVE[smp::current()].load(Ordering::Relaxed) == self.l4.start_address().as_u64()
+    }
+
+    /// Load this address space into CR3, for the calling thread. Safe because
+    /// the kernel half is identical in all of them, and `Drop` switches away
+    /// before the tables are freed.
+    pub fn switch(&self) {
+        switch_thread(self.l4);
+    }
+}
+
+/// Physical address and flags of `addr` in the address space loaded on this
+/// CPU, which is the calling thread's own.
+pub fn translate_active(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let (l4, _) = Cr3::read();
+    // Safety: only its owner changes a user address space, and that is us.
+    let mapper = unsafe { OffsetPageTable::new(table_at(l4), phys_offset()) };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, offset, flags } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    }
+}
+
+/// Go back to the kernel's own page tables (no user mappings).
+pub fn switch_to_kernel() {
+    switch_thread(*KERNEL_L4.get().expect("address_space::init not called"));
+}
+
+fn switch_thread(l4: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        THREAD_L4.set(l4.start_address().as_u64());
+        load(l4);
+    });
+}
+
+/// After a switch, on the thread switched to (interrupts are off): load the
+/// address space it was using, or the kernel's. So a CPU only has a user
+/// address space loaded while the thread using it runs there, and the owner
+/// can free it once it has switched away.
+pub fn resume() {
+    let l4 = match THREAD_L4.get() {
+        0 => *KERNEL_L4.get().expect("address_space::init not called"),
+        l4 => PhysFrame::containing_address(PhysAddr::new(l4)),
+    };
+    load(l4);
+}
+
+fn load(l4: PhysFrame) {
+    let cpu = smp::current();
+    if ACTIVE[cpu].swap(l4.start_address().as_u64(), Ordering::Relaxed) != l4.start_address().as_u64() {
+        unsafe { Cr3::write(l4, Cr3Flags::empty()) };
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() || THREAD_L4.get() == self.l4.start_address().as_u64() {
+            switch_to_kernel();
+        }
+        let table = unsafe { table_at(self.l4) };
+        for i in USER_SLOTS {
+            if !table[i].is_unused() {
+                unsafe { free_table(table[i].frame().expect("huge PML4 entry"), 3) };
+            }
+        }
+        unsafe { frame_alloc::deallocate_frame(self.l4) };
+    }
+}
+
+/// Free a user page table at `level`, everything it maps, and the table itself.
+///
+/// # Safety
+/// The table must belong to an inactive address space.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    for entry in table_at(frame).iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        // User mappings are only ever made with 4KiB pages.
+        let child = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            frame_alloc::deallocate_frame(child);
+        } else {
+            free_table(child, level - 1);
+        }
+    }
+    frame_alloc::deallocate_frame(frame);
+}
+
+/// Map the same user address to different frames in two spaces and switch between them.
+pub fn self_test() -> bool {
+    let before = frame_alloc::stats().map(|s| s.free);
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let ptr = page.start_address().as_mut_ptr::<u64>();
+    let kernel_data = alloc::boxed::Box::new(0x600D_u64);
+    let ok = {
+        let (Some(mut a), Some(mut b)) = (AddressSpace::new(), AddressSpace::new()) else { return false };
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if a.map_user(page, flags).is_err() || b.map_user(page, flags).is_err() {
+            return false;
+        }
+        a.switch();
+        unsafe { ptr.write_volatile(0xAAAA) };
+        b.switch();
+        let fresh = unsafe { ptr.read_volatile() } == 0;
+        unsafe { ptr.write_volatile(0xBBBB) };
+        a.switch();
+        let isolated = unsafe { ptr.read_volatile() } == 0xAAAA;
+        // Kernel memory is still there in a user address space.
+        let shared = unsafe { core::ptr::read_volatile(&*kernel_data) } == 0x600D;
+        let mapped = a.translate(page.start_address()).is_some_and(|(_, f)| f.contains(PageTableFlags::USER_ACCESSIBLE));
+        switch_to_kernel();
+        let hidden = paging::translate(page.start_address()).is_none();
+        fresh && isolated && shared && mapped && hidden && !a.is_active()
+    };
+    ok && frame_alloc::stats().map(|s| s.free) == before
+}
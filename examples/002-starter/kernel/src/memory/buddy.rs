@@ -0,0 +1,166 @@
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::{frame_alloc, phys_to_virt};
+
+const FRAME_SIZE: u64 = 4096;
+/// Largest block is 2^MAX_ORDER frames (4MiB); order 9 is a 2MiB huge page.
+pub const MAX_ORDER: usize = 10;
+
+/// Buddy-system allocator for physically contiguous, power-of-two sized blocks.
+///
+/// It manages a pool of memory reserved from the bitmap allocator at boot.
+/// Free blocks are kept in one singly-linked list per order; the link is stored
+/// in the first bytes of the free block itself.
+pub struct BuddyAllocator {
+    free_lists: [u64; MAX_ORDER + 1],
+    free_counts: [usize; MAX_ORDER + 1],
+    pool_frames: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyStats {
+    pub pool_frames: usize,
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+impl BuddyStats {
+    pub fn free_frames(&self) -> usize {
+        self.free_blocks.iter().enumerate().map(|(order, n)| n << order).sum()
+    }
+
+    /// Percentage of free memory that cannot serve a request of `order`
+    /// because it sits in smaller blocks (0 = no fragmentation).
+    pub fn fragmentation(&self, order: usize) -> usize {
+        let free = self.free_frames();
+        if free == 0 { return 0; }
+        let usable: usize = self.free_blocks[order..].iter().enumerate().map(|(i, n)| n << (order + i)).sum();
+        100 - usable * 100 / free
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator { free_lists: [0; MAX_ORDER + 1], free_counts: [0; MAX_ORDER + 1], pool_frames: 0 }
+    }
+
+    /// Hand a block of 2^order frames (aligned to its size) to the allocator.
+    ///
+    /// # Safety
+    /// The block must be unused RAM that nobody else will touch.
+    pub unsafe fn add_block(&mut self, addr: PhysAddr, order: usize) {
+        self.pool_frames += 1 << order;
+        self.push(addr.as_u64(), order);
+    }
+
+    pub fn alloc(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER { return None; }
+        // Find the smallest non-empty list that fits, then split down.
+        let from = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != 0)?;
+        let addr = self.pop(from);
+        for o in (order..from).rev() {
+            // Keep the lower half, give the upper half (our buddy) back.
+            self.push(addr + (FRAME_SIZE << o), o);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// # Safety
+    /// `frame` must come from `alloc(order)` with the same order.
+    pub unsafe fn free(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address().as_u64();
+        let mut order = order;
+        // Merge with the buddy as long as it is free too.
+        while order < MAX_ORDER {
+            let buddy = addr ^ (FRAME_SIZE << order);
+            if !self.remove(buddy, order) { break; }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats { pool_frames: self.pool_frames, free_blocks: self.free_counts }
+    }
+
+    fn push(&mut self, addr: u64, order: usize) {
+        unsafe { *link(addr) = self.free_lists[order] };
+        self.free_lists[order] = addr;
+        self.free_counts[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> u64 {
+        let addr = self.free_lists[order];
+        self.free_lists[order] = unsafe { *link(addr) };
+        self.free_counts[order] -= 1;
+        addr
+    }
+
+    /// Unlink `addr` from the free list of `order`, if it is there.
+    fn remove(&mut self, addr: u64, order: usize) -> bool {
+        let mut prev: *mut u64 = &mut self.free_lists[order];
+        unsafe {
+            while *prev != 0 {
+                if *prev == addr {
+                    *prev = *link(addr);
+                    self.free_counts[order] -= 1;
+                    return true;
+                }
+                prev = link(*prev);
+            }
+        }
+        false
+    }
+}
+
+/// The "next" pointer stored inside a free block.
+fn link(addr: u64) -> *mut u64 {
+    phys_to_virt(PhysAddr::new(addr)).as_mut_ptr()
+}
+
+pub static BUDDY: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+
+/// Reserve up to `max_frames` of max-order blocks from the frame allocator for the buddy pool.
+pub fn init(max_frames: usize) {
+    let block = 1 << MAX_ORDER;
+    let mut frames = frame_alloc::FRAME_ALLOCATOR.lock();
+    let Some(frames) = frames.as_mut() else { return };
+    let mut buddy = BUDDY.lock();
+    for _ in 0..max_frames / block {
+        match frames.allocate_contiguous(block, block) {
+            Some(frame) => unsafe { buddy.add_block(frame.start_address(), MAX_ORDER) },
+            None => break,
+        }
+    }
+}
+
+/// Allocate 2^order physically contiguous frames.
+pub fn alloc(order: usize) -> Option<PhysFrame> {
+    BUDDY.lock().alloc(order)
+}
+
+/// # Safety
+/// See [`BuddyAllocator::free`].
+pub unsafe fn free(frame: PhysFrame, order: usize) {
+    super::wipe::on_free_frames(frame, 1 << order);
+    BUDDY.lock().free(frame, order)
+}
+
+pub fn stats() -> BuddyStats {
+    BUDDY.lock().stats()
+}
+
+/// Allocate a mix of orders, free them in a different order and check everything merged back.
+pub fn self_test() -> bool {
+    let before = stats().free_blocks;
+    let mut blocks = [None; 6];
+    for (i, slot) in blocks.iter_mut().enumerate() {
+        *slot = alloc(i % 3).map(|f| (f, i % 3));
+    }
+    for &(frame, order) in blocks.iter().rev().flatten() {
+        unsafe { free(frame, order) };
+    }
+    stats().free_blocks == before
+}
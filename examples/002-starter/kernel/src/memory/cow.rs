@@ -0,0 +1,106 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::mapper::UnmapError;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{frame_alloc, paging, phys_to_virt};
+
+/// Software-defined PTE bit marking a read-only page as "copy on write".
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+static REUSES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub enum CowError {
+    NotMapped,
+    MapFailed,
+}
+
+/// Map `dst` to the same frame as `src` and make both copy-on-write.
+///
+/// Both pages stay readable; the first write to either one gets its own copy.
+/// The frame allocator's reference count tracks how many mappings share the frame.
+pub fn share(src: Page, dst: Page) -> Result<(), CowError> {
+    let (phys, flags) = paging::translate(src.start_address()).ok_or(CowError::NotMapped)?;
+    let frame = PhysFrame::containing_address(phys);
+    let cow_flags = (flags - PageTableFlags::WRITABLE) | COW;
+
+    unsafe {
+        paging::update_flags(src, cow_flags).map_err(|_| CowError::NotMapped)?;
+        paging::map_to(dst, frame, cow_flags).map_err(|_| CowError::MapFailed)?;
+    }
+    frame_alloc::share_frame(frame);
+    Ok(())
+}
+
+/// Unmap a page that may be shared, freeing its frame when the last mapping goes.
+pub fn unmap(page: Page) -> Result<(), UnmapError> {
+    let frame = paging::unmap(page)?;
+    unsafe { frame_alloc::deallocate_frame(frame) };
+    Ok(())
+}
+
+/// Resolve a write fault on a COW page. Returns false if `addr` isn't a COW page.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let Some((phys, flags)) = paging::translate(page.start_address()) else { return false };
+    if !flags.contains(COW) {
+        return false;
+    }
+    let frame = PhysFrame::containing_address(phys);
+    let writable = (flags - COW) | PageTableFlags::WRITABLE;
+
+    if frame_alloc::ref_count(frame) == 1 {
+        // Everybody else already copied: the page is ours alone, just make it writable.
+        REUSES.fetch_add(1, Ordering::Relaxed);
+        return unsafe { paging::update_flags(page, writable).is_ok() };
+    }
+
+    let Some(copy) = frame_alloc::allocate_frame() else { return false };
+    unsafe {
+        let src = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+        let dst = phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+        dst.copy_from_nonoverlapping(src, 4096);
+        if paging::unmap(page).is_err() || paging::map_to(page, copy, writable).is_err() {
+            return false;
+        }
+        // Our reference to the shared frame is gone.
+        frame_alloc::deallocate_frame(frame);
+    }
+    COPIES.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// (pages copied on write, pages made writable without copying)
+pub fn stats() -> (usize, usize) {
+    (COPIES.load(Ordering::Relaxed), REUSES.load(Ordering::Relaxed))
+}
+
+/// Share a page, write through both mappings and check who got a copy.
+pub fn self_test() -> bool {
+    let a = Page::<Size4KiB>::containing_address(VirtAddr::new(0x_4646_0000_0000));
+    let b = a + 1;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let Ok(original) = paging::map_new(a, flags) else { return false };
+    let pa = a.start_address().as_mut_ptr::<u64>();
+    let pb = b.start_address().as_mut_ptr::<u64>();
+
+    unsafe { pa.write_volatile(1) };
+    if share(a, b).is_err() {
+        return false;
+    }
+    let same = paging::translate_addr(b.start_address()) == Some(original.start_address());
+
+    // Writing through `b` copies; `a` keeps the original contents and frame.
+    unsafe { pb.write_volatile(2) };
+    let copied = unsafe { pa.read_volatile() == 1 && pb.read_volatile() == 2 }
+        && paging::translate_addr(b.start_address()) != Some(original.start_address());
+
+    // `a` is the last user of the original frame, so no copy is needed.
+    unsafe { pa.write_volatile(3) };
+    let reused = paging::translate_addr(a.start_address()) == Some(original.start_address());
+
+    let cleaned = unmap(a).is_ok() && unmap(b).is_ok();
+    same && copied && reused && cleaned
+}
@@ -0,0 +1,286 @@
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::{memtest, phys_to_virt};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// One bit per 4KiB physical frame: 1 = used (or not RAM), 0 = free.
+///
+/// Allocated frames also carry a reference count, so a frame can be mapped in
+/// several places (COW, shared memory) and is only freed when the last reference
+/// is dropped.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// References to each frame; 0 for free frames and frames that aren't RAM.
+    refs: &'static mut [u16],
+    /// Number of frames covered by the bitmap (up to the highest usable address).
+    frames: usize,
+    usable: usize,
+    free: usize,
+    /// Usable frames left out because they failed the boot memory test.
+    bad: usize,
+    /// Where to start searching next time; speeds up sequential allocation.
+    next: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub usable: usize,
+    pub free: usize,
+    pub used: usize,
+    pub bad: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build the allocator from the bootloader's memory map.
+    ///
+    /// The bitmap and reference counts are carved out of the first usable region that can hold them
+    /// on frames that passed `memtest`.
+    ///
+    /// # Safety
+    /// The memory map must be accurate and physical memory must be mapped (`memory::init`).
+    pub unsafe fn new(regions: &[MemoryRegion]) -> Self {
+        let max_addr = regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| r.end)
+            .max()
+            .unwrap_or(0);
+        let frames = (max_addr / FRAME_SIZE) as usize;
+        let words = frames.div_ceil(64);
+        let bitmap_bytes = (words * 8) as u64;
+        let total_bytes = bitmap_bytes + (frames * 2) as u64;
+        let count = total_bytes.div_ceil(FRAME_SIZE) as usize;
+
+        // The first run of that many frames in a usable region, none of
+        // them bad.
+        let home = regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .find_map(|r| {
+                let last = (r.end / FRAME_SIZE) as usize;
+                let mut first = ((align_up(r.start) / FRAME_SIZE) as usize).max(1);
+                while first + count <= last {
+                    match (first..first + count).find(|&frame| memtest::is_bad(frame)) {
+                        Some(bad) => first = bad + 1,
+                        None => return Some(first),
+                    }
+                }
+                None
+            })
+            .expect("no usable region large enough for the frame bitmap");
+
+        let ptr = phys_to_virt(PhysAddr::new(home as u64 * FRAME_SIZE)).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(ptr, words);
+        bitmap.fill(u64::MAX);
+        let refs = core::slice::from_raw_parts_mut(ptr.add(words).cast::<u16>(), frames);
+        refs.fill(0);
+
+        let mut alloc = BitmapFrameAllocator { bitmap, refs, frames, usable: 0, free: 0, bad: 0, next: 0 };
+
+        // Mark usable frames free (whole frames only; frame 0 stays reserved),
+        // except those that failed `memtest`.
+        for r in regions.iter().filter(|r| r.kind == MemoryRegionKind::Usable) {
+            let first = (align_up(r.start) / FRAME_SIZE) as usize;
+            let last = (r.end / FRAME_SIZE) as usize;
+            for frame in first.max(1)..last {
+                if memtest::is_bad(frame) {
+                    alloc.bad += 1;
+                    continue;
+                }
+                alloc.clear(frame);
+                alloc.usable += 1;
+                alloc.free += 1;
+            }
+        }
+
+        // And take the frames under the bitmap and counts back out, from
+        // the free count only those that were in it.
+        for frame in home..home + count {
+            if !alloc.is_used(frame) {
+                alloc.free -= 1;
+            }
+            alloc.set(frame);
+            alloc.refs[frame] = 1;
+        }
+        alloc
+    }
+
+    fn index(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(index < self.frames, "frame {:#x} is not RAM", frame.start_address().as_u64());
+        index
+    }
+
+    /// Take another reference to an allocated frame.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let index = self.index(frame);
+        debug_assert!(self.refs[index] > 0, "sharing frame {:#x} that is not allocated", frame.start_address().as_u64());
+        self.refs[index] = self.refs[index].checked_add(1).expect("frame reference count overflow");
+    }
+
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.refs[self.index(frame)] as usize
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats { usable: self.usable, free: self.free, used: self.usable - self.free, bad: self.bad }
+    }
+
+    /// Free frames whose start address lies in `range` (e.g. one NUMA node's memory).
+    pub fn free_in(&self, range: Range<u64>) -> usize {
+        let first = range.start.div_ceil(FRAME_SIZE) as usize;
+        let last = ((range.end / FRAME_SIZE) as usize).min(self.frames);
+        (first..last).filter(|&f| !self.is_used(f)).count()
+    }
+
+    /// Allocate `count` physically contiguous frames whose first frame index is a
+    /// multiple of `align` (in frames).
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        let align = align.max(1);
+        let mut frame = align;
+        while frame + count <= self.frames {
+            match (frame..frame + count).find(|&f| self.is_used(f)) {
+                Some(used) => frame = (used + 1).div_ceil(align) * align,
+                None => {
+                    for f in frame..frame + count {
+                        self.set(f);
+                        self.refs[f] = 1;
+                    }
+                    self.free -= count;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocate a frame that starts below `limit`, for code or devices that
+    /// can only address low memory (e.g. real-mode startup code).
+    pub fn allocate_below(&mut self, limit: u64) -> Option<PhysFrame> {
+        let last = ((limit / FRAME_SIZE) as usize).min(self.frames);
+        let frame = (1..last).find(|&f| !self.is_used(f))?;
+        self.set(frame);
+        self.refs[frame] = 1;
+        self.free -= 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)))
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    /// Find a free frame index starting from the search hint, wrapping around once.
+    fn find_free(&self) -> Option<usize> {
+        let words = self.bitmap.len();
+        let start = self.next / 64;
+        (0..words)
+            .map(|i| (start + i) % words)
+            .find(|&w| self.bitmap[w] != u64::MAX)
+            .map(|w| w * 64 + (!self.bitmap[w]).trailing_zeros() as usize)
+            .filter(|&frame| frame < self.frames)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.find_free()?;
+        self.set(frame);
+        self.refs[frame] = 1;
+        self.free -= 1;
+        self.next = frame + 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)))
+    }
+}
+
+/// Drops one reference; the frame is only freed when that was the last one.
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = self.index(frame);
+        assert!(self.is_used(index), "freeing frame {:#x} that is not allocated", frame.start_address().as_u64());
+        debug_assert!(self.refs[index] > 0, "double free of frame {:#x}", frame.start_address().as_u64());
+        self.refs[index] -= 1;
+        if self.refs[index] > 0 {
+            return;
+        }
+        super::wipe::on_free_frames(frame, 1);
+        self.clear(index);
+        self.free += 1;
+        if index < self.next { self.next = index; }
+    }
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// # Safety
+/// See [`BitmapFrameAllocator::new`]. Must be called once.
+pub unsafe fn init(regions: &[MemoryRegion]) {
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(regions));
+}
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+pub fn allocate_below(limit: u64) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_below(limit)
+}
+
+/// Drop a reference to `frame`, freeing it if it was the last.
+///
+/// # Safety
+/// The frame must have come from this allocator and the caller's reference must no longer be in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    if let Some(alloc) = FRAME_ALLOCATOR.lock().as_mut() {
+        alloc.deallocate_frame(frame);
+    }
+}
+
+/// Take another reference to `frame`; each one needs its own `deallocate_frame`.
+pub fn share_frame(frame: PhysFrame) {
+    FRAME_ALLOCATOR.lock().as_mut().expect("frame allocator not initialized").share(frame);
+}
+
+pub fn ref_count(frame: PhysFrame) -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().map_or(0, |a| a.ref_count(frame))
+}
+
+pub fn free_in(range: Range<u64>) -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().map_or(0, |a| a.free_in(range))
+}
+
+pub fn stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(|a| a.stats())
+}
+
+/// Share a frame twice and check it is only freed by the third release.
+pub fn self_test() -> bool {
+    let before = stats().map(|s| s.free);
+    let Some(frame) = allocate_frame() else { return false };
+    share_frame(frame);
+    share_frame(frame);
+    let mut ok = ref_count(frame) == 3;
+    unsafe {
+        deallocate_frame(frame);
+        deallocate_frame(frame);
+    }
+    ok &= ref_count(frame) == 1 && stats().map(|s| s.free) != before;
+    unsafe { deallocate_frame(frame) };
+    ok && ref_count(frame) == 0 && stats().map(|s| s.free) == before
+}
@@ -0,0 +1,143 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{buddy, map, paging, set_phys_offset};
+
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Our own direct map of physical memory, built from 2MiB pages (slid by KASLR).
+const PHYSMAP_BASE: u64 = 0x_5000_0000_0000;
+const PHYSMAP_SLIDE: u64 = 256 * 1024 * 1024 * 1024;
+/// Always cover the low 4GiB too, so ACPI tables and MMIO below 4GiB are reachable.
+const PHYSMAP_MIN: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Scratch windows for `bench`.
+const BENCH_HUGE: u64 = 0x_5100_0000_0000;
+const BENCH_SMALL: u64 = 0x_5200_0000_0000;
+const BENCH_LEN: u64 = 64 * 1024 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// CPU support for 2MiB (PSE) and 1GiB (PDPE1GB) pages.
+pub fn cpu_support() -> (bool, bool) {
+    let pse = __cpuid(1).edx & (1 << 3) != 0;
+    let gib = __cpuid(0x8000_0001).edx & (1 << 26) != 0;
+    (pse, gib)
+}
+
+/// Whether new kernel mappings (the heap, windows) should use 2MiB pages.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Toggle huge pages for future mappings, e.g. to compare TLB pressure. Ignored without CPU support.
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on && cpu_support().0, Ordering::Relaxed);
+}
+
+/// Detect support and, if available, move the kernel's physical-memory window onto 2MiB pages.
+///
+/// Without PSE we simply keep using the bootloader's window. Must run before
+/// anything records `phys_to_virt` addresses for later (e.g. slab pages).
+pub fn init() {
+    set_enabled(true);
+    if !enabled() {
+        crate::serial_println!("memory: no 2MiB page support, keeping the bootloader's physical map");
+        return;
+    }
+    let top = map::regions().iter().map(|r| r.end.as_u64()).max().unwrap_or(0).max(PHYSMAP_MIN);
+    let len = top.div_ceil(HUGE_PAGE_SIZE) * HUGE_PAGE_SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let base = VirtAddr::new(PHYSMAP_BASE + crate::kaslr::slide(PHYSMAP_SLIDE, HUGE_PAGE_SIZE));
+    match map_window(base, PhysAddr::new(0), len, flags, true) {
+        Ok(()) => {
+            set_phys_offset(base);
+            crate::serial_println!("memory: physical map at {:#x} uses {} 2MiB pages", base.as_u64(), len / HUGE_PAGE_SIZE);
+        }
+        Err(()) => crate::serial_println!("memory: could not build 2MiB physical map, keeping the bootloader's"),
+    }
+}
+
+/// Map `len` bytes of physical memory at `virt`, with 2MiB pages wherever both
+/// sides are 2MiB-aligned (if `huge`), and 4KiB pages for the rest.
+pub fn map_window(virt: VirtAddr, phys: PhysAddr, len: u64, flags: PageTableFlags, huge: bool) -> Result<(), ()> {
+    let mut offset = 0;
+    while offset < len {
+        let (v, p) = (virt + offset, phys + offset);
+        let aligned = v.is_aligned(HUGE_PAGE_SIZE) && p.is_aligned(HUGE_PAGE_SIZE);
+        if huge && aligned && len - offset >= HUGE_PAGE_SIZE {
+            let page = Page::<Size2MiB>::containing_address(v);
+            let frame = PhysFrame::<Size2MiB>::containing_address(p);
+            unsafe { paging::map_to_2mib(page, frame, flags).map_err(|_| ())? };
+            offset += HUGE_PAGE_SIZE;
+        } else {
+            let page = Page::<Size4KiB>::containing_address(v);
+            let frame = PhysFrame::<Size4KiB>::containing_address(p);
+            unsafe { paging::map_to(page, frame, flags).map_err(|_| ())? };
+            offset += 4096;
+        }
+    }
+    Ok(())
+}
+
+/// Undo `map_window` (frames are not freed: the window doesn't own them).
+pub fn unmap_window(virt: VirtAddr, len: u64) {
+    let mut offset = 0;
+    while offset < len {
+        let v = virt + offset;
+        if v.is_aligned(HUGE_PAGE_SIZE) && paging::unmap_2mib(Page::containing_address(v)).is_ok() {
+            offset += HUGE_PAGE_SIZE;
+        } else {
+            let _ = paging::unmap(Page::<Size4KiB>::containing_address(v));
+            offset += 4096;
+        }
+    }
+}
+
+/// Back the 2MiB chunk around `addr` with one huge page from the buddy allocator.
+///
+/// Used by the heap's fault handler; returns false (so the caller falls back to a
+/// 4KiB page) if huge pages are off, `[lo, hi)` doesn't contain the whole chunk,
+/// no 2MiB block is free, or part of the chunk is already mapped with small pages.
+pub fn try_map_chunk(addr: VirtAddr, lo: VirtAddr, hi: VirtAddr, flags: PageTableFlags) -> bool {
+    let base = addr.align_down(HUGE_PAGE_SIZE);
+    if !enabled() || base < lo || base + HUGE_PAGE_SIZE > hi {
+        return false;
+    }
+    let Some(block) = buddy::alloc(9) else { return false };
+    let frame = PhysFrame::<Size2MiB>::containing_address(block.start_address());
+    if unsafe { paging::map_to_2mib(Page::containing_address(base), frame, flags) }.is_err() {
+        unsafe { buddy::free(block, 9) };
+        return false;
+    }
+    true
+}
+
+/// Touch one byte per 4KiB across the same 64MiB of RAM through a 2MiB-page and
+/// a 4KiB-page window, and report the cycles per pass for each.
+pub fn bench() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    let phys = PhysAddr::new(0);
+    let windows = [("2MiB pages", BENCH_HUGE, true), ("4KiB pages", BENCH_SMALL, false)];
+    for (name, base, huge) in windows {
+        let base = VirtAddr::new(base);
+        if map_window(base, phys, BENCH_LEN, flags, huge && cpu_support().0).is_err() {
+            crate::serial_println!("  {}: mapping failed", name);
+            continue;
+        }
+        let mut best = u64::MAX;
+        for _ in 0..4 {
+            let start = unsafe { _rdtsc() };
+            let mut sum = 0u64;
+            for offset in (0..BENCH_LEN).step_by(4096) {
+                sum = sum.wrapping_add(unsafe { (base + offset).as_ptr::<u8>().read_volatile() } as u64);
+            }
+            core::hint::black_box(sum);
+            best = best.min(unsafe { _rdtsc() } - start);
+        }
+        unmap_window(base, BENCH_LEN);
+        crate::serial_println!("  {:<11} {:>12} cycles per pass over {} MiB", name, best, BENCH_LEN >> 20);
+    }
+}
@@ -0,0 +1,137 @@
+use alloc::vec::Vec;
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::fmt;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// What a physical range is used for, unified across the BIOS (E820) and UEFI memory maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Usable,
+    /// Holds the kernel, page tables, boot info... set up by the bootloader.
+    Bootloader,
+    AcpiReclaimable,
+    AcpiNvs,
+    Mmio,
+    Bad,
+    Reserved,
+}
+
+impl RegionKind {
+    fn from_boot(kind: MemoryRegionKind) -> Self {
+        match kind {
+            MemoryRegionKind::Usable => RegionKind::Usable,
+            MemoryRegionKind::Bootloader => RegionKind::Bootloader,
+            // EFI_MEMORY_TYPE values
+            MemoryRegionKind::UnknownUefi(9) => RegionKind::AcpiReclaimable,
+            MemoryRegionKind::UnknownUefi(10) => RegionKind::AcpiNvs,
+            MemoryRegionKind::UnknownUefi(8) => RegionKind::Bad,
+            MemoryRegionKind::UnknownUefi(11 | 12) => RegionKind::Mmio,
+            // E820 types
+            MemoryRegionKind::UnknownBios(3) => RegionKind::AcpiReclaimable,
+            MemoryRegionKind::UnknownBios(4) => RegionKind::AcpiNvs,
+            MemoryRegionKind::UnknownBios(5) => RegionKind::Bad,
+            _ => RegionKind::Reserved,
+        }
+    }
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RegionKind::Usable => "usable",
+            RegionKind::Bootloader => "bootloader",
+            RegionKind::AcpiReclaimable => "ACPI reclaim",
+            RegionKind::AcpiNvs => "ACPI NVS",
+            RegionKind::Mmio => "MMIO",
+            RegionKind::Bad => "bad",
+            RegionKind::Reserved => "reserved",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// NUMA node of the region's first byte (see [`super::numa::node_of`]).
+    pub fn node(&self) -> Option<u32> {
+        super::numa::node_of(self.start)
+    }
+}
+
+static REGIONS: Once<Vec<Region>> = Once::new();
+
+/// Copy the bootloader's memory map into typed, sorted regions (adjacent ranges of
+/// the same kind are merged). Needs the heap.
+pub fn init(boot_regions: &[MemoryRegion]) {
+    REGIONS.call_once(|| {
+        let mut regions: Vec<Region> = boot_regions
+            .iter()
+            .map(|r| Region { start: PhysAddr::new(r.start), end: PhysAddr::new(r.end), kind: RegionKind::from_boot(r.kind) })
+            .collect();
+        regions.sort_unstable_by_key(|r| r.start);
+        regions.dedup_by(|next, prev| {
+            let merge = prev.kind == next.kind && prev.end == next.start;
+            if merge { prev.end = next.end; }
+            merge
+        });
+        regions
+    });
+}
+
+pub fn regions() -> &'static [Region] {
+    REGIONS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+pub fn regions_of(kind: RegionKind) -> impl Iterator<Item = &'static Region> {
+    regions().iter().filter(move |r| r.kind == kind)
+}
+
+pub fn total(kind: RegionKind) -> u64 {
+    regions_of(kind).map(Region::len).sum()
+}
+
+pub fn dump() {
+    crate::serial_println!("  start              end                    size  kind");
+    let numa = super::numa::topology().is_some();
+    for r in regions() {
+        crate::serial_print!("  {:#018x} {:#018x} {:>9}  {}", r.start.as_u64(), r.end.as_u64(), Size(r.len()), r.kind);
+        match r.node() {
+            Some(node) if numa => crate::serial_println!("  (node {})", node),
+            _ => crate::serial_println!(),
+        }
+    }
+    crate::serial_println!(
+        "  usable {}, bootloader {}, ACPI {}",
+        Size(total(RegionKind::Usable)),
+        Size(total(RegionKind::Bootloader)),
+        Size(total(RegionKind::AcpiReclaimable) + total(RegionKind::AcpiNvs))
+    );
+}
+
+/// Human-readable byte count.
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+        let mut value = self.0;
+        let mut unit = 0;
+        while value >= 1024 && value.is_multiple_of(1024) && unit < UNITS.len() - 1 {
+            value /= 1024;
+            unit += 1;
+        }
+        let s = alloc::format!("{} {}", value, UNITS[unit]);
+        f.pad(&s)
+    }
+}
@@ -0,0 +1,135 @@
+//! Boot-time RAM test (`memtest=quick` or `memtest=full` on the command line).
+//!
+//! Runs before the frame allocator exists, over every usable region of the memory
+//! map, through the bootloader's physical-memory window. Frames that fail are
+//! remembered here and never handed to the frame allocator.
+//!
+//! - quick: every frame gets a checkerboard and its inverse.
+//! - full: adds all-zeros and all-ones, then writes each word's own address across
+//!   a whole region before reading any of it back, which also catches address-line
+//!   faults where two addresses hit the same cell.
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = 4096;
+const WORDS: usize = FRAME_SIZE as usize / 8;
+/// Bad frames we can remember without a heap; more than this and the RAM is hopeless anyway.
+const MAX_BAD: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Quick,
+    Full,
+}
+
+struct BadFrames {
+    frames: [u64; MAX_BAD],
+    count: usize,
+    /// Failures beyond MAX_BAD: reported, but those frames stay in use.
+    dropped: usize,
+}
+
+static BAD: Mutex<BadFrames> = Mutex::new(BadFrames { frames: [0; MAX_BAD], count: 0, dropped: 0 });
+
+fn mode() -> Option<Mode> {
+    match crate::cmdline::get("memtest")? {
+        "quick" => Some(Mode::Quick),
+        "full" => Some(Mode::Full),
+        other => {
+            crate::serial_println!("memtest: unknown mode {:?} (quick|full)", other);
+            None
+        }
+    }
+}
+
+/// Test usable RAM if the command line asks for it. Call before `frame_alloc::init`.
+pub fn run(regions: &[MemoryRegion]) {
+    let Some(mode) = mode() else { return };
+    let mut tested = 0;
+    for r in regions.iter().filter(|r| r.kind == MemoryRegionKind::Usable) {
+        // Whole frames only, and never frame 0 (which the allocator never uses).
+        let start = r.start.div_ceil(FRAME_SIZE).max(1);
+        let end = r.end / FRAME_SIZE;
+        if start >= end {
+            continue;
+        }
+        crate::serial_println!("memtest: {:#x}..{:#x}", start * FRAME_SIZE, end * FRAME_SIZE);
+        for frame in start..end {
+            let mut ok = test_pattern(frame, 0x5555_5555_5555_5555) && test_pattern(frame, 0xAAAA_AAAA_AAAA_AAAA);
+            if mode == Mode::Full {
+                ok &= test_pattern(frame, 0) && test_pattern(frame, u64::MAX);
+            }
+            if !ok {
+                mark_bad(frame);
+            }
+        }
+        if mode == Mode::Full {
+            address_test(start, end);
+        }
+        tested += end - start;
+    }
+    let bad = BAD.lock();
+    crate::serial_println!(
+        "memtest: {} MiB tested, {} bad frames{}",
+        tested * FRAME_SIZE / (1024 * 1024),
+        bad.count + bad.dropped,
+        if bad.dropped > 0 { " (too many to exclude them all)" } else { "" }
+    );
+}
+
+fn words(frame: u64) -> *mut u64 {
+    phys_to_virt(PhysAddr::new(frame * FRAME_SIZE)).as_mut_ptr()
+}
+
+/// Fill the frame with `pattern` and read it back.
+fn test_pattern(frame: u64, pattern: u64) -> bool {
+    let p = words(frame);
+    unsafe {
+        for i in 0..WORDS {
+            p.add(i).write_volatile(pattern);
+        }
+        (0..WORDS).all(|i| p.add(i).read_volatile() == pattern)
+    }
+}
+
+/// Write every word's address into it across the whole range, then check them all.
+fn address_test(start: u64, end: u64) {
+    for frame in start..end {
+        let p = words(frame);
+        for i in 0..WORDS {
+            unsafe { p.add(i).write_volatile(frame * FRAME_SIZE + i as u64 * 8) };
+        }
+    }
+    for frame in start..end {
+        let p = words(frame);
+        let ok = (0..WORDS).all(|i| unsafe { p.add(i).read_volatile() } == frame * FRAME_SIZE + i as u64 * 8);
+        if !ok {
+            mark_bad(frame);
+        }
+    }
+}
+
+fn mark_bad(frame: u64) {
+    let mut bad = BAD.lock();
+    if bad.frames[..bad.count].contains(&frame) {
+        return;
+    }
+    crate::serial_println!("memtest: frame {:#x} is bad", frame * FRAME_SIZE);
+    if bad.count < MAX_BAD {
+        let count = bad.count;
+        bad.frames[count] = frame;
+        bad.count += 1;
+    } else {
+        bad.dropped += 1;
+    }
+}
+
+/// Did frame number `frame` fail the test?
+pub fn is_bad(frame: usize) -> bool {
+    let bad = BAD.lock();
+    bad.frames[..bad.count].contains(&(frame as u64))
+}
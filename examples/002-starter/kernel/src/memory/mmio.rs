@@ -0,0 +1,119 @@
+//! Mapping device registers.
+//!
+//! `map_mmio` maps a physical range uncacheable (PCD|PWT, PAT entry 3 = UC) at an
+//! address from `vmalloc`, and the returned `Mmio` hands out `VolatileCell`s, so drivers
+//! never build raw pointers into device memory themselves.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{paging, vmalloc};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A value that is only ever read and written with volatile accesses.
+#[repr(transparent)]
+pub struct VolatileCell<T> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> VolatileCell<T> {
+    pub fn get(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+
+    pub fn set(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+}
+
+/// A mapped register window; unmapped on drop.
+pub struct Mmio {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    /// Registers must not move to another CPU's view while borrowed; keep it !Send.
+    _not_send: PhantomData<*mut u8>,
+}
+
+impl Mmio {
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The register of type `T` at byte `offset`. Panics if it is out of range or misaligned.
+    pub fn register<T: Copy>(&self, offset: usize) -> &VolatileCell<T> {
+        assert!(offset + core::mem::size_of::<T>() <= self.len, "mmio: offset {:#x} out of range", offset);
+        let addr = self.base + offset as u64;
+        assert!(addr.is_aligned(core::mem::align_of::<T>() as u64), "mmio: misaligned register at {:#x}", offset);
+        unsafe { &*addr.as_ptr::<VolatileCell<T>>() }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::<Size4KiB>::containing_address(self.base);
+        let end = Page::<Size4KiB>::containing_address(self.base + (self.len as u64).max(1) - 1);
+        Page::range_inclusive(start, end)
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        for page in self.pages() {
+            // The frames are device memory, not RAM: nothing to free.
+            paging::unmap(page).expect("mmio: page not mapped");
+        }
+        vmalloc::free_range(self.base.align_down(PAGE_SIZE));
+    }
+}
+
+/// Map `len` bytes of device registers at `phys` uncacheable.
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<Mmio, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first.start_address();
+    let pages = (offset + len as u64).max(1).div_ceil(PAGE_SIZE);
+    let virt = vmalloc::alloc_range(pages * PAGE_SIZE, "mmio").ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for i in 0..pages {
+        let page = Page::containing_address(virt + i * PAGE_SIZE);
+        if let Err(e) = unsafe { paging::map_to(page, first + i, flags) } {
+            for j in 0..i {
+                let _ = paging::unmap(Page::<Size4KiB>::containing_address(virt + j * PAGE_SIZE));
+            }
+            vmalloc::free_range(virt);
+            return Err(e);
+        }
+    }
+    Ok(Mmio { base: virt + offset, phys, len, _not_send: PhantomData })
+}
+
+/// Map part of the VGA text buffer, write a cell through it and unmap it again.
+pub fn self_test() -> bool {
+    let Ok(vga) = map_mmio(PhysAddr::new(0xb8000 + 0x10), 0x20) else { return false };
+    let cell = vga.register::<u16>(0x8);
+    let old = cell.get();
+    cell.set(0x0f41);
+    let ok = cell.get() == 0x0f41;
+    cell.set(old);
+    let base = vga.register::<u8>(0) as *const VolatileCell<u8> as u64;
+    let uncached = paging::translate(VirtAddr::new(base)).is_some_and(|(phys, flags)| {
+        phys.as_u64() == 0xb8010 && flags.contains(PageTableFlags::NO_CACHE)
+    });
+    let len_ok = vga.len() == 0x20 && !vga.is_empty() && vga.phys().as_u64() == 0xb8010;
+    drop(vga);
+    ok && uncached && len_ok && paging::translate(VirtAddr::new(base)).is_none()
+}
@@ -0,0 +1,46 @@
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
+pub mod frame_alloc;
+pub mod huge;
+pub mod map;
+pub mod memtest;
+pub mod mmio;
+pub mod numa;
+pub mod paging;
+pub mod slab;
+pub mod stack;
+pub mod swap;
+pub mod vma;
+pub mod vmalloc;
+pub mod wipe;
+pub mod wx;
+
+pub use mmio::map_mmio;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where all of physical memory is mapped: first the bootloader's window
+/// (see `BOOTLOADER_CONFIG`), later our own huge-page window (see `huge`).
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(physical_memory_offset: u64) {
+    PHYS_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+}
+
+pub fn phys_offset() -> VirtAddr {
+    let offset = PHYS_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init not called");
+    VirtAddr::new(offset)
+}
+
+/// Switch to another complete mapping of physical memory.
+fn set_phys_offset(offset: VirtAddr) {
+    PHYS_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
+}
+
+/// Translate a physical address into the kernel's view of it.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    phys_offset() + phys.as_u64()
+}
@@ -0,0 +1,179 @@
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use super::frame_alloc;
+use super::map::{self, RegionKind, Size};
+use crate::acpi::{self, SdtHeader};
+
+/// SRAT entries start after the header plus 12 reserved bytes.
+const SRAT_ENTRIES: u64 = 48;
+
+const LOCAL_APIC_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const X2APIC_AFFINITY: u8 = 2;
+
+const ENABLED: u32 = 1 << 0;
+const HOT_PLUGGABLE: u32 = 1 << 1;
+const NON_VOLATILE: u32 = 1 << 2;
+
+/// A physical range the firmware says belongs to one proximity domain (node).
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub node: u32,
+    pub hot_pluggable: bool,
+    pub non_volatile: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuAffinity {
+    pub apic_id: u32,
+    pub node: u32,
+}
+
+/// Node affinity from the ACPI System Resource Affinity Table.
+///
+/// Without an SRAT (e.g. QEMU without `-numa`) everything is node 0.
+pub struct Topology {
+    pub memory: Vec<MemoryAffinity>,
+    pub cpus: Vec<CpuAffinity>,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Parse the SRAT, if the firmware has one. Needs `acpi::init` and the heap.
+pub fn init() {
+    let Some(srat) = acpi::find_table(b"SRAT") else {
+        crate::serial_println!("numa: no SRAT, single node");
+        return;
+    };
+    let topology = TOPOLOGY.call_once(|| parse(srat));
+    crate::serial_println!(
+        "numa: {} node(s), {} memory range(s), {} CPU(s)",
+        nodes().len(),
+        topology.memory.len(),
+        topology.cpus.len()
+    );
+}
+
+fn parse(srat: PhysAddr) -> Topology {
+    let header: SdtHeader = unsafe { acpi::read_phys(srat) };
+    let end = srat + header.length as u64;
+    let mut topology = Topology { memory: Vec::new(), cpus: Vec::new() };
+
+    let mut entry = srat + SRAT_ENTRIES;
+    while entry + 2u64 <= end {
+        let (kind, len): (u8, u8) = unsafe { (acpi::read_phys(entry), acpi::read_phys(entry + 1u64)) };
+        if len < 2 || entry + len as u64 > end { break; }
+        let field = |offset: u64| entry + offset;
+        unsafe {
+            match kind {
+                LOCAL_APIC_AFFINITY if len >= 16 => {
+                    let flags: u32 = acpi::read_phys(field(4));
+                    if flags & ENABLED != 0 {
+                        // The domain is split: low byte at 2, high three bytes at 9.
+                        let lo = acpi::read_phys::<u8>(field(2)) as u32;
+                        let hi: [u8; 3] = acpi::read_phys(field(9));
+                        let node = lo | u32::from_le_bytes([0, hi[0], hi[1], hi[2]]);
+                        let apic_id = acpi::read_phys::<u8>(field(3)) as u32;
+                        topology.cpus.push(CpuAffinity { apic_id, node });
+                    }
+                }
+                MEMORY_AFFINITY if len >= 40 => {
+                    let flags: u32 = acpi::read_phys(field(28));
+                    let base: u64 = acpi::read_phys(field(8));
+                    let size: u64 = acpi::read_phys(field(16));
+                    if flags & ENABLED != 0 && size > 0 {
+                        topology.memory.push(MemoryAffinity {
+                            start: PhysAddr::new(base),
+                            end: PhysAddr::new(base + size),
+                            node: acpi::read_phys(field(2)),
+                            hot_pluggable: flags & HOT_PLUGGABLE != 0,
+                            non_volatile: flags & NON_VOLATILE != 0,
+                        });
+                    }
+                }
+                X2APIC_AFFINITY if len >= 24 => {
+                    let flags: u32 = acpi::read_phys(field(12));
+                    if flags & ENABLED != 0 {
+                        topology.cpus.push(CpuAffinity { apic_id: acpi::read_phys(field(8)), node: acpi::read_phys(field(4)) });
+                    }
+                }
+                _ => {}
+            }
+        }
+        entry += len as u64;
+    }
+    topology.memory.sort_unstable_by_key(|m| m.start);
+    topology
+}
+
+pub fn topology() -> Option<&'static Topology> {
+    TOPOLOGY.get()
+}
+
+/// The node owning `addr`; node 0 when there is no SRAT, `None` if the SRAT doesn't cover it.
+pub fn node_of(addr: PhysAddr) -> Option<u32> {
+    let Some(topology) = topology() else { return Some(0) };
+    topology.memory.iter().find(|m| m.start <= addr && addr < m.end).map(|m| m.node)
+}
+
+/// Distinct node ids, in ascending order.
+pub fn nodes() -> Vec<u32> {
+    let Some(topology) = topology() else { return alloc::vec![0] };
+    let mut nodes: Vec<u32> = topology.memory.iter().map(|m| m.node).chain(topology.cpus.iter().map(|c| c.node)).collect();
+    nodes.sort_unstable();
+    nodes.dedup();
+    nodes
+}
+
+/// Physical ranges of node `node`; the whole address space when there is no SRAT.
+fn ranges_of(node: u32) -> Vec<core::ops::Range<u64>> {
+    match topology() {
+        Some(t) => t.memory.iter().filter(|m| m.node == node).map(|m| m.start.as_u64()..m.end.as_u64()).collect(),
+        None => alloc::vec![0..u64::MAX],
+    }
+}
+
+/// Usable RAM from the memory map that falls inside `range`.
+fn usable_in(range: &core::ops::Range<u64>) -> u64 {
+    map::regions_of(RegionKind::Usable)
+        .map(|r| r.end.as_u64().min(range.end).saturating_sub(r.start.as_u64().max(range.start)))
+        .sum()
+}
+
+pub fn dump() {
+    if let Some(topology) = topology() {
+        crate::serial_println!("  start              end                node  flags");
+        for m in &topology.memory {
+            crate::serial_println!(
+                "  {:#018x} {:#018x} {:>4}  {}{}",
+                m.start.as_u64(),
+                m.end.as_u64(),
+                m.node,
+                if m.hot_pluggable { "hotplug " } else { "" },
+                if m.non_volatile { "nvram" } else { "" }
+            );
+        }
+        for c in &topology.cpus {
+            crate::serial_println!("  cpu apic {:>3} -> node {}", c.apic_id, c.node);
+        }
+    } else {
+        crate::serial_println!("  no SRAT: one node covering all memory and CPUs");
+    }
+    for node in nodes() {
+        let ranges = ranges_of(node);
+        let usable: u64 = ranges.iter().map(usable_in).sum();
+        let free: usize = ranges.into_iter().map(frame_alloc::free_in).sum();
+        crate::serial_println!("  node {}: {} usable, {} free", node, Size(usable), Size(free as u64 * 4096));
+    }
+    let uncovered: u64 = map::regions_of(RegionKind::Usable)
+        .filter(|r| r.node().is_none())
+        .map(|r| r.len())
+        .sum();
+    if uncovered > 0 {
+        crate::serial_println!("  {} usable RAM not covered by the SRAT", Size(uncovered));
+    }
+}
@@ -0,0 +1,259 @@
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frame_alloc::FRAME_ALLOCATOR;
+use super::phys_offset;
+
+/// The active page table, accessed through the bootloader's physical-memory mapping.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// # Safety
+/// Physical memory must be mapped at `memory::phys_offset()`. Call once.
+pub unsafe fn init() {
+    let offset = phys_offset();
+    let (l4_frame, _) = Cr3::read();
+    let l4: &'static mut PageTable = &mut *(offset + l4_frame.start_address().as_u64()).as_mut_ptr();
+    *MAPPER.lock() = Some(OffsetPageTable::new(l4, offset));
+}
+
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    f(MAPPER.lock().as_mut().expect("paging not initialized"))
+}
+
+/// Run `f` on the kernel's PML4 (the one the bootloader set up).
+pub fn with_level_4<R>(f: impl FnOnce(&mut PageTable) -> R) -> R {
+    with_mapper(|mapper| f(mapper.level_4_table_mut()))
+}
+
+/// Map `page` to `frame`; missing page tables are taken from the frame allocator.
+///
+/// # Safety
+/// Mapping the same frame twice (or over memory in use) breaks memory safety.
+pub unsafe fn map_to(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+        mapper.map_to(page, frame, flags, frames)?.flush();
+        Ok(())
+    })
+}
+
+/// Map a 2MiB huge page. The HUGE_PAGE flag is added by the mapper.
+///
+/// # Safety
+/// Same as [`map_to`].
+pub unsafe fn map_to_2mib(page: Page<Size2MiB>, frame: PhysFrame<Size2MiB>, flags: PageTableFlags) -> Result<(), MapToError<Size2MiB>> {
+    with_mapper(|mapper| {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+        mapper.map_to(page, frame, flags, frames)?.flush();
+        Ok(())
+    })
+}
+
+pub fn unmap_2mib(page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, UnmapError> {
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Map `page` to a freshly allocated frame and return that frame.
+pub fn map_new(page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = super::frame_alloc::allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe { map_to(page, frame, flags)? };
+    Ok(frame)
+}
+
+/// Remove the mapping of `page` and return the frame it pointed to (the frame is not freed).
+pub fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Replace the flags of an existing mapping.
+///
+/// # Safety
+/// Removing permissions from memory still in use will fault; adding them may expose memory.
+pub unsafe fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    with_mapper(|mapper| {
+        mapper.update_flags(page, flags)?.flush();
+        Ok(())
+    })
+}
+
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Whether `addr` is mapped, without waiting for the page table lock: false
+/// if it is held. For the panic path.
+pub fn try_is_mapped(addr: VirtAddr) -> bool {
+    MAPPER.try_lock().is_some_and(|mapper| mapper.as_ref().is_some_and(|m| m.translate_addr(addr).is_some()))
+}
+
+/// Physical address and flags of the mapping that covers `addr`.
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, offset, flags } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    })
+}
+
+/// Visit every present leaf mapping of the active page table, lowest address first.
+///
+/// `f` gets the start address, the size of the mapping (4KiB, 2MiB or 1GiB) and its
+/// effective flags: WRITABLE and USER_ACCESSIBLE only if set at every level, NO_EXECUTE
+/// if set at any level. It may edit the leaf entry; flush the TLB afterwards.
+/// The page table lock is held throughout, so `f` must not map, unmap or allocate.
+pub fn walk(mut f: impl FnMut(VirtAddr, u64, PageTableFlags, &mut PageTableEntry)) {
+    with_mapper(|mapper| {
+        let offset = mapper.phys_offset();
+        let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        walk_table(mapper.level_4_table_mut(), 4, 0, inherited, offset, &mut f);
+    })
+}
+
+fn walk_table(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    inherited: PageTableFlags,
+    offset: VirtAddr,
+    f: &mut dyn FnMut(VirtAddr, u64, PageTableFlags, &mut PageTableEntry),
+) {
+    use PageTableFlags as Flags;
+    let size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (i, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(Flags::PRESENT) {
+            continue;
+        }
+        // new_truncate sign-extends bit 47 for the upper half.
+        let start = VirtAddr::new_truncate(base + i as u64 * size);
+        let sticky = Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let effective = (inherited & flags & sticky) | (inherited & Flags::NO_EXECUTE) | (flags & Flags::NO_EXECUTE);
+        if level == 1 || flags.contains(Flags::HUGE_PAGE) {
+            f(start, size, (flags - sticky) | effective, entry);
+        } else {
+            let child: &mut PageTable = unsafe { &mut *(offset + entry.addr().as_u64()).as_mut_ptr() };
+            walk_table(child, level - 1, start.as_u64(), effective, offset, f);
+        }
+    }
+}
+
+/// Flags that distinguish ranges in [`dump`]; accessed/dirty bits are ignored.
+const DUMP_FLAGS: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::GLOBAL)
+    .union(super::cow::COW);
+
+/// A run of virtually contiguous leaves with the same flags.
+struct Run {
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+}
+
+/// Print the active page table as a tree: each used PML4 slot, then the mapped
+/// ranges under it with their size and effective flags.
+pub fn dump() {
+    use crate::serial_println;
+    let mut run: Option<Run> = None;
+    let mut slot = usize::MAX;
+    let mut counts = [0usize; 3];
+    walk(|start, size, flags, _| {
+        let start = start.as_u64();
+        let flags = flags & DUMP_FLAGS;
+        counts[(size.trailing_zeros() as usize - 12) / 9] += 1;
+        let index = (start >> 39) as usize & 511;
+        if index != slot {
+            if let Some(r) = run.take() {
+                print_run(&r);
+            }
+            slot = index;
+            serial_println!("pml4[{}] {:#x}", index, start & !((1 << 39) - 1));
+        }
+        match &mut run {
+            Some(r) if r.end == start && r.flags == flags => r.end += size,
+            _ => {
+                if let Some(r) = run.replace(Run { start, end: start + size, flags }) {
+                    print_run(&r);
+                }
+            }
+        }
+    });
+    if let Some(r) = run {
+        print_run(&r);
+    }
+    serial_println!("{} 4KiB, {} 2MiB, {} 1GiB pages", counts[0], counts[1], counts[2]);
+}
+
+/// Print one run. Called with the page table lock held, so it must not touch the heap
+/// (hence no `map::Size`).
+fn print_run(run: &Run) {
+    use crate::{serial_print, serial_println};
+    let (value, unit) = human_size(run.end - run.start);
+    serial_print!("  {:#014x}..{:#014x} {:>4} {:<3}", run.start, run.end, value, unit);
+    let f = run.flags;
+    serial_print!(" {}", if f.contains(PageTableFlags::WRITABLE) { "RW" } else { "RO" });
+    serial_print!(" {}", if f.contains(PageTableFlags::NO_EXECUTE) { "NX" } else { "X" });
+    let extra = [
+        (PageTableFlags::USER_ACCESSIBLE, "USER"),
+        (PageTableFlags::GLOBAL, "G"),
+        (PageTableFlags::NO_CACHE, "UC"),
+        (PageTableFlags::WRITE_THROUGH, "WT"),
+        (super::cow::COW, "COW"),
+    ];
+    for (flag, name) in extra {
+        if f.contains(flag) {
+            serial_print!(" {}", name);
+        }
+    }
+    serial_println!();
+}
+
+/// (value, unit) in the largest unit that divides `bytes` evenly.
+fn human_size(bytes: u64) -> (u64, &'static str) {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let (mut value, mut unit) = (bytes, 0);
+    while value >= 1024 && value.is_multiple_of(1024) && unit < UNITS.len() - 1 {
+        value /= 1024;
+        unit += 1;
+    }
+    (value, UNITS[unit])
+}
+
+/// Map a scratch page, use it, make it read-only, then unmap it again.
+pub fn self_test() -> bool {
+    use PageTableFlags as Flags;
+    // An arbitrary unused address in the lower half.
+    let page: Page = Page::containing_address(VirtAddr::new(0x4444_0000_0000));
+    let Ok(frame) = map_new(page, Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE) else {
+        return false;
+    };
+    let ptr = page.start_address().as_mut_ptr::<u64>();
+    unsafe { ptr.write_volatile(0xC0FFEE) };
+    let ok = unsafe { ptr.read_volatile() } == 0xC0FFEE
+        && translate_addr(page.start_address()) == Some(frame.start_address());
+
+    unsafe { update_flags(page, Flags::PRESENT | Flags::NO_EXECUTE).expect("update_flags") };
+    let read_only = translate(page.start_address()).is_some_and(|(_, f)| !f.contains(Flags::WRITABLE));
+
+    let frame = unmap(page).expect("unmap");
+    unsafe { super::frame_alloc::deallocate_frame(frame) };
+    ok && read_only && translate_addr(page.start_address()).is_none()
+}
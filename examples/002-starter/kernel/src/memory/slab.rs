@@ -0,0 +1,243 @@
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::{frame_alloc, phys_offset, phys_to_virt};
+
+const SLAB_SIZE: usize = 4096;
+
+/// Lives at the start of every slab page; objects follow it.
+struct SlabHeader {
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SlabStats {
+    pub objects_in_use: usize,
+    pub slabs: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+struct Inner {
+    /// Slabs with at least one free object.
+    partial: *mut SlabHeader,
+    /// Slabs with no free objects.
+    full: *mut SlabHeader,
+    registered: bool,
+    stats: SlabStats,
+}
+
+// The raw pointers only refer to slab pages owned by this cache.
+unsafe impl Send for Inner {}
+
+/// A cache of equally sized objects carved out of whole 4KiB frames.
+///
+/// Hot kernel objects get their own cache so they don't fragment the general heap,
+/// and a freed object's memory is reused by the next object of the same kind.
+pub struct SlabCache {
+    name: &'static str,
+    obj_size: usize,
+    obj_align: usize,
+    inner: Mutex<Inner>,
+}
+
+static CACHES: Mutex<Vec<&'static SlabCache>> = Mutex::new(Vec::new());
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        // Every free slot must be able to hold the free-list link.
+        let obj_align = if align > align_of::<FreeObject>() { align } else { align_of::<FreeObject>() };
+        let size = if size > size_of::<FreeObject>() { size } else { size_of::<FreeObject>() };
+        SlabCache {
+            name,
+            obj_size: size.div_ceil(obj_align) * obj_align,
+            obj_align,
+            inner: Mutex::new(Inner { partial: ptr::null_mut(), full: ptr::null_mut(), registered: false, stats: SlabStats { objects_in_use: 0, slabs: 0, allocs: 0, frees: 0 } }),
+        }
+    }
+
+    pub const fn for_type<T>(name: &'static str) -> Self {
+        Self::new(name, size_of::<T>(), align_of::<T>())
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.obj_size
+    }
+
+    fn first_object_offset(&self) -> usize {
+        size_of::<SlabHeader>().div_ceil(self.obj_align) * self.obj_align
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        (SLAB_SIZE - self.first_object_offset()) / self.obj_size
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.inner.lock().stats
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let mut inner = self.inner.lock();
+        if !inner.registered {
+            inner.registered = true;
+            CACHES.lock().push(self);
+        }
+        if inner.partial.is_null() {
+            inner.partial = self.grow()?;
+            inner.stats.slabs += 1;
+        }
+        unsafe {
+            let slab = &mut *inner.partial;
+            let obj = slab.free;
+            slab.free = (*obj).next;
+            slab.in_use += 1;
+            if slab.free.is_null() {
+                // Slab is now full: move it to the full list.
+                inner.partial = slab.next;
+                slab.next = inner.full;
+                inner.full = slab;
+            }
+            inner.stats.objects_in_use += 1;
+            inner.stats.allocs += 1;
+            NonNull::new(obj as *mut u8)
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must come from `alloc` on this cache and not be used afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let mut inner = self.inner.lock();
+        let slab = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let was_full = (*slab).free.is_null();
+
+        let obj = ptr.as_ptr() as *mut FreeObject;
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).in_use -= 1;
+        inner.stats.objects_in_use -= 1;
+        inner.stats.frees += 1;
+
+        if was_full {
+            unlink(&mut inner.full, slab);
+            (*slab).next = inner.partial;
+            inner.partial = slab;
+        }
+        if (*slab).in_use == 0 {
+            // Give completely empty slabs back to the frame allocator.
+            unlink(&mut inner.partial, slab);
+            inner.stats.slabs -= 1;
+            let phys = PhysAddr::new(slab as u64 - phys_offset().as_u64());
+            frame_alloc::deallocate_frame(PhysFrame::containing_address(phys));
+        }
+    }
+
+    /// Take a fresh frame and thread all of its objects onto a free list.
+    fn grow(&self) -> Option<*mut SlabHeader> {
+        let frame = frame_alloc::allocate_frame()?;
+        let base = phys_to_virt(frame.start_address());
+        let first = self.first_object_offset();
+        let count = self.objects_per_slab();
+
+        let mut free: *mut FreeObject = ptr::null_mut();
+        for i in (0..count).rev() {
+            let obj = (base + (first + i * self.obj_size) as u64).as_mut_ptr::<FreeObject>();
+            unsafe { obj.write(FreeObject { next: free }) };
+            free = obj;
+        }
+        let header = base.as_mut_ptr::<SlabHeader>();
+        unsafe { header.write(SlabHeader { next: ptr::null_mut(), free, in_use: 0 }) };
+        Some(header)
+    }
+}
+
+unsafe fn unlink(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    let mut cur: *mut *mut SlabHeader = list;
+    while !(*cur).is_null() {
+        if *cur == slab {
+            *cur = (*slab).next;
+            return;
+        }
+        cur = &mut (**cur).next;
+    }
+}
+
+/// Owning pointer to a `T` stored in a slab cache; freed back to the cache on drop.
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SlabBox<T> {
+    pub fn new_in(value: T, cache: &'static SlabCache) -> Option<Self> {
+        assert!(cache.object_size() >= size_of::<T>() && cache.obj_align >= align_of::<T>());
+        let ptr = cache.alloc()?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache, _marker: PhantomData })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr.cast());
+        }
+    }
+}
+
+/// Call `f` for every cache that has been used so far.
+pub fn for_each_cache(mut f: impl FnMut(&SlabCache)) {
+    for cache in CACHES.lock().iter() {
+        f(cache);
+    }
+}
+
+/// Fill more than one slab, free everything and check the pages went back.
+pub fn self_test() -> bool {
+    struct Sample([u64; 12]);
+    static SAMPLE_CACHE: SlabCache = SlabCache::for_type::<Sample>("slab-test");
+
+    let count = SAMPLE_CACHE.objects_per_slab() * 2 + 1;
+    let mut objects = Vec::new();
+    for i in 0..count {
+        match SlabBox::new_in(Sample([i as u64; 12]), &SAMPLE_CACHE) {
+            Some(obj) => objects.push(obj),
+            None => return false,
+        }
+    }
+    let grew = SAMPLE_CACHE.stats().slabs == 3;
+    let intact = objects.iter().enumerate().all(|(i, o)| o.0[11] == i as u64);
+    drop(objects);
+    let stats = SAMPLE_CACHE.stats();
+    grew && intact && stats.objects_in_use == 0 && stats.slabs == 0
+}
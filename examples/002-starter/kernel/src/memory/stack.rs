@@ -0,0 +1,85 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{frame_alloc, paging, vmalloc};
+
+/// Kernel stacks get their addresses from `vmalloc`, each above an unmapped guard page:
+///
+/// ```text
+///   | guard (unmapped) | stack pages ... | guard | stack pages ... |
+///   ^ low addresses                                  stacks grow down
+/// ```
+///
+/// Running off the bottom of a stack touches the guard page of that stack and
+/// page-faults instead of silently corrupting whatever lies below.
+const PAGE_SIZE: u64 = 4096;
+
+struct Guard {
+    page: Page,
+    name: &'static str,
+}
+
+static GUARDS: Mutex<Vec<Guard>> = Mutex::new(Vec::new());
+
+pub struct KernelStack {
+    /// Initial stack pointer (one past the highest usable byte).
+    pub top: VirtAddr,
+    guard: Page,
+    pages: u64,
+}
+
+/// Map `pages` stack pages with an unmapped guard page beneath them.
+pub fn alloc(name: &'static str, pages: u64) -> Option<KernelStack> {
+    let guard_addr = vmalloc::alloc_range((pages + 1) * PAGE_SIZE, name)?;
+    let guard = Page::<Size4KiB>::containing_address(guard_addr);
+    let first = guard + 1;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range(first, first + pages) {
+        paging::map_new(page, flags).ok()?;
+    }
+    register_guard(guard, name);
+    Some(KernelStack { top: (first + pages).start_address(), guard, pages })
+}
+
+/// Unmap a stack from `alloc` and give back its frames and address range.
+///
+/// # Safety
+/// Nothing may still be running on the stack or hold pointers into it.
+pub unsafe fn free(stack: KernelStack) {
+    let first = stack.guard + 1;
+    for page in Page::range(first, first + stack.pages) {
+        if let Ok(frame) = paging::unmap(page) {
+            frame_alloc::deallocate_frame(frame);
+        }
+    }
+    GUARDS.lock().retain(|g| g.page != stack.guard);
+    vmalloc::free_range(stack.guard.start_address());
+}
+
+/// Remember that `page` guards the stack called `name`.
+pub fn register_guard(page: Page, name: &'static str) {
+    GUARDS.lock().push(Guard { page, name });
+}
+
+/// Name of the stack whose guard page contains `addr`, if any.
+///
+/// Called from fault handlers, so it never blocks on the registry lock.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let guards = GUARDS.try_lock()?;
+    guards.iter().find(|g| g.page == page).map(|g| g.name)
+}
+
+/// The bootloader maps our initial stack above an unmapped page; find and register it.
+pub fn register_boot_stack() {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let rsp = VirtAddr::new(rsp);
+    let mut page = Page::<Size4KiB>::containing_address(rsp);
+    while paging::translate_addr(page.start_address()).is_some() {
+        page -= 1;
+    }
+    register_guard(page, "boot");
+}
@@ -0,0 +1,340 @@
+//! Swap prototype: evict anonymous pages to a swap device and fault them back in.
+//!
+//! Only pages of anonymous VMAs that are faulted in while swap is on are candidates.
+//! Eviction is a clock: a page whose ACCESSED bit is set gets a second chance
+//! (the bit is cleared), otherwise it is written to a slot and unmapped. A page that
+//! was swapped back in keeps its slot, so evicting it again without having written
+//! to it (PTE DIRTY bit clear) costs no I/O.
+//!
+//! Two devices: `RamSwap`, a buddy block standing in for a disk, and `SwapFile`,
+//! a file of any filesystem that writes. Anything implementing `SwapDevice` can
+//! replace them.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::{buddy, frame_alloc, paging, phys_to_virt};
+use crate::fs::file::KFile;
+use crate::fs::FsError;
+
+pub const PAGE_SIZE: usize = 4096;
+/// Below this many free frames, demand paging first tries to evict some pages.
+pub const LOW_WATERMARK: usize = 64;
+/// How many pages to evict per reclaim.
+pub const RECLAIM_BATCH: usize = 16;
+
+/// Backing store for swapped-out pages, addressed in page-sized slots.
+pub trait SwapDevice: Send {
+    fn name(&self) -> &'static str;
+    fn slots(&self) -> usize;
+    fn read(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]);
+    fn write(&mut self, slot: usize, page: &[u8; PAGE_SIZE]);
+}
+
+/// A swap "disk" made of contiguous RAM from the buddy allocator.
+pub struct RamSwap {
+    frame: PhysFrame,
+    order: usize,
+}
+
+impl RamSwap {
+    pub fn new(order: usize) -> Option<RamSwap> {
+        Some(RamSwap { frame: buddy::alloc(order)?, order })
+    }
+
+    fn slot(&mut self, slot: usize) -> &mut [u8; PAGE_SIZE] {
+        assert!(slot < self.slots());
+        let addr = phys_to_virt(self.frame.start_address()) + (slot * PAGE_SIZE) as u64;
+        unsafe { &mut *addr.as_mut_ptr() }
+    }
+}
+
+impl Drop for RamSwap {
+    fn drop(&mut self) {
+        unsafe { buddy::free(self.frame, self.order) };
+    }
+}
+
+impl SwapDevice for RamSwap {
+    fn name(&self) -> &'static str {
+        "ram"
+    }
+
+    fn slots(&self) -> usize {
+        1 << self.order
+    }
+
+    fn read(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) {
+        page.copy_from_slice(self.slot(slot));
+    }
+
+    fn write(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) {
+        self.slot(slot).copy_from_slice(page);
+    }
+}
+
+/// A file of page-sized slots. It is written whole when it is made, so that
+/// paging out never has to find blocks for it. A slot that can't be read or
+/// written back is a page lost: that panics.
+pub struct SwapFile {
+    file: KFile,
+    slots: usize,
+}
+
+impl SwapFile {
+    /// Make file `path` a swap file of `slots` pages of zeros, created if it
+    /// isn't there.
+    pub fn create(path: &str, slots: usize) -> Result<SwapFile, FsError> {
+        let mut file = KFile::create(path)?;
+        for _ in 0..slots {
+            file.write_all(&[0u8; PAGE_SIZE])?;
+        }
+        Ok(SwapFile { file, slots })
+    }
+}
+
+impl SwapDevice for SwapFile {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn slots(&self) -> usize {
+        self.slots
+    }
+
+    fn read(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) {
+        match self.file.read_at((slot * PAGE_SIZE) as u64, page) {
+            Ok(PAGE_SIZE) => {}
+            Ok(_) => panic!("swap: {} is shorter than slot {}", self.file.path(), slot),
+            Err(err) => panic!("swap: reading slot {} of {}: {}", slot, self.file.path(), err),
+        }
+    }
+
+    fn write(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) {
+        match self.file.write_at((slot * PAGE_SIZE) as u64, page) {
+            Ok(PAGE_SIZE) => {}
+            Ok(_) => panic!("swap: {} took part of slot {}", self.file.path(), slot),
+            Err(err) => panic!("swap: writing slot {} of {}: {}", slot, self.file.path(), err),
+        }
+    }
+}
+
+struct Swap {
+    device: Box<dyn SwapDevice>,
+    used: Vec<bool>,
+    /// Anonymous pages that are mapped and may be evicted, in clock order.
+    resident: BTreeSet<u64>,
+    /// Evicted pages and the slot holding their contents.
+    swapped: BTreeMap<u64, usize>,
+    /// Resident pages whose slot still holds an identical copy.
+    cached: BTreeMap<u64, usize>,
+    /// Clock hand: the page after which the next scan starts.
+    hand: u64,
+}
+
+impl Swap {
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let slot = self.used.iter().position(|used| !used)?;
+        self.used[slot] = true;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        self.used[slot] = false;
+    }
+
+    /// Write `page` out (unless its slot is still valid) and unmap it.
+    fn evict(&mut self, page: u64) -> bool {
+        let addr = VirtAddr::new(page);
+        let Some((_, flags)) = paging::translate(addr) else { return false };
+        let clean = !flags.contains(PageTableFlags::DIRTY);
+        let slot = match self.cached.remove(&page) {
+            Some(slot) if clean => {
+                CLEAN_EVICTIONS.fetch_add(1, Ordering::Relaxed);
+                slot
+            }
+            Some(slot) => self.write_out(addr, slot),
+            None => match self.alloc_slot() {
+                Some(slot) => self.write_out(addr, slot),
+                None => return false,
+            },
+        };
+        let frame = paging::unmap(Page::containing_address(addr)).expect("swap: page vanished");
+        unsafe { frame_alloc::deallocate_frame(frame) };
+        self.resident.remove(&page);
+        self.swapped.insert(page, slot);
+        true
+    }
+
+    fn write_out(&mut self, addr: VirtAddr, slot: usize) -> usize {
+        let contents = unsafe { &*addr.as_ptr::<[u8; PAGE_SIZE]>() };
+        self.device.write(slot, contents);
+        SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+        slot
+    }
+}
+
+static SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+static SWAPPED_OUT: AtomicUsize = AtomicUsize::new(0);
+static SWAPPED_IN: AtomicUsize = AtomicUsize::new(0);
+static CLEAN_EVICTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start swapping to `device`. Returns false if swap is already on or there is no memory for it.
+pub fn enable(device: Box<dyn SwapDevice>) -> bool {
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        return false;
+    }
+    let slots = device.slots();
+    let Some(mut used) = crate::heap::try_vec(slots) else { return false };
+    used.resize(slots, false);
+    crate::serial_println!("swap: {} device, {} slots", device.name(), slots);
+    *swap = Some(Swap {
+        device,
+        used,
+        resident: BTreeSet::new(),
+        swapped: BTreeMap::new(),
+        cached: BTreeMap::new(),
+        hand: 0,
+    });
+    true
+}
+
+pub fn enabled() -> bool {
+    SWAP.lock().is_some()
+}
+
+/// An anonymous page was just mapped: make it a candidate for eviction.
+pub fn note_resident(page: Page) {
+    if let Some(swap) = SWAP.lock().as_mut() {
+        swap.resident.insert(page.start_address().as_u64());
+    }
+}
+
+/// If `page` was swapped out, map it again with `flags` and read its contents back.
+/// Returns None if the page is not in swap, otherwise whether it could be mapped.
+pub fn swap_in(page: Page, flags: PageTableFlags) -> Option<bool> {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut()?;
+    let key = page.start_address().as_u64();
+    let slot = *swap.swapped.get(&key)?;
+    let Some(frame) = frame_alloc::allocate_frame() else { return Some(false) };
+    let contents = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<[u8; PAGE_SIZE]>() };
+    swap.device.read(slot, contents);
+    if unsafe { paging::map_to(page, frame, flags) }.is_err() {
+        unsafe { frame_alloc::deallocate_frame(frame) };
+        return Some(false);
+    }
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    swap.swapped.remove(&key);
+    swap.cached.insert(key, slot);
+    swap.resident.insert(key);
+    Some(true)
+}
+
+/// `page` is going away (its region is being removed): drop any swap state.
+pub fn forget(page: Page) {
+    if let Some(swap) = SWAP.lock().as_mut() {
+        let key = page.start_address().as_u64();
+        swap.resident.remove(&key);
+        if let Some(slot) = swap.swapped.remove(&key).or_else(|| swap.cached.remove(&key)) {
+            swap.free_slot(slot);
+        }
+    }
+}
+
+/// Evict up to `want` resident pages, giving recently used ones a second chance.
+/// Returns how many were evicted.
+pub fn reclaim(want: usize) -> usize {
+    let mut guard = SWAP.lock();
+    let Some(swap) = guard.as_mut() else { return 0 };
+    let mut evicted = 0;
+    // Two full turns of the clock: the first may only clear ACCESSED bits.
+    let mut budget = 2 * swap.resident.len();
+    while evicted < want && budget > 0 {
+        budget -= 1;
+        let next = swap.resident.range(swap.hand + 1..).next().or_else(|| swap.resident.iter().next()).copied();
+        let Some(page) = next else { break };
+        swap.hand = page;
+        let addr = VirtAddr::new(page);
+        match paging::translate(addr) {
+            Some((_, flags)) if flags.contains(PageTableFlags::ACCESSED) => unsafe {
+                let _ = paging::update_flags(Page::containing_address(addr), flags - PageTableFlags::ACCESSED);
+            },
+            Some(_) => {
+                if swap.evict(page) {
+                    evicted += 1;
+                }
+            }
+            None => {
+                swap.resident.remove(&page);
+            }
+        }
+    }
+    evicted
+}
+
+/// Evict one specific page right away.
+pub fn evict(page: Page) -> bool {
+    SWAP.lock().as_mut().is_some_and(|swap| swap.evict(page.start_address().as_u64()))
+}
+
+pub struct SwapStats {
+    pub slots: usize,
+    pub used: usize,
+    pub resident: usize,
+    pub swapped_out: usize,
+    pub swapped_in: usize,
+    pub clean_evictions: usize,
+}
+
+pub fn stats() -> Option<SwapStats> {
+    let guard = SWAP.lock();
+    let swap = guard.as_ref()?;
+    Some(SwapStats {
+        slots: swap.used.len(),
+        used: swap.used.iter().filter(|&&u| u).count(),
+        resident: swap.resident.len(),
+        swapped_out: SWAPPED_OUT.load(Ordering::Relaxed),
+        swapped_in: SWAPPED_IN.load(Ordering::Relaxed),
+        clean_evictions: CLEAN_EVICTIONS.load(Ordering::Relaxed),
+    })
+}
+
+/// Evict pages of a fresh reservation, fault them back, evict them clean, then release it.
+pub fn self_test() -> bool {
+    use super::vma::{self, Prot};
+    const PAGES: u64 = 4;
+    if !enabled() && !RamSwap::new(4).is_some_and(|dev| enable(Box::new(dev))) {
+        return false;
+    }
+    let Ok(start) = vma::reserve(PAGES * PAGE_SIZE as u64, Prot::READ | Prot::WRITE, "swap-test") else { return false };
+    let page = |i: u64| Page::containing_address(start + i * PAGE_SIZE as u64);
+    let ptr = |i: u64| (start + i * PAGE_SIZE as u64 + 8).as_mut_ptr::<u64>();
+    let out = SWAPPED_OUT.load(Ordering::Relaxed);
+    let clean = CLEAN_EVICTIONS.load(Ordering::Relaxed);
+    let mut ok = true;
+    unsafe {
+        for i in 0..PAGES {
+            ptr(i).write_volatile(0x5A5A_0000 + i);
+        }
+        ok &= (0..PAGES).all(|i| evict(page(i)));
+        ok &= (0..PAGES).all(|i| paging::translate(page(i).start_address()).is_none());
+        // Reading faults each page back in from its slot.
+        ok &= (0..PAGES).all(|i| ptr(i).read_volatile() == 0x5A5A_0000 + i);
+        ok &= SWAPPED_OUT.load(Ordering::Relaxed) - out == PAGES as usize;
+        // Untouched since swap-in: the second eviction needs no write.
+        ptr(0).write_volatile(1);
+        ok &= (0..PAGES).all(|i| evict(page(i)));
+        ok &= SWAPPED_OUT.load(Ordering::Relaxed) - out == PAGES as usize + 1;
+        ok &= CLEAN_EVICTIONS.load(Ordering::Relaxed) - clean == PAGES as usize - 1;
+        ok &= ptr(0).read_volatile() == 1;
+    }
+    let used = stats().map(|s| s.used);
+    ok && vma::release(start).is_ok() && stats().map(|s| s.used) == used.map(|u| u - PAGES as usize)
+}
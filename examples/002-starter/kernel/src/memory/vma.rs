@@ -0,0 +1,385 @@
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::ops::BitOr;
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{frame_alloc, paging, phys_to_virt, swap};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Access rights of a region.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Prot(u8);
+
+impl Prot {
+    pub const READ: Prot = Prot(1);
+    pub const WRITE: Prot = Prot(2);
+    pub const EXEC: Prot = Prot(4);
+    pub const USER: Prot = Prot(8);
+
+    /// Read, write and execute bits as `mmap` takes them (the same as Linux's
+    /// PROT_*); `None` if any other bit is set.
+    pub fn from_bits(bits: u64) -> Option<Prot> {
+        (bits & !7 == 0).then_some(Prot(bits as u8))
+    }
+
+    pub fn contains(self, other: Prot) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.contains(Prot::WRITE) { flags |= PageTableFlags::WRITABLE; }
+        if !self.contains(Prot::EXEC) { flags |= PageTableFlags::NO_EXECUTE; }
+        if self.contains(Prot::USER) { flags |= PageTableFlags::USER_ACCESSIBLE; }
+        flags
+    }
+}
+
+impl BitOr for Prot {
+    type Output = Prot;
+    fn bitor(self, rhs: Prot) -> Prot {
+        Prot(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for Prot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bit = |p, c| if self.contains(p) { c } else { '-' };
+        write!(f, "{}{}{}{}", bit(Prot::READ, 'r'), bit(Prot::WRITE, 'w'), bit(Prot::EXEC, 'x'), bit(Prot::USER, 'u'))
+    }
+}
+
+/// Where the contents of a region come from.
+#[derive(Clone, Copy)]
+pub enum Backing {
+    /// Zero-filled memory, allocated on first touch.
+    Anonymous,
+    /// Device registers at a fixed physical address (mapped uncached).
+    Mmio { phys: PhysAddr },
+    /// Initialized from a byte slice (e.g. a file in the initrd); the rest is zero.
+    File { data: &'static [u8] },
+}
+
+impl Backing {
+    fn name(&self) -> &'static str {
+        match self {
+            Backing::Anonymous => "anon",
+            Backing::Mmio { .. } => "mmio",
+            Backing::File { .. } => "file",
+        }
+    }
+}
+
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub prot: Prot,
+    pub backing: Backing,
+    pub name: &'static str,
+    /// Pages mapped in by the fault handler so far.
+    pub faulted_pages: usize,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Why an access that faulted with `code` isn't allowed here, if it isn't.
+    pub fn check_access(&self, code: PageFaultErrorCode) -> Result<(), &'static str> {
+        if !self.prot.contains(Prot::READ) {
+            return Err("access to a no-access region");
+        }
+        if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !self.prot.contains(Prot::WRITE) {
+            return Err("write to read-only region");
+        }
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !self.prot.contains(Prot::EXEC) {
+            return Err("execute in no-exec region");
+        }
+        if code.contains(PageFaultErrorCode::USER_MODE) && !self.prot.contains(Prot::USER) {
+            return Err("user access to kernel region");
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return Err("protection violation");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    Unaligned,
+    Overlap,
+    NotFound,
+    /// No gap big enough, or growing past the limit.
+    NoRoom,
+}
+
+/// What the page-fault handler should do with a fault.
+pub enum FaultOutcome {
+    /// The fault was legitimate demand paging and has been resolved.
+    Handled,
+    /// The address is inside a region, but the access isn't allowed.
+    AccessViolation(&'static str),
+    /// No region covers the address.
+    Unmapped,
+}
+
+/// The regions of one address space, ordered by start address.
+pub struct VmaTree {
+    regions: BTreeMap<u64, Vma>,
+}
+
+impl VmaTree {
+    pub const fn new() -> Self {
+        VmaTree { regions: BTreeMap::new() }
+    }
+
+    /// Reserve `[start, start + len)`. Nothing is mapped until it is touched.
+    pub fn insert(&mut self, start: VirtAddr, len: u64, prot: Prot, backing: Backing, name: &'static str) -> Result<(), VmaError> {
+        if !start.as_u64().is_multiple_of(PAGE_SIZE) || len == 0 || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(VmaError::Unaligned);
+        }
+        let end = start + len;
+        // Only the nearest regions on either side can overlap.
+        let before = self.regions.range(..end.as_u64()).next_back();
+        if before.is_some_and(|(_, v)| v.end > start) {
+            return Err(VmaError::Overlap);
+        }
+        self.regions.insert(start.as_u64(), Vma { start, end, prot, backing, name, faulted_pages: 0 });
+        Ok(())
+    }
+
+    /// Drop the region starting at `start`, unmapping its pages and freeing owned frames.
+    pub fn remove(&mut self, start: VirtAddr) -> Result<(), VmaError> {
+        let vma = self.regions.remove(&start.as_u64()).ok_or(VmaError::NotFound)?;
+        let owns_frames = !matches!(vma.backing, Backing::Mmio { .. });
+        let first = Page::<Size4KiB>::containing_address(vma.start);
+        let last = Page::<Size4KiB>::containing_address(vma.end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            if matches!(vma.backing, Backing::Anonymous) {
+                swap::forget(page);
+            }
+            if let Ok(frame) = paging::unmap(page) {
+                if owns_frames {
+                    unsafe { frame_alloc::deallocate_frame(frame) };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the region starting at `start` from the tree only; unmapping its
+    /// pages is up to the caller. For trees of other address spaces than the
+    /// kernel's, which `remove` can't reach.
+    pub fn take(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        self.regions.remove(&start.as_u64()).ok_or(VmaError::NotFound)
+    }
+
+    /// Move the end of the region starting at `start` so it is `len` long,
+    /// and return its old end. Pages past a new, lower end are the caller's
+    /// to unmap.
+    pub fn resize(&mut self, start: VirtAddr, len: u64) -> Result<VirtAddr, VmaError> {
+        if len == 0 || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(VmaError::Unaligned);
+        }
+        let end = start + len;
+        let next = self.regions.range(start.as_u64() + 1..).next().map(|(&next, _)| next);
+        if next.is_some_and(|next| next < end.as_u64()) {
+            return Err(VmaError::Overlap);
+        }
+        let vma = self.regions.get_mut(&start.as_u64()).ok_or(VmaError::NotFound)?;
+        Ok(core::mem::replace(&mut vma.end, end))
+    }
+
+    /// The lowest address in `[from, to)` where `len` bytes fit with an
+    /// unmapped page after them, so overruns fault.
+    pub fn find_gap(&self, from: VirtAddr, to: VirtAddr, len: u64) -> Option<VirtAddr> {
+        let mut at = from;
+        for vma in self.regions.range(..to.as_u64()).map(|(_, v)| v) {
+            if vma.end <= at {
+                continue;
+            }
+            if vma.start >= at + len + PAGE_SIZE {
+                break;
+            }
+            at = vma.end + PAGE_SIZE;
+        }
+        (at + len <= to).then_some(at)
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.regions.range(..=addr.as_u64()).next_back().map(|(_, v)| v).filter(|v| v.contains(addr))
+    }
+
+    pub fn find_mut(&mut self, addr: VirtAddr) -> Option<&mut Vma> {
+        self.regions.range_mut(..=addr.as_u64()).next_back().map(|(_, v)| v).filter(|v| v.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.regions.values()
+    }
+
+    /// Decide between demand paging and a real fault, and map the page in the first case.
+    pub fn handle_fault(&mut self, addr: VirtAddr, code: PageFaultErrorCode) -> FaultOutcome {
+        let Some(vma) = self.find_mut(addr) else { return FaultOutcome::Unmapped };
+        if let Err(reason) = vma.check_access(code) {
+            return FaultOutcome::AccessViolation(reason);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let offset = page.start_address() - vma.start;
+        let flags = vma.prot.page_flags();
+        if !matches!(vma.backing, Backing::Mmio { .. })
+            && frame_alloc::stats().is_some_and(|s| s.free < swap::LOW_WATERMARK)
+        {
+            swap::reclaim(swap::RECLAIM_BATCH);
+        }
+        let mapped = match vma.backing {
+            Backing::Mmio { phys } => {
+                let frame = PhysFrame::containing_address(phys + offset);
+                let flags = flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+                unsafe { paging::map_to(page, frame, flags).is_ok() }
+            }
+            Backing::Anonymous => match swap::swap_in(page, flags) {
+                Some(mapped) => mapped,
+                None => {
+                    ZERO_FILLED.fetch_add(1, Ordering::Relaxed);
+                    let mapped = map_filled(page, flags, &[]);
+                    if mapped {
+                        swap::note_resident(page);
+                    }
+                    mapped
+                }
+            },
+            Backing::File { data } => {
+                let from = (offset as usize).min(data.len());
+                let to = (from + PAGE_SIZE as usize).min(data.len());
+                map_filled(page, flags, &data[from..to])
+            }
+        };
+        if !mapped {
+            return FaultOutcome::AccessViolation("out of memory while paging in");
+        }
+        vma.faulted_pages += 1;
+        FaultOutcome::Handled
+    }
+}
+
+/// Map a fresh frame at `page` holding `contents` followed by zeroes.
+fn map_filled(page: Page, flags: PageTableFlags, contents: &[u8]) -> bool {
+    let Some(frame) = frame_alloc::allocate_frame() else { return false };
+    // Fill the frame through the physical-memory window before it becomes visible.
+    unsafe {
+        let dst = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        dst.write_bytes(0, PAGE_SIZE as usize);
+        dst.copy_from_nonoverlapping(contents.as_ptr(), contents.len());
+        if paging::map_to(page, frame, flags).is_err() {
+            frame_alloc::deallocate_frame(frame);
+            return false;
+        }
+    }
+    true
+}
+
+/// Regions of the kernel address space (the heap itself is not in here: it is
+/// handled before the tree is consulted, because the tree lives on the heap).
+pub static KERNEL_VMAS: Mutex<VmaTree> = Mutex::new(VmaTree::new());
+
+pub fn handle_page_fault(addr: VirtAddr, code: PageFaultErrorCode) -> FaultOutcome {
+    // A fault while the tree is locked (e.g. inside `insert`) can't be resolved here.
+    match KERNEL_VMAS.try_lock() {
+        Some(mut tree) => tree.handle_fault(addr, code),
+        None => FaultOutcome::Unmapped,
+    }
+}
+
+/// Virtual range handed out by `reserve`.
+const LAZY_START: u64 = 0x_4800_0000_0000;
+static NEXT_LAZY: AtomicU64 = AtomicU64::new(LAZY_START);
+static ZERO_FILLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserve `len` bytes of demand-zero memory anywhere in the kernel's lazy range.
+///
+/// Costs no physical memory until touched: each page is allocated and zeroed by
+/// the page-fault handler on first access.
+pub fn reserve(len: u64, prot: Prot, name: &'static str) -> Result<VirtAddr, VmaError> {
+    let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    // Leave an unmapped page between reservations so overruns fault.
+    let start = VirtAddr::new(NEXT_LAZY.fetch_add(len + PAGE_SIZE, Ordering::Relaxed));
+    KERNEL_VMAS.lock().insert(start, len, prot, Backing::Anonymous, name)?;
+    Ok(start)
+}
+
+/// Give back a reservation made with `reserve`, freeing the pages that were touched.
+pub fn release(start: VirtAddr) -> Result<(), VmaError> {
+    KERNEL_VMAS.lock().remove(start)
+}
+
+/// Pages allocated and zeroed on first touch so far (all anonymous regions).
+pub fn zero_filled_pages() -> usize {
+    ZERO_FILLED.load(Ordering::Relaxed)
+}
+
+pub fn dump() {
+    crate::serial_println!("  start              end                prot  backing  pages  name");
+    for vma in KERNEL_VMAS.lock().iter() {
+        crate::serial_println!(
+            "  {:#018x} {:#018x} {}  {:<7} {:>6}  {}",
+            vma.start.as_u64(), vma.end.as_u64(), vma.prot, vma.backing.name(), vma.faulted_pages, vma.name
+        );
+    }
+}
+
+/// Reserve 64 MiB, touch three pages and check only those three cost memory.
+pub fn lazy_self_test() -> bool {
+    const LEN: u64 = 64 * 1024 * 1024;
+    let Ok(start) = reserve(LEN, Prot::READ | Prot::WRITE, "lazy-test") else { return false };
+    let before = zero_filled_pages();
+    unsafe {
+        for offset in [0, LEN / 2, LEN - 8] {
+            (start + offset).as_mut_ptr::<u64>().write_volatile(offset);
+        }
+    }
+    let faulted = KERNEL_VMAS.lock().find(start).map(|v| v.faulted_pages);
+    let ok = faulted == Some(3) && zero_filled_pages() - before == 3;
+    release(start).is_ok() && ok
+}
+
+/// Reserve a region, touch part of it, check only touched pages were mapped, then remove it.
+pub fn self_test() -> bool {
+    const GREETING: &[u8] = b"hello from a file-backed region";
+    // The VGA text buffer makes a harmless MMIO target that exists on every PC.
+    const VGA_TEXT: u64 = 0xb8000;
+    let anon = VirtAddr::new(0x_4545_0000_0000);
+    let file = VirtAddr::new(0x_4545_1000_0000);
+    let mmio = VirtAddr::new(0x_4545_2000_0000);
+    {
+        let mut tree = KERNEL_VMAS.lock();
+        let rw = Prot::READ | Prot::WRITE;
+        if tree.insert(anon, 16 * PAGE_SIZE, rw, Backing::Anonymous, "vma-test").is_err()
+            || tree.insert(file, PAGE_SIZE, Prot::READ, Backing::File { data: GREETING }, "vma-test-file").is_err()
+            || tree.insert(mmio, PAGE_SIZE, rw, Backing::Mmio { phys: PhysAddr::new(VGA_TEXT) }, "vma-test-mmio").is_err()
+        {
+            return false;
+        }
+    }
+    let ok = unsafe {
+        let p = anon.as_mut_ptr::<u64>();
+        let zero = p.add(512 * 3).read_volatile() == 0;
+        p.add(512 * 5).write_volatile(7);
+        let text = core::slice::from_raw_parts(file.as_ptr::<u8>(), GREETING.len());
+        mmio.as_ptr::<u8>().read_volatile();
+        zero && p.add(512 * 5).read_volatile() == 7 && text == GREETING
+    };
+    let mmio_ok = paging::translate_addr(mmio) == Some(PhysAddr::new(VGA_TEXT));
+    let mut tree = KERNEL_VMAS.lock();
+    let touched = tree.find(anon).map(|v| v.faulted_pages) == Some(2);
+    let removed = tree.remove(anon).is_ok() && tree.remove(file).is_ok() && tree.remove(mmio).is_ok();
+    ok && mmio_ok && touched && removed
+}
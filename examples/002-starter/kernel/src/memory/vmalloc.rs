@@ -0,0 +1,179 @@
+//! Kernel virtual address allocator.
+//!
+//! Hands out page-aligned ranges of one big kernel window (randomized by KASLR),
+//! separate from the heap: MMIO mappings, DMA windows and kernel stacks take their
+//! addresses from here, and `vmalloc` backs a range with (not necessarily
+//! contiguous) frames for large buffers. Every range is followed by an unmapped
+//! page, so running off its end faults.
+//!
+//! Each live range remembers who asked for it and when, so ranges that were
+//! never given back can be listed (`leaks_since`).
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{frame_alloc, paging};
+
+const PAGE_SIZE: u64 = 4096;
+const WINDOW_START: u64 = 0x_5555_0000_0000;
+const WINDOW_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+/// How far KASLR may slide the window up.
+const WINDOW_SLIDE: u64 = 256 * 1024 * 1024 * 1024;
+
+struct Area {
+    /// Usable bytes, not counting the guard page.
+    len: u64,
+    owner: &'static str,
+    /// Value of the allocation counter when the range was handed out.
+    serial: u64,
+    /// Frames were allocated by `vmalloc` and are freed by `vfree`.
+    owns_frames: bool,
+}
+
+struct Kva {
+    /// Free ranges: start -> length.
+    free: BTreeMap<u64, u64>,
+    used: BTreeMap<u64, Area>,
+    allocations: u64,
+}
+
+impl Kva {
+    fn alloc(&mut self, len: u64, owner: &'static str, owns_frames: bool) -> Option<VirtAddr> {
+        let len = len.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let need = len + PAGE_SIZE;
+        let (&start, &free_len) = self.free.iter().find(|(_, &l)| l >= need)?;
+        self.free.remove(&start);
+        if free_len > need {
+            self.free.insert(start + need, free_len - need);
+        }
+        self.allocations += 1;
+        self.used.insert(start, Area { len, owner, serial: self.allocations, owns_frames });
+        Some(VirtAddr::new(start))
+    }
+
+    fn release(&mut self, start: u64) -> Option<Area> {
+        let area = self.used.remove(&start)?;
+        let mut start = start;
+        let mut len = area.len + PAGE_SIZE;
+        // Merge with the free neighbours on either side.
+        if let Some((&prev, &prev_len)) = self.free.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(start + len)) {
+            len += next_len;
+        }
+        self.free.insert(start, len);
+        Some(area)
+    }
+}
+
+static KVA: Mutex<Option<Kva>> = Mutex::new(None);
+
+/// Set up the window. Needs the heap.
+pub fn init() {
+    let start = WINDOW_START + crate::kaslr::slide(WINDOW_SLIDE, PAGE_SIZE);
+    let mut free = BTreeMap::new();
+    free.insert(start, WINDOW_SIZE);
+    *KVA.lock() = Some(Kva { free, used: BTreeMap::new(), allocations: 0 });
+    crate::serial_println!("memory: vmalloc window at {:#x}", start);
+}
+
+fn with_kva<R>(f: impl FnOnce(&mut Kva) -> R) -> R {
+    f(KVA.lock().as_mut().expect("vmalloc not initialized"))
+}
+
+/// Reserve `len` bytes (rounded up to pages) of address space; nothing is mapped.
+pub fn alloc_range(len: u64, owner: &'static str) -> Option<VirtAddr> {
+    with_kva(|kva| kva.alloc(len, owner, false))
+}
+
+/// Give back a range from `alloc_range`. The caller must have unmapped it.
+pub fn free_range(start: VirtAddr) {
+    let area = with_kva(|kva| kva.release(start.as_u64())).expect("vmalloc: freeing unknown range");
+    assert!(!area.owns_frames, "vmalloc: use vfree for {:#x}", start.as_u64());
+}
+
+/// Allocate `len` bytes of virtually contiguous, zeroed kernel memory.
+pub fn vmalloc(len: u64, owner: &'static str) -> Option<VirtAddr> {
+    let start = with_kva(|kva| kva.alloc(len, owner, true))?;
+    let pages = len.max(1).div_ceil(PAGE_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+        if paging::map_new(page, flags).is_err() {
+            vfree(start);
+            return None;
+        }
+        unsafe { core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    }
+    Some(start)
+}
+
+/// Free memory from `vmalloc`.
+pub fn vfree(start: VirtAddr) {
+    let area = with_kva(|kva| kva.release(start.as_u64())).expect("vfree: unknown address");
+    assert!(area.owns_frames, "vfree: {:#x} was not vmalloc'd", start.as_u64());
+    let first = Page::<Size4KiB>::containing_address(start);
+    for page in Page::range(first, first + area.len / PAGE_SIZE) {
+        if let Ok(frame) = paging::unmap(page) {
+            unsafe { frame_alloc::deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Current value of the allocation counter: pass it to `leaks_since` later.
+pub fn checkpoint() -> u64 {
+    with_kva(|kva| kva.allocations)
+}
+
+/// Call `f(start, len, owner)` for every range handed out after `checkpoint` and still live.
+pub fn leaks_since(checkpoint: u64, mut f: impl FnMut(VirtAddr, u64, &'static str)) -> usize {
+    with_kva(|kva| {
+        let mut count = 0;
+        for (&start, area) in kva.used.iter().filter(|(_, a)| a.serial > checkpoint) {
+            f(VirtAddr::new(start), area.len, area.owner);
+            count += 1;
+        }
+        count
+    })
+}
+
+pub fn dump() {
+    with_kva(|kva| {
+        crate::serial_println!("  start              size      owner");
+        for (&start, area) in &kva.used {
+            crate::serial_println!("  {:#018x} {:>7} KiB {}", start, area.len / 1024, area.owner);
+        }
+        let free: u64 = kva.free.values().sum();
+        crate::serial_println!("  {} ranges live, {} GiB free in {} holes", kva.used.len(), free >> 30, kva.free.len());
+    });
+}
+
+/// Allocate and free ranges of every kind, then check nothing leaked and holes merged.
+pub fn self_test() -> bool {
+    let mark = checkpoint();
+    let holes = with_kva(|kva| kva.free.len());
+    let Some(buf) = vmalloc(5 * PAGE_SIZE, "vmalloc-test") else { return false };
+    let ok = unsafe {
+        let p = buf.as_mut_ptr::<u64>();
+        p.add(4 * 512).write_volatile(9);
+        p.read_volatile() == 0 && p.add(4 * 512).read_volatile() == 9
+    };
+    // The guard page after the buffer stays unmapped.
+    let guarded = paging::translate_addr(buf + 5 * PAGE_SIZE).is_none();
+    let Some(range) = alloc_range(3 * PAGE_SIZE, "vmalloc-test-range") else { return false };
+    let leaked = leaks_since(mark, |_, _, _| {}) == 2;
+    vfree(buf);
+    free_range(range);
+    let unmapped = paging::translate_addr(buf).is_none();
+    let clean = leaks_since(mark, |start, len, owner| {
+        crate::serial_println!("  leaked {:#x} ({} bytes) by {}", start.as_u64(), len, owner)
+    }) == 0;
+    ok && guarded && leaked && unmapped && clean && with_kva(|kva| kva.free.len()) == holes
+}
@@ -0,0 +1,127 @@
+//! Zero-on-free and `SecretBox`.
+//!
+//! Freed memory normally keeps its old contents until it is reused, so whoever
+//! gets the block or frame next can read them (data remanence; try `wipe demo`).
+//! With zero-on-free on (`zero_on_free` on the command line or `wipe on`), heap
+//! blocks and physical frames are cleared when they are freed. `SecretBox` clears
+//! its contents on drop regardless, for key material.
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use x86_64::structures::paging::PhysFrame;
+
+use super::phys_to_virt;
+
+static ZERO_ON_FREE: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    if crate::cmdline::has_flag("zero_on_free") {
+        set_enabled(true);
+    }
+}
+
+pub fn enabled() -> bool {
+    ZERO_ON_FREE.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(on: bool) {
+    ZERO_ON_FREE.store(on, Ordering::Relaxed);
+}
+
+/// Zero `len` bytes in a way the compiler may not optimize out, even though
+/// nothing reads the memory afterwards.
+///
+/// # Safety
+/// `ptr..ptr + len` must be writable.
+pub unsafe fn secure_zero(ptr: *mut u8, len: usize) {
+    for i in 0..len {
+        ptr.add(i).write_volatile(0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Heap hook: clear a block that is being freed if zero-on-free is on.
+///
+/// # Safety
+/// See [`secure_zero`].
+pub unsafe fn on_free(ptr: *mut u8, len: usize) {
+    if enabled() {
+        secure_zero(ptr, len);
+    }
+}
+
+/// Frame allocator hook: clear `count` frames starting at `frame` if zero-on-free is on.
+pub fn on_free_frames(frame: PhysFrame, count: usize) {
+    if enabled() {
+        unsafe { secure_zero(phys_to_virt(frame.start_address()).as_mut_ptr(), count * 4096) };
+    }
+}
+
+/// A heap box whose contents are wiped when it is dropped.
+pub struct SecretBox<T> {
+    inner: Box<ManuallyDrop<T>>,
+}
+
+impl<T> SecretBox<T> {
+    pub fn new(value: T) -> Self {
+        SecretBox { inner: Box::new(ManuallyDrop::new(value)) }
+    }
+}
+
+impl<T> Deref for SecretBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for SecretBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for SecretBox<T> {
+    fn drop(&mut self) {
+        let value: &mut ManuallyDrop<T> = &mut self.inner;
+        unsafe {
+            ManuallyDrop::drop(value);
+            secure_zero((value as *mut ManuallyDrop<T>).cast(), core::mem::size_of::<T>());
+        }
+    }
+}
+
+/// Never print the secret by accident.
+impl<T> fmt::Debug for SecretBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretBox(<redacted>)")
+    }
+}
+
+const SECRET: [u8; 32] = *b"correct horse battery staple!!!!";
+
+/// Free a buffer holding a "secret" and allocate the same size again: returns how
+/// many bytes of the secret the new owner can still see.
+pub fn remanence_demo() -> usize {
+    let mut secret = alloc::vec![0u8; SECRET.len()];
+    secret.copy_from_slice(&SECRET);
+    drop(secret);
+    let reused: alloc::vec::Vec<u8> = alloc::vec::Vec::with_capacity(SECRET.len());
+    // Reading uninitialized capacity is exactly the bug being demonstrated.
+    let stale = unsafe { core::slice::from_raw_parts(reused.as_ptr(), SECRET.len()) };
+    stale.iter().zip(SECRET).filter(|(a, b)| **a == *b).count()
+}
+
+/// A dropped SecretBox leaves nothing of its contents behind.
+pub fn self_test() -> bool {
+    let secret = SecretBox::new(SECRET);
+    let ptr = (&*secret as *const [u8; 32]).cast::<u8>();
+    let ok = secret[..] == SECRET[..] && alloc::format!("{:?}", secret) == "SecretBox(<redacted>)";
+    drop(secret);
+    // Deliberately reading freed memory: nothing can have reused it yet.
+    let leftover = (0..SECRET.len()).filter(|&i| unsafe { ptr.add(i).read_volatile() } == SECRET[i]).count();
+    ok && leftover == 0
+}
@@ -0,0 +1,182 @@
+//! W^X: no kernel page may be both writable and executable.
+//!
+//! The kernel's ELF program headers tell us which ranges are code (read-execute),
+//! read-only data and writable data. Everything else mapped writable (heap, stacks,
+//! the physical map, the bootloader's framebuffer and boot info) must be NX.
+
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+
+use super::{paging, phys_to_virt};
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+/// More segments than any sane kernel has; keeps the list off the heap.
+const MAX_SEGMENTS: usize = 16;
+
+/// Where the bootloader loaded the kernel (from `BootInfo`).
+pub struct KernelImage {
+    /// Physical address of the ELF file.
+    pub addr: u64,
+    pub len: u64,
+    /// Added to every `p_vaddr` (non-zero for a relocated, position-independent kernel).
+    pub offset: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    writable: bool,
+    executable: bool,
+}
+
+#[derive(Default)]
+struct Sections {
+    list: [Option<Segment>; MAX_SEGMENTS],
+}
+
+impl Sections {
+    fn iter(&self) -> impl Iterator<Item = &Segment> {
+        self.list.iter().flatten()
+    }
+
+    /// True if any part of [start, end) holds kernel code.
+    fn overlaps_code(&self, start: u64, end: u64) -> bool {
+        self.iter().any(|s| s.executable && s.start < end && start < s.end)
+    }
+}
+
+fn read<T: Copy>(elf: &[u8], at: usize) -> Option<T> {
+    let bytes = elf.get(at..at + core::mem::size_of::<T>())?;
+    Some(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+}
+
+/// One entry of the kernel's ELF program header table.
+#[derive(Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    /// Relocated to where the segment is mapped.
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+/// The kernel's program headers; `None` if the image isn't an ELF file.
+pub fn program_headers(image: &KernelImage) -> Option<impl Iterator<Item = ProgramHeader>> {
+    let ptr = phys_to_virt(x86_64::PhysAddr::new(image.addr)).as_ptr::<u8>();
+    let elf: &'static [u8] = unsafe { core::slice::from_raw_parts(ptr, image.len as usize) };
+    if elf.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let phoff = read::<u64>(elf, 0x20)? as usize;
+    let phentsize = read::<u16>(elf, 0x36)? as usize;
+    let phnum = read::<u16>(elf, 0x38)? as usize;
+    let offset = image.offset;
+    Some((0..phnum).map_while(move |i| {
+        let ph = phoff + i * phentsize;
+        Some(ProgramHeader {
+            kind: read(elf, ph)?,
+            flags: read(elf, ph + 4)?,
+            vaddr: read::<u64>(elf, ph + 16)? + offset,
+            filesz: read(elf, ph + 32)?,
+            memsz: read(elf, ph + 40)?,
+            align: read(elf, ph + 48)?,
+        })
+    }))
+}
+
+/// Loadable and RELRO segments of the kernel ELF, relocated to where they run.
+fn sections(image: &KernelImage) -> Option<Sections> {
+    let mut out = Sections::default();
+    let mut slots = out.list.iter_mut();
+    for ph in program_headers(image)? {
+        if ph.kind != PT_LOAD && ph.kind != PT_GNU_RELRO {
+            continue;
+        }
+        *slots.next()? = Some(Segment {
+            start: ph.vaddr,
+            end: ph.vaddr + ph.memsz,
+            // RELRO is writable only while the loader applies relocations.
+            writable: ph.kind == PT_LOAD && ph.flags & PF_W != 0,
+            executable: ph.flags & PF_X != 0,
+        });
+    }
+    Some(out)
+}
+
+/// Tighten the kernel's own segments, then make every other writable mapping NX.
+pub fn enforce(image: &KernelImage) {
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    let Some(sections) = sections(image) else {
+        crate::serial_println!("wx: kernel image is not an ELF file, skipping");
+        return;
+    };
+    let mut tightened = 0usize;
+    paging::walk(|start, size, _, entry| {
+        use PageTableFlags as Flags;
+        let (lo, hi) = (start.as_u64(), start.as_u64() + size);
+        let mut flags = entry.flags();
+        for seg in sections.iter() {
+            // Only pages wholly inside one segment; a page shared between code
+            // and data cannot satisfy both and is left as the loader mapped it.
+            if lo < seg.start || hi > seg.end {
+                continue;
+            }
+            if !seg.writable {
+                flags.remove(Flags::WRITABLE);
+            }
+            if seg.executable {
+                flags.remove(Flags::NO_EXECUTE);
+            } else {
+                flags.insert(Flags::NO_EXECUTE);
+            }
+        }
+        if flags.contains(Flags::WRITABLE) && !sections.overlaps_code(lo, hi) {
+            flags.insert(Flags::NO_EXECUTE);
+        }
+        if flags != entry.flags() {
+            let addr = entry.addr();
+            entry.set_addr(addr, flags);
+            tightened += 1;
+        }
+    });
+    tlb::flush_all();
+    for seg in sections.iter() {
+        let kind = match (seg.writable, seg.executable) {
+            (_, true) => "r-x",
+            (true, false) => "rw-",
+            (false, false) => "r--",
+        };
+        crate::serial_println!("wx: kernel {:#x}..{:#x} {}", seg.start, seg.end, kind);
+    }
+    crate::serial_println!("wx: tightened {} mappings", tightened);
+    match check() {
+        0 => crate::serial_println!("wx: no writable+executable mappings"),
+        n => crate::serial_println!("wx: {} writable+executable mappings remain", n),
+    }
+}
+
+/// Report every mapping that is both writable and executable; returns how many.
+pub fn check() -> usize {
+    let mut found = 0;
+    paging::walk(|start, size, flags, _| {
+        if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+            if found < 8 {
+                crate::serial_println!("  W+X: {:#x} ({} KiB)", start.as_u64(), size / 1024);
+            }
+            found += 1;
+        }
+    });
+    found
+}
+
+/// W^X holds once `enforce` has run.
+pub fn self_test() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) && check() == 0
+}
@@ -0,0 +1,356 @@
+//! PCI: finding the devices on the bus and reading their configuration.
+//!
+//! Every function of every device has 256 bytes of configuration space:
+//! who made it and what it is, its BARs (where its registers are, in memory
+//! or I/O port space) and a list of capabilities. We reach it the legacy
+//! way, through two I/O ports: write the address to CONFIG_ADDRESS, then
+//! read or write 32 bits at CONFIG_DATA. `init` scans every bus once and
+//! keeps what it found; drivers look their devices up in `devices()`.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::memory::mmio::map_mmio;
+use crate::serial_println;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Configuration space registers.
+pub const VENDOR_ID: u8 = 0x00;
+pub const DEVICE_ID: u8 = 0x02;
+pub const COMMAND: u8 = 0x04;
+pub const STATUS: u8 = 0x06;
+/// Revision, programming interface, subclass and class, from low to high.
+pub const CLASS: u8 = 0x08;
+pub const HEADER_TYPE: u8 = 0x0E;
+pub const BAR0: u8 = 0x10;
+pub const SUBSYSTEM_ID: u8 = 0x2E;
+pub const CAPABILITIES: u8 = 0x34;
+pub const INTERRUPT_LINE: u8 = 0x3C;
+pub const INTERRUPT_PIN: u8 = 0x3D;
+
+/// COMMAND bits.
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Capability IDs.
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// MSI-X message control bits.
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// Where MSI-X messages go: the local APIC of the CPU in bits 12-19.
+const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+/// One lock for the address/data port pair.
+static CONFIG: Mutex<()> = Mutex::new(());
+
+/// Where a function is: bus, device (slot) and function number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+impl Address {
+    fn port_value(self, offset: u8) -> u32 {
+        1 << 31 | (self.bus as u32) << 16 | (self.device as u32) << 11 | (self.function as u32) << 8 | (offset & 0xFC) as u32
+    }
+
+    pub fn read_u32(self, offset: u8) -> u32 {
+        let _guard = CONFIG.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.port_value(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(self, offset: u8, value: u32) {
+        let _guard = CONFIG.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.port_value(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Write 16 bits, keeping the other half of the dword. Not for
+    /// registers whose bits clear when written with 1 (STATUS).
+    pub fn write_u16(self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+}
+
+/// Where a BAR points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { addr: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u32 },
+}
+
+/// A function found by the scan: the registers that say what it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub addr: Address,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// The PIC line the firmware routed its INTx pin to, if it has one.
+    pub interrupt_line: Option<u8>,
+}
+
+impl Device {
+    fn read(addr: Address) -> Option<Device> {
+        let vendor = addr.read_u16(VENDOR_ID);
+        if vendor == 0xFFFF {
+            return None;
+        }
+        let class = addr.read_u32(CLASS);
+        let pin = addr.read_u8(INTERRUPT_PIN);
+        let line = addr.read_u8(INTERRUPT_LINE);
+        Some(Device {
+            addr,
+            vendor,
+            device: addr.read_u16(DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            interrupt_line: (pin != 0 && line < 16).then_some(line),
+        })
+    }
+
+    /// Let it decode its memory and I/O BARs and do DMA, and raise INTx.
+    pub fn enable(&self) {
+        let command = self.addr.read_u16(COMMAND);
+        let command = (command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER) & !COMMAND_INTX_DISABLE;
+        self.addr.write_u16(COMMAND, command);
+    }
+
+    /// BAR `index` (0-5), sized the usual way: write all ones, read back
+    /// which bits stuck, restore. Decoding is off meanwhile, so the device
+    /// doesn't answer at the all-ones address. `None` if it is unused. The
+    /// upper half of a 64-bit BAR reads as nonsense; `bars` skips those.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        assert!(index < 6, "pci: no BAR {}", index);
+        let offset = BAR0 + index * 4;
+        let low = self.addr.read_u32(offset);
+        let command = self.addr.read_u16(COMMAND);
+        self.addr.write_u16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let bar = if low & 1 == 1 {
+            self.addr.write_u32(offset, 0xFFFF_FFFF);
+            let size = !(self.addr.read_u32(offset) & !3) & 0xFFFF;
+            self.addr.write_u32(offset, low);
+            (low & !3 != 0).then(|| Bar::Io { port: (low & !3) as u16, size: size.wrapping_add(1) })
+        } else {
+            let wide = (low >> 1) & 3 == 2;
+            let high = if wide && index < 5 { self.addr.read_u32(offset + 4) } else { 0 };
+            self.addr.write_u32(offset, 0xFFFF_FFFF);
+            let mut mask = (self.addr.read_u32(offset) & !0xF) as u64;
+            self.addr.write_u32(offset, low);
+            if wide && index < 5 {
+                self.addr.write_u32(offset + 4, 0xFFFF_FFFF);
+                mask |= (self.addr.read_u32(offset + 4) as u64) << 32;
+                self.addr.write_u32(offset + 4, high);
+            } else {
+                mask |= 0xFFFF_FFFF << 32;
+            }
+            let addr = (high as u64) << 32 | (low & !0xF) as u64;
+            (mask != 0 && addr != 0).then(|| Bar::Memory { addr, size: !mask + 1, prefetchable: low & 8 != 0 })
+        };
+        self.addr.write_u16(COMMAND, command);
+        bar
+    }
+
+    /// Its BARs in use, by index, skipping the upper halves of 64-bit ones.
+    pub fn bars(&self) -> Vec<(u8, Bar)> {
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < 6 {
+            let low = self.addr.read_u32(BAR0 + index * 4);
+            bars.extend(self.bar(index).map(|bar| (index, bar)));
+            index += if low & 1 == 0 && (low >> 1) & 3 == 2 { 2 } else { 1 };
+        }
+        bars
+    }
+
+    /// Its capabilities: (ID, offset in configuration space) for each.
+    pub fn capabilities(&self) -> Capabilities {
+        let next = if self.addr.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.addr.read_u8(CAPABILITIES) & 0xFC
+        } else {
+            0
+        };
+        // A broken list could loop; there is room for at most 48.
+        Capabilities { addr: self.addr, next, left: 48 }
+    }
+
+    /// Switch it to MSI-X, with table entry `entry` sending `vector` to
+    /// the local APIC `apic_id`, and INTx off. The table is in one of its
+    /// BARs; it is mapped only while we write the entry.
+    pub fn route_msix(&self, entry: u16, vector: u8, apic_id: u8) -> Result<(), &'static str> {
+        let cap = self.capabilities().find(|&(id, _)| id == CAP_MSIX).map(|(_, offset)| offset).ok_or("no MSI-X")?;
+        let control = self.addr.read_u16(cap + 2);
+        if entry > control & 0x7FF {
+            return Err("no such MSI-X entry");
+        }
+        let table = self.addr.read_u32(cap + 4);
+        let Some(Bar::Memory { addr, .. }) = self.bar((table & 7) as u8) else {
+            return Err("MSI-X table not in a memory BAR");
+        };
+        let offset = (table & !7) as u64 + 16 * entry as u64;
+        let regs = map_mmio(PhysAddr::new(addr + offset), 16).map_err(|_| "cannot map the MSI-X table")?;
+        // Enabled but masked while the entry changes.
+        self.addr.write_u16(cap + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        let address = MSI_ADDRESS | (apic_id as u64) << 12;
+        regs.register::<u32>(0).set(address as u32);
+        regs.register::<u32>(4).set((address >> 32) as u32);
+        regs.register::<u32>(8).set(vector as u32);
+        regs.register::<u32>(12).set(0);
+        self.addr.write_u16(cap + 2, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+        self.addr.write_u16(COMMAND, self.addr.read_u16(COMMAND) | COMMAND_INTX_DISABLE);
+        Ok(())
+    }
+
+    /// What it is, by class, in a few words.
+    pub fn kind(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, 0x00) => "SCSI controller",
+            (0x01, _) => "storage controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia device",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "serial bus controller",
+            _ => "device",
+        }
+    }
+}
+
+pub struct Capabilities {
+    addr: Address,
+    next: u8,
+    left: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<(u8, u8)> {
+        if self.next == 0 || self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let offset = self.next;
+        self.next = self.addr.read_u8(offset + 1) & 0xFC;
+        Some((self.addr.read_u8(offset), offset))
+    }
+}
+
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+/// Scan every bus, slot and function, and log what is there. Brute force
+/// rather than following bridges: 256 buses of 32 slots is quick enough.
+pub fn init() {
+    let devices = DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        for bus in 0..=255 {
+            for device in 0..32 {
+                let first = Address { bus, device, function: 0 };
+                let Some(found) = Device::read(first) else { continue };
+                devices.push(found);
+                if first.read_u8(HEADER_TYPE) & 0x80 == 0 {
+                    continue;
+                }
+                for function in 1..8 {
+                    devices.extend(Device::read(Address { bus, device, function }));
+                }
+            }
+        }
+        devices
+    });
+    serial_println!("pci: {} functions", devices.len());
+}
+
+/// What `init` found.
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn list() {
+    for device in devices() {
+        let irq = device.interrupt_line.map(|line| alloc::format!(" irq {}", line)).unwrap_or_default();
+        serial_println!(
+            "  {} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} {}{}",
+            device.addr,
+            device.vendor,
+            device.device,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.kind(),
+            irq
+        );
+        for (index, bar) in device.bars() {
+            match bar {
+                Bar::Memory { addr, size, prefetchable } => serial_println!(
+                    "      BAR{}: memory {:#x}, {} KiB{}",
+                    index,
+                    addr,
+                    size / 1024,
+                    if prefetchable { ", prefetchable" } else { "" }
+                ),
+                Bar::Io { port, size } => serial_println!("      BAR{}: I/O {:#x}, {} ports", index, port, size),
+            }
+        }
+    }
+}
+
+/// There is a host bridge at 00:00.0, the scan saw each function once, and
+/// reading a BAR back leaves it as it was.
+pub fn self_test() -> bool {
+    let devices = devices();
+    let host = devices.first().is_some_and(|d| d.addr == Address { bus: 0, device: 0, function: 0 } && d.class == 0x06);
+    let unique = devices.iter().enumerate().all(|(i, d)| devices[..i].iter().all(|other| other.addr != d.addr));
+    let bars_kept = devices.iter().all(|device| {
+        let before: Vec<u32> = (0..6).map(|i| device.addr.read_u32(BAR0 + i * 4)).collect();
+        let _ = device.bars();
+        (0..6).all(|i| device.addr.read_u32(BAR0 + i * 4) == before[i as usize])
+    });
+    let caps_end = devices.iter().all(|device| device.capabilities().count() < 48);
+    host && unique && bars_kept && caps_end
+}
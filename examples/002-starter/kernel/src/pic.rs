@@ -0,0 +1,154 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Vector of the first legacy IRQ; 0..32 are CPU exceptions.
+pub const PIC1_OFFSET: u8 = 32;
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Legacy IRQ lines we handle, as IDT vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Irq {
+    Timer = PIC1_OFFSET,
+    Keyboard = PIC1_OFFSET + 1,
+    Com1 = PIC1_OFFSET + 4,
+}
+
+impl Irq {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    fn line(self) -> u8 {
+        self as u8 - PIC1_OFFSET
+    }
+
+    /// The variant for `line`, if it is one of ours.
+    pub fn from_line(line: u8) -> Option<Irq> {
+        [Irq::Timer, Irq::Keyboard, Irq::Com1].into_iter().find(|irq| irq.line() == line)
+    }
+}
+
+/// The slave PIC's line on the master.
+pub const CASCADE_LINE: u8 = 2;
+
+const READ_ISR: u8 = 0x0B;
+
+/// The two cascaded 8259 PICs.
+struct Pics {
+    /// Interrupt mask: bit n set = IRQ n disabled (low byte master, high byte slave).
+    mask: u16,
+}
+
+impl Pics {
+    /// Remap the PICs away from the exception vectors, with every IRQ masked.
+    unsafe fn init(&mut self) {
+        let mut cmd1: Port<u8> = Port::new(PIC1_COMMAND);
+        let mut data1: Port<u8> = Port::new(PIC1_DATA);
+        let mut cmd2: Port<u8> = Port::new(PIC2_COMMAND);
+        let mut data2: Port<u8> = Port::new(PIC2_DATA);
+        cmd1.write(ICW1_INIT);
+        io_wait();
+        cmd2.write(ICW1_INIT);
+        io_wait();
+        data1.write(PIC1_OFFSET);
+        io_wait();
+        data2.write(PIC2_OFFSET);
+        io_wait();
+        // Master has the slave on IRQ2; slave has cascade identity 2.
+        data1.write(4);
+        io_wait();
+        data2.write(2);
+        io_wait();
+        data1.write(ICW4_8086);
+        io_wait();
+        data2.write(ICW4_8086);
+        io_wait();
+
+        self.mask = !(1 << CASCADE_LINE);
+        self.write_mask();
+    }
+
+    unsafe fn write_mask(&mut self) {
+        Port::<u8>::new(PIC1_DATA).write(self.mask as u8);
+        Port::<u8>::new(PIC2_DATA).write((self.mask >> 8) as u8);
+    }
+
+    unsafe fn end_of_interrupt(&mut self, line: u8) {
+        if line >= 8 {
+            Port::<u8>::new(PIC2_COMMAND).write(END_OF_INTERRUPT);
+        }
+        Port::<u8>::new(PIC1_COMMAND).write(END_OF_INTERRUPT);
+    }
+}
+
+/// Port 0x80 is unused; writing to it gives the old PICs time to settle.
+unsafe fn io_wait() {
+    Port::<u8>::new(0x80).write(0);
+}
+
+static PICS: Mutex<Pics> = Mutex::new(Pics { mask: 0xFFFF });
+
+/// Remap and mask all legacy IRQs. Interrupts stay disabled on the CPU until `sti`.
+pub fn init() {
+    unsafe { PICS.lock().init() };
+}
+
+pub fn unmask(irq: Irq) {
+    unmask_line(irq.line());
+}
+
+/// Unmask IRQ `line` (0-15), for devices whose line is only known at run
+/// time, like a PCI device's.
+pub fn unmask_line(line: u8) {
+    // Handlers take the lock for EOI, so keep them out while we hold it.
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        pics.mask &= !(1 << line);
+        unsafe { pics.write_mask() };
+    });
+}
+
+/// Acknowledge `irq`; call at the end of its handler.
+pub fn end_of_interrupt(irq: Irq) {
+    end_of_line(irq.line());
+}
+
+pub fn end_of_line(line: u8) {
+    unsafe { PICS.lock().end_of_interrupt(line) };
+}
+
+/// Whether an interrupt on `line` is spurious: raised for a request that
+/// went away before the CPU took it. The PICs then signal their lowest
+/// priority line, 7 or 15, without setting it in service. Such an
+/// interrupt gets no EOI, but one on 15 came through the master's
+/// cascade line, which does (done here).
+pub fn spurious(line: u8) -> bool {
+    if line != 7 && line != 15 {
+        return false;
+    }
+    let command = if line == 7 { PIC1_COMMAND } else { PIC2_COMMAND };
+    let _pics = PICS.lock();
+    let in_service = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(READ_ISR);
+        port.read()
+    };
+    if in_service & 0x80 != 0 {
+        return false;
+    }
+    if line == 15 {
+        unsafe { Port::<u8>::new(PIC1_COMMAND).write(END_OF_INTERRUPT) };
+    }
+    true
+}
@@ -0,0 +1,99 @@
+use core::arch::asm;
+use x86_64::instructions::{hlt, interrupts, port::Port};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi;
+
+/// Restart the machine, trying progressively more brutal methods.
+pub fn reboot() -> ! {
+    // While drivers can still take their interrupts.
+    crate::block::cache::sync_all();
+    interrupts::disable();
+    crate::serial_println!("power: rebooting...");
+
+    // 1) Pulse the CPU reset line through the 8042 keyboard controller.
+    unsafe {
+        let mut status: Port<u8> = Port::new(0x64);
+        // Wait until the controller's input buffer is empty (bit 1)
+        for _ in 0..100_000 {
+            if status.read() & 0x02 == 0 { break; }
+        }
+        status.write(0xFE);
+    }
+    settle();
+
+    // 2) ACPI reset register from the FADT (0xCF9 on QEMU q35).
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(reg) = fadt.reset_reg {
+            reg.write_u8(fadt.reset_value);
+            settle();
+        }
+    }
+
+    // 3) Triple fault: load an empty IDT and raise an exception.
+    crate::serial_println!("power: reset failed, forcing triple fault");
+    unsafe {
+        let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+        x86_64::instructions::tables::lidt(&empty);
+        asm!("int3", options(nomem, nostack));
+    }
+
+    loop { hlt(); }
+}
+
+/// Power the machine off via ACPI S5, falling back to emulator shortcut ports.
+pub fn shutdown() -> ! {
+    crate::block::cache::sync_all();
+    interrupts::disable();
+    crate::serial_println!("power: shutting down...");
+
+    if let (Some(fadt), Some(s5)) = (acpi::fadt(), acpi::s5()) {
+        unsafe {
+            enable_acpi(fadt);
+            // PM1x_CNT: SLP_TYP in bits 10-12, SLP_EN is bit 13
+            write_sleep(fadt.pm1a_cnt, s5.a);
+            if fadt.pm1b_cnt != 0 {
+                write_sleep(fadt.pm1b_cnt, s5.b);
+            }
+        }
+        settle();
+    }
+
+    // Well-known "power off" ports: QEMU q35/newer, Bochs/older QEMU, VirtualBox.
+    crate::serial_println!("power: ACPI S5 failed, trying emulator ports");
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+
+    crate::serial_println!("power: could not power off, halting");
+    loop { hlt(); }
+}
+
+/// Switch the chipset from legacy to ACPI mode if firmware left it off.
+unsafe fn enable_acpi(fadt: &acpi::FadtInfo) {
+    const SCI_EN: u16 = 1;
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_cnt);
+    if pm1a.read() & SCI_EN != 0 || fadt.smi_cmd == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    Port::<u8>::new(fadt.smi_cmd).write(fadt.acpi_enable);
+    for _ in 0..1_000_000 {
+        if pm1a.read() & SCI_EN != 0 { break; }
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_sleep(port: u16, slp_typ: u8) {
+    const SLP_EN: u16 = 1 << 13;
+    let mut cnt: Port<u16> = Port::new(port);
+    let value = (cnt.read() & !(0b111 << 10)) | ((slp_typ as u16 & 0b111) << 10) | SLP_EN;
+    cnt.write(value);
+}
+
+/// Give the hardware a moment to act before trying the next method.
+fn settle() {
+    crate::time::busy_wait_us(100_000);
+}
@@ -0,0 +1,182 @@
+//! Preemption control. While a `Guard` from `disable` lives, the timer and
+//! wakeup IPIs leave the current thread running on this CPU; a switch they
+//! wanted meanwhile happens when the last guard is dropped, or earlier at a
+//! preemption point (`Guard::point`).
+//!
+//! The contract: code with preemption disabled must not sleep. It may be
+//! relying on staying on this CPU, and the depth is kept per CPU, so it would
+//! also leave the next thread here unpreemptible. Debug builds panic (with a
+//! backtrace) when `thread::block` or `thread::yield_now` is called anyway.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::smp::{self, MAX_CPUS};
+use crate::{serial_println, thread, time};
+
+/// Nesting depth of `disable` per CPU; preemptible at 0.
+static DEPTH: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+/// A tick or wakeup wanted to switch threads while preemption was disabled.
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+static POINTS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Preemption stays disabled on this CPU until it is dropped. Not `Send`: it
+/// must be dropped on the CPU (and thread) that made it.
+pub struct Guard {
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Disable preemption; nests.
+pub fn disable() -> Guard {
+    without_interrupts(|| DEPTH[smp::current()].fetch_add(1, Ordering::Relaxed));
+    Guard { _not_send: PhantomData }
+}
+
+/// Undo one `disable`; at depth 0, switch if a tick asked for it meanwhile.
+fn enable() {
+    let enabled = interrupts::are_enabled();
+    let resched = without_interrupts(|| {
+        let cpu = smp::current();
+        // With interrupts off the flag stays set; the next tick preempts anyway.
+        DEPTH[cpu].fetch_sub(1, Ordering::Relaxed) == 1 && enabled && NEED_RESCHED[cpu].swap(false, Ordering::Relaxed)
+    });
+    if resched {
+        thread::yield_now();
+    }
+}
+
+impl Guard {
+    /// A preemption point for long loops: if a switch is pending and this is
+    /// the only guard, let it happen now, then disable preemption again (maybe
+    /// on another CPU, so per-CPU state must be looked up afresh). Needs
+    /// interrupts on.
+    pub fn point(&self) {
+        let take = without_interrupts(|| {
+            let cpu = smp::current();
+            let take = DEPTH[cpu].load(Ordering::Relaxed) == 1 && NEED_RESCHED[cpu].swap(false, Ordering::Relaxed);
+            if take {
+                DEPTH[cpu].store(0, Ordering::Relaxed);
+            }
+            take
+        });
+        if take {
+            POINTS_TAKEN.fetch_add(1, Ordering::Relaxed);
+            thread::yield_now();
+            without_interrupts(|| DEPTH[smp::current()].fetch_add(1, Ordering::Relaxed));
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        enable();
+    }
+}
+
+/// Whether the running thread may be preempted. Call with interrupts off.
+pub fn is_enabled() -> bool {
+    DEPTH[smp::current()].load(Ordering::Relaxed) == 0
+}
+
+/// For the timer and the wakeup IPI (interrupts are off): whether to switch
+/// threads now. If preemption is disabled, the request is kept for later.
+pub fn may_preempt() -> bool {
+    if is_enabled() {
+        return true;
+    }
+    NEED_RESCHED[smp::current()].store(true, Ordering::Relaxed);
+    DEFERRED.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+/// Debug builds: panic if the current thread is about to sleep or yield with
+/// preemption disabled.
+#[cfg(debug_assertions)]
+#[track_caller]
+pub fn assert_may_sleep() {
+    let depth = DEPTH[smp::current()].load(Ordering::Relaxed);
+    if depth != 0 {
+        panic!(
+            "thread {} sleeping with preemption disabled (depth {}) at {}",
+            thread::current_id().0,
+            depth,
+            core::panic::Location::caller()
+        );
+    }
+}
+
+pub fn dump() {
+    let depths = without_interrupts(|| {
+        let mut depths = [0; MAX_CPUS];
+        for (depth, cpu) in depths.iter_mut().zip(&DEPTH) {
+            *depth = cpu.load(Ordering::Relaxed);
+        }
+        depths
+    });
+    serial_println!(
+        "preempt: {} switches deferred, {} taken at preemption points",
+        DEFERRED.load(Ordering::Relaxed),
+        POINTS_TAKEN.load(Ordering::Relaxed)
+    );
+    for (cpu, depth) in depths.iter().enumerate().filter(|(_, &depth)| depth != 0) {
+        serial_println!("  CPU {} has preemption disabled (depth {})", cpu, depth);
+    }
+}
+
+/// Spin for `ticks` timer ticks with preemption disabled; returns the ticks
+/// that hit while another thread ran (0 if nothing preempted us).
+fn spin_disabled(ticks: u64, point: bool) -> u64 {
+    let guard = disable();
+    let start = time::ticks();
+    let mut lost = 0;
+    let mut last = start;
+    while time::ticks() < start + ticks {
+        if point {
+            guard.point();
+        }
+        let now = time::ticks();
+        lost += now.saturating_sub(last + 1);
+        last = now;
+        core::hint::spin_loop();
+    }
+    drop(guard);
+    lost
+}
+
+/// A disabled section is never switched out, not even with a competing
+/// thread ready on this CPU; a preemption point in it lets that thread run.
+pub fn self_test() -> bool {
+    let stop = AtomicBool::new(false);
+    let here = smp::CpuMask::single(without_interrupts(smp::current));
+    thread::scope(|s| {
+        let competitor = thread::Builder::new().name("preempt-test").affinity(here).spawn_scoped(s, || {
+            while !stop.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        });
+        let Some(competitor) = competitor else { return false };
+        let _ = thread::set_affinity(thread::current_id(), here);
+
+        let deferred = DEFERRED.load(Ordering::Relaxed);
+        let lost_disabled = spin_disabled(5, false);
+        let held_off = DEFERRED.load(Ordering::Relaxed) > deferred;
+        let points = POINTS_TAKEN.load(Ordering::Relaxed);
+        spin_disabled(5, true);
+        let took_point = POINTS_TAKEN.load(Ordering::Relaxed) > points;
+        let still_running = !competitor.is_finished();
+
+        stop.store(true, Ordering::Relaxed);
+        let _ = thread::set_affinity(thread::current_id(), smp::CpuMask::ALL);
+        lost_disabled == 0 && held_off && took_point && still_running
+    })
+}
+
+/// Break the contract on purpose and panic.
+#[cfg(debug_assertions)]
+pub fn sleep_demo() {
+    let _guard = disable();
+    time::sleep(core::time::Duration::from_millis(10));
+}
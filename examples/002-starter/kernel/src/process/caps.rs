@@ -0,0 +1,85 @@
+//! Capabilities: what a process is allowed to ask the kernel for, beyond
+//! what every program can do. Each is a bit in a mask; a system call can be
+//! registered as needing some (see `syscall::register_needing`), and the
+//! dispatcher refuses it with EPERM to a process without them.
+//!
+//! A process has two masks: its own, and the one its children start with,
+//! never more than its own. Both can only shrink, except that the
+//! children's can grow back up to the process's own: a shell narrows it
+//! around a `spawn` to start a sandboxed program. Processes the kernel
+//! starts get what it gives them, all of them unless it says otherwise.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caps(u32);
+
+/// Each capability's bit and name.
+const NAMES: [(Caps, &str); 4] =
+    [(Caps::SPAWN, "spawn"), (Caps::RAW_IO, "raw-io"), (Caps::NET, "net"), (Caps::FS_WRITE, "fs-write")];
+
+impl Caps {
+    pub const NONE: Caps = Caps(0);
+    /// Start other programs (`spawn`).
+    pub const SPAWN: Caps = Caps(1 << 0);
+    /// Talk to devices directly. No system call needs it yet.
+    pub const RAW_IO: Caps = Caps(1 << 1);
+    /// Use the network. No system call needs it yet.
+    pub const NET: Caps = Caps(1 << 2);
+    /// Open files for writing.
+    pub const FS_WRITE: Caps = Caps(1 << 3);
+    pub const ALL: Caps = Caps(0xf);
+
+    /// The mask as the system calls pass it; bits that aren't capabilities
+    /// make it `None`.
+    pub fn from_bits(bits: u64) -> Option<Caps> {
+        (bits & !(Caps::ALL.0 as u64) == 0).then_some(Caps(bits as u32))
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, other: Caps) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Caps) -> Caps {
+        Caps(self.0 & other.0)
+    }
+
+    pub fn without(self, other: Caps) -> Caps {
+        Caps(self.0 & !other.0)
+    }
+
+    /// `all`, `none`, or names separated by commas (`spawn,net`).
+    pub fn parse(s: &str) -> Option<Caps> {
+        match s {
+            "all" => return Some(Caps::ALL),
+            "none" => return Some(Caps::NONE),
+            _ => {}
+        }
+        let mut caps = Caps::NONE;
+        for name in s.split(',') {
+            let (cap, _) = NAMES.iter().find(|(_, n)| *n == name)?;
+            caps.0 |= cap.0;
+        }
+        Some(caps)
+    }
+}
+
+impl fmt::Display for Caps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Caps::ALL => return f.write_str("all"),
+            Caps::NONE => return f.write_str("none"),
+            _ => {}
+        }
+        let mut first = true;
+        for (_, name) in NAMES.iter().filter(|(cap, _)| self.contains(*cap)) {
+            write!(f, "{}{}", if first { "" } else { "," }, name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! A process's open files, by descriptor number.
+//!
+//! A descriptor refers to an open file: `dup2` and `spawn` copy the
+//! reference, not the file, so the copies share its offset, as in Unix.
+//! Children start with a copy of their parent's table; `exec` keeps it.
+//! Descriptors marked close-on-exec are the exception: children don't get
+//! them and `exec` closes them.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::{vfs, FsError};
+use crate::ipc::pipe::{PipeReader, PipeWriter};
+use crate::sync::Mutex;
+
+/// Descriptors a process can have: 0..MAX_FILES.
+pub const MAX_FILES: u32 = 64;
+
+/// What a descriptor refers to.
+#[derive(Clone)]
+pub enum File {
+    /// The serial console.
+    Console,
+    /// A file of the VFS.
+    Vfs(Arc<OpenFile>),
+    /// The read end of a pipe.
+    PipeRead(Arc<PipeReader>),
+    /// The write end of a pipe.
+    PipeWrite(Arc<PipeWriter>),
+}
+
+impl File {
+    /// What `procs files` shows for it.
+    pub fn describe(&self) -> String {
+        match self {
+            File::Console => String::from("/dev/console"),
+            File::Vfs(file) => alloc::format!("{} @ {}", file.path, *file.offset.lock()),
+            File::PipeRead(end) => alloc::format!("pipe:[{}] (read end)", end.id()),
+            File::PipeWrite(end) => alloc::format!("pipe:[{}] (write end)", end.id()),
+        }
+    }
+}
+
+/// A file opened through the VFS: the file, whether it was opened for
+/// writing, and where the next read or write starts.
+pub struct OpenFile {
+    path: String,
+    file: Arc<dyn vfs::File>,
+    writable: bool,
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(path: &str, file: Arc<dyn vfs::File>, writable: bool) -> OpenFile {
+        OpenFile { path: String::from(path), file, writable, offset: Mutex::new(0) }
+    }
+
+    pub fn metadata(&self) -> vfs::Metadata {
+        self.file.metadata()
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Read into `buf` from the offset, and move the offset past what was
+    /// read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let read = self.file.read_at(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Write `data` at the offset, and move the offset past what was
+    /// written.
+    pub fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let written = self.file.write_at(*offset, data)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Move the offset to what `f` makes of the current one; it may go past
+    /// the end. `None` (and no move) if `f` gives none.
+    pub fn seek(&self, f: impl FnOnce(u64) -> Option<u64>) -> Option<u64> {
+        let mut offset = self.offset.lock();
+        *offset = f(*offset)?;
+        Some(*offset)
+    }
+}
+
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+    /// Bit `fd` is set if `fd` is close-on-exec.
+    cloexec: u64,
+}
+
+impl FileTable {
+    /// Standard input, output and error (0, 1 and 2) on the console.
+    pub fn with_console() -> Self {
+        FileTable { files: Vec::from([const { Some(File::Console) }; 3]), cloexec: 0 }
+    }
+
+    /// What a child starts with: this table without its close-on-exec
+    /// descriptors.
+    pub fn inherit(&self) -> Self {
+        let mut table = self.clone();
+        table.close_on_exec();
+        table
+    }
+
+    pub fn get(&self, fd: u32) -> Option<File> {
+        self.files.get(fd as usize).cloned().flatten()
+    }
+
+    /// Put `file` at the lowest free descriptor; `None` if all are taken.
+    pub fn insert(&mut self, file: File) -> Option<u32> {
+        let fd = self.files.iter().position(Option::is_none).unwrap_or(self.files.len());
+        if fd as u32 >= MAX_FILES {
+            return None;
+        }
+        self.set(fd as u32, file);
+        Some(fd as u32)
+    }
+
+    /// Make `fd` (below `MAX_FILES`) refer to `file`, closing what it did.
+    /// It is not close-on-exec, whatever it was before.
+    pub fn set(&mut self, fd: u32, file: File) {
+        self.cloexec &= !(1 << fd);
+        let fd = fd as usize;
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+    }
+
+    /// Mark open descriptor `fd` close-on-exec, or not.
+    pub fn set_cloexec(&mut self, fd: u32, cloexec: bool) {
+        if self.get(fd).is_some() {
+            if cloexec {
+                self.cloexec |= 1 << fd;
+            } else {
+                self.cloexec &= !(1 << fd);
+            }
+        }
+    }
+
+    pub fn is_cloexec(&self, fd: u32) -> bool {
+        fd < MAX_FILES && self.cloexec & (1 << fd) != 0
+    }
+
+    /// Close `fd`; the file it referred to, if any.
+    pub fn close(&mut self, fd: u32) -> Option<File> {
+        let file = self.files.get_mut(fd as usize)?.take();
+        self.cloexec &= !(1 << fd);
+        while self.files.last().is_some_and(Option::is_none) {
+            self.files.pop();
+        }
+        file
+    }
+
+    /// Close the close-on-exec descriptors, as `exec` does.
+    pub fn close_on_exec(&mut self) {
+        for fd in 0..MAX_FILES {
+            if self.is_cloexec(fd) {
+                self.close(fd);
+            }
+        }
+    }
+
+    /// Close everything, as at exit.
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.cloexec = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &File)> + '_ {
+        self.files.iter().enumerate().filter_map(|(fd, file)| Some((fd as u32, file.as_ref()?)))
+    }
+}
@@ -0,0 +1,793 @@
+//! Processes: what everything user-facing hangs off. A process owns an
+//! address space, a file table and the main thread that runs its program in
+//! ring 3, and is found by its PID in the process table.
+//!
+//! A process is Running or Sleeping as its main thread is runnable or
+//! blocked. When the program exits it becomes a Zombie: its memory and files
+//! are given back at once, but it stays in the table with its exit status
+//! until it is reaped, by its parent's `wait_child` (or, for processes the
+//! kernel started, by the kernel). When a parent exits first, its zombie
+//! children are reaped with it and the others become orphans, which reap
+//! themselves when they exit.
+//!
+//! A process can be sent signals (see `signal`): by `kill`, or by the kernel
+//! when its program faults. Unhandled, most terminate it.
+//!
+//! What system calls a process may make beyond the basic ones depends on
+//! its capabilities (see `caps`), fixed when it starts and only dropped
+//! after.
+//!
+//! A process can also filter its own system calls, for good: once it has,
+//! any call outside its allow-list kills it with SIGSYS, as Linux's seccomp
+//! does. Its children start with the same filter.
+//!
+//! A traced process has each of its system calls logged to serial, with
+//! the arguments and the result (see `syscall`). Its children are traced
+//! too, like `strace -f`; so are those of a process that asked for only its
+//! children to be.
+
+mod caps;
+mod files;
+pub mod signal;
+
+pub use caps::Caps;
+pub use files::{File, FileTable, OpenFile, MAX_FILES};
+use signal::Signals;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+use crate::fs::{file, vfs, FsError};
+use crate::memory::address_space::{self, AddressSpace};
+use crate::memory::vma::FaultOutcome;
+use crate::sync::{Mutex, WaitQueue};
+use crate::syscall::{nr, Errno};
+use crate::smp::{self, CpuMask};
+use crate::thread::{self, ThreadId};
+use crate::user::elf::{self, ElfError, Image};
+use crate::user::programs;
+use crate::{initrd, serial_println, time, user};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Its main thread is blocked.
+    Sleeping,
+    /// Exited with this status; waiting to be reaped.
+    Zombie(i32),
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Sleeping => "sleeping",
+            State::Zombie(_) => "zombie",
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    /// Its parent's PID; 0 if the kernel started it or the parent exited.
+    parent: AtomicU64,
+    /// The parent exited first: nobody will reap it.
+    orphaned: AtomicBool,
+    /// The program it runs; changes with `exec`.
+    name: Mutex<String>,
+    /// `None` once it has exited.
+    space: Mutex<Option<AddressSpace>>,
+    files: Mutex<FileTable>,
+    signals: Signals,
+    /// Its capabilities, and those its children start with: `Caps` bits.
+    caps: AtomicU32,
+    child_caps: AtomicU32,
+    /// The system calls it may make, a bit per number: all of them until it
+    /// filters them.
+    syscalls: AtomicU64,
+    /// Log its system calls; start its children traced.
+    traced: AtomicBool,
+    trace_children: AtomicBool,
+    main: Once<ThreadId>,
+    status: Once<i32>,
+    exited: WaitQueue,
+    /// Children that exited so far, and where `wait_child` waits for more.
+    child_exits: AtomicU64,
+    child_exited: WaitQueue,
+}
+
+/// PIDs are never reused; 0 means "no process".
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+/// The process the running thread belongs to; `None` for kernel threads.
+#[thread_local]
+static CURRENT: RefCell<Option<Arc<Process>>> = RefCell::new(None);
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// No such file.
+    NotFound,
+    /// The file couldn't be read.
+    File(FsError),
+    Elf(ElfError),
+    /// Out of memory or thread slots.
+    OutOfMemory,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::NotFound => write!(f, "no such file"),
+            SpawnError::File(err) => write!(f, "{}", err),
+            SpawnError::Elf(err) => write!(f, "{}", err),
+            SpawnError::OutOfMemory => write!(f, "out of memory or thread slots"),
+        }
+    }
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        match self.parent.load(Ordering::Relaxed) {
+            0 => None,
+            pid => Some(Pid(pid)),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn state(&self) -> State {
+        if let Some(&status) = self.status.get() {
+            return State::Zombie(status);
+        }
+        match self.main.get().and_then(|&id| thread::state(id)) {
+            Some(thread::State::Blocked) => State::Sleeping,
+            _ => State::Running,
+        }
+    }
+
+    pub fn file(&self, fd: u32) -> Option<File> {
+        self.files.lock().get(fd)
+    }
+
+    /// How many descriptors it has open.
+    pub fn file_count(&self) -> usize {
+        self.files.lock().iter().count()
+    }
+
+    /// Its main thread; `None` until it has one.
+    pub fn main_thread(&self) -> Option<ThreadId> {
+        self.main.get().copied()
+    }
+
+    pub fn caps(&self) -> Caps {
+        Caps::from_bits(self.caps.load(Ordering::Relaxed) as u64).expect("valid caps")
+    }
+
+    /// Whether its filter lets it make system call `nr`.
+    pub fn allows_syscall(&self, nr: usize) -> bool {
+        nr < 64 && self.syscalls.load(Ordering::Relaxed) & 1 << nr != 0
+    }
+
+    /// Keep only the system calls whose bits are set in `allowed`, for good.
+    pub fn restrict_syscalls(&self, allowed: u64) {
+        self.syscalls.fetch_and(allowed, Ordering::Relaxed);
+    }
+
+    /// Whether it has filtered its system calls.
+    pub fn is_filtered(&self) -> bool {
+        self.syscalls.load(Ordering::Relaxed) != u64::MAX
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.traced.load(Ordering::Relaxed)
+    }
+
+    /// Trace the process itself, or not, and the children it starts from
+    /// now on (which a traced process's are anyway).
+    pub fn set_traced(&self, traced: bool, children: bool) {
+        self.traced.store(traced, Ordering::Relaxed);
+        self.trace_children.store(children, Ordering::Relaxed);
+    }
+
+    /// Whether the children it starts now are traced.
+    fn traces_children(&self) -> bool {
+        self.is_traced() || self.trace_children.load(Ordering::Relaxed)
+    }
+
+    /// What its children start with.
+    pub fn child_caps(&self) -> Caps {
+        Caps::from_bits(self.child_caps.load(Ordering::Relaxed) as u64).expect("valid caps")
+    }
+
+    /// Keep only the capabilities in `keep`, for good; the children's are
+    /// cut down to match.
+    pub fn drop_caps(&self, keep: Caps) {
+        self.caps.fetch_and(keep.bits() as u32, Ordering::Relaxed);
+        self.child_caps.fetch_and(keep.bits() as u32, Ordering::Relaxed);
+    }
+
+    /// Start children with `caps`; false (and no change) if that is more
+    /// than the process has itself.
+    pub fn set_child_caps(&self, caps: Caps) -> bool {
+        if !self.caps().contains(caps) {
+            return false;
+        }
+        self.child_caps.store(caps.bits() as u32, Ordering::Relaxed);
+        true
+    }
+
+    /// Run `f` on the descriptor table.
+    pub fn with_files<R>(&self, f: impl FnOnce(&mut FileTable) -> R) -> R {
+        f(&mut self.files.lock())
+    }
+
+    /// Run `f` on the address space; `None` once the process has exited.
+    pub fn with_space<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+        self.space.lock().as_mut().map(f)
+    }
+
+    /// Block until the program has exited, and get its status.
+    pub fn wait_exit(&self) -> i32 {
+        self.exited.wait_until(|| self.status.get().is_some());
+        *self.status.get().expect("woken before exit")
+    }
+
+    /// Wait for child `which` (any child if `None`) to exit, reap it, and
+    /// return its PID and exit status; `None` if there is no such child.
+    /// Without `block`, `Some(None)` if none has exited yet.
+    pub fn wait_child(&self, which: Option<Pid>, block: bool) -> Option<Option<(Pid, i32)>> {
+        loop {
+            let seen = {
+                let mut processes = PROCESSES.lock();
+                let children: Vec<&Arc<Process>> = processes
+                    .values()
+                    .filter(|p| p.parent() == Some(self.pid) && which.is_none_or(|pid| p.pid == pid))
+                    .collect();
+                if children.is_empty() {
+                    return None;
+                }
+                let zombie = children.iter().find_map(|child| Some((child.pid, *child.status.get()?)));
+                if let Some((pid, status)) = zombie {
+                    processes.remove(&pid);
+                    return Some(Some((pid, status)));
+                }
+                if !block {
+                    return Some(None);
+                }
+                // Read under the lock an exiting child takes to count itself.
+                self.child_exits.load(Ordering::Relaxed)
+            };
+            self.child_exited.wait_until(|| self.child_exits.load(Ordering::Relaxed) != seen);
+        }
+    }
+
+    /// Replace the program with the ELF executable at `path` in the VFS,
+    /// run with `args` and `env`, and return where it starts. Only for the process's
+    /// own thread, which this switches to the new address space. Its
+    /// close-on-exec descriptors are closed. On error the old program is
+    /// untouched.
+    pub fn exec(&self, path: &str, args: &[&str], env: &[&str]) -> Result<Image, SpawnError> {
+        let image = read_program(path)?;
+        let mut space = AddressSpace::new().ok_or(SpawnError::OutOfMemory)?;
+        let loaded = elf::load(&image, &mut space, args, env).map_err(SpawnError::Elf)?;
+        space.switch();
+        // Freed here: it is no longer loaded.
+        let old = self.space.lock().replace(space);
+        drop(old);
+        *self.name.lock() = String::from(path);
+        self.files.lock().close_on_exec();
+        self.signals.reset_handlers();
+        Ok(loaded)
+    }
+
+    pub fn signals(&self) -> &Signals {
+        &self.signals
+    }
+
+    /// Become a zombie: free the memory and files, keep `status`, and tell
+    /// the parent. Children are orphaned.
+    fn exit(&self, status: i32) {
+        self.space.lock().take();
+        self.files.lock().clear();
+        let parent = {
+            // Under the table lock, so a child exiting meanwhile is either
+            // reaped here or sees it is an orphan.
+            let mut processes = PROCESSES.lock();
+            let children: Vec<Arc<Process>> =
+                processes.values().filter(|p| p.parent() == Some(self.pid)).cloned().collect();
+            for child in children {
+                child.parent.store(0, Ordering::Relaxed);
+                child.orphaned.store(true, Ordering::Relaxed);
+                if child.status.get().is_some() {
+                    processes.remove(&child.pid);
+                }
+            }
+            self.status.call_once(|| status);
+            if self.orphaned.load(Ordering::Relaxed) {
+                processes.remove(&self.pid);
+            }
+            let parent = self.parent().and_then(|pid| processes.get(&pid).cloned());
+            if let Some(parent) = &parent {
+                parent.child_exits.fetch_add(1, Ordering::Relaxed);
+            }
+            parent
+        };
+        if let Some(parent) = parent {
+            parent.child_exited.notify_all();
+        }
+        self.exited.notify_all();
+    }
+}
+
+/// Run the first user program, `/bin/init` (`init=<path>` on the kernel
+/// command line for another, `init=none` for none), and wait for it: the
+/// kernel shell only starts once it has exited. Nothing happens if there
+/// is no such file.
+pub fn run_init() {
+    let path = crate::cmdline::get("init").unwrap_or("/bin/init");
+    if path == "none" {
+        return;
+    }
+    if file::stat(path).is_err() {
+        return serial_println!("init: no {}", path);
+    }
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match spawn(path, &[name]) {
+        Ok(init) => {
+            serial_println!("init: started {} (pid {})", path, init.pid());
+            let status = init.wait_exit();
+            reap(init.pid());
+            serial_println!("init: exited with status {}", status);
+        }
+        Err(err) => serial_println!("init: {}: {}", path, err),
+    }
+}
+
+/// The environment of the programs the kernel starts itself.
+pub const DEFAULT_ENV: &[&str] = &["PATH=/bin"];
+
+/// Start the ELF executable at `path` in the VFS in a new process, with
+/// `args` as its argv (by convention, `args[0]` is the program's name) and
+/// `DEFAULT_ENV`.
+pub fn spawn(path: &str, args: &[&str]) -> Result<Arc<Process>, SpawnError> {
+    spawn_env(path, args, DEFAULT_ENV)
+}
+
+/// `spawn` with `env` ("KEY=value" strings) as the environment.
+pub fn spawn_env(path: &str, args: &[&str], env: &[&str]) -> Result<Arc<Process>, SpawnError> {
+    let image = read_program(path)?;
+    start(path, &image, args, env, inherited_caps())
+}
+
+/// The whole of executable `path`, for `elf::load`.
+fn read_program(path: &str) -> Result<Vec<u8>, SpawnError> {
+    file::read(path).map_err(|err| match err {
+        FsError::NotFound => SpawnError::NotFound,
+        err => SpawnError::File(err),
+    })
+}
+
+/// Load the ELF executable `image` into a new process, with `DEFAULT_ENV`,
+/// and start its main thread. The caller's process, if any, is its parent.
+pub fn spawn_image(name: &str, image: &[u8], args: &[&str]) -> Result<Arc<Process>, SpawnError> {
+    start(name, image, args, DEFAULT_ENV, inherited_caps())
+}
+
+/// `spawn_image` with no more capabilities than `caps`.
+pub fn spawn_image_caps(name: &str, image: &[u8], args: &[&str], caps: Caps) -> Result<Arc<Process>, SpawnError> {
+    start(name, image, args, DEFAULT_ENV, inherited_caps().intersection(caps))
+}
+
+/// What a process started now gets: its parent's children's capabilities,
+/// or all of them if the kernel starts it.
+fn inherited_caps() -> Caps {
+    current().map_or(Caps::ALL, |parent| parent.child_caps())
+}
+
+fn start(name: &str, image: &[u8], args: &[&str], env: &[&str], caps: Caps) -> Result<Arc<Process>, SpawnError> {
+    let mut space = AddressSpace::new().ok_or(SpawnError::OutOfMemory)?;
+    let loaded = elf::load(image, &mut space, args, env).map_err(SpawnError::Elf)?;
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+    let process = Arc::new(Process {
+        pid,
+        parent: AtomicU64::new(current().map_or(0, |parent| parent.pid.0)),
+        orphaned: AtomicBool::new(false),
+        name: Mutex::new(String::from(name)),
+        space: Mutex::new(Some(space)),
+        files: Mutex::new(current().map_or_else(FileTable::with_console, |parent| parent.files.lock().inherit())),
+        signals: Signals::new(),
+        caps: AtomicU32::new(caps.bits() as u32),
+        child_caps: AtomicU32::new(caps.bits() as u32),
+        syscalls: AtomicU64::new(current().map_or(u64::MAX, |parent| parent.syscalls.load(Ordering::Relaxed))),
+        traced: AtomicBool::new(current().is_some_and(|parent| parent.traces_children())),
+        trace_children: AtomicBool::new(false),
+        main: Once::new(),
+        status: Once::new(),
+        exited: WaitQueue::new(),
+        child_exits: AtomicU64::new(0),
+        child_exited: WaitQueue::new(),
+    });
+    PROCESSES.lock().insert(pid, process.clone());
+    let theirs = process.clone();
+    let (entry, stack) = (loaded.entry, loaded.stack_pointer);
+    match thread::Builder::new().name("user").spawn(move || run_main(theirs, entry, stack)) {
+        // Detached: the process, not a join, says when it is done.
+        Some(handle) => {
+            process.main.call_once(|| handle.id());
+        }
+        None => {
+            PROCESSES.lock().remove(&pid);
+            return Err(SpawnError::OutOfMemory);
+        }
+    }
+    Ok(process)
+}
+
+/// The main thread: run the program until it exits.
+fn run_main(process: Arc<Process>, entry: VirtAddr, stack: VirtAddr) {
+    CURRENT.replace(Some(process.clone()));
+    if let Some(space) = process.space.lock().as_ref() {
+        space.switch();
+    }
+    let status = unsafe { user::run(entry, stack) };
+    address_space::switch_to_kernel();
+    CURRENT.take();
+    process.exit(status as i32);
+}
+
+/// Send `signal` to process `pid`, and wake its main thread in case it is
+/// in an interruptible sleep (`nanosleep`). False if there is no such
+/// process, or it has exited.
+pub fn kill(pid: Pid, signal: u32) -> bool {
+    match get(pid) {
+        Some(process) if process.status.get().is_none() => {
+            process.signals.send(signal);
+            if let Some(&id) = process.main.get() {
+                without_interrupts(|| thread::unblock(id));
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The calling thread's process.
+pub fn current() -> Option<Arc<Process>> {
+    CURRENT.borrow().clone()
+}
+
+/// Whether the calling thread may make system call `nr`: kernel threads
+/// may make all.
+pub fn allows_syscall(nr: usize) -> bool {
+    current().is_none_or(|process| process.allows_syscall(nr))
+}
+
+/// Whether the calling thread may use `caps`: kernel threads may use all.
+pub fn permits(caps: Caps) -> bool {
+    current().is_none_or(|process| process.caps().contains(caps))
+}
+
+/// A page fault at a user address, from the calling process's program or
+/// from the kernel touching its memory for it: demand paging if the address
+/// is in one of its regions. Call with interrupts on.
+pub fn handle_page_fault(addr: VirtAddr, code: PageFaultErrorCode) -> FaultOutcome {
+    current()
+        .and_then(|process| process.with_space(|space| space.handle_fault(addr, code)))
+        .unwrap_or(FaultOutcome::Unmapped)
+}
+
+/// The calling process's file `fd`. User code run straight from a kernel
+/// thread (the kernel's own tests) has no process, and gets the console as
+/// 0, 1 and 2.
+pub fn file(fd: u32) -> Option<File> {
+    match current() {
+        Some(process) => process.file(fd),
+        None => (fd <= 2).then_some(File::Console),
+    }
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Every process in the table, zombies included, by PID.
+pub fn all() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Remove zombie `pid` from the table and return its exit status; `None`
+/// if there is no such process or it hasn't exited.
+pub fn reap(pid: Pid) -> Option<i32> {
+    let mut processes = PROCESSES.lock();
+    let State::Zombie(status) = processes.get(&pid)?.state() else { return None };
+    processes.remove(&pid);
+    Some(status)
+}
+
+pub fn list() {
+    let processes = all();
+    if processes.is_empty() {
+        return serial_println!("processes: none");
+    }
+    serial_println!("  pid  ppid  state     thread  files  caps     name");
+    for process in processes {
+        let state = process.state();
+        let parent = process.parent.load(Ordering::Relaxed);
+        let thread = process.main_thread().map_or(0, |id| id.0);
+        let files = process.file_count();
+        let caps = alloc::format!("{}", process.caps());
+        let mut name = process.name();
+        if process.is_filtered() {
+            name.push_str(" (filtered)");
+        }
+        if process.is_traced() {
+            name.push_str(" (traced)");
+        }
+        match state {
+            State::Zombie(status) => serial_println!(
+                "  {:>3}  {:>4}  {:<8}  {:>6}  {:>5}  {:<7}  {} (exit status {})",
+                process.pid, parent, state.name(), thread, files, caps, name, status
+            ),
+            _ => serial_println!(
+                "  {:>3}  {:>4}  {:<8}  {:>6}  {:>5}  {:<7}  {}",
+                process.pid, parent, state.name(), thread, files, caps, name
+            ),
+        }
+    }
+}
+
+/// The open descriptors of process `pid`.
+pub fn list_files(pid: Pid) {
+    let Some(process) = get(pid) else {
+        return serial_println!("no process {}", pid.0);
+    };
+    let files: Vec<(u32, String)> = {
+        let table = process.files.lock();
+        table
+            .iter()
+            .map(|(fd, file)| {
+                let flag = if table.is_cloexec(fd) { " (close-on-exec)" } else { "" };
+                (fd, alloc::format!("{}{}", file.describe(), flag))
+            })
+            .collect()
+    };
+    if files.is_empty() {
+        return serial_println!("process {}: no open files", pid.0);
+    }
+    serial_println!("  fd  file");
+    for (fd, file) in files {
+        serial_println!("  {:>2}  {}", fd, file);
+    }
+}
+
+/// Keep the main threads of `processes` on the calling thread's CPU, so
+/// they take turns on it.
+fn share_cpu(processes: &[&Arc<Process>]) {
+    let cpu = CpuMask::single(smp::current());
+    for process in processes {
+        if let Some(&id) = process.main.get() {
+            thread::set_affinity(id, cpu);
+        }
+    }
+}
+
+/// Two programs printing a letter at a time on one CPU: the timer switches
+/// between them, so the letters interleave.
+pub fn demo() {
+    let (Ok(a), Ok(b)) = (spawn("/bin/ticker", &["ticker", "a"]), spawn("/bin/ticker", &["ticker", "b"])) else {
+        return serial_println!("processes: can't start /bin/ticker");
+    };
+    share_cpu(&[&a, &b]);
+    for process in [a, b] {
+        process.wait_exit();
+        reap(process.pid());
+    }
+    serial_println!();
+}
+
+/// Run a program that starts another (`programs::orphan_test`) twice: with
+/// every capability, then sandboxed, without SPAWN, where the dispatcher
+/// refuses its spawn. Its exit status is what spawn returned. `None` if one
+/// couldn't start.
+fn sandbox() -> Option<[i32; 2]> {
+    let mut statuses = [0; 2];
+    for (status, caps) in statuses.iter_mut().zip([Caps::ALL, Caps::ALL.without(Caps::SPAWN)]) {
+        let process = spawn_image_caps("sandbox", &programs::orphan_test(), &["sandbox"], caps).ok()?;
+        *status = process.wait_exit();
+        reap(process.pid());
+    }
+    Some(statuses)
+}
+
+pub fn sandbox_demo() {
+    let Some([trusted, sandboxed]) = sandbox() else {
+        return serial_println!("sandbox: can't start the program");
+    };
+    serial_println!("sandbox: with all capabilities, spawn returned {} (the child's PID)", trusted);
+    serial_println!("sandbox: without spawn, it returned {} (-EPERM)", sandboxed);
+}
+
+/// Crash a program each way in `programs::CRASHES`: each dies of its
+/// signal with a message saying what it did, and the rest of the system
+/// carries on. The statuses, in order; `None` if one couldn't start.
+fn crash_all() -> Option<Vec<i32>> {
+    let mut statuses = Vec::new();
+    for (name, instructions, _) in programs::CRASHES {
+        let process = spawn_image(name, &programs::crash(instructions), &[name]).ok()?;
+        statuses.push(process.wait_exit());
+        reap(process.pid());
+    }
+    Some(statuses)
+}
+
+pub fn crash_demo() {
+    let Some(statuses) = crash_all() else {
+        return serial_println!("crash: can't start the programs");
+    };
+    for ((name, _, _), status) in programs::CRASHES.iter().zip(statuses) {
+        serial_println!("crash: {} exited with status {}", name, status);
+    }
+}
+
+/// Two processes get their own PIDs and see them with getpid, turn into
+/// zombies holding their exit status, and leave the table when reaped. An
+/// exec replaces the program and its arguments but keeps the PID; one that
+/// fails returns an error to the old program. A parent waits for and reaps
+/// its child; one that doesn't leaves an orphan that reaps itself, and one
+/// without the SPAWN capability can't start a child at all. One that
+/// filtered its system calls is killed by the first it left out. A
+/// program can grow its heap and map memory, paged in as it touches it. A
+/// signal handler runs and returns to where the program was, an ignored
+/// signal does nothing, a bad pointer is a SIGSEGV (fatal unless caught)
+/// and SIGKILL stops a program that never makes a system call. `nanosleep`
+/// sleeps as long as asked, unless a signal cuts it short. Descriptors
+/// copied by `dup2` or inherited share the file's offset, close-on-exec
+/// ones aren't inherited, and a program can open, seek in, read and close
+/// an initrd file. A fault of any kind kills
+/// only the program, in a process or not. Two programs busy on
+/// one CPU each keep their own SSE registers. Missing and broken binaries
+/// leave nothing behind.
+pub fn self_test() -> bool {
+    let (Ok(a), Ok(b)) = (spawn("/bin/getpid", &["getpid"]), spawn("/bin/getpid", &["getpid"])) else { return false };
+    let mut ok = a.pid() != b.pid() && a.parent().is_none();
+    for process in [&a, &b] {
+        let status = process.wait_exit();
+        ok &= status as u64 == process.pid().0
+            && process.state() == State::Zombie(status)
+            && process.file(1).is_none()
+            && get(process.pid()).is_some()
+            && reap(process.pid()) == Some(status)
+            && get(process.pid()).is_none();
+    }
+
+    let exec_ok = programs::exec("/bin/argc", &["argc", "one", "two"]);
+    let exec_missing = programs::exec("/bin/missing", &["missing"]);
+    let (Ok(replaced), Ok(kept)) = (spawn_image("exec", &exec_ok, &["exec"]), spawn_image("exec", &exec_missing, &["exec"]))
+    else {
+        return false;
+    };
+    ok &= replaced.wait_exit() == 3 && replaced.name() == "/bin/argc" && reap(replaced.pid()) == Some(3);
+    ok &= kept.wait_exit() == -(Errno::ENOENT as i32) && kept.name() == "exec" && reap(kept.pid()).is_some();
+
+    let (Ok(waiter), Ok(orphaner)) =
+        (spawn_image("wait", &programs::wait_test(), &["wait"]), spawn_image("orphan", &programs::orphan_test(), &["orphan"]))
+    else {
+        return false;
+    };
+    ok &= waiter.wait_exit() == 13 && reap(waiter.pid()) == Some(13);
+    let orphan = Pid(orphaner.wait_exit() as u64);
+    ok &= reap(orphaner.pid()).is_some();
+    if let Some(orphan) = get(orphan) {
+        ok &= orphan.parent().is_none();
+        orphan.wait_exit();
+    }
+    ok &= get(orphan).is_none();
+
+    let Some([trusted, sandboxed]) = sandbox() else { return false };
+    ok &= trusted > 0 && sandboxed == -(Errno::EPERM as i32);
+    if let Some(orphan) = get(Pid(trusted as u64)) {
+        orphan.wait_exit();
+    }
+    ok &= Caps::parse("fs-write,spawn").is_some_and(|caps| alloc::format!("{}", caps) == "spawn,fs-write");
+    ok &= Caps::parse("disk").is_none() && Caps::from_bits(1 << 8).is_none();
+
+    let Ok(filtered) = spawn_image("filter", &programs::filter_test(), &["filter"]) else { return false };
+    let killed = 128 + signal::SIGSYS as i32;
+    ok &= filtered.wait_exit() == killed && filtered.is_filtered() && !filtered.allows_syscall(nr::UPTIME);
+    ok &= reap(filtered.pid()) == Some(killed);
+
+    let Ok(memory) = spawn_image("memory", &programs::memory_test(), &["memory"]) else { return false };
+    ok &= memory.wait_exit() == 8334 && reap(memory.pid()) == Some(8334);
+
+    let (Ok(handled), Ok(caught), Ok(faulted), Ok(spinner)) = (
+        spawn_image("signal", &programs::signal_test(), &["signal"]),
+        spawn_image("segv", &programs::segv_test(true), &["segv"]),
+        spawn_image("segv", &programs::segv_test(false), &["segv"]),
+        spawn_image("spin", &programs::spin(), &["spin"]),
+    ) else {
+        return false;
+    };
+    let killed = 128 + signal::SIGKILL as i32;
+    ok &= kill(spinner.pid(), signal::SIGKILL) && spinner.wait_exit() == killed && reap(spinner.pid()) == Some(killed);
+    ok &= !kill(spinner.pid(), signal::SIGTERM);
+    for (process, status) in [(handled, 15), (caught, 111), (faulted, 128 + signal::SIGSEGV as i32)] {
+        ok &= process.wait_exit() == status && reap(process.pid()) == Some(status);
+    }
+
+    let start = time::ticks();
+    let (Ok(napper), Ok(sleeper)) =
+        (spawn_image("sleep", &programs::sleep_test(30), &["sleep"]), spawn_image("sleep", &programs::sleep_test(10_000), &["sleep"]))
+    else {
+        return false;
+    };
+    ok &= napper.wait_exit() == 0 && reap(napper.pid()) == Some(0) && (3..=6).contains(&(time::ticks() - start));
+    let terminated = 128 + signal::SIGTERM as i32;
+    ok &= kill(sleeper.pid(), signal::SIGTERM) && sleeper.wait_exit() == terminated && reap(sleeper.pid()) == Some(terminated);
+    ok &= time::ticks() - start < 20;
+
+    let hello = initrd::read("/bin/hello").map_or(0, |data| data.len() as i32);
+    let Ok(reader) = spawn_image("files", &programs::file_test("/bin/hello"), &["files"]) else { return false };
+    // The second byte of an ELF file is 'E'; reading a closed descriptor is EBADF.
+    let expected = b'E' as i32 + (hello << 8) - ((Errno::EBADF as i32) << 24);
+    ok &= reader.wait_exit() == expected && reap(reader.pid()) == Some(expected);
+    let mut table = FileTable::with_console();
+    let fd = vfs::open("/bin/hello").ok().and_then(|file| table.insert(File::Vfs(Arc::new(OpenFile::new("/bin/hello", file, false)))));
+    let copy = table.clone();
+    let read = |table: &FileTable| match fd.and_then(|fd| table.get(fd)) {
+        Some(File::Vfs(file)) => file.read(&mut [0u8; 2]).ok(),
+        _ => None,
+    };
+    ok &= fd == Some(3) && read(&table) == Some(2) && read(&copy) == Some(2);
+    ok &= matches!(fd.and_then(|fd| copy.get(fd)), Some(File::Vfs(file)) if file.seek(Some) == Some(4));
+    ok &= fd.and_then(|fd| table.close(fd)).is_some() && table.iter().count() == 3 && copy.iter().count() == 4;
+    table.set_cloexec(0, true);
+    let child = table.inherit();
+    ok &= table.is_cloexec(0) && child.get(0).is_none() && child.get(1).is_some();
+    table.set(0, File::Console);
+    ok &= !table.is_cloexec(0) && table.inherit().iter().count() == 3;
+
+    let crashed = programs::CRASHES.map(|(_, _, signal)| 128 + signal as i32);
+    ok &= crash_all().is_some_and(|statuses| statuses == crashed);
+    ok &= user::run_code(&[0xfa]) == Some(128 + signal::SIGSEGV as i64);
+
+    let (Ok(x), Ok(y)) = (spawn_image("fpu", &programs::fpu_test(0x1111), &[]), spawn_image("fpu", &programs::fpu_test(0x2222), &[]))
+    else {
+        return false;
+    };
+    share_cpu(&[&x, &y]);
+    for process in [x, y] {
+        ok &= process.wait_exit() == 1 && reap(process.pid()) == Some(1);
+    }
+
+    let before = PROCESSES.lock().len();
+    let missing = spawn("/bin/missing", &[]);
+    let broken = spawn_image("broken", &exec_ok[..32], &[]);
+    ok && matches!(missing, Err(SpawnError::NotFound))
+        && matches!(broken, Err(SpawnError::Elf(ElfError::Truncated(_))))
+        && PROCESSES.lock().len() == before
+}
@@ -0,0 +1,261 @@
+//! Signals: a small number sent to a process, by another one (`kill`) or by
+//! the kernel when the program faults. Each is pending until the process is
+//! about to return to ring 3 (from a system call, a fault or a timer
+//! interrupt), and is acted on there, as its action says:
+//!
+//! - Default: the process is terminated, with exit status 128 + the signal.
+//! - Ignore: nothing happens.
+//! - Handle: the program's handler runs. The user's registers are pushed on
+//!   its stack below the red zone, under the address of its restorer, and it
+//!   starts at the handler with the signal number in RDI; when it returns,
+//!   the restorer calls `sigreturn`, which puts the registers back. The
+//!   x87/SSE registers are not saved: a handler must not change them.
+//!
+//! SIGKILL can't be handled or ignored. A fault's signal can't be ignored
+//! either: the instruction would only fault again. When a fault kills a
+//! process, the message says what it did (`Fault`). A process blocked in the
+//! kernel only acts on a signal once it gets back towards ring 3; `nanosleep`
+//! and waits on a pipe are cut short for it, other waits are not.
+
+use core::fmt;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+use super::current;
+use crate::memory::address_space::{USER_END, USER_START};
+use crate::serial_println;
+use crate::syscall::Errno;
+use crate::user::uaccess::{self, UserPtr};
+use crate::user::{self, TrapFrame};
+
+pub const SIGINT: u32 = 2;
+pub const SIGILL: u32 = 4;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGTERM: u32 = 15;
+pub const SIGSYS: u32 = 31;
+/// Signals are 1..NSIG.
+pub const NSIG: u32 = 32;
+
+/// What `sigaction` takes as the handler for the default action and for ignoring.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// RFLAGS bits a handler (or a forged frame) may hand back to `sigreturn`:
+/// the arithmetic flags and DF.
+const USER_FLAGS: u64 = 0xcd5;
+/// Kept free above the interrupted stack pointer: code may use the 128
+/// bytes below it without moving RSP.
+const RED_ZONE: u64 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    Handle { handler: u64, restorer: u64 },
+}
+
+/// A fault in ring 3: which exception, where, and at what instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub what: &'static str,
+    /// The address it touched, for a page fault.
+    pub addr: Option<u64>,
+    /// Why the access was refused; may be empty.
+    pub reason: &'static str,
+    pub rip: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.what)?;
+        if let Some(addr) = self.addr {
+            write!(f, " at {:#x}", addr)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, " ({})", self.reason)?;
+        }
+        write!(f, ", rip {:#x}", self.rip)
+    }
+}
+
+/// A process's signals: the pending set and what to do with each one.
+pub struct Signals {
+    /// Bit `n` is signal `n`.
+    pending: AtomicU32,
+    actions: Mutex<[Action; NSIG as usize]>,
+    /// The last fault and the signal it raised, until that is acted on.
+    fault: Mutex<Option<(u32, Fault)>>,
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            pending: AtomicU32::new(0),
+            actions: Mutex::new([Action::Default; NSIG as usize]),
+            fault: Mutex::new(None),
+        }
+    }
+
+    /// Make `signal` pending; sending it twice before it is acted on is the
+    /// same as once.
+    pub fn send(&self, signal: u32) {
+        self.pending.fetch_or(1 << signal, Ordering::Release);
+    }
+
+    /// Set the action for `signal`, returning the old one.
+    pub fn set_action(&self, signal: u32, action: Action) -> Result<Action, Errno> {
+        if !valid(signal) || signal == SIGKILL {
+            return Err(Errno::EINVAL);
+        }
+        Ok(core::mem::replace(&mut self.actions.lock()[signal as usize], action))
+    }
+
+    /// Back to the default for every handled signal, as `exec` does: the
+    /// handlers were in the old program. Ignored signals stay ignored.
+    pub fn reset_handlers(&self) {
+        for action in self.actions.lock().iter_mut() {
+            if matches!(action, Action::Handle { .. }) {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    /// Take the next pending signal, SIGKILL first, with its action.
+    fn take(&self) -> Option<(u32, Action)> {
+        let pending = self.pending.load(Ordering::Acquire);
+        if pending == 0 {
+            return None;
+        }
+        let signal = if pending & (1 << SIGKILL) != 0 { SIGKILL } else { pending.trailing_zeros() };
+        self.pending.fetch_and(!(1 << signal), Ordering::AcqRel);
+        Some((signal, self.actions.lock()[signal as usize]))
+    }
+}
+
+pub fn valid(signal: u32) -> bool {
+    (1..NSIG).contains(&signal)
+}
+
+pub fn name(signal: u32) -> &'static str {
+    match signal {
+        SIGINT => "SIGINT",
+        SIGILL => "SIGILL",
+        SIGFPE => "SIGFPE",
+        SIGKILL => "SIGKILL",
+        SIGUSR1 => "SIGUSR1",
+        SIGSEGV => "SIGSEGV",
+        SIGUSR2 => "SIGUSR2",
+        SIGPIPE => "SIGPIPE",
+        SIGTERM => "SIGTERM",
+        SIGSYS => "SIGSYS",
+        _ => "signal",
+    }
+}
+
+/// The running program faulted: send its process `signal`, which it may
+/// handle but not ignore. False if the thread has no process.
+pub fn raise_fault(signal: u32, fault: Fault) -> bool {
+    let Some(process) = current() else { return false };
+    let mut actions = process.signals.actions.lock();
+    if actions[signal as usize] == Action::Ignore {
+        actions[signal as usize] = Action::Default;
+    }
+    drop(actions);
+    *process.signals.fault.lock() = Some((signal, fault));
+    process.signals.send(signal);
+    true
+}
+
+/// Send the running program `signal` with its default action, whatever it
+/// set: for a program that must not carry on. False if the thread has no
+/// process.
+pub fn force(signal: u32) -> bool {
+    let Some(process) = current() else { return false };
+    process.signals.actions.lock()[signal as usize] = Action::Default;
+    process.signals.send(signal);
+    true
+}
+
+/// Whether the calling thread's process has signals to act on.
+pub fn pending() -> bool {
+    current().is_some_and(|process| process.signals.pending.load(Ordering::Acquire) != 0)
+}
+
+/// On the way back to ring 3 with the user's registers in `frame`: act on
+/// the pending signals. Either sets `frame` up to run one handler or
+/// terminates the process (and doesn't return). Call with interrupts on.
+pub fn deliver(frame: &mut TrapFrame) {
+    let Some(process) = current() else { return };
+    while let Some((signal, action)) = process.signals.take() {
+        let fault = process.signals.fault.lock().take_if(|(raised, _)| *raised == signal).map(|(_, fault)| fault);
+        match action {
+            Action::Ignore => {}
+            Action::Default => {
+                match fault {
+                    Some(fault) => serial_println!("process {} ({}): {}: killed by {}", process.pid(), process.name(), fault, name(signal)),
+                    None => serial_println!("process {} ({}): killed by {}", process.pid(), process.name(), name(signal)),
+                }
+                drop(process);
+                terminate(signal);
+            }
+            Action::Handle { handler, restorer } => {
+                if push_frame(frame, signal, handler, restorer).is_err() {
+                    serial_println!("process {} ({}): no stack for the {} handler", process.pid(), process.name(), name(signal));
+                    drop(process);
+                    terminate(SIGSEGV);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// End the program as killed by `signal`. Holds nothing: `user::leave`
+/// drops nothing on the way out.
+fn terminate(signal: u32) -> ! {
+    user::leave(128 + signal as i64)
+}
+
+fn as_bytes(frame: &TrapFrame) -> &[u8] {
+    unsafe { core::slice::from_raw_parts((frame as *const TrapFrame).cast::<u8>(), size_of::<TrapFrame>()) }
+}
+
+/// Save `frame` on the user stack under `restorer` and point it at `handler`.
+fn push_frame(frame: &mut TrapFrame, signal: u32, handler: u64, restorer: u64) -> Result<(), Errno> {
+    let below = frame.rsp.checked_sub(RED_ZONE + size_of::<TrapFrame>() as u64).ok_or(Errno::EFAULT)?;
+    // So that RSP + 8 is 16-byte aligned at the handler, as after a call.
+    let at = (below & !15).wrapping_sub(8);
+    uaccess::copy_to_user(UserPtr::new(at), &restorer.to_le_bytes())?;
+    uaccess::copy_to_user(UserPtr::new(at + 8), as_bytes(frame))?;
+    frame.rip = handler;
+    frame.rsp = at;
+    frame.rdi = signal as u64;
+    frame.rflags = user::INITIAL_RFLAGS;
+    Ok(())
+}
+
+/// `sigreturn`, from the restorer: `frame` is the system call's, with RSP
+/// where `push_frame` left the saved registers. Only the registers a
+/// program may set are taken from them.
+pub fn restore(frame: &mut TrapFrame) -> Result<(), Errno> {
+    let mut saved = TrapFrame::default();
+    let mut bytes = [0u8; size_of::<TrapFrame>()];
+    uaccess::copy_from_user(&mut bytes, UserPtr::new(frame.rsp))?;
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), (&mut saved as *mut TrapFrame).cast::<u8>(), bytes.len()) };
+    // `iretq` to a kernel or non-canonical address would fault in the kernel.
+    let in_user = |addr: u64| (USER_START..USER_END).contains(&addr);
+    if !in_user(saved.rip) || !in_user(saved.rsp) {
+        return Err(Errno::EFAULT);
+    }
+    saved.cs = frame.cs;
+    saved.ss = frame.ss;
+    saved.rflags = saved.rflags & USER_FLAGS | user::INITIAL_RFLAGS;
+    *frame = saved;
+    Ok(())
+}
@@ -1,6 +1,10 @@
-use spin::Mutex;
+use core::fmt;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::sync::{SpinLock, SpscQueue, WaitQueue};
+use crate::thread;
+
 pub struct SerialPort {
     data: Port<u8>,
     int_enable: Port<u8>,
@@ -46,13 +50,33 @@ impl SerialPort {
         }
     }
 
+    fn can_receive(&mut self) -> bool {
+        unsafe {
+            // Bit 0 = data ready
+            (self.line_status.read() & 0x01) != 0
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         while !self.can_send() {}
         unsafe { self.data.write(byte); }
     }
 
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.can_receive() {
+            Some(unsafe { self.data.read() })
+        } else {
+            None
+        }
+    }
+
     pub fn write_str(&mut self, s: &str) {
-        for b in s.bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Raw bytes, not necessarily UTF-8; newlines become CR LF.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
             if b == b'\n' {
                 self.write_byte(b'\r');
             }
@@ -61,13 +85,112 @@ impl SerialPort {
     }
 }
 
-static SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new());
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        SerialPort::write_str(self, s);
+        Ok(())
+    }
+}
+
+static SERIAL1: SpinLock<SerialPort> = SpinLock::new(SerialPort::new());
 
 pub fn init() {
     SERIAL1.lock().init();
 }
 
+/// Let the panic handler print even if the panicking code was printing.
+///
+/// # Safety
+/// Only from the panic handler.
+pub unsafe fn force_unlock() {
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+}
+
 pub fn println(s: &str) {
     SERIAL1.lock().write_str(s);
     SERIAL1.lock().write_str("\n");
 }
+
+/// Print bytes from somewhere else (user programs), as they are.
+pub fn write_bytes(bytes: &[u8]) {
+    SERIAL1.lock().write_bytes(bytes);
+}
+
+/// Bytes drained from the UART by the COM1 interrupt, for `read_byte`.
+static RX: SpscQueue<u8, 256> = SpscQueue::new();
+
+/// Threads waiting for input; the COM1 interrupt wakes them.
+static RX_READY: WaitQueue = WaitQueue::new();
+
+/// Readers take turns: `RX` has a single consumer.
+static RX_READER: SpinLock<()> = SpinLock::new(());
+
+/// Raise IRQ4 when a byte arrives, so `read_byte` can block instead of polling.
+pub fn enable_rx_interrupt() {
+    unsafe { SERIAL1.lock().int_enable.write(0x01) };
+}
+
+/// Called from the COM1 interrupt handler: move every received byte into `RX`.
+/// The registers are read without the port lock (a preempted writer may hold it).
+pub fn on_interrupt() {
+    let mut line_status = Port::<u8>::new(SerialPort::COM1 + 5);
+    let mut data = Port::<u8>::new(SerialPort::COM1);
+    unsafe {
+        while line_status.read() & 0x01 != 0 {
+            // Safety: this handler is the only producer. A full ring drops the byte.
+            let _ = RX.push(data.read());
+        }
+    }
+    RX_READY.notify_all();
+    crate::console::input_arrived();
+}
+
+/// A byte the receive interrupt stored, if there is one.
+pub fn pop_byte() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let _reader = RX_READER.lock();
+        // Safety: the lock makes this the only consumer.
+        unsafe { RX.pop() }
+    })
+}
+
+pub fn has_input() -> bool {
+    !RX.is_empty()
+}
+
+/// Wait for a byte from COM1: blocked on the receive interrupt once threads
+/// run, polling the UART before that. The shell reads with this, and the
+/// console for user programs while the shell waits for them.
+pub fn read_byte() -> u8 {
+    loop {
+        if let Some(b) = pop_byte() {
+            return b;
+        }
+        if thread::initialized() && interrupts::are_enabled() {
+            RX_READY.wait_until(|| !RX.is_empty());
+        } else if let Some(b) = SERIAL1.lock().try_read_byte() {
+            return b;
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = SERIAL1.lock().write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,797 @@
+use crate::{serial, serial_print, serial_println};
+
+const MAX_LINE: usize = 128;
+const MAX_ARGS: usize = 8;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+static COMMANDS: &[Command] = &[
+    Command { name: "help", help: "list commands", run: cmd_help },
+    Command { name: "aspace", help: "user address spaces and CR3 switching [test]", run: cmd_aspace },
+    Command { name: "async", help: "async executor: echo PS/2 keys until Esc [test|shell]", run: cmd_async },
+    Command { name: "blk", help: "block devices [test|dump <dev> <block>|ram <initrd path>|scan <dev>|cache|sync]", run: cmd_blk },
+    Command { name: "buddy", help: "buddy allocator free blocks per order [test]", run: cmd_buddy },
+    Command { name: "console", help: "the console user programs read and write [test]", run: cmd_console },
+    Command { name: "cow", help: "copy-on-write stats [test]", run: cmd_cow },
+    Command { name: "cpus", help: "processors found in the ACPI MADT, their state and utilization", run: cmd_cpus },
+    Command { name: "dma", help: "DMA buffer allocation self-test [test]", run: cmd_dma },
+    Command { name: "elf", help: "headers of the built-in ELF test program [test]", run: cmd_elf },
+    Command { name: "fat", help: "FAT volumes [test|mount <dev>|ls <dev> [path]|cat <dev> <path>|write <dev> <path> <text>|mkdir <dev> <path>]", run: cmd_fat },
+    Command { name: "frames", help: "physical frame allocator stats [test]", run: cmd_frames },
+    Command { name: "heap", help: "kernel heap usage and stats [test|compare|bench|smash|oom [panic|fail|kill]]", run: cmd_heap },
+    Command { name: "huge", help: "2MiB pages: show, on|off, bench", run: cmd_huge },
+    Command { name: "initrd", help: "files in the initial ramdisk [test|programs|ls [path]|cat <path>]", run: cmd_initrd },
+    Command { name: "ipc", help: "message queues and shared memory segments [test|bench]", run: cmd_ipc },
+    Command { name: "iso", help: "ISO 9660 discs [test|mount <dev>]", run: cmd_iso },
+    Command { name: "keys", help: "echo PS/2 keys from a thread blocked on a wait queue, until Esc", run: cmd_keys },
+    Command { name: "kill", help: "kill <pid> [signal]: send a process a signal (SIGTERM by default)", run: cmd_kill },
+    Command { name: "lockdep", help: "lock-order validation stats, debug builds only [test]", run: cmd_lockdep },
+    Command { name: "memmap", help: "physical memory map from the bootloader", run: cmd_memmap },
+    Command { name: "mmio", help: "MMIO mapping self-test [test]", run: cmd_mmio },
+    Command { name: "numa", help: "NUMA nodes from the ACPI SRAT", run: cmd_numa },
+    Command { name: "overflow", help: "overflow the kernel stack on purpose", run: cmd_overflow },
+    Command { name: "paging", help: "page-table tree of mapped ranges [test]", run: cmd_paging },
+    Command { name: "pci", help: "PCI functions, their BARs and interrupt lines [test]", run: cmd_pci },
+    Command { name: "preempt", help: "preemption-disable stats [test|sleep]", run: cmd_preempt },
+    Command { name: "procs", help: "user processes: PID, parent, state [test|demo|crash|sandbox|files <pid>]", run: cmd_procs },
+    Command { name: "ps", help: "threads by CPU time: runtime, switches, last CPU", run: cmd_ps },
+    Command { name: "rcu", help: "read-copy-update grace periods and callbacks [test|demo]", run: cmd_rcu },
+    Command { name: "reboot", help: "restart the machine", run: cmd_reboot },
+    Command { name: "ring3", help: "run a few instructions in user mode and come back with the exit system call", run: cmd_ring3 },
+    Command { name: "run", help: "run <path> [args...]: start a program and wait for it", run: cmd_run },
+    Command { name: "shutdown", help: "power the machine off (ACPI S5)", run: cmd_shutdown },
+    Command { name: "slab", help: "slab cache statistics [test]", run: cmd_slab },
+    Command { name: "softirq", help: "softirq runs and ksoftirqd hand-offs [test]", run: cmd_softirq },
+    Command { name: "swap", help: "swap counters [on [<file> <pages>]|test]", run: cmd_swap },
+    Command { name: "sync", help: "synchronization primitives self-test, deadlock and priority inversion demos [test|deadlock|inversion]", run: cmd_sync },
+    Command { name: "syscalls", help: "system call table and call counts [test]", run: cmd_syscalls },
+    Command { name: "tfs", help: "teaching filesystem volumes [test|mount <dev>|mkfs <dev>|check <dev>]", run: cmd_tfs },
+    Command { name: "threads", help: "kernel threads, their CPUs and ticks [test|demo|starve|prio <id> <level>|pin <id> <cpus>]", run: cmd_threads },
+    Command { name: "time", help: "uptime, wall clock and pending timers [test|sleep <ms>]", run: cmd_time },
+    Command { name: "tls", help: "thread-local storage block layout [test]", run: cmd_tls },
+    Command { name: "trace", help: "trace <pid> [on|off]: log a process's system calls to serial", run: cmd_trace },
+    Command { name: "translate", help: "translate <hex vaddr> to a physical address", run: cmd_translate },
+    Command { name: "vfs", help: "mounted filesystems [test|ls [path]|cat <path>|write <path> <text>|mkdir <path>|files]", run: cmd_vfs },
+    Command { name: "vmalloc", help: "kernel virtual address ranges [test|mark|leaks]", run: cmd_vmalloc },
+    Command { name: "vmas", help: "kernel virtual memory areas [test|lazy]", run: cmd_vmas },
+    Command { name: "wipe", help: "zero-on-free mode [on|off|demo|test]", run: cmd_wipe },
+    Command { name: "work", help: "deferred work queue stats [test]", run: cmd_work },
+    Command { name: "wx", help: "check that no mapping is writable and executable", run: cmd_wx },
+];
+
+/// A tiny line-based shell on COM1 (type into the terminal running QEMU).
+pub fn run() -> ! {
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+
+    serial_print!("> ");
+    loop {
+        match serial::read_byte() {
+            b'\r' | b'\n' => {
+                serial_println!();
+                if let Ok(s) = core::str::from_utf8(&line[..len]) {
+                    execute(s);
+                }
+                len = 0;
+                serial_print!("> ");
+            }
+            // Backspace / DEL
+            0x08 | 0x7F => {
+                if len > 0 {
+                    len -= 1;
+                    serial_print!("\x08 \x08");
+                }
+            }
+            b if (0x20..0x7F).contains(&b) && len < MAX_LINE => {
+                line[len] = b;
+                len += 1;
+                serial_print!("{}", b as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The same shell on the PS/2 keyboard, as an async task reading the key
+/// stream (output still goes to COM1). Esc leaves it.
+async fn keyboard_shell() {
+    use crate::task::{keyboard::{Key, KeyStream}, Stream};
+    let mut keys = KeyStream::default();
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+
+    serial_print!("kbd> ");
+    while let Some(key) = keys.next().await {
+        match key {
+            Key::Escape => break,
+            Key::Char('\n') => {
+                serial_println!();
+                if let Ok(s) = core::str::from_utf8(&line[..len]) {
+                    execute(s);
+                }
+                len = 0;
+                serial_print!("kbd> ");
+            }
+            Key::Backspace => {
+                if len > 0 {
+                    len -= 1;
+                    serial_print!("\x08 \x08");
+                }
+            }
+            Key::Char(c) if (' '..='~').contains(&c) && len < MAX_LINE => {
+                line[len] = c as u8;
+                len += 1;
+                serial_print!("{}", c);
+            }
+            _ => {}
+        }
+    }
+    serial_println!();
+}
+
+fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_whitespace().take(MAX_ARGS) {
+        args[argc] = word;
+        argc += 1;
+    }
+    if argc == 0 { return; }
+
+    match COMMANDS.iter().find(|c| c.name == args[0]) {
+        Some(cmd) => {
+            // Charge whatever the command allocates to its name (see `heap tags`).
+            let _tag = crate::heap::tag(cmd.name);
+            (cmd.run)(&args[1..argc])
+        }
+        None => serial_println!("unknown command: {} (try 'help')", args[0]),
+    }
+}
+
+fn cmd_help(_args: &[&str]) {
+    for cmd in COMMANDS {
+        serial_println!("  {:<12} {}", cmd.name, cmd.help);
+    }
+}
+
+fn cmd_heap(args: &[&str]) {
+    use crate::heap;
+    match args.first() {
+        Some(&"test") => return serial_println!("heap test: {}", if heap::self_test() { "ok" } else { "FAILED" }),
+        Some(&"compare") => return serial_println!("heap compare: {}", if heap::suite::run_all() { "ok" } else { "FAILED" }),
+        Some(&"oom") => {
+            match args.get(1).map(|name| heap::OomPolicy::parse(name)) {
+                Some(Some(policy)) => heap::set_oom_policy(policy),
+                Some(None) => return serial_println!("usage: heap oom [panic|fail|kill]"),
+                None => {}
+            }
+            return serial_println!("out-of-memory policy: {}", heap::oom_policy().name());
+        }
+        Some(&"bench") => return serial_println!("heap bench: {}", if heap::suite::bench_all() { "done" } else { "FAILED" }),
+        Some(&"smash") => {
+            heap::smash();
+            return serial_println!("heap overflow not detected (red zones are only checked in debug builds)");
+        }
+        _ => {}
+    }
+    let free = heap::free_bytes();
+    let stats = heap::stats();
+    serial_println!(
+        "heap ({}) at {:#x}: {} KiB mapped of {} KiB max, {} bytes free",
+        heap::backend_name(), heap::start(), heap::mapped_bytes() / 1024, heap::HEAP_MAX_SIZE / 1024, free
+    );
+    serial_println!("  live {} bytes, peak {} bytes", stats.live_bytes, stats.peak_bytes);
+    serial_println!("  {} allocs, {} frees, {} failed", stats.allocs, stats.frees, stats.failed);
+    serial_println!("  size class    allocs");
+    let mut low = 0;
+    for (max, count) in heap::SIZE_CLASSES.iter().zip(stats.by_class) {
+        if *max == usize::MAX {
+            serial_println!("  > {:<10} {:>7}", low, count);
+        } else {
+            serial_println!("  {:>4}..{:<6} {:>7}", low + 1, max, count);
+        }
+        low = *max;
+    }
+    heap::for_each_tag(|name, live, allocs| serial_println!("  tag {:<12} {:>8} bytes live {:>6} allocs", name, live, allocs));
+}
+
+fn cmd_huge(args: &[&str]) {
+    use crate::memory::huge;
+    match args.first() {
+        Some(&"on") => huge::set_enabled(true),
+        Some(&"off") => huge::set_enabled(false),
+        Some(&"bench") => return huge::bench(),
+        _ => {}
+    }
+    let (pse, gib) = huge::cpu_support();
+    serial_println!("huge pages: {} (CPU: 2MiB {}, 1GiB {})", if huge::enabled() { "on" } else { "off" }, pse, gib);
+}
+
+fn cmd_initrd(args: &[&str]) {
+    use crate::initrd;
+    match args {
+        ["test"] => {
+            let ok = initrd::self_test() && crate::fs::initramfs::self_test();
+            serial_println!("initrd test: {}", if ok { "ok" } else { "FAILED" });
+        }
+        ["programs"] => initrd::list_programs(),
+        ["ls"] => crate::fs::initramfs::ls("/"),
+        ["ls", path] => crate::fs::initramfs::ls(path),
+        ["cat", path] => match initrd::read(path) {
+            Some(data) => crate::serial::write_bytes(data),
+            None => serial_println!("initrd: no {}", path),
+        },
+        _ => initrd::list(),
+    }
+}
+
+fn cmd_ipc(args: &[&str]) {
+    use crate::ipc;
+    match args.first() {
+        Some(&"test") => serial_println!("ipc test: {}", if ipc::self_test() { "ok" } else { "FAILED" }),
+        Some(&"bench") => ipc::mqueue::bench(),
+        _ => ipc::dump(),
+    }
+}
+
+fn cmd_keys(_args: &[&str]) {
+    use crate::task::keyboard;
+    serial_println!("type in the QEMU window, Esc to stop");
+    keyboard::clear();
+    if let Some(thread) = crate::thread::Builder::new().name("keys").spawn(keyboard::print_keypresses_blocking) {
+        thread.join();
+    }
+}
+
+fn cmd_kill(args: &[&str]) {
+    use crate::process::{self, signal, Pid};
+    let (pid, sig) = match args {
+        [pid] => (pid.parse(), Ok(signal::SIGTERM)),
+        [pid, sig] => (pid.parse(), sig.parse()),
+        _ => return serial_println!("usage: kill <pid> [signal]"),
+    };
+    let (Ok(pid), Ok(sig)) = (pid, sig) else {
+        return serial_println!("usage: kill <pid> [signal]");
+    };
+    if !signal::valid(sig) {
+        return serial_println!("kill: bad signal {}", sig);
+    }
+    if !process::kill(Pid(pid), sig) {
+        serial_println!("kill: no process {}", pid);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn cmd_lockdep(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("lockdep test: {}", if crate::sync::lockdep::self_test() { "ok" } else { "FAILED" }),
+        _ => crate::sync::lockdep::dump(),
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn cmd_lockdep(_args: &[&str]) {
+    serial_println!("lockdep: only in debug builds");
+}
+
+fn cmd_memmap(_args: &[&str]) {
+    crate::memory::map::dump();
+}
+
+fn cmd_mmio(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("mmio test: {}", if crate::memory::mmio::self_test() { "ok" } else { "FAILED" }),
+        _ => serial_println!("usage: mmio test"),
+    }
+}
+
+fn cmd_numa(_args: &[&str]) {
+    crate::memory::numa::dump();
+}
+
+fn cmd_overflow(_args: &[&str]) {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: u64) -> u64 {
+        let buf = [depth; 64];
+        recurse(core::hint::black_box(buf[63]) + 1) + 1
+    }
+    serial_println!("recursing until the guard page is hit...");
+    recurse(0);
+}
+
+fn cmd_preempt(args: &[&str]) {
+    use crate::preempt;
+    match args.first() {
+        Some(&"test") => serial_println!("preempt test: {}", if preempt::self_test() { "ok" } else { "FAILED" }),
+        #[cfg(debug_assertions)]
+        Some(&"sleep") => preempt::sleep_demo(),
+        #[cfg(not(debug_assertions))]
+        Some(&"sleep") => serial_println!("sleeping with preemption disabled is only caught in debug builds"),
+        _ => preempt::dump(),
+    }
+}
+
+fn cmd_procs(args: &[&str]) {
+    use crate::process;
+    match args.first() {
+        Some(&"test") => serial_println!("process test: {}", if process::self_test() { "ok" } else { "FAILED" }),
+        Some(&"demo") => process::demo(),
+        Some(&"crash") => process::crash_demo(),
+        Some(&"sandbox") => process::sandbox_demo(),
+        Some(&"files") => match args.get(1).map(|pid| pid.parse()) {
+            Some(Ok(pid)) => process::list_files(process::Pid(pid)),
+            _ => serial_println!("usage: procs files <pid>"),
+        },
+        _ => process::list(),
+    }
+}
+
+fn cmd_ps(_args: &[&str]) {
+    crate::thread::ps();
+}
+
+fn cmd_rcu(args: &[&str]) {
+    use crate::sync::rcu;
+    match args.first() {
+        Some(&"test") => serial_println!("rcu test: {}", if rcu::self_test() { "ok" } else { "FAILED" }),
+        Some(&"demo") => rcu::demo(),
+        _ => rcu::dump(),
+    }
+}
+
+fn cmd_ring3(_args: &[&str]) {
+    match crate::user::demo() {
+        Some(cs) => serial_println!("ring3: user code saw CS={:#x} (privilege level {}) and exited with it", cs, cs & 3),
+        None => serial_println!("ring3: out of memory"),
+    }
+}
+
+fn cmd_syscalls(args: &[&str]) {
+    use crate::syscall;
+    match args.first() {
+        Some(&"test") => serial_println!("syscall test: {}", if syscall::self_test() { "ok" } else { "FAILED" }),
+        _ => syscall::dump(),
+    }
+}
+
+fn cmd_reboot(_args: &[&str]) {
+    crate::power::reboot();
+}
+
+fn cmd_run(args: &[&str]) {
+    use crate::process;
+    let Some(&path) = args.first() else {
+        return serial_println!("usage: run <path> [args...]");
+    };
+    match process::spawn(path, args) {
+        Ok(child) => {
+            let status = child.wait_exit();
+            process::reap(child.pid());
+            serial_println!("run: {} (pid {}) exited with status {}", path, child.pid(), status);
+        }
+        Err(err) => serial_println!("run: {}: {}", path, err),
+    }
+}
+
+fn cmd_shutdown(_args: &[&str]) {
+    crate::power::shutdown();
+}
+
+fn cmd_console(args: &[&str]) {
+    use crate::console;
+    match args.first() {
+        Some(&"test") => serial_println!("console test: {}", if console::self_test() { "ok" } else { "FAILED" }),
+        _ => serial_println!("console: COM1 and the PS/2 keyboard as fds 0-2, a line at a time; try `run /bin/cat` (Ctrl-D ends it)"),
+    }
+}
+
+fn cmd_cow(args: &[&str]) {
+    use crate::memory::cow;
+    if args.first() == Some(&"test") {
+        return serial_println!("cow test: {}", if cow::self_test() { "ok" } else { "FAILED" });
+    }
+    let (copies, reuses) = cow::stats();
+    serial_println!("cow: {} pages copied, {} reused in place", copies, reuses);
+}
+
+fn cmd_cpus(_args: &[&str]) {
+    crate::smp::dump();
+}
+
+fn cmd_dma(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("dma test: {}", if crate::dma::self_test() { "ok" } else { "FAILED" }),
+        _ => serial_println!("PAT: {}", if crate::dma::pat_supported() { "supported" } else { "not supported" }),
+    }
+}
+
+fn cmd_elf(args: &[&str]) {
+    use crate::user::elf;
+    match args.first() {
+        Some(&"test") => serial_println!("elf test: {}", if elf::self_test() { "ok" } else { "FAILED" }),
+        _ => elf::dump(&elf::test_image()),
+    }
+}
+
+fn cmd_fat(args: &[&str]) {
+    use crate::fs::fat;
+    match args {
+        ["test"] => serial_println!("fat test: {}", if fat::self_test() { "ok" } else { "FAILED" }),
+        ["mount", name] => {
+            if let Err(err) = fat::mount(name) {
+                serial_println!("fat: {}: {}", name, err);
+            }
+        }
+        ["ls", name] => fat::ls(name, "/"),
+        ["ls", name, path] => fat::ls(name, path),
+        ["cat", name, path] => fat::cat(name, path),
+        ["write", name, path, text @ ..] => {
+            let mut data = text.join(" ");
+            data.push('\n');
+            fat::write_file(name, path, data.as_bytes());
+        }
+        ["mkdir", name, path] => fat::mkdir(name, path),
+        _ => fat::list(),
+    }
+}
+
+fn cmd_iso(args: &[&str]) {
+    use crate::fs::iso9660;
+    match args {
+        ["test"] => serial_println!("iso test: {}", if iso9660::self_test() { "ok" } else { "FAILED" }),
+        ["mount", name] => {
+            if let Err(err) = iso9660::mount(name) {
+                serial_println!("iso9660: {}: {}", name, err);
+            }
+        }
+        _ => iso9660::list(),
+    }
+}
+
+fn cmd_tfs(args: &[&str]) {
+    use crate::fs::tfs;
+    match args {
+        ["test"] => serial_println!("tfs test: {}", if tfs::self_test() { "ok" } else { "FAILED" }),
+        ["mount", name] => {
+            if let Err(err) = tfs::mount(name) {
+                serial_println!("tfs: {}: {}", name, err);
+            }
+        }
+        ["mkfs", name] => tfs::mkfs(name),
+        ["check", name] => {
+            tfs::check(name);
+        }
+        _ => tfs::list(),
+    }
+}
+
+fn cmd_frames(args: &[&str]) {
+    if args.first() == Some(&"test") {
+        return serial_println!("frames test: {}", if crate::memory::frame_alloc::self_test() { "ok" } else { "FAILED" });
+    }
+    match crate::memory::frame_alloc::stats() {
+        Some(s) => {
+            serial_println!("frames: {} usable, {} used, {} free ({} KiB free)", s.usable, s.used, s.free, s.free * 4);
+            if s.bad > 0 {
+                serial_println!("  {} bad frames excluded by memtest", s.bad);
+            }
+        }
+        None => serial_println!("frame allocator not initialized"),
+    }
+}
+
+fn cmd_async(args: &[&str]) {
+    use crate::task::{self, keyboard, Executor};
+    if args.first() == Some(&"test") {
+        return serial_println!("async test: {}", if task::self_test() { "ok" } else { "FAILED" });
+    }
+    serial_println!("type in the QEMU window, Esc to stop");
+    keyboard::clear();
+    let mut executor = Executor::new();
+    if args.first() == Some(&"shell") {
+        executor.spawn(keyboard_shell());
+    } else {
+        executor.spawn(keyboard::print_keypresses());
+    }
+    executor.run();
+}
+
+fn cmd_aspace(args: &[&str]) {
+    use crate::memory::address_space;
+    match args.first() {
+        Some(&"test") => serial_println!("aspace test: {}", if address_space::self_test() { "ok" } else { "FAILED" }),
+        _ => {
+            let (l4, _) = x86_64::registers::control::Cr3::read();
+            serial_println!("CR3 {:#x}, user range {:#x}..{:#x}", l4.start_address().as_u64(), address_space::USER_START, address_space::USER_END);
+        }
+    }
+}
+
+fn cmd_blk(args: &[&str]) {
+    use crate::block::{self, RamDisk};
+    match args {
+        ["test"] => serial_println!("blk test: {}", if block::self_test() { "ok" } else { "FAILED" }),
+        ["dump", name, number] => match number.parse() {
+            Ok(number) => block::dump(name, number),
+            Err(_) => serial_println!("usage: blk dump <dev> <block>"),
+        },
+        ["ram", path] => match RamDisk::from_initrd(path) {
+            Some(disk) => {
+                block::register(&block::next_name("ram"), alloc::sync::Arc::new(disk));
+            }
+            None => serial_println!("blk: no {} in the initrd, or no memory", path),
+        },
+        ["scan", name] => block::partition::scan(name),
+        ["cache"] => block::cache::list(),
+        ["sync"] => block::cache::sync_all(),
+        _ => block::list(),
+    }
+}
+
+fn cmd_buddy(args: &[&str]) {
+    use crate::memory::buddy;
+    if args.first() == Some(&"test") {
+        serial_println!("buddy test: {}", if buddy::self_test() { "ok" } else { "FAILED (blocks did not merge)" });
+        return;
+    }
+    let stats = buddy::stats();
+    serial_println!("buddy: {} pool frames, {} free", stats.pool_frames, stats.free_frames());
+    serial_println!("  order  size      free  frag%");
+    for order in 0..=buddy::MAX_ORDER {
+        serial_println!("  {:>5}  {:>5} KiB {:>5}  {:>4}", order, 4 << order, stats.free_blocks[order], stats.fragmentation(order));
+    }
+}
+
+fn cmd_slab(args: &[&str]) {
+    use crate::memory::slab;
+    if args.first() == Some(&"test") {
+        return serial_println!("slab test: {}", if slab::self_test() { "ok" } else { "FAILED" });
+    }
+    serial_println!("  cache          size  per-slab  slabs  in-use   allocs    frees");
+    slab::for_each_cache(|cache| {
+        let s = cache.stats();
+        serial_println!(
+            "  {:<12} {:>6} {:>9} {:>6} {:>7} {:>8} {:>8}",
+            cache.name(), cache.object_size(), cache.objects_per_slab(), s.slabs, s.objects_in_use, s.allocs, s.frees
+        );
+    });
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+fn cmd_softirq(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("softirq test: {}", if crate::softirq::self_test() { "ok" } else { "FAILED" }),
+        _ => crate::softirq::dump(),
+    }
+}
+
+fn cmd_swap(args: &[&str]) {
+    use crate::memory::swap;
+    match args {
+        ["test"] => return serial_println!("swap test: {}", if swap::self_test() { "ok" } else { "FAILED" }),
+        ["on", ..] if swap::enabled() => serial_println!("swap is already on"),
+        ["on"] => {
+            if let Some(dev) = swap::RamSwap::new(8) {
+                // 1 MiB of RAM pretending to be a disk.
+                if !crate::heap::try_box(dev).is_ok_and(|dev| swap::enable(dev)) {
+                    serial_println!("no memory for the swap device");
+                }
+            } else {
+                serial_println!("no memory for the swap device");
+            }
+        }
+        ["on", path, pages] => match pages.parse().map(|pages| swap::SwapFile::create(path, pages)) {
+            Ok(Ok(dev)) => {
+                if !crate::heap::try_box(dev).is_ok_and(|dev| swap::enable(dev)) {
+                    serial_println!("no memory for the swap device");
+                }
+            }
+            Ok(Err(err)) => serial_println!("swap: {}: {}", path, err),
+            Err(_) => serial_println!("usage: swap on <file> <pages>"),
+        },
+        _ => {}
+    }
+    match swap::stats() {
+        Some(s) => {
+            serial_println!("swap: {}/{} slots used, {} resident candidates", s.used, s.slots, s.resident);
+            serial_println!("  {} written out, {} read in, {} clean evictions", s.swapped_out, s.swapped_in, s.clean_evictions);
+        }
+        None => serial_println!("swap is off (swap on)"),
+    }
+}
+
+fn cmd_sync(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("sync test: {}", if crate::sync::self_test() { "ok" } else { "FAILED" }),
+        #[cfg(debug_assertions)]
+        Some(&"deadlock") => crate::sync::deadlock_demo(),
+        #[cfg(not(debug_assertions))]
+        Some(&"deadlock") => serial_println!("sync: deadlock detection is only in debug builds"),
+        Some(&"inversion") => crate::sync::inversion_demo(),
+        _ => serial_println!("usage: sync test|deadlock|inversion"),
+    }
+}
+
+fn cmd_threads(args: &[&str]) {
+    use crate::thread::{self, Priority};
+    match args {
+        ["test"] => serial_println!("threads test: {}", if thread::self_test() { "ok" } else { "FAILED" }),
+        ["demo"] => thread::demo(),
+        ["starve"] => thread::starvation_demo(),
+        ["pin", id, cpus] => {
+            let (Ok(id), Some(mask)) = (id.parse(), crate::smp::CpuMask::parse(cpus)) else {
+                return serial_println!("usage: threads pin <id> all|<cpu>[,<cpu>...]");
+            };
+            if !thread::set_affinity(thread::ThreadId(id), mask) {
+                serial_println!("threads: no thread {} (or no online CPU in {})", id, mask);
+            }
+        }
+        ["prio", id, prio] => {
+            let (Ok(id), Some(prio)) = (id.parse(), Priority::parse(prio)) else {
+                return serial_println!("usage: threads prio <id> low|normal|high|realtime");
+            };
+            if !thread::set_priority(thread::ThreadId(id), prio) {
+                serial_println!("no thread {}", id);
+            }
+        }
+        _ => thread::list(),
+    }
+}
+
+fn cmd_time(args: &[&str]) {
+    use crate::time;
+    match args {
+        ["test"] => serial_println!("time test: {}", if time::self_test() { "ok" } else { "FAILED" }),
+        ["sleep", ms] => match ms.parse() {
+            Ok(ms) => {
+                let start = time::ticks();
+                time::sleep(core::time::Duration::from_millis(ms));
+                serial_println!("slept {} ticks", time::ticks() - start);
+            }
+            Err(_) => serial_println!("usage: time sleep <ms>"),
+        },
+        _ => {
+            let up = time::uptime();
+            serial_println!("up {}.{:03}s ({} ticks at {} Hz), {} timers pending", up.as_secs(), up.subsec_millis(), time::ticks(), time::HZ, time::pending_timers());
+            serial_println!("wall clock {}", time::now());
+        }
+    }
+}
+
+fn cmd_tls(args: &[&str]) {
+    use crate::thread::tls;
+    match args.first() {
+        Some(&"test") => serial_println!("tls test: {}", if tls::self_test() { "ok" } else { "FAILED" }),
+        _ => tls::dump(),
+    }
+}
+
+fn cmd_trace(args: &[&str]) {
+    use crate::process::{self, Pid};
+    let (pid, on) = match args {
+        [pid] | [pid, "on"] => (pid.parse(), true),
+        [pid, "off"] => (pid.parse(), false),
+        _ => return serial_println!("usage: trace <pid> [on|off]"),
+    };
+    let Ok(pid) = pid else {
+        return serial_println!("usage: trace <pid> [on|off]");
+    };
+    match process::get(Pid(pid)) {
+        Some(process) => process.set_traced(on, false),
+        None => serial_println!("trace: no process {}", pid),
+    }
+}
+
+fn cmd_translate(args: &[&str]) {
+    use crate::memory::paging;
+    let Some(addr) = args.first().and_then(|a| parse_hex(a)) else {
+        serial_println!("usage: translate <hex vaddr>");
+        return;
+    };
+    match x86_64::VirtAddr::try_new(addr).ok().and_then(paging::translate) {
+        Some((phys, flags)) => serial_println!("{:#x} -> {:#x} {:?}", addr, phys.as_u64(), flags),
+        None => serial_println!("{:#x} is not mapped", addr),
+    }
+}
+
+fn cmd_paging(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("paging test: {}", if crate::memory::paging::self_test() { "ok" } else { "FAILED" }),
+        _ => crate::memory::paging::dump(),
+    }
+}
+
+fn cmd_pci(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("pci test: {}", if crate::pci::self_test() { "ok" } else { "FAILED" }),
+        _ => crate::pci::list(),
+    }
+}
+
+fn cmd_vfs(args: &[&str]) {
+    use crate::fs::vfs;
+    match args {
+        ["test"] => {
+            let ok = vfs::self_test()
+                && crate::fs::devfs::self_test()
+                && crate::fs::procfs::self_test()
+                && crate::fs::file::self_test();
+            serial_println!("vfs test: {}", if ok { "ok" } else { "FAILED" });
+        }
+        ["ls"] => vfs::ls("/"),
+        ["ls", path] => vfs::ls(path),
+        ["cat", path] => vfs::cat(path),
+        ["write", path, text @ ..] => {
+            let mut data = text.join(" ");
+            data.push('\n');
+            vfs::write(path, data.as_bytes());
+        }
+        ["mkdir", path] => vfs::mkdir(path),
+        ["files"] => crate::fs::file::list(),
+        _ => vfs::list(),
+    }
+}
+
+fn cmd_vmalloc(args: &[&str]) {
+    use crate::memory::vmalloc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    // Set by `vmalloc mark`; `vmalloc leaks` lists what was allocated since and is still live.
+    static MARK: AtomicU64 = AtomicU64::new(0);
+    match args.first() {
+        Some(&"test") => serial_println!("vmalloc test: {}", if vmalloc::self_test() { "ok" } else { "FAILED" }),
+        Some(&"mark") => MARK.store(vmalloc::checkpoint(), Ordering::Relaxed),
+        Some(&"leaks") => {
+            let count = vmalloc::leaks_since(MARK.load(Ordering::Relaxed), |start, len, owner| {
+                serial_println!("  {:#018x} {:>7} KiB {}", start.as_u64(), len / 1024, owner)
+            });
+            serial_println!("{} ranges still live since the mark", count);
+        }
+        _ => vmalloc::dump(),
+    }
+}
+
+fn cmd_vmas(args: &[&str]) {
+    use crate::memory::vma;
+    match args.first() {
+        Some(&"test") => serial_println!("vma test: {}", if vma::self_test() { "ok" } else { "FAILED" }),
+        Some(&"lazy") => serial_println!("lazy test: {}", if vma::lazy_self_test() { "ok" } else { "FAILED" }),
+        _ => {
+            vma::dump();
+            serial_println!("  {} pages zero-filled on demand so far", vma::zero_filled_pages());
+        }
+    }
+}
+
+fn cmd_wipe(args: &[&str]) {
+    use crate::memory::wipe;
+    match args.first() {
+        Some(&"on") => wipe::set_enabled(true),
+        Some(&"off") => wipe::set_enabled(false),
+        Some(&"demo") => {
+            let seen = wipe::remanence_demo();
+            return serial_println!("the next owner of a freed 32-byte block could read {} bytes of the secret", seen);
+        }
+        Some(&"test") => return serial_println!("wipe test: {}", if wipe::self_test() { "ok" } else { "FAILED" }),
+        _ => {}
+    }
+    serial_println!("zero-on-free: {}", if wipe::enabled() { "on" } else { "off" });
+}
+
+fn cmd_work(args: &[&str]) {
+    match args.first() {
+        Some(&"test") => serial_println!("workqueue test: {}", if crate::workqueue::self_test() { "ok" } else { "FAILED" }),
+        _ => crate::workqueue::dump(),
+    }
+}
+
+fn cmd_wx(_args: &[&str]) {
+    let ok = crate::memory::wx::self_test();
+    serial_println!("W^X check: {}", if ok { "ok" } else { "FAILED" });
+}
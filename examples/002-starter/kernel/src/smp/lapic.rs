@@ -0,0 +1,105 @@
+//! The local APIC: every CPU's own interrupt controller, used here to tell
+//! CPUs apart and to send inter-processor interrupts.
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::memory::mmio::{self, Mmio};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+const ID: usize = 0x20;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Vector for spurious interrupts; its handler must not send an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const ICR_FIXED: u32 = 0b000 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// The register window. Every CPU reaches its own APIC through the same
+/// physical address, so one mapping serves all of them.
+struct Lapic(Mmio);
+
+// `Mmio` is !Send so a window isn't carried to a CPU that sees other
+// registers through it; here that is exactly the point.
+unsafe impl Send for Lapic {}
+unsafe impl Sync for Lapic {}
+
+static LAPIC: Once<Lapic> = Once::new();
+
+fn regs() -> &'static Mmio {
+    &LAPIC.get().expect("local APIC not initialized").0
+}
+
+/// Map the APIC registers. Call once, on the bootstrap processor.
+pub fn init() {
+    LAPIC.call_once(|| {
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+        let phys = PhysAddr::new(base & 0x000F_FFFF_FFFF_F000);
+        Lapic(mmio::map_mmio(phys, 4096).expect("lapic: cannot map registers"))
+    });
+}
+
+/// Enable the calling CPU's APIC, keeping the firmware's LINT setup (the
+/// bootstrap processor still gets the PIC's interrupts through LINT0).
+pub fn enable() {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        msr.write(msr.read() | APIC_GLOBAL_ENABLE);
+    }
+    let svr = regs().register::<u32>(SPURIOUS);
+    svr.set(svr.get() | SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// The calling CPU's APIC ID.
+pub fn id() -> u8 {
+    (regs().register::<u32>(ID).get() >> 24) as u8
+}
+
+/// Acknowledge an interrupt that came through the APIC (an IPI), so the next one can be delivered.
+pub fn end_of_interrupt() {
+    regs().register::<u32>(EOI).set(0);
+}
+
+fn send(apic_id: u8, command: u32) {
+    let regs = regs();
+    regs.register::<u32>(ERROR_STATUS).set(0);
+    regs.register::<u32>(ICR_HIGH).set((apic_id as u32) << 24);
+    // Writing the low half sends it.
+    regs.register::<u32>(ICR_LOW).set(command);
+    while regs.register::<u32>(ICR_LOW).get() & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset a CPU into its wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Start a CPU in real mode at `vector << 12`.
+pub fn send_startup(apic_id: u8, vector: u8) {
+    send(apic_id, ICR_STARTUP | ICR_ASSERT | vector as u32);
+}
+
+/// Raise `vector` on one CPU.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send(apic_id, ICR_FIXED | ICR_ASSERT | vector as u32);
+}
+
+/// Raise `vector` on every CPU but the caller.
+pub fn broadcast_ipi(vector: u8) {
+    send(0, ICR_FIXED | ICR_ASSERT | ICR_ALL_EXCLUDING_SELF | vector as u32);
+}
@@ -0,0 +1,314 @@
+//! Multiprocessor bring-up: find the CPUs in the ACPI MADT and start the
+//! application processors (APs) with INIT-SIPI-SIPI.
+//!
+//! An AP comes up in real mode in the low-memory trampoline, switches to long
+//! mode with the kernel's page tables, loads its own GDT/TSS, the shared IDT
+//! and the PAT, enables its local APIC and halts in `thread::idle`; once the
+//! scheduler is up, that loop is the CPU's idle thread.
+//!
+//! Only the bootstrap processor (BSP) gets the PIT's interrupts. It passes
+//! every tick on to the others with `TICK_VECTOR`, and the scheduler sends
+//! `WAKEUP_VECTOR` to a CPU that sits idle while there is work for it.
+
+pub mod lapic;
+mod trampoline;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Once;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{self, SdtHeader};
+use crate::gdt::{self, CpuTables};
+use crate::memory::{frame_alloc, paging, phys_to_virt, stack};
+use crate::{dma, serial_println, thread, time};
+use trampoline::{Boot, Trampoline};
+
+/// MADT entries start after the header, the LAPIC address and the flags.
+const MADT_ENTRIES: u64 = 44;
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const ENABLED: u32 = 1 << 0;
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The SIPI vector can only name a page below 1 MiB.
+const LOW_MEMORY: u64 = 0x10_0000;
+const AP_STACK_PAGES: u64 = 16;
+
+/// Most CPUs brought up; the scheduler keeps per-CPU state for this many.
+pub const MAX_CPUS: usize = 16;
+
+/// IPI from the BSP on every timer tick.
+pub const TICK_VECTOR: u8 = 0xF0;
+/// IPI that makes an idle CPU look for work.
+pub const WAKEUP_VECTOR: u8 = 0xF1;
+
+/// A set of CPUs, by index into `cpus()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u32);
+
+impl CpuMask {
+    pub const ALL: CpuMask = CpuMask(u32::MAX >> (32 - MAX_CPUS));
+
+    pub const fn single(cpu: usize) -> Self {
+        CpuMask(1 << cpu)
+    }
+
+    pub fn contains(self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    /// `all`, or CPU indices separated by commas (`0,2`).
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "all" {
+            return Some(CpuMask::ALL);
+        }
+        let mut mask = 0;
+        for cpu in s.split(',') {
+            let cpu: usize = cpu.parse().ok()?;
+            if cpu >= MAX_CPUS {
+                return None;
+            }
+            mask |= 1 << cpu;
+        }
+        Some(CpuMask(mask))
+    }
+}
+
+impl core::fmt::Display for CpuMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if *self == CpuMask::ALL {
+            return f.write_str("all");
+        }
+        let mut first = true;
+        for cpu in (0..MAX_CPUS).filter(|&cpu| self.contains(cpu)) {
+            write!(f, "{}{}", if first { "" } else { "," }, cpu)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+pub struct Cpu {
+    pub apic_id: u8,
+    online: AtomicBool,
+    /// Built by the BSP before the AP starts.
+    tables: Once<&'static CpuTables>,
+}
+
+impl Cpu {
+    fn new(apic_id: u8) -> Self {
+        Cpu { apic_id, online: AtomicBool::new(false), tables: Once::new() }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+/// All CPUs, the BSP first.
+static CPUS: Once<Vec<Cpu>> = Once::new();
+/// CPU index by APIC ID, for `current`.
+static INDEX: [AtomicU8; 256] = [const { AtomicU8::new(0) }; 256];
+/// More than one CPU came up.
+static MULTI: AtomicBool = AtomicBool::new(false);
+/// Page for the AP startup code, taken before the early boot allocations use up low memory.
+static TRAMPOLINE_FRAME: Once<PhysFrame> = Once::new();
+
+/// Set aside a page below 1 MiB for the AP startup code. Call right after the frame allocator is up.
+pub fn reserve_trampoline() {
+    if let Some(frame) = frame_alloc::allocate_below(LOW_MEMORY) {
+        TRAMPOLINE_FRAME.call_once(|| frame);
+    }
+}
+
+/// Enumerate the CPUs and start the APs. Needs `acpi::init`, the heap, `gdt::init` and the IDT.
+pub fn init() {
+    lapic::init();
+    lapic::enable();
+    let bsp = Cpu::new(lapic::id());
+    bsp.online.store(true, Ordering::Relaxed);
+    let mut cpus = alloc::vec![bsp];
+    if let Some(madt) = acpi::find_table(b"APIC") {
+        let bsp_id = cpus[0].apic_id;
+        cpus.extend(parse_madt(madt).into_iter().filter(|&id| id != bsp_id).map(Cpu::new));
+    }
+    if cpus.len() > MAX_CPUS {
+        serial_println!("smp: using {} of {} CPUs", MAX_CPUS, cpus.len());
+        cpus.truncate(MAX_CPUS);
+    }
+    for (index, cpu) in cpus.iter().enumerate() {
+        INDEX[cpu.apic_id as usize].store(index as u8, Ordering::Relaxed);
+    }
+    let cpus = CPUS.call_once(|| cpus);
+    if cpus.len() > 1 {
+        start_aps(cpus);
+    }
+    MULTI.store(online() > 1, Ordering::Release);
+    serial_println!("smp: {} of {} CPU(s) online", online(), cpus.len());
+}
+
+/// APIC IDs of the usable processors.
+fn parse_madt(madt: PhysAddr) -> Vec<u8> {
+    let header: SdtHeader = unsafe { acpi::read_phys(madt) };
+    let end = madt + header.length as u64;
+    let mut ids = Vec::new();
+    let mut entry = madt + MADT_ENTRIES;
+    while entry + 2u64 <= end {
+        let (kind, len): (u8, u8) = unsafe { (acpi::read_phys(entry), acpi::read_phys(entry + 1u64)) };
+        if len < 2 || entry + len as u64 > end { break; }
+        if kind == PROCESSOR_LOCAL_APIC && len >= 8 {
+            let (apic_id, flags): (u8, u32) = unsafe { (acpi::read_phys(entry + 3u64), acpi::read_phys(entry + 4u64)) };
+            if flags & (ENABLED | ONLINE_CAPABLE) != 0 {
+                ids.push(apic_id);
+            }
+        }
+        entry += len as u64;
+    }
+    ids
+}
+
+fn start_aps(cpus: &'static [Cpu]) {
+    let Some(&frame) = TRAMPOLINE_FRAME.get() else {
+        serial_println!("smp: no free page below 1 MiB for the AP startup code");
+        return;
+    };
+    let (cr3, _) = Cr3::read();
+    if cr3.start_address().as_u64() >= 1 << 32 {
+        serial_println!("smp: page tables above 4 GiB, APs can't load them from 32-bit code");
+        return;
+    }
+    // The AP turns on paging while running in this page, so it must be mapped at its physical address too.
+    let base = frame.start_address();
+    let page = Page::containing_address(VirtAddr::new(base.as_u64()));
+    if unsafe { paging::map_to(page, frame, PageTableFlags::PRESENT) }.is_err() {
+        serial_println!("smp: cannot identity-map the AP startup code at {:#x}", base.as_u64());
+        return;
+    }
+    let trampoline = unsafe { Trampoline::install(base.as_u64(), phys_to_virt(base).as_mut_ptr()) };
+    let boot = |stack_top: VirtAddr, arg: usize| Boot {
+        cr0: Cr0::read_raw(),
+        cr3: cr3.start_address().as_u64(),
+        // PCIDs can only be enabled once in long mode.
+        cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+        efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+        stack_top: stack_top.as_u64(),
+        entry: ap_main,
+        arg,
+    };
+
+    let mut stranded = false;
+    for (index, cpu) in cpus.iter().enumerate().skip(1) {
+        let Some(stack) = stack::alloc("ap", AP_STACK_PAGES) else {
+            serial_println!("smp: out of memory for AP stacks");
+            break;
+        };
+        cpu.tables.call_once(gdt::alloc_ap);
+        trampoline.prepare(&boot(stack.top, index));
+        if !start(cpu, &trampoline) {
+            serial_println!("smp: CPU {} (APIC ID {}) did not start", index, cpu.apic_id);
+            stranded = true;
+        }
+    }
+
+    // A CPU that missed its deadline may still run the code later; leave it in place then.
+    if !stranded {
+        paging::unmap(page).expect("smp: trampoline page not mapped");
+        unsafe { frame_alloc::deallocate_frame(frame) };
+    }
+}
+
+/// INIT, wait 10ms, then up to two SIPIs 200us apart (Intel SDM 8.4.4.1);
+/// true once the AP reports in.
+fn start(cpu: &Cpu, trampoline: &Trampoline) -> bool {
+    lapic::send_init(cpu.apic_id);
+    time::busy_wait_us(10_000);
+    for _ in 0..2 {
+        lapic::send_startup(cpu.apic_id, trampoline.vector());
+        time::busy_wait_us(200);
+        if cpu.is_online() {
+            return true;
+        }
+    }
+    for _ in 0..100 {
+        if cpu.is_online() {
+            return true;
+        }
+        time::busy_wait_us(1000);
+    }
+    false
+}
+
+/// Where an AP lands in Rust, on its own stack with interrupts off.
+extern "C" fn ap_main(index: usize) -> ! {
+    let cpu = &cpus()[index];
+    gdt::load(cpu.tables.get().expect("AP started without its tables"));
+    crate::user::init_cpu();
+    crate::interrupts::init_ap();
+    dma::init_ap();
+    // Forget the trampoline's identity mapping; the BSP removes it soon.
+    tlb::flush_all();
+    lapic::enable();
+    cpu.online.store(true, Ordering::Release);
+    // Halt until the scheduler has use for this CPU; `thread::init` adopts
+    // this loop as the CPU's idle thread.
+    thread::idle()
+}
+
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+pub fn online() -> usize {
+    cpus().iter().filter(|cpu| cpu.is_online()).count()
+}
+
+/// Whether other CPUs are running besides the BSP.
+pub fn is_multi() -> bool {
+    MULTI.load(Ordering::Acquire)
+}
+
+/// Index into `cpus()` of the calling CPU; 0 before `init`.
+pub fn current() -> usize {
+    if CPUS.get().is_none() {
+        return 0;
+    }
+    INDEX[lapic::id() as usize].load(Ordering::Relaxed) as usize
+}
+
+/// Make CPU `index` run the scheduler.
+pub fn send_wakeup(index: usize) {
+    if let Some(cpu) = cpus().get(index) {
+        lapic::send_ipi(cpu.apic_id, WAKEUP_VECTOR);
+    }
+}
+
+/// Pass a timer tick on to the other CPUs. Called by the BSP's timer interrupt.
+pub fn broadcast_tick() {
+    if is_multi() {
+        lapic::broadcast_ipi(TICK_VECTOR);
+    }
+}
+
+pub fn dump() {
+    let mut usage = [None; MAX_CPUS];
+    for (index, stats) in thread::cpu_stats().into_iter().flatten() {
+        usage[index] = Some(stats);
+    }
+    serial_println!("  cpu  apic  busy  last 1s  state");
+    for (index, cpu) in cpus().iter().enumerate() {
+        let state = match (index, cpu.is_online()) {
+            (0, _) => "online (bootstrap)",
+            (_, true) => "online",
+            (_, false) => "offline",
+        };
+        let (total, recent) = match usage[index] {
+            Some(stats) => (alloc::format!("{}%", stats.busy_percent()), alloc::format!("{}%", stats.recent_percent())),
+            None => ("-".into(), "-".into()),
+        };
+        serial_println!("  {:>3}  {:>4}  {:>4}  {:>7}  {}", index, cpu.apic_id, total, recent, state);
+    }
+}
@@ -0,0 +1,178 @@
+use core::arch::global_asm;
+use core::ptr::addr_of;
+
+// Startup code for application processors. The bootstrap processor copies it
+// to a page below 1 MiB and points a SIPI at it, so an AP starts here in real
+// mode with CS = page >> 4 and IP = 0. The code never assumes where that page
+// is: real mode addresses through CS/DS, protected mode through EBX (the page's
+// base), long mode RIP-relative. The addresses that far jumps and `lgdt` need
+// are patched in by `Trampoline::install`.
+//
+// The path is real mode -> 32-bit protected mode (flat GDT below) -> long mode
+// with the kernel's own CR3/CR4/EFER/CR0 -> the kernel stack and `ap_entry(arg)`.
+// The page must also be identity-mapped, since turning on paging happens in it.
+global_asm!(
+    ".global ap_trampoline_start, ap_trampoline_end, ap_prot32, ap_long64",
+    ".global ap_gdt, ap_gdt_ptr, ap_far32, ap_far64",
+    ".global ap_cr0, ap_cr3, ap_cr4, ap_efer, ap_stack, ap_entry, ap_arg",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    xor ebx, ebx",
+    "    mov bx, ax",
+    "    shl ebx, 4",
+    "    lgdt [ap_gdt_ptr_offset]",
+    "    mov eax, cr0",
+    "    or eax, 1",
+    "    mov cr0, eax",
+    "    jmp fword ptr [ap_far32_offset]",
+    ".code32",
+    "ap_prot32:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov eax, [ebx + ap_cr4_offset]",
+    "    mov cr4, eax",
+    "    mov eax, [ebx + ap_cr3_offset]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080",
+    "    mov eax, [ebx + ap_efer_offset]",
+    "    xor edx, edx",
+    "    wrmsr",
+    // Paging on with EFER.LME set: compatibility mode until the far jump.
+    "    mov eax, [ebx + ap_cr0_offset]",
+    "    mov cr0, eax",
+    "    jmp fword ptr [ebx + ap_far64_offset]",
+    ".code64",
+    "ap_long64:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, [rip + ap_stack]",
+    "    mov rdi, [rip + ap_arg]",
+    "    call [rip + ap_entry]",
+    "    ud2",
+    // Null, 32-bit code, data, 64-bit code. The accessed bits are preset so
+    // the CPU never writes to the (read-only) page when loading a selector.
+    ".align 8",
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00CF9B000000FFFF",
+    "    .quad 0x00CF93000000FFFF",
+    "    .quad 0x00AF9B000000FFFF",
+    "ap_gdt_ptr:",
+    "    .word 31",
+    "    .long 0",
+    "ap_far32:",
+    "    .long 0",
+    "    .word 0x08",
+    "ap_far64:",
+    "    .long 0",
+    "    .word 0x18",
+    ".align 8",
+    "ap_cr0: .quad 0",
+    "ap_cr3: .quad 0",
+    "ap_cr4: .quad 0",
+    "ap_efer: .quad 0",
+    "ap_stack: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_arg: .quad 0",
+    "ap_trampoline_end:",
+    // Intel syntax takes one symbol per memory operand, so name the offsets.
+    ".set ap_gdt_ptr_offset, ap_gdt_ptr - ap_trampoline_start",
+    ".set ap_far32_offset, ap_far32 - ap_trampoline_start",
+    ".set ap_far64_offset, ap_far64 - ap_trampoline_start",
+    ".set ap_cr0_offset, ap_cr0 - ap_trampoline_start",
+    ".set ap_cr3_offset, ap_cr3 - ap_trampoline_start",
+    ".set ap_cr4_offset, ap_cr4 - ap_trampoline_start",
+    ".set ap_efer_offset, ap_efer - ap_trampoline_start",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_prot32: u8;
+    static ap_long64: u8;
+    static ap_gdt: u8;
+    static ap_gdt_ptr: u8;
+    static ap_far32: u8;
+    static ap_far64: u8;
+    static ap_cr0: u8;
+    static ap_cr3: u8;
+    static ap_cr4: u8;
+    static ap_efer: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_arg: u8;
+}
+
+/// What an AP loads on its way to long mode, and where it goes from there.
+pub struct Boot {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub stack_top: u64,
+    pub entry: extern "C" fn(usize) -> !,
+    pub arg: usize,
+}
+
+/// The startup code, copied to a low page.
+pub struct Trampoline {
+    /// Physical (and identity-mapped virtual) address of the copy.
+    base: u64,
+    /// Where the copy can be written, through the physical memory map.
+    copy: *mut u8,
+}
+
+/// Offset of a trampoline symbol from its start.
+fn offset(symbol: *const u8) -> usize {
+    symbol as usize - addr_of!(ap_trampoline_start) as usize
+}
+
+impl Trampoline {
+    pub fn len() -> usize {
+        offset(addr_of!(ap_trampoline_end))
+    }
+
+    /// Copy the code to `base` (writable at `copy`) and patch in its absolute addresses.
+    ///
+    /// # Safety
+    /// `copy` must map `len()` bytes at physical `base`, which must be page-aligned and below 1 MiB.
+    pub unsafe fn install(base: u64, copy: *mut u8) -> Self {
+        core::ptr::copy_nonoverlapping(addr_of!(ap_trampoline_start), copy, Self::len());
+        let trampoline = Trampoline { base, copy };
+        let absolute = |symbol: *const u8| (base + offset(symbol) as u64) as u32;
+        trampoline.write(addr_of!(ap_gdt_ptr), 2, absolute(addr_of!(ap_gdt)));
+        trampoline.write(addr_of!(ap_far32), 0, absolute(addr_of!(ap_prot32)));
+        trampoline.write(addr_of!(ap_far64), 0, absolute(addr_of!(ap_long64)));
+        trampoline
+    }
+
+    /// The SIPI vector: the page number of the code.
+    pub fn vector(&self) -> u8 {
+        (self.base >> 12) as u8
+    }
+
+    /// Fill in the parameters for the next AP to start.
+    pub fn prepare(&self, boot: &Boot) {
+        unsafe {
+            self.write(addr_of!(ap_cr0), 0, boot.cr0);
+            self.write(addr_of!(ap_cr3), 0, boot.cr3);
+            self.write(addr_of!(ap_cr4), 0, boot.cr4);
+            self.write(addr_of!(ap_efer), 0, boot.efer);
+            self.write(addr_of!(ap_stack), 0, boot.stack_top);
+            self.write(addr_of!(ap_entry), 0, boot.entry as usize as u64);
+            self.write(addr_of!(ap_arg), 0, boot.arg as u64);
+        }
+    }
+
+    unsafe fn write<T>(&self, symbol: *const u8, extra: usize, value: T) {
+        self.copy.add(offset(symbol) + extra).cast::<T>().write_unaligned(value);
+    }
+}
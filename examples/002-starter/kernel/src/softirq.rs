@@ -0,0 +1,182 @@
+//! Softirqs: per-CPU deferred processing at interrupt exit. A handler raises
+//! a softirq (timer expiry, network receive or transmit) and returns; the
+//! work runs as the interrupt is about to return, with interrupts still off.
+//!
+//! Each pass gives a softirq a `BUDGET` of items. After `MAX_ROUNDS` passes
+//! the rest is left to the `ksoftirqd` thread, which competes with the other
+//! threads for the CPU, so a flood of packets can't starve them.
+//!
+//! Handlers run with interrupts off and possibly on a preempted thread's
+//! stack: they must not allocate or block. The same softirq may run on two
+//! CPUs at once.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::smp::{self, MAX_CPUS};
+use crate::sync::WaitQueue;
+use crate::thread::{self, Priority};
+use crate::{serial_println, time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Softirq {
+    Timer,
+    NetRx,
+    NetTx,
+}
+
+impl Softirq {
+    const COUNT: usize = 3;
+    const ALL: [Softirq; Softirq::COUNT] = [Softirq::Timer, Softirq::NetRx, Softirq::NetTx];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Softirq::Timer => "timer",
+            Softirq::NetRx => "net-rx",
+            Softirq::NetTx => "net-tx",
+        }
+    }
+}
+
+/// Items a handler may process per call.
+pub const BUDGET: usize = 64;
+/// Passes over the pending softirqs at one interrupt exit before handing over to `ksoftirqd`.
+const MAX_ROUNDS: usize = 2;
+
+/// Does up to `budget` items of work; true if there is more left.
+pub type Handler = fn(budget: usize) -> bool;
+
+/// Registered handlers, as `fn` addresses (0 for none).
+static HANDLERS: [AtomicUsize; Softirq::COUNT] = [const { AtomicUsize::new(0) }; Softirq::COUNT];
+/// Raised softirqs per CPU, one bit each.
+static PENDING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+/// `ksoftirqd` sleeps here until an interrupt exit leaves work behind.
+static KSOFTIRQD: WaitQueue = WaitQueue::new();
+
+static RUNS: [AtomicU64; Softirq::COUNT] = [const { AtomicU64::new(0) }; Softirq::COUNT];
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+
+/// Start `ksoftirqd`. Needs `thread::init`.
+pub fn init() {
+    if thread::Builder::new().name("ksoftirqd").priority(Priority::Normal).spawn(ksoftirqd).is_none() {
+        serial_println!("softirq: cannot start ksoftirqd");
+    }
+}
+
+/// Install (or with `None`, remove) the handler for `softirq`.
+pub fn register(softirq: Softirq, handler: Option<Handler>) {
+    HANDLERS[softirq as usize].store(handler.map_or(0, |h| h as usize), Ordering::Release);
+}
+
+fn handler(softirq: Softirq) -> Option<Handler> {
+    match HANDLERS[softirq as usize].load(Ordering::Acquire) {
+        0 => None,
+        // Safety: only `register` stores non-zero values, all of them `Handler`s.
+        addr => Some(unsafe { core::mem::transmute::<usize, Handler>(addr) }),
+    }
+}
+
+/// Mark `softirq` pending on this CPU; it runs at the next interrupt exit.
+/// Safe in interrupt handlers.
+pub fn raise(softirq: Softirq) {
+    PENDING[smp::current()].fetch_or(softirq.bit(), Ordering::AcqRel);
+}
+
+/// Run this CPU's pending softirqs. Called at the end of interrupt handlers, with interrupts off.
+pub fn run() {
+    let pending = &PENDING[smp::current()];
+    for _ in 0..MAX_ROUNDS {
+        let bits = pending.swap(0, Ordering::AcqRel);
+        if bits == 0 {
+            return;
+        }
+        process(bits, pending);
+    }
+    if pending.load(Ordering::Acquire) != 0 {
+        DEFERRED.fetch_add(1, Ordering::Relaxed);
+        KSOFTIRQD.notify_one();
+    }
+}
+
+/// One pass over `bits`; softirqs with work left are raised again in `pending`.
+fn process(bits: u32, pending: &AtomicU32) {
+    for softirq in Softirq::ALL {
+        if bits & softirq.bit() == 0 {
+            continue;
+        }
+        RUNS[softirq as usize].fetch_add(1, Ordering::Relaxed);
+        if handler(softirq).is_some_and(|handler| handler(BUDGET)) {
+            pending.fetch_or(softirq.bit(), Ordering::AcqRel);
+        }
+    }
+}
+
+fn any_pending() -> bool {
+    PENDING.iter().any(|p| p.load(Ordering::Acquire) != 0)
+}
+
+/// Works off what interrupt exits left behind, one pass at a time, yielding in between.
+fn ksoftirqd() {
+    loop {
+        KSOFTIRQD.wait_until(any_pending);
+        for pending in &PENDING {
+            // Interrupts off, as at interrupt exit: handlers may share locks with interrupt handlers.
+            without_interrupts(|| process(pending.swap(0, Ordering::AcqRel), pending));
+        }
+        thread::yield_now();
+    }
+}
+
+pub fn dump() {
+    serial_println!("  softirq     runs");
+    for softirq in Softirq::ALL {
+        let registered = if handler(softirq).is_some() { "" } else { "  (no handler)" };
+        serial_println!("  {:<8} {:>7}{}", softirq.name(), RUNS[softirq as usize].load(Ordering::Relaxed), registered);
+    }
+    serial_println!("  {} interrupt exits handed work to ksoftirqd", DEFERRED.load(Ordering::Relaxed));
+}
+
+/// Fake receive backlog for the self-test.
+static BACKLOG: AtomicUsize = AtomicUsize::new(0);
+/// Most items one call took.
+static LARGEST_BATCH: AtomicUsize = AtomicUsize::new(0);
+
+fn drain_backlog(budget: usize) -> bool {
+    let left = BACKLOG.load(Ordering::Relaxed);
+    let batch = left.min(budget);
+    BACKLOG.store(left - batch, Ordering::Relaxed);
+    LARGEST_BATCH.fetch_max(batch, Ordering::Relaxed);
+    left > batch
+}
+
+/// A backlog too big for one interrupt exit is worked off in budget-sized
+/// batches, the remainder by `ksoftirqd`.
+pub fn self_test() -> bool {
+    if handler(Softirq::NetRx).is_some() {
+        serial_println!("softirq test: net-rx is in use");
+        return false;
+    }
+    const ITEMS: usize = BUDGET * MAX_ROUNDS * 4;
+    BACKLOG.store(ITEMS, Ordering::Relaxed);
+    LARGEST_BATCH.store(0, Ordering::Relaxed);
+    register(Softirq::NetRx, Some(drain_backlog));
+    let left_at_exit = without_interrupts(|| {
+        raise(Softirq::NetRx);
+        run();
+        BACKLOG.load(Ordering::Relaxed)
+    });
+    for _ in 0..10 {
+        if BACKLOG.load(Ordering::Relaxed) == 0 {
+            break;
+        }
+        time::sleep(core::time::Duration::from_millis(10));
+    }
+    register(Softirq::NetRx, None);
+    left_at_exit == ITEMS - BUDGET * MAX_ROUNDS
+        && BACKLOG.load(Ordering::Relaxed) == 0
+        && LARGEST_BATCH.load(Ordering::Relaxed) == BUDGET
+}
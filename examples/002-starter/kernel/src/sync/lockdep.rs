@@ -0,0 +1,263 @@
+//! Lock-order validation ("lockdep"), debug builds only.
+//!
+//! Every `SpinLock` and `Mutex` acquisition is checked against the order
+//! locks have been taken in so far, anywhere in the kernel: taking B while
+//! holding A records "A before B", and a later attempt to take A while
+//! holding B (directly or through a chain of other locks) is reported as a
+//! possible deadlock, even if the two paths never actually race.
+//!
+//! A lock is identified by its address, so this suits locks in statics best.
+//! Each thread's held locks live in its control block. Reports are printed
+//! later by the work queue: printing takes a lock of its own.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::serial_println;
+use crate::smp::{self, MAX_CPUS};
+use crate::workqueue::Work;
+
+const MAX_CLASSES: usize = 128;
+/// Locks one thread can hold at once and still be tracked.
+const MAX_HELD: usize = 16;
+const MAX_REPORTS: usize = 8;
+
+/// One bit per class.
+type Set = [u64; MAX_CLASSES / 64];
+
+fn contains(set: &Set, class: usize) -> bool {
+    set[class / 64] & (1 << (class % 64)) != 0
+}
+
+fn insert(set: &mut Set, class: usize) {
+    set[class / 64] |= 1 << (class % 64);
+}
+
+fn remove(set: &mut Set, class: usize) {
+    set[class / 64] &= !(1 << (class % 64));
+}
+
+/// Locks a thread holds, in the order it took them.
+pub struct Held {
+    classes: [u8; MAX_HELD],
+    len: usize,
+}
+
+impl Held {
+    pub const fn new() -> Self {
+        Held { classes: [0; MAX_HELD], len: 0 }
+    }
+}
+
+struct Class {
+    lock: usize,
+    /// Where it was first locked; how reports name it.
+    site: &'static Location<'static>,
+}
+
+struct Graph {
+    classes: [Option<Class>; MAX_CLASSES],
+    /// `after[a]` holds `b` once `b` was taken while `a` was held.
+    after: [Set; MAX_CLASSES],
+    /// Pairs already reported, so each is reported once.
+    reported: [Set; MAX_CLASSES],
+}
+
+impl Graph {
+    fn class(&mut self, lock: usize, site: &'static Location<'static>) -> Option<usize> {
+        if let Some(class) = self.classes.iter().position(|c| c.as_ref().is_some_and(|c| c.lock == lock)) {
+            return Some(class);
+        }
+        let class = self.classes.iter().position(Option::is_none)?;
+        self.classes[class] = Some(Class { lock, site });
+        Some(class)
+    }
+
+    /// Whether `to` was ever taken after `from`, directly or through other locks.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut seen: Set = [0; MAX_CLASSES / 64];
+        let mut stack = [0u8; MAX_CLASSES];
+        let mut len = 1;
+        stack[0] = from as u8;
+        insert(&mut seen, from);
+        while len > 0 {
+            len -= 1;
+            let class = stack[len] as usize;
+            for next in (0..MAX_CLASSES).filter(|&next| contains(&self.after[class], next)) {
+                if next == to {
+                    return true;
+                }
+                if !contains(&seen, next) {
+                    insert(&mut seen, next);
+                    stack[len] = next as u8;
+                    len += 1;
+                }
+            }
+        }
+        false
+    }
+
+    fn site(&self, class: usize) -> &'static Location<'static> {
+        self.classes[class].as_ref().expect("lockdep class").site
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Report {
+    held: &'static Location<'static>,
+    taking: &'static Location<'static>,
+    at: &'static Location<'static>,
+}
+
+/// Not instrumented itself; locked with interrupts off.
+static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+    classes: [const { None }; MAX_CLASSES],
+    after: [[0; MAX_CLASSES / 64]; MAX_CLASSES],
+    reported: [[0; MAX_CLASSES / 64]; MAX_CLASSES],
+});
+static REPORTS: Mutex<[Option<Report>; MAX_REPORTS]> = Mutex::new([None; MAX_REPORTS]);
+static INVERSIONS: AtomicU64 = AtomicU64::new(0);
+static PRINT_REPORTS: Work = Work::new(print_reports);
+
+/// The running thread's `Held`, per CPU; null before the scheduler is up.
+static HELD: [AtomicPtr<Held>; MAX_CPUS] = [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+/// Called by the scheduler whenever `cpu` starts running another thread.
+pub fn set_current(cpu: usize, held: *mut Held) {
+    HELD[cpu].store(held, Ordering::Relaxed);
+}
+
+/// Run `f` on the current thread's held locks, with interrupts off (so the thread can't move).
+fn with_held<R>(f: impl FnOnce(&mut Held, &mut Graph) -> R) -> Option<R> {
+    without_interrupts(|| {
+        let held = unsafe { HELD[smp::current()].load(Ordering::Relaxed).as_mut()? };
+        Some(f(held, &mut GRAPH.lock()))
+    })
+}
+
+/// About to wait for `lock` at `at`: check it against every lock already held.
+pub fn check(lock: usize, at: &'static Location<'static>) {
+    with_held(|held, graph| {
+        let Some(class) = graph.class(lock, at) else { return };
+        for &h in &held.classes[..held.len] {
+            let h = h as usize;
+            if h == class {
+                continue;
+            }
+            if graph.reaches(class, h) {
+                if !contains(&graph.reported[h], class) {
+                    insert(&mut graph.reported[h], class);
+                    report(Report { held: graph.site(h), taking: graph.site(class), at });
+                }
+            } else {
+                insert(&mut graph.after[h], class);
+            }
+        }
+    });
+}
+
+fn report(report: Report) {
+    INVERSIONS.fetch_add(1, Ordering::Relaxed);
+    if let Some(slot) = REPORTS.lock().iter_mut().find(|r| r.is_none()) {
+        *slot = Some(report);
+    }
+    PRINT_REPORTS.schedule();
+}
+
+/// `lock` was taken.
+pub fn push(lock: usize, site: &'static Location<'static>) {
+    with_held(|held, graph| {
+        if let Some(class) = graph.class(lock, site) {
+            if held.len < MAX_HELD {
+                held.classes[held.len] = class as u8;
+                held.len += 1;
+            }
+        }
+    });
+}
+
+/// `lock` was released, in whatever order.
+pub fn pop(lock: usize) {
+    with_held(|held, graph| {
+        let Some(class) = graph.classes.iter().position(|c| c.as_ref().is_some_and(|c| c.lock == lock)) else { return };
+        if let Some(i) = held.classes[..held.len].iter().rposition(|&c| c as usize == class) {
+            held.classes.copy_within(i + 1..held.len, i);
+            held.len -= 1;
+        }
+    });
+}
+
+/// `lock` is going away; its address may be reused by an unrelated lock.
+pub fn forget(lock: usize) {
+    without_interrupts(|| {
+        let mut guard = GRAPH.lock();
+        let graph = &mut *guard;
+        let Some(class) = graph.classes.iter().position(|c| c.as_ref().is_some_and(|c| c.lock == lock)) else { return };
+        graph.classes[class] = None;
+        graph.after[class] = [0; MAX_CLASSES / 64];
+        graph.reported[class] = [0; MAX_CLASSES / 64];
+        for set in graph.after.iter_mut().chain(graph.reported.iter_mut()) {
+            remove(set, class);
+        }
+    });
+}
+
+fn print_reports() {
+    let reports = without_interrupts(|| core::mem::replace(&mut *REPORTS.lock(), [None; MAX_REPORTS]));
+    for report in reports.into_iter().flatten() {
+        serial_println!(
+            "lockdep: possible deadlock: taking the lock from {} at {} while holding the lock from {}; \
+             elsewhere they were taken the other way round",
+            report.taking,
+            report.at,
+            report.held
+        );
+    }
+}
+
+pub fn dump() {
+    let (classes, edges) = without_interrupts(|| {
+        let graph = GRAPH.lock();
+        let classes = graph.classes.iter().flatten().count();
+        let edges: u32 = graph.after.iter().flatten().map(|word| word.count_ones()).sum();
+        (classes, edges)
+    });
+    serial_println!(
+        "lockdep: {} locks, {} ordering rules, {} inversions found",
+        classes,
+        edges,
+        INVERSIONS.load(Ordering::Relaxed)
+    );
+}
+
+/// Whether taking `taking` while holding `held` was reported.
+fn reported(held: usize, taking: usize) -> bool {
+    without_interrupts(|| {
+        let graph = GRAPH.lock();
+        let class = |lock| graph.classes.iter().position(|c| c.as_ref().is_some_and(|c| c.lock == lock));
+        match (class(held), class(taking)) {
+            (Some(h), Some(t)) => contains(&graph.reported[h], t),
+            _ => false,
+        }
+    })
+}
+
+/// Taking two locks in both orders, never at the same time from two threads,
+/// is still reported (the report shows up on the console shortly after).
+pub fn self_test() -> bool {
+    static A: super::SpinLock<()> = super::SpinLock::new(());
+    static B: super::SpinLock<()> = super::SpinLock::new(());
+    let (a, b) = (&A as *const _ as usize, &B as *const _ as usize);
+    without_interrupts(|| {
+        let _a = A.lock();
+        let _b = B.lock();
+    });
+    let consistent = !reported(a, b);
+    without_interrupts(|| {
+        let _b = B.lock();
+        let _a = A.lock();
+    });
+    consistent && reported(b, a)
+}
@@ -0,0 +1,330 @@
+//! Blocking synchronization for kernel threads.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[cfg(debug_assertions)]
+pub mod lockdep;
+mod mutex;
+pub mod rcu;
+mod ring;
+mod rwlock;
+mod spinlock;
+mod wait_queue;
+
+pub use mutex::Mutex;
+pub use ring::{MpscQueue, SpscQueue};
+pub use rwlock::{Preference, RwLock};
+pub use spinlock::SpinLock;
+pub use wait_queue::{Condvar, WaitQueue};
+
+use crate::{serial_println, thread};
+
+/// A producer hands items to a consumer through a `Condvar`, threads parked
+/// on a `WaitQueue` are released by one `notify_all`, threads contending
+/// for a `Mutex` sleep until it is handed over (lending the owner their
+/// priority), an `RwLock` shares reads
+/// and orders a waiting writer by its preference, several producers feed
+/// one consumer through an `MpscQueue` without losing or repeating an entry,
+/// and a `SpinLock` serializes threads and knows its holder.
+pub fn self_test() -> bool {
+    const ITEMS: usize = 20;
+    let shared = Arc::new((spin::Mutex::new(VecDeque::new()), Condvar::new()));
+
+    let theirs = shared.clone();
+    let consumer = thread::spawn(move || {
+        let (queue, ready) = &*theirs;
+        let mut sum = 0;
+        for _ in 0..ITEMS {
+            let mut items = ready.wait_while(queue, |items: &mut VecDeque<usize>| items.is_empty());
+            sum += items.pop_front().expect("woken with an empty queue");
+        }
+        sum
+    });
+    for i in 1..=ITEMS {
+        shared.0.lock().push_back(i);
+        // The last item wakes every waiter, as a shutdown signal would.
+        if i == ITEMS {
+            shared.1.notify_all();
+        } else {
+            shared.1.notify_one();
+        }
+        if i % 3 == 0 {
+            thread::yield_now();
+        }
+    }
+    let mut ok = consumer.join() == ITEMS * (ITEMS + 1) / 2;
+
+    static GATE: WaitQueue = WaitQueue::new();
+    static OPEN: AtomicUsize = AtomicUsize::new(0);
+    static PASSED: AtomicUsize = AtomicUsize::new(0);
+    OPEN.store(0, Ordering::Relaxed);
+    PASSED.store(0, Ordering::Relaxed);
+    let waiters: alloc::vec::Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn(|| {
+                GATE.wait_until(|| OPEN.load(Ordering::Acquire) != 0);
+                PASSED.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    // Let them all get to the gate; none may pass while it is shut.
+    for _ in 0..5 {
+        thread::yield_now();
+    }
+    ok &= PASSED.load(Ordering::Relaxed) == 0;
+    OPEN.store(1, Ordering::Release);
+    GATE.notify_all();
+    for waiter in waiters {
+        waiter.join();
+    }
+    ok &= PASSED.load(Ordering::Relaxed) == 3;
+
+    ok && mutex_test() && inversion_test() && rwlock_test() && mpsc_test() && spinlock_test()
+}
+
+/// Threads that yield while holding the lock must not lose updates, and the
+/// ones that find it taken must block rather than spin.
+fn mutex_test() -> bool {
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 10;
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    static CONTENDED: AtomicUsize = AtomicUsize::new(0);
+    *COUNTER.lock() = 0;
+    CONTENDED.store(0, Ordering::Relaxed);
+
+    let workers: alloc::vec::Vec<_> = (0..THREADS)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..ROUNDS {
+                    let mut count = match COUNTER.try_lock() {
+                        Some(guard) => guard,
+                        None => {
+                            CONTENDED.fetch_add(1, Ordering::Relaxed);
+                            COUNTER.lock()
+                        }
+                    };
+                    let seen = *count;
+                    // A long critical section: everyone else gets a turn to contend.
+                    thread::yield_now();
+                    *count = seen + 1;
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join();
+    }
+    let ok = *COUNTER.lock() == THREADS * ROUNDS && CONTENDED.load(Ordering::Relaxed) > 0;
+    ok && owner_tracked(&COUNTER) && !COUNTER.is_locked()
+}
+
+/// Run for `ticks` ticks of this thread's own CPU time: ticks that pass while
+/// other threads run don't count.
+fn work(ticks: u64) {
+    let mut last = crate::time::ticks();
+    let mut done = 0;
+    while done < ticks {
+        let now = crate::time::ticks();
+        if now == last + 1 {
+            done += 1;
+        }
+        last = now;
+        core::hint::spin_loop();
+    }
+}
+
+/// Priority inversion on one CPU: a low-priority thread holds a mutex for 3
+/// ticks of work, a high-priority thread wants it, and a medium-priority one
+/// hogs the CPU for 20 ticks. Returns how long the high-priority thread waited.
+fn inversion(inherit: bool) -> Option<u64> {
+    use thread::{Builder, Priority};
+    static LOCK: Mutex<()> = Mutex::new(());
+    static HELD: AtomicBool = AtomicBool::new(false);
+
+    mutex::set_priority_inheritance(inherit);
+    HELD.store(false, Ordering::Relaxed);
+    let cpu = crate::smp::CpuMask::single(without_interrupts(crate::smp::current));
+    // Above them all until everything is set up; no aging, which would blur the picture.
+    let main = thread::current_id();
+    thread::set_priority(main, Priority::Realtime);
+    thread::set_aging(false);
+
+    let low = Builder::new().name("pi-low").priority(Priority::Low).affinity(cpu).spawn(|| {
+        let _guard = LOCK.lock();
+        HELD.store(true, Ordering::Relaxed);
+        work(3);
+    });
+    while low.is_some() && !HELD.load(Ordering::Relaxed) {
+        crate::time::sleep(core::time::Duration::from_millis(10));
+    }
+    let medium = Builder::new().name("pi-medium").priority(Priority::Normal).affinity(cpu).spawn(|| work(20));
+    let high = Builder::new().name("pi-high").priority(Priority::High).affinity(cpu).spawn(|| {
+        let start = crate::time::ticks();
+        drop(LOCK.lock());
+        crate::time::ticks() - start
+    });
+    thread::set_priority(main, Priority::Normal);
+
+    let waited = high.map(|high| high.join());
+    for handle in [medium, low].into_iter().flatten() {
+        handle.join();
+    }
+    thread::set_aging(true);
+    mutex::set_priority_inheritance(true);
+    waited
+}
+
+/// With inheritance the high-priority thread waits about as long as the
+/// critical section; without, behind the medium-priority hog too.
+fn inversion_test() -> bool {
+    match (inversion(false), inversion(true)) {
+        (Some(without), Some(with)) => with < 10 && without >= 15,
+        _ => false,
+    }
+}
+
+pub fn inversion_demo() {
+    for inherit in [false, true] {
+        match inversion(inherit) {
+            Some(waited) => serial_println!(
+                "priority inheritance {}: high-priority thread waited {} ticks (lock held for 3 ticks of work, medium hog 20)",
+                if inherit { "on " } else { "off" },
+                waited
+            ),
+            None => serial_println!("inversion demo: spawn failed"),
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+fn owner_tracked<T>(mutex: &Mutex<T>) -> bool {
+    let guard = mutex.lock();
+    let held = mutex.owner().is_some_and(|(id, _)| id == thread::current_id());
+    drop(guard);
+    held && mutex.owner().is_none()
+}
+
+#[cfg(not(debug_assertions))]
+fn owner_tracked<T>(_mutex: &Mutex<T>) -> bool {
+    true
+}
+
+/// Threads on any CPU incrementing under the lock (with interrupts off, as the rule is) lose nothing.
+fn spinlock_test() -> bool {
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 1000;
+    static COUNTER: SpinLock<usize> = SpinLock::new(0);
+    *COUNTER.lock() = 0;
+    let workers: alloc::vec::Vec<_> = (0..THREADS)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..ROUNDS {
+                    without_interrupts(|| *COUNTER.lock() += 1);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join();
+    }
+    let ok = without_interrupts(|| *COUNTER.lock()) == THREADS * ROUNDS;
+    ok && holder_tracked(&COUNTER) && !COUNTER.is_locked()
+}
+
+#[cfg(debug_assertions)]
+fn holder_tracked<T>(lock: &SpinLock<T>) -> bool {
+    without_interrupts(|| {
+        let guard = lock.lock();
+        let held = lock.holder().is_some_and(|h| h.thread == Some(thread::current_id()) && h.cpu == crate::smp::current());
+        drop(guard);
+        held && lock.holder().is_none()
+    })
+}
+
+#[cfg(not(debug_assertions))]
+fn holder_tracked<T>(_lock: &SpinLock<T>) -> bool {
+    true
+}
+
+/// Lock a spinlock twice on purpose: debug builds panic with both call sites.
+#[cfg(debug_assertions)]
+pub fn deadlock_demo() {
+    static LOCK: SpinLock<()> = SpinLock::new(());
+    without_interrupts(|| {
+        let _first = LOCK.lock();
+        let _second = LOCK.lock();
+    });
+}
+
+/// Readers share the lock; a blocked writer holds back new readers only under
+/// writer preference.
+fn rwlock_test() -> bool {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let mut ok = true;
+    for preference in [Preference::Readers, Preference::Writers] {
+        let lock = Arc::new(match preference {
+            Preference::Writers => RwLock::new(0),
+            Preference::Readers => RwLock::with_preference(0, preference),
+        });
+        WRITES.store(0, Ordering::Relaxed);
+        let first = lock.read();
+        ok &= lock.try_read().is_some() && lock.try_write().is_none();
+
+        let theirs = lock.clone();
+        let writer = thread::spawn(move || {
+            *theirs.write() += 1;
+            WRITES.fetch_add(1, Ordering::Relaxed);
+        });
+        for _ in 0..5 {
+            thread::yield_now();
+        }
+        // The writer is parked behind `first`.
+        ok &= WRITES.load(Ordering::Relaxed) == 0 && lock.readers() == 1;
+        ok &= lock.try_read().is_some() == (preference == Preference::Readers);
+        drop(first);
+        writer.join();
+        ok &= *lock.read() == 1 && lock.readers() == 0;
+    }
+    ok
+}
+
+fn mpsc_test() -> bool {
+    const PRODUCERS: usize = 3;
+    const ITEMS: usize = 200;
+    static QUEUE: MpscQueue<usize, 16> = MpscQueue::new();
+
+    let producers: alloc::vec::Vec<_> = (0..PRODUCERS)
+        .map(|p| {
+            thread::spawn(move || {
+                for i in 0..ITEMS {
+                    let mut item = p * ITEMS + i;
+                    // Full: let the consumer catch up.
+                    while let Err(back) = QUEUE.push(item) {
+                        item = back;
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut seen = alloc::vec![false; PRODUCERS * ITEMS];
+    let mut received = 0;
+    let mut ok = true;
+    while received < PRODUCERS * ITEMS {
+        // Safety: this thread is the only consumer.
+        match unsafe { QUEUE.pop() } {
+            Some(item) => {
+                ok &= !core::mem::replace(&mut seen[item], true);
+                received += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    for producer in producers {
+        producer.join();
+    }
+    ok && QUEUE.is_empty() && seen.iter().all(|&s| s)
+}
@@ -0,0 +1,180 @@
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(debug_assertions)]
+use core::{panic::Location, ptr, sync::atomic::AtomicPtr};
+
+#[cfg(debug_assertions)]
+use super::lockdep;
+use super::WaitQueue;
+use crate::thread::{self, ThreadId};
+
+/// Whether waiters lend their priority to the owner (off only to show why).
+static PRIORITY_INHERITANCE: AtomicBool = AtomicBool::new(true);
+
+/// Mutexes the current thread holds; what it inherited is given back when
+/// the last one is released.
+#[thread_local]
+static HELD: Cell<u32> = Cell::new(0);
+
+pub fn set_priority_inheritance(on: bool) {
+    PRIORITY_INHERITANCE.store(on, Ordering::Relaxed);
+}
+
+/// A sleeping lock: a thread that finds it taken blocks until it is released,
+/// instead of spinning. For long critical sections; never use it in interrupt
+/// handlers. There is no poisoning: a panic halts the kernel anyway.
+///
+/// A waiter lends its priority to the owner (priority inheritance), so a
+/// low-priority owner can't keep a high-priority waiter waiting behind
+/// medium-priority threads. The owner keeps it until it releases the last
+/// mutex it holds.
+///
+/// Debug builds record the owning thread and where it took the lock, panic
+/// on a thread locking a mutex it already holds, and check lock ordering
+/// (see `lockdep`).
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    /// Owner's thread id + 1, or 0 when unlocked or taken before threads.
+    owner: AtomicU64,
+    #[cfg(debug_assertions)]
+    site: AtomicPtr<Location<'static>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// Released by the thread that locked it: it counts in that thread's `HELD`.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            owner: AtomicU64::new(0),
+            #[cfg(debug_assertions)]
+            site: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Take the lock, blocking while another thread holds it.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            self.check_relock();
+            lockdep::check(self.addr(), Location::caller());
+        }
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            if thread::initialized() {
+                if let Some(owner) = self.owner_id().filter(|_| PRIORITY_INHERITANCE.load(Ordering::Relaxed)) {
+                    thread::lend_priority(owner);
+                }
+                self.waiters.wait_until(|| !self.locked.load(Ordering::Relaxed));
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        if thread::initialized() {
+            self.owner.store(thread::current_id().0 + 1, Ordering::Relaxed);
+            HELD.set(HELD.get() + 1);
+        }
+        #[cfg(debug_assertions)]
+        self.set_site();
+        Some(MutexGuard { mutex: self, _not_send: PhantomData })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn owner_id(&self) -> Option<ThreadId> {
+        self.owner.load(Ordering::Relaxed).checked_sub(1).map(ThreadId)
+    }
+
+    /// Who holds the lock and where they took it (debug builds only).
+    #[cfg(debug_assertions)]
+    pub fn owner(&self) -> Option<(ThreadId, &'static Location<'static>)> {
+        let id = self.owner_id()?;
+        let site = unsafe { self.site.load(Ordering::Relaxed).as_ref()? };
+        Some((id, site))
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn set_site(&self) {
+        self.site.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+        lockdep::push(self.addr(), Location::caller());
+    }
+
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_relock(&self) {
+        if !thread::initialized() {
+            return;
+        }
+        if let Some((owner, site)) = self.owner() {
+            if owner == thread::current_id() {
+                panic!("mutex deadlock: thread {} locks at {} what it already locked at {}", owner.0, Location::caller(), site);
+            }
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        lockdep::pop(self.mutex.addr());
+        let owned = self.mutex.owner.swap(0, Ordering::Relaxed) != 0;
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+        if owned {
+            HELD.set(HELD.get() - 1);
+            if HELD.get() == 0 {
+                thread::end_inheritance();
+            }
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.addr());
+    }
+}
@@ -0,0 +1,259 @@
+//! Read-copy-update: readers of a read-mostly structure take no lock at all,
+//! writers publish a new copy and free the old one only once no reader can
+//! still be looking at it.
+//!
+//! A read-side section (`read_lock`) disables preemption and must not sleep.
+//! So once a CPU has taken a timer tick with preemption enabled, it has left
+//! every section that was open on it before: a quiescent state. A grace
+//! period (`synchronize`) waits until every online CPU has had one, which
+//! takes a tick or two. `call_rcu` runs a callback after a grace period, on
+//! the `rcu` thread, without making the caller wait.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::{Mutex, WaitQueue};
+use crate::smp::{self, MAX_CPUS};
+use crate::{preempt, serial_println, thread, time};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Quiescent states seen per CPU.
+static QUIESCENT: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static CALLBACKS: Mutex<Vec<Callback>> = Mutex::new(Vec::new());
+/// The `rcu` thread sleeps here while there are no callbacks.
+static QUEUED: WaitQueue = WaitQueue::new();
+static HAVE_CALLBACKS: AtomicBool = AtomicBool::new(false);
+
+static GRACE_PERIODS: AtomicU64 = AtomicU64::new(0);
+static CALLBACKS_RUN: AtomicU64 = AtomicU64::new(0);
+
+/// Start the thread that runs `call_rcu` callbacks. Needs `thread::init`.
+pub fn init() {
+    if thread::Builder::new().name("rcu").spawn(callback_thread).is_none() {
+        serial_println!("rcu: cannot start the callback thread");
+    }
+}
+
+/// From the timer tick, when it finds preemption enabled (interrupts are off).
+pub fn quiescent() {
+    QUIESCENT[smp::current()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// A read-side section; ends when dropped. Not `Send`, like the preemption
+/// guard it holds.
+pub struct ReadGuard {
+    _preempt: preempt::Guard,
+}
+
+/// Enter a read-side section: what `Rcu::read` returns stays valid until the
+/// guard is dropped. Sections nest; they must not sleep.
+pub fn read_lock() -> ReadGuard {
+    ReadGuard { _preempt: preempt::disable() }
+}
+
+/// Wait until every read-side section that was open when this was called has
+/// ended. Sleeps: not from inside a section.
+pub fn synchronize() {
+    if !thread::initialized() {
+        // Nothing else runs yet, so nobody is reading.
+        return;
+    }
+    let online: Vec<usize> = match smp::cpus() {
+        [] => Vec::from([0]),
+        cpus => cpus.iter().enumerate().filter(|(_, cpu)| cpu.is_online()).map(|(index, _)| index).collect(),
+    };
+    let start: Vec<u64> = online.iter().map(|&cpu| QUIESCENT[cpu].load(Ordering::Relaxed)).collect();
+    while online.iter().zip(&start).any(|(&cpu, &start)| QUIESCENT[cpu].load(Ordering::Relaxed) == start) {
+        time::sleep(Duration::from_millis(1000 / time::HZ));
+    }
+    GRACE_PERIODS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Run `callback` after a grace period, on the `rcu` thread. Returns at once.
+pub fn call_rcu(callback: impl FnOnce() + Send + 'static) {
+    CALLBACKS.lock().push(Box::new(callback));
+    HAVE_CALLBACKS.store(true, Ordering::Release);
+    QUEUED.notify_one();
+}
+
+/// Takes what has been queued so far, waits one grace period for all of it, runs it.
+fn callback_thread() {
+    loop {
+        QUEUED.wait_until(|| HAVE_CALLBACKS.load(Ordering::Acquire));
+        HAVE_CALLBACKS.store(false, Ordering::Relaxed);
+        let batch = core::mem::take(&mut *CALLBACKS.lock());
+        if batch.is_empty() {
+            continue;
+        }
+        synchronize();
+        for callback in batch {
+            callback();
+            CALLBACKS_RUN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A pointer readers follow without locking. Writers replace the whole value;
+/// the old one is freed after a grace period.
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+    _owns: PhantomData<Box<T>>,
+}
+
+// Readers on any thread get `&T`; writers hand old values to the `rcu` thread.
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send> Send for Rcu<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// Nothing published yet.
+    pub const fn empty() -> Self {
+        Rcu { ptr: AtomicPtr::new(ptr::null_mut()), _owns: PhantomData }
+    }
+
+    /// The current value, valid for the read-side section.
+    pub fn read<'a>(&'a self, _guard: &'a ReadGuard) -> Option<&'a T> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Publish `value`; the old one is dropped by the `rcu` thread after a grace period.
+    pub fn replace(&self, value: T) {
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        if !old.is_null() {
+            // Carried as an address: raw pointers aren't `Send`.
+            let old = old as usize;
+            call_rcu(move || drop(unsafe { Box::from_raw(old as *mut T) }));
+        }
+    }
+
+    /// Publish `value` and return the old one once no reader can see it any more.
+    pub fn swap(&self, value: T) -> Option<Box<T>> {
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        if old.is_null() {
+            return None;
+        }
+        synchronize();
+        Some(unsafe { Box::from_raw(old) })
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // `&mut self`: no reader can hold a reference any more.
+        let old = *self.ptr.get_mut();
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+pub fn dump() {
+    serial_println!(
+        "rcu: {} grace periods, {} callbacks run, {} pending",
+        GRACE_PERIODS.load(Ordering::Relaxed),
+        CALLBACKS_RUN.load(Ordering::Relaxed),
+        CALLBACKS.lock().len()
+    );
+}
+
+/// A config struct readers check for consistency: `sum` is always `a + b`.
+struct Config {
+    a: u64,
+    b: u64,
+    sum: u64,
+}
+
+static CONFIG: Rcu<Config> = Rcu::empty();
+
+/// Readers hammer `CONFIG` on every CPU while the writer swaps in a new one
+/// every tick; returns (reads, inconsistent reads, swaps).
+fn config_swap(ticks: u64) -> (u64, u64, u64) {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static READS: AtomicU64 = AtomicU64::new(0);
+    static TORN: AtomicU64 = AtomicU64::new(0);
+    STOP.store(false, Ordering::Relaxed);
+    READS.store(0, Ordering::Relaxed);
+    TORN.store(0, Ordering::Relaxed);
+    CONFIG.replace(Config { a: 0, b: 0, sum: 0 });
+
+    let readers: Vec<_> = (0..smp::online().max(2))
+        .filter_map(|_| {
+            thread::Builder::new().name("rcu-reader").spawn(|| {
+                while !STOP.load(Ordering::Relaxed) {
+                    let guard = read_lock();
+                    if let Some(config) = CONFIG.read(&guard) {
+                        if config.a + config.b != config.sum {
+                            TORN.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    drop(guard);
+                    READS.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    let mut swaps = 0;
+    let end = time::ticks() + ticks;
+    while time::ticks() < end {
+        swaps += 1;
+        CONFIG.replace(Config { a: swaps, b: swaps * 2, sum: swaps * 3 });
+        time::sleep(Duration::from_millis(1000 / time::HZ));
+    }
+    STOP.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join();
+    }
+    (READS.load(Ordering::Relaxed), TORN.load(Ordering::Relaxed), swaps)
+}
+
+pub fn demo() {
+    let (reads, torn, swaps) = config_swap(time::HZ);
+    serial_println!("rcu: {} lock-free reads across {} config swaps in 1s, {} inconsistent", reads, swaps, torn);
+}
+
+/// `synchronize` outlasts a reader that was already in its section, a
+/// callback runs only after one, and readers never see a half-made config.
+pub fn self_test() -> bool {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static CALLED: AtomicBool = AtomicBool::new(false);
+    STARTED.store(false, Ordering::Relaxed);
+    DONE.store(false, Ordering::Relaxed);
+    CALLED.store(false, Ordering::Relaxed);
+
+    let reader = thread::spawn(|| {
+        let _guard = read_lock();
+        STARTED.store(true, Ordering::Release);
+        // Spin, not sleep: sleeping in a read-side section is not allowed.
+        let end = time::ticks() + 5;
+        while time::ticks() < end {
+            core::hint::spin_loop();
+        }
+        DONE.store(true, Ordering::Release);
+    });
+    while !STARTED.load(Ordering::Acquire) {
+        thread::yield_now();
+    }
+    call_rcu(|| CALLED.store(true, Ordering::Release));
+    let early = CALLED.load(Ordering::Acquire);
+    synchronize();
+    let mut ok = DONE.load(Ordering::Acquire) && !early;
+    reader.join();
+    for _ in 0..10 {
+        if CALLED.load(Ordering::Acquire) {
+            break;
+        }
+        time::sleep(Duration::from_millis(10));
+    }
+    ok &= CALLED.load(Ordering::Acquire);
+
+    let (reads, torn, swaps) = config_swap(10);
+    ok &= reads > 0 && torn == 0 && swaps > 0;
+    // Taking back the last config the writer published.
+    let last = CONFIG.swap(Config { a: 0, b: 0, sum: 0 });
+    ok && last.is_some_and(|config| config.a == swaps && config.sum == swaps * 3)
+}
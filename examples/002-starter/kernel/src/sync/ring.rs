@@ -0,0 +1,154 @@
+//! Fixed-capacity lock-free rings for handing data from interrupt handlers to
+//! threads. Neither side takes a lock, so a handler can never spin on a
+//! thread it interrupted; neither side allocates.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Single-producer, single-consumer ring of `N` entries.
+pub struct SpscQueue<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Next slot to read; only the consumer moves it.
+    head: AtomicUsize,
+    /// Next slot to write; only the producer moves it.
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+impl<T, const N: usize> SpscQueue<T, N> {
+    pub const fn new() -> Self {
+        SpscQueue {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append `value`, or hand it back if the ring is full.
+    ///
+    /// # Safety
+    /// Only one context may push: one interrupt handler, or one thread.
+    pub unsafe fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Take the oldest entry.
+    ///
+    /// # Safety
+    /// Only one context may pop at a time.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+impl<T, const N: usize> Drop for SpscQueue<T, N> {
+    fn drop(&mut self) {
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+struct Slot<T> {
+    /// Equal to the claiming position when free, position + 1 once written.
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Multi-producer, single-consumer ring of `N` entries: any number of
+/// handlers and threads push, one thread pops.
+///
+/// Producers claim a slot, then publish it. An entry claimed by a producer
+/// that gets interrupted before publishing holds back the entries behind it
+/// until that producer resumes; nothing is lost.
+pub struct MpscQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    pub const fn new() -> Self {
+        let mut slots = [const { Slot { seq: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) } }; N];
+        let mut i = 0;
+        while i < N {
+            slots[i].seq = AtomicUsize::new(i);
+            i += 1;
+        }
+        MpscQueue { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    /// Append `value`, or hand it back if the ring is full. Safe from any context.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == pos {
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(now) => pos = now,
+                }
+            } else if (seq.wrapping_sub(pos) as isize) < 0 {
+                // The slot still holds the entry from one lap ago.
+                return Err(value);
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Take the oldest published entry.
+    ///
+    /// # Safety
+    /// Only one context may pop at a time.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[head % N];
+        if slot.seq.load(Ordering::Acquire) != head.wrapping_add(1) {
+            return None;
+        }
+        let value = unsafe { (*slot.value.get()).assume_init_read() };
+        slot.seq.store(head.wrapping_add(N), Ordering::Release);
+        self.head.store(head.wrapping_add(1), Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Whether an entry is ready to pop.
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        self.slots[head % N].seq.load(Ordering::Acquire) != head.wrapping_add(1)
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
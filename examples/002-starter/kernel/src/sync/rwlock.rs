@@ -0,0 +1,174 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::WaitQueue;
+use crate::thread;
+
+/// Set in `state` while a writer holds the lock; the low bits count readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// Who goes first when readers hold the lock and a writer is waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preference {
+    /// New readers keep coming in; a writer may wait as long as reads overlap.
+    Readers,
+    /// New readers queue behind a waiting writer, so updates are not starved.
+    Writers,
+}
+
+/// A reader-writer lock for read-mostly state. Waiters spin until threads
+/// exist and block on a wait queue after that.
+///
+/// Interrupt-safe: a writer holds the lock with interrupts off, so a handler
+/// never finds it write-locked, and readers in a handler skip the writer
+/// preference (the waiting writer may be the thread they interrupted). A
+/// handler that needs to write must use `try_write`.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    preference: Preference,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// Whether to turn interrupts back on at unlock.
+    interrupts: bool,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_preference(value, Preference::Writers)
+    }
+
+    pub const fn with_preference(value: T, preference: Preference) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            preference,
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Shared access; waits while a writer holds (or, with writer preference, wants) the lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.wait(|| self.may_read(self.state.load(Ordering::Relaxed)));
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if !self.may_read(state) {
+                return None;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(now) => state = now,
+            }
+        }
+    }
+
+    fn may_read(&self, state: usize) -> bool {
+        if state & WRITER != 0 {
+            return false;
+        }
+        self.preference == Preference::Readers
+            || !interrupts::are_enabled()
+            || self.writers_waiting.load(Ordering::Relaxed) == 0
+    }
+
+    /// Exclusive access. Interrupts stay off until the guard is dropped, so keep it short.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        let guard = loop {
+            if let Some(guard) = self.try_write() {
+                break guard;
+            }
+            self.wait(|| self.state.load(Ordering::Relaxed) == 0);
+        };
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        guard
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            return Some(RwLockWriteGuard { lock: self, interrupts: enabled });
+        }
+        if enabled {
+            interrupts::enable();
+        }
+        None
+    }
+
+    /// Readers holding the lock right now.
+    pub fn readers(&self) -> usize {
+        self.state.load(Ordering::Relaxed) & !WRITER
+    }
+
+    fn wait(&self, condition: impl FnMut() -> bool) {
+        if thread::initialized() && interrupts::are_enabled() {
+            self.queue.wait_until(condition);
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // The last reader out lets a waiting writer in.
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.queue.notify_all();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.queue.notify_all();
+        if self.interrupts {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::{
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+#[cfg(debug_assertions)]
+use super::lockdep;
+#[cfg(debug_assertions)]
+use crate::{smp, thread};
+
+/// Time-stamp counter cycles a lock may be waited for before debug builds
+/// call it a deadlock: a few seconds on any machine this runs on.
+#[cfg(debug_assertions)]
+const SPIN_LIMIT_CYCLES: u64 = 1 << 33;
+
+/// A spinning lock for short critical sections, usable in interrupt handlers
+/// (with the usual rule: lock it with interrupts off everywhere).
+///
+/// Debug builds record who holds it (thread, CPU and call site) and panic,
+/// naming both call sites, when the holder locks it again (which includes an
+/// interrupt handler locking what the thread it interrupted holds) or when a
+/// waiter has spun for `SPIN_LIMIT_CYCLES`. They also check the order it is
+/// taken in against other locks (see `lockdep`).
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    /// Holder's thread id + 1, or 0 if unknown (before threads) or unlocked.
+    #[cfg(debug_assertions)]
+    thread: AtomicU64,
+    /// Holder's CPU + 1, or 0 when unlocked.
+    #[cfg(debug_assertions)]
+    cpu: AtomicUsize,
+    #[cfg(debug_assertions)]
+    site: AtomicPtr<Location<'static>>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    lock: &'a SpinLock<T>,
+}
+
+/// Where a debug build last saw the lock taken.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+pub struct Holder {
+    pub thread: Option<thread::ThreadId>,
+    pub cpu: usize,
+    pub site: &'static Location<'static>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            inner: spin::Mutex::new(value),
+            #[cfg(debug_assertions)]
+            thread: AtomicU64::new(0),
+            #[cfg(debug_assertions)]
+            cpu: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        #[cfg(debug_assertions)]
+        lockdep::check(self.addr(), Location::caller());
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            #[cfg(debug_assertions)]
+            self.check_deadlock(start);
+            core::hint::spin_loop();
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(debug_assertions)]
+        {
+            self.thread.store(thread::try_current_id().map_or(0, |id| id.0 + 1), Ordering::Relaxed);
+            self.cpu.store(smp::current() + 1, Ordering::Relaxed);
+            self.site.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+            lockdep::push(self.addr(), Location::caller());
+        }
+        Some(SpinLockGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            lock: self,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Release the lock without its guard.
+    ///
+    /// # Safety
+    /// Only for the panic handler, so it can print through a lock the
+    /// panicking code held; the holder must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.cpu.store(0, Ordering::Relaxed);
+        unsafe { self.inner.force_unlock() };
+    }
+
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Who holds the lock and where they took it (debug builds only).
+    #[cfg(debug_assertions)]
+    pub fn holder(&self) -> Option<Holder> {
+        let cpu = self.cpu.load(Ordering::Relaxed).checked_sub(1)?;
+        let thread = self.thread.load(Ordering::Relaxed).checked_sub(1).map(thread::ThreadId);
+        let site = unsafe { self.site.load(Ordering::Relaxed).as_ref()? };
+        Some(Holder { thread, cpu, site })
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_deadlock(&self, start: u64) {
+        let Some(holder) = self.holder() else { return };
+        let here = smp::current();
+        if holder.cpu == here && holder.thread.is_some() && holder.thread == thread::try_current_id() {
+            panic!(
+                "spinlock deadlock: CPU {} locks at {} what it already locked at {} (thread {})",
+                here,
+                Location::caller(),
+                holder.site,
+                holder.thread.map_or(0, |id| id.0)
+            );
+        }
+        let waited = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+        if waited > SPIN_LIMIT_CYCLES {
+            panic!(
+                "spinlock stuck: CPU {} waited {} cycles at {}; held by thread {:?} on CPU {} since {}",
+                here,
+                waited,
+                Location::caller(),
+                holder.thread.map(|id| id.0),
+                holder.cpu,
+                holder.site
+            );
+        }
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Cleared before the inner guard unlocks, so a new holder's record isn't wiped.
+        self.lock.cpu.store(0, Ordering::Relaxed);
+        self.lock.thread.store(0, Ordering::Relaxed);
+        lockdep::pop(self.lock.addr());
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T> Drop for SpinLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.addr());
+    }
+}
@@ -0,0 +1,182 @@
+use core::time::Duration;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::thread::{self, ThreadId, MAX_THREADS};
+use crate::time;
+
+/// FIFO of blocked threads. Fixed size (a thread waits on one queue at a time),
+/// so notifying from an interrupt handler never allocates.
+struct Waiters {
+    ids: [ThreadId; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl Waiters {
+    fn push(&mut self, id: ThreadId) {
+        assert!(self.len < MAX_THREADS, "wait queue overflow");
+        self.ids[(self.head + self.len) % MAX_THREADS] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<ThreadId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+
+    /// Take `id` out of the queue, keeping the others in order.
+    fn remove(&mut self, id: ThreadId) {
+        for _ in 0..self.len {
+            let other = self.pop().expect("queue length");
+            if other != id {
+                self.push(other);
+            }
+        }
+    }
+}
+
+/// Threads blocked until some condition holds, typically "an interrupt has
+/// delivered data". Replaces polling loops: waiters use no CPU.
+pub struct WaitQueue {
+    /// Only locked with interrupts off: notifiers may be interrupt handlers.
+    waiters: Mutex<Waiters>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(Waiters { ids: [ThreadId(0); MAX_THREADS], head: 0, len: 0 }) }
+    }
+
+    /// Block until `condition` returns true.
+    ///
+    /// The thread queues itself before checking, with interrupts off, so a
+    /// notify can't be lost in between, from this CPU or another. The condition
+    /// must be quick and must not take locks a preempted thread could hold
+    /// (read an atomic or a device register).
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let done = without_interrupts(|| {
+                let id = thread::current_id();
+                self.waiters.lock().push(id);
+                if condition() {
+                    self.waiters.lock().remove(id);
+                    return true;
+                }
+                thread::block();
+                false
+            });
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// `wait_until` with a time limit: block until `condition` returns true
+    /// (`true`) or `time::uptime()` reaches `deadline` (`false`), whichever
+    /// comes first. A deadline already past only checks the condition.
+    pub fn wait_until_deadline(&self, mut condition: impl FnMut() -> bool, deadline: Duration) -> bool {
+        loop {
+            let outcome = without_interrupts(|| {
+                let id = thread::current_id();
+                self.waiters.lock().push(id);
+                if condition() {
+                    self.waiters.lock().remove(id);
+                    return Some(true);
+                }
+                if time::uptime() >= deadline {
+                    self.waiters.lock().remove(id);
+                    return Some(false);
+                }
+                let Some(timer) = time::wake_at(deadline) else {
+                    // Out of timer entries: poll instead.
+                    self.waiters.lock().remove(id);
+                    return None;
+                };
+                thread::block();
+                // Woken by a notify, by the timer or spuriously: undo both.
+                self.waiters.lock().remove(id);
+                time::cancel_wake(timer);
+                None
+            });
+            match outcome {
+                Some(done) => return done,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    /// Queue the current thread, with interrupts already off; `thread::block` next.
+    fn enqueue_current(&self) {
+        self.waiters.lock().push(thread::current_id());
+    }
+
+    /// Wake the longest waiting thread, if any. Safe in interrupt handlers.
+    pub fn notify_one(&self) -> bool {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            while let Some(id) = waiters.pop() {
+                if thread::unblock(id) {
+                    return true;
+                }
+            }
+            false
+        })
+    }
+
+    /// Wake every waiting thread; each re-checks its condition. Safe in interrupt handlers.
+    pub fn notify_all(&self) -> usize {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut woken = 0;
+            while let Some(id) = waiters.pop() {
+                woken += thread::unblock(id) as usize;
+            }
+            woken
+        })
+    }
+}
+
+/// Condition variable over a `spin::Mutex`: wait for the protected state to
+/// change without holding the lock or burning CPU.
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { queue: WaitQueue::new() }
+    }
+
+    /// Lock `mutex` and block while `condition` holds, releasing the lock while
+    /// asleep. Returns with the lock held and the condition false.
+    pub fn wait_while<'a, T>(&self, mutex: &'a Mutex<T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        loop {
+            // Locked with interrupts on: the holder may be a preempted thread.
+            let mut guard = mutex.lock();
+            if !condition(&mut guard) {
+                return guard;
+            }
+            // Queue, unlock and block with interrupts off. A notifier needs the
+            // lock to change the state, so it finds us queued.
+            without_interrupts(|| {
+                self.queue.enqueue_current();
+                drop(guard);
+                thread::block();
+            });
+        }
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.queue.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.queue.notify_all()
+    }
+}
@@ -0,0 +1,250 @@
+//! File system calls: opening files and reading, writing and seeking
+//! through descriptors (see `process::files`).
+//!
+//! Files are the VFS's (see `fs::vfs`): the initrd at `/`, devices at
+//! `/dev` (`/dev/console` is what 0, 1 and 2 start as), FAT volumes at
+//! `/mnt/<device>`. `open(path, path_len, flags)` takes the path as
+//! (pointer, length) like `spawn`, and Linux's O_* access mode in `flags`. `pipe` makes the other
+//! kind of descriptor there is: the two ends of a pipe (see `ipc::pipe`).
+//!
+//! A descriptor opened with O_CLOEXEC is not inherited by children and is
+//! closed by `exec`: how a shell keeps its own ends of a pipe out of the
+//! programs it connects with it.
+
+use alloc::sync::Arc;
+
+use super::proc::copy_path;
+use super::{Errno, SysResult};
+use crate::console;
+use crate::fs::vfs::{self, FileType};
+use crate::fs::FsError;
+use crate::ipc::pipe::{self, PipeError, PipeWriter};
+use crate::process::signal::{self, SIGPIPE};
+use crate::process::{self, Caps, File, OpenFile, MAX_FILES};
+use crate::user::uaccess::{self, UserPtr};
+
+/// Bytes moved per step, through a buffer on the kernel stack.
+const CHUNK: usize = 256;
+
+const O_ACCMODE: u32 = 3;
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CLOEXEC: u32 = 0o2000000;
+
+const SEEK_SET: u32 = 0;
+const SEEK_CUR: u32 = 1;
+const SEEK_END: u32 = 2;
+
+pub(super) fn errno(err: FsError) -> Errno {
+    match err {
+        FsError::NotFound => Errno::ENOENT,
+        FsError::NotADirectory => Errno::ENOTDIR,
+        FsError::IsADirectory => Errno::EISDIR,
+        FsError::Exists => Errno::EEXIST,
+        FsError::InvalidName => Errno::EINVAL,
+        FsError::NoSpace => Errno::ENOSPC,
+        FsError::ReadOnly => Errno::EROFS,
+        FsError::Corrupt(_) | FsError::Unsupported(_) | FsError::Io(_) => Errno::EIO,
+    }
+}
+
+/// read(fd, buf, len): blocks until there is input; returns how much was
+/// read, 0 at end of file. A signal cuts a wait on a pipe short: EINTR.
+pub(super) fn read(fd: u32, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let file = process::file(fd).ok_or(Errno::EBADF)?;
+    let read = match file {
+        File::Console => {
+            // Checked first: input taken can't be put back.
+            let n = len.min(CHUNK);
+            uaccess::check(buf.addr(), n, true)?;
+            let mut chunk = [0u8; CHUNK];
+            let read = console::read(&mut chunk[..n]);
+            uaccess::copy_to_user(buf, &chunk[..read])?;
+            read
+        }
+        File::Vfs(file) => {
+            let n = len.min(CHUNK);
+            uaccess::check(buf.addr(), n, true)?;
+            let mut chunk = [0u8; CHUNK];
+            let read = file.read(&mut chunk[..n]).map_err(errno)?;
+            uaccess::copy_to_user(buf, &chunk[..read])?;
+            read
+        }
+        File::PipeRead(end) => {
+            let n = len.min(CHUNK);
+            uaccess::check(buf.addr(), n, true)?;
+            let mut chunk = [0u8; CHUNK];
+            let read = end.read(&mut chunk[..n], signal::pending).map_err(|_| Errno::EINTR)?;
+            uaccess::copy_to_user(buf, &chunk[..read])?;
+            read
+        }
+        File::PipeWrite(_) => return Err(Errno::EBADF),
+    };
+    Ok(read as u64)
+}
+
+/// write(fd, buf, len).
+pub(super) fn write(fd: u32, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let file = process::file(fd).ok_or(Errno::EBADF)?;
+    match file {
+        File::Console => {}
+        File::PipeWrite(end) => return write_pipe(&end, buf, len),
+        File::Vfs(file) if file.writable() => return write_file(&file, buf, len),
+        File::Vfs(_) | File::PipeRead(_) => return Err(Errno::EBADF),
+    }
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(CHUNK);
+        uaccess::copy_from_user(&mut chunk[..n], UserPtr::new(buf.addr() + done as u64))?;
+        console::write(&chunk[..n]);
+        done += n;
+    }
+    Ok(len as u64)
+}
+
+/// `buf` into a file at its offset; how much was written, short if the
+/// disk fills.
+fn write_file(file: &OpenFile, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(CHUNK);
+        uaccess::copy_from_user(&mut chunk[..n], UserPtr::new(buf.addr() + done as u64))?;
+        match file.write(&chunk[..n]) {
+            Ok(written) if written < n => return Ok((done + written) as u64),
+            Ok(_) => done += n,
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(errno(err)),
+        }
+    }
+    Ok(done as u64)
+}
+
+/// All of `buf` into a pipe, waiting for room as often as it takes. A
+/// signal, or the read end closing, stops it early: how much was written by
+/// then, or if nothing was, EINTR, or EPIPE and a SIGPIPE.
+fn write_pipe(end: &PipeWriter, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(CHUNK);
+        uaccess::copy_from_user(&mut chunk[..n], UserPtr::new(buf.addr() + done as u64))?;
+        let mut sent = 0;
+        while sent < n {
+            match end.write(&chunk[sent..n], signal::pending) {
+                Ok(written) => sent += written,
+                Err(_) if done + sent > 0 => return Ok((done + sent) as u64),
+                Err(PipeError::Interrupted) => return Err(Errno::EINTR),
+                Err(PipeError::Broken) => {
+                    if let Some(process) = process::current() {
+                        process.signals().send(SIGPIPE);
+                    }
+                    return Err(Errno::EPIPE);
+                }
+            }
+        }
+        done += n;
+    }
+    Ok(len as u64)
+}
+
+/// open(path, path_len, flags): the lowest free descriptor, for the file at
+/// `path`. Opening for writing needs the FS_WRITE capability (else EPERM)
+/// and a filesystem that writes (else EROFS, as for initrd files).
+pub(super) fn open(path: UserPtr<u8>, path_len: usize, flags: u32) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    let path = copy_path(path, path_len)?;
+    let mode = flags & O_ACCMODE;
+    if !matches!(mode, O_RDONLY | O_WRONLY | O_RDWR) {
+        return Err(Errno::EINVAL);
+    }
+    if mode != O_RDONLY && !process.caps().contains(Caps::FS_WRITE) {
+        return Err(Errno::EPERM);
+    }
+    let file = vfs::open(&path).map_err(errno)?;
+    if mode != O_RDONLY && !file.writable() {
+        return Err(Errno::EROFS);
+    }
+    let file = File::Vfs(Arc::new(OpenFile::new(&path, file, mode != O_RDONLY)));
+    let fd = process
+        .with_files(|files| {
+            let fd = files.insert(file)?;
+            files.set_cloexec(fd, flags & O_CLOEXEC != 0);
+            Some(fd)
+        })
+        .ok_or(Errno::EMFILE)?;
+    Ok(fd as u64)
+}
+
+/// pipe(fds, flags): a new pipe, its read end and its write end at the two
+/// lowest free descriptors, stored at `fds` as two u32. O_CLOEXEC in
+/// `flags` makes both close-on-exec.
+pub(super) fn pipe(fds: UserPtr<u8>, flags: u32) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    if flags & !O_CLOEXEC != 0 {
+        return Err(Errno::EINVAL);
+    }
+    // Checked first: descriptors the program can't learn about would leak.
+    uaccess::check(fds.addr(), 8, true)?;
+    let (reader, writer) = pipe::new();
+    let ends = process.with_files(|files| {
+        let read = files.insert(File::PipeRead(Arc::new(reader)))?;
+        let Some(write) = files.insert(File::PipeWrite(Arc::new(writer))) else {
+            files.close(read);
+            return None;
+        };
+        files.set_cloexec(read, flags & O_CLOEXEC != 0);
+        files.set_cloexec(write, flags & O_CLOEXEC != 0);
+        Some((read, write))
+    });
+    let (read, write) = ends.ok_or(Errno::EMFILE)?;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&read.to_le_bytes());
+    bytes[4..].copy_from_slice(&write.to_le_bytes());
+    uaccess::copy_to_user(fds, &bytes)?;
+    Ok(0)
+}
+
+/// close(fd).
+pub(super) fn close(fd: u32) -> SysResult {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    process.with_files(|files| files.close(fd)).map(|_| 0).ok_or(Errno::EBADF)
+}
+
+/// lseek(fd, offset, whence): move the offset of a file to `offset`
+/// from the start (SEEK_SET), the current offset (SEEK_CUR) or the end
+/// (SEEK_END); returns the new offset. Character devices, the console and
+/// pipes can't seek: ESPIPE.
+pub(super) fn lseek(fd: u32, offset: i64, whence: u32) -> SysResult {
+    let file = process::file(fd).ok_or(Errno::EBADF)?;
+    let File::Vfs(file) = file else { return Err(Errno::ESPIPE) };
+    let metadata = file.metadata();
+    if metadata.kind == FileType::CharDevice {
+        return Err(Errno::ESPIPE);
+    }
+    let end = metadata.size;
+    let to = |current: u64| {
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => current,
+            SEEK_END => end,
+            _ => return None,
+        };
+        base.checked_add_signed(offset).filter(|&to| to <= i64::MAX as u64)
+    };
+    file.seek(to).ok_or(Errno::EINVAL)
+}
+
+/// dup2(old, new): make `new` refer to the file `old` does, closing what it
+/// referred to first; returns `new`, which is not close-on-exec.
+pub(super) fn dup2(old: u32, new: u32) -> SysResult {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let file = process.file(old).ok_or(Errno::EBADF)?;
+    if new >= MAX_FILES {
+        return Err(Errno::EBADF);
+    }
+    process.with_files(|files| files.set(new, file));
+    Ok(new as u64)
+}
@@ -0,0 +1,106 @@
+//! Message-queue and shared-memory system calls (see `ipc`).
+//!
+//! Queues and segments are named by the ID `mq_open` and `shm_create`
+//! return. The timeout of a send or receive is in milliseconds: negative
+//! waits for as long as it takes, 0 doesn't wait (EAGAIN), and otherwise
+//! ETIMEDOUT when it runs out. A segment is mapped whole, and unmapped
+//! with `munmap` or when the process exits.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use core::time::Duration;
+
+use super::{Errno, SysResult};
+use crate::ipc::mqueue::{self, MessageQueue, MqError, MAX_MESSAGE};
+use crate::ipc::shm::{self, ShmError};
+use crate::memory::vma::{Prot, VmaError};
+use crate::process;
+use crate::time;
+use crate::user::uaccess::{self, UserPtr};
+
+fn errno(err: MqError, timeout_ms: i64) -> Errno {
+    match err {
+        MqError::NotFound | MqError::BadCapacity => Errno::EINVAL,
+        MqError::Removed => Errno::EIDRM,
+        MqError::TooBig => Errno::EMSGSIZE,
+        MqError::TooMany => Errno::ENOSPC,
+        MqError::TimedOut if timeout_ms == 0 => Errno::EAGAIN,
+        MqError::TimedOut => Errno::ETIMEDOUT,
+    }
+}
+
+fn shm_errno(err: ShmError) -> Errno {
+    match err {
+        ShmError::NotFound | ShmError::BadSize => Errno::EINVAL,
+        ShmError::TooMany => Errno::ENOSPC,
+        ShmError::OutOfMemory => Errno::ENOMEM,
+    }
+}
+
+fn queue(qid: u64) -> Result<Arc<MessageQueue>, Errno> {
+    mqueue::get(qid).ok_or(Errno::EINVAL)
+}
+
+fn deadline(timeout_ms: i64) -> Option<Duration> {
+    (timeout_ms >= 0).then(|| time::uptime() + Duration::from_millis(timeout_ms as u64))
+}
+
+/// mq_open(key, capacity): the ID of the queue for `key`, created with room
+/// for `capacity` messages if there is none; key 0 makes a private one.
+pub(super) fn mq_open(key: u64, capacity: usize) -> SysResult {
+    mqueue::open(key, capacity).map(|queue| queue.id()).map_err(|err| errno(err, 0))
+}
+
+/// mq_send(qid, buf, len, timeout_ms).
+pub(super) fn mq_send(qid: u64, buf: UserPtr<u8>, len: usize, timeout_ms: i64) -> SysResult {
+    let queue = queue(qid)?;
+    if len > MAX_MESSAGE {
+        return Err(Errno::EMSGSIZE);
+    }
+    let mut message = vec![0u8; len];
+    uaccess::copy_from_user(&mut message, buf)?;
+    queue.send(&message, deadline(timeout_ms)).map_err(|err| errno(err, timeout_ms))?;
+    Ok(0)
+}
+
+/// mq_recv(qid, buf, len, timeout_ms): the oldest message, which must fit
+/// in `len` bytes (else EMSGSIZE, and it stays queued); returns its length.
+pub(super) fn mq_recv(qid: u64, buf: UserPtr<u8>, len: usize, timeout_ms: i64) -> SysResult {
+    let queue = queue(qid)?;
+    // Checked first: a message taken can't be put back.
+    let len = len.min(MAX_MESSAGE);
+    uaccess::check(buf.addr(), len, true)?;
+    let message = queue.receive(len, deadline(timeout_ms)).map_err(|err| errno(err, timeout_ms))?;
+    uaccess::copy_to_user(buf, &message)?;
+    Ok(message.len() as u64)
+}
+
+/// mq_remove(qid): delete the queue; whoever is blocked on it gets EIDRM.
+pub(super) fn mq_remove(qid: u64) -> SysResult {
+    mqueue::remove(qid).map(|()| 0).map_err(|err| errno(err, 0))
+}
+
+/// shm_create(key, size): the ID of the segment for `key`, created with
+/// `size` zeroed bytes if there is none; key 0 makes a private one.
+pub(super) fn shm_create(key: u64, size: u64) -> SysResult {
+    shm::create(key, size).map(|segment| segment.id()).map_err(shm_errno)
+}
+
+/// shm_map(id, prot): map the segment with PROT_* bits `prot`, which must
+/// include PROT_READ; returns where.
+pub(super) fn shm_map(id: u64, prot: u64) -> SysResult {
+    let segment = shm::get(id).ok_or(Errno::EINVAL)?;
+    let prot = Prot::from_bits(prot).filter(|prot| prot.contains(Prot::READ)).ok_or(Errno::EINVAL)?;
+    let process = process::current().ok_or(Errno::EPERM)?;
+    match process.with_space(|space| segment.map_into(space, prot)).ok_or(Errno::EPERM)? {
+        Ok(addr) => Ok(addr.as_u64()),
+        Err(VmaError::NoRoom | VmaError::Overlap) => Err(Errno::ENOMEM),
+        Err(_) => Err(Errno::EINVAL),
+    }
+}
+
+/// shm_remove(id): delete the segment; its memory stays until the last
+/// process has unmapped it.
+pub(super) fn shm_remove(id: u64) -> SysResult {
+    shm::remove(id).map(|()| 0).map_err(shm_errno)
+}
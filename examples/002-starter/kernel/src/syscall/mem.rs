@@ -0,0 +1,44 @@
+//! Memory system calls: a heap that grows with `sbrk`, and anonymous
+//! mappings from `mmap`. Both only reserve address space; each page is
+//! allocated, zeroed, when the program first touches it.
+//!
+//! `mmap(len, prot)` is Linux's `mmap(NULL, len, prot, MAP_PRIVATE |
+//! MAP_ANONYMOUS, -1, 0)`, with the same PROT_* bits.
+
+use x86_64::VirtAddr;
+
+use super::{Errno, SysResult};
+use crate::memory::address_space::AddressSpace;
+use crate::memory::vma::{Prot, VmaError};
+use crate::process;
+
+fn errno(err: VmaError) -> Errno {
+    match err {
+        VmaError::Unaligned | VmaError::NotFound => Errno::EINVAL,
+        VmaError::Overlap | VmaError::NoRoom => Errno::ENOMEM,
+    }
+}
+
+/// Run `f` on the calling process's address space.
+fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> Result<R, VmaError>) -> Result<R, Errno> {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    process.with_space(f).ok_or(Errno::EPERM)?.map_err(errno)
+}
+
+/// sbrk(increment): move the end of the heap; returns the old end, so
+/// `sbrk(0)` is where it is now.
+pub(super) fn sbrk(increment: i64) -> SysResult {
+    with_space(|space| space.sbrk(increment)).map(VirtAddr::as_u64)
+}
+
+/// mmap(len, prot): zero-filled memory somewhere; returns its address.
+pub(super) fn mmap(len: usize, prot: u64) -> SysResult {
+    let prot = Prot::from_bits(prot).ok_or(Errno::EINVAL)?;
+    with_space(|space| space.mmap(len as u64, prot)).map(VirtAddr::as_u64)
+}
+
+/// munmap(addr, len): give back a whole mapping from `mmap` or `shm_map`.
+pub(super) fn munmap(addr: u64, len: usize) -> SysResult {
+    let addr = VirtAddr::try_new(addr).map_err(|_| Errno::EINVAL)?;
+    with_space(|space| space.munmap(addr, len as u64)).map(|()| 0)
+}
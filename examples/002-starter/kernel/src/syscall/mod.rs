@@ -0,0 +1,464 @@
+//! System calls: the ABI user code calls the kernel with, the table of
+//! handlers, and the dispatcher both entries (`int 0x80` and the `syscall`
+//! instruction, see `user`) end up in.
+//!
+//! The ABI is Linux's x86_64 one with our own numbers (`nr`):
+//!
+//! - RAX holds the number; RDI, RSI, RDX, R10, R8 and R9 the arguments
+//!   (R10 rather than RCX, which `syscall` overwrites).
+//! - The result comes back in RAX. -4095..=-1 are errors, the negated
+//!   `Errno`; anything else is success.
+//! - Every other register is preserved, except RCX and R11 with `syscall`.
+//!
+//! Handlers are ordinary functions with typed arguments, registered by
+//! number: `register(nr::WRITE, "write", write)` with `fn write(fd: u32, buf:
+//! UserPtr<u8>, len: usize) -> SysResult`. Each argument is converted from
+//! its register by `Arg`; one that doesn't fit is EINVAL.
+//!
+//! A handler registered with `register_needing` also names the capabilities
+//! (see `process::caps`) a process needs to call it; the dispatcher refuses
+//! everyone else with EPERM before the handler runs.
+//!
+//! A process that filtered its system calls (`seccomp`) and makes one its
+//! filter leaves out is killed with SIGSYS: the call returns ENOSYS, which
+//! the program never sees.
+//!
+//! For a traced process (see `process`) the dispatcher logs each call once
+//! it returns, strace-style: `[pid 3 /bin/cat] read(0, 0x600000402000, 512)
+//! = 6`. Each argument is shown as its handler's type says (`Arg::show`);
+//! `exit` is logged before it runs, since it doesn't return.
+
+mod io;
+mod ipc;
+mod mem;
+mod proc;
+mod signal;
+mod time;
+
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::sync::RwLock;
+use crate::user::uaccess::UserPtr;
+use crate::user::{self, TrapFrame};
+use crate::process::{self, Caps};
+use crate::serial_println;
+
+/// System call numbers.
+pub mod nr {
+    pub const EXIT: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const GETPID: usize = 2;
+    pub const SPAWN: usize = 3;
+    pub const EXEC: usize = 4;
+    pub const SBRK: usize = 5;
+    pub const MMAP: usize = 6;
+    pub const MUNMAP: usize = 7;
+    pub const READ: usize = 8;
+    pub const WAIT: usize = 9;
+    pub const MQ_OPEN: usize = 10;
+    pub const MQ_SEND: usize = 11;
+    pub const MQ_RECV: usize = 12;
+    pub const MQ_REMOVE: usize = 13;
+    pub const SHM_CREATE: usize = 14;
+    pub const SHM_MAP: usize = 15;
+    pub const SHM_REMOVE: usize = 16;
+    pub const KILL: usize = 17;
+    pub const SIGACTION: usize = 18;
+    pub const SIGRETURN: usize = 19;
+    pub const CLOCK_GETTIME: usize = 20;
+    pub const NANOSLEEP: usize = 21;
+    pub const UPTIME: usize = 22;
+    pub const OPEN: usize = 23;
+    pub const CLOSE: usize = 24;
+    pub const LSEEK: usize = 25;
+    pub const DUP2: usize = 26;
+    pub const PIPE: usize = 27;
+    pub const CAPGET: usize = 28;
+    pub const CAPSET: usize = 29;
+    pub const TRACE: usize = 30;
+    pub const SECCOMP: usize = 31;
+}
+
+const MAX_SYSCALLS: usize = 64;
+
+/// Error numbers, as in Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    EIDRM = 43,
+    EMSGSIZE = 90,
+    ETIMEDOUT = 110,
+}
+
+pub type SysResult = Result<u64, Errno>;
+
+/// The value RAX gets for `result`.
+fn encode(result: SysResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// A handler argument, converted from the register it came in.
+pub trait Arg: Sized {
+    fn from_reg(reg: u64) -> Result<Self, Errno>;
+
+    /// Write `reg` as this type, for the trace.
+    fn show(reg: u64, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", reg)
+    }
+}
+
+impl Arg for u64 {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        Ok(reg)
+    }
+}
+
+impl Arg for usize {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        Ok(reg as usize)
+    }
+}
+
+impl Arg for i64 {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        Ok(reg as i64)
+    }
+
+    fn show(reg: u64, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", reg as i64)
+    }
+}
+
+impl Arg for u32 {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        u32::try_from(reg).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl Arg for i32 {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        i32::try_from(reg as i64).map_err(|_| Errno::EINVAL)
+    }
+
+    fn show(reg: u64, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", reg as i64)
+    }
+}
+
+impl<T> Arg for UserPtr<T> {
+    fn from_reg(reg: u64) -> Result<Self, Errno> {
+        Ok(UserPtr::new(reg))
+    }
+
+    fn show(reg: u64, out: &mut dyn Write) -> fmt::Result {
+        match reg {
+            0 => out.write_str("NULL"),
+            addr => write!(out, "{:#x}", addr),
+        }
+    }
+}
+
+/// A handler with its arguments still in registers.
+type Handler = dyn Fn(&[u64; 6]) -> SysResult + Send + Sync;
+
+/// Writes the arguments in registers as a handler's types say.
+type Show = fn(&[u64; 6], &mut dyn Write) -> fmt::Result;
+
+/// Functions (and closures) that can be registered: up to six `Arg`s,
+/// returning a `SysResult`. `Args` is the tuple of argument types.
+pub trait IntoHandler<Args> {
+    const ARGS: usize;
+    fn into_handler(self) -> Box<Handler>;
+    /// Write the arguments, separated by commas.
+    fn show_args(regs: &[u64; 6], out: &mut dyn Write) -> fmt::Result;
+}
+
+macro_rules! into_handler {
+    ($count:expr; $($arg:ident $index:tt),*) => {
+        impl<F, $($arg: Arg),*> IntoHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> SysResult + Send + Sync + 'static,
+        {
+            const ARGS: usize = $count;
+
+            fn into_handler(self) -> Box<Handler> {
+                Box::new(move |_regs: &[u64; 6]| self($($arg::from_reg(_regs[$index])?),*))
+            }
+
+            fn show_args(_regs: &[u64; 6], _out: &mut dyn Write) -> fmt::Result {
+                let mut _separator = "";
+                $(
+                    _out.write_str(_separator)?;
+                    $arg::show(_regs[$index], _out)?;
+                    _separator = ", ";
+                )*
+                Ok(())
+            }
+        }
+    };
+}
+
+into_handler!(0;);
+into_handler!(1; A 0);
+into_handler!(2; A 0, B 1);
+into_handler!(3; A 0, B 1, C 2);
+into_handler!(4; A 0, B 1, C 2, D 3);
+into_handler!(5; A 0, B 1, C 2, D 3, E 4);
+into_handler!(6; A 0, B 1, C 2, D 3, E 4, F6 5);
+
+#[derive(Clone, Copy)]
+struct Syscall {
+    name: &'static str,
+    args: usize,
+    /// What a process must have to call it.
+    needs: Caps,
+    show: Show,
+    /// Registered for good: the dispatcher calls it without holding the table.
+    handler: &'static Handler,
+}
+
+static TABLE: RwLock<[Option<Syscall>; MAX_SYSCALLS]> = RwLock::new([None; MAX_SYSCALLS]);
+static CALLS: [AtomicU64; MAX_SYSCALLS] = [const { AtomicU64::new(0) }; MAX_SYSCALLS];
+
+/// The registers of the system call the running thread is in.
+#[thread_local]
+static FRAME: Cell<*mut TrapFrame> = Cell::new(ptr::null_mut());
+
+/// Make `handler` system call `nr`. Panics if the number is taken.
+pub fn register<Args, H: IntoHandler<Args>>(nr: usize, name: &'static str, handler: H) {
+    register_needing(nr, name, Caps::NONE, handler);
+}
+
+/// `register`, for processes with the capabilities `needs` only.
+pub fn register_needing<Args, H: IntoHandler<Args>>(nr: usize, name: &'static str, needs: Caps, handler: H) {
+    let handler = Box::leak(handler.into_handler());
+    let mut table = TABLE.write();
+    let slot = table.get_mut(nr).unwrap_or_else(|| panic!("syscall number {} out of range", nr));
+    if let Some(old) = slot {
+        panic!("syscall {} is taken by {}", nr, old.name);
+    }
+    *slot = Some(Syscall { name, args: H::ARGS, needs, show: H::show_args, handler });
+}
+
+/// Register the system calls every user program has.
+pub fn init() {
+    register(nr::EXIT, "exit", proc::exit);
+    register(nr::WRITE, "write", io::write);
+    register(nr::GETPID, "getpid", proc::getpid);
+    register_needing(nr::SPAWN, "spawn", Caps::SPAWN, proc::spawn);
+    register(nr::EXEC, "exec", proc::exec);
+    register(nr::WAIT, "wait", proc::wait);
+    register(nr::SBRK, "sbrk", mem::sbrk);
+    register(nr::MMAP, "mmap", mem::mmap);
+    register(nr::MUNMAP, "munmap", mem::munmap);
+    register(nr::READ, "read", io::read);
+    register(nr::MQ_OPEN, "mq_open", ipc::mq_open);
+    register(nr::MQ_SEND, "mq_send", ipc::mq_send);
+    register(nr::MQ_RECV, "mq_recv", ipc::mq_recv);
+    register(nr::MQ_REMOVE, "mq_remove", ipc::mq_remove);
+    register(nr::SHM_CREATE, "shm_create", ipc::shm_create);
+    register(nr::SHM_MAP, "shm_map", ipc::shm_map);
+    register(nr::SHM_REMOVE, "shm_remove", ipc::shm_remove);
+    register(nr::KILL, "kill", signal::kill);
+    register(nr::SIGACTION, "sigaction", signal::sigaction);
+    register(nr::SIGRETURN, "sigreturn", signal::sigreturn);
+    register(nr::CLOCK_GETTIME, "clock_gettime", time::clock_gettime);
+    register(nr::NANOSLEEP, "nanosleep", time::nanosleep);
+    register(nr::UPTIME, "uptime", time::uptime);
+    register(nr::OPEN, "open", io::open);
+    register(nr::CLOSE, "close", io::close);
+    register(nr::LSEEK, "lseek", io::lseek);
+    register(nr::DUP2, "dup2", io::dup2);
+    register(nr::PIPE, "pipe", io::pipe);
+    register(nr::CAPGET, "capget", proc::capget);
+    register(nr::CAPSET, "capset", proc::capset);
+    register(nr::TRACE, "trace", proc::trace);
+    register(nr::SECCOMP, "seccomp", proc::seccomp);
+}
+
+/// Called by the entry stubs on the thread's ring-0 stack, with interrupts
+/// off. Handlers run with interrupts on: they may block or be preempted.
+/// Signals sent meanwhile are acted on before going back.
+pub extern "C" fn dispatch(frame: &mut TrapFrame) {
+    interrupts::enable();
+    let nr = frame.rax as usize;
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let syscall = TABLE.read().get(nr).copied().flatten();
+    let traced = process::current().filter(|process| process.is_traced());
+    if let (Some(process), Some(syscall), nr::EXIT) = (&traced, &syscall, nr) {
+        trace(process, syscall, &args, None);
+    }
+    FRAME.set(frame);
+    let result = match syscall {
+        _ if !process::allows_syscall(nr) => {
+            kill_filtered(nr, syscall.as_ref());
+            Err(Errno::ENOSYS)
+        }
+        Some(syscall) if !process::permits(syscall.needs) => {
+            deny(&syscall);
+            Err(Errno::EPERM)
+        }
+        Some(syscall) => {
+            CALLS[nr].fetch_add(1, Ordering::Relaxed);
+            (syscall.handler)(&args)
+        }
+        None => Err(Errno::ENOSYS),
+    };
+    FRAME.set(ptr::null_mut());
+    if let Some(process) = &traced {
+        match &syscall {
+            Some(syscall) => trace(process, syscall, &args, Some(result)),
+            None => serial_println!("[pid {} {}] syscall {} = -{} (ENOSYS)", process.pid(), process.name(), nr, Errno::ENOSYS as i64),
+        }
+    }
+    frame.rax = encode(result);
+    process::signal::deliver(frame);
+    interrupts::disable();
+}
+
+/// Log a call of `syscall` by `process`, with what it returned; `None` for
+/// one that won't return.
+fn trace(process: &process::Process, syscall: &Syscall, args: &[u64; 6], result: Option<SysResult>) {
+    let mut line = alloc::format!("[pid {} {}] {}(", process.pid(), process.name(), syscall.name);
+    let _ = (syscall.show)(args, &mut line);
+    line.push_str(") = ");
+    let _ = match result {
+        None => write!(line, "?"),
+        // Addresses, from sbrk or mmap: no small number is that big.
+        Some(Ok(value)) if value > u32::MAX as u64 => write!(line, "{:#x}", value),
+        Some(Ok(value)) => write!(line, "{}", value),
+        Some(Err(errno)) => write!(line, "-{} ({:?})", errno as i64, errno),
+    };
+    serial_println!("{}", line);
+}
+
+/// Say which process was refused `syscall`, and what it lacked.
+fn deny(syscall: &Syscall) {
+    if let Some(process) = process::current() {
+        let missing = syscall.needs.without(process.caps());
+        serial_println!("syscall: pid {} ({}) denied {}: no {} capability", process.pid(), process.name(), syscall.name, missing);
+    }
+}
+
+/// Kill the calling process for making system call `nr`, which its filter
+/// leaves out.
+fn kill_filtered(nr: usize, syscall: Option<&Syscall>) {
+    if let Some(process) = process::current() {
+        let name = syscall.map_or("unknown", |syscall| syscall.name);
+        serial_println!("syscall: pid {} ({}) made {} ({}), which its filter leaves out", process.pid(), process.name(), name, nr);
+    }
+    process::signal::force(process::signal::SIGSYS);
+}
+
+/// The registers of the system call in progress.
+fn frame<'a>() -> &'a mut TrapFrame {
+    unsafe { FRAME.get().as_mut() }.expect("no system call in progress")
+}
+
+/// Make the system call in progress return into a new program: at `entry`,
+/// on `stack`, with every other register cleared.
+fn restart(entry: VirtAddr, stack: VirtAddr) {
+    user::reset_fpu();
+    let frame = frame();
+    *frame = TrapFrame {
+        rip: entry.as_u64(),
+        rsp: stack.as_u64(),
+        rflags: user::INITIAL_RFLAGS,
+        cs: frame.cs,
+        ss: frame.ss,
+        ..TrapFrame::default()
+    };
+}
+
+pub fn dump() {
+    serial_println!("  nr  name           args      calls  needs");
+    let table = *TABLE.read();
+    for (nr, syscall) in table.iter().enumerate() {
+        if let Some(syscall) = syscall {
+            serial_println!(
+                "  {:>2}  {:<13} {:>5}  {:>9}  {}",
+                nr, syscall.name, syscall.args, CALLS[nr].load(Ordering::Relaxed), syscall.needs
+            );
+        }
+    }
+}
+
+const TEST_MESSAGE: &[u8] = b"syscall test: hello from ring 3\n";
+
+/// write(1, message) with `syscall`, an unknown number with `int 0x80`,
+/// getpid with `syscall`, then exit((pid << 16) + written - ENOSYS).
+fn test_program() -> alloc::vec::Vec<u8> {
+    let mut code = alloc::vec::Vec::new();
+    let imm = |code: &mut alloc::vec::Vec<u8>, op: u8, value: u32| {
+        code.push(op);
+        code.extend_from_slice(&value.to_le_bytes());
+    };
+    imm(&mut code, 0xb8, nr::WRITE as u32); // mov eax, WRITE
+    imm(&mut code, 0xbf, 1); // mov edi, 1
+    code.extend_from_slice(&[0x48, 0x8d, 0x35]); // lea rsi, [rip + message]
+    let lea_end = code.len() + 4;
+    code.extend_from_slice(&[0; 4]);
+    imm(&mut code, 0xba, TEST_MESSAGE.len() as u32); // mov edx, len
+    code.extend_from_slice(&[0x0f, 0x05]); // syscall
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    imm(&mut code, 0xb8, MAX_SYSCALLS as u32 - 1); // mov eax, <unused>
+    code.extend_from_slice(&[0xcd, user::TRAP_VECTOR]); // int 0x80
+    code.extend_from_slice(&[0x48, 0x01, 0xc3]); // add rbx, rax
+    imm(&mut code, 0xb8, nr::GETPID as u32); // mov eax, GETPID
+    code.extend_from_slice(&[0x0f, 0x05]); // syscall
+    code.extend_from_slice(&[0x48, 0xc1, 0xe0, 0x10]); // shl rax, 16
+    code.extend_from_slice(&[0x48, 0x01, 0xd8]); // add rax, rbx
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    imm(&mut code, 0xb8, nr::EXIT as u32); // mov eax, EXIT
+    code.extend_from_slice(&[0x0f, 0x05]); // syscall
+    code.extend_from_slice(&[0x0f, 0x0b]); // ud2
+    let offset = (code.len() - lea_end) as u32;
+    code[lea_end - 4..lea_end].copy_from_slice(&offset.to_le_bytes());
+    code.extend_from_slice(TEST_MESSAGE);
+    code
+}
+
+/// Run `test_program` and check what it got back from each call, and that
+/// the trace shows write's arguments as their types say.
+pub fn self_test() -> bool {
+    let pid = process::current().map_or(0, |process| process.pid().0);
+    let expected = ((pid as i64) << 16) + TEST_MESSAGE.len() as i64 - Errno::ENOSYS as i64;
+    let mut shown = alloc::string::String::new();
+    if let Some(write) = TABLE.read()[nr::WRITE] {
+        let _ = (write.show)(&[1, 0x6000_0040_1000, 6, 0, 0, 0], &mut shown);
+    }
+    user::run_code(&test_program()) == Some(expected) && shown == "1, 0x600000401000, 6"
+}
@@ -0,0 +1,197 @@
+//! Process system calls.
+//!
+//! Paths and arguments are passed as (pointer, length), not NUL-terminated:
+//! `spawn(path, path_len, argv, argc, envp, envc)` with `argv` pointing at
+//! `argc` pairs of u64 (pointer, length), one per argument, and `envp` at
+//! `envc` such pairs, one per "KEY=value" environment string. The new
+//! program finds both on its stack (see `elf::push_args`).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{Errno, SysResult};
+use crate::process::{self, Caps, Pid, SpawnError};
+use crate::user::elf::ElfError;
+use crate::user::uaccess::{self, UserPtr};
+use crate::user;
+
+const MAX_PATH: usize = 256;
+const MAX_ARGS: usize = 64;
+/// All argument strings together.
+const MAX_ARG_BYTES: usize = 4096;
+/// `wait` option: don't block.
+const WNOHANG: u64 = 1;
+/// Whose capabilities `capget` and `capset` are about: the process's own,
+/// or those its children start with.
+const CAPS_SELF: u32 = 0;
+const CAPS_CHILDREN: u32 = 1;
+/// `trace` flags: log the process's own system calls, and start its
+/// children traced.
+const TRACE_SELF: u32 = 1;
+const TRACE_CHILDREN: u32 = 2;
+
+fn errno(err: SpawnError) -> Errno {
+    match err {
+        SpawnError::NotFound => Errno::ENOENT,
+        SpawnError::File(err) => super::io::errno(err),
+        SpawnError::Elf(ElfError::ArgsTooLong) => Errno::E2BIG,
+        SpawnError::Elf(ElfError::OutOfMemory) | SpawnError::OutOfMemory => Errno::ENOMEM,
+        SpawnError::Elf(_) => Errno::ENOEXEC,
+    }
+}
+
+pub(super) fn copy_path(path: UserPtr<u8>, len: usize) -> Result<String, Errno> {
+    if len > MAX_PATH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    uaccess::copy_string(path, len)
+}
+
+fn copy_args(argv: UserPtr<u64>, argc: usize) -> Result<Vec<String>, Errno> {
+    if argc > MAX_ARGS {
+        return Err(Errno::E2BIG);
+    }
+    if argc == 0 {
+        return Ok(Vec::new());
+    }
+    let mut pairs = alloc::vec![0u8; argc * 16];
+    uaccess::copy_from_user(&mut pairs, UserPtr::new(argv.addr()))?;
+    let mut total = 0;
+    pairs
+        .chunks(16)
+        .map(|pair| {
+            let ptr = u64::from_le_bytes(pair[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(pair[8..].try_into().unwrap()) as usize;
+            total += len;
+            if total > MAX_ARG_BYTES {
+                return Err(Errno::E2BIG);
+            }
+            uaccess::copy_string(UserPtr::new(ptr), len)
+        })
+        .collect()
+}
+
+/// exit(status): end the program; `user::run` returns `status`.
+pub(super) fn exit(status: i32) -> SysResult {
+    user::leave(status as i64)
+}
+
+/// getpid(): 0 for user code run without a process.
+pub(super) fn getpid() -> SysResult {
+    Ok(process::current().map_or(0, |process| process.pid().0))
+}
+
+/// spawn(path, path_len, argv, argc, envp, envc): start a program in a
+/// new process, a child of this one; returns its PID.
+pub(super) fn spawn(path: UserPtr<u8>, path_len: usize, argv: UserPtr<u64>, argc: usize, envp: UserPtr<u64>, envc: usize) -> SysResult {
+    let path = copy_path(path, path_len)?;
+    let args = copy_args(argv, argc)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let env = copy_args(envp, envc)?;
+    let env: Vec<&str> = env.iter().map(String::as_str).collect();
+    let child = process::spawn_env(&path, &args, &env).map_err(errno)?;
+    Ok(child.pid().0)
+}
+
+/// exec(path, path_len, argv, argc, envp, envc): replace this process's
+/// program. Only returns on error; on success the new program starts with
+/// argv and envp.
+pub(super) fn exec(path: UserPtr<u8>, path_len: usize, argv: UserPtr<u64>, argc: usize, envp: UserPtr<u64>, envc: usize) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    let path = copy_path(path, path_len)?;
+    let args = copy_args(argv, argc)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let env = copy_args(envp, envc)?;
+    let env: Vec<&str> = env.iter().map(String::as_str).collect();
+    let image = process.exec(&path, &args, &env).map_err(errno)?;
+    super::restart(image.entry, image.stack_pointer);
+    Ok(0)
+}
+
+/// wait(pid, status, options): wait for child `pid` (any child if -1) to
+/// exit and reap it. Stores its exit status (an i32) at `status` unless
+/// that is 0, and returns its PID; ECHILD if there is no such child. With
+/// WNOHANG in `options`, returns 0 at once if none has exited yet.
+pub(super) fn wait(pid: i64, status: UserPtr<u8>, options: u64) -> SysResult {
+    let which = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(Pid(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = process::current().ok_or(Errno::ECHILD)?;
+    // Checked first: a reaped child's status can't be put back.
+    if status.addr() != 0 {
+        uaccess::check(status.addr(), 4, true)?;
+    }
+    let Some((child, code)) = process.wait_child(which, options & WNOHANG == 0).ok_or(Errno::ECHILD)? else {
+        return Ok(0);
+    };
+    if status.addr() != 0 {
+        uaccess::copy_to_user(status, &code.to_le_bytes())?;
+    }
+    Ok(child.0)
+}
+
+/// capget(which): the capability bits of this process (CAPS_SELF) or of
+/// the children it starts (CAPS_CHILDREN).
+pub(super) fn capget(which: u32) -> SysResult {
+    let caps = match process::current() {
+        None => Caps::ALL,
+        Some(process) => match which {
+            CAPS_SELF => process.caps(),
+            CAPS_CHILDREN => process.child_caps(),
+            _ => return Err(Errno::EINVAL),
+        },
+    };
+    Ok(caps.bits())
+}
+
+/// capset(which, caps): keep only `caps` of this process's own capabilities
+/// (CAPS_SELF), for good, or start its children with `caps` (CAPS_CHILDREN),
+/// which may not be more than its own: EPERM.
+pub(super) fn capset(which: u32, caps: u64) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    let caps = Caps::from_bits(caps).ok_or(Errno::EINVAL)?;
+    match which {
+        CAPS_SELF => process.drop_caps(caps),
+        CAPS_CHILDREN if process.set_child_caps(caps) => {}
+        CAPS_CHILDREN => return Err(Errno::EPERM),
+        _ => return Err(Errno::EINVAL),
+    }
+    Ok(0)
+}
+
+/// seccomp(allowed): from now on, allow this process only the system calls
+/// whose bits are set in `allowed`, and `exit`; making any other kills it
+/// with SIGSYS. For good: a later call can only leave more out. Its
+/// children start with the same filter.
+pub(super) fn seccomp(allowed: u64) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    process.restrict_syscalls(allowed | 1 << super::nr::EXIT);
+    Ok(0)
+}
+
+/// trace(pid, flags): trace process `pid` (0 for this one), a child of this
+/// one, as `flags` say: TRACE_SELF logs its system calls, TRACE_CHILDREN
+/// traces the children it starts from now on; 0 stops both.
+pub(super) fn trace(pid: u64, flags: u32) -> SysResult {
+    let caller = process::current().ok_or(Errno::EPERM)?;
+    if flags & !(TRACE_SELF | TRACE_CHILDREN) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = match pid {
+        0 => caller,
+        pid => {
+            let process = process::get(Pid(pid)).ok_or(Errno::ESRCH)?;
+            if process.parent() != Some(caller.pid()) {
+                return Err(Errno::EPERM);
+            }
+            process
+        }
+    };
+    process.set_traced(flags & TRACE_SELF != 0, flags & TRACE_CHILDREN != 0);
+    Ok(0)
+}
@@ -0,0 +1,53 @@
+//! Signal system calls (see `process::signal`).
+
+use super::{Errno, SysResult};
+use crate::process::signal::{self, Action, Fault, SIG_DFL, SIG_IGN};
+use crate::process::{self, Pid};
+
+/// kill(pid, signal): send `signal` to process `pid`. Signal 0 sends
+/// nothing, only checks that the process exists.
+pub(super) fn kill(pid: u64, signal: u32) -> SysResult {
+    if signal != 0 && !signal::valid(signal) {
+        return Err(Errno::EINVAL);
+    }
+    let exists = if signal == 0 {
+        process::get(Pid(pid)).is_some_and(|process| !matches!(process.state(), process::State::Zombie(_)))
+    } else {
+        process::kill(Pid(pid), signal)
+    };
+    if exists { Ok(0) } else { Err(Errno::ESRCH) }
+}
+
+/// sigaction(signal, handler, restorer): SIG_DFL (0), SIG_IGN (1), or the
+/// address of a handler, which returns to `restorer`; that must call
+/// `sigreturn`. Returns the old handler (0 or 1 for the others).
+pub(super) fn sigaction(signal: u32, handler: u64, restorer: u64) -> SysResult {
+    let process = process::current().ok_or(Errno::EPERM)?;
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        _ if restorer == 0 => return Err(Errno::EINVAL),
+        _ => Action::Handle { handler, restorer },
+    };
+    let old = process.signals().set_action(signal, action)?;
+    Ok(match old {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handle { handler, .. } => handler,
+    })
+}
+
+/// sigreturn(): back from a signal handler to where the signal interrupted
+/// the program, with all its registers. A bad frame is a SIGSEGV.
+pub(super) fn sigreturn() -> SysResult {
+    let frame = super::frame();
+    match signal::restore(frame) {
+        // Comes back in RAX as it was.
+        Ok(()) => Ok(frame.rax),
+        Err(err) => {
+            let fault = Fault { what: "bad sigreturn frame", addr: Some(frame.rsp), reason: "", rip: frame.rip };
+            signal::raise_fault(signal::SIGSEGV, fault);
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Time system calls: the clocks, and sleeping on the timer wheel.
+//!
+//! Times are passed as Linux's `struct timespec`: two i64, seconds and
+//! nanoseconds (0..1_000_000_000).
+
+use core::time::Duration;
+
+use super::{Errno, SysResult};
+use crate::process::signal;
+use crate::time;
+use crate::user::uaccess::{self, UserPtr};
+
+/// Since the Unix epoch, from the CMOS clock at boot.
+const CLOCK_REALTIME: u32 = 0;
+/// Since boot; never jumps.
+const CLOCK_MONOTONIC: u32 = 1;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+fn write_timespec(to: UserPtr<u8>, time: Duration) -> Result<(), Errno> {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+    bytes[8..].copy_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+    uaccess::copy_to_user(to, &bytes)
+}
+
+fn read_timespec(from: UserPtr<u8>) -> Result<Duration, Errno> {
+    let mut bytes = [0u8; 16];
+    uaccess::copy_from_user(&mut bytes, from)?;
+    let secs = i64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let nanos = i64::from_le_bytes(bytes[8..].try_into().unwrap());
+    if secs < 0 || !(0..NANOS_PER_SEC).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(Duration::new(secs as u64, nanos as u32))
+}
+
+/// clock_gettime(clock, ts): store the time of CLOCK_REALTIME (0) or
+/// CLOCK_MONOTONIC (1) at `ts`.
+pub(super) fn clock_gettime(clock: u32, ts: UserPtr<u8>) -> SysResult {
+    let now = match clock {
+        CLOCK_REALTIME => time::realtime(),
+        CLOCK_MONOTONIC => time::monotonic(),
+        _ => return Err(Errno::EINVAL),
+    };
+    write_timespec(ts, now)?;
+    Ok(0)
+}
+
+/// nanosleep(req, rem): sleep for the time at `req`, rounded up to whole
+/// ticks. A signal ends it early with EINTR, and the time that was left
+/// stored at `rem` unless that is 0.
+pub(super) fn nanosleep(req: UserPtr<u8>, rem: UserPtr<u8>) -> SysResult {
+    let duration = read_timespec(req)?;
+    let left = time::sleep_interruptible(duration, signal::pending);
+    if left.is_zero() {
+        return Ok(0);
+    }
+    if rem.addr() != 0 {
+        write_timespec(rem, left)?;
+    }
+    Err(Errno::EINTR)
+}
+
+/// uptime(): milliseconds since boot.
+pub(super) fn uptime() -> SysResult {
+    Ok(time::monotonic().as_millis() as u64)
+}
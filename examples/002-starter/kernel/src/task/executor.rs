@@ -0,0 +1,60 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::sync::atomic::AtomicBool;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{ReadyQueue, Task, TaskId};
+
+/// Polls spawned tasks until they have all completed. Single CPU, no preemption:
+/// a task runs until it returns `Pending`.
+pub struct Executor {
+    ready: Arc<ReadyQueue>,
+    live: usize,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor { ready: Arc::new(ReadyQueue { tasks: Mutex::new(Default::default()) }), live: 0 }
+    }
+
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        self.live += 1;
+        self.ready.reserve(self.live);
+        let task = Arc::new(Task {
+            id: TaskId::new(),
+            future: Mutex::new(Some(alloc::boxed::Box::pin(future))),
+            queued: AtomicBool::new(true),
+            ready: self.ready.clone(),
+        });
+        let id = task.id;
+        self.ready.push(task);
+        id
+    }
+
+    /// Run until every task has finished, halting the CPU while none is ready.
+    ///
+    /// Tasks waiting on an interrupt (e.g. the keyboard) need interrupts enabled.
+    pub fn run(&mut self) {
+        while self.live > 0 {
+            while let Some(task) = self.ready.pop() {
+                if task.poll() {
+                    self.live -= 1;
+                }
+            }
+            if self.live == 0 {
+                break;
+            }
+            // Check and halt with interrupts off, so a wake can't slip in between.
+            let enabled = interrupts::are_enabled();
+            interrupts::disable();
+            if self.ready.is_empty() && enabled {
+                interrupts::enable_and_hlt();
+            } else if enabled {
+                interrupts::enable();
+            } else if self.ready.is_empty() {
+                panic!("executor: {} task(s) blocked with interrupts disabled", self.live);
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! PS/2 keyboard input: the IRQ1 handler queues raw scancodes and wakes
+//! whichever async task or blocked thread is waiting for the next one.
+//! Async code reads decoded keys through `KeyStream`.
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::Stream;
+use crate::sync::{SpscQueue, WaitQueue};
+use crate::workqueue::Work;
+use crate::{serial_print, serial_println};
+
+/// Scancodes from the interrupt handler (the only producer) to whichever
+/// reader is active (the only consumer).
+static QUEUE: SpscQueue<u8, 128> = SpscQueue::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Tells the console that keys are being lost; printing is no job for the handler.
+static OVERFLOW_WARNING: Work = Work::new(|| serial_println!("\nkeyboard: queue full, dropping scancodes"));
+
+/// Readers take turns: `QUEUE` has a single consumer.
+static READER: Mutex<()> = Mutex::new(());
+
+/// The next scancode, if one is queued. For readers that wait themselves
+/// (the console waits for either input).
+pub fn pop_scancode() -> Option<u8> {
+    without_interrupts(|| {
+        let _reader = READER.lock();
+        // Safety: the lock makes this the only consumer.
+        unsafe { QUEUE.pop() }
+    })
+}
+
+pub fn has_input() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// The task waiting for a scancode. Registered with interrupts off, so the
+/// handler can take the lock without deadlocking.
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Threads blocked in `read_scancode`.
+static KEY_READY: WaitQueue = WaitQueue::new();
+
+/// Called from the keyboard interrupt handler; must not allocate or block.
+pub fn add_scancode(scancode: u8) {
+    // Safety: this handler is the only producer.
+    if unsafe { QUEUE.push(scancode) }.is_err() && DROPPED.fetch_add(1, Ordering::Relaxed) == 0 {
+        OVERFLOW_WARNING.schedule();
+    }
+    KEY_READY.notify_one();
+    crate::console::input_arrived();
+    // `wake_by_ref` rather than `take`: dropping the last reference to a task here would free it.
+    if let Some(waker) = WAKER.lock().as_ref() {
+        waker.wake_by_ref();
+    }
+}
+
+/// Take the next scancode, or register `cx`'s waker for the interrupt handler.
+fn poll_scancode(cx: &mut Context<'_>) -> Poll<u8> {
+    if let Some(scancode) = pop_scancode() {
+        return Poll::Ready(scancode);
+    }
+    without_interrupts(|| *WAKER.lock() = Some(cx.waker().clone()));
+    // A key may have arrived before the waker was in place.
+    match pop_scancode() {
+        Some(scancode) => Poll::Ready(scancode),
+        None => Poll::Pending,
+    }
+}
+
+/// Key presses, decoded, as an endless `Stream`. Like the other readers it
+/// consumes the shared scancode queue, so use one at a time (two would each
+/// get some of the keys).
+#[derive(Default)]
+pub struct KeyStream {
+    decoder: Decoder,
+}
+
+impl Stream for KeyStream {
+    type Item = Key;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Key>> {
+        loop {
+            let Poll::Ready(scancode) = poll_scancode(cx) else { return Poll::Pending };
+            if let Some(key) = self.decoder.feed(scancode) {
+                return Poll::Ready(Some(key));
+            }
+        }
+    }
+}
+
+/// Block the calling thread until a scancode arrives. Use either this or
+/// `KeyStream`, not both at once: they would share out the keys.
+pub fn read_scancode() -> u8 {
+    loop {
+        if let Some(scancode) = pop_scancode() {
+            return scancode;
+        }
+        KEY_READY.wait_until(|| !QUEUE.is_empty());
+    }
+}
+
+/// Throw away keys pressed while nobody was listening.
+pub fn clear() {
+    while pop_scancode().is_some() {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Escape,
+    Backspace,
+    Other(u8),
+}
+
+/// Scancode set 1, US layout: make codes 0x00..0x3A, unshifted and shifted.
+const KEYMAP: &[u8; 0x3A] = b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 0x3A] = b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const CAPS_LOCK: u8 = 0x3A;
+const RELEASE: u8 = 0x80;
+const EXTENDED: u8 = 0xE0;
+
+/// Turns scancodes into key presses, tracking shift and caps lock.
+#[derive(Default)]
+pub struct Decoder {
+    shift: bool,
+    caps: bool,
+    extended: bool,
+}
+
+impl Decoder {
+    /// Feed one scancode; returns a key on a press, `None` for releases and modifiers.
+    pub fn feed(&mut self, scancode: u8) -> Option<Key> {
+        if scancode == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        // Arrow keys, right Ctrl/Alt... reported raw.
+        if core::mem::take(&mut self.extended) {
+            return (scancode & RELEASE == 0).then_some(Key::Other(scancode));
+        }
+        let code = scancode & !RELEASE;
+        let pressed = scancode & RELEASE == 0;
+        match code {
+            LEFT_SHIFT | RIGHT_SHIFT => self.shift = pressed,
+            CAPS_LOCK if pressed => self.caps = !self.caps,
+            _ if pressed => return Some(self.translate(code)),
+            _ => {}
+        }
+        None
+    }
+
+    fn translate(&self, code: u8) -> Key {
+        let plain = KEYMAP.get(code as usize).copied().unwrap_or(0);
+        let upper = self.shift ^ (self.caps && plain.is_ascii_alphabetic());
+        let byte = if upper { KEYMAP_SHIFT[code as usize] } else { plain };
+        match byte {
+            0 => Key::Other(code),
+            0x1b => Key::Escape,
+            0x08 => Key::Backspace,
+            b => Key::Char(b as char),
+        }
+    }
+}
+
+/// Print `key`; false for Escape.
+fn echo(key: Key) -> bool {
+    match key {
+        Key::Char(c) => serial_print!("{}", c),
+        Key::Backspace => serial_print!("\x08 \x08"),
+        Key::Escape => return false,
+        Key::Other(code) => serial_print!("<{:#04x}>", code),
+    }
+    true
+}
+
+/// Echo key presses to serial until Escape is pressed.
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::default();
+    while let Some(key) = keys.next().await {
+        if !echo(key) {
+            break;
+        }
+    }
+    report_dropped();
+}
+
+/// Like `print_keypresses`, for a thread blocking on the wait queue.
+pub fn print_keypresses_blocking() {
+    let mut decoder = Decoder::default();
+    loop {
+        if let Some(key) = decoder.feed(read_scancode()) {
+            if !echo(key) {
+                break;
+            }
+        }
+    }
+    report_dropped();
+}
+
+fn report_dropped() {
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        serial_println!("\n({} scancodes dropped: queue full)", dropped);
+    } else {
+        serial_println!();
+    }
+}
@@ -0,0 +1,173 @@
+//! Cooperative multitasking with `async fn`: an executor polls futures, and
+//! wakers put a task back on the ready queue when it can make progress.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod executor;
+pub mod keyboard;
+mod stream;
+
+pub use executor::Executor;
+pub use stream::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Tasks waiting to be polled. Interrupt handlers push to it through wakers,
+/// so it is only locked with interrupts off, and its capacity is reserved up
+/// front so a wake never allocates.
+struct ReadyQueue {
+    tasks: Mutex<VecDeque<Arc<Task>>>,
+}
+
+impl ReadyQueue {
+    fn push(&self, task: Arc<Task>) {
+        without_interrupts(|| self.tasks.lock().push_back(task));
+    }
+
+    fn pop(&self) -> Option<Arc<Task>> {
+        without_interrupts(|| self.tasks.lock().pop_front())
+    }
+
+    fn is_empty(&self) -> bool {
+        without_interrupts(|| self.tasks.lock().is_empty())
+    }
+
+    /// Make room for `live` tasks, the most that can be queued at once.
+    ///
+    /// The new buffer is allocated with interrupts on: a preempted thread may
+    /// hold the heap lock, and we must not spin on it with interrupts off.
+    fn reserve(&self, live: usize) {
+        let capacity = without_interrupts(|| self.tasks.lock().capacity());
+        if capacity >= live {
+            return;
+        }
+        let mut bigger = VecDeque::with_capacity(live.max(capacity * 2));
+        let old = without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            bigger.extend(tasks.drain(..));
+            core::mem::replace(&mut *tasks, bigger)
+        });
+        drop(old);
+    }
+}
+
+/// A spawned future. It is its own waker: waking an `Arc<Task>` queues it.
+pub struct Task {
+    id: TaskId,
+    future: Mutex<Option<BoxFuture>>,
+    /// Set while the task sits in the ready queue (or for good once it has
+    /// finished), so repeated wakes queue it at most once.
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Task {
+    /// Poll the future once; returns true when it has completed.
+    fn poll(self: &Arc<Self>) -> bool {
+        self.queued.store(false, Ordering::Release);
+        let waker = self.clone().into();
+        let mut cx = Context::from_waker(&waker);
+        let mut slot = self.future.lock();
+        let Some(future) = slot.as_mut() else { return true };
+        if future.as_mut().poll(&mut cx).is_pending() {
+            return false;
+        }
+        *slot = None;
+        // Stale wakers may still fire; keep them from queueing a finished task.
+        self.queued.store(true, Ordering::Release);
+        true
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.push(self.clone());
+        }
+    }
+}
+
+/// A future that returns `Pending` once, letting other tasks run.
+pub fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    core::future::poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+/// Run tasks that hand work to each other through yields and an explicit
+/// waker, and check they interleave and all finish.
+pub fn self_test() -> bool {
+    struct Signal {
+        set: AtomicBool,
+        waker: Mutex<Option<core::task::Waker>>,
+    }
+
+    let log = Arc::new(Mutex::new(alloc::vec::Vec::new()));
+    let signal = Arc::new(Signal { set: AtomicBool::new(false), waker: Mutex::new(None) });
+    let mut executor = Executor::new();
+
+    let (waiter_log, waiter_signal) = (log.clone(), signal.clone());
+    executor.spawn(async move {
+        waiter_log.lock().push('w');
+        core::future::poll_fn(|cx| {
+            if waiter_signal.set.load(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            *waiter_signal.waker.lock() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await;
+        waiter_log.lock().push('W');
+    });
+    for name in ['a', 'b'] {
+        let log = log.clone();
+        executor.spawn(async move {
+            for _ in 0..2 {
+                log.lock().push(name);
+                yield_now().await;
+            }
+        });
+    }
+    let (setter_log, setter_signal) = (log.clone(), signal.clone());
+    executor.spawn(async move {
+        yield_now().await;
+        setter_log.lock().push('s');
+        setter_signal.set.store(true, Ordering::Release);
+        if let Some(waker) = setter_signal.waker.lock().take() {
+            waker.wake();
+        }
+    });
+    executor.run();
+
+    let log: alloc::string::String = log.lock().iter().collect();
+    log == "wababsW"
+}
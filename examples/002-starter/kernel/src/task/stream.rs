@@ -0,0 +1,33 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// An asynchronous sequence of values, the async counterpart of `Iterator`
+/// (the same shape as `futures::Stream`).
+pub trait Stream {
+    type Item;
+
+    /// `Ready(Some(item))` for the next item, `Ready(None)` once the stream has
+    /// ended, or `Pending` after arranging for `cx`'s waker to be woken.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    /// A future for the next item: `while let Some(x) = stream.next().await`.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
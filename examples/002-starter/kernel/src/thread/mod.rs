@@ -0,0 +1,807 @@
+//! Preemptive kernel threads: each has its own stack, the timer interrupt
+//! switches between them round-robin, and `yield_now` gives up the CPU early.
+//! Every CPU runs threads from its own queues and takes work from the others
+//! when it runs out (see `scheduler`). A thread running user code is
+//! preempted the same way, in ring 3 (see `user`).
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+
+use crate::memory::stack::{self, KernelStack};
+use crate::smp::{self, CpuMask};
+use crate::{preempt, serial_println};
+
+mod scheduler;
+mod scope;
+mod switch;
+pub mod tls;
+
+pub use scheduler::{CpuStats, ThreadStats, MAX_THREADS};
+use scheduler::Scheduler;
+pub use scope::{scope, Scope, ScopedJoinHandle};
+use scope::ScopeData;
+
+/// Default stack size of spawned threads, in pages.
+const STACK_PAGES: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Waiting for `unblock`; not in the run queue.
+    Blocked,
+    Finished,
+}
+
+/// Scheduling priority; a ready thread of a higher level always runs first,
+/// except that waiting threads age up (see `scheduler::AGING_TICKS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Realtime,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            2 => Priority::High,
+            _ => Priority::Realtime,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            "realtime" => Some(Priority::Realtime),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Realtime => "realtime",
+        }
+    }
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// Thread control block.
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    /// Saved stack pointer while the thread is switched out.
+    rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack: Option<KernelStack>,
+    /// What the thread runs; taken when it starts.
+    entry: Option<Entry>,
+    /// Timer ticks that hit while this thread was running.
+    ticks: u64,
+    /// Time-stamp counter cycles it ran for, up to its last switch out.
+    runtime: u64,
+    /// The time-stamp counter when it last started running.
+    switched_in: u64,
+    /// Times it was switched out, for any reason.
+    switches: u64,
+    /// CPU it last ran on; `None` if it hasn't run yet.
+    last_cpu: Option<usize>,
+    priority: Priority,
+    /// Lent by threads waiting for a mutex it holds (priority inheritance).
+    inherited: Option<Priority>,
+    /// `priority` plus inheritance and aging; the queue the thread waits in.
+    effective: Priority,
+    /// Ticks spent in a run queue since last queued or aged.
+    waited: u32,
+    /// CPU whose queue the thread waits in, or that runs it.
+    cpu: usize,
+    /// Its registers are live on `cpu` (or being saved there); it can't be queued or freed yet.
+    on_cpu: bool,
+    /// Woken while not blocked; the next `block` returns at once.
+    wake_pending: bool,
+    /// Times it moved to another CPU's queue.
+    migrations: u64,
+    /// CPUs it may run on.
+    affinity: CpuMask,
+    /// Its `#[thread_local]` statics.
+    tls: tls::Block,
+    /// Instrumented locks it holds, for lock-order checking.
+    #[cfg(debug_assertions)]
+    held: crate::sync::lockdep::Held,
+}
+
+impl Thread {
+    fn new(name: &'static str, stack: Option<KernelStack>, entry: Option<Entry>) -> Option<Box<Thread>> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let tls = tls::Block::new()?;
+        let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let priority = Priority::Normal;
+        Some(Box::new(Thread {
+            id,
+            name,
+            state: State::Ready,
+            rsp: 0,
+            stack,
+            entry,
+            ticks: 0,
+            runtime: 0,
+            switched_in: unsafe { core::arch::x86_64::_rdtsc() },
+            switches: 0,
+            last_cpu: None,
+            priority,
+            inherited: None,
+            effective: priority,
+            waited: 0,
+            cpu: 0,
+            on_cpu: false,
+            wake_pending: false,
+            migrations: 0,
+            affinity: CpuMask::ALL,
+            tls,
+            #[cfg(debug_assertions)]
+            held: crate::sync::lockdep::Held::new(),
+        }))
+    }
+
+    /// What `effective` falls back to after aging: its own priority, or one lent to it.
+    fn base(&self) -> Priority {
+        self.inherited.map_or(self.priority, |lent| lent.max(self.priority))
+    }
+
+    /// A thread that starts in `start` on its own fresh stack.
+    fn with_stack(name: &'static str, pages: u64, entry: Entry) -> Option<Box<Thread>> {
+        let stack = stack::alloc(name, pages)?;
+        let top = stack.top;
+        let mut thread = Thread::new(name, Some(stack), Some(entry))?;
+        thread.rsp = unsafe { switch::initial_stack(top, start) };
+        Some(thread)
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            // Only finished threads are dropped, and never by themselves.
+            unsafe { stack::free(stack) };
+        }
+    }
+}
+
+/// Locked only with interrupts disabled: the timer interrupt takes it too.
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// Set once `SCHEDULER` is, so `initialized` needs no lock.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Id + 1 of the thread each CPU runs (0 before `init`), so finding the
+/// current thread takes no lock.
+static CURRENT: [AtomicU64; smp::MAX_CPUS] = [const { AtomicU64::new(0) }; smp::MAX_CPUS];
+
+/// Record that `cpu` runs `thread` from now on. On that CPU this also loads
+/// the thread's TLS; an AP's idle thread gets it at its first switch (the
+/// idle loop uses no thread-locals).
+fn set_current(cpu: usize, thread: &mut Thread) {
+    CURRENT[cpu].store(thread.id.0 + 1, Ordering::Relaxed);
+    if cpu == smp::current() {
+        thread.tls.activate();
+    }
+    #[cfg(debug_assertions)]
+    crate::sync::lockdep::set_current(cpu, &mut thread.held);
+}
+
+/// Turn the boot code into thread 0 ("main") and create an idle thread per CPU.
+/// Needs the heap and `smp::init`; preemption starts with `time::init`.
+pub fn init() {
+    let mut boot = Thread::new("main", None, None).expect("boot thread TLS");
+    boot.state = State::Running;
+    boot.on_cpu = true;
+    let mut idlers = Vec::new();
+    let mut bsp_idle = Thread::with_stack("idle", 4, Box::new(|| idle())).expect("idle thread stack");
+    bsp_idle.affinity = CpuMask::single(0);
+    idlers.push((bsp_idle, true));
+    // The APs already run `idle` on their boot stacks: that becomes their idle thread.
+    for (index, cpu) in smp::cpus().iter().enumerate().skip(1) {
+        let mut thread = Thread::new("idle", None, None).expect("idle thread TLS");
+        thread.state = State::Running;
+        thread.on_cpu = true;
+        thread.cpu = index;
+        thread.affinity = CpuMask::single(index);
+        idlers.push((thread, cpu.is_online()));
+    }
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(Scheduler::new(boot, idlers)));
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// What a CPU runs when no thread is ready: halt until the next interrupt.
+/// Ticks that land here count as idle time (see `cpu_stats`).
+pub fn idle() -> ! {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Thread settings, like `std::thread::Builder`.
+pub struct Builder {
+    name: &'static str,
+    stack_pages: u64,
+    priority: Priority,
+    affinity: CpuMask,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder { name: "thread", stack_pages: STACK_PAGES, priority: Priority::Normal, affinity: CpuMask::ALL }
+    }
+
+    /// Only run on these CPUs. A mask without an online CPU is ignored.
+    pub fn affinity(mut self, mask: CpuMask) -> Self {
+        self.affinity = mask;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn stack_pages(mut self, pages: u64) -> Self {
+        self.stack_pages = pages;
+        self
+    }
+
+    /// Start a thread running `f`; `None` if there is no memory or no free thread slot.
+    /// It is ready at once and runs at the next switch.
+    pub fn spawn<T, F>(self, f: F) -> Option<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // Safety: `'static` closures and values outlive any thread.
+        unsafe { self.spawn_unchecked(f, None) }
+    }
+
+    /// Like `spawn`, but the thread belongs to `scope` and may borrow what
+    /// outlives it.
+    pub fn spawn_scoped<'scope, 'env, T, F>(self, scope: &'scope Scope<'scope, 'env>, f: F) -> Option<ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let data = scope.data().clone();
+        data.started();
+        // Safety: `scope::scope` doesn't return before `finished` below has
+        // run, by which time the thread has dropped `f` and its packet.
+        match unsafe { self.spawn_unchecked(f, Some(data)) } {
+            Some(handle) => Some(ScopedJoinHandle::new(handle)),
+            None => {
+                scope.data().finished();
+                None
+            }
+        }
+    }
+
+    /// Safety: whatever `f` and `T` borrow must stay valid until the thread
+    /// has finished with them, which for a thread in a `scope` is when it
+    /// calls `finished` on it.
+    unsafe fn spawn_unchecked<'a, T, F>(self, f: F, scope: Option<Arc<ScopeData>>) -> Option<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        reap();
+        let packet = Arc::new(Packet { result: Mutex::new(None), finished: AtomicBool::new(false), joiner: Mutex::new(None) });
+        let their_packet = packet.clone();
+        let main = move || {
+            let value = f();
+            *their_packet.result.lock() = Some(value);
+            their_packet.finish();
+            // If the handle is gone this drops the value: before the scope can end.
+            drop(their_packet);
+            if let Some(scope) = scope {
+                scope.finished();
+            }
+        };
+        let main: Box<dyn FnOnce() + Send + 'a> = Box::new(main);
+        // Safety: the caller keeps the borrows alive for as long as the thread uses them.
+        let main: Entry = unsafe { core::mem::transmute::<Box<dyn FnOnce() + Send + 'a>, Entry>(main) };
+        let mut thread = Thread::with_stack(self.name, self.stack_pages, main)?;
+        thread.priority = self.priority;
+        thread.effective = self.priority;
+        if smp::cpus().iter().enumerate().any(|(index, cpu)| cpu.is_online() && self.affinity.contains(index)) {
+            thread.affinity = self.affinity;
+        }
+        let id = thread.id;
+        // Allocations and frees stay outside the lock: a preempted thread might hold the heap.
+        let rejected = interrupts::without_interrupts(|| SCHEDULER.lock().as_mut()?.add(thread, smp::current()).err());
+        match rejected {
+            None => Some(JoinHandle { id, packet }),
+            Some(thread) => {
+                drop(thread);
+                None
+            }
+        }
+    }
+}
+
+/// Start a kernel thread running `f`, like `std::thread::spawn`. Panics if it can't.
+pub fn spawn<T, F>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("thread::spawn: out of memory or thread slots")
+}
+
+/// Where a thread's return value waits for `join`.
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    finished: AtomicBool,
+    /// Thread blocked in `join`. Only locked with interrupts off.
+    joiner: Mutex<Option<ThreadId>>,
+}
+
+impl<T> Packet<T> {
+    fn finish(&self) {
+        interrupts::without_interrupts(|| {
+            self.finished.store(true, Ordering::Release);
+            if let Some(joiner) = self.joiner.lock().take() {
+                unblock(joiner);
+            }
+        });
+    }
+}
+
+/// Owned permission to wait for a thread and take its return value.
+/// Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.finished.load(Ordering::Acquire)
+    }
+
+    /// Block until the thread has returned, and get its value.
+    pub fn join(self) -> T {
+        // Register, then check: a `finish` on another CPU either sees the
+        // joiner or has already set `finished`.
+        interrupts::without_interrupts(|| loop {
+            *self.packet.joiner.lock() = Some(current_id());
+            if self.is_finished() {
+                break;
+            }
+            block();
+        });
+        self.packet.result.lock().take().expect("thread finished without a result")
+    }
+}
+
+pub fn current_id() -> ThreadId {
+    try_current_id().expect("scheduler not initialized")
+}
+
+/// The running thread, or `None` before `init`. Takes no lock.
+pub fn try_current_id() -> Option<ThreadId> {
+    // Interrupts off, so we can't move to another CPU between the two reads.
+    let id = interrupts::without_interrupts(|| CURRENT[smp::current()].load(Ordering::Relaxed));
+    id.checked_sub(1).map(ThreadId)
+}
+
+/// What thread `id` is doing; `None` if there is no such thread (any more).
+pub fn state(id: ThreadId) -> Option<State> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref()?.state(id))
+}
+
+/// Change the base priority of thread `id`; false if there is no such thread.
+pub fn set_priority(id: ThreadId, priority: Priority) -> bool {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_mut().is_some_and(|s| s.set_priority(id, priority)))
+}
+
+/// Restrict thread `id` to the CPUs in `mask`; false if there is no such
+/// thread or `mask` has no online CPU. The current thread moves right away.
+pub fn set_affinity(id: ThreadId, mask: CpuMask) -> bool {
+    let set = interrupts::without_interrupts(|| SCHEDULER.lock().as_mut().is_some_and(|s| s.set_affinity(id, mask)));
+    if set && id == current_id() {
+        yield_now();
+    }
+    set
+}
+
+/// Lend thread `id` the current thread's priority, if that is higher, until
+/// `id` calls `end_inheritance`. For a mutex waiter and the mutex's owner.
+pub fn lend_priority(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else { return false };
+        let priority = scheduler.current(smp::current()).effective;
+        scheduler.lend(id, priority)
+    })
+}
+
+/// Give back any priority lent to the current thread.
+pub fn end_inheritance() {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.end_inheritance(smp::current());
+        }
+    });
+}
+
+/// Turn starvation protection on or off (for comparing the two).
+pub fn set_aging(on: bool) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.aging = on;
+        }
+    });
+}
+
+/// Let another ready thread of at least the same priority run; returns when
+/// this one is scheduled again.
+#[track_caller]
+pub fn yield_now() {
+    #[cfg(debug_assertions)]
+    preempt::assert_may_sleep();
+    interrupts::without_interrupts(schedule);
+}
+
+/// Whether `init` has run, i.e. `block` and `sleep` can be used.
+pub fn initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Put the current thread to sleep until someone calls `unblock` on it.
+/// Interrupts must be off, so a wakeup on this CPU can't slip in before we
+/// block. One from another CPU can; then this returns at once, so callers
+/// re-check what they wait for in a loop.
+#[track_caller]
+pub fn block() {
+    #[cfg(debug_assertions)]
+    preempt::assert_may_sleep();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        let thread = scheduler.current(smp::current());
+        if core::mem::take(&mut thread.wake_pending) {
+            return;
+        }
+        thread.state = State::Blocked;
+    }
+    schedule();
+}
+
+/// Make a blocked thread runnable again. Interrupts must be off.
+pub fn unblock(id: ThreadId) -> bool {
+    SCHEDULER.lock().as_mut().is_some_and(|s| s.unblock(id))
+}
+
+/// Called from the timer interrupt (interrupts are off): account the tick and preempt.
+pub fn on_tick() {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else { return };
+        let cpu = smp::current();
+        scheduler.account(cpu);
+        scheduler.age(cpu);
+    }
+    if preempt::may_preempt() {
+        // Not inside an RCU read-side section either: those disable preemption.
+        crate::sync::rcu::quiescent();
+        schedule();
+    }
+}
+
+/// Called from the wakeup IPI (interrupts are off): run the work queued for this CPU.
+pub fn reschedule() {
+    if preempt::may_preempt() {
+        schedule();
+    }
+}
+
+/// Switch to the next ready thread. Interrupts must be off.
+fn schedule() {
+    // While the thread-locals are still this thread's: `switch` loads the
+    // next one's. Wasted if this thread keeps running, but harmless.
+    crate::user::suspend();
+    let switch = SCHEDULER.lock().as_mut().and_then(|s| s.switch(smp::current()));
+    if let Some((prev_rsp, next_rsp)) = switch {
+        if smp::is_multi() {
+            // Mapping changes only flush the TLB of the CPU making them (there is
+            // no shootdown yet), so at least don't carry stale entries into
+            // another thread, which may have just moved here.
+            tlb::flush_all();
+        }
+        // The lock is released; with interrupts off nothing can run on this CPU
+        // until the switch is done, and `prev` stays off the queues until then.
+        unsafe { switch::context_switch(prev_rsp, next_rsp) };
+        finish_switch();
+    }
+}
+
+/// Second half of a switch, on the thread switched to (possibly on another CPU by now).
+fn finish_switch() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.finish_switch(smp::current());
+    }
+    // The thread's address space and, if it is running user code, the stack
+    // its system calls and interrupts land on and its FPU registers.
+    crate::memory::address_space::resume();
+    crate::user::resume();
+}
+
+/// Where a new thread's first switch lands. The switch happened with interrupts off.
+extern "C" fn start() -> ! {
+    finish_switch();
+    let entry = SCHEDULER.lock().as_mut().expect("scheduler not initialized").current(smp::current()).entry.take();
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// End the current thread. Its stack is freed later by `spawn`.
+fn exit() -> ! {
+    interrupts::disable();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current(smp::current()).state = State::Finished;
+    }
+    schedule();
+    unreachable!("a finished thread was scheduled again");
+}
+
+/// Drop threads that have finished, freeing their stacks.
+fn reap() {
+    while let Some(thread) = interrupts::without_interrupts(|| SCHEDULER.lock().as_mut()?.take_finished()) {
+        drop(thread);
+    }
+}
+
+/// Every thread's scheduling state and counters, in slot order (`None` past
+/// the last one); all `None` before `init`.
+pub fn snapshot() -> [Option<ThreadStats>; MAX_THREADS] {
+    reap();
+    let mut rows = [None; MAX_THREADS];
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_ref() {
+            for (row, stats) in rows.iter_mut().zip(scheduler.snapshot()) {
+                *row = Some(stats);
+            }
+        }
+    });
+    rows
+}
+
+pub fn list() {
+    // Copy out first: printing with interrupts off could spin forever on a
+    // serial lock held by a preempted thread.
+    let rows = snapshot();
+    if rows[0].is_none() {
+        return serial_println!("threads: not initialized");
+    }
+    serial_println!("  id  state    priority          ticks  cpu  moved  allowed  name");
+    for t in rows.into_iter().flatten() {
+        let aged = if t.effective != t.priority { alloc::format!("->{}", t.effective.name()) } else { alloc::string::String::new() };
+        serial_println!(
+            "  {:>2}  {:<8} {:<8}{:<10} {:>5}  {:>3}  {:>5}  {:<7}  {}",
+            t.id.0,
+            alloc::format!("{:?}", t.state),
+            t.priority.name(),
+            aged,
+            t.ticks,
+            t.cpu,
+            t.migrations,
+            alloc::format!("{}", t.affinity),
+            t.name
+        );
+    }
+    for (index, cpu) in cpu_stats().into_iter().flatten() {
+        serial_println!(
+            "  cpu {}: running {}, {} queued, {} stolen, {} switches, {}% busy",
+            index,
+            cpu.current.0,
+            cpu.queued,
+            cpu.stolen,
+            cpu.switches,
+            cpu.recent_percent()
+        );
+    }
+}
+
+/// Threads by CPU time used, most first, like `ps`.
+pub fn ps() {
+    let mut rows: Vec<ThreadStats> = snapshot().into_iter().flatten().collect();
+    rows.sort_by_key(|t| core::cmp::Reverse(t.runtime));
+    let uptime = crate::time::uptime().as_micros().max(1);
+    serial_println!("  id  state    last cpu  switches      runtime   %cpu  name");
+    for t in rows {
+        let last_cpu = t.last_cpu.map_or(alloc::string::String::from("-"), |cpu| alloc::format!("{}", cpu));
+        let runtime = t.runtime.as_micros();
+        serial_println!(
+            "  {:>2}  {:<8} {:>8}  {:>8}  {:>8}.{:03}s  {:>4}  {}",
+            t.id.0,
+            alloc::format!("{:?}", t.state),
+            last_cpu,
+            t.switches,
+            runtime / 1_000_000,
+            runtime / 1000 % 1000,
+            runtime * 100 / uptime,
+            t.name
+        );
+    }
+}
+
+/// Scheduling state and load of each online CPU, by CPU index; all `None` before `init`.
+pub fn cpu_stats() -> [Option<(usize, CpuStats)>; smp::MAX_CPUS] {
+    let mut cpus = [None; smp::MAX_CPUS];
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_ref() {
+            for (row, cpu) in cpus.iter_mut().zip(scheduler.cpu_stats()) {
+                *row = Some(cpu);
+            }
+        }
+    });
+    cpus
+}
+
+const ROUNDS: usize = 50;
+
+/// Two threads take turns through `yield_now`, then hand their results back through `join`.
+pub fn self_test() -> bool {
+    let log = Arc::new(Mutex::new(alloc::vec::Vec::new()));
+    let counter = |letter: u8| {
+        let log = log.clone();
+        move || {
+            for _ in 0..ROUNDS {
+                log.lock().push(letter);
+                yield_now();
+            }
+            letter as usize * ROUNDS
+        }
+    };
+    let Some(a) = Builder::new().name("test-a").spawn(counter(b'a')) else { return false };
+    let b = spawn(counter(b'b'));
+    let sums = a.join() + b.join();
+    let log = log.lock();
+    let interleaved = log.windows(2).filter(|w| w[0] != w[1]).count() > ROUNDS;
+    sums == (b'a' as usize + b'b' as usize) * ROUNDS && log.len() == 2 * ROUNDS && interleaved && spread() && pinned() && scope::self_test()
+}
+
+/// With several CPUs, busy threads spawned on one CPU end up running on others.
+fn spread() -> bool {
+    if !smp::is_multi() {
+        return true;
+    }
+    let seen = Arc::new(AtomicU64::new(0));
+    let handles: Vec<_> = (0..2 * smp::online())
+        .filter_map(|_| {
+            let seen = seen.clone();
+            Builder::new().name("spread").stack_pages(4).spawn(move || {
+                let end = crate::time::ticks() + 10;
+                while crate::time::ticks() < end {
+                    seen.fetch_or(1 << smp::current(), Ordering::Relaxed);
+                    core::hint::spin_loop();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    seen.load(Ordering::Relaxed).count_ones() > 1
+}
+
+/// A thread pinned to the last CPU runs only there, even with the other CPUs
+/// idle, and `set_affinity` moves the current thread.
+fn pinned() -> bool {
+    let Some(last) = smp::cpus().iter().rposition(|cpu| cpu.is_online()) else { return true };
+    let seen = Arc::new(AtomicU64::new(0));
+    let theirs = seen.clone();
+    let Some(handle) = Builder::new().name("pinned").stack_pages(4).affinity(CpuMask::single(last)).spawn(move || {
+        let end = crate::time::ticks() + 10;
+        while crate::time::ticks() < end {
+            theirs.fetch_or(1 << smp::current(), Ordering::Relaxed);
+            yield_now();
+        }
+    }) else {
+        return false;
+    };
+    handle.join();
+    let me = current_id();
+    let moved = set_affinity(me, CpuMask::single(last)) && smp::current() == last;
+    set_affinity(me, CpuMask::ALL);
+    seen.load(Ordering::Relaxed) == 1 << last && moved
+}
+
+/// Busy-loop without ever yielding, printing `letter` now and then.
+fn spin(letter: char) -> u64 {
+    let start = crate::time::ticks();
+    for _ in 0..5 {
+        for _ in 0..20_000_000 {
+            core::hint::spin_loop();
+        }
+        crate::serial_print!("{}", letter);
+    }
+    crate::time::ticks() - start
+}
+
+/// Three threads that never yield: the output only interleaves because the timer preempts them.
+pub fn demo() {
+    let handles: alloc::vec::Vec<_> = ['a', 'b', 'c']
+        .into_iter()
+        .filter_map(|letter| Builder::new().name("spinner").stack_pages(4).spawn(move || spin(letter)))
+        .collect();
+    // `join` blocks, so `main` doesn't burn a share of the CPU while waiting.
+    for handle in handles {
+        let id = handle.id();
+        let ticks = handle.join();
+        serial_println!("\nthread {} took {} ticks of wall time", id.0, ticks);
+    }
+}
+
+/// Spin for `ticks` timer ticks without yielding.
+fn hog(ticks: u64) -> u64 {
+    let end = crate::time::ticks() + ticks;
+    while crate::time::ticks() < end {
+        core::hint::spin_loop();
+    }
+    ticks
+}
+
+/// A high-priority hog next to a low-priority worker, with and without aging:
+/// how long does the worker wait for its first tick of CPU?
+pub fn starvation_demo() {
+    // Stay above the hog until both threads exist.
+    let main = current_id();
+    set_priority(main, Priority::Realtime);
+    for aging in [false, true] {
+        set_aging(aging);
+        let first_run = Arc::new(AtomicU64::new(0));
+        let start = crate::time::ticks();
+        let hog = Builder::new().name("hog").priority(Priority::High).spawn(|| hog(100));
+        let seen = first_run.clone();
+        let worker = Builder::new().name("worker").priority(Priority::Low).spawn(move || {
+            seen.store(crate::time::ticks(), Ordering::Relaxed);
+        });
+        let (Some(hog), Some(worker)) = (hog, worker) else {
+            serial_println!("starvation demo: spawn failed");
+            break;
+        };
+        hog.join();
+        worker.join();
+        serial_println!(
+            "aging {}: low-priority worker first ran after {} ticks (hog ran for 100)",
+            if aging { "on " } else { "off" },
+            first_run.load(Ordering::Relaxed) - start
+        );
+    }
+    set_aging(true);
+    set_priority(main, Priority::Normal);
+}
@@ -0,0 +1,500 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::{set_current, Priority, State, Thread, ThreadId};
+use crate::smp::{self, CpuMask, MAX_CPUS};
+use crate::time::{self, HZ};
+
+/// Most threads that can exist at once, including the boot and idle threads.
+pub const MAX_THREADS: usize = 64;
+
+/// Ticks a ready thread may wait before it is bumped up one priority level.
+pub const AGING_TICKS: u32 = 10;
+
+/// FIFO of thread slots. Fixed size, so scheduling from the timer interrupt never allocates.
+struct RunQueue {
+    slots: [u8; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue { slots: [0; MAX_THREADS], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, slot: usize) {
+        assert!(self.len < MAX_THREADS, "run queue overflow");
+        self.slots[(self.head + self.len) % MAX_THREADS] = slot as u8;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let slot = self.slots[self.head] as usize;
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(slot)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).map(|i| self.slots[(self.head + i) % MAX_THREADS] as usize)
+    }
+
+    /// Take `slot` out of the queue, keeping the others in order.
+    fn remove(&mut self, slot: usize) -> bool {
+        let mut found = false;
+        for _ in 0..self.len {
+            let s = self.pop().expect("queue length");
+            if s == slot && !found {
+                found = true;
+            } else {
+                self.push(s);
+            }
+        }
+        found
+    }
+}
+
+/// One CPU's share of the scheduler.
+struct Cpu {
+    ready: [RunQueue; Priority::COUNT],
+    current: usize,
+    /// Runs when nobody else can; never queued.
+    idle: usize,
+    /// The thread switched away from, until the switch is complete (see `finish_switch`).
+    prev: Option<usize>,
+    online: bool,
+    /// Threads this CPU took from other CPUs' queues.
+    stolen: u64,
+    /// Context switches it made.
+    switches: u64,
+    /// Timer ticks that found the CPU in its idle thread, or in any other.
+    idle_ticks: u64,
+    busy_ticks: u64,
+    /// Busy ticks in the last full second, and `busy_ticks` when it began.
+    recent_busy: u64,
+    busy_mark: u64,
+}
+
+impl Cpu {
+    fn queued(&self) -> usize {
+        self.ready.iter().map(|q| q.len).sum()
+    }
+}
+
+/// A snapshot of one CPU's scheduling state and load.
+#[derive(Clone, Copy)]
+pub struct CpuStats {
+    pub current: ThreadId,
+    pub queued: usize,
+    pub stolen: u64,
+    pub switches: u64,
+    pub idle_ticks: u64,
+    pub busy_ticks: u64,
+    recent_busy: u64,
+}
+
+impl CpuStats {
+    /// Share of ticks spent outside the idle thread since the scheduler started.
+    pub fn busy_percent(&self) -> u64 {
+        (self.busy_ticks * 100).checked_div(self.idle_ticks + self.busy_ticks).unwrap_or(0)
+    }
+
+    /// The same over the last full second.
+    pub fn recent_percent(&self) -> u64 {
+        self.recent_busy * 100 / HZ
+    }
+}
+
+/// A snapshot of one thread's scheduling state and counters.
+#[derive(Clone, Copy)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub priority: Priority,
+    pub effective: Priority,
+    pub ticks: u64,
+    /// Time it has spent running, including the current stretch if it runs now.
+    pub runtime: Duration,
+    pub switches: u64,
+    /// CPU whose queue it waits in, or that runs it.
+    pub cpu: usize,
+    pub last_cpu: Option<usize>,
+    pub migrations: u64,
+    pub affinity: CpuMask,
+}
+
+/// Per-CPU round-robin queues, one per priority, over a shared table of threads;
+/// each CPU runs the highest non-empty queue of its own and steals from the
+/// busiest other CPU when it has nothing left. A thread only ever waits on,
+/// runs on or is stolen by a CPU in its affinity mask. Threads that wait too
+/// long are aged up a level so low priorities can't starve.
+///
+/// One lock covers it all, taken with interrupts off. A thread that is being
+/// switched away from is only queued again once its registers are saved, by
+/// the next thread on that CPU, so no other CPU can pick it up half-saved.
+pub struct Scheduler {
+    threads: [Option<Box<Thread>>; MAX_THREADS],
+    cpus: [Cpu; MAX_CPUS],
+    pub aging: bool,
+}
+
+impl Scheduler {
+    /// Start with the boot thread running on CPU 0. `idle` holds one idle
+    /// thread per CPU, with whether that CPU is online.
+    pub fn new(boot: Box<Thread>, idle: Vec<(Box<Thread>, bool)>) -> Self {
+        let mut threads = [const { None }; MAX_THREADS];
+        let mut boot = boot;
+        set_current(0, &mut boot);
+        threads[0] = Some(boot);
+        let mut cpus = [const {
+            Cpu {
+                ready: [const { RunQueue::new() }; Priority::COUNT],
+                current: 0,
+                idle: 0,
+                prev: None,
+                online: false,
+                stolen: 0,
+                switches: 0,
+                idle_ticks: 0,
+                busy_ticks: 0,
+                recent_busy: 0,
+                busy_mark: 0,
+            }
+        }; MAX_CPUS];
+        for (index, (mut thread, online)) in idle.into_iter().enumerate() {
+            let slot = index + 1;
+            let cpu = &mut cpus[index];
+            cpu.idle = slot;
+            cpu.online = online;
+            // The APs are already running their idle loop; CPU 0 runs `boot`.
+            if index > 0 {
+                cpu.current = slot;
+                set_current(index, &mut thread);
+            }
+            threads[slot] = Some(thread);
+        }
+        Scheduler { threads, cpus, aging: true }
+    }
+
+    pub fn current(&mut self, cpu: usize) -> &mut Thread {
+        let slot = self.cpus[cpu].current;
+        self.thread(slot)
+    }
+
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("empty thread slot")
+    }
+
+    fn is_idle(&self, slot: usize) -> bool {
+        self.cpus.iter().any(|cpu| cpu.online && cpu.idle == slot)
+    }
+
+    /// The CPU a ready thread should wait on: its last one, unless its
+    /// affinity no longer allows that.
+    fn home(&self, slot: usize) -> usize {
+        let thread = self.threads[slot].as_ref().expect("empty thread slot");
+        let allowed = |cpu: usize| self.cpus[cpu].online && thread.affinity.contains(cpu);
+        if allowed(thread.cpu) {
+            return thread.cpu;
+        }
+        (0..MAX_CPUS).find(|&cpu| allowed(cpu)).unwrap_or(thread.cpu)
+    }
+
+    /// Queue a ready thread on its CPU at its effective priority, and wake a
+    /// CPU that has nothing to do: the owner, or else one that can steal it.
+    fn enqueue(&mut self, slot: usize) {
+        let cpu = self.home(slot);
+        let thread = self.thread(slot);
+        if thread.cpu != cpu {
+            thread.cpu = cpu;
+            thread.migrations += 1;
+        }
+        thread.waited = 0;
+        let (level, affinity) = (thread.effective as usize, thread.affinity);
+        self.cpus[cpu].ready[level].push(slot);
+        let here = smp::current();
+        let idle = |c: &Cpu| c.online && c.current == c.idle;
+        if cpu != here && idle(&self.cpus[cpu]) {
+            smp::send_wakeup(cpu);
+        } else if let Some(other) =
+            (0..MAX_CPUS).find(|&c| c != here && c != cpu && affinity.contains(c) && idle(&self.cpus[c]))
+        {
+            smp::send_wakeup(other);
+        }
+    }
+
+    /// Add a ready thread on `cpu`; gives it back if the table is full.
+    pub fn add(&mut self, mut thread: Box<Thread>, cpu: usize) -> Result<(), Box<Thread>> {
+        let Some(slot) = self.threads.iter().position(Option::is_none) else { return Err(thread) };
+        thread.cpu = cpu;
+        self.threads[slot] = Some(thread);
+        self.enqueue(slot);
+        Ok(())
+    }
+
+    /// Make a blocked thread ready again; false if it doesn't exist or has finished.
+    /// A thread that hasn't blocked yet (it may be about to, on another CPU) gets
+    /// a pending wakeup instead, and its next `block` returns at once.
+    pub fn unblock(&mut self, id: ThreadId) -> bool {
+        let Some(slot) = self.threads.iter().position(|t| t.as_ref().is_some_and(|t| t.id == id)) else { return false };
+        let thread = self.thread(slot);
+        match thread.state {
+            State::Finished => return false,
+            State::Blocked => {
+                thread.state = State::Ready;
+                // Still switching out: `finish_switch` queues it.
+                if !thread.on_cpu {
+                    self.enqueue(slot);
+                }
+            }
+            State::Ready | State::Running => thread.wake_pending = true,
+        }
+        true
+    }
+
+    pub fn state(&self, id: ThreadId) -> Option<State> {
+        self.threads.iter().flatten().find(|t| t.id == id).map(|t| t.state)
+    }
+
+    /// Change a thread's base priority. A queued thread moves to its new level at once.
+    pub fn set_priority(&mut self, id: ThreadId, priority: Priority) -> bool {
+        let Some(slot) = self.threads.iter().position(|t| t.as_ref().is_some_and(|t| t.id == id)) else { return false };
+        let thread = self.thread(slot);
+        let old_level = thread.effective as usize;
+        thread.priority = priority;
+        thread.effective = thread.base();
+        self.requeue(slot, old_level);
+        true
+    }
+
+    /// Raise thread `id` to at least `priority` until it calls `end_inheritance`.
+    /// Only one level deep: if `id` itself waits for a mutex, its owner isn't boosted.
+    pub fn lend(&mut self, id: ThreadId, priority: Priority) -> bool {
+        let Some(slot) = self.threads.iter().position(|t| t.as_ref().is_some_and(|t| t.id == id)) else { return false };
+        let thread = self.thread(slot);
+        thread.inherited = thread.inherited.max(Some(priority));
+        if thread.effective < priority {
+            let old_level = thread.effective as usize;
+            thread.effective = priority;
+            self.requeue(slot, old_level);
+        }
+        true
+    }
+
+    /// `cpu`'s current thread gives back what was lent to it.
+    pub fn end_inheritance(&mut self, cpu: usize) {
+        let thread = self.current(cpu);
+        if thread.inherited.take().is_some() {
+            thread.effective = thread.priority;
+        }
+    }
+
+    /// Move a queued thread from `old_level` to the queue of its effective priority.
+    fn requeue(&mut self, slot: usize, old_level: usize) {
+        let thread = self.thread(slot);
+        let (state, cpu) = (thread.state, thread.cpu);
+        if state == State::Ready && !self.is_idle(slot) && self.cpus[cpu].ready[old_level].remove(slot) {
+            self.enqueue(slot);
+        }
+    }
+
+    /// Charge a timer tick on `cpu` to its current thread, and to the CPU as idle or busy.
+    pub fn account(&mut self, cpu: usize) {
+        self.current(cpu).ticks += 1;
+        let cpu = &mut self.cpus[cpu];
+        if cpu.current == cpu.idle {
+            cpu.idle_ticks += 1;
+        } else {
+            cpu.busy_ticks += 1;
+        }
+        let total = cpu.idle_ticks + cpu.busy_ticks;
+        if total.is_multiple_of(HZ) {
+            cpu.recent_busy = cpu.busy_ticks - cpu.busy_mark;
+            cpu.busy_mark = cpu.busy_ticks;
+        }
+    }
+
+    /// Restrict thread `id` to the CPUs in `mask`. False if there is no such
+    /// thread or no online CPU in `mask`. A queued thread moves at once, a
+    /// running one at its next switch (see `switch`).
+    pub fn set_affinity(&mut self, id: ThreadId, mask: CpuMask) -> bool {
+        if !(0..MAX_CPUS).any(|cpu| self.cpus[cpu].online && mask.contains(cpu)) {
+            return false;
+        }
+        let Some(slot) = self.threads.iter().position(|t| t.as_ref().is_some_and(|t| t.id == id)) else { return false };
+        if self.is_idle(slot) {
+            return false;
+        }
+        let thread = self.thread(slot);
+        thread.affinity = mask;
+        let (state, on_cpu, cpu, level) = (thread.state, thread.on_cpu, thread.cpu, thread.effective as usize);
+        if state == State::Ready && !on_cpu && !mask.contains(cpu) && self.cpus[cpu].ready[level].remove(slot) {
+            self.enqueue(slot);
+        }
+        true
+    }
+
+    /// Count one more tick of waiting for every thread queued on `cpu`, promoting
+    /// those that reached `AGING_TICKS`. Called from that CPU's timer tick.
+    pub fn age(&mut self, cpu: usize) {
+        if !self.aging {
+            return;
+        }
+        // Top down, so a promoted thread is not looked at again on this tick.
+        for level in (0..Priority::COUNT - 1).rev() {
+            for _ in 0..self.cpus[cpu].ready[level].len {
+                let slot = self.cpus[cpu].ready[level].pop().expect("queue length");
+                let thread = self.thread(slot);
+                thread.waited += 1;
+                let level = if thread.waited >= AGING_TICKS {
+                    thread.effective = Priority::from_level(level + 1);
+                    thread.waited = 0;
+                    level + 1
+                } else {
+                    level
+                };
+                self.cpus[cpu].ready[level].push(slot);
+            }
+        }
+    }
+
+    /// Remove one finished thread so the caller can drop it outside the lock.
+    pub fn take_finished(&mut self) -> Option<Box<Thread>> {
+        let slot = self.threads.iter().position(|t| t.as_ref().is_some_and(|t| t.state == State::Finished && !t.on_cpu))?;
+        self.threads[slot].take()
+    }
+
+    /// Pick the next thread for `cpu` and mark it running. A running thread only
+    /// gives way to one of at least its own effective priority; one that blocked,
+    /// finished or may no longer run here (or the idle thread) gives way to anyone,
+    /// stolen from another CPU if need be. Returns pointers for `context_switch`,
+    /// or `None` if the current thread should keep going. Call `finish_switch`
+    /// after the switch.
+    pub fn switch(&mut self, cpu: usize) -> Option<(*mut u64, u64)> {
+        let prev = self.cpus[cpu].current;
+        let idle = self.cpus[cpu].idle;
+        let running = self.thread(prev).state == State::Running;
+        let leaving = running && !self.thread(prev).affinity.contains(cpu);
+        let keeps = running && prev != idle && !leaving;
+        let floor = if keeps { self.thread(prev).effective as usize } else { 0 };
+        let mut next = (floor..Priority::COUNT).rev().find_map(|level| self.cpus[cpu].ready[level].pop());
+        if next.is_none() && !keeps {
+            next = self.steal(cpu);
+        }
+        let next = match next {
+            Some(next) => next,
+            None if running && !leaving => return None,
+            None => idle,
+        };
+        if running {
+            let thread = self.thread(prev);
+            thread.state = State::Ready;
+            // It had its turn: back to its own (or inherited) priority.
+            thread.effective = thread.base();
+        }
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        {
+            let thread = self.thread(prev);
+            thread.runtime += now.saturating_sub(thread.switched_in);
+            thread.switches += 1;
+        }
+        self.cpus[cpu].switches += 1;
+        self.cpus[cpu].prev = Some(prev);
+        self.cpus[cpu].current = next;
+        let next_rsp = {
+            let thread = self.thread(next);
+            thread.switched_in = now;
+            thread.last_cpu = Some(cpu);
+            thread.state = State::Running;
+            thread.on_cpu = true;
+            thread.cpu = cpu;
+            set_current(cpu, thread);
+            thread.rsp
+        };
+        let prev_rsp = &mut self.thread(prev).rsp as *mut u64;
+        Some((prev_rsp, next_rsp))
+    }
+
+    /// Take the most urgent thread that may run on `cpu` from another CPU's
+    /// queues, from the longest queues if there is a choice.
+    fn steal(&mut self, cpu: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        for victim in (0..MAX_CPUS).filter(|&c| c != cpu && self.cpus[c].online) {
+            let queued = self.cpus[victim].queued();
+            for level in (0..Priority::COUNT).rev() {
+                let found = self.cpus[victim].ready[level]
+                    .iter()
+                    .find(|&slot| self.threads[slot].as_ref().is_some_and(|t| t.affinity.contains(cpu)));
+                if let Some(slot) = found {
+                    if best.is_none_or(|(l, q, _, _)| (level, queued) > (l, q)) {
+                        best = Some((level, queued, victim, slot));
+                    }
+                    break;
+                }
+            }
+        }
+        let (level, _, victim, slot) = best?;
+        self.cpus[victim].ready[level].remove(slot);
+        let thread = self.thread(slot);
+        thread.cpu = cpu;
+        thread.migrations += 1;
+        self.cpus[cpu].stolen += 1;
+        Some(slot)
+    }
+
+    /// Runs on `cpu` right after a switch: the previous thread's registers are
+    /// saved now, so it may be queued (if it is ready) or freed (if finished).
+    pub fn finish_switch(&mut self, cpu: usize) {
+        let Some(prev) = self.cpus[cpu].prev.take() else { return };
+        let thread = self.thread(prev);
+        thread.on_cpu = false;
+        if thread.state == State::Ready && prev != self.cpus[cpu].idle {
+            self.enqueue(prev);
+        }
+    }
+
+    /// Every thread's state and counters, in slot order.
+    pub fn snapshot(&self) -> impl Iterator<Item = ThreadStats> + '_ {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        self.threads.iter().flatten().map(move |t| {
+            let running = if t.state == State::Running { now.saturating_sub(t.switched_in) } else { 0 };
+            ThreadStats {
+                id: t.id,
+                name: t.name,
+                state: t.state,
+                priority: t.priority,
+                effective: t.effective,
+                ticks: t.ticks,
+                runtime: time::tsc_duration(t.runtime + running),
+                switches: t.switches,
+                cpu: t.cpu,
+                last_cpu: t.last_cpu,
+                migrations: t.migrations,
+                affinity: t.affinity,
+            }
+        })
+    }
+
+    /// Online CPUs' state, by CPU index.
+    pub fn cpu_stats(&self) -> impl Iterator<Item = (usize, CpuStats)> + '_ {
+        self.cpus.iter().enumerate().filter(|(_, c)| c.online).map(|(index, c)| {
+            let current = self.threads[c.current].as_ref().map_or(ThreadId(0), |t| t.id);
+            let stats = CpuStats {
+                current,
+                queued: c.queued(),
+                stolen: c.stolen,
+                switches: c.switches,
+                idle_ticks: c.idle_ticks,
+                busy_ticks: c.busy_ticks,
+                recent_busy: c.recent_busy,
+            };
+            (index, stats)
+        })
+    }
+}
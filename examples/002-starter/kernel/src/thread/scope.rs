@@ -0,0 +1,140 @@
+//! Scoped threads, like `std::thread::scope`: every thread spawned in a
+//! scope has finished before `scope` returns, so it may borrow the caller's
+//! locals instead of needing everything behind an `Arc`.
+//!
+//! ```ignore
+//! let mut counts = [0u64; 4];
+//! thread::scope(|s| {
+//!     for count in counts.iter_mut() {
+//!         s.spawn(move || *count = work());
+//!     }
+//! });
+//! ```
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::sync::Arc;
+
+use super::{Builder, JoinHandle};
+use crate::sync::WaitQueue;
+
+/// Shared with the scope's threads, which may outlive the `Scope` itself by
+/// the few instructions after they call `finished`.
+pub(super) struct ScopeData {
+    running: AtomicUsize,
+    done: WaitQueue,
+}
+
+impl ScopeData {
+    pub(super) fn started(&self) {
+        self.running.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The thread is done with everything it borrowed.
+    pub(super) fn finished(&self) {
+        if self.running.fetch_sub(1, Ordering::Release) == 1 {
+            self.done.notify_all();
+        }
+    }
+}
+
+/// Handed to the closure of `scope` for spawning threads.
+///
+/// `'scope` is the lifetime of the scope itself, `'env` that of what the
+/// threads may borrow. Both are invariant, as in std, so neither can be
+/// shortened to let a borrow slip out.
+pub struct Scope<'scope, 'env: 'scope> {
+    data: Arc<ScopeData>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Run `f` with a `Scope`, then wait for every thread it spawned (joined or
+/// not) before returning `f`'s value.
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        data: Arc::new(ScopeData { running: AtomicUsize::new(0), done: WaitQueue::new() }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    let value = f(&scope);
+    scope.data.done.wait_until(|| scope.data.running.load(Ordering::Acquire) == 0);
+    value
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Start a thread that may borrow anything outliving the scope. Panics if
+    /// it can't; see `Builder::spawn_scoped` for the fallible version.
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        Builder::new().spawn_scoped(self, f).expect("Scope::spawn: out of memory or thread slots")
+    }
+
+    pub(super) fn data(&self) -> &Arc<ScopeData> {
+        &self.data
+    }
+}
+
+/// A `JoinHandle` that can't leave its scope. Dropping it doesn't detach the
+/// thread: the scope still waits for it.
+pub struct ScopedJoinHandle<'scope, T> {
+    handle: JoinHandle<T>,
+    _scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, T> ScopedJoinHandle<'scope, T> {
+    pub(super) fn new(handle: JoinHandle<T>) -> Self {
+        ScopedJoinHandle { handle, _scope: PhantomData }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Block until the thread has returned, and get its value.
+    pub fn join(self) -> T {
+        self.handle.join()
+    }
+}
+
+/// Threads fill and sum slices of an array on the caller's stack and are
+/// joined for the sums; one more is left for the scope to wait for.
+pub fn self_test() -> bool {
+    let mut values = [0u64; 64];
+    let total = AtomicUsize::new(0);
+    let unjoined = AtomicBool::new(false);
+    let sums = scope(|s| {
+        let handles: alloc::vec::Vec<_> = values
+            .chunks_mut(16)
+            .enumerate()
+            .map(|(n, chunk)| {
+                let total = &total;
+                s.spawn(move || {
+                    for (i, value) in chunk.iter_mut().enumerate() {
+                        *value = (n * 16 + i) as u64;
+                        super::yield_now();
+                    }
+                    total.fetch_add(chunk.len(), Ordering::Relaxed);
+                    chunk.iter().sum::<u64>()
+                })
+            })
+            .collect();
+        s.spawn(|| {
+            crate::time::sleep(core::time::Duration::from_millis(20));
+            unjoined.store(true, Ordering::Relaxed);
+        });
+        handles.into_iter().map(|handle| handle.join()).sum::<u64>()
+    });
+    let expected: u64 = (0..64).sum();
+    sums == expected
+        && values.iter().sum::<u64>() == expected
+        && total.load(Ordering::Relaxed) == values.len()
+        && unjoined.load(Ordering::Relaxed)
+}
@@ -0,0 +1,51 @@
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Save the callee-saved registers on the current stack, store its stack pointer
+// in `*old_rsp`, then load `new_rsp` and pop the next thread's registers. The
+// `ret` returns into wherever that thread last called `context_switch` (or into
+// `start` for a new thread). Caller-saved registers are already on the stack,
+// saved by the compiler around the call.
+global_asm!(
+    ".global context_switch",
+    "context_switch:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+);
+
+extern "C" {
+    /// # Safety
+    /// Interrupts must be off, `new_rsp` must come from a previous switch or
+    /// `initial_stack`, and `old_rsp` must stay valid until that thread resumes.
+    pub fn context_switch(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Lay out a fresh stack so that the first `context_switch` to it "returns" into `start`.
+///
+/// # Safety
+/// `top` must be the 16-byte aligned top of a mapped stack with room for 8 words.
+pub unsafe fn initial_stack(top: VirtAddr, start: extern "C" fn() -> !) -> u64 {
+    let top = top.as_mut_ptr::<u64>();
+    // A zero return address ends stack traces; it also leaves rsp 8 mod 16 at
+    // `start`'s entry, as if it had been called.
+    top.sub(1).write(0);
+    top.sub(2).write(start as usize as u64);
+    // rbp, rbx, r12..r15 start out zeroed.
+    for i in 3..=8 {
+        top.sub(i).write(0);
+    }
+    top.sub(8) as u64
+}
@@ -0,0 +1,157 @@
+//! Thread-local storage: every thread gets its own copy of the kernel's
+//! `#[thread_local]` statics, found through the FS base register.
+//!
+//! The linker gathers those statics into the ELF's TLS segment: `.tdata`
+//! (initial values) followed by `.tbss` (zeroed). A thread's block copies
+//! that template and ends in a one-word thread control block holding its own
+//! address, as the x86_64 ELF ABI ("variant II") expects:
+//!
+//! ```text
+//!   | .tdata copy | .tbss (zeroed) | pad | TCB: self pointer |
+//!   ^ block                               ^ FS base (thread pointer)
+//! ```
+//!
+//! Statics are addressed at fixed negative offsets from the thread pointer.
+//! The scheduler loads a thread's FS base each time a CPU starts running it.
+
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::ptr::NonNull;
+use spin::Once;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+
+use crate::memory::wx::{self, KernelImage};
+use crate::serial_println;
+
+const PT_TLS: u32 = 7;
+
+/// The TLS segment: what a new block starts out as.
+#[derive(Clone, Copy)]
+struct Template {
+    start: u64,
+    filesz: usize,
+    memsz: usize,
+    align: usize,
+}
+
+impl Template {
+    /// Bytes from the start of the block to the thread pointer. The linker
+    /// computes the offsets of the statics the same way.
+    fn data_len(&self) -> usize {
+        self.memsz.next_multiple_of(self.align)
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.data_len() + 8, self.align).expect("TLS layout")
+    }
+}
+
+static TEMPLATE: Once<Template> = Once::new();
+
+/// Find the TLS segment in the kernel image. Call before `thread::init`.
+pub fn init(image: &KernelImage) {
+    let segment = wx::program_headers(image).and_then(|mut headers| headers.find(|ph| ph.kind == PT_TLS));
+    let template = match segment {
+        Some(ph) => Template {
+            start: ph.vaddr,
+            filesz: ph.filesz as usize,
+            memsz: ph.memsz as usize,
+            align: (ph.align as usize).max(8),
+        },
+        // No thread-locals at all: blocks are just the TCB.
+        None => Template { start: 0, filesz: 0, memsz: 0, align: 8 },
+    };
+    TEMPLATE.call_once(|| template);
+}
+
+fn template() -> &'static Template {
+    TEMPLATE.get().expect("tls::init not called")
+}
+
+/// One thread's copy of the thread-locals.
+pub struct Block {
+    base: NonNull<u8>,
+}
+
+// Safety: only the owning thread touches the contents, through FS.
+unsafe impl Send for Block {}
+
+impl Block {
+    /// A fresh block initialized from the template; `None` if out of memory.
+    pub fn new() -> Option<Block> {
+        let template = template();
+        let base = NonNull::new(unsafe { alloc_zeroed(template.layout()) })?;
+        unsafe {
+            let ptr = base.as_ptr();
+            if template.filesz > 0 {
+                core::ptr::copy_nonoverlapping(template.start as *const u8, ptr, template.filesz);
+            }
+            let tcb = ptr.add(template.data_len()).cast::<u64>();
+            tcb.write(tcb as u64);
+        }
+        Some(Block { base })
+    }
+
+    /// What FS base points at while the owner runs.
+    fn thread_pointer(&self) -> VirtAddr {
+        VirtAddr::from_ptr(unsafe { self.base.as_ptr().add(template().data_len()) })
+    }
+
+    /// Make this the block the current CPU's `#[thread_local]`s resolve to.
+    pub fn activate(&self) {
+        FsBase::write(self.thread_pointer());
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base.as_ptr(), template().layout()) };
+    }
+}
+
+pub fn dump() {
+    let t = template();
+    serial_println!(
+        "tls: {} bytes per thread ({} initialized, {} zeroed), aligned to {}",
+        t.layout().size(),
+        t.filesz,
+        t.memsz - t.filesz,
+        t.align
+    );
+}
+
+#[thread_local]
+static SEED: Cell<u64> = Cell::new(0x5eed);
+#[thread_local]
+static SCRATCH: Cell<[u8; 16]> = Cell::new([0; 16]);
+
+/// Each thread sees its own thread-locals, starting from their initial
+/// values, across yields and whichever CPU it lands on.
+pub fn self_test() -> bool {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static FAILED: AtomicBool = AtomicBool::new(false);
+    FAILED.store(false, Ordering::Relaxed);
+    let threads: Vec<_> = (1..=4u8)
+        .filter_map(|n| {
+            super::Builder::new().name("tls-test").spawn(move || {
+                let fresh = SEED.get() == 0x5eed && SCRATCH.get() == [0; 16];
+                SEED.set(n as u64);
+                SCRATCH.set([n; 16]);
+                for _ in 0..10 {
+                    super::yield_now();
+                    if SEED.get() != n as u64 || SCRATCH.get() != [n; 16] {
+                        FAILED.store(true, Ordering::Relaxed);
+                    }
+                }
+                fresh
+            })
+        })
+        .collect();
+    let spawned = threads.len() == 4;
+    let fresh: Vec<bool> = threads.into_iter().map(|t| t.join()).collect();
+    spawned && fresh.iter().all(|&f| f) && !FAILED.load(Ordering::Relaxed)
+}
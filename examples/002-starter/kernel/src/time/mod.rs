@@ -0,0 +1,402 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::pic::{self, Irq};
+use crate::serial_println;
+use crate::softirq::{self, Softirq};
+use crate::sync::SpinLock;
+use crate::thread;
+
+mod rtc;
+pub mod vdso;
+mod wheel;
+
+pub use rtc::DateTime;
+use wheel::{Target, TimerId, TimerWheel};
+
+/// Timer interrupts per second.
+pub const HZ: u64 = 100;
+
+/// The PIT's input clock.
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary.
+const PIT_ONE_SHOT: u8 = 0b1011_0000;
+/// Gate of channel 2 (bit 0), speaker enable (bit 1) and channel 2 output (bit 5).
+const PORT_B: u16 = 0x61;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Time-stamp counter increments per millisecond, measured by `init`.
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// The time-stamp counter when `init` started: `monotonic` counts from it.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+/// The wall-clock time at `monotonic` 0, in microseconds since the Unix
+/// epoch: the CMOS clock at boot, less how long `monotonic` had run.
+static BOOT_TIME_US: AtomicU64 = AtomicU64::new(0);
+
+/// Pending sleeps. Locked only with interrupts off: the timer interrupt expires it.
+static WHEEL: SpinLock<TimerWheel> = SpinLock::new(TimerWheel::new());
+
+/// Program the PIT to interrupt `HZ` times a second and unmask IRQ0, and
+/// set the wall clock from the CMOS clock.
+pub fn init() {
+    softirq::register(Softirq::Timer, Some(expire));
+    TSC_START.store(unsafe { core::arch::x86_64::_rdtsc() }, Ordering::Relaxed);
+    calibrate_tsc();
+    match rtc::read() {
+        Some(now) => {
+            let boot = (now.to_unix() * 1_000_000).saturating_sub(monotonic().as_micros() as u64);
+            BOOT_TIME_US.store(boot, Ordering::Relaxed);
+            serial_println!("time: wall clock {}", now);
+        }
+        None => serial_println!("time: no CMOS clock, wall clock starts at the epoch"),
+    }
+    vdso::init(
+        TSC_PER_MS.load(Ordering::Relaxed),
+        TSC_START.load(Ordering::Relaxed),
+        BOOT_TIME_US.load(Ordering::Relaxed),
+    );
+    let divisor = (PIT_FREQUENCY / HZ) as u16;
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(PIT_CHANNEL0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    });
+    pic::unmask(Irq::Timer);
+    RUNNING.store(true, Ordering::Release);
+}
+
+/// Called from the timer interrupt handler: count the tick; sleepers that are
+/// due are woken by the timer softirq on the way out.
+pub fn on_tick() {
+    vdso::set_ticks(TICKS.fetch_add(1, Ordering::Relaxed) + 1);
+    softirq::raise(Softirq::Timer);
+}
+
+/// The timer softirq. Wakes every due sleeper at once: there are at most as
+/// many as the wheel has entries.
+fn expire(_budget: usize) -> bool {
+    WHEEL.lock().expire(ticks(), |id| {
+        thread::unblock(id);
+    });
+    false
+}
+
+/// Time the TSC against the PIT for 10ms.
+fn calibrate_tsc() {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    busy_wait_us(10_000);
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() } - start;
+    TSC_PER_MS.store((cycles / 10).max(1), Ordering::Relaxed);
+}
+
+/// `cycles` of the time-stamp counter as a duration (zero before `init`).
+pub fn tsc_duration(cycles: u64) -> Duration {
+    match TSC_PER_MS.load(Ordering::Relaxed) {
+        0 => Duration::ZERO,
+        per_ms => Duration::from_micros((cycles as u128 * 1000 / per_ms as u128) as u64),
+    }
+}
+
+/// Timer ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Sleeps armed and not yet expired.
+pub fn pending_timers() -> usize {
+    without_interrupts(|| WHEEL.lock().pending())
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_millis(ticks() * 1000 / HZ)
+}
+
+/// Time since `init`, from the time-stamp counter: finer than `uptime`,
+/// which only moves once a tick. Never goes back.
+pub fn monotonic() -> Duration {
+    tsc_duration(unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(TSC_START.load(Ordering::Relaxed)))
+}
+
+/// The wall-clock time, since the Unix epoch.
+pub fn realtime() -> Duration {
+    Duration::from_micros(BOOT_TIME_US.load(Ordering::Relaxed)) + monotonic()
+}
+
+/// The wall-clock date and time, to the second.
+pub fn now() -> DateTime {
+    DateTime::from_unix(realtime().as_secs())
+}
+
+/// Whole ticks covering `duration`, at least one.
+fn ticks_for(duration: Duration) -> u64 {
+    (duration.as_micros() as u64 * HZ).div_ceil(1_000_000).max(1)
+}
+
+/// Block the current thread for at least `duration` (rounded up to whole ticks).
+///
+/// Other threads run meanwhile. Before the timer and scheduler are up this
+/// falls back to `busy_wait_us`.
+pub fn sleep(duration: Duration) {
+    if !RUNNING.load(Ordering::Acquire) || !thread::initialized() {
+        return busy_wait_us(duration.as_micros() as u64);
+    }
+    let deadline = ticks() + ticks_for(duration);
+    // Arming the timer and blocking with interrupts off, so it can't fire in
+    // between on this CPU. Another wakeup may still end the block early.
+    let armed = without_interrupts(|| {
+        let id = thread::current_id();
+        if WHEEL.lock().insert(deadline, Target::Thread(id)).is_none() {
+            return false;
+        }
+        while ticks() < deadline {
+            thread::block();
+        }
+        true
+    });
+    if !armed {
+        // Out of timer entries: fall back to polling.
+        while ticks() < deadline {
+            thread::yield_now();
+        }
+    }
+}
+
+/// `sleep` that also ends as soon as `interrupted` says so; the thread must
+/// be unblocked for that to be noticed. Returns the time that was left,
+/// zero if it slept it all.
+pub fn sleep_interruptible(duration: Duration, interrupted: impl Fn() -> bool) -> Duration {
+    let deadline = uptime() + duration;
+    if !RUNNING.load(Ordering::Acquire) || !thread::initialized() {
+        busy_wait_us(duration.as_micros() as u64);
+        return Duration::ZERO;
+    }
+    let deadline_ticks = ticks() + ticks_for(duration);
+    let armed = without_interrupts(|| {
+        let id = thread::current_id();
+        let Some(timer) = WHEEL.lock().insert(deadline_ticks, Target::Thread(id)) else { return false };
+        while ticks() < deadline_ticks && !interrupted() {
+            thread::block();
+        }
+        WHEEL.lock().cancel(timer, id);
+        true
+    });
+    if !armed {
+        // Out of timer entries: fall back to polling.
+        while ticks() < deadline_ticks && !interrupted() {
+            thread::yield_now();
+        }
+    }
+    if ticks() >= deadline_ticks { Duration::ZERO } else { deadline.saturating_sub(uptime()) }
+}
+
+/// Arm a timer that unblocks the current thread once `uptime()` reaches
+/// `deadline`, for a `thread::block` with a time limit. Interrupts must be
+/// off. `None` if all timer entries are in use.
+pub fn wake_at(deadline: Duration) -> Option<TimerId> {
+    let deadline = (deadline.as_micros() as u64 * HZ).div_ceil(1_000_000);
+    WHEEL.lock().insert(deadline, Target::Thread(thread::current_id()))
+}
+
+/// Disarm a `wake_at` timer of the current thread if it hasn't fired.
+/// Interrupts must be off.
+pub fn cancel_wake(timer: TimerId) {
+    WHEEL.lock().cancel(timer, thread::current_id());
+}
+
+/// Future that completes `duration` from now; the async counterpart of `sleep`.
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep { deadline: ticks() + ticks_for(duration), timer: None }
+}
+
+/// Future that completes once `uptime()` reaches `deadline` (rounded up to a
+/// whole tick); at once if it already has.
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep { deadline: (deadline.as_micros() as u64 * HZ).div_ceil(1_000_000), timer: None }
+}
+
+/// Run `future`, giving up on it after `duration`: `Err(Elapsed)` then, and
+/// the future is dropped unfinished when the `Timeout` is.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout { future, sleep: sleep_async(duration) }
+}
+
+/// The deadline of a `timeout` passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `future` is never moved out of the pinned `Timeout`; `Sleep` is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        // Both wakers are now registered: whichever fires first polls us again.
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    fn disarm(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            let target = without_interrupts(|| WHEEL.lock().remove(timer));
+            // Dropped here, with interrupts back on: it may be the last reference to a task.
+            drop(target);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let done = match self.timer {
+            Some(timer) => {
+                let (fired, old_waker) = without_interrupts(|| {
+                    let mut wheel = WHEEL.lock();
+                    let old = wheel.set_waker(timer, cx.waker());
+                    (wheel.fired(timer), old)
+                });
+                drop(old_waker);
+                fired
+            }
+            None => ticks() >= self.deadline,
+        };
+        if done {
+            self.disarm();
+            return Poll::Ready(());
+        }
+        if self.timer.is_none() {
+            let target = Target::Task(cx.waker().clone());
+            let deadline = self.deadline;
+            self.timer = without_interrupts(|| WHEEL.lock().insert(deadline, target));
+            if self.timer.is_none() {
+                // Out of timer entries: poll again soon.
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.disarm();
+    }
+}
+
+/// Spin for `us` microseconds using PIT channel 2, which needs neither
+/// interrupts nor the scheduler. For early boot and interrupts-off paths.
+pub fn busy_wait_us(us: u64) {
+    // The 16-bit counter holds up to ~54ms; wait in chunks of 50ms.
+    let mut left = us;
+    while left > 0 {
+        let chunk = left.min(50_000);
+        left -= chunk;
+        let count = (chunk * PIT_FREQUENCY / 1_000_000).max(1) as u16;
+        unsafe {
+            let mut port_b = Port::<u8>::new(PORT_B);
+            // Gate on, speaker off.
+            let b = port_b.read();
+            port_b.write((b & !0b10) | 0b01);
+            Port::<u8>::new(PIT_COMMAND).write(PIT_ONE_SHOT);
+            let mut data = Port::<u8>::new(PIT_CHANNEL2);
+            data.write(count as u8);
+            data.write((count >> 8) as u8);
+            // Output goes high when the count reaches zero.
+            while port_b.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Sleep from a thread and from async tasks, and check the timers fired in
+/// deadline order and that timeouts cut off only what overruns them. An
+/// interrupted sleep stops early with the rest left; the monotonic clock
+/// agrees with the ticks, and the CMOS clock reads right.
+pub fn self_test() -> bool {
+    use crate::task::Executor;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let start = ticks();
+    sleep(Duration::from_millis(50));
+    let slept = ticks() - start;
+    let mut ok = (5..=7).contains(&slept);
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for ms in [30u64, 10, 20] {
+        let order = order.clone();
+        executor.spawn(async move {
+            sleep_async(Duration::from_millis(ms)).await;
+            order.lock().push(ms);
+        });
+    }
+    executor.run();
+    ok &= *order.lock() == [10, 20, 30];
+    ok &= pending_timers() == 0;
+
+    let outcome = Arc::new(Mutex::new(None));
+    let mut executor = Executor::new();
+    let task_outcome = outcome.clone();
+    executor.spawn(async move {
+        let start = ticks();
+        let quick = timeout(sleep_async(Duration::from_millis(10)), Duration::from_millis(50)).await;
+        let slow = timeout(sleep_async(Duration::from_secs(10)), Duration::from_millis(20)).await;
+        // A deadline already behind us completes at once.
+        sleep_until(uptime()).await;
+        *task_outcome.lock() = Some((quick, slow, ticks() - start));
+    });
+    executor.run();
+    ok &= matches!(*outcome.lock(), Some((Ok(()), Err(Elapsed), took)) if (3..=5).contains(&took));
+    ok &= pending_timers() == 0;
+
+    let start = ticks();
+    busy_wait_us(30_000);
+    ok &= ticks() - start >= 2;
+
+    let (sleeper, stop) = (thread::current_id(), Arc::new(AtomicBool::new(false)));
+    let theirs = stop.clone();
+    let waker = thread::spawn(move || {
+        sleep(Duration::from_millis(20));
+        theirs.store(true, Ordering::Release);
+        without_interrupts(|| thread::unblock(sleeper));
+    });
+    let start = ticks();
+    let left = sleep_interruptible(Duration::from_secs(1), || stop.load(Ordering::Acquire));
+    waker.join();
+    ok &= ticks() - start <= 4 && left > Duration::from_millis(900) && pending_timers() == 0;
+
+    let (before, start) = (monotonic(), ticks());
+    sleep(Duration::from_millis(30));
+    let took = monotonic() - before;
+    ok &= took >= Duration::from_millis(10 * (ticks() - start - 1)) && took <= Duration::from_millis(10 * (ticks() - start + 1));
+    ok && realtime() > Duration::from_secs(DateTime { year: 2020, month: 1, day: 1, hour: 0, minute: 0, second: 0 }.to_unix()) && rtc::self_test() && vdso::self_test()
+}
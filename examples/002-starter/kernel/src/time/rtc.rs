@@ -0,0 +1,168 @@
+//! The CMOS real-time clock: the battery-backed date and time of day, read
+//! once at boot for the wall clock. It counts whole seconds, in UTC as QEMU
+//! sets it up, either in BCD or binary and with a 12- or 24-hour clock,
+//! as status register B says.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+/// Status A: the clock is updating its registers; what they hold is torn.
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: the registers are binary rather than BCD.
+const BINARY: u8 = 0x04;
+/// Status B: the hours are 0-23 rather than 1-12 with PM in bit 7.
+const HOURS_24: u8 = 0x02;
+const PM: u8 = 0x80;
+
+/// The address port selects the register the data port reads.
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// A date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days from 1970-01-01 to `year`-`month`-`day` in the proleptic Gregorian
+/// calendar (Howard Hinnant's `days_from_civil`).
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of `days_from_civil`: (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        (days * 86_400) as u64 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let time = seconds % 86_400;
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    fn valid(&self) -> bool {
+        (1..=12).contains(&self.month) && (1..=31).contains(&self.day) && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// The registers as they are, in whatever format the clock keeps them.
+fn read_raw() -> [u8; 6] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register)
+}
+
+/// The date and time now; `None` if the clock holds nonsense (or there is
+/// none).
+pub fn read() -> Option<DateTime> {
+    let (raw, status) = without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        // Read until two reads agree: an update may start right after the
+        // flag was checked.
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(STATUS_B))
+    });
+    let decode = |value: u8| if status & BINARY != 0 { value } else { (value & 0x0f) + (value >> 4) * 10 };
+    let [second, minute, hours, day, month, year] = raw;
+    let mut hour = decode(hours & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is 0, 12 PM is 12.
+        hour = hour % 12 + if hours & PM != 0 { 12 } else { 0 };
+    }
+    let time = DateTime {
+        // Two digits only; this clock has seen no other century.
+        year: 2000 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    };
+    time.valid().then_some(time)
+}
+
+/// Conversions both ways for dates around leap days and centuries, and a
+/// clock that reads as a valid date.
+pub fn self_test() -> bool {
+    let known = [
+        (0, DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 }),
+        (951_782_400, DateTime { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 }),
+        (1_709_251_199, DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 59 }),
+        (4_107_542_400, DateTime { year: 2100, month: 3, day: 1, hour: 0, minute: 0, second: 0 }),
+    ];
+    let converts = known.iter().all(|&(seconds, date)| date.to_unix() == seconds && DateTime::from_unix(seconds) == date);
+    converts && read().is_some()
+}
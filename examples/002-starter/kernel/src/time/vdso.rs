@@ -0,0 +1,99 @@
+//! The time page: one page the kernel keeps the clocks' inputs in, mapped
+//! read-only at `TIME_PAGE` in every user address space, so a program can
+//! read the clocks without a system call (as Linux's vDSO lets it). Only
+//! data is shared, not code: usys does the same sums as `monotonic` and
+//! `realtime` over it.
+//!
+//! Everything but `ticks` is written once by `time::init`, before any
+//! process runs, and `ticks` is a single word the timer interrupt stores, so
+//! a reader needs no lock.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::memory::frame_alloc;
+use crate::memory::phys_to_virt;
+
+/// Where the page is in user address spaces: the first page after the
+/// `mmap` area, far below the stack.
+pub const TIME_PAGE: u64 = crate::memory::address_space::MMAP_END;
+/// Bumped when `TimePage`'s layout changes; usys checks it.
+pub const VERSION: u64 = 1;
+
+/// The page's layout, shared with usys's `time`.
+#[repr(C)]
+pub struct TimePage {
+    pub version: AtomicU64,
+    /// Timer interrupts per second.
+    pub hz: AtomicU64,
+    /// Timer ticks since `time::init`.
+    pub ticks: AtomicU64,
+    /// Time-stamp counter increments per millisecond; 0 until calibrated.
+    pub tsc_per_ms: AtomicU64,
+    /// The time-stamp counter at monotonic 0.
+    pub tsc_start: AtomicU64,
+    /// The wall-clock time at monotonic 0, in microseconds since the epoch.
+    pub boot_time_us: AtomicU64,
+}
+
+static FRAME: Once<PhysFrame> = Once::new();
+
+/// Allocate the page and fill it in. Called by `time::init` once the
+/// clocks are set; address spaces made earlier go without it.
+pub(super) fn init(tsc_per_ms: u64, tsc_start: u64, boot_time_us: u64) {
+    let Some(frame) = frame_alloc::allocate_frame() else {
+        crate::serial_println!("time: no memory for the time page");
+        return;
+    };
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096) };
+    let page = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<TimePage>() };
+    page.hz.store(super::HZ, Ordering::Relaxed);
+    page.tsc_per_ms.store(tsc_per_ms, Ordering::Relaxed);
+    page.tsc_start.store(tsc_start, Ordering::Relaxed);
+    page.boot_time_us.store(boot_time_us, Ordering::Relaxed);
+    page.version.store(VERSION, Ordering::Release);
+    FRAME.call_once(|| frame);
+}
+
+/// The page's frame, for `AddressSpace::new` to map; `None` before `init`.
+pub fn frame() -> Option<PhysFrame> {
+    FRAME.get().copied()
+}
+
+fn page() -> Option<&'static TimePage> {
+    FRAME.get().map(|frame| unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<TimePage>() })
+}
+
+/// Publish the tick count; from the timer interrupt.
+pub(super) fn set_ticks(ticks: u64) {
+    if let Some(page) = page() {
+        page.ticks.store(ticks, Ordering::Release);
+    }
+}
+
+/// The page agrees with the kernel's own clocks, and a user address space
+/// has it mapped read-only.
+pub fn self_test() -> bool {
+    use crate::memory::address_space::AddressSpace;
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    let Some(page) = page() else { return false };
+    let ticks = page.ticks.load(Ordering::Acquire);
+    let mut ok = page.version.load(Ordering::Acquire) == VERSION
+        && page.hz.load(Ordering::Relaxed) == super::HZ
+        && page.tsc_per_ms.load(Ordering::Relaxed) != 0
+        && super::ticks().abs_diff(ticks) <= 1;
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let cycles = tsc - page.tsc_start.load(Ordering::Relaxed);
+    let us = (cycles as u128 * 1000 / page.tsc_per_ms.load(Ordering::Relaxed) as u128) as u64;
+    ok &= super::monotonic().as_micros().abs_diff(us as u128) < 1000;
+
+    let Some(mut space) = AddressSpace::new() else { return false };
+    ok && space.translate(VirtAddr::new(TIME_PAGE)).is_some_and(|(phys, flags)| {
+        Some(phys) == frame().map(|f| f.start_address())
+            && flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE)
+            && !flags.contains(PageTableFlags::WRITABLE)
+    })
+}
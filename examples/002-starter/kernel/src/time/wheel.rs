@@ -0,0 +1,178 @@
+use core::task::Waker;
+
+use crate::thread::ThreadId;
+
+/// Slots in the wheel, one per tick; deadlines further out wrap around and
+/// simply stay in their slot until the tick they are due.
+const SLOTS: usize = 256;
+/// Timers that can be pending at once. Fixed, so the timer interrupt never allocates.
+pub const MAX_TIMERS: usize = 128;
+
+const NONE: u16 = u16::MAX;
+
+/// Who to wake when a timer expires.
+pub enum Target {
+    /// A blocked thread; the entry is freed as soon as it fires.
+    Thread(ThreadId),
+    /// An async task; the entry stays (marked fired) until its owner removes it,
+    /// so the waker is never dropped inside the interrupt handler.
+    Task(Waker),
+}
+
+struct Entry {
+    deadline: u64,
+    target: Option<Target>,
+    /// Next entry in the same slot (or in the free list).
+    next: u16,
+    fired: bool,
+}
+
+/// A hashed timing wheel: timer `t` lives in slot `deadline % SLOTS`, so each
+/// tick only looks at the timers of one slot.
+pub struct TimerWheel {
+    entries: [Entry; MAX_TIMERS],
+    slots: [u16; SLOTS],
+    free: u16,
+    /// The next tick `expire` will process.
+    next_tick: u64,
+    pending: usize,
+}
+
+/// Handle to a timer in the wheel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u16);
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        let mut entries = [const { Entry { deadline: 0, target: None, next: NONE, fired: false } }; MAX_TIMERS];
+        let mut i = 0;
+        while i < MAX_TIMERS - 1 {
+            entries[i].next = (i + 1) as u16;
+            i += 1;
+        }
+        TimerWheel { entries, slots: [NONE; SLOTS], free: 0, next_tick: 0, pending: 0 }
+    }
+
+    /// Arm a timer for tick `deadline`; `None` if all entries are in use.
+    /// A deadline already in the past fires on the next tick.
+    pub fn insert(&mut self, deadline: u64, target: Target) -> Option<TimerId> {
+        if self.free == NONE {
+            return None;
+        }
+        let index = self.free;
+        let deadline = deadline.max(self.next_tick);
+        let slot = deadline as usize % SLOTS;
+        let entry = &mut self.entries[index as usize];
+        self.free = entry.next;
+        *entry = Entry { deadline, target: Some(target), next: self.slots[slot], fired: false };
+        self.slots[slot] = index;
+        self.pending += 1;
+        Some(TimerId(index))
+    }
+
+    /// Whether a task timer has gone off.
+    pub fn fired(&self, id: TimerId) -> bool {
+        self.entries[id.0 as usize].fired
+    }
+
+    /// Point a task timer at a new waker (the task may have moved executors).
+    /// Returns the old one for the caller to drop.
+    pub fn set_waker(&mut self, id: TimerId, waker: &Waker) -> Option<Waker> {
+        match &mut self.entries[id.0 as usize].target {
+            Some(Target::Task(old)) if !old.will_wake(waker) => Some(core::mem::replace(old, waker.clone())),
+            _ => None,
+        }
+    }
+
+    /// Disarm a timer (fired or not) and free its entry. Returns its target so the
+    /// caller can drop it where that is safe.
+    pub fn remove(&mut self, id: TimerId) -> Option<Target> {
+        let index = id.0;
+        if !self.entries[index as usize].fired {
+            self.unlink(index);
+        }
+        let entry = &mut self.entries[index as usize];
+        let target = entry.target.take();
+        entry.fired = false;
+        entry.next = self.free;
+        self.free = index;
+        target
+    }
+
+    /// Disarm thread timer `id`, armed for `thread`, unless it has fired:
+    /// its entry is free then, and may already be someone else's.
+    pub fn cancel(&mut self, id: TimerId, thread: ThreadId) -> bool {
+        let index = id.0;
+        match self.entries[index as usize].target {
+            Some(Target::Thread(owner)) if owner == thread => {
+                self.unlink(index);
+                let entry = &mut self.entries[index as usize];
+                entry.target = None;
+                entry.next = self.free;
+                self.free = index;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let slot = self.entries[index as usize].deadline as usize % SLOTS;
+        let next = self.entries[index as usize].next;
+        if self.slots[slot] == index {
+            self.slots[slot] = next;
+            self.pending -= 1;
+            return;
+        }
+        let mut prev = self.slots[slot];
+        while prev != NONE {
+            if self.entries[prev as usize].next == index {
+                self.entries[prev as usize].next = next;
+                self.pending -= 1;
+                return;
+            }
+            prev = self.entries[prev as usize].next;
+        }
+    }
+
+    /// Fire every timer due up to and including tick `now`, calling `wake_thread`
+    /// for thread timers and waking task timers directly.
+    pub fn expire(&mut self, now: u64, mut wake_thread: impl FnMut(ThreadId)) {
+        while self.next_tick <= now {
+            let slot = self.next_tick as usize % SLOTS;
+            let mut link = self.slots[slot];
+            let mut prev = NONE;
+            while link != NONE {
+                let index = link;
+                let next = self.entries[index as usize].next;
+                if self.entries[index as usize].deadline <= self.next_tick {
+                    if prev == NONE { self.slots[slot] = next } else { self.entries[prev as usize].next = next }
+                    self.pending -= 1;
+                    let entry = &mut self.entries[index as usize];
+                    match &entry.target {
+                        Some(Target::Thread(id)) => {
+                            wake_thread(*id);
+                            entry.target = None;
+                            entry.next = self.free;
+                            self.free = index;
+                        }
+                        Some(Target::Task(waker)) => {
+                            waker.wake_by_ref();
+                            entry.fired = true;
+                        }
+                        None => {}
+                    }
+                } else {
+                    prev = index;
+                }
+                link = next;
+            }
+            self.next_tick += 1;
+        }
+    }
+
+    /// Timers armed and not yet fired.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
@@ -0,0 +1,480 @@
+//! Loading ELF64 executables into a user address space.
+//!
+//! Only statically linked, non-PIE x86_64 executables: the PT_LOAD segments
+//! are mapped where they were linked, readable, and writable or executable as
+//! their flags say (never both unless the binary asks). What lies beyond a
+//! segment's file bytes (.bss) is zero. The stack is mapped right below
+//! `STACK_TOP`, with argc, argv, envp and auxv at the stack pointer as the
+//! SysV ABI has them at `_start` (see `push_args`). The heap starts right
+//! after the highest segment, empty.
+//!
+//! Everything is checked before anything is mapped, and a broken binary gets
+//! an `ElfError` saying what is wrong with it rather than a fault later.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::entropy;
+use crate::memory::address_space::{AddressSpace, MMAP_START, USER_END, USER_START};
+use crate::memory::phys_to_virt;
+use crate::serial_println;
+use crate::time::vdso::TIME_PAGE;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// One page below the end of user memory is left unmapped.
+pub const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const STACK_PAGES: u64 = 16;
+const STACK_BOTTOM: u64 = STACK_TOP - STACK_PAGES * PAGE_SIZE;
+/// Room for the arguments at the top of the stack.
+const MAX_ARGS: usize = 2 * PAGE_SIZE as usize;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Auxiliary vector entries: the end, the page size, the program's entry
+/// point, the address of 16 random bytes (for stack canaries and the
+/// like), and the address of the time page (see `time::vdso`; the key is
+/// ours, well clear of Linux's).
+pub const AT_NULL: u64 = 0;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_TIME_PAGE: u64 = 0x1000;
+/// Key-value pairs in the auxiliary vector, AT_NULL included.
+const AUXV_LEN: usize = 5;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The file ends before the `&str` does.
+    Truncated(&'static str),
+    BadMagic,
+    Not64Bit,
+    BigEndian,
+    BadVersion,
+    /// A shared object or position-independent executable.
+    NotExecutable(u16),
+    WrongMachine(u16),
+    BadHeaderSize,
+    /// Needs a dynamic linker.
+    Interpreter,
+    NoSegments,
+    Segment { index: usize, problem: &'static str },
+    BadEntry(u64),
+    ArgsTooLong,
+    OutOfMemory,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated(what) => write!(f, "file too short for the {}", what),
+            ElfError::BadMagic => write!(f, "not an ELF file (bad magic)"),
+            ElfError::Not64Bit => write!(f, "not a 64-bit ELF file"),
+            ElfError::BigEndian => write!(f, "big-endian ELF file"),
+            ElfError::BadVersion => write!(f, "unknown ELF version"),
+            ElfError::NotExecutable(ET_DYN) => write!(f, "shared object or PIE; link with -static -no-pie"),
+            ElfError::NotExecutable(kind) => write!(f, "not an executable (type {})", kind),
+            ElfError::WrongMachine(machine) => write!(f, "built for machine {}, not x86_64", machine),
+            ElfError::BadHeaderSize => write!(f, "unexpected header sizes"),
+            ElfError::Interpreter => write!(f, "dynamically linked; link with -static"),
+            ElfError::NoSegments => write!(f, "no loadable segments"),
+            ElfError::Segment { index, problem } => write!(f, "segment {}: {}", index, problem),
+            ElfError::BadEntry(entry) => write!(f, "entry point {:#x} is not in an executable segment", entry),
+            ElfError::ArgsTooLong => write!(f, "arguments too long"),
+            ElfError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// A PT_LOAD program header.
+#[derive(Clone, Copy)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub memsz: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub flags: u32,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.vaddr + self.memsz
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A checked executable.
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+/// Where a loaded program starts.
+pub struct Image {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    /// End of the highest segment, page aligned: where the heap starts.
+    pub end: VirtAddr,
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Check the headers and every segment of `data`.
+pub fn parse(data: &[u8]) -> Result<Elf, ElfError> {
+    if data.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated("ELF header"));
+    }
+    if data[..4] != *b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    if data[4] != ELFCLASS64 {
+        return Err(ElfError::Not64Bit);
+    }
+    if data[5] != ELFDATA2LSB {
+        return Err(ElfError::BigEndian);
+    }
+    if data[6] != EV_CURRENT || u32_at(data, 20) != EV_CURRENT as u32 {
+        return Err(ElfError::BadVersion);
+    }
+    let kind = u16_at(data, 16);
+    if kind != ET_EXEC {
+        return Err(ElfError::NotExecutable(kind));
+    }
+    let machine = u16_at(data, 18);
+    if machine != EM_X86_64 {
+        return Err(ElfError::WrongMachine(machine));
+    }
+    let entry = u64_at(data, 24);
+    let phoff = u64_at(data, 32);
+    let phnum = u16_at(data, 56) as u64;
+    if u16_at(data, 52) as usize != EHDR_SIZE || (phnum > 0 && u16_at(data, 54) as usize != PHDR_SIZE) {
+        return Err(ElfError::BadHeaderSize);
+    }
+    let table_end = phoff.checked_add(phnum * PHDR_SIZE as u64);
+    if table_end.is_none_or(|end| end > data.len() as u64) {
+        return Err(ElfError::Truncated("program headers"));
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for index in 0..phnum as usize {
+        let at = phoff as usize + index * PHDR_SIZE;
+        match u32_at(data, at) {
+            PT_LOAD => {}
+            PT_INTERP => return Err(ElfError::Interpreter),
+            _ => continue,
+        }
+        let segment = Segment {
+            flags: u32_at(data, at + 4),
+            offset: u64_at(data, at + 8),
+            vaddr: u64_at(data, at + 16),
+            filesz: u64_at(data, at + 32),
+            memsz: u64_at(data, at + 40),
+        };
+        let align = u64_at(data, at + 48);
+        let problem = |problem| ElfError::Segment { index, problem };
+        if segment.memsz == 0 {
+            continue;
+        }
+        if segment.filesz > segment.memsz {
+            return Err(problem("file size larger than memory size"));
+        }
+        if segment.offset.checked_add(segment.filesz).is_none_or(|end| end > data.len() as u64) {
+            return Err(problem("data past the end of the file"));
+        }
+        if segment.vaddr < USER_START || segment.vaddr.checked_add(segment.memsz).is_none_or(|end| end > MMAP_START) {
+            return Err(problem("outside the program area of user memory"));
+        }
+        if align > 1 && (!align.is_power_of_two() || segment.vaddr % align != segment.offset % align) {
+            return Err(problem("address and file offset disagree with the alignment"));
+        }
+        // Pages may be shared (the linker packs segments), bytes may not.
+        if segments.iter().any(|other| segment.vaddr < other.end() && other.vaddr < segment.end()) {
+            return Err(problem("overlaps another segment"));
+        }
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err(ElfError::NoSegments);
+    }
+    let executable = segments.iter().any(|s| s.flags & PF_X != 0 && s.vaddr <= entry && entry < s.end());
+    if !executable {
+        return Err(ElfError::BadEntry(entry));
+    }
+    Ok(Elf { entry, segments })
+}
+
+/// Map the segments of `data` and a stack holding `args` and `env` (each
+/// "KEY=value") into `space`. On error, whatever was mapped already stays
+/// until `space` is dropped.
+pub fn load(data: &[u8], space: &mut AddressSpace, args: &[&str], env: &[&str]) -> Result<Image, ElfError> {
+    let elf = parse(data)?;
+    let strings: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    if strings + 16 + (args.len() + env.len() + 3 + 2 * AUXV_LEN) * 8 + 15 > MAX_ARGS {
+        return Err(ElfError::ArgsTooLong);
+    }
+    for segment in &elf.segments {
+        let flags = segment.page_flags();
+        let first = Page::containing_address(VirtAddr::new(segment.vaddr));
+        let last = Page::containing_address(VirtAddr::new(segment.end() - 1));
+        for page in Page::range_inclusive(first, last) {
+            match space.translate(page.start_address()) {
+                // Shared with the previous segment: allow what either allows.
+                Some((_, old)) => {
+                    let mut merged = old | (flags & PageTableFlags::WRITABLE);
+                    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                        merged.remove(PageTableFlags::NO_EXECUTE);
+                    }
+                    space.update_flags(page, merged).map_err(|_| ElfError::OutOfMemory)?;
+                }
+                None => {
+                    space.map_user(page, flags).map_err(|_| ElfError::OutOfMemory)?;
+                }
+            }
+        }
+        let bytes = &data[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        space.write(VirtAddr::new(segment.vaddr), bytes);
+    }
+
+    let first = Page::containing_address(VirtAddr::new(STACK_BOTTOM));
+    let last = Page::containing_address(VirtAddr::new(STACK_TOP - 1));
+    for page in Page::range_inclusive(first, last) {
+        space.map_user(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).map_err(|_| ElfError::OutOfMemory)?;
+    }
+    let stack_pointer = push_args(space, args, env, elf.entry)?;
+
+    let end = VirtAddr::new(align_up(elf.segments.iter().map(|s| s.end()).max().unwrap_or(USER_START)));
+    space.set_heap_start(end);
+    Ok(Image { entry: VirtAddr::new(elf.entry), stack_pointer, end })
+}
+
+/// Lay out the top of the stack as `_start` expects it, and return the stack
+/// pointer (16-byte aligned, at argc):
+///
+/// ```text
+/// argc | argv[0..argc] | NULL | envp[..] | NULL | auxv: (key, value)..., AT_NULL, 0
+///      | ... | 16 random bytes | the strings
+/// ```
+fn push_args(space: &mut AddressSpace, args: &[&str], env: &[&str], entry: u64) -> Result<VirtAddr, ElfError> {
+    let mut top = STACK_TOP;
+    let mut push_strings = |strings: &[&str]| {
+        let mut pointers = Vec::with_capacity(strings.len() + 1);
+        for string in strings.iter().rev() {
+            top -= string.len() as u64 + 1;
+            space.write(VirtAddr::new(top), string.as_bytes());
+            space.write(VirtAddr::new(top + string.len() as u64), &[0]);
+            pointers.push(top);
+        }
+        pointers.reverse();
+        pointers.push(0);
+        pointers
+    };
+    let env_pointers = push_strings(env);
+    let arg_pointers = push_strings(args);
+    let random = (top - 16) & !7;
+    let bytes = [entropy::random_u64().to_le_bytes(), entropy::random_u64().to_le_bytes()].concat();
+    space.write(VirtAddr::new(random), &bytes);
+
+    let mut words = Vec::with_capacity(args.len() + env.len() + 3 + 2 * AUXV_LEN);
+    words.push(args.len() as u64);
+    words.extend_from_slice(&arg_pointers);
+    words.extend_from_slice(&env_pointers);
+    words.extend_from_slice(&[AT_PAGESZ, PAGE_SIZE, AT_ENTRY, entry, AT_RANDOM, random]);
+    if space.translate(VirtAddr::new(TIME_PAGE)).is_some() {
+        words.extend_from_slice(&[AT_TIME_PAGE, TIME_PAGE]);
+    }
+    words.extend_from_slice(&[AT_NULL, 0]);
+    let stack_pointer = (random - words.len() as u64 * 8) & !15;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    if !space.write(VirtAddr::new(stack_pointer), &bytes) {
+        return Err(ElfError::ArgsTooLong);
+    }
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+fn describe(flags: u32) -> [char; 3] {
+    let bit = |mask, c| if flags & mask != 0 { c } else { '-' };
+    [bit(PF_R, 'r'), bit(PF_W, 'w'), bit(PF_X, 'x')]
+}
+
+/// Print the entry point and segments of `data`, or what is wrong with it.
+pub fn dump(data: &[u8]) {
+    let elf = match parse(data) {
+        Ok(elf) => elf,
+        Err(err) => {
+            serial_println!("elf: {}", err);
+            return;
+        }
+    };
+    serial_println!("elf: entry {:#x}, {} loadable segments", elf.entry, elf.segments.len());
+    serial_println!("  vaddr             memsz     filesz    offset    flags");
+    for s in &elf.segments {
+        let [r, w, x] = describe(s.flags);
+        serial_println!("  {:#016x}  {:#08x}  {:#08x}  {:#08x}  {}{}{}", s.vaddr, s.memsz, s.filesz, s.offset, r, w, x);
+    }
+}
+
+pub const TEXT_BASE: u64 = USER_START + 0x40_0000;
+pub const DATA_BASE: u64 = TEXT_BASE + 0x2000;
+/// Where the code given to `build` starts: right after the headers.
+pub const ENTRY: u64 = TEXT_BASE + (EHDR_SIZE + 2 * PHDR_SIZE) as u64;
+
+/// A static executable as a linker would lay it out, for the kernel's own
+/// test programs: the headers and `code` in an r-x segment at `TEXT_BASE`,
+/// with the code at `ENTRY`, then `data` and `bss` zero bytes in an rw-
+/// segment at `DATA_BASE`.
+pub fn build(code: &[u8], data: &[u8], bss: u64) -> Vec<u8> {
+    let text_size = (ENTRY - TEXT_BASE) + code.len() as u64;
+    let data_offset = 0x1000u64;
+    assert!(text_size <= data_offset, "test program too long");
+    let mut image = Vec::new();
+    image.extend_from_slice(b"\x7fELF");
+    image.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&ET_EXEC.to_le_bytes());
+    image.extend_from_slice(&EM_X86_64.to_le_bytes());
+    image.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+    image.extend_from_slice(&ENTRY.to_le_bytes());
+    image.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+    image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    for half in [EHDR_SIZE, PHDR_SIZE, 2, 0, 0, 0] {
+        image.extend_from_slice(&(half as u16).to_le_bytes());
+    }
+    let mut phdr = |flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64| {
+        image.extend_from_slice(&PT_LOAD.to_le_bytes());
+        image.extend_from_slice(&flags.to_le_bytes());
+        for word in [offset, vaddr, vaddr, filesz, memsz, PAGE_SIZE] {
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+    };
+    phdr(PF_R | PF_X, 0, TEXT_BASE, text_size, text_size);
+    phdr(PF_R | PF_W, data_offset, DATA_BASE, data.len() as u64, data.len() as u64 + bss);
+    image.extend_from_slice(code);
+    image.resize(data_offset as usize, 0);
+    image.extend_from_slice(data);
+    image
+}
+
+const TEST_MAGIC: u64 = 0x5e1f;
+
+/// One word of data and one of .bss. The code adds the data word, the .bss
+/// word (zero, then written and read back) and argc, and exits with the sum.
+pub fn test_image() -> Vec<u8> {
+    let data_word = DATA_BASE;
+    let bss_word = DATA_BASE + 8;
+    let mut code = Vec::new();
+    let rip_relative = |code: &mut Vec<u8>, op: [u8; 3], target: u64| {
+        code.extend_from_slice(&op);
+        let next = ENTRY + code.len() as u64 + 4;
+        code.extend_from_slice(&(target.wrapping_sub(next) as u32).to_le_bytes());
+    };
+    rip_relative(&mut code, [0x48, 0x8b, 0x05], data_word); // mov rax, [data]
+    rip_relative(&mut code, [0x48, 0x03, 0x05], bss_word); // add rax, [bss]
+    rip_relative(&mut code, [0x48, 0x89, 0x05], bss_word); // mov [bss], rax
+    rip_relative(&mut code, [0x48, 0x8b, 0x3d], bss_word); // mov rdi, [bss]
+    code.extend_from_slice(&[0x48, 0x03, 0x3c, 0x24]); // add rdi, [rsp] (argc)
+    code.push(0xb8); // mov eax, EXIT
+    code.extend_from_slice(&(crate::syscall::nr::EXIT as u32).to_le_bytes());
+    code.extend_from_slice(&[0x0f, 0x05]); // syscall
+    code.extend_from_slice(&[0x0f, 0x0b]); // ud2
+    build(&code, &TEST_MAGIC.to_le_bytes(), 8)
+}
+
+/// Read a `T` at `addr` in `space`, which needn't be the active one. `T`
+/// must not cross a page.
+fn peek<T: Copy>(space: &mut AddressSpace, addr: u64) -> Option<T> {
+    let (phys, _) = space.translate(VirtAddr::new(addr))?;
+    Some(unsafe { phys_to_virt(phys).as_ptr::<T>().read_unaligned() })
+}
+
+/// Broken variants of `test_image` must be refused for the right reason; the
+/// good one must map with the right permissions, lay out its arguments,
+/// environment and auxiliary vector on the stack, and run.
+pub fn self_test() -> bool {
+    let good = test_image();
+    let patched = |at: usize, bytes: &[u8]| {
+        let mut image = good.clone();
+        image[at..at + bytes.len()].copy_from_slice(bytes);
+        image
+    };
+    let second_phdr = EHDR_SIZE + PHDR_SIZE;
+    let rejected = matches!(parse(&good[..40]), Err(ElfError::Truncated(_)))
+        && matches!(parse(&patched(0, b"\x7fELG")), Err(ElfError::BadMagic))
+        && matches!(parse(&patched(18, &3u16.to_le_bytes())), Err(ElfError::WrongMachine(3)))
+        && matches!(parse(&patched(16, &ET_DYN.to_le_bytes())), Err(ElfError::NotExecutable(ET_DYN)))
+        && matches!(parse(&patched(56, &100u16.to_le_bytes())), Err(ElfError::Truncated(_)))
+        && matches!(parse(&patched(second_phdr + 32, &0x10_0000u64.to_le_bytes())), Err(ElfError::Segment { index: 1, .. }))
+        && matches!(parse(&patched(second_phdr + 16, &TEXT_BASE.to_le_bytes())), Err(ElfError::Segment { index: 1, .. }))
+        && matches!(parse(&patched(24, &DATA_BASE.to_le_bytes())), Err(ElfError::BadEntry(DATA_BASE)));
+    if !rejected {
+        return false;
+    }
+
+    let Some(mut space) = AddressSpace::new() else { return false };
+    let Ok(image) = load(&good, &mut space, &["elf-test", "x"], &["A=1"]) else { return false };
+    let mut word = |index: u64| peek::<u64>(&mut space, image.stack_pointer.as_u64() + index * 8);
+    // argc, argv[0], argv[1], NULL, envp[0], NULL, then the auxv pairs.
+    let laid_out = word(0) == Some(2)
+        && word(3) == Some(0)
+        && word(5) == Some(0)
+        && (word(6), word(7)) == (Some(AT_PAGESZ), Some(PAGE_SIZE))
+        && (word(8), word(9)) == (Some(AT_ENTRY), Some(ENTRY))
+        && word(10) == Some(AT_RANDOM)
+        && (word(12), word(13)) == (Some(AT_TIME_PAGE), Some(TIME_PAGE))
+        && (word(14), word(15)) == (Some(AT_NULL), Some(0));
+    let env = word(4);
+    let laid_out = laid_out && env.and_then(|env| peek::<[u8; 4]>(&mut space, env)) == Some(*b"A=1\0");
+    let mut flags = |addr: u64| space.translate(VirtAddr::new(addr)).map(|(_, flags)| flags).unwrap_or(PageTableFlags::empty());
+    let text = flags(TEXT_BASE);
+    let data = flags(DATA_BASE);
+    let stack = flags(STACK_TOP - 8);
+    let mapped = !text.contains(PageTableFlags::WRITABLE)
+        && !text.contains(PageTableFlags::NO_EXECUTE)
+        && data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        && stack.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        && flags(STACK_TOP).is_empty();
+    space.switch();
+    let status = unsafe { super::run(image.entry, image.stack_pointer) };
+    crate::memory::address_space::switch_to_kernel();
+    mapped && laid_out && status == TEST_MAGIC as i64 + 2 && image.end == VirtAddr::new(DATA_BASE + PAGE_SIZE)
+}
@@ -0,0 +1,392 @@
+//! Ring 3: code running at the CPU's lowest privilege level, with the user
+//! segments from the GDT. It can only touch pages marked USER_ACCESSIBLE and
+//! can't run privileged instructions; the only way back into the kernel is an
+//! interrupt, an exception or a system call.
+//!
+//! `run` enters user code from a kernel thread and returns when it calls
+//! `exit`. Before the `iretq` it saves the thread's callee-saved registers
+//! and makes the stack right below them the thread's ring-0 stack: where the
+//! CPU switches to on an interrupt from ring 3 (the TSS's RSP0) and where the
+//! `syscall` entry moves to. Both system call entries, `int 0x80` and the
+//! `syscall` instruction, store the user's registers there as a `TrapFrame`,
+//! call `syscall::dispatch` and go back with `iretq`. `leave` (the `exit`
+//! system call) throws all of that away and returns from `run`.
+//!
+//! User code runs with interrupts on, so the timer preempts it like any
+//! kernel thread: the interrupt lands on the thread's ring-0 stack and the
+//! switch happens there. The general-purpose registers are saved by the
+//! interrupt and the switch; CS and SS come back with the interrupt frame
+//! (DS and ES mean nothing in 64-bit mode, and FS and GS bases stay the
+//! kernel's). That leaves the x87/SSE registers, which only user code uses
+//! (the kernel is built without them): `suspend` saves them when a thread
+//! running user code is switched out, `resume` loads them back.
+
+pub mod elf;
+pub mod programs;
+pub mod uaccess;
+
+use alloc::boxed::Box;
+use core::arch::{asm, global_asm};
+use core::cell::Cell;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::memory::address_space::{self, AddressSpace, USER_START};
+use crate::memory::phys_to_virt;
+use crate::smp::{self, MAX_CPUS};
+use crate::syscall;
+
+/// `int 0x80`: the system call gate user code may raise (DPL 3).
+pub const TRAP_VECTOR: u8 = 0x80;
+
+/// RFLAGS a program starts with: interrupts on, and the reserved bit 1.
+pub const INITIAL_RFLAGS: u64 = 0x202;
+
+/// The user's registers at a system call, as the entry stubs push them: the
+/// general-purpose registers, then what the CPU pushes for an interrupt.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// What the `syscall` entry finds through GS: the instruction switches
+/// neither stacks nor segments itself. Field offsets are used by the stub.
+#[repr(C)]
+struct SyscallStack {
+    kernel_rsp: AtomicU64,
+    user_rsp: AtomicU64,
+    user_cs: AtomicU64,
+    user_ss: AtomicU64,
+}
+
+static SYSCALL_STACKS: [SyscallStack; MAX_CPUS] = [const {
+    SyscallStack {
+        kernel_rsp: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+        user_cs: AtomicU64::new(0),
+        user_ss: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+
+/// The running thread's ring-0 stack top while it runs user code; 0 otherwise.
+#[thread_local]
+static RING0_STACK: Cell<u64> = Cell::new(0);
+
+/// The x87 and SSE registers, as `fxsave` stores them.
+#[repr(C, align(16))]
+struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// What a program starts with: everything zero but the x87 control word
+    /// and MXCSR, at their reset values (all exceptions masked).
+    fn initial() -> FpuState {
+        let mut area = [0; 512];
+        area[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        area[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        FpuState(area)
+    }
+}
+
+/// Where the running thread's user FPU registers are kept while it is
+/// switched out; null when it runs no user code.
+#[thread_local]
+static FPU: Cell<*mut FpuState> = Cell::new(ptr::null_mut());
+
+// user_enter(rip, rsp, cs, ss): the seventh push leaves RSP 16-byte aligned,
+// so the CPU's interrupt frame lands right below it. Registers are cleared
+// so no kernel values leak into ring 3.
+//
+// user_leave(rsp, status): back to the caller of user_enter.
+//
+// The entries build the same TrapFrame: `syscall` leaves the user's RIP in
+// RCX and RFLAGS in R11, and GS is swapped in only to find the stack.
+global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    pushfq",
+    "    cli",
+    "    mov r12, rdi",
+    "    mov r13, rsi",
+    "    mov r14, rdx",
+    "    mov r15, rcx",
+    "    mov rdi, rsp",
+    "    call {entered}",
+    "    push r15",
+    "    push r13",
+    "    push {rflags}",
+    "    push r14",
+    "    push r12",
+    "    xor eax, eax",
+    "    xor ebx, ebx",
+    "    xor ecx, ecx",
+    "    xor edx, edx",
+    "    xor esi, esi",
+    "    xor edi, edi",
+    "    xor ebp, ebp",
+    "    xor r8d, r8d",
+    "    xor r9d, r9d",
+    "    xor r10d, r10d",
+    "    xor r11d, r11d",
+    "    xor r12d, r12d",
+    "    xor r13d, r13d",
+    "    xor r14d, r14d",
+    "    xor r15d, r15d",
+    "    iretq",
+    "",
+    ".global user_leave",
+    "user_leave:",
+    "    mov rsp, rdi",
+    "    mov rax, rsi",
+    "    popfq",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+    "",
+    ".global user_syscall_entry",
+    "user_syscall_entry:",
+    "    swapgs",
+    "    mov gs:[8], rsp",
+    "    mov rsp, gs:[0]",
+    "    push qword ptr gs:[24]",
+    "    push qword ptr gs:[8]",
+    "    push r11",
+    "    push qword ptr gs:[16]",
+    "    push rcx",
+    "    swapgs",
+    "    jmp 1f",
+    "",
+    ".global user_int_entry",
+    "user_int_entry:",
+    "1:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    call {dispatch}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    iretq",
+    rflags = const INITIAL_RFLAGS,
+    entered = sym entered,
+    dispatch = sym syscall::dispatch,
+);
+
+extern "C" {
+    fn user_enter(rip: u64, rsp: u64, cs: u64, ss: u64) -> u64;
+    fn user_leave(rsp: u64, status: u64) -> !;
+    fn user_syscall_entry();
+    fn user_int_entry();
+}
+
+/// The handler for `TRAP_VECTOR`, for the IDT.
+pub fn trap_handler() -> VirtAddr {
+    VirtAddr::from_ptr(user_int_entry as *const ())
+}
+
+/// Enable the `syscall` instruction on the calling CPU. Needs `gdt::init`
+/// (or `gdt::load` on an AP).
+pub fn init_cpu() {
+    let (user_code, user_data) = gdt::user_selectors();
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let stacks = &SYSCALL_STACKS[smp::current()];
+    stacks.user_cs.store(user_code.0 as u64, Ordering::Relaxed);
+    stacks.user_ss.store(user_data.0 as u64, Ordering::Relaxed);
+    Star::write(user_code, user_data, kernel_code, kernel_data).expect("GDT layout doesn't suit syscall");
+    LStar::write(VirtAddr::from_ptr(user_syscall_entry as *const ()));
+    // Entered with interrupts off, like an interrupt gate.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    KernelGsBase::write(VirtAddr::from_ptr(stacks));
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    // x87 and SSE for user code: no emulation, `fxsave`/`fxrstor` and SSE
+    // exceptions on.
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// Point this CPU's ring-0 entries at `top`.
+fn load_stacks(top: u64) {
+    gdt::set_kernel_stack(top);
+    SYSCALL_STACKS[smp::current()].kernel_rsp.store(top, Ordering::Relaxed);
+}
+
+/// Called by `user_enter` with interrupts off.
+extern "C" fn entered(top: u64) {
+    RING0_STACK.set(top);
+    load_stacks(top);
+}
+
+unsafe fn fxsave(state: *mut FpuState) {
+    asm!("fxsave64 [{}]", in(reg) state, options(nostack));
+}
+
+unsafe fn fxrstor(state: *const FpuState) {
+    asm!("fxrstor64 [{}]", in(reg) state, options(nostack, readonly));
+}
+
+/// Before a switch, on the thread switched from (interrupts are off): if it
+/// is in the middle of running user code, keep its FPU registers.
+pub fn suspend() {
+    let state = FPU.get();
+    if !state.is_null() {
+        unsafe { fxsave(state) };
+    }
+}
+
+/// After a switch, on the thread switched to (interrupts are off): if it is
+/// in the middle of running user code, its entries must land on its stack
+/// and its FPU registers come back.
+pub fn resume() {
+    let top = RING0_STACK.get();
+    if top != 0 {
+        load_stacks(top);
+    }
+    let state = FPU.get();
+    if !state.is_null() {
+        unsafe { fxrstor(state) };
+    }
+}
+
+/// Start the calling thread's user code with fresh FPU registers in `state`.
+fn start_fpu(state: *mut FpuState) {
+    interrupts::without_interrupts(|| {
+        unsafe { state.write(FpuState::initial()) };
+        FPU.set(state);
+        unsafe { fxrstor(state) };
+    });
+}
+
+/// Give the user code the current thread runs (about to become a new
+/// program) fresh FPU registers.
+pub fn reset_fpu() {
+    let state = FPU.get();
+    assert!(!state.is_null(), "user::reset_fpu outside user code");
+    start_fpu(state);
+}
+
+/// Run user code at `rip` on the stack `rsp` until it calls `exit`, and
+/// return the exit status. It starts with cleared FPU registers, and may be
+/// preempted.
+///
+/// # Safety
+/// The address space holding `rip` and `rsp` must be active.
+pub unsafe fn run(rip: VirtAddr, rsp: VirtAddr) -> i64 {
+    let (cs, ss) = gdt::user_selectors();
+    let mut fpu = Box::new(FpuState::initial());
+    start_fpu(&mut *fpu);
+    let status = user_enter(rip.as_u64(), rsp.as_u64(), cs.0 as u64, ss.0 as u64) as i64;
+    interrupts::without_interrupts(|| FPU.set(ptr::null_mut()));
+    status
+}
+
+/// End the user code the current thread runs: return `status` from `run`.
+/// Nothing on the stack below `run` is dropped, so the caller must hold no
+/// locks or guards.
+pub fn leave(status: i64) -> ! {
+    interrupts::disable();
+    let top = RING0_STACK.replace(0);
+    assert!(top != 0, "user::leave outside user code");
+    unsafe { user_leave(top, status as u64) }
+}
+
+/// Map `code` and a stack into a fresh address space and run it in ring 3;
+/// returns the exit status, or `None` if out of memory.
+pub fn run_code(code: &[u8]) -> Option<i64> {
+    assert!(code.len() <= 4096, "one page of code at most");
+    let mut space = AddressSpace::new()?;
+    let text = Page::containing_address(VirtAddr::new(USER_START));
+    let stack = Page::containing_address(VirtAddr::new(USER_START + 0x10_0000));
+    // Read-only and executable; the stack is the opposite.
+    let frame = space.map_user(text, PageTableFlags::empty()).ok()?;
+    space.map_user(stack, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).ok()?;
+    unsafe {
+        let dst = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        dst.copy_from_nonoverlapping(code.as_ptr(), code.len());
+    }
+    space.switch();
+    let status = unsafe { run(text.start_address(), stack.start_address() + 4096u64) };
+    address_space::switch_to_kernel();
+    Some(status)
+}
+
+/// The demo program: read CS (its low bits are the privilege level), push
+/// and pop it to show the user stack works, and exit with it as the status.
+const DEMO: &[u8] = &[
+    0x8c, 0xcf, // mov edi, cs
+    0x57, // push rdi
+    0x5f, // pop rdi
+    0xb8, syscall::nr::EXIT as u8, 0, 0, 0, // mov eax, EXIT
+    0xcd, TRAP_VECTOR, // int 0x80
+    0x0f, 0x0b, // ud2: never reached
+];
+
+/// Run `DEMO` in ring 3 and return the CS it saw.
+pub fn demo() -> Option<u64> {
+    run_code(DEMO).map(|status| status as u64)
+}
@@ -0,0 +1,649 @@
+//! Small programs the kernel assembles itself, by hand, and installs in the
+//! initrd under /bin: something to run before there are real user programs.
+
+use alloc::vec::Vec;
+
+use super::elf;
+use crate::initrd;
+use crate::ipc::mqueue::MAX_MESSAGE;
+use crate::process::signal::{SIGFPE, SIGILL, SIGSEGV, SIGTERM, SIGUSR1};
+use crate::syscall::nr;
+
+const SYSCALL: [u8; 2] = [0x0f, 0x05];
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+/// `mov eax, number`
+fn load_number(code: &mut Vec<u8>, number: usize) {
+    code.push(0xb8);
+    code.extend_from_slice(&(number as u32).to_le_bytes());
+}
+
+/// `mov eax, EXIT; syscall; ud2`, with the status already in RDI.
+fn exit(code: &mut Vec<u8>) {
+    load_number(code, nr::EXIT);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&UD2);
+}
+
+/// write(1, "hello from user space\n"); exit(0).
+fn hello() -> Vec<u8> {
+    const MESSAGE: &[u8] = b"hello from user space\n";
+    let mut code = Vec::new();
+    load_number(&mut code, nr::WRITE);
+    code.extend_from_slice(&[0xbf, 1, 0, 0, 0]); // mov edi, 1
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, message
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.push(0xba); // mov edx, len
+    code.extend_from_slice(&(MESSAGE.len() as u32).to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    exit(&mut code);
+    elf::build(&code, MESSAGE, 0)
+}
+
+/// exit(getpid()).
+fn getpid() -> Vec<u8> {
+    let mut code = Vec::new();
+    load_number(&mut code, nr::GETPID);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// exit(argc).
+fn argc() -> Vec<u8> {
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0x48, 0x8b, 0x3c, 0x24]); // mov rdi, [rsp]
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// Copy standard input to standard output until end of file: exit(0), or
+/// exit with the error.
+fn cat() -> Vec<u8> {
+    const BUFFER: u32 = 256;
+    let mut code = Vec::new();
+    let top = code.len();
+    load_number(&mut code, nr::READ);
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.push(0xba); // mov edx, BUFFER
+    code.extend_from_slice(&BUFFER.to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    code.extend_from_slice(&[0x7e, 0]); // jle done
+    let jle_end = code.len();
+    code.extend_from_slice(&[0x48, 0x89, 0xc2]); // mov rdx, rax
+    load_number(&mut code, nr::WRITE);
+    code.extend_from_slice(&[0xbf, 1, 0, 0, 0]); // mov edi, 1
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0xeb, (top as isize - (code.len() + 2) as isize) as u8]); // jmp top
+    code[jle_end - 1] = (code.len() - jle_end) as u8;
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // done: mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &[], BUFFER as u64)
+}
+
+/// `dec ecx; jnz` from `count` down: keeps the CPU busy in ring 3.
+fn busy_loop(code: &mut Vec<u8>, count: u32) {
+    code.push(0xb9); // mov ecx, count
+    code.extend_from_slice(&count.to_le_bytes());
+    code.extend_from_slice(&[0xff, 0xc9, 0x75, 0xfc]); // dec ecx; jnz (back to dec)
+}
+
+/// Print the first letter of argv[1] twenty times, busy in between; exit(0).
+fn ticker() -> Vec<u8> {
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0x48, 0x8b, 0x5c, 0x24, 0x10]); // mov rbx, [rsp + 16]: argv[1]
+    code.extend_from_slice(&[0x41, 0xbc, 20, 0, 0, 0]); // mov r12d, 20
+    let top = code.len();
+    load_number(&mut code, nr::WRITE);
+    code.extend_from_slice(&[0xbf, 1, 0, 0, 0]); // mov edi, 1
+    code.extend_from_slice(&[0x48, 0x89, 0xde]); // mov rsi, rbx
+    code.extend_from_slice(&[0xba, 1, 0, 0, 0]); // mov edx, 1
+    code.extend_from_slice(&SYSCALL);
+    busy_loop(&mut code, 0x200_0000);
+    code.extend_from_slice(&[0x41, 0xff, 0xcc]); // dec r12d
+    code.extend_from_slice(&[0x75, (top as isize - (code.len() + 2) as isize) as u8]); // jnz top
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// Put `value` in XMM0, stay busy long enough to be preempted, and exit(1)
+/// if XMM0 still holds it, exit(0) if not.
+pub fn fpu_test(value: u64) -> Vec<u8> {
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0x48, 0xb8]); // mov rax, value
+    code.extend_from_slice(&value.to_le_bytes());
+    code.extend_from_slice(&[0x66, 0x48, 0x0f, 0x6e, 0xc0]); // movq xmm0, rax
+    busy_loop(&mut code, 0x400_0000);
+    code.extend_from_slice(&[0x66, 0x48, 0x0f, 0x7e, 0xc3]); // movq rbx, xmm0
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    code.extend_from_slice(&[0x48, 0x39, 0xc3]); // cmp rbx, rax
+    code.extend_from_slice(&[0x40, 0x0f, 0x94, 0xc7]); // sete dil
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// `path` and `args` laid out for spawn and exec, to go at `DATA_BASE`: the
+/// path, the argument strings, then an array of (pointer, length) pairs.
+/// Returns the data and the address of the array.
+fn command_line(path: &str, args: &[&str]) -> (Vec<u8>, u64) {
+    let mut data = Vec::new();
+    data.extend_from_slice(path.as_bytes());
+    let mut strings = Vec::new();
+    for arg in args {
+        strings.push((elf::DATA_BASE + data.len() as u64, arg.len() as u64));
+        data.extend_from_slice(arg.as_bytes());
+    }
+    data.resize(data.len().next_multiple_of(8), 0);
+    let argv = elf::DATA_BASE + data.len() as u64;
+    for (ptr, len) in strings {
+        data.extend_from_slice(&ptr.to_le_bytes());
+        data.extend_from_slice(&len.to_le_bytes());
+    }
+    (data, argv)
+}
+
+/// The system call `number` (spawn or exec) with the `command_line` of
+/// `path` and `args`, whose array is at `argv`, and no environment.
+fn run_command(code: &mut Vec<u8>, number: usize, path: &str, args: &[&str], argv: u64) {
+    load_number(code, number);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, path
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.push(0xbe); // mov esi, path length
+    code.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    code.extend_from_slice(&[0x48, 0xba]); // mov rdx, argv
+    code.extend_from_slice(&argv.to_le_bytes());
+    code.extend_from_slice(&[0x41, 0xba]); // mov r10d, argc
+    code.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    code.extend_from_slice(&[0x45, 0x31, 0xc0]); // xor r8d, r8d
+    code.extend_from_slice(&[0x45, 0x31, 0xc9]); // xor r9d, r9d
+    code.extend_from_slice(&SYSCALL);
+}
+
+/// exec(path, args), and exit with the error if it returns.
+pub fn exec(path: &str, args: &[&str]) -> Vec<u8> {
+    let (data, argv) = command_line(path, args);
+    let mut code = Vec::new();
+    run_command(&mut code, nr::EXEC, path, args, argv);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &data, 0)
+}
+
+/// Spawn `/bin/argc a b` and wait for it by PID, then wait for any child
+/// (there is none left). Exits with its status (3) + the PID wait returned
+/// - the PID spawn did (0) - the second wait's result (-ECHILD): 13.
+pub fn wait_test() -> Vec<u8> {
+    const ARGS: [&str; 3] = ["argc", "a", "b"];
+    let (mut data, argv) = command_line("/bin/argc", &ARGS);
+    let status = elf::DATA_BASE + data.len() as u64;
+    data.extend_from_slice(&[0; 8]);
+    let mut code = Vec::new();
+    run_command(&mut code, nr::SPAWN, "/bin/argc", &ARGS, argv);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    load_number(&mut code, nr::WAIT);
+    code.extend_from_slice(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, status
+    code.extend_from_slice(&status.to_le_bytes());
+    code.extend_from_slice(&[0x31, 0xd2]); // xor edx, edx
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x29, 0xd8]); // sub rax, rbx
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    load_number(&mut code, nr::WAIT);
+    code.extend_from_slice(&[0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff]); // mov rdi, -1
+    code.extend_from_slice(&[0x31, 0xf6]); // xor esi, esi
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, status
+    code.extend_from_slice(&status.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0x63, 0x3e]); // movsxd rdi, dword [rsi]
+    code.extend_from_slice(&[0x4c, 0x01, 0xe7]); // add rdi, r12
+    code.extend_from_slice(&[0x48, 0x29, 0xc7]); // sub rdi, rax
+    exit(&mut code);
+    elf::build(&code, &data, 0)
+}
+
+/// Spawn `/bin/getpid` and exit with its PID without waiting for it.
+pub fn orphan_test() -> Vec<u8> {
+    const ARGS: [&str; 1] = ["getpid"];
+    let (data, argv) = command_line("/bin/getpid", &ARGS);
+    let mut code = Vec::new();
+    run_command(&mut code, nr::SPAWN, "/bin/getpid", &ARGS, argv);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &data, 0)
+}
+
+/// Grow the heap by two pages and touch both, map four pages and touch the
+/// last, unmap them, and exit with (heap growth + 7 + 35 + 100 + munmap's
+/// result): 8334 if it all worked.
+pub fn memory_test() -> Vec<u8> {
+    let mut code = Vec::new();
+    load_number(&mut code, nr::SBRK);
+    code.extend_from_slice(&[0xbf, 0x00, 0x20, 0, 0]); // mov edi, 8192
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    code.extend_from_slice(&[0x48, 0xc7, 0x03, 7, 0, 0, 0]); // mov qword [rbx], 7
+    code.extend_from_slice(&[0x48, 0xc7, 0x83, 0x00, 0x10, 0, 0, 35, 0, 0, 0]); // mov qword [rbx + 4096], 35
+    load_number(&mut code, nr::SBRK);
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x29, 0xd8]); // sub rax, rbx
+    code.extend_from_slice(&[0x48, 0x89, 0xc5]); // mov rbp, rax
+    load_number(&mut code, nr::MMAP);
+    code.extend_from_slice(&[0xbf, 0x00, 0x40, 0, 0]); // mov edi, 4 pages
+    code.extend_from_slice(&[0xbe, 3, 0, 0, 0]); // mov esi, PROT_READ | PROT_WRITE
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    code.extend_from_slice(&[0x49, 0xc7, 0x84, 0x24, 0x00, 0x30, 0, 0, 100, 0, 0, 0]); // mov qword [r12 + 0x3000], 100
+    code.extend_from_slice(&[0x49, 0x8b, 0x84, 0x24, 0x00, 0x30, 0, 0]); // mov rax, [r12 + 0x3000]
+    code.extend_from_slice(&[0x48, 0x03, 0x03]); // add rax, [rbx]
+    code.extend_from_slice(&[0x48, 0x03, 0x83, 0x00, 0x10, 0, 0]); // add rax, [rbx + 4096]
+    code.extend_from_slice(&[0x48, 0x01, 0xc5]); // add rbp, rax
+    load_number(&mut code, nr::MUNMAP);
+    code.extend_from_slice(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+    code.extend_from_slice(&[0xbe, 0x00, 0x40, 0, 0]); // mov esi, 4 pages
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x01, 0xc5]); // add rbp, rax
+    code.extend_from_slice(&[0x48, 0x89, 0xef]); // mov rdi, rbp
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// Message-queue keys of the ping-pong pair: ping sends on the first,
+/// pong answers on the second.
+pub const PING_KEY: u64 = 0x7069_6e67;
+pub const PONG_KEY: u64 = 0x706f_6e67;
+/// How long the programs here wait on a message queue, in milliseconds.
+const MQ_TIMEOUT: u32 = 1000;
+
+/// mq_open(key, 1), the ID left in RAX.
+fn mq_open(code: &mut Vec<u8>, key: u64) {
+    load_number(code, nr::MQ_OPEN);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, key
+    code.extend_from_slice(&key.to_le_bytes());
+    code.extend_from_slice(&[0xbe, 1, 0, 0, 0]); // mov esi, 1
+    code.extend_from_slice(&SYSCALL);
+}
+
+/// The system call `number` (mq_send or mq_recv) on the queue in `qid`
+/// (the ModRM byte of `mov rdi, qid`) with the buffer already in RSI.
+fn mq_call(code: &mut Vec<u8>, number: usize, qid: [u8; 3]) {
+    load_number(code, number);
+    code.extend_from_slice(&qid);
+    code.extend_from_slice(&[0x41, 0xba]); // mov r10d, timeout
+    code.extend_from_slice(&MQ_TIMEOUT.to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+}
+
+/// `mov rdi, rbx` and `mov rdi, r12`.
+const RDI_FROM_RBX: [u8; 3] = [0x48, 0x89, 0xdf];
+const RDI_FROM_R12: [u8; 3] = [0x4c, 0x89, 0xe7];
+
+/// Send the round number to pong and wait for it to come back, `rounds`
+/// times, then send an empty message to stop pong. Exits with 0, or with
+/// what the failing call returned (8 for a reply that isn't the round sent).
+pub fn ping(rounds: u32) -> Vec<u8> {
+    assert!(rounds > 0);
+    let mut code = Vec::new();
+    mq_open(&mut code, PING_KEY);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    mq_open(&mut code, PONG_KEY);
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    code.extend_from_slice(&[0x41, 0xbd]); // mov r13d, rounds
+    code.extend_from_slice(&rounds.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    let top = code.len();
+    let mut to_fail = Vec::new();
+    code.extend_from_slice(&[0x4c, 0x89, 0x2e]); // mov [rsi], r13
+    code.extend_from_slice(&[0xba, 8, 0, 0, 0]); // mov edx, 8
+    mq_call(&mut code, nr::MQ_SEND, RDI_FROM_RBX);
+    code.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    code.extend_from_slice(&[0x75, 0]); // jnz fail
+    to_fail.push(code.len());
+    code.push(0xba); // mov edx, MAX_MESSAGE
+    code.extend_from_slice(&(MAX_MESSAGE as u32).to_le_bytes());
+    mq_call(&mut code, nr::MQ_RECV, RDI_FROM_R12);
+    code.extend_from_slice(&[0x48, 0x83, 0xf8, 8]); // cmp rax, 8
+    code.extend_from_slice(&[0x75, 0]); // jne fail
+    to_fail.push(code.len());
+    code.extend_from_slice(&[0x4c, 0x39, 0x2e]); // cmp [rsi], r13
+    code.extend_from_slice(&[0x75, 0]); // jne fail
+    to_fail.push(code.len());
+    code.extend_from_slice(&[0x41, 0xff, 0xcd]); // dec r13d
+    code.extend_from_slice(&[0x75, (top as isize - (code.len() + 2) as isize) as u8]); // jnz top
+    code.extend_from_slice(&[0x31, 0xd2]); // xor edx, edx
+    mq_call(&mut code, nr::MQ_SEND, RDI_FROM_RBX);
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    exit(&mut code);
+    for end in to_fail {
+        code[end - 1] = (code.len() - end) as u8;
+    }
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // fail: mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &[], MAX_MESSAGE as u64)
+}
+
+/// Send every message from ping back to it until an empty one; exit(0),
+/// or with the error (ETIMEDOUT if ping went quiet).
+fn pong() -> Vec<u8> {
+    let mut code = Vec::new();
+    mq_open(&mut code, PING_KEY);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    mq_open(&mut code, PONG_KEY);
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    let top = code.len();
+    code.push(0xba); // mov edx, MAX_MESSAGE
+    code.extend_from_slice(&(MAX_MESSAGE as u32).to_le_bytes());
+    mq_call(&mut code, nr::MQ_RECV, RDI_FROM_RBX);
+    code.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    code.extend_from_slice(&[0x7e, 0]); // jle done
+    let jle_end = code.len();
+    code.extend_from_slice(&[0x48, 0x89, 0xc2]); // mov rdx, rax
+    mq_call(&mut code, nr::MQ_SEND, RDI_FROM_R12);
+    code.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    code.extend_from_slice(&[0x74, (top as isize - (code.len() + 2) as isize) as u8]); // jz top
+    code[jle_end - 1] = (code.len() - jle_end) as u8;
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // done: mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &[], MAX_MESSAGE as u64)
+}
+
+/// The shared memory segment and the message queue of the writer-reader pair.
+pub const SHM_KEY: u64 = 0x0073_686d;
+pub const SHM_QUEUE_KEY: u64 = 0x7368_6d71;
+/// What the writer leaves in the segment, at `SHM_OFFSET`.
+pub const SHM_VALUE: u32 = 4242;
+const SHM_OFFSET: u32 = 0x800;
+
+/// shm_create(SHM_KEY, 4096) then shm_map(that, prot); the address in RBX,
+/// or exit with the error.
+fn shm_attach(code: &mut Vec<u8>, prot: u32) {
+    load_number(code, nr::SHM_CREATE);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, SHM_KEY
+    code.extend_from_slice(&SHM_KEY.to_le_bytes());
+    code.extend_from_slice(&[0xbe, 0x00, 0x10, 0, 0]); // mov esi, 4096
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    load_number(code, nr::SHM_MAP);
+    code.push(0xbe); // mov esi, prot
+    code.extend_from_slice(&prot.to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    code.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    code.extend_from_slice(&[0x79, 0]); // jns mapped
+    let jns_end = code.len();
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(code);
+    code[jns_end - 1] = (code.len() - jns_end) as u8;
+}
+
+/// Map the segment writable, put `SHM_VALUE` at `SHM_OFFSET` in it and send
+/// the offset to the reader; exit with what the send returned.
+pub fn shm_writer() -> Vec<u8> {
+    let mut code = Vec::new();
+    shm_attach(&mut code, 3);
+    code.extend_from_slice(&[0x48, 0xc7, 0x83]); // mov qword [rbx + SHM_OFFSET], SHM_VALUE
+    code.extend_from_slice(&SHM_OFFSET.to_le_bytes());
+    code.extend_from_slice(&SHM_VALUE.to_le_bytes());
+    mq_open(&mut code, SHM_QUEUE_KEY);
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0xc7, 0x06]); // mov qword [rsi], SHM_OFFSET
+    code.extend_from_slice(&SHM_OFFSET.to_le_bytes());
+    code.extend_from_slice(&[0xba, 8, 0, 0, 0]); // mov edx, 8
+    mq_call(&mut code, nr::MQ_SEND, RDI_FROM_R12);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &[], 8)
+}
+
+/// Map the segment read-only, wait for an offset from the writer and exit
+/// with the quadword there (0 if no offset came).
+pub fn shm_reader() -> Vec<u8> {
+    let mut code = Vec::new();
+    shm_attach(&mut code, 1);
+    mq_open(&mut code, SHM_QUEUE_KEY);
+    code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&[0xba, 8, 0, 0, 0]); // mov edx, 8
+    mq_call(&mut code, nr::MQ_RECV, RDI_FROM_R12);
+    code.extend_from_slice(&[0x48, 0x8b, 0x06]); // mov rax, [rsi]
+    code.extend_from_slice(&[0x48, 0x8b, 0x3c, 0x03]); // mov rdi, [rbx + rax]
+    exit(&mut code);
+    elf::build(&code, &[], 8)
+}
+
+/// `mov reg, imm64` with the immediate to be patched to a code address:
+/// returns where the immediate is.
+fn code_address(code: &mut Vec<u8>, opcode: [u8; 2]) -> usize {
+    code.extend_from_slice(&opcode);
+    code.extend_from_slice(&[0; 8]);
+    code.len() - 8
+}
+
+/// Point the immediate at `at` (from `code_address`) to `target`, an offset in `code`.
+fn patch_address(code: &mut [u8], at: usize, target: usize) {
+    code[at..at + 8].copy_from_slice(&(elf::ENTRY + target as u64).to_le_bytes());
+}
+
+/// sigaction(signal, handler, restorer); the handler and restorer are
+/// patched in later: returns where their immediates are.
+fn sigaction(code: &mut Vec<u8>, signal: u32) -> (usize, usize) {
+    load_number(code, nr::SIGACTION);
+    code.push(0xbf); // mov edi, signal
+    code.extend_from_slice(&signal.to_le_bytes());
+    let handler = code_address(code, [0x48, 0xbe]); // mov rsi, handler
+    let restorer = code_address(code, [0x48, 0xba]); // mov rdx, restorer
+    code.extend_from_slice(&SYSCALL);
+    (handler, restorer)
+}
+
+/// kill(getpid(), signal).
+fn kill_self(code: &mut Vec<u8>, signal: u32) {
+    load_number(code, nr::GETPID);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    load_number(code, nr::KILL);
+    code.push(0xbe); // mov esi, signal
+    code.extend_from_slice(&signal.to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+}
+
+/// Ignore SIGTERM and send it to itself, then handle SIGUSR1 and send that:
+/// the handler stores the signal number and clears R12, which `sigreturn`
+/// puts back. Exits with the number stored + R12 (5) + kill's result (0):
+/// 15, or 143 if SIGTERM killed it.
+pub fn signal_test() -> Vec<u8> {
+    let mut code = Vec::new();
+    load_number(&mut code, nr::SIGACTION);
+    code.push(0xbf); // mov edi, SIGTERM
+    code.extend_from_slice(&SIGTERM.to_le_bytes());
+    code.extend_from_slice(&[0xbe, 1, 0, 0, 0]); // mov esi, SIG_IGN
+    code.extend_from_slice(&[0x31, 0xd2]); // xor edx, edx
+    code.extend_from_slice(&SYSCALL);
+    kill_self(&mut code, SIGTERM);
+    let (handler, restorer) = sigaction(&mut code, SIGUSR1);
+    code.extend_from_slice(&[0x41, 0xbc, 5, 0, 0, 0]); // mov r12d, 5
+    kill_self(&mut code, SIGUSR1);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, data
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0x8b, 0x3f]); // mov rdi, [rdi]
+    code.extend_from_slice(&[0x4c, 0x01, 0xe7]); // add rdi, r12
+    code.extend_from_slice(&[0x48, 0x01, 0xc7]); // add rdi, rax
+    exit(&mut code);
+    let handler_at = code.len();
+    code.extend_from_slice(&[0x48, 0xb8]); // mov rax, data
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0x89, 0x38]); // mov [rax], rdi
+    code.extend_from_slice(&[0x45, 0x31, 0xe4]); // xor r12d, r12d
+    code.push(0xc3); // ret
+    let restorer_at = code.len();
+    load_number(&mut code, nr::SIGRETURN);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&UD2);
+    patch_address(&mut code, handler, handler_at);
+    patch_address(&mut code, restorer, restorer_at);
+    elf::build(&code, &[], 8)
+}
+
+/// Write to address 0. With `catch`, a SIGSEGV handler exits with the
+/// signal number + 100 (111); without, SIGSEGV kills it (139).
+pub fn segv_test(catch: bool) -> Vec<u8> {
+    let mut code = Vec::new();
+    let patches = catch.then(|| sigaction(&mut code, SIGSEGV));
+    code.extend_from_slice(&[0x31, 0xc0]); // xor eax, eax
+    code.extend_from_slice(&[0x48, 0x89, 0x00]); // mov [rax], rax
+    code.extend_from_slice(&UD2);
+    let handler_at = code.len();
+    code.extend_from_slice(&[0x83, 0xc7, 100]); // add edi, 100
+    exit(&mut code);
+    if let Some((handler, restorer)) = patches {
+        // The handler never returns: any restorer will do.
+        patch_address(&mut code, handler, handler_at);
+        patch_address(&mut code, restorer, handler_at);
+    }
+    elf::build(&code, &[], 0)
+}
+
+/// Loop forever without a system call, until killed.
+pub fn spin() -> Vec<u8> {
+    elf::build(&[0xeb, 0xfe], &[], 0) // jmp $
+}
+
+/// Filter its system calls down to getpid (and exit), call getpid, then
+/// uptime, which gets it killed with SIGSYS; were it not, it would exit
+/// with its PID.
+pub fn filter_test() -> Vec<u8> {
+    let mut code = Vec::new();
+    load_number(&mut code, nr::SECCOMP);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, 1 << GETPID
+    code.extend_from_slice(&(1u64 << nr::GETPID).to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    load_number(&mut code, nr::GETPID);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    load_number(&mut code, nr::UPTIME);
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// nanosleep for `ms` milliseconds, with the time left stored after the
+/// request; exit with its result (0, or -EINTR if a signal cut it short).
+pub fn sleep_test(ms: u64) -> Vec<u8> {
+    let mut request = Vec::new();
+    request.extend_from_slice(&(ms / 1000).to_le_bytes());
+    request.extend_from_slice(&(ms % 1000 * 1_000_000).to_le_bytes());
+    let mut code = Vec::new();
+    load_number(&mut code, nr::NANOSLEEP);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, request
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, request + 16
+    code.extend_from_slice(&(elf::DATA_BASE + 16).to_le_bytes());
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc7]); // mov rdi, rax
+    exit(&mut code);
+    elf::build(&code, &request, 16)
+}
+
+/// Open `path` and dup2 it to 9, seek 9 to offset 1 and read a byte
+/// through the first descriptor, close that and read from it again, then
+/// seek 9 to the end. Exits with the byte + (the file's size << 8) + (the
+/// second read's result << 24).
+pub fn file_test(path: &str) -> Vec<u8> {
+    let buffer = elf::DATA_BASE + path.len() as u64;
+    let mut code = Vec::new();
+    load_number(&mut code, nr::OPEN);
+    code.extend_from_slice(&[0x48, 0xbf]); // mov rdi, path
+    code.extend_from_slice(&elf::DATA_BASE.to_le_bytes());
+    code.push(0xbe); // mov esi, path length
+    code.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    code.extend_from_slice(&[0x31, 0xd2]); // xor edx, edx: O_RDONLY
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+    load_number(&mut code, nr::DUP2);
+    code.extend_from_slice(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+    code.extend_from_slice(&[0xbe, 9, 0, 0, 0]); // mov esi, 9
+    code.extend_from_slice(&SYSCALL);
+    load_number(&mut code, nr::LSEEK);
+    code.extend_from_slice(&[0xbf, 9, 0, 0, 0]); // mov edi, 9
+    code.extend_from_slice(&[0xbe, 1, 0, 0, 0]); // mov esi, 1
+    code.extend_from_slice(&[0x31, 0xd2]); // xor edx, edx: SEEK_SET
+    code.extend_from_slice(&SYSCALL);
+    for _ in 0..2 {
+        load_number(&mut code, nr::READ);
+        code.extend_from_slice(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+        code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+        code.extend_from_slice(&buffer.to_le_bytes());
+        code.extend_from_slice(&[0xba, 1, 0, 0, 0]); // mov edx, 1
+        code.extend_from_slice(&SYSCALL);
+        code.extend_from_slice(&[0x49, 0x89, 0xc4]); // mov r12, rax
+        load_number(&mut code, nr::CLOSE);
+        code.extend_from_slice(&[0x48, 0x89, 0xdf]); // mov rdi, rbx
+        code.extend_from_slice(&SYSCALL);
+    }
+    load_number(&mut code, nr::LSEEK);
+    code.extend_from_slice(&[0xbf, 9, 0, 0, 0]); // mov edi, 9
+    code.extend_from_slice(&[0x31, 0xf6]); // xor esi, esi
+    code.extend_from_slice(&[0xba, 2, 0, 0, 0]); // mov edx, SEEK_END
+    code.extend_from_slice(&SYSCALL);
+    code.extend_from_slice(&[0x48, 0xc1, 0xe0, 0x08]); // shl rax, 8
+    code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, buffer
+    code.extend_from_slice(&buffer.to_le_bytes());
+    code.extend_from_slice(&[0x0f, 0xb6, 0x3e]); // movzx edi, byte [rsi]
+    code.extend_from_slice(&[0x48, 0x01, 0xc7]); // add rdi, rax
+    code.extend_from_slice(&[0x4c, 0x89, 0xe0]); // mov rax, r12
+    code.extend_from_slice(&[0x48, 0xc1, 0xe0, 0x18]); // shl rax, 24
+    code.extend_from_slice(&[0x48, 0x01, 0xc7]); // add rdi, rax
+    exit(&mut code);
+    elf::build(&code, path.as_bytes(), 8)
+}
+
+/// Ways for a program to crash: a name, the instructions, and the signal
+/// the fault raises.
+pub const CRASHES: [(&str, &[u8], u32); 4] = [
+    ("segv", &[0x31, 0xc0, 0x48, 0x89, 0x00], SIGSEGV), // xor eax, eax; mov [rax], rax
+    ("gp", &[0xfa], SIGSEGV),                           // cli: not allowed in ring 3
+    ("ud", &UD2, SIGILL),
+    ("div", &[0x31, 0xc9, 0xf7, 0xf1], SIGFPE), // xor ecx, ecx; div ecx
+];
+
+/// Run `instructions`, then exit(0) if they didn't fault.
+pub fn crash(instructions: &[u8]) -> Vec<u8> {
+    let mut code = instructions.to_vec();
+    code.extend_from_slice(&[0x31, 0xff]); // xor edi, edi
+    exit(&mut code);
+    elf::build(&code, &[], 0)
+}
+
+/// Put the programs in the initrd, except where the archive has a program
+/// of that name: a real one (from `userland`) beats a stand-in. Needs the
+/// heap.
+pub fn install() {
+    let programs = [
+        ("/bin/hello", hello()),
+        ("/bin/getpid", getpid()),
+        ("/bin/argc", argc()),
+        ("/bin/cat", cat()),
+        ("/bin/ticker", ticker()),
+        ("/bin/pong", pong()),
+    ];
+    for (path, image) in programs {
+        if initrd::read(path).is_none() {
+            initrd::add(path, image.leak());
+        }
+    }
+}
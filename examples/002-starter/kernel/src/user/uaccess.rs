@@ -0,0 +1,96 @@
+//! Reading and writing user memory on behalf of system calls.
+//!
+//! A pointer from user code is only a number until it is checked: every
+//! byte must lie in the user half and on a page the active address space
+//! maps user-accessible (and writable, to write to it). Otherwise the call
+//! fails with EFAULT instead of the kernel faulting on the user's behalf.
+//! Pages of the process's regions that haven't been touched yet are paged
+//! in first, as if the program had touched them.
+
+use alloc::string::String;
+use core::marker::PhantomData;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::{self, USER_END, USER_START};
+use crate::memory::vma::FaultOutcome;
+use crate::process;
+use crate::syscall::Errno;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// An address in user memory, as a system call received it.
+pub struct UserPtr<T> {
+    addr: u64,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        UserPtr { addr, _type: PhantomData }
+    }
+
+    pub fn addr(self) -> u64 {
+        self.addr
+    }
+}
+
+/// Whether `len` bytes at `addr` may be read (or written) by the kernel for
+/// the current thread.
+pub fn check(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+    if addr < USER_START || end > USER_END {
+        return Err(Errno::EFAULT);
+    }
+    let mut needed = PageTableFlags::USER_ACCESSIBLE;
+    let mut code = PageFaultErrorCode::USER_MODE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+        code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+    }
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let page_addr = VirtAddr::new(page);
+        let mapped = address_space::translate_active(page_addr).map(|(_, flags)| flags);
+        match mapped {
+            Some(flags) if flags.contains(needed) => {}
+            None if matches!(process::handle_page_fault(page_addr, code), FaultOutcome::Handled) => {}
+            _ => return Err(Errno::EFAULT),
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Fill `dst` from user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: UserPtr<u8>) -> Result<(), Errno> {
+    check(src.addr, dst.len(), false)?;
+    unsafe { dst.as_mut_ptr().copy_from_nonoverlapping(src.addr as *const u8, dst.len()) };
+    Ok(())
+}
+
+/// Copy `src` to user memory at `dst`.
+pub fn copy_to_user(dst: UserPtr<u8>, src: &[u8]) -> Result<(), Errno> {
+    check(dst.addr, src.len(), true)?;
+    unsafe { (dst.addr as *mut u8).copy_from_nonoverlapping(src.as_ptr(), src.len()) };
+    Ok(())
+}
+
+/// The `len` bytes at `src`, which must be UTF-8 (EINVAL otherwise).
+pub fn copy_string(src: UserPtr<u8>, len: usize) -> Result<String, Errno> {
+    let mut bytes = alloc::vec![0; len];
+    copy_from_user(&mut bytes, src)?;
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
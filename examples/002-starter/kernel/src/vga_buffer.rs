@@ -1,5 +1,7 @@
 use core::fmt::{self, Write};
-use spin::Mutex;
+use core::ops::DerefMut;
+
+use crate::sync::{RwLock, SpinLock};
 
 #[repr(transparent)]
 pub struct Volatile<T> {
@@ -24,6 +26,12 @@ const BUFFER_HEIGHT: usize = 25;
 #[derive(Copy, Clone)]
 struct ColorCode(u8);
 
+impl ColorCode {
+    const fn new(foreground: u8, background: u8) -> Self {
+        ColorCode((background & 0x0F) << 4 | (foreground & 0x0F))
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct ScreenChar {
@@ -38,11 +46,19 @@ struct Buffer {
 
 pub struct Writer {
     column_position: usize,
+    /// Copied from `COLOR` at the start of each write.
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    buffer: *mut Buffer,
 }
 
+// The buffer is only touched through the `WRITER` lock.
+unsafe impl Send for Writer {}
+
 impl Writer {
+    fn buffer(&mut self) -> &mut Buffer {
+        unsafe { &mut *self.buffer }
+    }
+
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
@@ -50,10 +66,8 @@ impl Writer {
                 if self.column_position >= BUFFER_WIDTH { self.new_line(); }
                 let row = BUFFER_HEIGHT - 1;
                 let col = self.column_position;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code: self.color_code,
-                });
+                let color_code = self.color_code;
+                self.buffer().chars[row][col].write(ScreenChar { ascii_character: byte, color_code });
                 self.column_position += 1;
             }
         }
@@ -62,8 +76,8 @@ impl Writer {
     fn new_line(&mut self) {
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
-                let ch = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(ch);
+                let ch = self.buffer().chars[row][col].read();
+                self.buffer().chars[row - 1][col].write(ch);
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
@@ -72,7 +86,7 @@ impl Writer {
 
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
-        for col in 0..BUFFER_WIDTH { self.buffer.chars[row][col].write(blank); }
+        for col in 0..BUFFER_WIDTH { self.buffer().chars[row][col].write(blank); }
     }
 }
 
@@ -83,28 +97,33 @@ impl Write for Writer {
     }
 }
 
-static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+/// Colour for new text: read by every print, changed rarely.
+static COLOR: RwLock<ColorCode> = RwLock::new(ColorCode::new(0x7, 0x0));
+
+static WRITER: SpinLock<Writer> = SpinLock::new(Writer {
+    column_position: 0,
+    color_code: ColorCode::new(0x7, 0x0),
+    buffer: BUFFER_ADDR as *mut Buffer,
+});
 
-fn writer() -> spin::MutexGuard<'static, Option<Writer>> {
+fn writer() -> impl DerefMut<Target = Writer> {
+    let color_code = *COLOR.read();
     let mut guard = WRITER.lock();
-    if guard.is_none() {
-        *guard = Some(Writer {
-            column_position: 0,
-            color_code: ColorCode(0x07),
-            buffer: unsafe { &mut *(BUFFER_ADDR as *mut Buffer) },
-        });
-    }
+    guard.color_code = color_code;
     guard
 }
 
+/// Set the colour of text printed from now on (VGA palette indices 0-15).
+pub fn set_color(foreground: u8, background: u8) {
+    *COLOR.write() = ColorCode::new(foreground, background);
+}
+
 pub fn printk(s: &str) {
-    use core::fmt::Write;
-    if let Some(w) = &mut *writer() { let _ = w.write_str(s); }
+    let _ = writer().write_str(s);
 }
 
 pub fn clear_screen() {
-    if let Some(w) = &mut *writer() {
-        for row in 0..BUFFER_HEIGHT { w.clear_row(row); }
-        w.column_position = 0;
-    }
+    let mut w = writer();
+    for row in 0..BUFFER_HEIGHT { w.clear_row(row); }
+    w.column_position = 0;
 }
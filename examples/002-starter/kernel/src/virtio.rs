@@ -0,0 +1,379 @@
+//! Virtio over PCI: the transport that any virtio device sits on, and its
+//! split virtqueues. A driver for one kind of device (`block::virtio`)
+//! builds on these.
+//!
+//! A modern (virtio 1.0) device describes where its register blocks are
+//! with vendor capabilities in PCI configuration space: the common
+//! configuration (features, status, queue setup), the notify area (one
+//! doorbell per queue), the ISR byte (read to acknowledge an interrupt) and
+//! the device-specific configuration. Each is a window into one of its
+//! memory BARs.
+//!
+//! A virtqueue is three rings in memory shared with the device. The driver
+//! describes buffers in the descriptor table, chained for a request in
+//! several parts, and offers the head of each chain in the available ring;
+//! the device hands chains back in the used ring when done, and raises an
+//! interrupt.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+use crate::dma::{self, DmaBuffer, DmaError};
+use crate::memory::mmio::{map_mmio, Mmio};
+use crate::pci::{self, Bar};
+
+pub const VENDOR: u16 = 0x1AF4;
+
+/// Device types.
+pub const TYPE_BLOCK: u16 = 2;
+
+/// Device status bits.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// The feature every modern device offers, and we need.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// Vendor capability types.
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+/// Common configuration registers.
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+/// "No MSI-X vector": interrupts come on the INTx line.
+const NO_VECTOR: u16 = 0xFFFF;
+
+/// ISR bit: a queue has used buffers.
+pub const ISR_QUEUE: u8 = 1;
+
+/// The virtio device type of `device`, if it is a virtio device: modern
+/// ones have it in the device ID, transitional ones in the subsystem ID.
+pub fn device_type(device: &pci::Device) -> Option<u16> {
+    match (device.vendor, device.device) {
+        (VENDOR, 0x1040..=0x107F) => Some(device.device - 0x1040),
+        (VENDOR, 0x1000..=0x103F) => Some(device.addr.read_u16(pci::SUBSYSTEM_ID)),
+        _ => None,
+    }
+}
+
+/// A virtio device's register blocks.
+pub struct Transport {
+    pub device: pci::Device,
+    common: Mmio,
+    notify: Mmio,
+    notify_multiplier: u32,
+    isr: Mmio,
+    config: Mmio,
+}
+
+// The windows are in the kernel half of the address space, the same on
+// every CPU, and every access is a volatile read or write of one register:
+// the interrupt handler reads the ISR while a thread sets up a request.
+unsafe impl Send for Transport {}
+unsafe impl Sync for Transport {}
+
+impl Transport {
+    /// Find and map `device`'s register blocks, and let it do DMA.
+    pub fn new(device: pci::Device) -> Result<Transport, &'static str> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        let addr = device.addr;
+        for (id, cap) in device.capabilities() {
+            if id != pci::CAP_VENDOR {
+                continue;
+            }
+            let kind = addr.read_u8(cap + 3);
+            let slot = match kind {
+                CAP_COMMON => &mut common,
+                CAP_NOTIFY => &mut notify,
+                CAP_ISR => &mut isr,
+                CAP_DEVICE => &mut config,
+                _ => continue,
+            };
+            if slot.is_some() {
+                // The first of a type is the one to use.
+                continue;
+            }
+            let bar = addr.read_u8(cap + 4);
+            let (offset, len) = (addr.read_u32(cap + 8) as u64, addr.read_u32(cap + 12) as usize);
+            let Some(Bar::Memory { addr: base, size, .. }) = (bar < 6).then(|| device.bar(bar)).flatten() else {
+                return Err("capability in a BAR that isn't memory");
+            };
+            if offset + len as u64 > size {
+                return Err("capability past the end of its BAR");
+            }
+            *slot = Some(map_mmio(PhysAddr::new(base + offset), len).map_err(|_| "cannot map registers")?);
+            if kind == CAP_NOTIFY {
+                notify_multiplier = addr.read_u32(cap + 16);
+            }
+        }
+        let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) else {
+            return Err("not a modern (virtio 1.0) device");
+        };
+        // Devices without configuration get an empty window.
+        let config = match config {
+            Some(config) => config,
+            None => map_mmio(common.phys(), 0).map_err(|_| "cannot map registers")?,
+        };
+        device.enable();
+        Ok(Transport { device, common, notify, notify_multiplier, isr, config })
+    }
+
+    pub fn status(&self) -> u8 {
+        self.common.register::<u8>(DEVICE_STATUS).get()
+    }
+
+    /// Set `bits` in the status, on top of those set already.
+    pub fn add_status(&self, bits: u8) {
+        self.common.register::<u8>(DEVICE_STATUS).set(self.status() | bits);
+    }
+
+    /// Reset the device, and wait until it has.
+    pub fn reset(&self) {
+        self.common.register::<u8>(DEVICE_STATUS).set(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reset, then acknowledge the device and agree on the features in
+    /// `wanted` it offers, which must include `F_VERSION_1`. Returns the
+    /// features agreed on. Queues are set up next, then `driver_ok`.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut offered = 0;
+        for half in 0..2 {
+            self.common.register::<u32>(DEVICE_FEATURE_SELECT).set(half);
+            offered |= (self.common.register::<u32>(DEVICE_FEATURE).get() as u64) << (32 * half);
+        }
+        let features = offered & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("device doesn't offer virtio 1.0");
+        }
+        for half in 0..2 {
+            self.common.register::<u32>(DRIVER_FEATURE_SELECT).set(half);
+            self.common.register::<u32>(DRIVER_FEATURE).set((features >> (32 * half)) as u32);
+        }
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("device refused the features");
+        }
+        Ok(features)
+    }
+
+    /// Give queue `index` the rings of `queue`, and enable it.
+    pub fn setup_queue(&self, index: u16, queue: &mut Virtqueue) -> Result<(), &'static str> {
+        self.common.register::<u16>(QUEUE_SELECT).set(index);
+        let max = self.common.register::<u16>(QUEUE_SIZE).get();
+        if max == 0 {
+            return Err("no such queue");
+        }
+        if max < queue.size {
+            return Err("queue too large for the device");
+        }
+        self.common.register::<u16>(QUEUE_SIZE).set(queue.size);
+        self.common.register::<u16>(QUEUE_MSIX_VECTOR).set(NO_VECTOR);
+        self.set_common_u64(QUEUE_DESC, queue.desc_phys().as_u64());
+        self.set_common_u64(QUEUE_DRIVER, queue.avail_phys().as_u64());
+        self.set_common_u64(QUEUE_DEVICE, queue.used_phys().as_u64());
+        queue.notify_offset = self.common.register::<u16>(QUEUE_NOTIFY_OFF).get() as usize * self.notify_multiplier as usize;
+        queue.index = index;
+        self.common.register::<u16>(QUEUE_ENABLE).set(1);
+        Ok(())
+    }
+
+    /// 64-bit registers are written as two halves, low first, as the
+    /// specification asks.
+    fn set_common_u64(&self, offset: usize, value: u64) {
+        self.common.register::<u32>(offset).set(value as u32);
+        self.common.register::<u32>(offset + 4).set((value >> 32) as u32);
+    }
+
+    /// Setup is done: the device may start.
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Ring `queue`'s doorbell: it has new buffers.
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        self.notify.register::<u16>(queue.notify_offset).set(queue.index);
+    }
+
+    /// Read (and so clear) the ISR: why the device interrupted, if it did.
+    /// Reading lowers its INTx line.
+    pub fn read_isr(&self) -> u8 {
+        self.isr.register::<u8>(0).get()
+    }
+
+    /// A `T` from the device-specific configuration.
+    pub fn config<T: Copy>(&self, offset: usize) -> T {
+        self.config.register::<T>(offset).get()
+    }
+
+    /// A 64-bit configuration field, read as two halves; read again if the
+    /// device changed it in between.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.common.register::<u8>(CONFIG_GENERATION).get();
+            let value = self.config::<u32>(offset) as u64 | (self.config::<u32>(offset + 4) as u64) << 32;
+            if self.common.register::<u8>(CONFIG_GENERATION).get() == generation {
+                return value;
+            }
+        }
+    }
+}
+
+/// Descriptor flags.
+pub const DESC_NEXT: u16 = 1;
+/// The device writes this buffer (rather than reading it).
+pub const DESC_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer of a request: where it is, how long, and whether the device
+/// writes it.
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+/// A split virtqueue of `size` descriptors, its rings in one DMA buffer:
+/// the descriptor table, then the available ring (flags, index, `size`
+/// heads, unused event), then the used ring (flags, index, `size` id/len
+/// pairs, unused event).
+pub struct Virtqueue {
+    memory: DmaBuffer,
+    size: u16,
+    /// Descriptors not in a chain the device has.
+    free: Vec<u16>,
+    /// The used ring index we have seen up to.
+    last_used: u16,
+    index: u16,
+    notify_offset: usize,
+}
+
+impl Virtqueue {
+    pub fn new(size: u16) -> Result<Virtqueue, DmaError> {
+        assert!(size.is_power_of_two(), "virtio: queue size {} is not a power of two", size);
+        let memory = dma::alloc(Self::used_offset(size) + 6 + 8 * size as usize, 4096)?;
+        Ok(Virtqueue { memory, size, free: (0..size).rev().collect(), last_used: 0, index: 0, notify_offset: 0 })
+    }
+
+    fn avail_offset(size: u16) -> usize {
+        16 * size as usize
+    }
+
+    fn used_offset(size: u16) -> usize {
+        (Self::avail_offset(size) + 6 + 2 * size as usize).next_multiple_of(4)
+    }
+
+    fn desc_phys(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    fn avail_phys(&self) -> PhysAddr {
+        self.memory.phys() + Self::avail_offset(self.size) as u64
+    }
+
+    fn used_phys(&self) -> PhysAddr {
+        self.memory.phys() + Self::used_offset(self.size) as u64
+    }
+
+    fn at<T>(&self, offset: usize) -> *mut T {
+        (self.memory.virt() + offset as u64).as_mut_ptr()
+    }
+
+    /// The device's index into the used ring: how many chains it has handed
+    /// back, ever, modulo 2^16.
+    fn used_index(&self) -> u16 {
+        unsafe { self.at::<u16>(Self::used_offset(self.size) + 2).read_volatile() }
+    }
+
+    /// Whether the device has handed back a chain `pop_used` hasn't taken.
+    pub fn has_used(&self) -> bool {
+        self.used_index() != self.last_used
+    }
+
+    /// Chain `buffers` in descriptors and offer the chain to the device;
+    /// its head, which `pop_used` gives back when the device is done. `None`
+    /// if there aren't enough free descriptors. `Transport::notify` next.
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let ids: Vec<u16> = (0..buffers.len()).map(|_| self.free.pop().expect("free descriptor")).collect();
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = ids.get(i + 1).copied();
+            let mut flags = if buffer.device_writes { DESC_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESC_NEXT;
+            }
+            let descriptor = Descriptor { addr: buffer.addr.as_u64(), len: buffer.len, flags, next: next.unwrap_or(0) };
+            unsafe { self.at::<Descriptor>(16 * ids[i] as usize).write_volatile(descriptor) };
+        }
+        let avail = Self::avail_offset(self.size);
+        unsafe {
+            let index = self.at::<u16>(avail + 2).read_volatile();
+            self.at::<u16>(avail + 4 + 2 * (index % self.size) as usize).write_volatile(ids[0]);
+            // The device must see the entry before the index that covers it.
+            fence(Ordering::SeqCst);
+            self.at::<u16>(avail + 2).write_volatile(index.wrapping_add(1));
+        }
+        Some(ids[0])
+    }
+
+    /// The next chain the device handed back: its head and how many bytes
+    /// the device wrote. Its descriptors are free again.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let entry = Self::used_offset(self.size) + 4 + 8 * (self.last_used % self.size) as usize;
+        let (head, len) = unsafe { (self.at::<u32>(entry).read_volatile() as u16, self.at::<u32>(entry + 4).read_volatile()) };
+        self.last_used = self.last_used.wrapping_add(1);
+        let mut id = head;
+        loop {
+            self.free.push(id);
+            let descriptor = unsafe { self.at::<Descriptor>(16 * id as usize).read_volatile() };
+            if descriptor.flags & DESC_NEXT == 0 {
+                break;
+            }
+            id = descriptor.next;
+        }
+        Some((head, len))
+    }
+}
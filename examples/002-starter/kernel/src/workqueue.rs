@@ -0,0 +1,110 @@
+//! Deferred work ("bottom halves"): an interrupt handler does the minimum,
+//! then schedules a `Work` item that a kernel thread runs later, where it may
+//! allocate, block or print.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::sync::{MpscQueue, WaitQueue};
+use crate::thread::{self, Priority, ThreadId};
+use crate::{serial_println, time};
+
+/// Work items scheduled and not yet started.
+const QUEUE_LEN: usize = 64;
+
+/// A function to run in thread context. Lives in a static, so scheduling it
+/// from an interrupt handler never allocates. A non-capturing closure works
+/// too; state goes in statics next to it.
+pub struct Work {
+    func: fn(),
+    /// Queued and not yet started; scheduling it again until then is a no-op.
+    pending: AtomicBool,
+}
+
+impl Work {
+    pub const fn new(func: fn()) -> Self {
+        Work { func, pending: AtomicBool::new(false) }
+    }
+
+    /// Queue this item for the worker thread. Safe in interrupt handlers.
+    /// False if it was already pending (it runs once for both) or the queue is full.
+    pub fn schedule(&'static self) -> bool {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        if QUEUE.push(self).is_err() {
+            self.pending.store(false, Ordering::Release);
+            OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        SCHEDULED.fetch_add(1, Ordering::Relaxed);
+        READY.notify_one();
+        true
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+static QUEUE: MpscQueue<&'static Work, QUEUE_LEN> = MpscQueue::new();
+/// The worker sleeps here while the queue is empty.
+static READY: WaitQueue = WaitQueue::new();
+
+static SCHEDULED: AtomicU64 = AtomicU64::new(0);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+
+/// Start the worker thread. Needs `thread::init`; items scheduled before run then.
+pub fn init() {
+    // Above normal threads, so deferred interrupt work isn't starved by busy ones.
+    let worker = thread::Builder::new().name("kworker").priority(Priority::High).spawn(worker);
+    if worker.is_none() {
+        serial_println!("workqueue: cannot start the worker thread");
+    }
+}
+
+fn worker() {
+    loop {
+        // Safety: this thread is the only consumer.
+        while let Some(work) = unsafe { QUEUE.pop() } {
+            // Cleared first, so the item can be scheduled again while it runs.
+            work.pending.store(false, Ordering::Release);
+            (work.func)();
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+        READY.wait_until(|| !QUEUE.is_empty());
+    }
+}
+
+pub fn dump() {
+    serial_println!(
+        "workqueue: {} scheduled, {} completed, {} lost to a full queue",
+        SCHEDULED.load(Ordering::Relaxed),
+        COMPLETED.load(Ordering::Relaxed),
+        OVERFLOWS.load(Ordering::Relaxed)
+    );
+}
+
+static TEST_RUNS: AtomicU64 = AtomicU64::new(0);
+/// Id + 1 of the thread the test item ran on.
+static TEST_THREAD: AtomicU64 = AtomicU64::new(0);
+static TEST_WORK: Work = Work::new(|| {
+    TEST_THREAD.store(thread::current_id().0 + 1, Ordering::Relaxed);
+    TEST_RUNS.fetch_add(1, Ordering::Release);
+});
+
+/// Scheduling twice with interrupts off (as a handler would) runs the item
+/// once, later, on the worker thread.
+pub fn self_test() -> bool {
+    let before = TEST_RUNS.load(Ordering::Relaxed);
+    let (first, second) = without_interrupts(|| (TEST_WORK.schedule(), TEST_WORK.schedule()));
+    for _ in 0..10 {
+        if !TEST_WORK.is_pending() && TEST_RUNS.load(Ordering::Acquire) > before {
+            break;
+        }
+        time::sleep(core::time::Duration::from_millis(10));
+    }
+    let ran_on = ThreadId(TEST_THREAD.load(Ordering::Relaxed).wrapping_sub(1));
+    first && !second && TEST_RUNS.load(Ordering::Relaxed) == before + 1 && ran_on != thread::current_id()
+}
@@ -1,4 +1,12 @@
-use std::{env, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+#[path = "build/fat.rs"]
+mod fat;
+#[path = "build/iso.rs"]
+mod iso;
 
 fn main() {
     // Path to the compiled kernel binary (from the artifact dependency)
@@ -8,14 +16,133 @@ fn main() {
     let uefi_img = out_dir.join("uefi.img");
     let bios_img = out_dir.join("bios.img");
 
+    // Pack ../initrd and the user programs into the ramdisk the bootloader
+    // loads for the kernel
+    let initrd_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("../initrd");
+    let initrd = out_dir.join("initrd.cpio");
+    println!("cargo:rerun-if-changed={}", initrd_dir.display());
+    let files = initrd_files(&initrd_dir, &user_programs());
+    fs::write(&initrd, pack_initrd(&files)).expect("write initrd");
+
     // Build UEFI and BIOS disk images
     let mut uefi = bootloader::UefiBoot::new(&kernel_bin);
+    uefi.set_ramdisk(&initrd);
     uefi.create_disk_image(&uefi_img).expect("create UEFI image");
 
     let mut bios = bootloader::BiosBoot::new(&kernel_bin);
+    bios.set_ramdisk(&initrd);
     bios.create_disk_image(&bios_img).expect("create BIOS image");
 
+    // A CD with the initrd's files, booting the BIOS image, for --cdrom
+    let iso_img = out_dir.join("teachme.iso");
+    let boot_image = fs::read(&bios_img).expect("read BIOS image");
+    fs::write(&iso_img, iso::build("TEACHMERUSTOS", &files, &boot_image)).expect("write ISO image");
+
+    // The data disk: ../data as a FAT volume, the same image for the same
+    // files, which the kernel mounts at /mnt/vd1p1
+    let data_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("../data");
+    let data_img = out_dir.join("data.img");
+    println!("cargo:rerun-if-changed={}", data_dir.display());
+    fs::write(&data_img, fat::build("TEACHMEDATA", &data_files(&data_dir))).expect("write data image");
+
     // Export paths for runner/src/main.rs
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_img.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_img.display());
-}
\ No newline at end of file
+    println!("cargo:rustc-env=ISO_IMAGE={}", iso_img.display());
+    println!("cargo:rustc-env=DATA_IMAGE={}", data_img.display());
+}
+
+/// Where the kernel finds the list of the programs packed from `userland`.
+const MANIFEST: &str = "etc/programs";
+
+/// The programs of the `userland` artifact dependency, built for the
+/// kernel's target: (name, path) for each, sorted by name. Cargo gives one
+/// `CARGO_BIN_FILE_USERLAND_<name>` per binary.
+fn user_programs() -> Vec<(String, PathBuf)> {
+    let mut programs: Vec<(String, PathBuf)> = env::vars_os()
+        .filter_map(|(key, path)| {
+            let name = key.to_str()?.strip_prefix("CARGO_BIN_FILE_USERLAND_")?;
+            Some((name.to_string(), PathBuf::from(path)))
+        })
+        .collect();
+    programs.sort();
+    programs
+}
+
+/// What goes in the initrd, and on the CD: every file under `dir`, named
+/// relative to it, each of `programs` as bin/<name>, and a manifest
+/// listing those at `MANIFEST`. A built program takes the place of a file
+/// of the same name under `dir`: that would be a stale copy.
+fn initrd_files(dir: &Path, programs: &[(String, PathBuf)]) -> Vec<iso::File> {
+    let mut files = Vec::new();
+    collect(dir, dir, &mut files);
+    let programs: Vec<(String, &PathBuf)> = programs.iter().map(|(name, path)| (format!("bin/{}", name), path)).collect();
+    files.retain(|(name, _)| {
+        let built = programs.iter().any(|(program, _)| program == name);
+        if built {
+            println!("cargo:warning=initrd/{} is replaced by the one built from userland", name);
+        }
+        !built && name != MANIFEST
+    });
+    files.sort();
+    let mut packed = Vec::new();
+    for (name, path) in files {
+        packed.push((name, 0o100644, fs::read(path).expect("read initrd file")));
+    }
+    let mut manifest = String::new();
+    for (name, path) in programs {
+        manifest.push_str(&format!("/{}\n", name));
+        packed.push((name, 0o100755, fs::read(path).expect("read user program")));
+    }
+    packed.push((MANIFEST.to_string(), 0o100644, manifest.into_bytes()));
+    packed
+}
+
+/// What goes on the data disk: every file under `dir`, named relative to
+/// it, sorted.
+fn data_files(dir: &Path) -> Vec<fat::File> {
+    let mut files = Vec::new();
+    collect(dir, dir, &mut files);
+    files.sort();
+    files.into_iter().map(|(name, path)| (name, fs::read(path).expect("read data file"))).collect()
+}
+
+/// A cpio archive in the "newc" format (what Linux's initramfs uses) of
+/// `files`.
+fn pack_initrd(files: &[iso::File]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (name, mode, contents) in files {
+        entry(&mut archive, name, *mode, contents);
+    }
+    entry(&mut archive, "TRAILER!!!", 0, &[]);
+    archive
+}
+
+fn collect(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries {
+        let path = entry.expect("read directory").path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            collect(root, &path, files);
+        } else {
+            let name = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+            files.push((name, path));
+        }
+    }
+}
+
+/// Header (magic and 13 fields of 8 hex digits), name with its NUL, padded
+/// to 4 bytes, then the contents, padded to 4 bytes.
+fn entry(archive: &mut Vec<u8>, name: &str, mode: u32, contents: &[u8]) {
+    let fields = [0, mode, 0, 0, 1, 0, contents.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(contents);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
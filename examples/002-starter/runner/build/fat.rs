@@ -0,0 +1,506 @@
+//! A disk image holding a FAT16 volume of a tree of files, as the kernel's
+//! `fs/fat.rs` reads it: an MBR with one partition, 1 MiB in, on which the
+//! boot sector with its BPB, two copies of the FAT, the fixed root
+//! directory, then clusters of 2 KiB. Files and directories take clusters
+//! in order, each one run, so a chain is just the next cluster each time.
+//! Names that aren't 8.3 in one case get long-name entries.
+//!
+//! Nothing depends on the host: every timestamp is `TIMESTAMP` and the
+//! volume serial is fixed, so the same files make the same image, byte for
+//! byte. The volume has room to spare, at least `MIN_CLUSTERS` clusters
+//! and half as many again as the files take, for the guest to write to.
+
+use std::collections::BTreeMap;
+
+/// A file: its path, `/` between directories, and its contents.
+pub type File = (String, Vec<u8>);
+
+const SECTOR: usize = 512;
+/// Where the partition starts, in sectors.
+const PARTITION_START: usize = 2048;
+/// FAT16 with LBA addressing.
+const PARTITION_TYPE: u8 = 0x0E;
+const SECTORS_PER_CLUSTER: usize = 4;
+const CLUSTER: usize = SECTOR * SECTORS_PER_CLUSTER;
+const RESERVED_SECTORS: usize = 1;
+const FATS: usize = 2;
+const ROOT_ENTRIES: usize = 512;
+const ENTRY_SIZE: usize = 32;
+/// 16 MiB of clusters at least; fewer than 4085 would make it FAT12, more
+/// than 65524 FAT32.
+const MIN_CLUSTERS: usize = 8192;
+const MAX_CLUSTERS: usize = 65524;
+const SERIAL: u32 = 0x7EAC_4E00;
+/// 2000-01-01 00:00:00, as (date, time).
+const TIMESTAMP: (u16, u16) = ((20 << 9) | (1 << 5) | 1, 0);
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+const LONG_LAST: u8 = 0x40;
+const LONG_CHARS: usize = 13;
+const MAX_NAME_UNITS: usize = 255;
+const SHORT_NAME_SYMBOLS: &[u8] = b"$%'-_@~`!(){}^#&";
+const INVALID_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+const END_OF_CHAIN: u16 = 0xFFFF;
+
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Node>),
+}
+
+/// The image: the MBR, the volume labelled `label` with `files` on it.
+/// Panics on a name FAT can't hold, or files too large for the volume.
+pub fn build(label: &str, files: &[File]) -> Vec<u8> {
+    let mut root = BTreeMap::new();
+    for (path, contents) in files {
+        insert(&mut root, path, contents);
+    }
+    let needed = clusters(&root);
+    if needed > MAX_CLUSTERS {
+        panic!("{} clusters of files is more than a FAT16 volume of {} byte clusters holds", needed, CLUSTER);
+    }
+    let clusters = (needed + needed / 2).clamp(MIN_CLUSTERS, MAX_CLUSTERS);
+    let fat_sectors = ((clusters + 2) * 2).div_ceil(SECTOR);
+    let root_sectors = ROOT_ENTRIES * ENTRY_SIZE / SECTOR;
+    let data_start = RESERVED_SECTORS + FATS * fat_sectors + root_sectors;
+    let total = data_start + clusters * SECTORS_PER_CLUSTER;
+
+    let mut volume = Volume { bytes: vec![0; total * SECTOR], fat: vec![0; clusters + 2], data_start, next: 2 };
+    volume.fat[0] = 0xFFF8;
+    volume.fat[1] = END_OF_CHAIN;
+    let mut root_dir = vec![short_entry(&label_name(label), ATTR_VOLUME_ID, 0, 0, 0)];
+    root_dir.extend(volume.place_entries(&root, 0));
+    if root_dir.len() > ROOT_ENTRIES {
+        panic!("{} entries in the root directory; FAT16's holds {}", root_dir.len(), ROOT_ENTRIES);
+    }
+    let root_start = (RESERVED_SECTORS + FATS * fat_sectors) * SECTOR;
+    volume.bytes[root_start..root_start + root_dir.len() * ENTRY_SIZE].copy_from_slice(&root_dir.concat());
+    for copy in 0..FATS {
+        let start = (RESERVED_SECTORS + copy * fat_sectors) * SECTOR;
+        for (i, entry) in volume.fat.iter().enumerate() {
+            volume.bytes[start + i * 2..start + i * 2 + 2].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+    volume.bytes[..SECTOR].copy_from_slice(&boot_sector(label, total, fat_sectors));
+
+    let mut image = mbr(total);
+    image.resize(PARTITION_START * SECTOR, 0);
+    image.extend_from_slice(&volume.bytes);
+    image
+}
+
+/// Put `contents` at `path` under `dir`, making the directories on the way.
+fn insert(dir: &mut BTreeMap<String, Node>, path: &str, contents: &[u8]) {
+    let (name, rest) = match path.split_once('/') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    check_name(name, path);
+    if let Some((existing, _)) = dir.iter().find(|(other, _)| other.as_str() != name && other.eq_ignore_ascii_case(name)) {
+        panic!("{}: FAT doesn't tell it from {}", path, existing);
+    }
+    match rest {
+        Some(rest) => match dir.entry(name.to_string()).or_insert_with(|| Node::Dir(BTreeMap::new())) {
+            Node::Dir(sub) => insert(sub, rest, contents),
+            Node::File(_) => panic!("{}: {} is a file", path, name),
+        },
+        None => {
+            if dir.insert(name.to_string(), Node::File(contents.to_vec())).is_some() {
+                panic!("{}: a directory has the name", path);
+            }
+        }
+    }
+}
+
+/// Panic unless the kernel's `create` would take `name`.
+fn check_name(name: &str, path: &str) {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_UNITS
+        || name.ends_with(['.', ' '])
+        || name.chars().any(|c| c < ' ' || INVALID_CHARS.contains(&c))
+    {
+        panic!("{}: {:?} is not a name FAT holds", path, name);
+    }
+}
+
+/// Clusters the files and directories under `dir` take, not counting it.
+fn clusters(dir: &BTreeMap<String, Node>) -> usize {
+    dir.values()
+        .map(|node| match node {
+            Node::File(contents) => contents.len().div_ceil(CLUSTER),
+            Node::Dir(sub) => dir_clusters(sub) + clusters(sub),
+        })
+        .sum()
+}
+
+/// Clusters directory `dir` itself takes: `.`, `..`, and an entry for
+/// each name, with long-name entries whether it needs them or not. At
+/// least one.
+fn dir_clusters(dir: &BTreeMap<String, Node>) -> usize {
+    let entries: usize = 2 + dir.keys().map(|name| 1 + (name.encode_utf16().count() + 1).div_ceil(LONG_CHARS)).sum::<usize>();
+    (entries * ENTRY_SIZE).div_ceil(CLUSTER)
+}
+
+struct Volume {
+    /// The partition, from its boot sector on.
+    bytes: Vec<u8>,
+    fat: Vec<u16>,
+    /// The first data sector.
+    data_start: usize,
+    /// The next free cluster.
+    next: usize,
+}
+
+impl Volume {
+    /// Put `data` in a run of new clusters, chained in the FAT; the first,
+    /// or 0 for nothing.
+    fn place(&mut self, data: &[u8]) -> u32 {
+        let count = data.len().div_ceil(CLUSTER);
+        if count == 0 {
+            return 0;
+        }
+        let first = self.next;
+        for cluster in first..first + count {
+            self.fat[cluster] = if cluster + 1 < first + count { cluster as u16 + 1 } else { END_OF_CHAIN };
+        }
+        self.next += count;
+        let start = (self.data_start + (first - 2) * SECTORS_PER_CLUSTER) * SECTOR;
+        self.bytes[start..start + data.len()].copy_from_slice(data);
+        first as u32
+    }
+
+    /// The entries of a directory holding `dir`, its files and directories
+    /// placed; `own` is its first cluster (0 for the root).
+    fn place_entries(&mut self, dir: &BTreeMap<String, Node>, own: u32) -> Vec<[u8; ENTRY_SIZE]> {
+        let mut shorts: Vec<[u8; 11]> = Vec::new();
+        let mut entries = Vec::new();
+        for (name, node) in dir {
+            let (short, case, long) = short_name_for(name, &shorts);
+            if shorts.contains(&short) {
+                panic!("{}: another name in its directory has the same short name", name);
+            }
+            shorts.push(short);
+            let (attr, cluster, size) = match node {
+                Node::File(contents) => (ATTR_ARCHIVE, self.place(contents), contents.len() as u32),
+                Node::Dir(sub) => (ATTR_DIRECTORY, self.place_dir(sub, own), 0),
+            };
+            if long {
+                entries.extend(long_name_entries(name, &short));
+            }
+            entries.push(short_entry(&short, attr, case, cluster, size));
+        }
+        entries
+    }
+
+    /// Place directory `dir`, in directory `parent`'s first cluster (0 for
+    /// the root), and everything under it; its first cluster.
+    fn place_dir(&mut self, dir: &BTreeMap<String, Node>, parent: u32) -> u32 {
+        // Its clusters come before what it holds: reserve them now, fill
+        // them in once the entries are known.
+        let own = self.place(&vec![0; dir_clusters(dir) * CLUSTER]);
+        let mut entries = vec![
+            short_entry(b".          ", ATTR_DIRECTORY, 0, own, 0),
+            short_entry(b"..         ", ATTR_DIRECTORY, 0, parent, 0),
+        ];
+        entries.extend(self.place_entries(dir, own));
+        let start = (self.data_start + (own as usize - 2) * SECTORS_PER_CLUSTER) * SECTOR;
+        let bytes = entries.concat();
+        self.bytes[start..start + bytes.len()].copy_from_slice(&bytes);
+        own
+    }
+}
+
+/// A short name for `name` in a directory holding `taken`, as the kernel
+/// picks one: `name` itself if it is 8.3 in one case (and the case bits
+/// saying which), else one like `LONGNA~1.TXT`, with a long name.
+fn short_name_for(name: &str, taken: &[[u8; 11]]) -> ([u8; 11], u8, bool) {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let valid = |c: u8| c.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&c);
+    let one_case = |part: &str| !(part.bytes().any(|c| c.is_ascii_lowercase()) && part.bytes().any(|c| c.is_ascii_uppercase()));
+    let mut short = [b' '; 11];
+    if (1..=8).contains(&base.len()) && ext.len() <= 3 && base.bytes().chain(ext.bytes()).all(valid) && one_case(base) && one_case(ext) {
+        short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+        let mut case = 0;
+        if base.bytes().any(|c| c.is_ascii_lowercase()) {
+            case |= CASE_LOWER_BASE;
+        }
+        if ext.bytes().any(|c| c.is_ascii_lowercase()) {
+            case |= CASE_LOWER_EXT;
+        }
+        return (short, case, false);
+    }
+    let squash = |part: &str, max: usize| -> Vec<u8> { part.bytes().filter(|&c| valid(c)).map(|c| c.to_ascii_uppercase()).take(max).collect() };
+    let mut basis = squash(base, 6);
+    if basis.is_empty() {
+        basis.push(b'_');
+    }
+    let ext = squash(ext, 3);
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let keep = basis.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            break;
+        }
+    }
+    (short, 0, true)
+}
+
+fn short_entry(name: &[u8; 11], attr: u8, case: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let (date, time) = TIMESTAMP;
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[12] = case;
+    for at in [14, 22] {
+        entry[at..at + 2].copy_from_slice(&time.to_le_bytes());
+        entry[at + 2..at + 4].copy_from_slice(&date.to_le_bytes());
+    }
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// The long-name entries for `name` on short name `short`, in disk order:
+/// the end of the name first.
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    units.push(0);
+    units.resize(units.len().div_ceil(LONG_CHARS) * LONG_CHARS, 0xFFFF);
+    let count = units.len() / LONG_CHARS;
+    let checksum = short.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c));
+    let offsets: Vec<usize> = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2)).collect();
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = sequence as u8 | if sequence == count { LONG_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (unit, &offset) in units[(sequence - 1) * LONG_CHARS..].iter().zip(&offsets) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// `label` as the 11 bytes of a volume label: upper case, padded.
+fn label_name(label: &str) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    for (byte, c) in name.iter_mut().zip(label.to_ascii_uppercase().bytes()) {
+        *byte = c;
+    }
+    name
+}
+
+/// The boot sector: a jump over the BPB, the BPB for a volume of `total`
+/// sectors, the extended BPB with the label, and the signature.
+fn boot_sector(label: &str, total: usize, fat_sectors: usize) -> [u8; SECTOR] {
+    let mut boot = [0u8; SECTOR];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"TEACHME ");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = SECTORS_PER_CLUSTER as u8;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = FATS as u8;
+    boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    // Under 65536 sectors in the 16-bit count, else in the 32-bit one.
+    match u16::try_from(total) {
+        Ok(total) => boot[19..21].copy_from_slice(&total.to_le_bytes()),
+        Err(_) => boot[32..36].copy_from_slice(&(total as u32).to_le_bytes()),
+    }
+    boot[21] = 0xF8;
+    boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    boot[36] = 0x80;
+    boot[38] = 0x29;
+    boot[39..43].copy_from_slice(&SERIAL.to_le_bytes());
+    boot[43..54].copy_from_slice(&label_name(label));
+    boot[54..62].copy_from_slice(b"FAT16   ");
+    boot[510..].copy_from_slice(&[0x55, 0xAA]);
+    boot
+}
+
+/// The MBR: no boot code, one partition of `sectors` at `PARTITION_START`.
+fn mbr(sectors: usize) -> Vec<u8> {
+    let mut mbr = vec![0u8; SECTOR];
+    let entry = &mut mbr[446..462];
+    // CHS fields saying "use the LBA ones".
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = PARTITION_TYPE;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+    mbr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// A volume read back the way the kernel reads one: where things are
+    /// from the BPB, nothing from the builder's constants.
+    struct Read<'a> {
+        bytes: &'a [u8],
+        fat: usize,
+        root: usize,
+        root_entries: usize,
+        data: usize,
+        cluster: usize,
+    }
+
+    impl Read<'_> {
+        fn new(image: &[u8]) -> Read<'_> {
+            assert_eq!(image[510..512], [0x55, 0xAA]);
+            let partition = &image[446..462];
+            assert_eq!(partition[4], 0x0E);
+            let start = u32_at(partition, 8) as usize * 512;
+            let bytes = &image[start..start + u32_at(partition, 12) as usize * 512];
+
+            assert_eq!((bytes[510], bytes[511]), (0x55, 0xAA));
+            assert_eq!(&bytes[54..62], b"FAT16   ");
+            let sector = u16_at(bytes, 11) as usize;
+            let (reserved, fats, fat_sectors) = (u16_at(bytes, 14) as usize, bytes[16] as usize, u16_at(bytes, 22) as usize);
+            let root_entries = u16_at(bytes, 17) as usize;
+            let fat = reserved * sector;
+            // Every copy of the FAT the same.
+            let fat_bytes = fat_sectors * sector;
+            for copy in 1..fats {
+                assert_eq!(bytes[fat..fat + fat_bytes], bytes[fat + copy * fat_bytes..fat + (copy + 1) * fat_bytes]);
+            }
+            let root = fat + fats * fat_bytes;
+            let data = root + root_entries * 32;
+            Read { bytes, fat, root, root_entries, data, cluster: bytes[13] as usize * sector }
+        }
+
+        /// The clusters of the chain from `first`.
+        fn chain(&self, first: u32) -> Vec<u32> {
+            let mut chain = Vec::new();
+            let mut cluster = first;
+            while (2..0xFFF8).contains(&cluster) {
+                chain.push(cluster);
+                cluster = u16_at(self.bytes, self.fat + cluster as usize * 2) as u32;
+            }
+            assert_eq!(cluster, 0xFFFF, "chain from {} ends in {:#x}", first, cluster);
+            chain
+        }
+
+        /// The bytes of the chain from `first`, `size` of them if given.
+        fn contents(&self, first: u32, size: Option<usize>) -> Vec<u8> {
+            let mut bytes: Vec<u8> = self
+                .chain(first)
+                .iter()
+                .flat_map(|&cluster| {
+                    let at = self.data + (cluster as usize - 2) * self.cluster;
+                    self.bytes[at..at + self.cluster].to_vec()
+                })
+                .collect();
+            if let Some(size) = size {
+                assert_eq!(bytes.len(), size.div_ceil(self.cluster) * self.cluster);
+                bytes.truncate(size);
+            }
+            bytes
+        }
+
+        fn root(&self) -> Vec<(String, u8, u32, u32)> {
+            entries(&self.bytes[self.root..self.root + self.root_entries * 32])
+        }
+    }
+
+    /// The entries in `bytes`: name, attributes, first cluster and size.
+    /// A long name is taken only if its checksum is the short name's.
+    fn entries(bytes: &[u8]) -> Vec<(String, u8, u32, u32)> {
+        let offsets: Vec<usize> = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2)).collect();
+        let mut entries = Vec::new();
+        let mut long: (Vec<u16>, Option<u8>) = (Vec::new(), None);
+        for entry in bytes.as_chunks::<32>().0 {
+            if entry[0] == 0 {
+                break;
+            }
+            if entry[11] == 0x0F {
+                // The end of the name comes first.
+                let units: Vec<u16> = offsets.iter().map(|&at| u16_at(entry, at)).collect();
+                long.0.splice(0..0, units);
+                long.1 = Some(entry[13]);
+                continue;
+            }
+            let short = &entry[..11];
+            let name = match long.1.take() {
+                Some(checksum) => {
+                    let sum = short.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c));
+                    assert_eq!(checksum, sum, "long name checksum for {:?}", String::from_utf8_lossy(short));
+                    let units = core::mem::take(&mut long.0);
+                    String::from_utf16(&units[..units.iter().position(|&u| u == 0).unwrap_or(units.len())]).unwrap()
+                }
+                None => {
+                    let part = |bytes: &[u8], lower: bool| {
+                        let part = String::from_utf8(bytes.to_vec()).unwrap().trim_end().to_string();
+                        if lower { part.to_lowercase() } else { part }
+                    };
+                    let (base, ext) = (part(&short[..8], entry[12] & 0x08 != 0), part(&short[8..], entry[12] & 0x10 != 0));
+                    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+                }
+            };
+            entries.push((name, entry[11], u16_at(entry, 26) as u32, u32_at(entry, 28)));
+        }
+        entries
+    }
+
+    #[test]
+    fn reads_back() {
+        let long: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let files = vec![
+            (String::from("A file with a long name.txt"), long.clone()),
+            (String::from("docs/readme.md"), b"hello\n".to_vec()),
+        ];
+        let image = build("TeachMe", &files);
+        let volume = Read::new(&image);
+        assert_eq!(&volume.bytes[43..54], b"TEACHME    ");
+        assert_eq!(volume.cluster, CLUSTER);
+
+        let root = volume.root();
+        assert_eq!(root.len(), 3);
+        assert_eq!((root[0].0.as_str(), root[0].1), ("TEACHME", ATTR_VOLUME_ID));
+        let (name, attr, first, size) = &root[1];
+        assert_eq!((name.as_str(), *attr, *size), ("A file with a long name.txt", ATTR_ARCHIVE, 5000));
+        assert_eq!(volume.chain(*first).len(), 3);
+        assert_eq!(volume.contents(*first, Some(5000)), long);
+
+        let (name, attr, docs, _) = &root[2];
+        assert_eq!((name.as_str(), *attr), ("docs", ATTR_DIRECTORY));
+        let docs_entries = entries(&volume.contents(*docs, None));
+        let names: Vec<&str> = docs_entries.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(names, [".", "..", "readme.md"]);
+        assert_eq!((docs_entries[0].2, docs_entries[1].2), (*docs, 0));
+        let (_, attr, first, size) = &docs_entries[2];
+        assert_eq!((*attr, *size), (ATTR_ARCHIVE, 6));
+        assert_eq!(volume.contents(*first, Some(6)), b"hello\n");
+    }
+}
@@ -0,0 +1,324 @@
+//! A bootable ISO 9660 image with Rock Ridge, for `--cdrom`: the files
+//! are the initrd's, for the kernel to read from the disc, and El Torito
+//! boots it by emulating a hard disk from the BIOS image, which sits
+//! after the boot catalog. The kernel's `fs/iso9660.rs` describes the
+//! format.
+//!
+//! The layout, in 2 KiB sectors: 16 empty ones for the system, the
+//! primary volume descriptor, the El Torito boot record, the terminator,
+//! the boot catalog, the boot image, the path tables (little-endian, then
+//! big-endian), the directories a level at a time, then the files.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+const SECTOR: usize = 2048;
+const PRIMARY: usize = 16;
+const BOOT_RECORD: usize = 17;
+const TERMINATOR: usize = 18;
+const CATALOG: usize = 19;
+const BOOT_IMAGE: usize = 20;
+/// Where the root's record is in the primary descriptor.
+const ROOT_RECORD: usize = 156;
+const FLAG_DIRECTORY: u8 = 0x02;
+/// 1 January 1970, on every record: years since 1900, month, day, hour,
+/// minute, second, time zone.
+const DATE: [u8; 7] = [70, 1, 1, 0, 0, 0, 0];
+/// The longest name `NM` takes here: a record is at most 255 bytes.
+const MAX_NAME: usize = 160;
+const DIR_MODE: u32 = 0o040755;
+
+/// A file to put on the disc: path, mode (type and permissions), contents.
+pub type File = (String, u32, Vec<u8>);
+
+#[derive(Default)]
+struct Tree<'a> {
+    files: BTreeMap<&'a str, (u32, &'a [u8])>,
+    dirs: BTreeMap<&'a str, Tree<'a>>,
+}
+
+impl<'a> Tree<'a> {
+    fn add(&mut self, path: &'a str, mode: u32, contents: &'a [u8]) {
+        match path.split_once('/') {
+            Some((dir, rest)) => self.dirs.entry(dir).or_default().add(rest, mode, contents),
+            None => {
+                self.files.insert(path, (mode, contents));
+            }
+        }
+    }
+}
+
+/// A directory being laid out.
+struct Dir<'a> {
+    tree: &'a Tree<'a>,
+    /// Its parent's index in the list; the root is its own.
+    parent: usize,
+    /// Its ISO name; the root's is a 0 byte.
+    iso: Vec<u8>,
+    /// Real name, ISO name, mode, and what it is, in ISO name order.
+    entries: Vec<(&'a str, Vec<u8>, u32, Entry)>,
+    /// Bytes, a whole number of sectors.
+    size: usize,
+    sector: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Entry {
+    /// Its index in the list of directories.
+    Dir(usize),
+    /// Its index in the list of files.
+    File(usize),
+}
+
+/// An image of `files`, called `volume_id`, booting `boot_image` (a hard
+/// disk image with a partition table).
+pub fn build(volume_id: &str, files: &[File], boot_image: &[u8]) -> Vec<u8> {
+    let mut tree = Tree::default();
+    for (path, mode, contents) in files {
+        tree.add(path, *mode, contents);
+    }
+
+    // Directories in the path tables' order: a level at a time, each
+    // one's children by name.
+    let mut dirs = vec![Dir { tree: &tree, parent: 0, iso: vec![0], entries: Vec::new(), size: 0, sector: 0 }];
+    let mut contents: Vec<&[u8]> = Vec::new();
+    let mut i = 0;
+    while i < dirs.len() {
+        let tree = dirs[i].tree;
+        let mut taken = BTreeSet::new();
+        let mut entries = Vec::new();
+        for &name in tree.dirs.keys() {
+            entries.push((name, iso_name(name, true, &mut taken), DIR_MODE, None));
+        }
+        for (&name, &(mode, data)) in &tree.files {
+            entries.push((name, iso_name(name, false, &mut taken), mode, Some(data)));
+        }
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+        for (name, iso, mode, data) in entries {
+            let entry = match data {
+                Some(data) => {
+                    contents.push(data);
+                    Entry::File(contents.len() - 1)
+                }
+                None => {
+                    dirs.push(Dir { tree: &tree.dirs[name], parent: i, iso: iso.clone(), entries: Vec::new(), size: 0, sector: 0 });
+                    Entry::Dir(dirs.len() - 1)
+                }
+            };
+            dirs[i].entries.push((name, iso, mode, entry));
+        }
+        i += 1;
+    }
+
+    // Records are as long whatever they point to, so sizes come first.
+    let mut placed: Vec<(usize, usize)> = contents.iter().map(|data| (0, data.len())).collect();
+    for i in 0..dirs.len() {
+        dirs[i].size = directory(&dirs, i, &placed).len();
+    }
+    let boot_sectors = boot_image.len().div_ceil(SECTOR);
+    let table_len = path_table(&dirs, false).len();
+    let table_sectors = table_len.div_ceil(SECTOR);
+    let mut next = BOOT_IMAGE + boot_sectors + 2 * table_sectors;
+    for dir in dirs.iter_mut() {
+        dir.sector = next;
+        next += dir.size / SECTOR;
+    }
+    for (sector, len) in placed.iter_mut() {
+        if *len > 0 {
+            *sector = next;
+            next += len.div_ceil(SECTOR);
+        }
+    }
+
+    let mut image = vec![0u8; next * SECTOR];
+    let mut put = |sector: usize, data: &[u8]| image[sector * SECTOR..sector * SECTOR + data.len()].copy_from_slice(data);
+    put(PRIMARY, &primary(volume_id, next, &dirs[0], table_len, BOOT_IMAGE + boot_sectors, table_sectors));
+    put(BOOT_RECORD, &boot_record());
+    put(TERMINATOR, b"\xFFCD001\x01");
+    put(CATALOG, &catalog(boot_image));
+    put(BOOT_IMAGE, boot_image);
+    put(BOOT_IMAGE + boot_sectors, &path_table(&dirs, false));
+    put(BOOT_IMAGE + boot_sectors + table_sectors, &path_table(&dirs, true));
+    for i in 0..dirs.len() {
+        put(dirs[i].sector, &directory(&dirs, i, &placed));
+    }
+    for (&(sector, _), data) in placed.iter().zip(&contents) {
+        put(sector, data);
+    }
+    image
+}
+
+/// `name` as ISO 9660 level 1 has it, not one in `taken`: capitals,
+/// digits and `_`, eight of them, then for a file a dot, three more and
+/// the version, `README.TXT;1`. A clash ends in `~1`, `~2`, ...
+fn iso_name(name: &str, dir: bool, taken: &mut BTreeSet<Vec<u8>>) -> Vec<u8> {
+    let clean = |part: &str, max: usize| -> String {
+        part.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).take(max).collect()
+    };
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !dir && !stem.is_empty() => (clean(stem, 8), clean(ext, 3)),
+        _ => (clean(name, 8), String::new()),
+    };
+    for n in 0.. {
+        let stem = match n {
+            0 => stem.clone(),
+            n => {
+                let suffix = format!("~{}", n);
+                format!("{}{}", &stem[..stem.len().min(8 - suffix.len())], suffix)
+            }
+        };
+        let iso = if dir { stem } else { format!("{}.{};1", stem, ext) };
+        if taken.insert(iso.clone().into_bytes()) {
+            return iso.into_bytes();
+        }
+    }
+    unreachable!()
+}
+
+fn both16(value: u16) -> [u8; 4] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [le[0], le[1], be[0], be[1]]
+}
+
+fn both32(value: u32) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    bytes[4..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+/// A directory record for `iso`, with `system_use` after the name.
+fn record(iso: &[u8], sector: usize, size: usize, dir: bool, system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; 33];
+    record[2..10].copy_from_slice(&both32(sector as u32));
+    record[10..18].copy_from_slice(&both32(size as u32));
+    record[18..25].copy_from_slice(&DATE);
+    record[25] = if dir { FLAG_DIRECTORY } else { 0 };
+    record[28..32].copy_from_slice(&both16(1));
+    record[32] = iso.len() as u8;
+    record.extend_from_slice(iso);
+    if iso.len().is_multiple_of(2) {
+        record.push(0);
+    }
+    record.extend_from_slice(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    assert!(record.len() <= 255, "directory record too long");
+    record[0] = record.len() as u8;
+    record
+}
+
+/// A Rock Ridge (SUSP) entry.
+fn entry(signature: &[u8; 2], data: &[u8]) -> Vec<u8> {
+    [&signature[..], &[4 + data.len() as u8, 1], data].concat()
+}
+
+fn px(mode: u32) -> Vec<u8> {
+    entry(b"PX", &[both32(mode), both32(1), both32(0), both32(0)].concat())
+}
+
+fn nm(name: &str) -> Vec<u8> {
+    assert!(name.len() <= MAX_NAME, "{}: name too long for the disc", name);
+    entry(b"NM", &[&[0], name.as_bytes()].concat())
+}
+
+/// Directory `i`'s contents, the records packed so that none crosses a
+/// sector; `files` has each file's sector and length. `.` of the root
+/// starts with `SP`, saying there is Rock Ridge.
+fn directory(dirs: &[Dir], i: usize, files: &[(usize, usize)]) -> Vec<u8> {
+    let (dir, parent) = (&dirs[i], &dirs[dirs[i].parent]);
+    let dot = if i == 0 { [entry(b"SP", &[0xBE, 0xEF, 0]), px(DIR_MODE)].concat() } else { px(DIR_MODE) };
+    let mut records = vec![record(&[0], dir.sector, dir.size, true, &dot), record(&[1], parent.sector, parent.size, true, &px(DIR_MODE))];
+    for (name, iso, mode, what) in &dir.entries {
+        let system_use = [nm(name), px(*mode)].concat();
+        records.push(match *what {
+            Entry::Dir(j) => record(iso, dirs[j].sector, dirs[j].size, true, &system_use),
+            Entry::File(k) => record(iso, files[k].0, files[k].1, false, &system_use),
+        });
+    }
+    let mut data = Vec::new();
+    for record in records {
+        if data.len() % SECTOR + record.len() > SECTOR {
+            data.resize(data.len().next_multiple_of(SECTOR), 0);
+        }
+        data.extend_from_slice(&record);
+    }
+    data.resize(data.len().next_multiple_of(SECTOR), 0);
+    data
+}
+
+/// Each directory's ISO name, sector and parent's number (counting from
+/// 1), in order; in `big_endian` for the M table.
+fn path_table(dirs: &[Dir], big_endian: bool) -> Vec<u8> {
+    let mut table = Vec::new();
+    for dir in dirs {
+        let (sector, parent) = (dir.sector as u32, dir.parent as u16 + 1);
+        table.extend_from_slice(&[dir.iso.len() as u8, 0]);
+        if big_endian {
+            table.extend_from_slice(&sector.to_be_bytes());
+            table.extend_from_slice(&parent.to_be_bytes());
+        } else {
+            table.extend_from_slice(&sector.to_le_bytes());
+            table.extend_from_slice(&parent.to_le_bytes());
+        }
+        table.extend_from_slice(&dir.iso);
+        if dir.iso.len() % 2 == 1 {
+            table.push(0);
+        }
+    }
+    table
+}
+
+/// The primary volume descriptor, for a disc of `sectors`. The path tables
+/// are `table_len` bytes each, the L one at `tables` and the M one
+/// `table_sectors` after.
+fn primary(volume_id: &str, sectors: usize, root: &Dir, table_len: usize, tables: usize, table_sectors: usize) -> Vec<u8> {
+    let mut pvd = vec![0u8; SECTOR];
+    pvd[..7].copy_from_slice(b"\x01CD001\x01");
+    pvd[8..72].fill(b' ');
+    pvd[40..40 + volume_id.len().min(32)].copy_from_slice(&volume_id.as_bytes()[..volume_id.len().min(32)]);
+    pvd[80..88].copy_from_slice(&both32(sectors as u32));
+    pvd[120..124].copy_from_slice(&both16(1));
+    pvd[124..128].copy_from_slice(&both16(1));
+    pvd[128..132].copy_from_slice(&both16(SECTOR as u16));
+    pvd[132..140].copy_from_slice(&both32(table_len as u32));
+    pvd[140..144].copy_from_slice(&(tables as u32).to_le_bytes());
+    pvd[148..152].copy_from_slice(&((tables + table_sectors) as u32).to_be_bytes());
+    pvd[ROOT_RECORD..ROOT_RECORD + 34].copy_from_slice(&record(&[0], root.sector, root.size, true, &[]));
+    // The set, publisher, preparer and application, then the file names.
+    pvd[190..813].fill(b' ');
+    // Created, modified, expires, effective: none given.
+    for date in (813..881).step_by(17) {
+        pvd[date..date + 16].fill(b'0');
+    }
+    pvd[881] = 1;
+    pvd
+}
+
+/// El Torito's boot record: where the catalog is.
+fn boot_record() -> Vec<u8> {
+    let mut record = vec![0u8; SECTOR];
+    record[..7].copy_from_slice(b"\x00CD001\x01");
+    record[7..7 + 23].copy_from_slice(b"EL TORITO SPECIFICATION");
+    record[71..75].copy_from_slice(&(CATALOG as u32).to_le_bytes());
+    record
+}
+
+/// The boot catalog: a validation entry, whose words sum to 0, then the
+/// default entry, emulating a hard disk from the image at `BOOT_IMAGE`.
+/// Its system type is the first partition's.
+fn catalog(boot_image: &[u8]) -> Vec<u8> {
+    let mut catalog = vec![0u8; 64];
+    catalog[0] = 1;
+    catalog[30..32].copy_from_slice(&[0x55, 0xAA]);
+    let sum = catalog[..32].chunks(2).fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+    catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    let entry = &mut catalog[32..];
+    entry[0] = 0x88;
+    entry[1] = 4;
+    entry[4] = boot_image[0x1BE + 4];
+    entry[6..8].copy_from_slice(&1u16.to_le_bytes());
+    entry[8..12].copy_from_slice(&(BOOT_IMAGE as u32).to_le_bytes());
+    catalog
+}
@@ -1,13 +1,36 @@
 use std::env;
-use std::process::{Command, Stdio};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// build.rs isn't built for `cargo test`: this brings its FAT image
+// builder's tests along.
+#[cfg(test)]
+#[path = "../build/fat.rs"]
+mod fat;
 
 fn main() {
     let bios_img = env!("BIOS_IMAGE");
     let uefi_img = env!("UEFI_IMAGE");
+    let iso_img = env!("ISO_IMAGE");
+    // --cdrom boots from the CD instead (BIOS only): SeaBIOS emulates a
+    // hard disk from the boot image on it, and the kernel finds the disc on
+    // the IDE secondary master as cd0 and mounts it at /mnt/cd0.
+    let cdrom = env::args().any(|arg| arg == "--cdrom");
+    // --crash-test boots over and over on the same scratch disk, killing
+    // QEMU while the kernel writes to it, and has the kernel check the
+    // volume at the next boot (QEMU_CRASH_ROUNDS kills, default 5).
+    let crash_test = env::args().any(|arg| arg == "--crash-test");
 
     // Prefer UEFI if OVMF is available (set OVMF_PATH if needed)
-    let ovmf_path = env::var("OVMF_PATH").ok();
-    let headless = env::var("QEMU_HEADLESS").is_ok();
+    let ovmf_path = env::var("OVMF_PATH").ok().filter(|_| !cdrom);
+    let headless = env::var("QEMU_HEADLESS").is_ok() || crash_test;
+    // By default a guest reset exits QEMU; set QEMU_ALLOW_REBOOT to really restart.
+    let allow_reboot = env::var("QEMU_ALLOW_REBOOT").is_ok();
 
     let mut cmd;
     if let Some(ovmf) = ovmf_path {
@@ -18,24 +41,154 @@ fn main() {
             "-m", "256M",
             "-machine", "q35",
             "-serial", "stdio",
-            "-no-reboot",
-            "-no-shutdown",
         ]);
         if headless { cmd.arg("-nographic"); } else { cmd.args(&["-vga","std"]); }
     } else {
         cmd = Command::new("qemu-system-x86_64");
+        if cdrom {
+            cmd.args(["-cdrom", iso_img, "-boot", "order=d"]);
+        } else {
+            cmd.args(["-drive", &format!("format=raw,file={}", bios_img), "-boot", "order=c"]);
+        }
         cmd.args([
-            "-drive", &format!("format=raw,file={}", bios_img),
             "-m", "256M",
             "-machine", "pc",
-            "-boot", "order=c",
             "-serial", "stdio",
-            "-no-reboot",
-            "-no-shutdown",
         ]);
         if headless { cmd.arg("-nographic"); } else { cmd.args(&["-vga","std"]); }
     }
+    if !allow_reboot { cmd.arg("-no-reboot"); }
+    // QEMU_SMP=<n> gives the guest n CPUs; the kernel starts the extra ones at boot.
+    if let Ok(cpus) = env::var("QEMU_SMP") {
+        if env::var("QEMU_NUMA").is_err() { cmd.args(["-smp", &cpus]); }
+    }
+    // QEMU_NUMA splits the 256M and two CPUs into two nodes, so the firmware publishes an SRAT.
+    if env::var("QEMU_NUMA").is_ok() {
+        cmd.args([
+            "-smp", "2",
+            "-object", "memory-backend-ram,id=ram0,size=128M",
+            "-object", "memory-backend-ram,id=ram1,size=128M",
+            "-numa", "node,nodeid=0,cpus=0,memdev=ram0",
+            "-numa", "node,nodeid=1,cpus=1,memdev=ram1",
+        ]);
+    }
+    // A virtio disk (vd0 in the kernel): QEMU_DISK=<file>, or a blank one
+    // kept next to the boot images, so what the guest writes stays there.
+    // The crash test starts from a blank crash.img each time.
+    let disk = if crash_test {
+        let _ = fs::remove_file(Path::new(bios_img).with_file_name("crash.img"));
+        scratch_disk(Path::new(bios_img), "crash.img")
+    } else {
+        env::var_os("QEMU_DISK").map(PathBuf::from).unwrap_or_else(|| scratch_disk(Path::new(bios_img), "disk.img"))
+    };
+    cmd.args([
+        "-drive", &format!("if=none,id=vd0,format=raw,file={}", disk.display()),
+        "-device", "virtio-blk-pci,drive=vd0",
+    ]);
+    // The data disk (vd1): a FAT volume of ../data, which the kernel mounts
+    // at /mnt/vd1p1. snapshot=on drops what the guest writes to it when
+    // QEMU exits, so every boot starts from the files in the repository.
+    cmd.args([
+        "-drive", &format!("if=none,id=vd1,format=raw,snapshot=on,file={}", env!("DATA_IMAGE")),
+        "-device", "virtio-blk-pci,drive=vd1",
+    ]);
+    // QEMU_NVME adds an NVMe disk (nvme0): a blank nvme.img beside the
+    // boot images with QEMU_NVME=1, else the file it names.
+    if let Some(nvme) = env::var_os("QEMU_NVME") {
+        let nvme = if nvme == "1" { scratch_disk(Path::new(bios_img), "nvme.img") } else { PathBuf::from(nvme) };
+        cmd.args([
+            "-drive", &format!("if=none,id=nvm,format=raw,file={}", nvme.display()),
+            "-device", "nvme,serial=teachme,drive=nvm",
+        ]);
+    }
+    // Kernel command line (e.g. KERNEL_CMDLINE=nokaslr), read by the kernel via fw_cfg.
+    // QEMU's option parser needs commas doubled.
+    let mut cmdline = env::var("KERNEL_CMDLINE").unwrap_or_default();
+    if crash_test {
+        cmdline = format!("{} crashtest=vd0", cmdline).trim_start().to_string();
+    }
+    if !cmdline.is_empty() {
+        cmd.args(["-fw_cfg", &format!("name=opt/teachme/cmdline,string={}", cmdline.replace(',', ",,"))]);
+    }
 
+    if crash_test {
+        let rounds = env::var("QEMU_CRASH_ROUNDS").ok().and_then(|rounds| rounds.parse().ok()).unwrap_or(CRASH_ROUNDS);
+        return crash_test_run(&mut cmd, rounds);
+    }
     let status = cmd.status().expect("failed to start qemu");
     eprintln!("QEMU exited with: {status}");
-}
\ No newline at end of file
+}
+
+/// Kills the crash test makes when QEMU_CRASH_ROUNDS doesn't say.
+const CRASH_ROUNDS: u32 = 5;
+/// How long a boot may take to get to writing.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Boot `cmd` `rounds` times, killing QEMU a while after the kernel says
+/// it is writing, then once more to see the last check; each boot's check
+/// of the volume, and a summary. Exits with 1 unless every boot found the
+/// volume consistent.
+fn crash_test_run(cmd: &mut Command, rounds: u32) {
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+    let mut checks = Vec::new();
+    for boot in 0..=rounds {
+        let mut child = cmd.spawn().expect("failed to start qemu");
+        let stdout = child.stdout.take().expect("qemu stdout");
+        let (lines, received) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if lines.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut deadline = Instant::now() + BOOT_TIMEOUT;
+        let checked = checks.len();
+        // Until time's up, or QEMU is gone.
+        while let Ok(line) = received.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            println!("{}", line);
+            if line.contains(": check: ") {
+                checks.push(format!("boot {}: {}", boot, line));
+            }
+            if line.contains(": writing") {
+                // Somewhere in the middle of a write, not always the same
+                // place; the last boot only checks.
+                let delay = if boot == rounds { 0 } else { 100 + crash_delay() % 1500 };
+                deadline = Instant::now() + Duration::from_millis(delay);
+            }
+        }
+        if checks.len() == checked {
+            checks.push(format!("boot {}: no check", boot));
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        eprintln!("crash test: boot {} of {}: QEMU killed", boot, rounds);
+    }
+    eprintln!("crash test: {} kills", rounds);
+    for check in &checks {
+        eprintln!("  {}", check);
+    }
+    let consistent = checks.iter().filter(|check| check.ends_with(": consistent")).count();
+    eprintln!("crash test: {} of {} boots found the volume consistent", consistent, rounds + 1);
+    if consistent < checks.len() {
+        process::exit(1);
+    }
+}
+
+/// A number that differs from call to call, to kill QEMU at.
+fn crash_delay() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |now| now.subsec_nanos() as u64 / 1000)
+}
+
+/// Size of the blank disks made when QEMU_DISK or QEMU_NVME doesn't name one.
+const SCRATCH_DISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// `name` beside `image`, made (all zeros) if it isn't there.
+fn scratch_disk(image: &Path, name: &str) -> PathBuf {
+    let path = image.with_file_name(name);
+    if !path.exists() {
+        let file = File::create(&path).expect("create disk image");
+        file.set_len(SCRATCH_DISK_SIZE).expect("size disk image");
+    }
+    path
+}
@@ -0,0 +1,447 @@
+//! The on-disk format, as the kernel's `fs/tfs.rs` lays it out, over an
+//! image held whole in memory.
+
+pub const BLOCK_SIZE: usize = 1024;
+pub const MAGIC: &[u8; 4] = b"TFS1";
+/// The magic and ten fields.
+pub const SUPERBLOCK_SIZE: usize = 44;
+pub const INODE_SIZE: usize = 64;
+pub const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+pub const BITS_PER_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+pub const DIRECT: usize = 12;
+pub const POINTERS: usize = BLOCK_SIZE / 4;
+pub const MAX_FILE_SIZE: u64 = ((DIRECT + POINTERS) * BLOCK_SIZE) as u64;
+pub const ENTRY_SIZE: usize = 32;
+pub const MAX_NAME: usize = ENTRY_SIZE - 4;
+pub const ROOT: u32 = 1;
+/// What the kernel's `format` makes too: an inode per this many bytes,
+/// and a journal of this many blocks.
+pub const BYTES_PER_INODE: u64 = 4096;
+pub const JOURNAL_BLOCKS: u32 = 32;
+pub const JOURNAL_MAGIC: &[u8; 4] = b"JRNL";
+/// Where the block numbers start in the journal header.
+pub const JOURNAL_HOMES: usize = 16;
+
+pub const KIND_FREE: u16 = 0;
+pub const KIND_FILE: u16 = 1;
+pub const KIND_DIR: u16 = 2;
+
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Block 0. The fields in the order they are stored, after the magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    pub block_count: u32,
+    pub inode_count: u32,
+    pub inode_bitmap: u32,
+    pub block_bitmap: u32,
+    pub inode_table: u32,
+    pub data_start: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    /// 0 without a journal.
+    pub journal: u32,
+    pub journal_blocks: u32,
+}
+
+impl Superblock {
+    /// The layout of a volume of `block_count` blocks, `inode_count`
+    /// inodes and a journal of `journal_blocks` (maybe 0), with nothing but
+    /// the root directory in it.
+    pub fn new(block_count: u32, inode_count: u32, journal_blocks: u32) -> Superblock {
+        let inode_bitmap = 1;
+        let block_bitmap = inode_bitmap + inode_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table = block_bitmap + block_count.div_ceil(BITS_PER_BLOCK);
+        let journal = inode_table + inode_count.div_ceil(INODES_PER_BLOCK);
+        let data_start = journal + journal_blocks;
+        Superblock {
+            block_count,
+            inode_count,
+            inode_bitmap,
+            block_bitmap,
+            inode_table,
+            data_start,
+            free_blocks: block_count.saturating_sub(data_start),
+            free_inodes: inode_count.saturating_sub(2),
+            journal: if journal_blocks > 0 { journal } else { 0 },
+            journal_blocks,
+        }
+    }
+
+    /// How many blocks a transaction may have.
+    pub fn journal_capacity(&self) -> usize {
+        (self.journal_blocks.saturating_sub(1) as usize).min((BLOCK_SIZE - JOURNAL_HOMES) / 4)
+    }
+
+    /// The superblock in `block`, if its magic is there and the regions
+    /// are where the two counts put them.
+    pub fn parse(block: &[u8]) -> Result<Superblock, String> {
+        if &block[..4] != MAGIC {
+            return Err(String::from("no TFS1 magic: not a tfs image"));
+        }
+        let field = |i: usize| u32_at(block, 4 + i * 4);
+        let sb = Superblock {
+            block_count: field(0),
+            inode_count: field(1),
+            inode_bitmap: field(2),
+            block_bitmap: field(3),
+            inode_table: field(4),
+            data_start: field(5),
+            free_blocks: field(6),
+            free_inodes: field(7),
+            journal: field(8),
+            journal_blocks: field(9),
+        };
+        let layout = Superblock::new(sb.block_count, sb.inode_count, sb.journal_blocks);
+        if (sb.inode_bitmap, sb.block_bitmap, sb.inode_table, sb.journal, sb.data_start)
+            != (layout.inode_bitmap, layout.block_bitmap, layout.inode_table, layout.journal, layout.data_start)
+        {
+            return Err(format!(
+                "regions at blocks {}, {}, {}, {}, {}; {} blocks, {} inodes and a journal of {} put them at {}, {}, {}, {}, {}",
+                sb.inode_bitmap,
+                sb.block_bitmap,
+                sb.inode_table,
+                sb.journal,
+                sb.data_start,
+                sb.block_count,
+                sb.inode_count,
+                sb.journal_blocks,
+                layout.inode_bitmap,
+                layout.block_bitmap,
+                layout.inode_table,
+                layout.journal,
+                layout.data_start
+            ));
+        }
+        if sb.inode_count < 2 || sb.data_start >= sb.block_count {
+            return Err(String::from("no room for the root inode or for data"));
+        }
+        Ok(sb)
+    }
+
+    pub fn to_bytes(self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut bytes = [0u8; SUPERBLOCK_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        let fields = [
+            self.block_count,
+            self.inode_count,
+            self.inode_bitmap,
+            self.block_bitmap,
+            self.inode_table,
+            self.data_start,
+            self.free_blocks,
+            self.free_inodes,
+            self.journal,
+            self.journal_blocks,
+        ];
+        for (i, field) in fields.into_iter().enumerate() {
+            put_u32(&mut bytes, 4 + i * 4, field);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Inode {
+    pub kind: u16,
+    pub links: u16,
+    pub size: u32,
+    pub direct: [u32; DIRECT],
+    pub indirect: u32,
+}
+
+impl Inode {
+    fn parse(bytes: &[u8]) -> Inode {
+        Inode {
+            kind: u16::from_le_bytes([bytes[0], bytes[1]]),
+            links: u16::from_le_bytes([bytes[2], bytes[3]]),
+            size: u32_at(bytes, 4),
+            direct: std::array::from_fn(|i| u32_at(bytes, 8 + i * 4)),
+            indirect: u32_at(bytes, 8 + DIRECT * 4),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; INODE_SIZE] {
+        let mut bytes = [0u8; INODE_SIZE];
+        bytes[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.links.to_le_bytes());
+        put_u32(&mut bytes, 4, self.size);
+        for (i, &block) in self.direct.iter().enumerate() {
+            put_u32(&mut bytes, 8 + i * 4, block);
+        }
+        put_u32(&mut bytes, 8 + DIRECT * 4, self.indirect);
+        bytes
+    }
+
+    /// Blocks its size covers.
+    pub fn block_count(&self) -> usize {
+        (self.size as u64).div_ceil(BLOCK_SIZE as u64) as usize
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            KIND_FREE => "free",
+            KIND_FILE => "file",
+            KIND_DIR => "dir",
+            _ => "?",
+        }
+    }
+}
+
+/// A volume: its superblock and the image's bytes.
+pub struct Volume {
+    pub sb: Superblock,
+    bytes: Vec<u8>,
+}
+
+impl Volume {
+    /// An empty volume filling `len` bytes, with `inodes` inodes or the
+    /// kernel's default, rounded up to a whole block of them, and an empty
+    /// journal of `journal_blocks` (0 for none).
+    pub fn format(len: u64, inodes: Option<u32>, journal_blocks: u32) -> Result<Volume, String> {
+        let block_count = u32::try_from(len / BLOCK_SIZE as u64).map_err(|_| String::from("image too large"))?;
+        let inodes = inodes.unwrap_or_else(|| (block_count as u64 * BLOCK_SIZE as u64 / BYTES_PER_INODE).clamp(16, u32::MAX as u64) as u32);
+        let sb = Superblock::new(block_count, inodes.max(2).next_multiple_of(INODES_PER_BLOCK), journal_blocks);
+        if sb.data_start >= block_count {
+            return Err(format!("{} blocks leave no room for data after {} of metadata", block_count, sb.data_start));
+        }
+        let mut volume = Volume { sb, bytes: vec![0u8; block_count as usize * BLOCK_SIZE] };
+        volume.bytes[..SUPERBLOCK_SIZE].copy_from_slice(&sb.to_bytes());
+        if journal_blocks > 0 {
+            volume.block_mut(sb.journal).copy_from_slice(&journal_header(0, &[], &[]));
+        }
+        volume.set_bit(sb.inode_bitmap, 0, true);
+        volume.set_bit(sb.inode_bitmap, ROOT, true);
+        for block in 0..sb.data_start {
+            volume.set_bit(sb.block_bitmap, block, true);
+        }
+        volume.set_inode(ROOT, &Inode { kind: KIND_DIR, links: 1, ..Inode::default() });
+        Ok(volume)
+    }
+
+    /// The volume in `bytes`, if its superblock is good and it fits.
+    pub fn load(bytes: Vec<u8>) -> Result<Volume, String> {
+        if bytes.len() < BLOCK_SIZE {
+            return Err(String::from("smaller than a block"));
+        }
+        let sb = Superblock::parse(&bytes[..BLOCK_SIZE])?;
+        if sb.block_count as u64 * BLOCK_SIZE as u64 > bytes.len() as u64 {
+            return Err(format!("{} blocks, but the image holds {}", sb.block_count, bytes.len() / BLOCK_SIZE));
+        }
+        Ok(Volume { sb, bytes })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Store `sb` in block 0.
+    pub fn set_superblock(&mut self, sb: Superblock) {
+        self.sb = sb;
+        self.bytes[..SUPERBLOCK_SIZE].copy_from_slice(&sb.to_bytes());
+    }
+
+    pub fn block(&self, block: u32) -> &[u8] {
+        let at = block as usize * BLOCK_SIZE;
+        &self.bytes[at..at + BLOCK_SIZE]
+    }
+
+    pub fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        let at = block as usize * BLOCK_SIZE;
+        &mut self.bytes[at..at + BLOCK_SIZE]
+    }
+
+    pub fn is_data_block(&self, block: u32) -> bool {
+        (self.sb.data_start..self.sb.block_count).contains(&block)
+    }
+
+    /// Where inode `ino` is, in bytes from the start of the image.
+    pub fn inode_offset(&self, ino: u32) -> usize {
+        self.sb.inode_table as usize * BLOCK_SIZE + ino as usize * INODE_SIZE
+    }
+
+    pub fn inode(&self, ino: u32) -> Inode {
+        let at = self.inode_offset(ino);
+        Inode::parse(&self.bytes[at..at + INODE_SIZE])
+    }
+
+    pub fn set_inode(&mut self, ino: u32, inode: &Inode) {
+        let at = self.inode_offset(ino);
+        self.bytes[at..at + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+    }
+
+    /// Bit `n` of the bitmap starting at block `start`.
+    pub fn bit(&self, start: u32, n: u32) -> bool {
+        self.bytes[start as usize * BLOCK_SIZE + n as usize / 8] & 1 << (n % 8) != 0
+    }
+
+    pub fn set_bit(&mut self, start: u32, n: u32, value: bool) {
+        let byte = &mut self.bytes[start as usize * BLOCK_SIZE + n as usize / 8];
+        if value {
+            *byte |= 1 << (n % 8);
+        } else {
+            *byte &= !(1 << (n % 8));
+        }
+    }
+
+    /// The block numbers in indirect block `block`.
+    pub fn pointers(&self, block: u32) -> Vec<u32> {
+        let bytes = self.block(block);
+        (0..POINTERS).map(|i| u32_at(bytes, i * 4)).collect()
+    }
+
+    /// The block holding each `BLOCK_SIZE` of `inode`'s size, 0 for a
+    /// hole, as the inode says; whether they are data blocks is up to the
+    /// caller to check.
+    pub fn file_blocks(&self, inode: &Inode) -> Vec<u32> {
+        let mut blocks = inode.direct.to_vec();
+        if inode.block_count() > DIRECT && self.is_data_block(inode.indirect) {
+            blocks.extend(self.pointers(inode.indirect));
+        }
+        blocks.resize(inode.block_count(), 0);
+        blocks
+    }
+
+    /// The contents of `inode`, a block outside the data area reading as
+    /// a hole.
+    pub fn read_file(&self, inode: &Inode) -> Vec<u8> {
+        let mut data = Vec::with_capacity(inode.block_count() * BLOCK_SIZE);
+        for block in self.file_blocks(inode) {
+            if self.is_data_block(block) {
+                data.extend_from_slice(self.block(block));
+            } else {
+                data.resize(data.len() + BLOCK_SIZE, 0);
+            }
+        }
+        data.truncate(inode.size as usize);
+        data
+    }
+
+    /// Every entry of directory `dir`, free ones included: inode number
+    /// (0 if free) and the name's bytes up to the first NUL.
+    pub fn slots(&self, dir: &Inode) -> Vec<(u32, Vec<u8>)> {
+        self.read_file(dir)
+            .as_chunks::<ENTRY_SIZE>()
+            .0
+            .iter()
+            .map(|entry| {
+                let name = &entry[4..];
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                (u32_at(entry, 0), name[..len].to_vec())
+            })
+            .collect()
+    }
+
+    /// Write `entry` as slot `slot` of directory `dir`, which must have a
+    /// block there.
+    pub fn set_slot(&mut self, dir: &Inode, slot: usize, entry: &[u8; ENTRY_SIZE]) {
+        let at = slot * ENTRY_SIZE;
+        let block = self.file_blocks(dir)[at / BLOCK_SIZE];
+        self.block_mut(block)[at % BLOCK_SIZE..at % BLOCK_SIZE + ENTRY_SIZE].copy_from_slice(entry);
+    }
+}
+
+/// The journal's header, as the kernel reads it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalHeader {
+    /// Transactions committed.
+    pub sequence: u32,
+    /// Where each of the blocks after the header goes; none once they are
+    /// in place.
+    pub homes: Vec<u32>,
+    /// Whether the checksum matches: if not, a crash tore the header
+    /// before the transaction committed.
+    pub whole: bool,
+}
+
+impl Volume {
+    /// The journal's header, if the volume has a journal and it has been
+    /// written.
+    pub fn journal_header(&self) -> Option<JournalHeader> {
+        let sb = self.sb;
+        if sb.journal_blocks == 0 {
+            return None;
+        }
+        let header = self.block(sb.journal);
+        if &header[..4] != JOURNAL_MAGIC {
+            return None;
+        }
+        let count = u32_at(header, 8) as usize;
+        if count > sb.journal_capacity() {
+            return Some(JournalHeader { sequence: u32_at(header, 4), homes: Vec::new(), whole: false });
+        }
+        let homes: Vec<u32> = (0..count).map(|i| u32_at(header, JOURNAL_HOMES + i * 4)).collect();
+        let copies = &self.bytes[(sb.journal as usize + 1) * BLOCK_SIZE..(sb.journal as usize + 1 + count) * BLOCK_SIZE];
+        let whole = journal_checksum(header, copies) == u32_at(header, 12);
+        Some(JournalHeader { sequence: u32_at(header, 4), homes, whole })
+    }
+
+    /// Put the blocks of a whole transaction in the journal in place, as
+    /// mounting does, and empty it; how many, or why not.
+    pub fn replay(&mut self) -> Result<usize, String> {
+        let Some(header) = self.journal_header().filter(|header| header.whole && !header.homes.is_empty()) else { return Ok(0) };
+        let sb = self.sb;
+        if let Some(home) = header.homes.iter().find(|&&home| home >= sb.block_count || (sb.journal..sb.data_start).contains(&home)) {
+            return Err(format!("journal: block {} goes outside the volume or into the journal", home));
+        }
+        for (i, &home) in header.homes.iter().enumerate() {
+            let copy = self.block(sb.journal + 1 + i as u32).to_vec();
+            self.block_mut(home).copy_from_slice(&copy);
+        }
+        self.block_mut(sb.journal).copy_from_slice(&journal_header(header.sequence, &[], &[]));
+        // The superblock may have been among the blocks.
+        self.sb = Superblock::parse(self.block(0))?;
+        Ok(header.homes.len())
+    }
+}
+
+/// The journal's header block for a transaction of `copies` going to
+/// `homes`, as the kernel writes it.
+pub fn journal_header(sequence: u32, homes: &[u32], copies: &[u8]) -> Vec<u8> {
+    let mut header = vec![0u8; BLOCK_SIZE];
+    header[..4].copy_from_slice(JOURNAL_MAGIC);
+    put_u32(&mut header, 4, sequence);
+    put_u32(&mut header, 8, homes.len() as u32);
+    for (i, &home) in homes.iter().enumerate() {
+        put_u32(&mut header, JOURNAL_HOMES + i * 4, home);
+    }
+    let checksum = journal_checksum(&header, copies);
+    put_u32(&mut header, 12, checksum);
+    header
+}
+
+/// FNV-1a of the header, its checksum taken as 0, and the copies.
+fn journal_checksum(header: &[u8], copies: &[u8]) -> u32 {
+    let bytes = header[..12].iter().chain(&[0; 4]).chain(&header[16..]).chain(copies);
+    bytes.fold(0x811C_9DC5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// A directory entry naming `ino` as `name`.
+pub fn entry(ino: u32, name: &str) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    put_u32(&mut entry, 0, ino);
+    entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+    entry
+}
+
+/// `numbers`, sorted, as runs: "0-259, 300, 302-303".
+pub fn ranges(numbers: impl IntoIterator<Item = u32>) -> String {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for n in numbers {
+        match runs.last_mut() {
+            Some((_, end)) if *end + 1 == n => *end = n,
+            _ => runs.push((n, n)),
+        }
+    }
+    let runs: Vec<String> = runs
+        .into_iter()
+        .map(|(start, end)| if start == end { format!("{}", start) } else { format!("{}-{}", start, end) })
+        .collect();
+    if runs.is_empty() { String::from("none") } else { runs.join(", ") }
+}
@@ -0,0 +1,84 @@
+//! Print what is on a volume, with where each piece sits in the image,
+//! to go with a hex dump of it.
+
+use crate::disk::{ranges, Volume, BLOCK_SIZE, DIRECT, KIND_DIR, KIND_FREE, ROOT};
+
+pub fn run(volume: &Volume) {
+    let sb = volume.sb;
+    let at = |block: u32| block as usize * BLOCK_SIZE;
+    println!("superblock: block 0");
+    println!("  blocks         {}", sb.block_count);
+    println!("  inodes         {}", sb.inode_count);
+    println!("  inode bitmap   blocks {}-{}, byte {:#x}", sb.inode_bitmap, sb.block_bitmap - 1, at(sb.inode_bitmap));
+    println!("  block bitmap   blocks {}-{}, byte {:#x}", sb.block_bitmap, sb.inode_table - 1, at(sb.block_bitmap));
+    let table_end = if sb.journal_blocks > 0 { sb.journal } else { sb.data_start };
+    println!("  inode table    blocks {}-{}, byte {:#x}", sb.inode_table, table_end - 1, at(sb.inode_table));
+    if sb.journal_blocks > 0 {
+        println!("  journal        blocks {}-{}, byte {:#x}", sb.journal, sb.data_start - 1, at(sb.journal));
+    }
+    println!("  data           blocks {}-{}, byte {:#x}", sb.data_start, sb.block_count - 1, at(sb.data_start));
+    println!("  free           {} blocks, {} inodes", sb.free_blocks, sb.free_inodes);
+    println!("inode bitmap: in use {}", ranges((0..sb.inode_count).filter(|&n| volume.bit(sb.inode_bitmap, n))));
+    println!("block bitmap: in use {}", ranges((0..sb.block_count).filter(|&n| volume.bit(sb.block_bitmap, n))));
+    match volume.journal_header() {
+        Some(header) if header.homes.is_empty() => println!("journal: empty, {} transactions", header.sequence),
+        Some(header) => {
+            let homes: Vec<String> = header.homes.iter().map(|home| home.to_string()).collect();
+            let state = if header.whole { "committed, not yet in place" } else { "torn, to be ignored" };
+            println!("journal: transaction {}, {}; blocks {}", header.sequence, state, homes.join(" "));
+        }
+        None if sb.journal_blocks > 0 => println!("journal: no header"),
+        None => println!("journal: none"),
+    }
+
+    println!("inodes:");
+    for ino in 1..sb.inode_count {
+        let inode = volume.inode(ino);
+        if inode.kind == KIND_FREE {
+            continue;
+        }
+        println!(
+            "  {:>5}  {:<4} links {} size {}, byte {:#x}",
+            ino,
+            inode.kind_name(),
+            inode.links,
+            inode.size,
+            volume.inode_offset(ino)
+        );
+        let blocks: Vec<String> = volume.file_blocks(&inode).iter().map(|block| block.to_string()).collect();
+        if !blocks.is_empty() {
+            println!("         blocks {}", blocks.join(" "));
+        }
+        if inode.indirect != 0 || inode.block_count() > DIRECT {
+            println!("         indirect block {}", inode.indirect);
+        }
+    }
+
+    println!("tree:");
+    println!("  / (inode {})", ROOT);
+    let mut seen = vec![ROOT];
+    tree(volume, ROOT, 2, &mut seen);
+}
+
+/// The entries of directory `ino` and below, indented by `depth`; a
+/// directory already in `seen` isn't gone into again.
+fn tree(volume: &Volume, ino: u32, depth: usize, seen: &mut Vec<u32>) {
+    let dir = volume.inode(ino);
+    for (slot, (child, name)) in volume.slots(&dir).into_iter().enumerate() {
+        if child == 0 {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&name);
+        let indent = "  ".repeat(depth);
+        if child >= volume.sb.inode_count {
+            println!("{}{} (inode {}: out of range), entry {}", indent, name, child, slot);
+            continue;
+        }
+        let inode = volume.inode(child);
+        println!("{}{} (inode {}, {}, {} bytes), entry {}", indent, name, child, inode.kind_name(), inode.size, slot);
+        if inode.kind == KIND_DIR && !seen.contains(&child) {
+            seen.push(child);
+            tree(volume, child, depth + 1, seen);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! Check a volume in five passes, as e2fsck does, repairing what's wrong
+//! in memory; whether the repairs are written back is up to the caller.
+//! First, as mounting does, a transaction committed to the journal is put
+//! in place.
+//!
+//! 1. Inodes: a known kind, a size a file can have, and block pointers
+//!    inside the size and the data area, no block claimed twice.
+//! 2. Directories, from the root down: entries naming inodes in use, by
+//!    good names, once each, and a directory named only once.
+//! 3. Connectivity: an inode in use that no directory names goes into
+//!    `/lost+found` as `#<inode>`.
+//! 4. Link counts: as many as the entries naming the inode.
+//! 5. Bitmaps and the superblock's free counts, from what the inodes use.
+//!
+//! With a journal, a crash leaves nothing behind once it is replayed (see
+//! the kernel's `fs/tfs.rs`). On a volume made without one, what a crash
+//! can leave only ever shows up in pass 5; anything else is damage.
+
+use std::collections::VecDeque;
+
+use crate::disk::{
+    entry, journal_header, put_u32, ranges, Inode, Superblock, Volume, BLOCK_SIZE, DIRECT, ENTRY_SIZE, KIND_DIR, KIND_FILE, KIND_FREE, MAX_FILE_SIZE,
+    MAX_NAME,
+    ROOT,
+};
+
+const LOST_FOUND: &str = "lost+found";
+
+/// Check and repair `volume`, printing each problem and what was done
+/// about it; how many there were.
+pub fn run(volume: &mut Volume) -> usize {
+    println!("journal");
+    let mut problems = journal(volume);
+    let sb = volume.sb;
+    let mut check = Check {
+        owner: vec![0; sb.block_count as usize],
+        refs: vec![0; sb.inode_count as usize],
+        volume,
+        problems: 0,
+    };
+    println!("pass 1: inodes");
+    check.inodes();
+    println!("pass 2: directories");
+    check.directories();
+    println!("pass 3: connectivity");
+    check.connectivity();
+    println!("pass 4: link counts");
+    check.links();
+    println!("pass 5: bitmaps");
+    check.bitmaps();
+    let sb = check.volume.sb;
+    println!(
+        "{} of {} inodes, {} of {} blocks in use",
+        sb.inode_count - sb.free_inodes,
+        sb.inode_count,
+        sb.block_count - sb.free_blocks,
+        sb.block_count
+    );
+    problems += check.problems;
+    problems
+}
+
+/// Replay the journal; 1 if it is damaged, which empties it.
+fn journal(volume: &mut Volume) -> usize {
+    let Some(header) = volume.journal_header() else { return 0 };
+    if !header.whole {
+        println!("  transaction {} never committed; ignored", header.sequence);
+    }
+    match volume.replay() {
+        Ok(0) => 0,
+        Ok(replayed) => {
+            println!("  put {} blocks in place from transaction {}", replayed, header.sequence);
+            0
+        }
+        Err(why) => {
+            println!("  {}; emptied", why);
+            let start = volume.sb.journal;
+            volume.block_mut(start).copy_from_slice(&journal_header(header.sequence, &[], &[]));
+            1
+        }
+    }
+}
+
+struct Check<'a> {
+    volume: &'a mut Volume,
+    problems: usize,
+    /// The inode each block belongs to, 0 for none, as the inodes say.
+    owner: Vec<u32>,
+    /// How many directory entries name each inode.
+    refs: Vec<u32>,
+}
+
+impl Check<'_> {
+    fn problem(&mut self, what: String) {
+        println!("  {}", what);
+        self.problems += 1;
+    }
+
+    fn kind(&self, ino: u32) -> u16 {
+        self.volume.inode(ino).kind
+    }
+
+    fn inodes(&mut self) {
+        for ino in 1..self.volume.sb.inode_count {
+            let mut inode = self.volume.inode(ino);
+            if inode.kind == KIND_FREE {
+                continue;
+            }
+            if inode.kind != KIND_FILE && inode.kind != KIND_DIR {
+                self.problem(format!("inode {}: unknown kind {}; cleared", ino, inode.kind));
+                self.volume.set_inode(ino, &Inode::default());
+                continue;
+            }
+            let before = inode;
+            if inode.size as u64 > MAX_FILE_SIZE {
+                self.problem(format!("inode {}: size {} is over the largest, {}; cut to it", ino, inode.size, MAX_FILE_SIZE));
+                inode.size = MAX_FILE_SIZE as u32;
+            }
+            if inode.kind == KIND_DIR && !(inode.size as usize).is_multiple_of(ENTRY_SIZE) {
+                let size = inode.size - inode.size % ENTRY_SIZE as u32;
+                self.problem(format!("inode {}: directory size {} is not whole entries; cut to {}", ino, inode.size, size));
+                inode.size = size;
+            }
+            let count = inode.block_count();
+            for (i, pointer) in inode.direct.iter_mut().enumerate() {
+                let block = *pointer;
+                if block == 0 {
+                    continue;
+                }
+                if let Some(why) = self.claim(ino, block, i < count) {
+                    self.problem(format!("inode {}: block {} of the file, {}, {}; cleared", ino, i, block, why));
+                    *pointer = 0;
+                }
+            }
+            if inode.indirect != 0 {
+                match self.claim(ino, inode.indirect, count > DIRECT) {
+                    Some(why) => {
+                        self.problem(format!("inode {}: indirect block {} {}; cleared", ino, inode.indirect, why));
+                        inode.indirect = 0;
+                    }
+                    None => self.indirect(ino, inode.indirect, count),
+                }
+            }
+            if inode != before {
+                self.volume.set_inode(ino, &inode);
+            }
+        }
+    }
+
+    /// The pointers in `ino`'s indirect block `block`, for a file of
+    /// `count` blocks.
+    fn indirect(&mut self, ino: u32, block: u32, count: usize) {
+        let mut pointers = self.volume.pointers(block);
+        let mut changed = false;
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            if *pointer == 0 {
+                continue;
+            }
+            if let Some(why) = self.claim(ino, *pointer, DIRECT + i < count) {
+                self.problem(format!("inode {}: block {} of the file, {}, {}; cleared", ino, DIRECT + i, pointer, why));
+                *pointer = 0;
+                changed = true;
+            }
+        }
+        if changed {
+            let bytes = self.volume.block_mut(block);
+            for (i, &pointer) in pointers.iter().enumerate() {
+                put_u32(bytes, i * 4, pointer);
+            }
+        }
+    }
+
+    /// Give `block` to `ino`, or why it can't have it.
+    fn claim(&mut self, ino: u32, block: u32, in_size: bool) -> Option<String> {
+        if !in_size {
+            return Some(String::from("is past the size"));
+        }
+        if !self.volume.is_data_block(block) {
+            return Some(String::from("is outside the data area"));
+        }
+        match self.owner[block as usize] {
+            0 => {
+                self.owner[block as usize] = ino;
+                None
+            }
+            other => Some(format!("is already inode {}'s", other)),
+        }
+    }
+
+    /// Give back every block `ino` has.
+    fn release(&mut self, ino: u32) {
+        for owner in self.owner.iter_mut().filter(|owner| **owner == ino) {
+            *owner = 0;
+        }
+    }
+
+    fn directories(&mut self) {
+        if self.kind(ROOT) != KIND_DIR {
+            self.problem(format!("inode {}, the root, is not a directory; made an empty one", ROOT));
+            self.release(ROOT);
+            self.volume.set_inode(ROOT, &Inode { kind: KIND_DIR, links: 1, ..Inode::default() });
+        }
+        self.refs[ROOT as usize] = 1;
+        self.walk(ROOT);
+    }
+
+    /// Scan directory `dir` and every directory under it.
+    fn walk(&mut self, dir: u32) {
+        let mut queue = VecDeque::from([dir]);
+        while let Some(dir) = queue.pop_front() {
+            self.scan(dir, &mut queue);
+        }
+    }
+
+    /// Check the entries of directory `dir`, counting the inodes they
+    /// name; directories named for the first time go on `queue`.
+    fn scan(&mut self, dir: u32, queue: &mut VecDeque<u32>) {
+        let inode = self.volume.inode(dir);
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        for (slot, (ino, name)) in self.volume.slots(&inode).into_iter().enumerate() {
+            if ino == 0 {
+                continue;
+            }
+            let why = if ino >= self.volume.sb.inode_count {
+                Some("the inode is out of range")
+            } else if ino == ROOT {
+                Some("it names the root")
+            } else if self.kind(ino) == KIND_FREE {
+                Some("the inode is free")
+            } else if !valid(&name) {
+                Some("the name is not one the kernel makes")
+            } else if names.contains(&name) {
+                Some("an earlier entry has the name")
+            } else if self.kind(ino) == KIND_DIR && self.refs[ino as usize] > 0 {
+                Some("the directory is named elsewhere already")
+            } else {
+                None
+            };
+            match why {
+                Some(why) => {
+                    let shown = String::from_utf8_lossy(&name).into_owned();
+                    self.problem(format!("directory {}, entry {} ({:?}, inode {}): {}; cleared", dir, slot, shown, ino, why));
+                    self.volume.set_slot(&inode, slot, &[0; ENTRY_SIZE]);
+                }
+                None => {
+                    names.push(name);
+                    self.refs[ino as usize] += 1;
+                    if self.kind(ino) == KIND_DIR {
+                        queue.push_back(ino);
+                    }
+                }
+            }
+        }
+    }
+
+    fn connectivity(&mut self) {
+        let lost: Vec<u32> = (ROOT + 1..self.volume.sb.inode_count)
+            .filter(|&ino| self.kind(ino) != KIND_FREE && self.refs[ino as usize] == 0)
+            .collect();
+        // Directories first, the ones no other lost directory names before
+        // the rest, so that what's under them comes back with them.
+        let (mut order, files): (Vec<u32>, Vec<u32>) = lost.into_iter().partition(|&ino| self.kind(ino) == KIND_DIR);
+        let named: Vec<u32> = order.iter().flat_map(|&dir| self.volume.slots(&self.volume.inode(dir))).map(|(ino, _)| ino).collect();
+        order.sort_by_key(|dir| named.contains(dir));
+        order.extend(files);
+
+        for ino in order {
+            if self.refs[ino as usize] != 0 {
+                continue;
+            }
+            let inode = self.volume.inode(ino);
+            let what = format!("inode {} ({}, {} bytes) is in no directory", ino, inode.kind_name(), inode.size);
+            match self.reconnect(ino) {
+                Ok(name) => {
+                    self.problem(format!("{}; reconnected as /{}/{}", what, LOST_FOUND, name));
+                    self.refs[ino as usize] = 1;
+                    if inode.kind == KIND_DIR {
+                        self.walk(ino);
+                    }
+                }
+                Err(why) => {
+                    self.problem(format!("{}; cleared, as {}", what, why));
+                    self.release(ino);
+                    self.volume.set_inode(ino, &Inode::default());
+                }
+            }
+        }
+    }
+
+    /// Name `ino` in `/lost+found`; the name.
+    fn reconnect(&mut self, ino: u32) -> Result<String, String> {
+        let dir = self.lost_found()?;
+        let name = format!("#{}", ino);
+        self.add_entry(dir, &name, ino)?;
+        Ok(name)
+    }
+
+    /// `/lost+found`, made if it isn't there.
+    fn lost_found(&mut self) -> Result<u32, String> {
+        let root = self.volume.inode(ROOT);
+        if let Some((ino, _)) = self.volume.slots(&root).into_iter().find(|(ino, name)| *ino != 0 && name == LOST_FOUND.as_bytes()) {
+            return match self.kind(ino) {
+                KIND_DIR => Ok(ino),
+                _ => Err(format!("/{} is not a directory", LOST_FOUND)),
+            };
+        }
+        let ino = (ROOT + 1..self.volume.sb.inode_count)
+            .find(|&ino| self.kind(ino) == KIND_FREE)
+            .ok_or_else(|| format!("there is no free inode for /{}", LOST_FOUND))?;
+        self.volume.set_inode(ino, &Inode { kind: KIND_DIR, links: 1, ..Inode::default() });
+        if let Err(why) = self.add_entry(ROOT, LOST_FOUND, ino) {
+            self.release(ino);
+            self.volume.set_inode(ino, &Inode::default());
+            return Err(why);
+        }
+        self.refs[ino as usize] = 1;
+        println!("  made /{}, inode {}", LOST_FOUND, ino);
+        Ok(ino)
+    }
+
+    /// Name `ino` as `name` in directory `dir`: in the first free entry
+    /// with a block under it, else at the end.
+    fn add_entry(&mut self, dir: u32, name: &str, ino: u32) -> Result<(), String> {
+        let mut inode = self.volume.inode(dir);
+        let blocks = self.volume.file_blocks(&inode);
+        let free = self
+            .volume
+            .slots(&inode)
+            .iter()
+            .enumerate()
+            .position(|(slot, (ino, _))| *ino == 0 && blocks[slot * ENTRY_SIZE / BLOCK_SIZE] != 0);
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                if inode.size as u64 + ENTRY_SIZE as u64 > MAX_FILE_SIZE {
+                    return Err(format!("directory {} is as large as a file gets", dir));
+                }
+                inode.size += ENTRY_SIZE as u32;
+                let index = (inode.size as usize - 1) / BLOCK_SIZE;
+                if self.volume.file_blocks(&inode)[index] == 0 {
+                    self.add_block(dir, &mut inode, index)?;
+                }
+                inode.size as usize / ENTRY_SIZE - 1
+            }
+        };
+        self.volume.set_slot(&inode, slot, &entry(ino, name));
+        self.volume.set_inode(dir, &inode);
+        Ok(())
+    }
+
+    /// Put a free block, zeroed, at `index` of `dir`'s file.
+    fn add_block(&mut self, dir: u32, inode: &mut Inode, index: usize) -> Result<(), String> {
+        if index < DIRECT {
+            inode.direct[index] = self.free_block(dir)?;
+            return Ok(());
+        }
+        if inode.indirect == 0 {
+            inode.indirect = self.free_block(dir)?;
+        }
+        let block = self.free_block(dir)?;
+        put_u32(self.volume.block_mut(inode.indirect), (index - DIRECT) * 4, block);
+        Ok(())
+    }
+
+    /// A data block no inode has, zeroed and given to `ino`.
+    fn free_block(&mut self, ino: u32) -> Result<u32, String> {
+        let sb = self.volume.sb;
+        let block = (sb.data_start..sb.block_count)
+            .find(|&block| self.owner[block as usize] == 0)
+            .ok_or_else(|| String::from("there is no free block"))?;
+        self.owner[block as usize] = ino;
+        self.volume.block_mut(block).fill(0);
+        Ok(block)
+    }
+
+    fn links(&mut self) {
+        for ino in 1..self.volume.sb.inode_count {
+            let mut inode = self.volume.inode(ino);
+            let refs = self.refs[ino as usize].min(u16::MAX as u32) as u16;
+            if inode.kind == KIND_FREE || inode.links == refs {
+                continue;
+            }
+            self.problem(format!("inode {}: links {}, entries naming it {}; set to {}", ino, inode.links, refs, refs));
+            inode.links = refs;
+            self.volume.set_inode(ino, &inode);
+        }
+    }
+
+    fn bitmaps(&mut self) {
+        let sb = self.volume.sb;
+        let inodes: Vec<bool> = (0..sb.inode_count).map(|ino| ino == 0 || self.kind(ino) != KIND_FREE).collect();
+        let blocks: Vec<bool> = (0..sb.block_count).map(|block| block < sb.data_start || self.owner[block as usize] != 0).collect();
+        self.bitmap("inode", sb.inode_bitmap, &inodes);
+        self.bitmap("block", sb.block_bitmap, &blocks);
+
+        let free_inodes = inodes.iter().filter(|used| !**used).count() as u32;
+        let free_blocks = blocks.iter().filter(|used| !**used).count() as u32;
+        if (sb.free_blocks, sb.free_inodes) != (free_blocks, free_inodes) {
+            self.problem(format!(
+                "superblock: {} free blocks and {} free inodes, but {} and {} are; set to those",
+                sb.free_blocks, sb.free_inodes, free_blocks, free_inodes
+            ));
+            self.volume.set_superblock(Superblock { free_blocks, free_inodes, ..sb });
+        }
+    }
+
+    /// Make the bitmap at block `start` say `used`.
+    fn bitmap(&mut self, what: &str, start: u32, used: &[bool]) {
+        let (marked, unmarked): (Vec<u32>, Vec<u32>) =
+            (0..used.len() as u32).filter(|&n| self.volume.bit(start, n) != used[n as usize]).partition(|&n| !used[n as usize]);
+        if !marked.is_empty() {
+            self.problem(format!("{} bitmap: {} marked in use but not; freed", what, ranges(marked.iter().copied())));
+        }
+        if !unmarked.is_empty() {
+            self.problem(format!("{} bitmap: {} in use but marked free; marked", what, ranges(unmarked.iter().copied())));
+        }
+        for n in marked.into_iter().chain(unmarked) {
+            self.volume.set_bit(start, n, used[n as usize]);
+        }
+    }
+}
+
+/// Whether the kernel's `create` would take `name`.
+fn valid(name: &[u8]) -> bool {
+    match std::str::from_utf8(name) {
+        Ok(name) => !name.is_empty() && name.len() <= MAX_NAME && name != "." && name != ".." && !name.contains('/'),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{JournalHeader, JOURNAL_BLOCKS};
+
+    const LEN: u64 = 1024 * 1024;
+
+    /// Check `volume`, then check it again: the problems the first run
+    /// found, once the second has found none.
+    fn repair(volume: &mut Volume) -> usize {
+        let problems = run(volume);
+        assert_eq!(run(volume), 0, "problems left after the repair");
+        problems
+    }
+
+    /// Make inode `ino` a file of one block, `block`, touching nothing
+    /// else: no bitmaps, no directory entry, no free counts.
+    fn file(volume: &mut Volume, ino: u32, block: u32) {
+        let mut direct = [0; DIRECT];
+        direct[0] = block;
+        volume.set_inode(ino, &Inode { kind: KIND_FILE, links: 1, size: BLOCK_SIZE as u32, direct, indirect: 0 });
+    }
+
+    /// The names in directory `dir` and the inodes they name.
+    fn names(volume: &Volume, dir: u32) -> Vec<(u32, String)> {
+        let inode = volume.inode(dir);
+        volume
+            .slots(&inode)
+            .into_iter()
+            .filter(|(ino, _)| *ino != 0)
+            .map(|(ino, name)| (ino, String::from_utf8(name).unwrap()))
+            .collect()
+    }
+
+    /// A copy of `volume` with file inode 2, of one block, named `a` in the
+    /// root, and its bitmaps and counts to match.
+    fn with_file(volume: &Volume) -> Volume {
+        let mut changed = Volume::load(volume.bytes().to_vec()).unwrap();
+        let (data, entries) = (changed.sb.data_start, changed.sb.data_start + 1);
+        let mut root = changed.inode(ROOT);
+        root.size = ENTRY_SIZE as u32;
+        root.direct[0] = entries;
+        changed.set_inode(ROOT, &root);
+        changed.set_slot(&root, 0, &entry(2, "a"));
+        file(&mut changed, 2, data);
+        repair(&mut changed);
+        changed
+    }
+
+    /// Commit to `volume`'s journal, as the kernel does, the blocks in
+    /// which `changed` differs from it.
+    fn commit(volume: &mut Volume, changed: &Volume) {
+        let journal = volume.sb.journal;
+        let homes: Vec<u32> = (0..volume.sb.block_count).filter(|&block| volume.block(block) != changed.block(block)).collect();
+        let copies: Vec<u8> = homes.iter().flat_map(|&block| changed.block(block).to_vec()).collect();
+        for (i, copy) in copies.chunks(BLOCK_SIZE).enumerate() {
+            volume.block_mut(journal + 1 + i as u32).copy_from_slice(copy);
+        }
+        volume.block_mut(journal).copy_from_slice(&journal_header(7, &homes, &copies));
+    }
+
+    #[test]
+    fn mkfs_is_clean() {
+        let image = std::env::temp_dir().join(format!("tfs-mkfs-{}.img", std::process::id()));
+        let path = image.to_str().unwrap();
+        crate::mkfs::run(path, Some(1), None).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut volume = Volume::load(bytes.clone()).unwrap();
+        assert_eq!(run(&mut volume), 0);
+        assert_eq!(volume.bytes(), bytes);
+    }
+
+    #[test]
+    fn reconnects_lost_files() {
+        let mut volume = Volume::format(LEN, None, JOURNAL_BLOCKS).unwrap();
+        let block = volume.sb.data_start;
+        file(&mut volume, 2, block);
+        assert!(repair(&mut volume) > 0);
+
+        let (lost, _) = names(&volume, ROOT).into_iter().find(|(_, name)| name == LOST_FOUND).unwrap();
+        assert_eq!(names(&volume, lost), [(2, String::from("#2"))]);
+        assert_eq!(volume.inode(2).direct[0], block);
+        assert!(volume.bit(volume.sb.inode_bitmap, 2) && volume.bit(volume.sb.block_bitmap, block));
+    }
+
+    #[test]
+    fn clears_bad_entries_and_shared_blocks() {
+        let mut volume = Volume::format(LEN, None, JOURNAL_BLOCKS).unwrap();
+        let (shared, entries) = (volume.sb.data_start, volume.sb.data_start + 1);
+        let mut root = volume.inode(ROOT);
+        root.size = 3 * ENTRY_SIZE as u32;
+        root.direct[0] = entries;
+        volume.set_inode(ROOT, &root);
+        volume.set_slot(&root, 0, &entry(9, "ghost"));
+        volume.set_slot(&root, 1, &entry(2, "a"));
+        volume.set_slot(&root, 2, &entry(3, "b"));
+        file(&mut volume, 2, shared);
+        file(&mut volume, 3, shared);
+        assert!(repair(&mut volume) > 0);
+
+        assert_eq!(names(&volume, ROOT), [(2, String::from("a")), (3, String::from("b"))]);
+        assert_eq!(volume.inode(2).direct[0], shared);
+        assert_eq!(volume.inode(3).direct[0], 0);
+        let sb = volume.sb;
+        assert_eq!(sb.free_blocks, sb.block_count - sb.data_start - 2);
+    }
+
+    #[test]
+    fn replays_committed_transactions() {
+        let mut volume = Volume::format(LEN, None, JOURNAL_BLOCKS).unwrap();
+        let changed = with_file(&volume);
+        commit(&mut volume, &changed);
+        assert_eq!(run(&mut volume), 0);
+        assert_eq!(run(&mut volume), 0);
+
+        assert_eq!(names(&volume, ROOT), [(2, String::from("a"))]);
+        assert_eq!(volume.sb, changed.sb);
+        assert_eq!(volume.journal_header(), Some(JournalHeader { sequence: 7, homes: Vec::new(), whole: true }));
+    }
+
+    #[test]
+    fn ignores_torn_headers() {
+        let mut volume = Volume::format(LEN, None, JOURNAL_BLOCKS).unwrap();
+        let before = volume.bytes().to_vec();
+        let changed = with_file(&volume);
+        commit(&mut volume, &changed);
+        let journal = volume.sb.journal;
+        volume.block_mut(journal + 1)[0] ^= 1;
+        assert!(!volume.journal_header().unwrap().whole);
+        assert_eq!(run(&mut volume), 0);
+
+        assert_eq!(names(&volume, ROOT), []);
+        let (start, end) = (journal as usize * BLOCK_SIZE, volume.sb.data_start as usize * BLOCK_SIZE);
+        assert_eq!(volume.bytes()[..start], before[..start]);
+        assert_eq!(volume.bytes()[end..], before[end..]);
+    }
+
+    #[test]
+    fn empties_journals_going_outside_the_volume() {
+        let mut volume = Volume::format(LEN, None, JOURNAL_BLOCKS).unwrap();
+        let (journal, outside) = (volume.sb.journal, volume.sb.block_count + 5);
+        let copy = vec![0xAB; BLOCK_SIZE];
+        volume.block_mut(journal + 1).copy_from_slice(&copy);
+        volume.block_mut(journal).copy_from_slice(&journal_header(7, &[outside], &copy));
+        assert_eq!(repair(&mut volume), 1);
+        assert_eq!(volume.journal_header(), Some(JournalHeader { sequence: 7, homes: Vec::new(), whole: true }));
+    }
+}
@@ -0,0 +1,98 @@
+//! Make, check and look into images of the teaching filesystem, the one
+//! the kernel mounts from `fs/tfs.rs` (whose doc comment describes the
+//! format):
+//!
+//!     cargo run -p tfs -- mkfs <image> [--size <MiB>] [--inodes <count>]
+//!     cargo run -p tfs -- fsck [-y] <image>
+//!     cargo run -p tfs -- dump <image>
+//!
+//! Boot with the image as the data disk (`QEMU_DISK=<image> cargo run -p
+//! runner`) and the kernel mounts it at `/mnt/vd0`. To see `fsck` at work,
+//! change a few bytes where `dump` says something is (a bitmap, an inode,
+//! a directory entry) and check the image again.
+
+mod disk;
+mod dump;
+mod fsck;
+mod mkfs;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use disk::Volume;
+
+const USAGE: &str = "usage: tfs mkfs <image> [--size <MiB>] [--inodes <count>]
+       tfs fsck [-y] <image>
+       tfs dump <image>";
+
+/// `fsck`'s exit codes, as e2fsck's: nothing wrong, everything fixed,
+/// problems left (run with `-y` to fix them), couldn't check at all.
+const CLEAN: i32 = 0;
+const FIXED: i32 = 1;
+const UNFIXED: i32 = 4;
+const FAILED: i32 = 8;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let code = match args.as_slice() {
+        ["mkfs", image, options @ ..] => match mkfs::options(options) {
+            Some((size, inodes)) => report(mkfs::run(image, size, inodes)),
+            None => usage(),
+        },
+        ["fsck", "-y", image] => check(image, true),
+        ["fsck", image] => check(image, false),
+        ["dump", image] => report(load(image).map(|volume| dump::run(&volume))),
+        _ => usage(),
+    };
+    process::exit(code);
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+fn report(result: Result<(), String>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("tfs: {}", err);
+            1
+        }
+    }
+}
+
+fn load(image: &str) -> Result<Volume, String> {
+    let bytes = fs::read(image).map_err(|err| format!("{}: {}", image, err))?;
+    Volume::load(bytes).map_err(|err| format!("{}: {}", image, err))
+}
+
+fn check(image: &str, fix: bool) -> i32 {
+    let mut volume = match load(image) {
+        Ok(volume) => volume,
+        Err(err) => {
+            eprintln!("fsck: {}", err);
+            return FAILED;
+        }
+    };
+    let problems = fsck::run(&mut volume);
+    if problems == 0 {
+        return CLEAN;
+    }
+    if !fix {
+        println!("{}: {} problems; nothing written (fix them with -y)", image, problems);
+        return UNFIXED;
+    }
+    match fs::write(image, volume.bytes()) {
+        Ok(()) => {
+            println!("{}: {} problems fixed", image, problems);
+            FIXED
+        }
+        Err(err) => {
+            eprintln!("fsck: {}: {}", image, err);
+            FAILED
+        }
+    }
+}
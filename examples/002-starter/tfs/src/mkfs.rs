@@ -0,0 +1,43 @@
+//! Make an empty volume on an image file.
+
+use std::fs;
+
+use crate::disk::{Volume, BLOCK_SIZE, JOURNAL_BLOCKS};
+
+/// What a new image is without `--size`: the runner's blank disk size.
+const DEFAULT_SIZE_MIB: u64 = 16;
+
+/// `--size <MiB>` and `--inodes <count>`, each optional; `None` if
+/// something else is there.
+pub fn options(args: &[&str]) -> Option<(Option<u64>, Option<u32>)> {
+    let (mut size, mut inodes) = (None, None);
+    for pair in args.chunks(2) {
+        match pair {
+            ["--size", mib] => size = Some(mib.parse().ok()?),
+            ["--inodes", count] => inodes = Some(count.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some((size, inodes))
+}
+
+/// Make `image` an empty volume: `size_mib` MiB, else as long as the file
+/// already is, else `DEFAULT_SIZE_MIB`. Everything on it goes.
+pub fn run(image: &str, size_mib: Option<u64>, inodes: Option<u32>) -> Result<(), String> {
+    let len = match (size_mib, fs::metadata(image)) {
+        (Some(mib), _) => mib * 1024 * 1024,
+        (None, Ok(metadata)) => metadata.len(),
+        (None, Err(_)) => DEFAULT_SIZE_MIB * 1024 * 1024,
+    };
+    let volume = Volume::format(len, inodes, JOURNAL_BLOCKS).map_err(|err| format!("{}: {}", image, err))?;
+    let mut bytes = volume.bytes().to_vec();
+    // Keep a partial block at the end: the file stays the size it was.
+    bytes.resize(len as usize, 0);
+    fs::write(image, &bytes).map_err(|err| format!("{}: {}", image, err))?;
+    let sb = volume.sb;
+    println!(
+        "{}: {} blocks of {} bytes, {} inodes, a journal of {} blocks at block {}; data from block {}",
+        image, sb.block_count, BLOCK_SIZE, sb.inode_count, sb.journal_blocks, sb.journal, sb.data_start
+    );
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    // The kernel loads static, non-PIE executables in the program area of
+    // user memory: link there with our script instead of the target's
+    // default static PIE.
+    let dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let script = dir.join("link.ld");
+    println!("cargo:rerun-if-changed={}", script.display());
+    println!("cargo:rustc-link-arg-bins=-T{}", script.display());
+    println!("cargo:rustc-link-arg-bins=--no-pie");
+}
@@ -0,0 +1,40 @@
+//! cat [files...]: copy each file (standard input for none, or for `-`) to
+//! standard output.
+
+#![no_std]
+#![no_main]
+
+use usys::io::{self, File, STDIN, STDOUT};
+use usys::{eprintln, Errno};
+
+usys::entry!(main);
+
+/// Copy `fd` to standard output until end of file.
+fn copy(fd: u32) -> Result<(), Errno> {
+    let mut chunk = [0u8; 512];
+    loop {
+        match io::read(fd, &mut chunk)? {
+            0 => return Ok(()),
+            n => io::write_all(STDOUT, &chunk[..n])?,
+        }
+    }
+}
+
+fn main() -> i32 {
+    let mut status = 0;
+    let mut paths = usys::env::args().skip(1).peekable();
+    if paths.peek().is_none() {
+        return copy(STDIN).map_or(1, |()| 0);
+    }
+    for path in paths {
+        let result = match path {
+            "-" => copy(STDIN),
+            path => File::open(path).and_then(|file| copy(file.fd())),
+        };
+        if let Err(err) = result {
+            eprintln!("cat: {}: {}", path, err);
+            status = 1;
+        }
+    }
+    status
+}
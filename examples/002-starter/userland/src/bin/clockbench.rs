@@ -0,0 +1,47 @@
+//! clockbench: how long reading the clock takes from the time page and with
+//! a system call, as in `clockbench 100000`.
+
+#![no_std]
+#![no_main]
+
+use core::time::Duration;
+use usys::time::{self, Instant, CLOCK_MONOTONIC};
+use usys::{env, eprintln, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    let rounds = match env::args().nth(1).map(str::parse::<u32>) {
+        None => 10_000,
+        Some(Ok(n)) if n > 0 => n,
+        Some(_) => {
+            eprintln!("usage: clockbench [rounds]");
+            return 1;
+        }
+    };
+    if time::ticks().is_none() {
+        eprintln!("clockbench: no time page, both ways are system calls");
+    }
+    let page = measure(rounds, || time::clock_gettime(CLOCK_MONOTONIC));
+    let syscall = measure(rounds, || time::clock_gettime_syscall(CLOCK_MONOTONIC));
+    let per_call = |total: Duration| total.as_nanos() / rounds as u128;
+    println!("{} reads of the monotonic clock", rounds);
+    println!("  time page:   {:>6} ns each", per_call(page));
+    println!("  system call: {:>6} ns each", per_call(syscall));
+    if page.as_nanos() > 0 {
+        println!("  {}x faster without the system call", syscall.as_nanos() / page.as_nanos());
+    }
+    0
+}
+
+/// How long `rounds` calls of `read` took; each reading must not go back.
+fn measure(rounds: u32, read: impl Fn() -> usys::syscall::Result<Duration>) -> Duration {
+    let start = Instant::now();
+    let mut last = Duration::ZERO;
+    for _ in 0..rounds {
+        let now = read().expect("the monotonic clock is always there");
+        assert!(now >= last, "the monotonic clock went back");
+        last = now;
+    }
+    start.elapsed()
+}
@@ -0,0 +1,22 @@
+//! date [-u]: print the date and time (UTC), or with -u the seconds since
+//! the Unix epoch.
+
+#![no_std]
+#![no_main]
+
+use usys::time::{self, DateTime};
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    match usys::env::args().nth(1) {
+        None => println!("{}", DateTime::now()),
+        Some("-u") => println!("{}", time::unix_time().as_secs()),
+        Some(_) => {
+            eprintln!("usage: date [-u]");
+            return 1;
+        }
+    }
+    0
+}
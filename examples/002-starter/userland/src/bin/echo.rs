@@ -0,0 +1,19 @@
+//! echo [args...]: print the arguments, separated by spaces.
+
+#![no_std]
+#![no_main]
+
+use usys::{print, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    for (i, arg) in usys::env::args().skip(1).enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+    0
+}
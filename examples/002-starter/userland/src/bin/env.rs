@@ -0,0 +1,30 @@
+//! env [-a]: print the environment, one "KEY=value" per line; with -a, the
+//! auxiliary vector the kernel passed too.
+
+#![no_std]
+#![no_main]
+
+use usys::env::{self, AT_ENTRY, AT_PAGESZ, AT_RANDOM};
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    let auxv = match env::args().nth(1) {
+        None => false,
+        Some("-a") => true,
+        Some(_) => {
+            eprintln!("usage: env [-a]");
+            return 1;
+        }
+    };
+    env::environ().for_each(|var| println!("{}", var));
+    if auxv {
+        for (name, key) in [("AT_PAGESZ", AT_PAGESZ), ("AT_ENTRY", AT_ENTRY), ("AT_RANDOM", AT_RANDOM)] {
+            if let Some(value) = env::aux(key) {
+                println!("{}: {:#x}", name, value);
+            }
+        }
+    }
+    0
+}
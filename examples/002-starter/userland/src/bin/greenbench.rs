@@ -0,0 +1,149 @@
+//! greenbench [rounds]: green threads against kernel ones. Passes a message
+//! back and forth `rounds` times between two green threads over channels,
+//! then between two processes over pipes, and shows what each round trip
+//! cost; starts and joins green threads; and shows the catch: a green
+//! thread that blocks in the kernel stops the others.
+//!
+//! `greenbench echo <rounds> <in> <out>` is the other process of the pipe
+//! test: it copies a byte from descriptor `in` to `out`, `rounds` times.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::rc::Rc;
+use core::cell::Cell;
+use core::time::Duration;
+use usys::green;
+use usys::io;
+use usys::process::{spawn, wait};
+use usys::time::{self, Instant};
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+const PATH: &str = "/bin/greenbench";
+
+fn main() -> i32 {
+    let args: alloc::vec::Vec<&str> = usys::env::args().collect();
+    let rounds = match args.get(1..) {
+        Some(["echo", rounds, input, output]) => return echo(rounds, input, output),
+        Some([]) => 10_000,
+        Some([rounds]) => match rounds.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return usage(),
+        },
+        _ => return usage(),
+    };
+
+    let green = green_ping_pong(rounds);
+    let kernel = match process_ping_pong(rounds) {
+        Ok(took) => took,
+        Err(err) => {
+            eprintln!("greenbench: {}", err);
+            return 1;
+        }
+    };
+    let per = |took: Duration| took.as_nanos() / rounds as u128;
+    println!("{} round trips of a message", rounds);
+    println!("  green threads, channels: {:>8} ns each", per(green));
+    println!("  processes, pipes:        {:>8} ns each", per(kernel));
+
+    let start = Instant::now();
+    for i in 0..rounds {
+        green::spawn(move || i).join();
+    }
+    println!("  green spawn and join:    {:>8} ns each", per(start.elapsed()));
+
+    let (ran, slept) = blocked_by_sleep();
+    println!("while one green thread slept {:?} in the kernel, another ran {} times", slept, ran);
+    0
+}
+
+fn usage() -> i32 {
+    eprintln!("usage: greenbench [rounds]");
+    1
+}
+
+/// `rounds` round trips between this green thread and another.
+fn green_ping_pong(rounds: u32) -> Duration {
+    let (to_echo, echo_in) = green::channel::<u32>();
+    let (echo_out, from_echo) = green::channel::<u32>();
+    let echo = green::spawn(move || {
+        while let Some(n) = echo_in.recv() {
+            let _ = echo_out.send(n);
+        }
+    });
+    let start = Instant::now();
+    for i in 0..rounds {
+        let _ = to_echo.send(i);
+        assert_eq!(from_echo.recv(), Some(i));
+    }
+    let took = start.elapsed();
+    drop(to_echo);
+    echo.join();
+    took
+}
+
+/// `rounds` round trips between this process and a child, each a byte
+/// through one pipe and back through another.
+fn process_ping_pong(rounds: u32) -> usys::syscall::Result<Duration> {
+    // Not close-on-exec: the child gets its ends by number.
+    let (child_in, to_child) = io::pipe(0)?;
+    let (from_child, child_out) = io::pipe(0)?;
+    let (rounds_arg, in_arg, out_arg) = (format!("{}", rounds), format!("{}", child_in), format!("{}", child_out));
+    let child = spawn(PATH, &["greenbench", "echo", &rounds_arg, &in_arg, &out_arg]);
+    io::close(child_in)?;
+    io::close(child_out)?;
+    let child = child?;
+    let mut byte = [0u8];
+    let start = Instant::now();
+    for i in 0..rounds {
+        io::write_all(to_child, &[i as u8])?;
+        io::read(from_child, &mut byte)?;
+    }
+    let took = start.elapsed();
+    io::close(to_child)?;
+    io::close(from_child)?;
+    wait(Some(child))?;
+    Ok(took)
+}
+
+/// The child's half of `process_ping_pong`.
+fn echo(rounds: &str, input: &str, output: &str) -> i32 {
+    let (Ok(rounds), Ok(input), Ok(output)) = (rounds.parse::<u32>(), input.parse(), output.parse()) else {
+        return usage();
+    };
+    let mut byte = [0u8];
+    for _ in 0..rounds {
+        if io::read(input, &mut byte) != Ok(1) || io::write_all(output, &byte).is_err() {
+            return 1;
+        }
+    }
+    0
+}
+
+/// How many times a green thread that only yields got to run while another
+/// slept in the kernel, and how long that was.
+fn blocked_by_sleep() -> (u32, Duration) {
+    let (runs, stop) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(false)));
+    let (their_runs, their_stop) = (runs.clone(), stop.clone());
+    let spinner = green::spawn(move || {
+        while !their_stop.get() {
+            their_runs.set(their_runs.get() + 1);
+            green::yield_now();
+        }
+    });
+    // Let it start, then block all of us.
+    green::yield_now();
+    let before = runs.get();
+    let start = Instant::now();
+    let _ = time::sleep(Duration::from_millis(50));
+    let slept = start.elapsed();
+    let ran = runs.get() - before;
+    stop.set(true);
+    spinner.join();
+    (ran, slept)
+}
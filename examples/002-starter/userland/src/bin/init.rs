@@ -0,0 +1,34 @@
+//! init: the first program, started by the kernel at boot. Runs the shell,
+//! and starts a new one whenever it dies; when it exits normally (status 0,
+//! `exit` typed), init exits too and the kernel shell takes over.
+
+#![no_std]
+#![no_main]
+
+use usys::println;
+use usys::process::{self, spawn, wait};
+
+usys::entry!(main);
+
+const SHELL: &str = "/bin/sh";
+
+fn main() -> i32 {
+    println!("init: pid {}", process::getpid());
+    loop {
+        let shell = match spawn(SHELL, &["sh"]) {
+            Ok(pid) => pid,
+            Err(err) => {
+                println!("init: can't start {}: {}", SHELL, err);
+                return 1;
+            }
+        };
+        match wait(Some(shell)) {
+            Ok((_, 0)) => return 0,
+            Ok((_, status)) => println!("init: {} exited with status {}, restarting it", SHELL, status),
+            Err(err) => {
+                println!("init: wait: {}", err);
+                return 1;
+            }
+        }
+    }
+}
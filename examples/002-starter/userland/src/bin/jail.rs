@@ -0,0 +1,33 @@
+//! jail: filter its own system calls down to `write` before running some
+//! "untrusted" code, which then tries to open a file and is killed for it
+//! with SIGSYS (exit status 159). Run it under `strace` to see the call
+//! that did it.
+
+#![no_std]
+#![no_main]
+
+use usys::syscall::nr;
+use usys::{eprintln, io, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    println!("jail: pid {}, filtering system calls down to write (and exit)", usys::process::getpid());
+    if let Err(err) = usys::process::seccomp(&[nr::WRITE]) {
+        eprintln!("jail: seccomp: {}", err);
+        return 1;
+    }
+    untrusted()
+}
+
+/// Does what it was given to do, which only needs `write`, then reaches
+/// for more.
+fn untrusted() -> i32 {
+    let sum: u64 = (1..=100).filter(|n| n % 3 == 0 || n % 5 == 0).sum();
+    println!("jail: the untrusted code worked out {} on its own", sum);
+    println!("jail: now it tries to open /etc/programs...");
+    let opened = io::open("/etc/programs", io::O_RDONLY);
+    // Never reached: the kernel killed the process in `open`.
+    println!("jail: the filter let it through: {:?}", opened);
+    1
+}
@@ -0,0 +1,33 @@
+//! primes [n]: the primes below n (100 by default), from a sieve on the heap.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    let n = match usys::env::args().nth(1).map(str::parse::<usize>) {
+        None => 100,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("usage: primes [n]");
+            return 1;
+        }
+    };
+    let mut composite = vec![false; n];
+    let mut primes = Vec::new();
+    for i in 2..n {
+        if !composite[i] {
+            primes.push(i);
+            (i * i..n).step_by(i).for_each(|j| composite[j] = true);
+        }
+    }
+    println!("{} primes below {}: {:?}", primes.len(), n, primes);
+    0
+}
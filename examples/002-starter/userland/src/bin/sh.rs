@@ -0,0 +1,381 @@
+//! sh: a small interactive shell. It reads a line at a time from standard
+//! input and runs programs from the initrd by name, looked up in the
+//! directories of PATH (`echo hi` runs `/bin/echo`), waiting for each unless
+//! the line ends with `&`. Programs get the shell's environment, which
+//! starts as its own and changes with `export`. `< path` and `> path`
+//! redirect a program's standard input and output, and `a | b` connects
+//! a's standard output to b's standard input with a pipe. `sandbox` runs a
+//! program with fewer capabilities than the shell's, and `strace` one with
+//! its system calls logged by the kernel. Background jobs
+//! are reported, with their exit status, before the next prompt after they
+//! finish. Ctrl-D on an empty line leaves, like `exit`.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use usys::io::{self, read_line, O_CLOEXEC, O_RDONLY, O_WRONLY, STDIN, STDOUT};
+use usys::env;
+use usys::process::{self, spawn_env, try_wait, wait, TRACE_CHILDREN};
+use usys::signal::{self, SIGTERM};
+use usys::{caps, eprintln, print, println, Errno};
+
+usys::entry!(main);
+
+const HELP: &str = "\
+built-ins:
+  help                  this list
+  exit [status]         leave the shell
+  export KEY=value      set an environment variable for the programs run
+  jobs                  background jobs still running
+  wait                  wait for every background job
+  kill <pid> [signal]   send a process a signal (SIGTERM by default)
+  caps                  the shell's capabilities
+  sandbox <caps> <cmd>  run cmd with only caps (e.g. none, or spawn,fs-write)
+  strace <cmd>          run cmd with its system calls logged to the serial port
+anything else runs <name> from PATH (/bin by default), with < path and > path
+redirecting its input and output, and cmd1 | cmd2 piping the output of one
+into the next; end the line with & to run it in the background";
+
+/// Where programs are looked up if PATH isn't set.
+const DEFAULT_PATH: &str = "/bin";
+/// The shell keeps its own descriptor `fd` at `SAVED + fd` while a program
+/// is started with `fd` redirected.
+const SAVED: u32 = 10;
+
+/// Descriptor `fd` of a program, pointed elsewhere while it starts.
+struct Redirect<'a> {
+    fd: u32,
+    to: Target<'a>,
+}
+
+enum Target<'a> {
+    /// `< path` or `> path`: `path`, opened with these flags.
+    Path(&'a str, u32),
+    /// A descriptor of the shell's: an end of a pipe.
+    Fd(u32),
+}
+
+struct Job {
+    pid: u64,
+    command: String,
+}
+
+fn main() -> i32 {
+    let mut vars: Vec<String> = env::environ().map(String::from).collect();
+    let mut jobs = Vec::new();
+    let mut line = String::new();
+    loop {
+        reap(&mut jobs);
+        print!("$ ");
+        line.clear();
+        match read_line(STDIN, &mut line) {
+            Ok(0) => {
+                println!();
+                return 0;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("sh: read: {}", err);
+                return 1;
+            }
+        }
+        let mut commands: Vec<Vec<&str>> = line.split('|').map(|command| command.split_whitespace().collect()).collect();
+        let last = commands.last_mut().expect("split gives at least one");
+        let background = last.last() == Some(&"&");
+        if background {
+            last.pop();
+        }
+        if commands.len() > 1 {
+            pipeline(&mut commands, &vars, background, &mut jobs);
+            continue;
+        }
+        let mut words = commands.pop().expect("one command");
+        let redirects = match redirections(&mut words) {
+            Ok(redirects) => redirects,
+            Err(err) => {
+                eprintln!("sh: {}", err);
+                continue;
+            }
+        };
+        match words[..] {
+            [] => {}
+            ["help"] => println!("{}", HELP),
+            ["exit"] => return 0,
+            ["exit", status] => match status.parse() {
+                Ok(status) => return status,
+                Err(_) => eprintln!("sh: exit: bad status {}", status),
+            },
+            ["export", var] => export(&mut vars, var),
+            ["jobs"] => jobs.iter().for_each(|job| println!("[{}] running  {}", job.pid, job.command)),
+            ["wait"] => {
+                for job in jobs.drain(..) {
+                    if let Ok((pid, status)) = wait(Some(job.pid)) {
+                        println!("[{}] exited with status {}  {}", pid, status, job.command);
+                    }
+                }
+            }
+            ["kill", ..] => kill(&words[1..]),
+            ["caps"] => println!("{}", caps_list(caps::get())),
+            ["sandbox", allowed, _, ..] => match caps::parse(allowed) {
+                Some(allowed) => sandboxed(allowed, || run(&words[2..], &vars, &redirects, background, &mut jobs)),
+                None => eprintln!("sh: sandbox: unknown capabilities {}", allowed),
+            },
+            ["strace", _, ..] => traced(|| run(&words[1..], &vars, &redirects, background, &mut jobs)),
+            _ => run(&words, &vars, &redirects, background, &mut jobs),
+        }
+    }
+}
+
+/// Report the background jobs that finished.
+fn reap(jobs: &mut Vec<Job>) {
+    while let Ok(Some((pid, status))) = try_wait(None) {
+        let Some(at) = jobs.iter().position(|job| job.pid == pid) else { continue };
+        let job = jobs.remove(at);
+        println!("[{}] exited with status {}  {}", pid, status, job.command);
+    }
+}
+
+/// Set (or replace) the variable `var`, "KEY=value".
+fn export(vars: &mut Vec<String>, var: &str) {
+    let Some((key, _)) = var.split_once('=').filter(|(key, _)| !key.is_empty()) else {
+        return eprintln!("usage: export KEY=value");
+    };
+    vars.retain(|v| v.split_once('=').map_or(v.as_str(), |(k, _)| k) != key);
+    vars.push(String::from(var));
+}
+
+/// Spawn `words[0]` with `vars` as its environment: the path itself if it
+/// has a `/`, else the first directory of PATH that has it.
+fn spawn(words: &[&str], vars: &[String]) -> Result<u64, Errno> {
+    let name = words[0];
+    if name.contains('/') {
+        return spawn_env(name, words, vars);
+    }
+    let path = vars.iter().find_map(|var| var.strip_prefix("PATH=")).unwrap_or(DEFAULT_PATH);
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        match spawn_env(&format!("{}/{}", dir.trim_end_matches('/'), name), words, vars) {
+            Err(Errno::ENOENT) => continue,
+            result => return result,
+        }
+    }
+    Err(Errno::ENOENT)
+}
+
+/// Take the redirections out of `words`: `<` or `>` and a path, apart or
+/// together (`<in`).
+fn redirections<'a>(words: &mut Vec<&'a str>) -> Result<Vec<Redirect<'a>>, &'static str> {
+    let mut redirects = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let (fd, flags) = match words[i].as_bytes()[0] {
+            b'<' => (STDIN, O_RDONLY),
+            b'>' => (STDOUT, O_WRONLY),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let path = match &words[i][1..] {
+            "" if i + 1 < words.len() => words.remove(i + 1),
+            "" => return Err("redirection without a path"),
+            path => path,
+        };
+        words.remove(i);
+        redirects.push(Redirect { fd, to: Target::Path(path, flags) });
+    }
+    Ok(redirects)
+}
+
+/// Point the descriptors at the files `redirects` name, keeping the
+/// shell's own at `SAVED + fd`; what to give back to `restore`. On error,
+/// nothing is left changed.
+fn redirect(redirects: &[Redirect]) -> Result<Vec<u32>, String> {
+    let mut saved = Vec::new();
+    let point = |fd, file| io::dup2(fd, SAVED + fd).and_then(|_| io::dup2(file, fd));
+    for redirect in redirects {
+        let done = match redirect.to {
+            Target::Path(path, flags) => io::open(path, flags)
+                .and_then(|file| {
+                    let moved = point(redirect.fd, file);
+                    let _ = io::close(file);
+                    moved
+                })
+                .map_err(|err| format!("{}: {}", path, err)),
+            Target::Fd(file) => point(redirect.fd, file).map_err(|err| format!("pipe: {}", err)),
+        };
+        if let Err(err) = done {
+            restore(&saved);
+            return Err(err);
+        }
+        saved.push(redirect.fd);
+    }
+    Ok(saved)
+}
+
+/// Put back the descriptors `redirect` changed.
+fn restore(saved: &[u32]) {
+    for &fd in saved.iter().rev() {
+        let _ = io::dup2(SAVED + fd, fd);
+        let _ = io::close(SAVED + fd);
+    }
+}
+
+/// Start `words` with `redirects` in place; its PID, or `None` once the
+/// error is reported.
+fn start(words: &[&str], vars: &[String], redirects: &[Redirect]) -> Option<u64> {
+    let name = words[0];
+    let saved = match redirect(redirects) {
+        Ok(saved) => saved,
+        Err(err) => {
+            eprintln!("sh: {}", err);
+            return None;
+        }
+    };
+    let spawned = spawn(words, vars);
+    restore(&saved);
+    match spawned {
+        Ok(pid) => Some(pid),
+        Err(Errno::ENOENT) => {
+            eprintln!("sh: {}: command not found", name);
+            None
+        }
+        Err(err) => {
+            eprintln!("sh: {}: {}", name, err);
+            None
+        }
+    }
+}
+
+/// Wait for `pid`, running `name`, and say if it failed.
+fn finish(pid: u64, name: &str) {
+    match wait(Some(pid)) {
+        Ok((_, 0)) => {}
+        Ok((_, status)) => eprintln!("sh: {}: exited with status {}", name, status),
+        Err(err) => eprintln!("sh: wait: {}", err),
+    }
+}
+
+fn run(words: &[&str], vars: &[String], redirects: &[Redirect], background: bool, jobs: &mut Vec<Job>) {
+    let name = words[0];
+    let Some(pid) = start(words, vars, redirects) else { return };
+    if background {
+        println!("[{}] {}", pid, name);
+        return jobs.push(Job { pid, command: words.join(" ") });
+    }
+    finish(pid, name);
+}
+
+/// Run `commands` side by side, each one's standard output piped into the
+/// next one's standard input (unless redirected elsewhere), and wait for
+/// them all unless in the background. The shell's ends of the pipes are
+/// close-on-exec, and closed once the programs have theirs, so each pipe
+/// is only open in the two programs it connects: the reader sees end of
+/// file when the writer exits, and the writer a broken pipe if the reader
+/// does. If a command can't start, the ones before it still run.
+fn pipeline(commands: &mut [Vec<&str>], vars: &[String], background: bool, jobs: &mut Vec<Job>) {
+    if commands.iter().any(Vec::is_empty) {
+        return eprintln!("sh: missing command around |");
+    }
+    let count = commands.len();
+    let mut started = Vec::new();
+    // The read end of the pipe from the command before.
+    let mut input = None;
+    for (i, words) in commands.iter_mut().enumerate() {
+        let mut redirects = match redirections(words) {
+            Ok(redirects) => redirects,
+            Err(err) => {
+                eprintln!("sh: {}", err);
+                break;
+            }
+        };
+        let output = if i + 1 < count {
+            match io::pipe(O_CLOEXEC) {
+                Ok(ends) => Some(ends),
+                Err(err) => {
+                    eprintln!("sh: pipe: {}", err);
+                    break;
+                }
+            }
+        } else {
+            None
+        };
+        let redirected = |fd| redirects.iter().any(|redirect: &Redirect| redirect.fd == fd);
+        let (from, to) = (input.filter(|_| !redirected(STDIN)), output.filter(|_| !redirected(STDOUT)));
+        if let Some(fd) = from {
+            redirects.push(Redirect { fd: STDIN, to: Target::Fd(fd) });
+        }
+        if let Some((_, fd)) = to {
+            redirects.push(Redirect { fd: STDOUT, to: Target::Fd(fd) });
+        }
+        let pid = start(words, vars, &redirects);
+        if let Some(fd) = input.take() {
+            let _ = io::close(fd);
+        }
+        if let Some((read, write)) = output {
+            let _ = io::close(write);
+            input = Some(read);
+        }
+        match pid {
+            Some(pid) => started.push((pid, words[0], words.join(" "))),
+            None => break,
+        }
+    }
+    if let Some(fd) = input {
+        let _ = io::close(fd);
+    }
+    for (pid, name, command) in started {
+        if background {
+            println!("[{}] {}", pid, name);
+            jobs.push(Job { pid, command });
+        } else {
+            finish(pid, name);
+        }
+    }
+}
+
+/// `caps` as `sandbox` takes them.
+fn caps_list(caps: u64) -> String {
+    match caps {
+        0 => String::from("none"),
+        caps::ALL => String::from("all"),
+        bits => caps::names(bits).collect::<Vec<_>>().join(","),
+    }
+}
+
+/// Run `f` with the programs it starts limited to `allowed`.
+fn sandboxed(allowed: u64, f: impl FnOnce()) {
+    let before = caps::children();
+    if let Err(err) = caps::set_children(before & allowed) {
+        return eprintln!("sh: sandbox: {}", err);
+    }
+    f();
+    let _ = caps::set_children(before);
+}
+
+/// Run `f` with the programs it starts traced.
+fn traced(f: impl FnOnce()) {
+    if let Err(err) = process::trace(0, TRACE_CHILDREN) {
+        return eprintln!("sh: strace: {}", err);
+    }
+    f();
+    let _ = process::trace(0, 0);
+}
+
+fn kill(args: &[&str]) {
+    let (pid, sig) = match args {
+        [pid] => (pid.parse(), Ok(SIGTERM)),
+        [pid, sig] => (pid.parse(), sig.parse()),
+        _ => return eprintln!("usage: kill <pid> [signal]"),
+    };
+    let (Ok(pid), Ok(sig)) = (pid, sig) else {
+        return eprintln!("usage: kill <pid> [signal]");
+    };
+    if let Err(err) = signal::kill(pid, sig) {
+        eprintln!("sh: kill: {}", err);
+    }
+}
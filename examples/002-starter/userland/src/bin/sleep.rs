@@ -0,0 +1,35 @@
+//! sleep <seconds>: wait that long; the seconds may have a fraction
+//! (`sleep 0.25`).
+
+#![no_std]
+#![no_main]
+
+use core::time::Duration;
+use usys::time::{self, Instant};
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+/// "1.5" as a duration; `None` unless it is digits with at most one point.
+fn parse(seconds: &str) -> Option<Duration> {
+    let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    if whole.is_empty() && fraction.is_empty() || fraction.len() > 9 {
+        return None;
+    }
+    let whole = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+    let nanos = if fraction.is_empty() { 0 } else { fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32) };
+    Some(Duration::new(whole, nanos))
+}
+
+fn main() -> i32 {
+    let Some(duration) = usys::env::args().nth(1).and_then(parse) else {
+        eprintln!("usage: sleep <seconds>");
+        return 1;
+    };
+    let start = Instant::now();
+    if let Err(left) = time::sleep(duration) {
+        println!("sleep: interrupted after {:?}, {:?} left", start.elapsed(), left);
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,14 @@
+//! uptime: how long since the machine booted.
+
+#![no_std]
+#![no_main]
+
+use usys::println;
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    let up = usys::time::uptime().as_secs();
+    println!("up {}:{:02}:{:02}", up / 3600, up / 60 % 60, up % 60);
+    0
+}
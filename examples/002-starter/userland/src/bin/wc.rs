@@ -0,0 +1,35 @@
+//! wc: count the lines, words and bytes of standard input, as in
+//! `primes 1000 | wc`.
+
+#![no_std]
+#![no_main]
+
+use usys::io::{self, STDIN};
+use usys::{eprintln, println};
+
+usys::entry!(main);
+
+fn main() -> i32 {
+    let (mut lines, mut words, mut bytes) = (0, 0, 0);
+    let mut in_word = false;
+    let mut chunk = [0u8; 512];
+    loop {
+        let n = match io::read(STDIN, &mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) => {
+                eprintln!("wc: {}", err);
+                return 1;
+            }
+        };
+        for &byte in &chunk[..n] {
+            lines += (byte == b'\n') as usize;
+            let space = byte.is_ascii_whitespace();
+            words += (!space && !in_word) as usize;
+            in_word = !space;
+        }
+        bytes += n;
+    }
+    println!("{:>7} {:>7} {:>7}", lines, words, bytes);
+    0
+}
@@ -0,0 +1,53 @@
+//! Capabilities (see the kernel's `process::caps`): what this program may
+//! ask the kernel for beyond the basics, and what the programs it starts
+//! may. Without SPAWN, `spawn` fails with EPERM; without FS_WRITE, opening a
+//! file for writing does.
+
+use crate::syscall::{check, nr, syscall1, syscall2, Result};
+
+pub const SPAWN: u64 = 1 << 0;
+pub const RAW_IO: u64 = 1 << 1;
+pub const NET: u64 = 1 << 2;
+pub const FS_WRITE: u64 = 1 << 3;
+pub const ALL: u64 = 0xf;
+
+const NAMES: [(u64, &str); 4] = [(SPAWN, "spawn"), (RAW_IO, "raw-io"), (NET, "net"), (FS_WRITE, "fs-write")];
+
+const SELF: u64 = 0;
+const CHILDREN: u64 = 1;
+
+/// This program's capabilities.
+pub fn get() -> u64 {
+    unsafe { syscall1(nr::CAPGET, SELF) }
+}
+
+/// The capabilities the programs it starts get.
+pub fn children() -> u64 {
+    unsafe { syscall1(nr::CAPGET, CHILDREN) }
+}
+
+/// Give up every capability not in `keep`, for good.
+pub fn drop_all_but(keep: u64) -> Result<()> {
+    check(unsafe { syscall2(nr::CAPSET, SELF, keep) }).map(|_| ())
+}
+
+/// Start programs with `caps` from now on; EPERM if that is more than this
+/// one has.
+pub fn set_children(caps: u64) -> Result<()> {
+    check(unsafe { syscall2(nr::CAPSET, CHILDREN, caps) }).map(|_| ())
+}
+
+/// `all`, `none`, or names separated by commas (`spawn,net`).
+pub fn parse(s: &str) -> Option<u64> {
+    match s {
+        "all" => return Some(ALL),
+        "none" => return Some(0),
+        _ => {}
+    }
+    s.split(',').try_fold(0, |caps, name| Some(caps | NAMES.iter().find(|(_, n)| *n == name)?.0))
+}
+
+/// The names of the capabilities in `caps`, as `parse` takes them.
+pub fn names(caps: u64) -> impl Iterator<Item = &'static str> {
+    NAMES.into_iter().filter(move |(cap, _)| caps & cap != 0).map(|(_, name)| name)
+}
@@ -0,0 +1,134 @@
+//! The program's arguments, environment and auxiliary vector, as the kernel
+//! lays them out on the stack at `_start` (see its `elf::push_args`):
+//!
+//! ```text
+//! argc | argv[0..argc] | NULL | envp[..] | NULL | auxv: (key, value)..., AT_NULL, 0
+//! ```
+//!
+//! They live on that stack for the whole run, so everything here hands out
+//! `&'static str`. A string that isn't UTF-8 comes out empty.
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Auxiliary vector keys (see `aux`).
+pub const AT_NULL: u64 = 0;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+/// Where the kernel's time page is (see `time`).
+pub const AT_TIME_PAGE: u64 = 0x1000;
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static AUXV: AtomicPtr<u64> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Find the three arrays from the stack pointer `_start` was entered with.
+///
+/// # Safety
+/// `stack` must be laid out as above.
+pub(crate) unsafe fn init(stack: *const usize) {
+    let argc = *stack;
+    let argv = stack.add(1) as *mut *const u8;
+    let envp = argv.add(argc + 1);
+    let mut end = envp;
+    while !(*end).is_null() {
+        end = end.add(1);
+    }
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(envp, Ordering::Relaxed);
+    AUXV.store(end.add(1) as *mut u64, Ordering::Relaxed);
+}
+
+/// The NUL-terminated string at `start`.
+unsafe fn string(start: *const u8) -> &'static str {
+    let mut len = 0;
+    // Not `CStr::from_ptr`: that calls a libc `strlen` we don't have.
+    while *start.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8(core::slice::from_raw_parts(start, len)).unwrap_or("")
+}
+
+/// The program's arguments, `args[0]` (by convention) being its name.
+pub fn args() -> Args {
+    Args { next: 0 }
+}
+
+/// Iterator over the arguments.
+pub struct Args {
+    next: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next >= ARGC.load(Ordering::Relaxed) {
+            return None;
+        }
+        let arg = unsafe { string(*ARGV.load(Ordering::Relaxed).add(self.next)) };
+        self.next += 1;
+        Some(arg)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = ARGC.load(Ordering::Relaxed).saturating_sub(self.next);
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+/// The environment strings, "KEY=value" each, as the program got them.
+pub fn environ() -> Environ {
+    Environ { next: ENVP.load(Ordering::Relaxed) }
+}
+
+/// Iterator over the environment strings.
+pub struct Environ {
+    next: *const *const u8,
+}
+
+impl Iterator for Environ {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        // Null before `init` has run: no environment.
+        if self.next.is_null() || unsafe { (*self.next).is_null() } {
+            return None;
+        }
+        let var = unsafe { string(*self.next) };
+        self.next = unsafe { self.next.add(1) };
+        Some(var)
+    }
+}
+
+/// The environment as (key, value) pairs. A string without `=` is a key
+/// with an empty value.
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    environ().map(|var| var.split_once('=').unwrap_or((var, "")))
+}
+
+/// The value of environment variable `key`, the first if it is there twice.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|&(k, _)| k == key).map(|(_, value)| value)
+}
+
+/// The value of auxiliary vector entry `key` (an `AT_*`), if the kernel
+/// passed it.
+pub fn aux(key: u64) -> Option<u64> {
+    let mut entry = AUXV.load(Ordering::Relaxed) as *const u64;
+    if entry.is_null() {
+        return None;
+    }
+    loop {
+        let (k, value) = unsafe { (*entry, *entry.add(1)) };
+        match k {
+            AT_NULL => return None,
+            k if k == key => return Some(value),
+            _ => entry = unsafe { entry.add(2) },
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Green threads: threads the program schedules itself, all on the one
+//! thread the kernel runs it on. Each has its own stack; switching between
+//! them saves the callee-saved registers on one stack and loads them from
+//! the other, with no system call, so it costs a few nanoseconds where the
+//! kernel's switch between processes costs microseconds (`greenbench`
+//! measures both).
+//!
+//! Scheduling is cooperative: a green thread runs until it yields, waits to
+//! join another, or waits on a channel, and the others run in turn, oldest
+//! first. The price is that nothing preempts one that doesn't, and that a
+//! blocking system call (a `sleep`, a `read` of the console) stops all of
+//! them: the kernel only sees the one thread.
+//!
+//! The main function is a green thread too, the first. When it returns the
+//! program exits, whether or not the others are done. Signal handlers must
+//! not use any of this.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::cell::{RefCell, UnsafeCell};
+
+/// Each green thread's stack, but the main one's.
+pub const STACK_SIZE: usize = 64 * 1024;
+
+// Save the callee-saved registers on the current stack and the stack
+// pointer at `*from`; load the stack pointer `to` and the registers saved
+// there, and return to whatever saved them. A new thread's stack is made
+// to look saved with `usys_green_start` to return to.
+global_asm!(
+    ".global usys_green_switch",
+    "usys_green_switch:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+    "usys_green_start:",
+    "    xor ebp, ebp",
+    "    and rsp, -16",
+    "    call {run}",
+    "    ud2",
+    run = sym run_current,
+);
+
+extern "C" {
+    fn usys_green_switch(from: *mut u64, to: u64);
+    fn usys_green_start();
+}
+
+/// Which green thread; the main function's is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(usize);
+
+struct Thread {
+    /// Where its registers were saved, while it isn't running.
+    rsp: u64,
+    /// `None` for the main thread, which runs on the program's stack.
+    stack: Option<Box<[u8]>>,
+    /// What to run, until it starts.
+    entry: Option<Box<dyn FnOnce()>>,
+    /// The thread waiting in `join` for this one.
+    joiner: Option<usize>,
+}
+
+struct Runtime {
+    /// By ID; `None` for IDs free for reuse.
+    threads: Vec<Option<Thread>>,
+    /// Threads waiting for their turn, oldest first.
+    ready: VecDeque<usize>,
+    current: usize,
+    /// The stack of the thread that just finished: it ran on it up to the
+    /// switch away, so the next one to run frees it.
+    dead: Option<Box<[u8]>>,
+    switches: u64,
+}
+
+struct Shared(UnsafeCell<Runtime>);
+
+// A program has one thread.
+unsafe impl Sync for Shared {}
+
+static RUNTIME: Shared = Shared(UnsafeCell::new(Runtime {
+    threads: Vec::new(),
+    ready: VecDeque::new(),
+    current: 0,
+    dead: None,
+    switches: 0,
+}));
+
+/// Run `f` on the runtime, with the main thread in it. `f` must not switch
+/// threads or call back in here.
+fn with<R>(f: impl FnOnce(&mut Runtime) -> R) -> R {
+    let runtime = unsafe { &mut *RUNTIME.0.get() };
+    if runtime.threads.is_empty() {
+        runtime.threads.push(Some(Thread { rsp: 0, stack: None, entry: None, joiner: None }));
+    }
+    f(runtime)
+}
+
+/// The green thread running now.
+pub fn current() -> ThreadId {
+    ThreadId(with(|rt| rt.current))
+}
+
+/// Switches between green threads so far.
+pub fn switches() -> u64 {
+    with(|rt| rt.switches)
+}
+
+/// Run the next ready thread, putting this one at the back of the queue if
+/// `requeue`, and return when it is this one's turn again. With no thread
+/// ready, a requeued one just carries on; otherwise every thread is waiting
+/// for another and none can ever run.
+fn switch(requeue: bool) {
+    let Some((from, to)) = with(|rt| {
+        let Some(next) = rt.ready.pop_front() else {
+            assert!(requeue, "green: every thread is blocked");
+            return None;
+        };
+        let prev = rt.current;
+        if requeue {
+            rt.ready.push_back(prev);
+        }
+        rt.current = next;
+        rt.switches += 1;
+        let to = rt.threads[next].as_ref().expect("a ready thread exists").rsp;
+        // A thread that finished has no slot left to save its registers in.
+        let from = match &mut rt.threads[prev] {
+            Some(thread) => &mut thread.rsp as *mut u64,
+            None => core::ptr::addr_of_mut!(DISCARDED),
+        };
+        Some((from, to))
+    }) else {
+        return;
+    };
+    unsafe { usys_green_switch(from, to) };
+    with(|rt| drop(rt.dead.take()));
+}
+
+/// Where a finished thread's stack pointer goes.
+static mut DISCARDED: u64 = 0;
+
+/// Stop running this thread until `unpark` queues it again.
+fn park() {
+    switch(false);
+}
+
+fn unpark(id: usize) {
+    with(|rt| rt.ready.push_back(id));
+}
+
+/// Let the other ready threads run first.
+pub fn yield_now() {
+    switch(true);
+}
+
+/// Where a new thread starts, on its own stack.
+extern "C" fn run_current() -> ! {
+    let entry = with(|rt| {
+        drop(rt.dead.take());
+        let current = rt.current;
+        rt.threads[current].as_mut().and_then(|thread| thread.entry.take())
+    });
+    entry.expect("a new thread has something to run")();
+    // Finished: hand the stack to the next thread to free, and the slot back.
+    with(|rt| {
+        let current = rt.current;
+        let thread = rt.threads[current].take().expect("the running thread exists");
+        rt.dead = thread.stack;
+        if let Some(joiner) = thread.joiner {
+            rt.ready.push_back(joiner);
+        }
+    });
+    switch(false);
+    unreachable!("a finished green thread ran again");
+}
+
+/// A thread to `join`.
+pub struct JoinHandle<T> {
+    id: usize,
+    result: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        ThreadId(self.id)
+    }
+
+    /// Wait for the thread to finish, and what its function returned.
+    pub fn join(self) -> T {
+        loop {
+            if let Some(result) = self.result.borrow_mut().take() {
+                return result;
+            }
+            with(|rt| {
+                let me = rt.current;
+                rt.threads[self.id].as_mut().expect("an unfinished thread exists").joiner = Some(me);
+            });
+            park();
+        }
+    }
+}
+
+/// Start a green thread running `f`, at the back of the queue: it first
+/// runs when this one yields or waits.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    let result = Rc::new(RefCell::new(None));
+    let slot = result.clone();
+    let entry: Box<dyn FnOnce()> = Box::new(move || *slot.borrow_mut() = Some(f()));
+
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    // Laid out as `usys_green_switch` leaves a stack: six registers, all
+    // zero, then where to return.
+    let top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !15;
+    let rsp = top - 8 * 8;
+    unsafe { *((rsp + 6 * 8) as *mut u64) = usys_green_start as *const () as usize as u64 };
+    let thread = Thread { rsp, stack: Some(stack), entry: Some(entry), joiner: None };
+
+    let id = with(|rt| {
+        let id = match rt.threads.iter().position(Option::is_none) {
+            Some(free) => {
+                rt.threads[free] = Some(thread);
+                free
+            }
+            None => {
+                rt.threads.push(Some(thread));
+                rt.threads.len() - 1
+            }
+        };
+        rt.ready.push_back(id);
+        id
+    });
+    JoinHandle { id, result }
+}
+
+struct Channel<T> {
+    queue: VecDeque<T>,
+    /// The receiver, if it is waiting for a message.
+    waiting: Option<usize>,
+    senders: usize,
+    receiver: bool,
+}
+
+/// The sending side of a `channel`; clone it for more senders.
+pub struct Sender<T> {
+    channel: Rc<RefCell<Channel<T>>>,
+}
+
+/// The receiving side of a `channel`.
+pub struct Receiver<T> {
+    channel: Rc<RefCell<Channel<T>>>,
+}
+
+/// A queue of messages between green threads, as long as it needs to be:
+/// sending never waits, receiving waits for a message.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Rc::new(RefCell::new(Channel { queue: VecDeque::new(), waiting: None, senders: 1, receiver: true }));
+    (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+impl<T> Sender<T> {
+    /// Queue `message` and wake the receiver if it waits; the message back
+    /// if the receiver is gone.
+    pub fn send(&self, message: T) -> Result<(), T> {
+        let mut channel = self.channel.borrow_mut();
+        if !channel.receiver {
+            return Err(message);
+        }
+        channel.queue.push_back(message);
+        if let Some(receiver) = channel.waiting.take() {
+            unpark(receiver);
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.channel.borrow_mut().senders += 1;
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.borrow_mut();
+        channel.senders -= 1;
+        if channel.senders == 0 {
+            if let Some(receiver) = channel.waiting.take() {
+                unpark(receiver);
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The oldest message, waiting for one; `None` once it is empty and
+    /// every sender is gone.
+    pub fn recv(&self) -> Option<T> {
+        loop {
+            {
+                let mut channel = self.channel.borrow_mut();
+                if let Some(message) = channel.queue.pop_front() {
+                    return Some(message);
+                }
+                if channel.senders == 0 {
+                    return None;
+                }
+                channel.waiting = Some(current().0);
+            }
+            park();
+        }
+    }
+
+    /// The oldest message, if there is one now.
+    pub fn try_recv(&self) -> Option<T> {
+        self.channel.borrow_mut().queue.pop_front()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.borrow_mut().receiver = false;
+    }
+}
@@ -0,0 +1,95 @@
+//! The allocator behind `alloc`: power-of-two size classes from 16 bytes
+//! to a page, each with a free list, carved out of pages from `sbrk`.
+//! Anything bigger is its own `mmap`, unmapped when freed. Freed blocks go
+//! back on their class's list; the heap never shrinks.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::mem::{self, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+const MIN_BLOCK: usize = 16;
+/// 16, 32, ... 4096.
+const CLASSES: usize = 9;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    /// A program has one thread, so this is never contended, except by a
+    /// signal handler allocating while `main` was: that would spin forever,
+    /// so handlers must not allocate.
+    locked: AtomicBool,
+    free: UnsafeCell<[*mut FreeBlock; CLASSES]>,
+}
+
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap { locked: AtomicBool::new(false), free: UnsafeCell::new([ptr::null_mut(); CLASSES]) };
+
+/// The block size and class for `layout`; `None` for a whole mapping.
+fn class(layout: Layout) -> Option<(usize, usize)> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK).next_power_of_two();
+    (size <= PAGE_SIZE).then(|| (size, (size / MIN_BLOCK).trailing_zeros() as usize))
+}
+
+impl Heap {
+    fn with_free<R>(&self, f: impl FnOnce(&mut [*mut FreeBlock; CLASSES]) -> R) -> R {
+        while self.locked.swap(true, Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.free.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// A fresh page from `sbrk`, cut into blocks of `size` on `list`. The heap
+/// starts page aligned and only grows by pages, so each block is aligned to
+/// its size.
+unsafe fn refill(list: &mut *mut FreeBlock, size: usize) -> bool {
+    let Ok(page) = mem::sbrk(PAGE_SIZE as isize) else { return false };
+    for offset in (0..PAGE_SIZE).step_by(size).rev() {
+        let block = page.add(offset).cast::<FreeBlock>();
+        block.write(FreeBlock { next: *list });
+        *list = block;
+    }
+    true
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((size, class)) = class(layout) else {
+            if layout.align() > PAGE_SIZE {
+                return ptr::null_mut();
+            }
+            return mem::mmap(layout.size(), PROT_READ | PROT_WRITE).unwrap_or_default();
+        };
+        self.with_free(|free| {
+            let list = &mut free[class];
+            if list.is_null() && !refill(list, size) {
+                return ptr::null_mut();
+            }
+            let block = *list;
+            *list = (*block).next;
+            block.cast()
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some((_, class)) = class(layout) else {
+            let _ = mem::munmap(ptr, layout.size());
+            return;
+        };
+        self.with_free(|free| {
+            let block = ptr.cast::<FreeBlock>();
+            block.write(FreeBlock { next: free[class] });
+            free[class] = block;
+        });
+    }
+}
@@ -0,0 +1,188 @@
+//! Files: opening them, reading, writing and seeking through descriptors,
+//! pipes, and the console macros.
+//!
+//! Descriptors 0, 1 and 2 start as the console, unless the parent made them
+//! something else: children get a copy of their parent's descriptors, all
+//! but those opened with `O_CLOEXEC`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::syscall::{check, nr, syscall1, syscall2, syscall3, Result};
+
+pub const STDIN: u32 = 0;
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/// Access modes for `open`.
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+/// With an access mode, or for `pipe`: children don't inherit the
+/// descriptor, and `exec` closes it.
+pub const O_CLOEXEC: u32 = 0o2000000;
+
+/// Where `lseek` counts from.
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Open the file at `path` with access mode `flags` (an `O_*`); the lowest
+/// free descriptor.
+pub fn open(path: &str, flags: u32) -> Result<u32> {
+    check(unsafe { syscall3(nr::OPEN, path.as_ptr() as u64, path.len() as u64, flags as u64) }).map(|fd| fd as u32)
+}
+
+pub fn close(fd: u32) -> Result<()> {
+    check(unsafe { syscall1(nr::CLOSE, fd as u64) }).map(|_| ())
+}
+
+/// Move the offset of `fd`; returns the new one. Only files can seek, not
+/// the console (ESPIPE).
+pub fn lseek(fd: u32, pos: SeekFrom) -> Result<u64> {
+    let (offset, whence) = match pos {
+        SeekFrom::Start(offset) => (offset as i64, 0),
+        SeekFrom::Current(offset) => (offset, 1),
+        SeekFrom::End(offset) => (offset, 2),
+    };
+    check(unsafe { syscall3(nr::LSEEK, fd as u64, offset as u64, whence) })
+}
+
+/// Make `new` refer to what `old` does (closing what it did), sharing the
+/// offset. `new` is not close-on-exec.
+pub fn dup2(old: u32, new: u32) -> Result<u32> {
+    check(unsafe { syscall2(nr::DUP2, old as u64, new as u64) }).map(|fd| fd as u32)
+}
+
+/// A new pipe: its read end and its write end, with `flags` 0 or
+/// `O_CLOEXEC`. Reads block until something is written and return 0 once
+/// every descriptor for the write end is closed; writes block while the
+/// pipe is full and fail with EPIPE (and a SIGPIPE) once the read end is
+/// closed.
+pub fn pipe(flags: u32) -> Result<(u32, u32)> {
+    let mut fds = [0u32; 2];
+    check(unsafe { syscall2(nr::PIPE, fds.as_mut_ptr() as u64, flags as u64) })?;
+    Ok((fds[0], fds[1]))
+}
+
+/// An open file, closed when dropped.
+pub struct File {
+    fd: u32,
+}
+
+impl File {
+    /// Open `path` for reading.
+    pub fn open(path: &str) -> Result<File> {
+        open(path, O_RDONLY).map(|fd| File { fd })
+    }
+
+    pub fn fd(&self) -> u32 {
+        self.fd
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read(self.fd, buf)
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        lseek(self.fd, pos)
+    }
+
+    /// Everything from the offset to the end.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+/// Read into `buf`, blocking until there is input; how much was read, 0 at
+/// end of file.
+pub fn read(fd: u32, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe { syscall3(nr::READ, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64) }).map(|n| n as usize)
+}
+
+/// Write `buf`; how much was written.
+pub fn write(fd: u32, buf: &[u8]) -> Result<usize> {
+    check(unsafe { syscall3(nr::WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64) }).map(|n| n as usize)
+}
+
+/// Write all of `buf`, however many calls that takes.
+pub fn write_all(fd: u32, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let n = write(fd, buf)?;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+/// Read a line from `fd` into `line`, newline included; how many bytes
+/// that was, 0 at end of file. Reads until one `read` ends with a newline,
+/// as the console's do (a line at a time). Bytes that aren't UTF-8 come out
+/// as U+FFFD.
+pub fn read_line(fd: u32, line: &mut String) -> Result<usize> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    while bytes.last() != Some(&b'\n') {
+        let n = read(fd, &mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..n]);
+    }
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(bytes.len())
+}
+
+/// A file descriptor as a `fmt::Write`.
+pub struct Fd(pub u32);
+
+impl Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u32, args: fmt::Arguments) {
+    let _ = Fd(fd).write_fmt(args);
+}
+
+/// Print to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Print to standard error.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
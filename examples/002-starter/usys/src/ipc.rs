@@ -0,0 +1,55 @@
+//! Message queues and shared memory segments (see the kernel's `ipc`).
+//!
+//! Both are named by a key: the first to open a key creates it, the others
+//! get the same one, and key 0 always creates a private one. Timeouts are
+//! in milliseconds; `None` waits for as long as it takes.
+
+use crate::mem::{PROT_READ, PROT_WRITE};
+use crate::syscall::{check, nr, syscall1, syscall2, syscall4, Result};
+
+/// The largest message.
+pub const MAX_MESSAGE: usize = 256;
+
+fn timeout_ms(timeout: Option<u64>) -> u64 {
+    timeout.map_or(-1i64 as u64, |ms| ms.min(i64::MAX as u64))
+}
+
+/// The ID of the queue for `key`, created with room for `capacity`
+/// messages if there is none.
+pub fn mq_open(key: u64, capacity: usize) -> Result<u64> {
+    check(unsafe { syscall2(nr::MQ_OPEN, key, capacity as u64) })
+}
+
+/// Send `message`, waiting up to `timeout` for room: ETIMEDOUT, or EAGAIN
+/// with a timeout of 0.
+pub fn mq_send(qid: u64, message: &[u8], timeout: Option<u64>) -> Result<()> {
+    let ret = unsafe { syscall4(nr::MQ_SEND, qid, message.as_ptr() as u64, message.len() as u64, timeout_ms(timeout)) };
+    check(ret).map(|_| ())
+}
+
+/// Receive the oldest message into `buf`, waiting up to `timeout` for one;
+/// its length. EMSGSIZE if it doesn't fit.
+pub fn mq_recv(qid: u64, buf: &mut [u8], timeout: Option<u64>) -> Result<usize> {
+    let ret = unsafe { syscall4(nr::MQ_RECV, qid, buf.as_mut_ptr() as u64, buf.len() as u64, timeout_ms(timeout)) };
+    check(ret).map(|n| n as usize)
+}
+
+pub fn mq_remove(qid: u64) -> Result<()> {
+    check(unsafe { syscall1(nr::MQ_REMOVE, qid) }).map(|_| ())
+}
+
+/// The ID of the segment for `key`, created with `size` zeroed bytes if
+/// there is none.
+pub fn shm_create(key: u64, size: usize) -> Result<u64> {
+    check(unsafe { syscall2(nr::SHM_CREATE, key, size as u64) })
+}
+
+/// Map the whole segment, readable and, if `writable`, writable; where.
+pub fn shm_map(id: u64, writable: bool) -> Result<*mut u8> {
+    let prot = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+    check(unsafe { syscall2(nr::SHM_MAP, id, prot) }).map(|addr| addr as *mut u8)
+}
+
+pub fn shm_remove(id: u64) -> Result<()> {
+    check(unsafe { syscall1(nr::SHM_REMOVE, id) }).map(|_| ())
+}
@@ -0,0 +1,53 @@
+//! The runtime user programs link with: typed wrappers for the kernel's
+//! system calls, `print!` and `println!` on the console, a heap for `alloc`,
+//! a panic handler, and the `_start` that calls the program's `main`.
+//!
+//! A program is a `#![no_std]`, `#![no_main]` binary that names its main
+//! function with `entry!`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! usys::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     usys::println!("hello from pid {}", usys::process::getpid());
+//!     0
+//! }
+//! ```
+//!
+//! What `main` returns is the exit status; `env` has the arguments and the
+//! environment. Calls that can fail return a `Result` with the kernel's
+//! `Errno`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod caps;
+pub mod env;
+pub mod green;
+mod heap;
+pub mod io;
+pub mod ipc;
+pub mod mem;
+pub mod process;
+mod rt;
+pub mod signal;
+pub mod syscall;
+pub mod time;
+
+pub use syscall::Errno;
+
+/// Make `$main`, a `fn() -> i32`, the program's main function.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        fn __usys_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
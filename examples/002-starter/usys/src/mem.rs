@@ -0,0 +1,27 @@
+//! Memory: the heap's end (`sbrk`) and anonymous mappings (`mmap`). The
+//! allocator behind `alloc` uses both; programs rarely need to.
+
+use crate::syscall::{check, nr, syscall1, syscall2, Result};
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Move the end of the heap by `increment`; returns the old end. The
+/// allocator expects the end to stay page aligned.
+pub fn sbrk(increment: isize) -> Result<*mut u8> {
+    check(unsafe { syscall1(nr::SBRK, increment as u64) }).map(|addr| addr as *mut u8)
+}
+
+/// `len` bytes of zeroed memory, page aligned, with PROT_* bits `prot`.
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8> {
+    check(unsafe { syscall2(nr::MMAP, len as u64, prot) }).map(|addr| addr as *mut u8)
+}
+
+/// Give back a whole mapping from `mmap` (or `ipc::shm_map`).
+///
+/// # Safety
+/// Nothing may use the memory afterwards.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    check(syscall2(nr::MUNMAP, addr as u64, len as u64)).map(|_| ())
+}
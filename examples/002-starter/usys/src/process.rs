@@ -0,0 +1,105 @@
+//! Processes: exiting, starting programs and waiting for them.
+//!
+//! Paths, arguments and environment strings go to the kernel as (pointer,
+//! length) pairs, not NUL-terminated strings. A program started with
+//! `spawn` or `exec` inherits this one's environment; `spawn_env` and
+//! `exec_env` give it another.
+
+use alloc::vec::Vec;
+
+use crate::env;
+use crate::syscall::{check, nr, syscall0, syscall1, syscall2, syscall3, syscall6, Errno, Result};
+
+/// `trace` flags: log the process's own system calls to the kernel's
+/// serial port, and trace the children it starts from now on.
+pub const TRACE_SELF: u32 = 1;
+pub const TRACE_CHILDREN: u32 = 2;
+
+/// End the program with `status`.
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall1(nr::EXIT, status as u64) };
+    unreachable!("exit returned");
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall0(nr::GETPID) }
+}
+
+/// `args` as the kernel takes them: (pointer, length) for each.
+fn pairs<S: AsRef<str>>(args: &[S]) -> Vec<[u64; 2]> {
+    args.iter().map(|arg| [arg.as_ref().as_ptr() as u64, arg.as_ref().len() as u64]).collect()
+}
+
+/// The system call `number` (spawn or exec) on `path`, `args` and `env`.
+fn run<S: AsRef<str>>(number: usize, path: &str, args: &[&str], env: &[S]) -> Result<u64> {
+    let (argv, envp) = (pairs(args), pairs(env));
+    check(unsafe {
+        syscall6(
+            number,
+            path.as_ptr() as u64,
+            path.len() as u64,
+            argv.as_ptr() as u64,
+            argv.len() as u64,
+            envp.as_ptr() as u64,
+            envp.len() as u64,
+        )
+    })
+}
+
+/// Start the program at `path` in a child process, with `args` as its argv
+/// (`args[0]` being its name, by convention); returns its PID.
+pub fn spawn(path: &str, args: &[&str]) -> Result<u64> {
+    spawn_env(path, args, &env::environ().collect::<Vec<_>>())
+}
+
+/// `spawn` with `env` ("KEY=value" strings) as the environment.
+pub fn spawn_env<S: AsRef<str>>(path: &str, args: &[&str], env: &[S]) -> Result<u64> {
+    run(nr::SPAWN, path, args, env)
+}
+
+/// Replace this program with the one at `path`. Only returns if that failed.
+pub fn exec(path: &str, args: &[&str]) -> Errno {
+    exec_env(path, args, &env::environ().collect::<Vec<_>>())
+}
+
+/// `exec` with `env` as the environment.
+pub fn exec_env<S: AsRef<str>>(path: &str, args: &[&str], env: &[S]) -> Errno {
+    match run(nr::EXEC, path, args, env) {
+        Ok(_) => unreachable!("exec returned"),
+        Err(errno) => errno,
+    }
+}
+
+const WNOHANG: u64 = 1;
+
+/// Wait for child `pid`, or any child if `None`, to exit; its PID and exit
+/// status. ECHILD if there is no such child.
+pub fn wait(pid: Option<u64>) -> Result<(u64, i32)> {
+    wait_with(pid, 0).map(|waited| waited.expect("wait blocks"))
+}
+
+/// `wait` without blocking: `None` if the child hasn't exited yet.
+pub fn try_wait(pid: Option<u64>) -> Result<Option<(u64, i32)>> {
+    wait_with(pid, WNOHANG)
+}
+
+fn wait_with(pid: Option<u64>, options: u64) -> Result<Option<(u64, i32)>> {
+    let mut status = 0i32;
+    let pid = pid.map_or(-1, |pid| pid as i64);
+    let child = check(unsafe { syscall3(nr::WAIT, pid as u64, &mut status as *mut i32 as u64, options) })?;
+    Ok((child != 0).then_some((child, status)))
+}
+
+/// From now on, allow this program only the system calls `allowed` (each
+/// an `nr::*`), and `exit`: any other kills it with SIGSYS. For good, and
+/// its children inherit it; calling this again can only leave more out.
+pub fn seccomp(allowed: &[usize]) -> Result<()> {
+    let mask = allowed.iter().fold(0u64, |mask, &nr| mask | 1u64.checked_shl(nr as u32).unwrap_or(0));
+    check(unsafe { syscall1(nr::SECCOMP, mask) }).map(|_| ())
+}
+
+/// Trace process `pid` (0 for this one, else a child of it) as `flags`
+/// say; 0 stops tracing it. A traced process's children are traced too.
+pub fn trace(pid: u64, flags: u32) -> Result<()> {
+    check(unsafe { syscall2(nr::TRACE, pid, flags as u64) }).map(|_| ())
+}
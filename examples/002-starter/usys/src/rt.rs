@@ -0,0 +1,39 @@
+//! Startup and panics. The kernel starts the program at `_start` with the
+//! stack pointer at argc, followed by argv, envp and auxv (see `env`);
+//! `_start` hands that to `env` and calls the program's main function.
+
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+use crate::{env, process};
+
+// RBP cleared ends frame-pointer walks here; the call leaves RSP as any
+// function expects it.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "    xor ebp, ebp",
+    "    mov rdi, rsp",
+    "    and rsp, -16",
+    "    call {start}",
+    "    ud2",
+    start = sym start,
+);
+
+extern "Rust" {
+    /// The program's main function, named by `entry!`.
+    fn __usys_main() -> i32;
+}
+
+unsafe extern "C" fn start(stack: *const usize) -> ! {
+    env::init(stack);
+    process::exit(__usys_main())
+}
+
+/// Print the panic to standard error and exit with 101, like a Rust program
+/// on Linux.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crate::eprintln!("{}", info);
+    process::exit(101)
+}
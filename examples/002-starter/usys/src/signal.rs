@@ -0,0 +1,63 @@
+//! Signals (see the kernel's `process::signal`): sending them, and choosing
+//! what happens when one arrives.
+//!
+//! A handler is an `extern "C" fn(signal)`. It runs on the program's stack
+//! wherever the program was, and returns there through `sigreturn`, which
+//! the restorer here calls. It must not use the x87/SSE registers, which
+//! the kernel doesn't save around it, or allocate.
+
+use core::arch::global_asm;
+
+use crate::syscall::{check, nr, syscall2, syscall3, Result};
+
+pub const SIGINT: u32 = 2;
+pub const SIGILL: u32 = 4;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGTERM: u32 = 15;
+pub const SIGSYS: u32 = 31;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+#[derive(Clone, Copy)]
+pub enum Handler {
+    /// Most signals terminate the program.
+    Default,
+    Ignore,
+    Call(extern "C" fn(u32)),
+}
+
+// Where handlers return to: their frame's stack pointer is where the
+// kernel saved the program's registers.
+global_asm!(
+    ".global __usys_restorer",
+    "__usys_restorer:",
+    "    mov eax, {sigreturn}",
+    "    syscall",
+    "    ud2",
+    sigreturn = const nr::SIGRETURN,
+);
+
+extern "C" {
+    fn __usys_restorer();
+}
+
+/// Send `signal` to process `pid`; signal 0 only checks that it exists.
+pub fn kill(pid: u64, signal: u32) -> Result<()> {
+    check(unsafe { syscall2(nr::KILL, pid, signal as u64) }).map(|_| ())
+}
+
+/// What to do with `signal` from now on. SIGKILL can't be changed.
+pub fn signal(signal: u32, handler: Handler) -> Result<()> {
+    let (handler, restorer) = match handler {
+        Handler::Default => (SIG_DFL, 0),
+        Handler::Ignore => (SIG_IGN, 0),
+        Handler::Call(f) => (f as usize as u64, __usys_restorer as *const () as u64),
+    };
+    check(unsafe { syscall3(nr::SIGACTION, signal as u64, handler, restorer) }).map(|_| ())
+}
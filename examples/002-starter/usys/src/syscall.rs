@@ -0,0 +1,191 @@
+//! The raw system call instruction, the numbers, and the errors.
+//!
+//! The ABI is the kernel's (see its `syscall` module): the number in RAX,
+//! arguments in RDI, RSI, RDX, R10, R8 and R9, and the result in RAX, where
+//! -4095..=-1 is a negated `Errno`. RCX and R11 are clobbered.
+
+use core::arch::asm;
+use core::fmt;
+
+/// System call numbers.
+pub mod nr {
+    pub const EXIT: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const GETPID: usize = 2;
+    pub const SPAWN: usize = 3;
+    pub const EXEC: usize = 4;
+    pub const SBRK: usize = 5;
+    pub const MMAP: usize = 6;
+    pub const MUNMAP: usize = 7;
+    pub const READ: usize = 8;
+    pub const WAIT: usize = 9;
+    pub const MQ_OPEN: usize = 10;
+    pub const MQ_SEND: usize = 11;
+    pub const MQ_RECV: usize = 12;
+    pub const MQ_REMOVE: usize = 13;
+    pub const SHM_CREATE: usize = 14;
+    pub const SHM_MAP: usize = 15;
+    pub const SHM_REMOVE: usize = 16;
+    pub const KILL: usize = 17;
+    pub const SIGACTION: usize = 18;
+    pub const SIGRETURN: usize = 19;
+    pub const CLOCK_GETTIME: usize = 20;
+    pub const NANOSLEEP: usize = 21;
+    pub const UPTIME: usize = 22;
+    pub const OPEN: usize = 23;
+    pub const CLOSE: usize = 24;
+    pub const LSEEK: usize = 25;
+    pub const DUP2: usize = 26;
+    pub const PIPE: usize = 27;
+    pub const CAPGET: usize = 28;
+    pub const CAPSET: usize = 29;
+    pub const TRACE: usize = 30;
+    pub const SECCOMP: usize = 31;
+}
+
+/// An error number, as in Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const EROFS: Errno = Errno(30);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const EIDRM: Errno = Errno(43);
+    pub const EMSGSIZE: Errno = Errno(90);
+    pub const ETIMEDOUT: Errno = Errno(110);
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Errno::EPERM => "EPERM",
+            Errno::ENOENT => "ENOENT",
+            Errno::ESRCH => "ESRCH",
+            Errno::EINTR => "EINTR",
+            Errno::EIO => "EIO",
+            Errno::E2BIG => "E2BIG",
+            Errno::ENOEXEC => "ENOEXEC",
+            Errno::EBADF => "EBADF",
+            Errno::ECHILD => "ECHILD",
+            Errno::EAGAIN => "EAGAIN",
+            Errno::ENOMEM => "ENOMEM",
+            Errno::EFAULT => "EFAULT",
+            Errno::EEXIST => "EEXIST",
+            Errno::ENOTDIR => "ENOTDIR",
+            Errno::EISDIR => "EISDIR",
+            Errno::EINVAL => "EINVAL",
+            Errno::EMFILE => "EMFILE",
+            Errno::ENOSPC => "ENOSPC",
+            Errno::ESPIPE => "ESPIPE",
+            Errno::EROFS => "EROFS",
+            Errno::EPIPE => "EPIPE",
+            Errno::ENAMETOOLONG => "ENAMETOOLONG",
+            Errno::ENOSYS => "ENOSYS",
+            Errno::EIDRM => "EIDRM",
+            Errno::EMSGSIZE => "EMSGSIZE",
+            Errno::ETIMEDOUT => "ETIMEDOUT",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name(), self.0)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// Split a raw result into the value and the error.
+pub fn check(ret: u64) -> Result<u64> {
+    if ret >= -4095i64 as u64 {
+        Err(Errno(-(ret as i64) as i32))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// # Safety
+/// The arguments must be what system call `nr` expects: pointers to memory
+/// it may read or write.
+pub unsafe fn syscall0(nr: usize) -> u64 {
+    let ret;
+    asm!("syscall", inlateout("rax") nr as u64 => ret, out("rcx") _, out("r11") _, options(nostack));
+    ret
+}
+
+/// # Safety
+/// See `syscall0`.
+pub unsafe fn syscall1(nr: usize, a: u64) -> u64 {
+    let ret;
+    asm!("syscall", inlateout("rax") nr as u64 => ret, in("rdi") a, out("rcx") _, out("r11") _, options(nostack));
+    ret
+}
+
+/// # Safety
+/// See `syscall0`.
+pub unsafe fn syscall2(nr: usize, a: u64, b: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as u64 => ret, in("rdi") a, in("rsi") b,
+        out("rcx") _, out("r11") _, options(nostack),
+    );
+    ret
+}
+
+/// # Safety
+/// See `syscall0`.
+pub unsafe fn syscall3(nr: usize, a: u64, b: u64, c: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as u64 => ret, in("rdi") a, in("rsi") b, in("rdx") c,
+        out("rcx") _, out("r11") _, options(nostack),
+    );
+    ret
+}
+
+/// # Safety
+/// See `syscall0`.
+pub unsafe fn syscall4(nr: usize, a: u64, b: u64, c: u64, d: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as u64 => ret, in("rdi") a, in("rsi") b, in("rdx") c, in("r10") d,
+        out("rcx") _, out("r11") _, options(nostack),
+    );
+    ret
+}
+
+/// # Safety
+/// See `syscall0`.
+pub unsafe fn syscall6(nr: usize, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> u64 {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as u64 => ret, in("rdi") a, in("rsi") b, in("rdx") c, in("r10") d, in("r8") e, in("r9") f,
+        out("rcx") _, out("r11") _, options(nostack),
+    );
+    ret
+}
@@ -0,0 +1,174 @@
+//! Time: the monotonic clock for measuring, the wall clock for telling the
+//! date, and sleeping.
+//!
+//! Both clocks come as a `Duration`: since boot for the monotonic one, since
+//! the Unix epoch for the wall clock, which the kernel takes from the CMOS
+//! clock (UTC) at boot.
+//!
+//! Reading either clock needs no system call: the kernel maps a read-only
+//! page into every process with what the clocks are computed from (the
+//! time-stamp counter's rate and where it started, the wall-clock time at
+//! boot), and `clock_gettime` does the kernel's sums over it. Only when
+//! there is no such page does it ask the kernel.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::env::{self, AT_TIME_PAGE};
+use crate::syscall::{check, nr, syscall0, syscall2, Errno, Result};
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// `struct timespec`: seconds and nanoseconds.
+#[repr(C)]
+#[derive(Default)]
+struct Timespec {
+    secs: i64,
+    nanos: i64,
+}
+
+impl Timespec {
+    fn from_duration(duration: Duration) -> Timespec {
+        Timespec { secs: duration.as_secs().min(i64::MAX as u64) as i64, nanos: duration.subsec_nanos() as i64 }
+    }
+
+    fn to_duration(&self) -> Duration {
+        Duration::new(self.secs as u64, self.nanos as u32)
+    }
+}
+
+/// The kernel's time page, as its `time::vdso` lays it out.
+#[repr(C)]
+struct TimePage {
+    version: AtomicU64,
+    hz: AtomicU64,
+    ticks: AtomicU64,
+    tsc_per_ms: AtomicU64,
+    tsc_start: AtomicU64,
+    boot_time_us: AtomicU64,
+}
+
+/// The layout this reads.
+const TIME_PAGE_VERSION: u64 = 1;
+
+/// The time page, if the kernel mapped one this can read.
+fn time_page() -> Option<&'static TimePage> {
+    let page = unsafe { &*(env::aux(AT_TIME_PAGE)? as *const TimePage) };
+    let ready = page.version.load(Ordering::Acquire) == TIME_PAGE_VERSION && page.tsc_per_ms.load(Ordering::Relaxed) != 0;
+    ready.then_some(page)
+}
+
+/// The time of `clock` (a `CLOCK_*`), from the time page if there is one.
+pub fn clock_gettime(clock: u32) -> Result<Duration> {
+    let Some(page) = time_page() else { return clock_gettime_syscall(clock) };
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(page.tsc_start.load(Ordering::Relaxed));
+    let since_boot = Duration::from_micros((cycles as u128 * 1000 / page.tsc_per_ms.load(Ordering::Relaxed) as u128) as u64);
+    match clock {
+        CLOCK_MONOTONIC => Ok(since_boot),
+        CLOCK_REALTIME => Ok(Duration::from_micros(page.boot_time_us.load(Ordering::Relaxed)) + since_boot),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// The time of `clock`, asking the kernel: the slow way, for comparing.
+pub fn clock_gettime_syscall(clock: u32) -> Result<Duration> {
+    let mut ts = Timespec::default();
+    check(unsafe { syscall2(nr::CLOCK_GETTIME, clock as u64, &mut ts as *mut Timespec as u64) })?;
+    Ok(ts.to_duration())
+}
+
+/// Block for `duration`, rounded up to the kernel's 10 ms ticks. A signal
+/// cuts it short: `Err` then, with the time that was left.
+pub fn sleep(duration: Duration) -> core::result::Result<(), Duration> {
+    let request = Timespec::from_duration(duration);
+    let mut left = Timespec::default();
+    match check(unsafe { syscall2(nr::NANOSLEEP, &request as *const Timespec as u64, &mut left as *mut Timespec as u64) }) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(left.to_duration()),
+    }
+}
+
+/// Timer ticks since boot and how many there are a second, from the time
+/// page; `None` without one.
+pub fn ticks() -> Option<(u64, u64)> {
+    time_page().map(|page| (page.ticks.load(Ordering::Acquire), page.hz.load(Ordering::Relaxed)))
+}
+
+/// Time since boot, in milliseconds.
+pub fn uptime() -> Duration {
+    Duration::from_millis(unsafe { syscall0(nr::UPTIME) })
+}
+
+/// A point on the monotonic clock, for measuring how long something took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(clock_gettime(CLOCK_MONOTONIC).expect("the monotonic clock is always there"))
+    }
+
+    /// Time from `earlier` to this one; zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+/// The wall-clock time, since the Unix epoch.
+pub fn unix_time() -> Duration {
+    clock_gettime(CLOCK_REALTIME).expect("the wall clock is always there")
+}
+
+/// A date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date `seconds` after the Unix epoch (Howard Hinnant's
+    /// `civil_from_days`).
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let time = seconds % 86_400;
+        DateTime {
+            year: year_of_era + era * 400 + if month <= 2 { 1 } else { 0 },
+            month,
+            day: (day_of_year - (153 * mp + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    pub fn now() -> DateTime {
+        DateTime::from_unix(unix_time().as_secs())
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}